@@ -0,0 +1,423 @@
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hmac::{Hmac, Mac};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Noise protocol name, also used as the initial handshake hash.
+pub const PROTOCOL_NAME: &[u8] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+/// Length of hashes and keys.
+pub const HASH_LENGTH: usize = 32;
+/// Length of X25519 public keys.
+pub const DH_LENGTH: usize = 32;
+/// Length of AEAD authentication tags.
+pub const TAG_LENGTH: usize = 16;
+
+/// Error during handshake
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum HandshakeError {
+    /// Message written or read out of turn, or after the handshake finished
+    #[error("handshake message out of order")]
+    OutOfOrder,
+    /// Message ended before all handshake tokens were read
+    #[error("handshake message too short")]
+    MessageTooShort,
+    /// Message failed authentication
+    #[error("handshake message decryption failed")]
+    DecryptFailed,
+    /// Handshake has not completed
+    #[error("handshake not finished")]
+    NotFinished,
+}
+
+/// X25519 keypair.
+#[derive(Clone)]
+pub struct Keypair {
+    pub secret: StaticSecret,
+    pub public: PublicKey,
+}
+
+impl Keypair {
+    /// Generate new random keypair.
+    pub fn generate() -> Self {
+        Keypair::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Construct keypair from secret key.
+    pub fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Keypair { secret, public }
+    }
+}
+
+/// Handshake tokens, as defined by the Noise specification
+#[derive(Clone, Copy)]
+enum Token {
+    E,
+    S,
+    EE,
+    ES,
+    SE,
+}
+
+/// Noise XX pattern, one entry per message. Even messages are sent by the
+/// initiator.
+const PATTERN_XX: [&[Token]; 3] = [
+    &[Token::E],
+    &[Token::E, Token::EE, Token::S, Token::ES],
+    &[Token::S, Token::SE],
+];
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// HMAC-SHA256 over the concatenation of `data`.
+pub(crate) fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; HASH_LENGTH] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("hmac accepts any key length");
+    for d in data {
+        mac.update(d);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Noise HKDF producing two outputs.
+fn hkdf2(chaining_key: &[u8], ikm: &[u8]) -> ([u8; HASH_LENGTH], [u8; HASH_LENGTH]) {
+    let temp_key = hmac(chaining_key, &[ikm]);
+    let out1 = hmac(&temp_key, &[&[1]]);
+    let out2 = hmac(&temp_key, &[&out1, &[2]]);
+    (out1, out2)
+}
+
+/// Noise cipher state.
+#[derive(Clone, Default)]
+struct CipherState {
+    key: Option<[u8; HASH_LENGTH]>,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn nonce_bytes(&self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        nonce
+    }
+
+    fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let Some(key) = self.key else {
+            return plaintext.to_vec();
+        };
+        let aead = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let payload = Payload {
+            msg: plaintext,
+            aad: ad,
+        };
+        let out = aead
+            .encrypt(Nonce::from_slice(&self.nonce_bytes()), payload)
+            .expect("handshake message too large");
+        self.nonce += 1;
+        out
+    }
+
+    fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let Some(key) = self.key else {
+            return Ok(ciphertext.to_vec());
+        };
+        let aead = ChaCha20Poly1305::new(Key::from_slice(&key));
+        let payload = Payload {
+            msg: ciphertext,
+            aad: ad,
+        };
+        let out = aead
+            .decrypt(Nonce::from_slice(&self.nonce_bytes()), payload)
+            .map_err(|_| HandshakeError::DecryptFailed)?;
+        self.nonce += 1;
+        Ok(out)
+    }
+}
+
+/// Noise symmetric state.
+#[derive(Clone)]
+struct SymmetricState {
+    cipher: CipherState,
+    chaining_key: [u8; HASH_LENGTH],
+    hash: [u8; HASH_LENGTH],
+}
+
+impl SymmetricState {
+    fn new(prologue: &[u8]) -> Self {
+        let mut hash = [0u8; HASH_LENGTH];
+        hash[..PROTOCOL_NAME.len()].copy_from_slice(PROTOCOL_NAME);
+        let mut state = SymmetricState {
+            cipher: CipherState::default(),
+            chaining_key: hash,
+            hash,
+        };
+        state.mix_hash(prologue);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash);
+        hasher.update(data);
+        self.hash = hasher.finalize().into();
+    }
+
+    fn mix_key(&mut self, ikm: &[u8]) {
+        let (chaining_key, key) = hkdf2(&self.chaining_key, ikm);
+        self.chaining_key = chaining_key;
+        self.cipher = CipherState {
+            key: Some(key),
+            nonce: 0,
+        };
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = self.cipher.encrypt(&self.hash, plaintext);
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let plaintext = self.cipher.decrypt(&self.hash, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    fn tag_length(&self) -> usize {
+        if self.cipher.key.is_some() {
+            TAG_LENGTH
+        } else {
+            0
+        }
+    }
+}
+
+/// Keys resulting from a completed handshake.
+pub struct HandshakeResult {
+    /// Key for packets we send
+    pub send_key: [u8; HASH_LENGTH],
+    /// Key for packets we receive
+    pub recv_key: [u8; HASH_LENGTH],
+    /// Hash of the handshake transcript, identical on both sides
+    pub handshake_hash: [u8; HASH_LENGTH],
+    /// Static public key of the peer
+    pub remote_static: PublicKey,
+}
+
+/// Sans-IO Noise XX handshake state machine.
+///
+/// Messages are produced by `write_message` and consumed by `read_message`,
+/// alternating starting with the initiator. Transport of messages is left to
+/// the caller.
+pub struct Handshake {
+    is_initiator: bool,
+    symmetric: SymmetricState,
+    local_static: Keypair,
+    local_ephemeral: Option<Keypair>,
+    remote_static: Option<PublicKey>,
+    remote_ephemeral: Option<PublicKey>,
+    /// Index of next message in pattern
+    message_index: usize,
+}
+
+impl Handshake {
+    /// Construct new handshake. Both sides must use the same prologue.
+    pub fn new(is_initiator: bool, local_static: Keypair, prologue: &[u8]) -> Self {
+        Handshake {
+            is_initiator,
+            symmetric: SymmetricState::new(prologue),
+            local_static,
+            local_ephemeral: None,
+            remote_static: None,
+            remote_ephemeral: None,
+            message_index: 0,
+        }
+    }
+
+    /// Whether all handshake messages have been exchanged.
+    pub fn is_finished(&self) -> bool {
+        self.message_index >= PATTERN_XX.len()
+    }
+
+    /// Whether the next message is ours to write.
+    pub fn is_my_turn(&self) -> bool {
+        !self.is_finished() && self.message_index.is_multiple_of(2) == self.is_initiator
+    }
+
+    /// Perform DH for token.
+    fn dh(&self, token: Token) -> Result<[u8; DH_LENGTH], HandshakeError> {
+        let local_ephemeral = self.local_ephemeral.as_ref().map(|k| &k.secret);
+        let (local, remote) = match (token, self.is_initiator) {
+            (Token::EE, _) => (local_ephemeral, self.remote_ephemeral),
+            (Token::ES, true) | (Token::SE, false) => (local_ephemeral, self.remote_static),
+            (Token::ES, false) | (Token::SE, true) => {
+                (Some(&self.local_static.secret), self.remote_ephemeral)
+            }
+            _ => unreachable!("not a dh token"),
+        };
+        let local = local.ok_or(HandshakeError::OutOfOrder)?;
+        let remote = remote.ok_or(HandshakeError::OutOfOrder)?;
+        Ok(local.diffie_hellman(&remote).to_bytes())
+    }
+
+    /// Write next handshake message carrying `payload` to `out`.
+    pub fn write_message(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), HandshakeError> {
+        if !self.is_my_turn() {
+            return Err(HandshakeError::OutOfOrder);
+        }
+        for token in PATTERN_XX[self.message_index] {
+            match token {
+                Token::E => {
+                    let ephemeral = Keypair::generate();
+                    out.extend_from_slice(ephemeral.public.as_bytes());
+                    self.symmetric.mix_hash(ephemeral.public.as_bytes());
+                    self.local_ephemeral = Some(ephemeral);
+                }
+                Token::S => {
+                    let public = *self.local_static.public.as_bytes();
+                    let ciphertext = self.symmetric.encrypt_and_hash(&public);
+                    out.extend_from_slice(&ciphertext);
+                }
+                dh_token => {
+                    let shared = self.dh(*dh_token)?;
+                    self.symmetric.mix_key(&shared);
+                }
+            }
+        }
+        let ciphertext = self.symmetric.encrypt_and_hash(payload);
+        out.extend_from_slice(&ciphertext);
+        self.message_index += 1;
+        Ok(())
+    }
+
+    /// Read next handshake message, returning its payload.
+    ///
+    /// On error, the handshake state is left unchanged.
+    pub fn read_message(&mut self, message: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        if self.is_finished() || self.is_my_turn() {
+            return Err(HandshakeError::OutOfOrder);
+        }
+        let symmetric = self.symmetric.clone();
+        let remote_keys = (self.remote_static, self.remote_ephemeral);
+        let result = self.read_tokens(message);
+        if result.is_err() {
+            self.symmetric = symmetric;
+            (self.remote_static, self.remote_ephemeral) = remote_keys;
+        }
+        result
+    }
+
+    /// Process tokens of message, returning its payload.
+    fn read_tokens(&mut self, message: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+        let mut rest = message;
+        let mut take = |len: usize| -> Result<&[u8], HandshakeError> {
+            if rest.len() < len {
+                return Err(HandshakeError::MessageTooShort);
+            }
+            let (head, tail) = rest.split_at(len);
+            rest = tail;
+            Ok(head)
+        };
+        for token in PATTERN_XX[self.message_index] {
+            match token {
+                Token::E => {
+                    let public: [u8; DH_LENGTH] = take(DH_LENGTH)?.try_into().unwrap();
+                    self.symmetric.mix_hash(&public);
+                    self.remote_ephemeral = Some(PublicKey::from(public));
+                }
+                Token::S => {
+                    let ciphertext = take(DH_LENGTH + self.symmetric.tag_length())?;
+                    let public = self.symmetric.decrypt_and_hash(ciphertext)?;
+                    let public: [u8; DH_LENGTH] = public.try_into().unwrap();
+                    self.remote_static = Some(PublicKey::from(public));
+                }
+                dh_token => {
+                    let shared = self.dh(*dh_token)?;
+                    self.symmetric.mix_key(&shared);
+                }
+            }
+        }
+        if rest.len() < self.symmetric.tag_length() {
+            return Err(HandshakeError::MessageTooShort);
+        }
+        let payload = self.symmetric.decrypt_and_hash(rest)?;
+        self.message_index += 1;
+        Ok(payload)
+    }
+
+    /// Derive directional keys after the handshake completes.
+    pub fn finish(self) -> Result<HandshakeResult, HandshakeError> {
+        if !self.is_finished() {
+            return Err(HandshakeError::NotFinished);
+        }
+        let (initiator_key, responder_key) = hkdf2(&self.symmetric.chaining_key, &[]);
+        let (send_key, recv_key) = if self.is_initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+        Ok(HandshakeResult {
+            send_key,
+            recv_key,
+            handshake_hash: self.symmetric.hash,
+            remote_static: self.remote_static.expect("remote static key missing"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Handshake, HandshakeError, Keypair};
+
+    #[test]
+    fn handshake_xx() {
+        let client_static = Keypair::generate();
+        let server_static = Keypair::generate();
+        let mut client = Handshake::new(true, client_static.clone(), b"kinesin");
+        let mut server = Handshake::new(false, server_static.clone(), b"kinesin");
+        assert!(client.is_my_turn());
+        assert!(!server.is_my_turn());
+
+        let mut message = Vec::new();
+        assert_eq!(
+            server.write_message(&[], &mut message),
+            Err(HandshakeError::OutOfOrder)
+        );
+        client.write_message(b"hello", &mut message).unwrap();
+        assert_eq!(server.read_message(&message).unwrap(), b"hello");
+
+        let mut message = Vec::new();
+        server.write_message(b"from server", &mut message).unwrap();
+        // tampered message fails authentication
+        let mut tampered = message.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            client.read_message(&tampered),
+            Err(HandshakeError::DecryptFailed)
+        );
+        assert_eq!(
+            client.read_message(&message[..40]),
+            Err(HandshakeError::MessageTooShort)
+        );
+        assert_eq!(client.read_message(&message).unwrap(), b"from server");
+
+        let mut message = Vec::new();
+        client.write_message(&[], &mut message).unwrap();
+        assert!(server.read_message(&message).unwrap().is_empty());
+        assert!(client.is_finished() && server.is_finished());
+
+        let client = client.finish().unwrap();
+        let server = server.finish().unwrap();
+        assert_eq!(client.send_key, server.recv_key);
+        assert_eq!(client.recv_key, server.send_key);
+        assert_ne!(client.send_key, client.recv_key);
+        assert_eq!(client.handshake_hash, server.handshake_hash);
+        assert_eq!(client.remote_static, server_static.public);
+        assert_eq!(server.remote_static, client_static.public);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::handshake::{hmac, HASH_LENGTH};
+use crate::packet_protection::{ChaCha20Poly1305Cipher, OpenError, PacketCipher, IV_LENGTH};
+use crate::replay_protection::ReplayProtection;
+
+/// Error during key update or opening packets
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum KeyUpdateError {
+    /// A previous key update has not been confirmed by the peer
+    #[error("key update already in progress")]
+    UpdatePending,
+    /// Packet number was already seen in its epoch
+    #[error("packet replayed")]
+    Replayed,
+    /// Packet could not be opened with any available keys
+    #[error(transparent)]
+    Open(#[from] OpenError),
+}
+
+/// Tag packet number with the key phase bit, which is the lowest bit of the
+/// key epoch.
+pub fn encode_phase(packet_number: u64, phase: bool) -> u64 {
+    assert!(packet_number < 1 << 63, "packet number out of range");
+    (packet_number << 1) | phase as u64
+}
+
+/// Split tagged packet number into (packet number, key phase).
+pub fn decode_phase(tagged: u64) -> (u64, bool) {
+    (tagged >> 1, tagged & 1 == 1)
+}
+
+/// Derive secret for the next key epoch.
+pub fn next_secret(secret: &[u8; HASH_LENGTH]) -> [u8; HASH_LENGTH] {
+    hmac(secret, &[b"kinesin key update"])
+}
+
+/// Derive packet cipher from secret.
+pub fn cipher_from_secret(secret: &[u8; HASH_LENGTH]) -> ChaCha20Poly1305Cipher {
+    let key = hmac(secret, &[b"kinesin key"]);
+    let iv_full = hmac(secret, &[b"kinesin iv"]);
+    let mut iv = [0u8; IV_LENGTH];
+    iv.copy_from_slice(&iv_full[..IV_LENGTH]);
+    ChaCha20Poly1305Cipher::new(&key, iv)
+}
+
+/// Receive keys and replay state for one key epoch.
+pub struct RecvEpoch {
+    pub epoch: u64,
+    pub secret: [u8; HASH_LENGTH],
+    pub cipher: ChaCha20Poly1305Cipher,
+    /// Replay protection, reset for every epoch
+    pub replay: ReplayProtection,
+}
+
+impl RecvEpoch {
+    fn new(epoch: u64, secret: [u8; HASH_LENGTH], replay_window: usize) -> Self {
+        RecvEpoch {
+            epoch,
+            secret,
+            cipher: cipher_from_secret(&secret),
+            replay: ReplayProtection::new(replay_window),
+        }
+    }
+
+    /// Derive the following epoch.
+    fn next(&self, replay_window: usize) -> Self {
+        RecvEpoch::new(self.epoch + 1, next_secret(&self.secret), replay_window)
+    }
+
+    /// Open packet and mark packet number as seen.
+    fn open(
+        &self,
+        packet_number: u64,
+        packet: &mut Vec<u8>,
+        header_len: usize,
+    ) -> Result<(), KeyUpdateError> {
+        if self.replay.test_index(packet_number) {
+            return Err(KeyUpdateError::Replayed);
+        }
+        self.cipher.open(packet_number, packet, header_len)?;
+        if self.replay.set_index(packet_number) {
+            return Err(KeyUpdateError::Replayed);
+        }
+        Ok(())
+    }
+}
+
+/// Key schedule for a connection, supporting key updates.
+///
+/// Both directions advance together, one epoch at a time. An endpoint
+/// initiates an update by switching to the next epoch, after which it may not
+/// initiate another until the update is confirmed, either explicitly (e.g.
+/// when the peer acknowledges a packet of the new epoch) or by receiving a
+/// packet of the new epoch. The peer follows when it first receives a packet
+/// of the next epoch. Keys of the previous epoch are retained to open
+/// reordered packets until the next update or `discard_previous`.
+pub struct KeySchedule {
+    /// Current epoch
+    epoch: u64,
+    send_secret: [u8; HASH_LENGTH],
+    send_cipher: ChaCha20Poly1305Cipher,
+    recv_current: RecvEpoch,
+    recv_previous: Option<RecvEpoch>,
+    /// Precomputed keys for a peer initiated update
+    recv_next: RecvEpoch,
+    update_pending: bool,
+    replay_window: usize,
+}
+
+impl KeySchedule {
+    /// Construct new instance from initial secrets (e.g. from the handshake).
+    pub fn new(
+        send_secret: [u8; HASH_LENGTH],
+        recv_secret: [u8; HASH_LENGTH],
+        replay_window: usize,
+    ) -> Self {
+        let recv_current = RecvEpoch::new(0, recv_secret, replay_window);
+        let recv_next = recv_current.next(replay_window);
+        KeySchedule {
+            epoch: 0,
+            send_secret,
+            send_cipher: cipher_from_secret(&send_secret),
+            recv_current,
+            recv_previous: None,
+            recv_next,
+            update_pending: false,
+            replay_window,
+        }
+    }
+
+    /// Current key epoch.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Key phase bit of the current epoch.
+    pub fn key_phase(&self) -> bool {
+        self.epoch & 1 == 1
+    }
+
+    /// Whether a locally initiated update awaits confirmation.
+    pub fn update_pending(&self) -> bool {
+        self.update_pending
+    }
+
+    /// Advance both directions to the next epoch.
+    fn advance(&mut self) {
+        self.epoch += 1;
+        self.send_secret = next_secret(&self.send_secret);
+        self.send_cipher = cipher_from_secret(&self.send_secret);
+        let next = self.recv_next.next(self.replay_window);
+        let current = std::mem::replace(&mut self.recv_next, next);
+        self.recv_previous = Some(std::mem::replace(&mut self.recv_current, current));
+    }
+
+    /// Initiate key update. Packets sealed afterwards use the new epoch.
+    pub fn initiate_update(&mut self) -> Result<(), KeyUpdateError> {
+        if self.update_pending {
+            return Err(KeyUpdateError::UpdatePending);
+        }
+        self.advance();
+        self.update_pending = true;
+        Ok(())
+    }
+
+    /// Confirm that the peer has switched to the current epoch.
+    pub fn confirm_update(&mut self) {
+        self.update_pending = false;
+    }
+
+    /// Discard keys of the previous epoch once reordered packets are no
+    /// longer expected.
+    pub fn discard_previous(&mut self) {
+        self.recv_previous = None;
+    }
+
+    /// Seal packet with current keys, returning the packet number tagged with
+    /// the key phase.
+    pub fn seal(&self, packet_number: u64, packet: &mut Vec<u8>, header_len: usize) -> u64 {
+        self.send_cipher.seal(packet_number, packet, header_len);
+        encode_phase(packet_number, self.key_phase())
+    }
+
+    /// Open packet given its tagged packet number, returning the epoch of the
+    /// keys used. Receiving a packet of the next epoch completes a peer
+    /// initiated update.
+    pub fn open(
+        &mut self,
+        tagged_packet_number: u64,
+        packet: &mut Vec<u8>,
+        header_len: usize,
+    ) -> Result<u64, KeyUpdateError> {
+        let (packet_number, phase) = decode_phase(tagged_packet_number);
+        if phase == self.key_phase() {
+            self.recv_current.open(packet_number, packet, header_len)?;
+            self.update_pending = false;
+            return Ok(self.epoch);
+        }
+
+        // other phase, either reordered from previous epoch or peer update
+        if let Some(previous) = &self.recv_previous {
+            match previous.open(packet_number, packet, header_len) {
+                Ok(()) => return Ok(previous.epoch),
+                Err(KeyUpdateError::Open(_)) if !self.update_pending => {}
+                Err(e) => return Err(e),
+            }
+        } else if self.update_pending {
+            return Err(OpenError::AuthenticationFailed.into());
+        }
+        self.recv_next.open(packet_number, packet, header_len)?;
+        self.advance();
+        Ok(self.epoch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeySchedule, KeyUpdateError};
+
+    fn send(from: &KeySchedule, to: &mut KeySchedule, pn: u64) -> Result<u64, KeyUpdateError> {
+        let mut packet = b"hdr payload".to_vec();
+        let tagged = from.seal(pn, &mut packet, 3);
+        let result = to.open(tagged, &mut packet, 3);
+        if result.is_ok() {
+            assert_eq!(packet, b"hdr payload");
+        }
+        result
+    }
+
+    #[test]
+    fn key_update() {
+        let mut a = KeySchedule::new([1; 32], [2; 32], 256);
+        let mut b = KeySchedule::new([2; 32], [1; 32], 256);
+        assert_eq!(send(&a, &mut b, 0), Ok(0));
+        assert_eq!(send(&a, &mut b, 0), Err(KeyUpdateError::Replayed));
+
+        // a initiates, b follows on first packet of new epoch
+        a.initiate_update().unwrap();
+        assert_eq!(a.initiate_update(), Err(KeyUpdateError::UpdatePending));
+        let mut old = b"hdr old".to_vec();
+        let old_tagged = b.seal(1, &mut old, 3);
+        assert_eq!(send(&a, &mut b, 0), Ok(1));
+        assert_eq!(b.epoch(), 1);
+        assert!(!b.update_pending());
+
+        // reordered packet from previous epoch still opens
+        assert_eq!(a.open(old_tagged, &mut old, 3), Ok(0));
+        assert!(a.update_pending());
+        assert_eq!(send(&b, &mut a, 0), Ok(1));
+        assert!(!a.update_pending());
+
+        // b initiates next update
+        b.initiate_update().unwrap();
+        assert_eq!(send(&b, &mut a, 0), Ok(2));
+        assert_eq!(send(&a, &mut b, 5), Ok(2));
+        a.discard_previous();
+        assert_eq!(a.epoch(), 2);
+    }
+}
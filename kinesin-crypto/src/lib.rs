@@ -1 +1,5 @@
+pub mod handshake;
+pub mod key_update;
+pub mod packet_protection;
 pub mod replay_protection;
+mod sync;
@@ -0,0 +1,149 @@
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+
+/// Length of the per-packet initialization vector.
+pub const IV_LENGTH: usize = 12;
+
+/// Error returned when a packet cannot be opened
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum OpenError {
+    /// Packet is shorter than header plus authentication tag
+    #[error("packet too short")]
+    TooShort,
+    /// Authentication tag did not match
+    #[error("packet authentication failed")]
+    AuthenticationFailed,
+}
+
+/// Authenticated encryption of packets.
+///
+/// Packets are split into a header, which is authenticated but left in the
+/// clear, and a payload, which is encrypted. The authentication tag is
+/// appended after the payload.
+pub trait PacketCipher {
+    /// Length of the authentication tag appended to sealed packets.
+    fn tag_length(&self) -> usize;
+
+    /// Encrypt `packet[header_len..]` in place and append the authentication
+    /// tag, authenticating `packet[..header_len]` as associated data.
+    fn seal(&self, packet_number: u64, packet: &mut Vec<u8>, header_len: usize);
+
+    /// Verify and decrypt a packet sealed with `seal`, removing the
+    /// authentication tag. The packet is left unmodified on error.
+    fn open(
+        &self,
+        packet_number: u64,
+        packet: &mut Vec<u8>,
+        header_len: usize,
+    ) -> Result<(), OpenError>;
+}
+
+/// Derive per-packet nonce by XORing the packet number (big-endian) into the
+/// low bytes of the IV.
+pub fn packet_nonce(iv: &[u8; IV_LENGTH], packet_number: u64) -> [u8; IV_LENGTH] {
+    let mut nonce = *iv;
+    let pn = packet_number.to_be_bytes();
+    for (n, p) in nonce[IV_LENGTH - 8..].iter_mut().zip(pn) {
+        *n ^= p;
+    }
+    nonce
+}
+
+/// ChaCha20-Poly1305 packet protection.
+pub struct ChaCha20Poly1305Cipher {
+    /// AEAD instance
+    aead: ChaCha20Poly1305,
+    /// Base IV for nonce derivation
+    iv: [u8; IV_LENGTH],
+}
+
+impl ChaCha20Poly1305Cipher {
+    /// Construct new instance from key and base IV.
+    pub fn new(key: &[u8; 32], iv: [u8; IV_LENGTH]) -> Self {
+        ChaCha20Poly1305Cipher {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+            iv,
+        }
+    }
+}
+
+impl PacketCipher for ChaCha20Poly1305Cipher {
+    fn tag_length(&self) -> usize {
+        16
+    }
+
+    fn seal(&self, packet_number: u64, packet: &mut Vec<u8>, header_len: usize) {
+        let nonce = packet_nonce(&self.iv, packet_number);
+        let (header, payload) = packet.split_at_mut(header_len);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), header, payload)
+            .expect("packet too large to seal");
+        packet.extend_from_slice(&tag);
+    }
+
+    fn open(
+        &self,
+        packet_number: u64,
+        packet: &mut Vec<u8>,
+        header_len: usize,
+    ) -> Result<(), OpenError> {
+        let tag_length = self.tag_length();
+        if packet.len() < header_len + tag_length {
+            return Err(OpenError::TooShort);
+        }
+        let nonce = packet_nonce(&self.iv, packet_number);
+        let tag_start = packet.len() - tag_length;
+        let (rest, tag) = packet.split_at_mut(tag_start);
+        let (header, payload) = rest.split_at_mut(header_len);
+        self.aead
+            .decrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                header,
+                payload,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| OpenError::AuthenticationFailed)?;
+        packet.truncate(tag_start);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChaCha20Poly1305Cipher, OpenError, PacketCipher};
+
+    #[test]
+    fn seal_open() {
+        let cipher = ChaCha20Poly1305Cipher::new(&[7; 32], [3; 12]);
+        let plain = b"header|payload data".to_vec();
+        let mut packet = plain.clone();
+        cipher.seal(42, &mut packet, 7);
+        assert_eq!(packet.len(), plain.len() + cipher.tag_length());
+        assert_eq!(&packet[..7], b"header|");
+        assert_ne!(&packet[7..plain.len()], &plain[7..]);
+
+        // wrong packet number
+        let mut wrong = packet.clone();
+        assert_eq!(
+            cipher.open(43, &mut wrong, 7),
+            Err(OpenError::AuthenticationFailed)
+        );
+        assert_eq!(wrong, packet);
+
+        // tampered header
+        let mut tampered = packet.clone();
+        tampered[0] ^= 1;
+        assert_eq!(
+            cipher.open(42, &mut tampered, 7),
+            Err(OpenError::AuthenticationFailed)
+        );
+
+        assert_eq!(
+            cipher.open(42, &mut packet[..10].to_vec(), 7),
+            Err(OpenError::TooShort)
+        );
+        cipher.open(42, &mut packet, 7).unwrap();
+        assert_eq!(packet, plain);
+    }
+}
@@ -1,9 +1,8 @@
-use parking_lot::RwLock;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::vec::Vec;
 
-/// Concurrent replay protection implemented as a circular buffer.
+use crate::sync::{AtomicUsize, Ordering, RwLock};
 
+/// Concurrent replay protection implemented as a circular buffer.
 pub struct ReplayProtectionInner {
     /// Offset from actual sequence number to head position
     pub start_offset: u64,
@@ -15,6 +14,24 @@ pub struct ReplayProtectionInner {
 }
 
 /// Replay protection implementation for unreliable datagrams
+///
+/// Indices are marked in a window of `size` bits. The window only moves
+/// forward, and is advanced under the write lock when an index past its end
+/// is set, such that the new index lies in the middle of the window.
+///
+/// Guarantees, including under concurrent window advancement:
+///
+/// - No false negatives from `set_index`: of all calls with the same index,
+///   at most one returns false. Once a call returns, every later call with
+///   that index returns true, whether the index is still in the window or
+///   has since fallen behind it.
+/// - False positives only for old indices: `set_index` returns true for an
+///   index never set before only if the index is behind the window, either
+///   before the call or because a concurrent call advanced the window past it
+///   first.
+/// - `test_index` is advisory. It may return false for an index being set
+///   concurrently, but never for an index set by a call which happened
+///   before it, such as an earlier call on the same thread.
 pub struct ReplayProtection {
     pub inner: RwLock<ReplayProtectionInner>,
 }
@@ -41,11 +58,11 @@ impl ReplayProtection {
         let mut bitfield = Vec::new();
         let mut new_len = size / usize::BITS as usize;
         // ensure capacity for at least `size` bits
-        if size % usize::BITS as usize > 0 {
+        if !size.is_multiple_of(usize::BITS as usize) {
             new_len += 1
         }
         // ensure even because i don't trust my ability to write code
-        if new_len % 2 > 0 {
+        if !new_len.is_multiple_of(2) {
             new_len += 1
         }
         bitfield.resize_with(new_len, || AtomicUsize::new(0));
@@ -103,7 +120,7 @@ impl ReplayProtection {
             // advance tail by el_shift, zeroing all elements along the way
             inner.start_offset += el_shift * usize_len_u64;
             while el_shift > 0 {
-                *inner.bitfield[inner.tail].get_mut() = 0;
+                inner.bitfield[inner.tail].store(0, Ordering::Relaxed);
                 inner.tail = (inner.tail + 1) % inner.bitfield.len();
                 el_shift -= 1;
             }
@@ -112,6 +129,7 @@ impl ReplayProtection {
 
     /// Test whether the provided index has been seen.
     /// Always use `set_index` whenever an index needs to be set, or races may occur.
+    /// The result may be outdated by concurrent calls to `set_index`.
     pub fn test_index(&self, index: u64) -> bool {
         let inner_read = self.inner.read();
         match ReplayProtection::resolve_index(&inner_read, index) {
@@ -135,7 +153,10 @@ impl ReplayProtection {
             let inner_read = self.inner.read();
             match ReplayProtection::resolve_index(&inner_read, index) {
                 ResolveIndexResult::Found { element, mask } => {
-                    // TODO: learn about memory order rofl
+                    // relaxed is sufficient: read-modify-write operations on one
+                    // element are totally ordered, so only one caller can see
+                    // the bit unset, and the lock orders these against window
+                    // advancement. no other memory is published by the bit.
                     let old = inner_read.bitfield[element].fetch_or(mask, Ordering::Relaxed);
                     return old & mask > 0;
                 }
@@ -153,7 +174,7 @@ impl ReplayProtection {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod test {
     use super::ReplayProtection;
 
@@ -285,3 +306,70 @@ mod test {
         assert_eq!(total_counts.iter().sum::<u64>(), PER_THREAD);
     }
 }
+
+/// Run with `RUSTFLAGS="--cfg loom" cargo test -p kinesin-crypto --release loom`.
+#[cfg(all(test, loom))]
+mod loom_test {
+    use loom::sync::Arc;
+    use loom::thread;
+
+    use super::ReplayProtection;
+
+    /// 4 elements, window of 256 bits on 64-bit platforms
+    const RP_SIZE: usize = 4 * usize::BITS as usize;
+
+    #[test]
+    fn set_same_index() {
+        loom::model(|| {
+            let rp = Arc::new(ReplayProtection::new(RP_SIZE));
+            let rp_cloned = rp.clone();
+            let t = thread::spawn(move || rp_cloned.set_index(7));
+            let here = rp.set_index(7);
+            let there = t.join().unwrap();
+            assert!(here ^ there, "exactly one call sees the index unset");
+            assert!(rp.test_index(7));
+        });
+    }
+
+    #[test]
+    fn set_while_advancing() {
+        // index stays in the window when advanced to the far index
+        let index = RP_SIZE as u64 / 2 + 20;
+        let far = RP_SIZE as u64 + 20;
+        loom::model(move || {
+            let rp = Arc::new(ReplayProtection::new(RP_SIZE));
+            let threads: Vec<_> = [index, far]
+                .into_iter()
+                .map(|i| {
+                    let rp_cloned = rp.clone();
+                    thread::spawn(move || rp_cloned.set_index(i))
+                })
+                .collect();
+            let here = rp.set_index(index);
+            let results: Vec<bool> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+            assert!(here ^ results[0], "exactly one call sees the index unset");
+            assert!(!results[1]);
+            assert!(rp.test_index(index) && rp.test_index(far));
+            assert!(rp.set_index(index) && rp.set_index(far));
+        });
+    }
+
+    #[test]
+    fn set_while_skipping() {
+        // far index moves the window past the index
+        let far = 3 * RP_SIZE as u64;
+        loom::model(move || {
+            let rp = Arc::new(ReplayProtection::new(RP_SIZE));
+            let rp_cloned = rp.clone();
+            let t = thread::spawn(move || rp_cloned.set_index(far));
+            let here = rp.set_index(5);
+            assert!(!t.join().unwrap());
+            // true only if the window already moved past
+            if here {
+                assert!(rp.inner.read().start_offset > 5);
+            }
+            assert!(rp.test_index(5) && rp.set_index(5));
+            assert!(!rp.test_index(far + 1));
+        });
+    }
+}
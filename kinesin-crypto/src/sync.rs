@@ -0,0 +1,29 @@
+//! Synchronization primitives, replaced by loom's models when built with
+//! `--cfg loom`
+
+#[cfg(not(loom))]
+pub use parking_lot::RwLock;
+#[cfg(not(loom))]
+pub use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(loom)]
+pub use loom::sync::atomic::{AtomicUsize, Ordering};
+
+/// loom RwLock with the parking_lot interface
+#[cfg(loom)]
+pub struct RwLock<T>(loom::sync::RwLock<T>);
+
+#[cfg(loom)]
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> Self {
+        RwLock(loom::sync::RwLock::new(value))
+    }
+
+    pub fn read(&self) -> loom::sync::RwLockReadGuard<'_, T> {
+        self.0.read().unwrap()
+    }
+
+    pub fn write(&self) -> loom::sync::RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap()
+    }
+}
@@ -1,30 +1,13 @@
 //! TCP frame layer
 
-macro_rules! frames_to_enum {
-    ($name:ident; $($variants:ident),*) => {
-        pub enum $name {
-            $(
-                $variants(::kinesin_rdt::frame::$variants)
-            ),*
-        }
+use kinesin_rdt::frame::{Frame, FrameDecodeError, Serialize};
 
-        impl $name {
-            pub fn type_erase(&mut self) -> &mut dyn ::kinesin_rdt::frame::SerializeToEnd {
-                match self {
-                    $(
-                        Self::$variants(ref mut f) => f
-                    ),*
-                }
-            }
-        }
-    }
+/// write frame prefixed with its type tag, returning serialized length
+pub fn write_frame(frame: &Frame, buf: &mut [u8]) -> usize {
+    frame.write(buf)
 }
 
-frames_to_enum! {
-    MacroFrame;
-    StreamData, StreamWindowLimit, StreamFinal
-}
-
-pub fn yay(mut frame: MacroFrame, buf: &mut [u8]) {
-    frame.type_erase().write(buf);
+/// read frame of any type, returning serialized length and frame
+pub fn read_frame(buf: &[u8]) -> Result<(usize, Frame), FrameDecodeError> {
+    Frame::read(buf)
 }
@@ -0,0 +1,181 @@
+//! RangeSet insert, remove, query and complement patterns for each backing
+
+use std::hint::black_box;
+
+use criterion::{
+    criterion_group, criterion_main, measurement::WallTime, BatchSize, BenchmarkGroup,
+    BenchmarkId, Criterion, Throughput,
+};
+use kinesin_rdt::common::range_set::{BTreeRanges, RangeSet, RangeStorage, VecRanges};
+
+/// numbers of ranges to benchmark
+const COUNTS: [u64; 3] = [16, 256, 4096];
+
+/// disjoint ranges of length 10 with gaps of 10, in a shuffled order
+fn shuffled_ranges(count: u64) -> Vec<u64> {
+    // multiplying by an odd constant permutes indices modulo a power of two
+    let modulus = count.next_power_of_two();
+    (0..modulus)
+        .map(|i| i.wrapping_mul(0x9e37_79b9) % modulus)
+        .filter(|&i| i < count)
+        .map(|i| i * 20)
+        .collect()
+}
+
+/// set of `count` disjoint ranges of length 10 with gaps of 10
+fn disjoint<S: RangeStorage>(count: u64) -> RangeSet<S> {
+    let mut set = RangeSet::default();
+    for i in 0..count {
+        set.insert_range(i * 20..i * 20 + 10);
+    }
+    set
+}
+
+/// segment offsets as received for a stream with occasional reordering:
+/// every eighth segment arrives three segments late
+fn reassembly_order(count: u64) -> Vec<u64> {
+    let mut order: Vec<u64> = (0..count).collect();
+    for i in (0..count as usize).step_by(8) {
+        let late = usize::min(i + 3, order.len() - 1);
+        order[i..=late].rotate_left(1);
+    }
+    order
+}
+
+fn insert_backing<S: RangeStorage>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    backing: &str,
+    count: u64,
+) {
+    let ascending: Vec<u64> = (0..count).map(|i| i * 20).collect();
+    let descending: Vec<u64> = ascending.iter().rev().copied().collect();
+    let shuffled = shuffled_ranges(count);
+    for (name, starts) in [
+        ("ascending", &ascending),
+        ("descending", &descending),
+        ("shuffled", &shuffled),
+    ] {
+        let id = BenchmarkId::new(format!("{backing}/{name}"), count);
+        group.bench_with_input(id, starts, |b, starts| {
+            b.iter(|| {
+                let mut set = RangeSet::<S>::default();
+                for &start in starts {
+                    set.insert_range(start..start + 10);
+                }
+                black_box(set)
+            })
+        });
+    }
+
+    // segments merging into one, as for stream data arriving mostly in order
+    let order = reassembly_order(count);
+    let id = BenchmarkId::new(format!("{backing}/reassembly"), count);
+    group.bench_with_input(id, &order, |b, order| {
+        b.iter(|| {
+            let mut set = RangeSet::<S>::default();
+            for &i in order {
+                set.insert_range(i * 1200..i * 1200 + 1200);
+            }
+            black_box(set)
+        })
+    });
+}
+
+/// insert disjoint ranges in ascending, descending and shuffled order
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    for count in COUNTS {
+        group.throughput(Throughput::Elements(count));
+        insert_backing::<BTreeRanges>(&mut group, "btree", count);
+        insert_backing::<VecRanges>(&mut group, "vec", count);
+    }
+    group.finish();
+}
+
+fn remove_backing<S: RangeStorage + Clone>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    backing: &str,
+    count: u64,
+) {
+    let full = disjoint::<S>(count);
+
+    // advancing from the front, as for acknowledged data
+    let id = BenchmarkId::new(format!("{backing}/front"), count);
+    group.bench_with_input(id, &count, |b, &count| {
+        b.iter_batched(
+            || full.clone(),
+            |mut set| {
+                for i in 0..count {
+                    set.remove_range(..i * 20 + 10);
+                }
+                black_box(set)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // punching holes splitting every range
+    let id = BenchmarkId::new(format!("{backing}/split"), count);
+    group.bench_with_input(id, &count, |b, &count| {
+        b.iter_batched(
+            || full.clone(),
+            |mut set| {
+                for i in 0..count {
+                    set.remove_range(i * 20 + 4..i * 20 + 6);
+                }
+                black_box(set)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+/// remove ranges from a set of disjoint ranges
+fn remove(c: &mut Criterion) {
+    let mut group = c.benchmark_group("remove");
+    for count in COUNTS {
+        group.throughput(Throughput::Elements(count));
+        remove_backing::<BTreeRanges>(&mut group, "btree", count);
+        remove_backing::<VecRanges>(&mut group, "vec", count);
+    }
+    group.finish();
+}
+
+fn query_backing<S: RangeStorage>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    backing: &str,
+    count: u64,
+) {
+    let set = disjoint::<S>(count);
+    let id = BenchmarkId::new(format!("{backing}/has_range"), count);
+    group.bench_with_input(id, &count, |b, &count| {
+        b.iter(|| {
+            let mut found = 0;
+            for i in 0..count {
+                found += set.has_range(i * 20 + 2..i * 20 + 8) as u64;
+                found += set.has_value(i * 20 + 15) as u64;
+            }
+            black_box(found)
+        })
+    });
+
+    // missing ranges across the whole set, as for retransmission
+    let id = BenchmarkId::new(format!("{backing}/complement"), count);
+    group.bench_with_input(id, &count, |b, &count| {
+        b.iter(|| black_box(set.range_complement(0..count * 20).count()))
+    });
+}
+
+/// look up values, ranges and gaps in a set of disjoint ranges
+fn query(c: &mut Criterion) {
+    let mut group = c.benchmark_group("query");
+    for count in COUNTS {
+        group.throughput(Throughput::Elements(count));
+        query_backing::<BTreeRanges>(&mut group, "btree", count);
+        query_backing::<VecRanges>(&mut group, "vec", count);
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert, remove, query);
+criterion_main!(benches);
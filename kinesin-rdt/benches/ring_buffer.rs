@@ -0,0 +1,109 @@
+//! RingBuf operations, compared against VecDeque where applicable
+
+use std::collections::VecDeque;
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kinesin_rdt::common::ring_buffer::RingBuf;
+
+/// block sizes to benchmark
+const SIZES: [usize; 3] = [64, 4096, 65536];
+
+/// overwrite and read back one block in the middle of a buffer of 4 blocks
+fn range_copy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("range_copy");
+    for size in SIZES {
+        group.throughput(Throughput::Bytes(size as u64 * 2));
+        let write_buf = vec![5u8; size];
+        let mut read_buf = vec![0u8; size];
+
+        let mut buf: RingBuf<u8> = RingBuf::new();
+        buf.push_back_copy_from_slice(&vec![6u8; size * 4]);
+        group.bench_with_input(BenchmarkId::new("RingBuf", size), &size, |b, &size| {
+            b.iter(|| {
+                buf.range_mut(size..size * 2)
+                    .copy_from_slice(black_box(&write_buf));
+                buf.range(size..size * 2).copy_to_slice(&mut read_buf);
+                black_box(&mut read_buf);
+            })
+        });
+
+        let mut deque: VecDeque<u8> = VecDeque::new();
+        deque.extend(vec![6u8; size * 4]);
+        group.bench_with_input(BenchmarkId::new("VecDeque", size), &size, |b, &size| {
+            b.iter(|| {
+                for (v, w) in deque.range_mut(size..size * 2).zip(black_box(&write_buf)) {
+                    *v = *w;
+                }
+                for (r, v) in read_buf.iter_mut().zip(deque.range(size..size * 2)) {
+                    *r = *v;
+                }
+                black_box(&mut read_buf);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// push a block to the back and pop it from the front, wrapping around
+fn push_pop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("push_pop");
+    for size in SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        let data = vec![5u8; size];
+        let mut out = vec![0u8; size];
+
+        // offset head so blocks wrap around the end of the buffer
+        let mut buf: RingBuf<u8> = RingBuf::with_capacity(size * 2);
+        buf.push_back_copy_from_slice(&vec![0u8; size / 2]);
+        group.bench_with_input(BenchmarkId::new("RingBuf", size), &size, |b, _| {
+            b.iter(|| {
+                buf.push_back_copy_from_slice(black_box(&data));
+                buf.pop_front_copy_to_slice(&mut out);
+                black_box(&mut out);
+            })
+        });
+
+        let mut deque: VecDeque<u8> = VecDeque::with_capacity(size * 2);
+        deque.extend(vec![0u8; size / 2]);
+        group.bench_with_input(BenchmarkId::new("VecDeque", size), &size, |b, _| {
+            b.iter(|| {
+                deque.extend(black_box(&data));
+                for (o, v) in out.iter_mut().zip(deque.drain(..data.len())) {
+                    *o = v;
+                }
+                black_box(&mut out);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// fill an empty buffer with a value
+fn fill(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fill");
+    for size in SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        let mut buf: RingBuf<u8> = RingBuf::new();
+        group.bench_with_input(BenchmarkId::new("RingBuf", size), &size, |b, &size| {
+            b.iter(|| {
+                buf.fill_at_back(size, 0);
+                black_box(&mut buf);
+                buf.clear();
+            })
+        });
+
+        let mut deque: VecDeque<u8> = VecDeque::new();
+        group.bench_with_input(BenchmarkId::new("VecDeque", size), &size, |b, &size| {
+            b.iter(|| {
+                deque.resize(size, 0);
+                black_box(&mut deque);
+                deque.clear();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, range_copy, push_pop, fill);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+//! StreamInboundState receive paths
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kinesin_rdt::stream::inbound::StreamInboundState;
+
+/// segments received per iteration
+const SEGMENTS: usize = 64;
+/// segment sizes to benchmark
+const SEGMENT_SIZES: [usize; 3] = [64, 1200, 16384];
+
+/// receive segments at the given indices, then read everything received
+fn receive_all(stream: &mut StreamInboundState, order: &[usize], data: &[u8]) {
+    let size = data.len();
+    for &i in order {
+        let result = stream.receive_segment((i * size) as u64, data);
+        black_box(result);
+    }
+    while let Some(slice) = stream.read_next(usize::MAX) {
+        let new_base = stream.buffer_offset + slice.len() as u64;
+        stream.advance_buffer(new_base);
+    }
+}
+
+fn receive(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stream_receive");
+    let in_order: Vec<usize> = (0..SEGMENTS).collect();
+    let reversed: Vec<usize> = (0..SEGMENTS).rev().collect();
+    // every other segment first, then the gaps
+    let interleaved: Vec<usize> = (0..SEGMENTS)
+        .step_by(2)
+        .chain((1..SEGMENTS).step_by(2))
+        .collect();
+    let duplicated: Vec<usize> = (0..SEGMENTS).flat_map(|i| [i, i]).collect();
+
+    for size in SEGMENT_SIZES {
+        let data = vec![7u8; size];
+        let window = (SEGMENTS * size) as u64;
+        group.throughput(Throughput::Bytes(window));
+        for (name, order) in [
+            ("in_order", &in_order),
+            ("reversed", &reversed),
+            ("interleaved", &interleaved),
+            ("duplicated", &duplicated),
+        ] {
+            group.bench_with_input(BenchmarkId::new(name, size), order, |b, order| {
+                b.iter_batched(
+                    || StreamInboundState::new(window, true),
+                    |mut stream| {
+                        receive_all(&mut stream, order, &data);
+                        stream
+                    },
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, receive);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+//! varint8 encoding and decoding at each encoded size
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kinesin_rdt::frame::encoding::{read_varint8_checked, varint8_size, write_varint8};
+
+/// values encoded by each benchmark
+const BATCH: usize = 1024;
+
+/// sample value for each encoded size
+const VALUES: [u64; 5] = [37, 9_000, 57_829_138, 3_933_194_752_826_327_366, u64::MAX];
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("varint8_encode");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    for value in VALUES {
+        let size = varint8_size(value);
+        let mut buf = vec![0u8; size * BATCH];
+        group.bench_with_input(BenchmarkId::from_parameter(size), &value, |b, &value| {
+            b.iter(|| {
+                let mut index = 0;
+                for _ in 0..BATCH {
+                    index += write_varint8(&mut buf[index..], black_box(value));
+                }
+                black_box(&mut buf);
+            })
+        });
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("varint8_decode");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    for value in VALUES {
+        let size = varint8_size(value);
+        let mut buf = vec![0u8; size * BATCH];
+        let mut index = 0;
+        for _ in 0..BATCH {
+            index += write_varint8(&mut buf[index..], value);
+        }
+        group.bench_with_input(BenchmarkId::from_parameter(size), &buf, |b, buf| {
+            b.iter(|| {
+                let mut index = 0;
+                let mut sum = 0u64;
+                while index < buf.len() {
+                    let (value, len) = read_varint8_checked(&buf[index..]).unwrap();
+                    sum = sum.wrapping_add(value);
+                    index += len;
+                }
+                black_box(sum)
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -0,0 +1,145 @@
+//! Shared pool of stream buffers
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+/// default size of pooled buffers
+pub const BUFFER_POOL_DEFAULT_CHUNK_SIZE: usize = 16 << 10; // 16 KB
+/// default max number of free buffers retained
+pub const BUFFER_POOL_DEFAULT_MAX_FREE: usize = 1024;
+
+/// pool of reusable byte buffers
+///
+/// Buffers are handed out with at least `chunk_size` capacity. Returned
+/// buffers are kept on a freelist if their capacity is between `chunk_size`
+/// and twice `chunk_size` (i.e. they grew at most once) and the freelist is
+/// not full, otherwise they are freed. Requests larger than `chunk_size` and
+/// requests while the freelist is empty fall back to the global allocator.
+#[derive(Debug)]
+pub struct BufferPool {
+    /// capacity of pooled buffers
+    pub chunk_size: usize,
+    /// max number of buffers on the freelist
+    pub max_free: usize,
+    /// free buffers, always empty
+    free: Mutex<Vec<Vec<u8>>>,
+    /// buffers allocated from the global allocator
+    allocated: AtomicU64,
+    /// buffers taken from the freelist
+    reused: AtomicU64,
+    /// buffers put back on the freelist
+    returned: AtomicU64,
+    /// buffers given back but freed
+    discarded: AtomicU64,
+}
+
+/// snapshot of buffer pool counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolMetrics {
+    /// buffers allocated from the global allocator
+    pub allocated: u64,
+    /// buffers taken from the freelist
+    pub reused: u64,
+    /// buffers put back on the freelist
+    pub returned: u64,
+    /// buffers given back but freed
+    pub discarded: u64,
+    /// buffers currently on the freelist
+    pub free: usize,
+}
+
+impl BufferPool {
+    /// create new instance
+    pub fn new(chunk_size: usize, max_free: usize) -> BufferPool {
+        assert!(chunk_size > 0, "chunk size cannot be zero");
+        BufferPool {
+            chunk_size,
+            max_free,
+            free: Mutex::new(Vec::new()),
+            allocated: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            returned: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        }
+    }
+
+    /// take empty buffer with capacity of at least `min_capacity`
+    pub fn take(&self, min_capacity: usize) -> Vec<u8> {
+        if min_capacity <= self.chunk_size {
+            if let Some(buf) = self.free.lock().pop() {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                return buf;
+            }
+        }
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+        Vec::with_capacity(usize::max(min_capacity, self.chunk_size))
+    }
+
+    /// give buffer back to the pool
+    pub fn give(&self, mut buf: Vec<u8>) {
+        let capacity = buf.capacity();
+        if capacity >= self.chunk_size && capacity <= self.chunk_size * 2 {
+            let mut free = self.free.lock();
+            if free.len() < self.max_free {
+                buf.clear();
+                free.push(buf);
+                self.returned.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        }
+        self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// get current counters
+    pub fn metrics(&self) -> BufferPoolMetrics {
+        BufferPoolMetrics {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            returned: self.returned.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            free: self.free.lock().len(),
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(BUFFER_POOL_DEFAULT_CHUNK_SIZE, BUFFER_POOL_DEFAULT_MAX_FREE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BufferPool, BufferPoolMetrics};
+
+    #[test]
+    fn take_and_give() {
+        let pool = BufferPool::new(64, 1);
+        let a = pool.take(16);
+        assert!(a.capacity() >= 64);
+        let b = pool.take(16);
+        let large = pool.take(1024);
+        pool.give(a);
+        // freelist full
+        pool.give(b);
+        // too large to retain
+        pool.give(large);
+
+        let mut c = pool.take(64);
+        assert!(c.is_empty() && c.capacity() >= 64);
+        c.push(1);
+        pool.give(c);
+        assert_eq!(pool.take(8).len(), 0);
+        assert_eq!(
+            pool.metrics(),
+            BufferPoolMetrics {
+                allocated: 3,
+                reused: 2,
+                returned: 2,
+                discarded: 2,
+                free: 0,
+            }
+        );
+    }
+}
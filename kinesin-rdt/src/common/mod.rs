@@ -1,5 +1,7 @@
+pub mod buffer_pool;
 pub mod messaging;
 pub mod range_set;
 pub mod ring_buffer;
+pub mod timer;
 #[cfg(test)]
 pub mod test_util;
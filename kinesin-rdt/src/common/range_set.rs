@@ -3,36 +3,179 @@
 use std::collections::BTreeMap;
 use std::ops::{Bound, Range, RangeBounds};
 
+/// Backing storage of a RangeSet, holding disjoint ranges as (start, length)
+/// ordered by start.
+pub trait RangeStorage: Default {
+    /// Number of ranges stored.
+    fn len(&self) -> usize;
+
+    /// Test if no ranges are stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Last range starting at or before `value`.
+    fn last_at_or_before(&self, value: u64) -> Option<(u64, u64)>;
+
+    /// First range.
+    fn first(&self) -> Option<(u64, u64)>;
+
+    /// Last range.
+    fn last(&self) -> Option<(u64, u64)>;
+
+    /// Iterate ranges starting within `starts`, in order.
+    fn range(
+        &self,
+        starts: impl RangeBounds<u64>,
+    ) -> impl DoubleEndedIterator<Item = (u64, u64)> + '_;
+
+    /// Replace all ranges starting within `starts` with `ranges`. The new
+    /// ranges must be ordered and fit between the ranges remaining.
+    fn replace(&mut self, starts: impl RangeBounds<u64>, ranges: &[(u64, u64)]);
+}
+
+/// RangeSet backing with a BTreeMap, where key = start and value = length.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct BTreeRanges(BTreeMap<u64, u64>);
+
+impl RangeStorage for BTreeRanges {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn last_at_or_before(&self, value: u64) -> Option<(u64, u64)> {
+        self.0
+            .range(..=value)
+            .next_back()
+            .map(|(&start, &len)| (start, len))
+    }
+
+    fn first(&self) -> Option<(u64, u64)> {
+        self.0.first_key_value().map(|(&start, &len)| (start, len))
+    }
+
+    fn last(&self) -> Option<(u64, u64)> {
+        self.0.last_key_value().map(|(&start, &len)| (start, len))
+    }
+
+    fn range(
+        &self,
+        starts: impl RangeBounds<u64>,
+    ) -> impl DoubleEndedIterator<Item = (u64, u64)> + '_ {
+        self.0.range(starts).map(|(&start, &len)| (start, len))
+    }
+
+    fn replace(&mut self, starts: impl RangeBounds<u64>, ranges: &[(u64, u64)]) {
+        let to_remove: Vec<u64> = self.0.range(starts).map(|(&start, _)| start).collect();
+        for start in to_remove {
+            self.0.remove(&start);
+        }
+        self.0.extend(ranges.iter().copied());
+    }
+}
+
+/// RangeSet backing with a sorted Vec searched by binary search. Cheaper than
+/// BTreeRanges for the small sets and mostly in-order insertions typical of
+/// stream reassembly, but changes in the middle of large sets are linear.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct VecRanges(Vec<(u64, u64)>);
+
+impl VecRanges {
+    /// Indices of ranges starting within `starts`.
+    fn indices(&self, starts: impl RangeBounds<u64>) -> Range<usize> {
+        let lower = match starts.start_bound() {
+            Bound::Included(&s) => self.0.partition_point(|&(start, _)| start < s),
+            Bound::Excluded(&s) => self.0.partition_point(|&(start, _)| start <= s),
+            Bound::Unbounded => 0,
+        };
+        let upper = match starts.end_bound() {
+            Bound::Included(&e) => self.0.partition_point(|&(start, _)| start <= e),
+            Bound::Excluded(&e) => self.0.partition_point(|&(start, _)| start < e),
+            Bound::Unbounded => self.0.len(),
+        };
+        lower..usize::max(lower, upper)
+    }
+}
+
+impl RangeStorage for VecRanges {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn last_at_or_before(&self, value: u64) -> Option<(u64, u64)> {
+        let index = self.0.partition_point(|&(start, _)| start <= value);
+        index.checked_sub(1).map(|i| self.0[i])
+    }
+
+    fn first(&self) -> Option<(u64, u64)> {
+        self.0.first().copied()
+    }
+
+    fn last(&self) -> Option<(u64, u64)> {
+        self.0.last().copied()
+    }
+
+    fn range(
+        &self,
+        starts: impl RangeBounds<u64>,
+    ) -> impl DoubleEndedIterator<Item = (u64, u64)> + '_ {
+        self.0[self.indices(starts)].iter().copied()
+    }
+
+    fn replace(&mut self, starts: impl RangeBounds<u64>, ranges: &[(u64, u64)]) {
+        let indices = self.indices(starts);
+        self.0.splice(indices, ranges.iter().copied());
+    }
+}
+
 // TODO: this is apparently massively horrible, ditch ranges and use start/len
 // directly to clean up the mess
-/// Set of ranges implemented with a BTreeMap. No overlapping ranges are
-/// allowed. Consecutive ranges are merged. Representable ranges are
-/// [0, u64::MAX).
-pub struct RangeSet {
-    /// Backing map, where key = start and value = length.
-    map: BTreeMap<u64, u64>,
+/// Set of ranges. No overlapping ranges are allowed. Consecutive ranges are
+/// merged. Representable ranges are [0, u64::MAX). Backed by a BTreeMap by
+/// default; other backings can be chosen with the type parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RangeSet<S = BTreeRanges> {
+    /// Backing storage of ranges.
+    map: S,
     max_size: usize,
 }
 
 impl RangeSet {
     pub fn new(max_size: usize) -> RangeSet {
-        RangeSet {
-            map: BTreeMap::new(),
-            max_size,
-        }
+        Self::with_backing(max_size)
     }
 
     pub fn unlimited() -> RangeSet {
         Self::new(usize::MAX)
     }
+}
+
+impl<S: RangeStorage> Default for RangeSet<S> {
+    fn default() -> Self {
+        Self::with_backing(usize::MAX)
+    }
+}
+
+impl<S: RangeStorage> RangeSet<S> {
+    /// Create set with the backing given by the type parameter.
+    pub fn with_backing(max_size: usize) -> RangeSet<S> {
+        RangeSet {
+            map: S::default(),
+            max_size,
+        }
+    }
 
     /// Test if a single value is contained in the set.
     pub fn has_value(&self, val: u64) -> bool {
         // ------ [ start ------------------ start + len ] ----
         //                              ^ val
         // search backwards
-        let mut range_iter = self.map.range(..=val);
-        if let Some((&start, &len)) = range_iter.next_back() {
+        if let Some((start, len)) = self.map.last_at_or_before(val) {
             start + len > val
         } else {
             false
@@ -43,89 +186,57 @@ impl RangeSet {
     pub fn has_range(&self, range: Range<u64>) -> bool {
         // ------ [ start ------------------ start + len ] ----
         // ------------ [ range ---------------------- ] ------
-        let mut range_iter = self.map.range(..=range.start);
-        if let Some((&start, &len)) = range_iter.next_back() {
+        if let Some((start, len)) = self.map.last_at_or_before(range.start) {
             start + len >= range.end
         } else {
             false
         }
     }
 
-    fn direct_insert(&mut self, new_range: Range<u64>) {
-        self.map
-            .insert(new_range.start, new_range.end - new_range.start);
-    }
-
     fn max_checked_insert(&mut self, new_range: Range<u64>) -> bool {
         if self.map.len() >= self.max_size {
             // set is full
             false
         } else {
-            self.direct_insert(new_range);
+            let start = new_range.start;
+            self.map
+                .replace(start..=start, &[(start, new_range.end - start)]);
             true
         }
     }
 
-    fn intersecting_insert(&mut self, mut new_range: Range<u64>) {
-        let range_iter = self.map.range(..=new_range.end);
-        let mut to_remove: Vec<u64> = Vec::new();
-        for (&start, &len) in range_iter.rev() {
-            let end = start + len;
-            if start > new_range.start {
-                if end > new_range.end {
-                    // intersecting or immediately following range extends
-                    // past end of new range
-                    new_range.end = end;
-                } else {
-                    // intersecting range entirely contained with in new range
-                }
-                to_remove.push(start);
-            } else if end < new_range.start {
-                // new range is entirely after current range (no intersection)
-                // no more ranges to search
-                break;
-            } else if end < new_range.end {
-                // intersecting range or immediately preceding range extends
-                // past start of new range
-                new_range.start = start;
-                to_remove.push(start);
-            } else {
-                // new range is entirely contained within existing range
-                // Initial should've handled this
-                unreachable!();
-            }
-        }
-        for s in to_remove {
-            self.map.remove(&s);
-        }
-
-        self.direct_insert(new_range);
-    }
-
     /// Insert a range into the set
     pub fn insert_range(&mut self, new_range: Range<u64>) -> bool {
         if new_range.start == new_range.end {
             panic!("cannot insert zero-length range");
         }
-        let mut range_iter = self.map.range(..=new_range.end);
-        if let Some((&start, &len)) = range_iter.next_back() {
+        let mut merged = new_range.clone();
+        if let Some((start, len)) = self.map.last_at_or_before(new_range.start) {
             let end = start + len;
-            if start <= new_range.start && end >= new_range.end {
+            if end >= new_range.end {
                 // range already covered in set
-                true
-            } else if end < new_range.start {
-                // new range is after all existing ranges
-                self.max_checked_insert(new_range)
-            } else {
-                // new range intersects or is adjacent to an existing range
-                self.intersecting_insert(new_range);
-                true
+                return true;
+            } else if end >= new_range.start {
+                // intersecting or immediately preceding range extends past
+                // start of new range
+                merged.start = start;
             }
-        } else {
-            // new range is before all existing ranges (or no ranges exist),
-            // insert new range after capacity check
-            self.max_checked_insert(new_range)
         }
+        if let Some((start, len)) = self.map.last_at_or_before(new_range.end) {
+            // intersecting or immediately following range may extend past
+            // end of new range
+            merged.end = u64::max(merged.end, start + len);
+        }
+
+        let starts = merged.start..=new_range.end;
+        if self.map.range(starts.clone()).next().is_none() {
+            // no intersecting or adjacent ranges, insert new range after
+            // capacity check
+            return self.max_checked_insert(new_range);
+        }
+        self.map
+            .replace(starts, &[(merged.start, merged.end - merged.start)]);
+        true
     }
 
     /// Convert RangeBounds to ordinary range
@@ -144,101 +255,46 @@ impl RangeSet {
         lower_bound..upper_bound
     }
 
-    /// Remove range from set
+    /// Remove range from set, returning the number of ranges affected
     pub fn remove_range(&mut self, to_remove: impl RangeBounds<u64> + Clone) -> usize {
         let Range {
             start: lower_bound,
             end: upper_bound,
-        } = Self::materialize_bounds(to_remove.clone());
+        } = Self::materialize_bounds(to_remove);
 
         if lower_bound == upper_bound {
             panic!("cannot remove zero-length range");
         }
 
-        // fast path: Unbounded can use split_off
-        if to_remove.start_bound() == Bound::Unbounded {
-            if to_remove.end_bound() == Bound::Unbounded {
-                let affected = self.map.len();
-                self.map.clear();
-                return affected;
-            } else {
-                // split off everything after the upper bound, then drop old map
-                let mut after = self.map.split_off(&upper_bound);
-                if let Some((&start, &len)) = self.map.last_key_value() {
-                    if start + len > upper_bound {
-                        // range extends over split point, add it back into the new map
-                        let new_len = len - (upper_bound - start);
-                        after.insert(upper_bound, new_len);
-                    }
-                }
-                let affected = self.map.len();
-                self.map = after;
-                return affected;
-            }
-        } else if to_remove.end_bound() == Bound::Unbounded {
-            // split off everything after the lower bound
-            self.map.split_off(&lower_bound);
-            if let Some((&start, &len)) = self.map.last_key_value() {
-                if start + len > lower_bound {
-                    // highest range extends over split point, trim it back
-                    self.map.insert(start, lower_bound - start);
-                }
+        // parts of affected ranges outside of to_remove
+        let mut remaining = Vec::with_capacity(2);
+        let mut first_start = lower_bound;
+        if let Some((start, len)) = self.map.last_at_or_before(lower_bound) {
+            if start < lower_bound && start + len > lower_bound {
+                // range extends into to_remove, trim end of range
+                first_start = start;
+                remaining.push((start, lower_bound - start));
             }
         }
-
-        let mut affected = 0;
-        let range_iter = self.map.range(..upper_bound);
-        let mut pending_ops: Vec<(u64, Option<u64>)> = Vec::new();
-
-        for (&start, &len) in range_iter.rev() {
+        if let Some((start, len)) = self.map.last_at_or_before(upper_bound - 1) {
             let end = start + len;
-            if end <= lower_bound {
-                // no more ranges could possibly match
-                break;
-            } else if end <= upper_bound {
-                if start >= lower_bound {
-                    // range is entirely contained within to_remove
-                    pending_ops.push((start, None));
-                    affected += 1;
-                } else {
-                    // range extends into to_remove
-                    pending_ops.push((start, Some(lower_bound - start)));
-                    affected += 1;
-                    break;
-                }
-            } else if end > upper_bound {
-                if start < lower_bound {
-                    // current range includes to_remove, split range
-                    pending_ops.push((start, Some(lower_bound - start)));
-                    pending_ops.push((upper_bound, Some(end - upper_bound)));
-                    affected += 1;
-                    break;
-                } else {
-                    // current range starts within and extends past end of to_remove,
-                    // trim start of range
-                    // delete old range
-                    pending_ops.push((start, None));
-                    // insert trimmed range
-                    pending_ops.push((upper_bound, Some(end - upper_bound)));
-                    affected += 1;
-                }
-            } else {
-                unreachable!();
+            if end > upper_bound {
+                // range extends past end of to_remove, trim start of range
+                remaining.push((upper_bound, end - upper_bound));
             }
         }
-        for (start, maybe_len) in pending_ops {
-            if let Some(len) = maybe_len {
-                self.map.insert(start, len);
-            } else {
-                self.map.remove(&start);
-            }
+
+        let starts = first_start..upper_bound;
+        let affected = self.map.range(starts.clone()).count();
+        if affected > 0 {
+            self.map.replace(starts, &remaining);
         }
         affected
     }
 
     /// Iterate all ranges contained in set
     pub fn iter(&self) -> impl Iterator<Item = Range<u64>> + '_ {
-        self.map.iter().map(|(key, value)| *key..(key + value))
+        self.map.range(..).map(|(start, len)| start..(start + len))
     }
 
     /// Iterate all ranges in set intersecting provided range
@@ -250,28 +306,22 @@ impl RangeSet {
             start: requested_start,
             end,
         } = Self::materialize_bounds(range);
-        let start = if requested_start == 0 {
-            0
-        } else {
-            let mut back_search = self.map.range(..=requested_start);
-            if let Some((&prev_start, &len)) = back_search.next_back() {
-                if prev_start + len > requested_start {
-                    // previous range extends into requested
-                    prev_start
-                } else {
-                    requested_start
-                }
-            } else {
-                requested_start
-            }
+        let start = match self.map.last_at_or_before(requested_start) {
+            // previous range extends into requested
+            Some((prev_start, len)) if prev_start + len > requested_start => prev_start,
+            _ => requested_start,
         };
         self.map
             .range(start..end)
-            .map(|(key, value)| *key..(key + value))
+            .map(|(start, len)| start..(start + len))
     }
 
     /// Find all ranges within provided range but which do not exist in the set
-    pub fn range_complement(&self, range: Range<u64>) -> impl Iterator<Item = Range<u64>> + '_ {
+    pub fn range_complement(
+        &self,
+        range: impl RangeBounds<u64>,
+    ) -> impl Iterator<Item = Range<u64>> + '_ {
+        let range = Self::materialize_bounds(range);
         ComplementIterator {
             range: range.clone(),
             prev_end: range.start,
@@ -280,18 +330,78 @@ impl RangeSet {
         }
     }
 
+    /// Number of disjoint ranges in set
+    pub fn count(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Test if set contains no ranges
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Total length of all ranges in set
+    pub fn total_len(&self) -> u64 {
+        self.map.range(..).map(|(_, len)| len).sum()
+    }
+
+    /// Total length of the parts of `range` contained in set
+    pub fn covered_len(&self, range: impl RangeBounds<u64>) -> u64 {
+        let range = Self::materialize_bounds(range);
+        self.iter_range(range.clone())
+            .map(|r| u64::min(r.end, range.end).saturating_sub(u64::max(r.start, range.start)))
+            .sum()
+    }
+
+    /// Total length of the parts of `range` not contained in set
+    pub fn complement_len(&self, range: impl RangeBounds<u64>) -> u64 {
+        let range = Self::materialize_bounds(range);
+        (range.end - range.start) - self.covered_len(range)
+    }
+
+    /// Find the lowest value at or after `offset` not contained in the set
+    pub fn first_missing_after(&self, offset: u64) -> u64 {
+        match self.map.last_at_or_before(offset) {
+            // adjacent ranges are merged, so the end is always missing
+            Some((start, len)) if start + len > offset => start + len,
+            _ => offset,
+        }
+    }
+
+    /// Insert all ranges of another set. Returns false if some ranges could
+    /// not be inserted due to the size limit.
+    pub fn union<T: RangeStorage>(&mut self, other: &RangeSet<T>) -> bool {
+        let mut ok = true;
+        for range in other.iter() {
+            ok &= self.insert_range(range);
+        }
+        ok
+    }
+
+    /// Create new set containing ranges present in both sets, with the size
+    /// limit and backing of this set
+    pub fn intersection<T: RangeStorage>(&self, other: &RangeSet<T>) -> RangeSet<S> {
+        let mut out = RangeSet::with_backing(self.max_size);
+        for range in self.iter() {
+            for other_range in other.iter_range(range.clone()) {
+                let start = u64::max(range.start, other_range.start);
+                let end = u64::min(range.end, other_range.end);
+                if start < end && !out.max_checked_insert(start..end) {
+                    return out;
+                }
+            }
+        }
+        out
+    }
+
     /// Peek first value in set
     pub fn peek_first(&self) -> Option<Range<u64>> {
-        self.map
-            .first_key_value()
-            .map(|(&start, &len)| start..(start + len))
+        self.map.first().map(|(start, len)| start..(start + len))
     }
 
     /// Peek last value in set
     pub fn peek_last(&self) -> Option<Range<u64>> {
-        self.map
-            .last_key_value()
-            .map(|(&start, &len)| start..(start + len))
+        self.map.last().map(|(start, len)| start..(start + len))
     }
 
     /// Dump all ranges in set
@@ -343,15 +453,17 @@ impl<T: Iterator<Item = Range<u64>>> Iterator for ComplementIterator<T> {
 mod test {
     use std::ops::Range;
 
-    use super::RangeSet;
+    use proptest::prelude::*;
+
+    use super::{RangeSet, RangeStorage, VecRanges};
 
-    fn ensure_consistency(rs: &RangeSet) {
+    fn ensure_consistency<S: RangeStorage>(rs: &RangeSet<S>) {
         assert!(!rs.map.is_empty());
-        let mut iter = rs.map.iter();
+        let mut iter = rs.map.range(..);
         let first_el = iter.next().unwrap();
         let mut last_end = first_el.0 + first_el.1;
 
-        for (&start, &len) in iter {
+        for (start, len) in iter {
             assert!(start > last_end);
             assert!(len > 0);
             let did_overflow;
@@ -562,4 +674,135 @@ mod test {
             vec![6..10]
         );
     }
+
+    #[test]
+    fn set_operations() {
+        let mut a = RangeSet::unlimited();
+        a.insert_range(0..10);
+        a.insert_range(20..30);
+        let mut b = RangeSet::unlimited();
+        b.insert_range(5..25);
+        b.insert_range(28..40);
+        assert_eq!(a.count(), 2);
+        assert_eq!(a.total_len(), 20);
+
+        let both = a.intersection(&b);
+        assert_eq!(
+            both.iter().collect::<Vec<Range<u64>>>(),
+            vec![5..10, 20..25, 28..30]
+        );
+        assert_eq!(both.total_len(), 12);
+        ensure_consistency(&both);
+
+        assert_eq!(a.covered_len(5..25), 10);
+        assert_eq!(a.complement_len(5..25), 10);
+        assert_eq!(a.covered_len(..), 20);
+        assert_eq!(a.first_missing_after(0), 10);
+        assert_eq!(a.first_missing_after(10), 10);
+        assert_eq!(a.first_missing_after(22), 30);
+        assert_eq!(a.first_missing_after(35), 35);
+
+        assert!(a.union(&b));
+        assert_eq!(a.iter().collect::<Vec<Range<u64>>>(), vec![0..40]);
+        assert!(a.intersection(&RangeSet::unlimited()).is_empty());
+        assert_eq!(a.range_complement(30..).next(), Some(40..u64::MAX));
+    }
+
+    #[test]
+    fn coverage() {
+        let empty = RangeSet::unlimited();
+        assert_eq!(empty.covered_len(..), 0);
+        assert_eq!(empty.complement_len(5..25), 20);
+        assert_eq!(empty.complement_len(..), u64::MAX);
+        assert_eq!(empty.first_missing_after(0), 0);
+        assert_eq!(empty.first_missing_after(u64::MAX), u64::MAX);
+
+        let mut rs = RangeSet::unlimited();
+        rs.insert_range(10..20);
+        rs.insert_range(30..40);
+        // partial overlap on both ends
+        assert_eq!(rs.covered_len(15..35), 10);
+        assert_eq!(rs.complement_len(15..35), 10);
+        assert_eq!(rs.covered_len(0..10), 0);
+        assert_eq!(rs.covered_len(20..30), 0);
+        assert_eq!(rs.complement_len(12..18), 0);
+        assert_eq!(rs.covered_len(25..25), 0);
+        assert_eq!(rs.first_missing_after(9), 9);
+        assert_eq!(rs.first_missing_after(19), 20);
+        assert_eq!(rs.first_missing_after(20), 20);
+
+        // adjacent ranges merge, so the first missing value skips both
+        rs.insert_range(20..30);
+        assert_eq!(rs.covered_len(15..35), 20);
+        assert_eq!(rs.first_missing_after(10), 40);
+
+        // ranges ending at u64::MAX
+        rs.insert_range(u64::MAX - 10..u64::MAX);
+        assert_eq!(rs.covered_len(u64::MAX - 20..), 10);
+        assert_eq!(rs.complement_len(u64::MAX - 20..), 10);
+        assert_eq!(rs.covered_len(..), 40);
+        assert_eq!(rs.complement_len(..), u64::MAX - 40);
+        assert_eq!(rs.first_missing_after(u64::MAX - 5), u64::MAX);
+        assert_eq!(rs.first_missing_after(u64::MAX), u64::MAX);
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Insert(Range<u64>),
+        Remove(Range<u64>),
+        RemoveBefore(u64),
+        RemoveAfter(u64),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        // small offsets so ranges frequently overlap and touch
+        let range = (0u64..200, 1u64..40).prop_map(|(start, len)| start..start + len);
+        prop_oneof![
+            4 => range.clone().prop_map(Op::Insert),
+            2 => range.prop_map(Op::Remove),
+            1 => (1u64..240).prop_map(Op::RemoveBefore),
+            1 => (0u64..240).prop_map(Op::RemoveAfter),
+        ]
+    }
+
+    proptest! {
+        /// VecRanges backing behaves like the default backing
+        #[test]
+        fn vec_backing(ops in prop::collection::vec(op(), 1..64), max_size in 1usize..12) {
+            let mut model = RangeSet::new(max_size);
+            let mut rs: RangeSet<VecRanges> = RangeSet::with_backing(max_size);
+            for op in ops {
+                match op {
+                    Op::Insert(range) => {
+                        prop_assert_eq!(rs.insert_range(range.clone()), model.insert_range(range));
+                    }
+                    Op::Remove(range) => {
+                        prop_assert_eq!(rs.remove_range(range.clone()), model.remove_range(range));
+                    }
+                    Op::RemoveBefore(end) => {
+                        prop_assert_eq!(rs.remove_range(..end), model.remove_range(..end));
+                    }
+                    Op::RemoveAfter(start) => {
+                        prop_assert_eq!(rs.remove_range(start..), model.remove_range(start..));
+                    }
+                }
+                prop_assert_eq!(rs.iter().collect::<Vec<_>>(), model.iter().collect::<Vec<_>>());
+                if !rs.is_empty() {
+                    ensure_consistency(&rs);
+                }
+            }
+            for value in 0..250 {
+                prop_assert_eq!(rs.has_value(value), model.has_value(value));
+                prop_assert_eq!(rs.first_missing_after(value), model.first_missing_after(value));
+                prop_assert_eq!(
+                    rs.range_complement(value..value + 30).collect::<Vec<_>>(),
+                    model.range_complement(value..value + 30).collect::<Vec<_>>()
+                );
+            }
+            prop_assert_eq!(
+                rs.intersection(&model).iter().collect::<Vec<_>>(),
+                model.intersection(&rs).iter().collect::<Vec<_>>()
+            );
+        }
+    }
 }
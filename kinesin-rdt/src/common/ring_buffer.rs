@@ -1,9 +1,11 @@
 // TODO: fix documentation
 #![allow(clippy::missing_safety_doc)]
 
+use std::fmt;
+use std::io::IoSlice;
 use std::marker::PhantomData;
 use std::mem::{size_of, MaybeUninit};
-use std::ops::{Bound, Range, RangeBounds};
+use std::ops::{Bound, Index, IndexMut, Range, RangeBounds};
 use std::{ptr, slice};
 
 /// ring buffer supporting batch copy in/out
@@ -48,6 +50,28 @@ pub struct Drain<'a, T> {
     // op_type: DrainType,
 }
 
+/// immutable iterator over elements of a RingBuf
+#[derive(Clone)]
+pub struct Iter<'a, T> {
+    /// elements before wraparound
+    a: slice::Iter<'a, T>,
+    /// elements after wraparound
+    b: slice::Iter<'a, T>,
+}
+
+/// mutable iterator over elements of a RingBuf
+pub struct IterMut<'a, T> {
+    /// elements before wraparound
+    a: slice::IterMut<'a, T>,
+    /// elements after wraparound
+    b: slice::IterMut<'a, T>,
+}
+
+/// owning iterator over elements of a RingBuf
+pub struct IntoIter<T> {
+    buf: RingBuf<T>,
+}
+
 impl<T> RingBuf<T> {
     /// ensure T is not something strange
     const fn ensure_type_ok() {
@@ -78,6 +102,31 @@ impl<T> RingBuf<T> {
         }
     }
 
+    /// create new empty buffer using the allocation of an existing Vec
+    ///
+    /// Existing elements of the Vec are dropped.
+    #[allow(clippy::uninit_vec)] // does not allow access to uninitialized regions
+    pub fn from_storage(mut vec: Vec<T>) -> RingBuf<T> {
+        Self::ensure_type_ok();
+        vec.clear();
+        // safety: uninitialized bytes are not leaked
+        unsafe { vec.set_len(vec.capacity()) };
+        RingBuf {
+            buf: vec,
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// drop all elements and return the backing allocation as an empty Vec
+    pub fn into_storage(mut self) -> Vec<T> {
+        self.clear();
+        let mut vec = std::mem::take(&mut self.buf);
+        // safety: all elements were dropped above
+        unsafe { vec.set_len(0) };
+        vec
+    }
+
     /// max capacity before reallocating
     pub fn capacity(&self) -> usize {
         self.buf.len()
@@ -143,7 +192,9 @@ impl<T> RingBuf<T> {
     /// get offset into backing buffer from element index and explicit head index
     fn offset_of_explicit(&self, head: usize, index: usize) -> usize {
         // disclaimer: the math worked. outside of that, i have no idea what this does
-        debug_assert!(index < self.capacity(), "index cannot exceed capacity");
+        // index == capacity maps back to head, used when moving head by a
+        // full buffer
+        debug_assert!(index <= self.capacity(), "index cannot exceed capacity");
         let remaining = self.capacity() - index;
         if head < remaining {
             // does not wrap
@@ -158,7 +209,7 @@ impl<T> RingBuf<T> {
     fn offset_of_reverse(&self, negative_index: usize) -> usize {
         // disclaimer: same as above
         debug_assert!(
-            negative_index < self.capacity(),
+            negative_index <= self.capacity(),
             "index cannot exceed capacity"
         );
         if self.head >= negative_index {
@@ -326,7 +377,7 @@ impl<T> RingBuf<T> {
 
         let out;
         unsafe {
-            let target = self.ptr_at(self.offset_of(self.len));
+            let target = self.ptr_at(self.offset_of(self.len - 1));
             out = ptr::read(target);
             self.len -= 1;
         }
@@ -387,7 +438,7 @@ impl<T> RingBuf<T> {
     }
 
     /// get immutable reference to range
-    pub fn range(&self, range: Range<usize>) -> RingBufSlice<T> {
+    pub fn range(&self, range: Range<usize>) -> RingBufSlice<'_, T> {
         self.check_range(&range);
         RingBufSlice {
             buf: self,
@@ -397,7 +448,7 @@ impl<T> RingBuf<T> {
     }
 
     /// get mutable reference to range
-    pub fn range_mut(&mut self, range: Range<usize>) -> RingBufSliceMut<T> {
+    pub fn range_mut(&mut self, range: Range<usize>) -> RingBufSliceMut<'_, T> {
         self.check_range(&range);
         RingBufSliceMut {
             buf: self,
@@ -463,15 +514,15 @@ impl<T> RingBuf<T> {
     ///
     /// Currently only supports draining from either the start or the end.
     /// Drained elements are dropped when the iterator is dropped.
-    pub fn drain<R: RangeBounds<usize>>(&mut self, range: R) -> Drain<T> {
+    pub fn drain<R: RangeBounds<usize>>(&mut self, range: R) -> Drain<'_, T> {
         let lower_bound = match range.start_bound() {
             Bound::Included(&start) => {
-                assert!(start < self.len, "start index out of bounds");
+                assert!(start <= self.len, "start index out of bounds");
                 Some(start)
             }
             Bound::Excluded(&start) => {
                 let start = start.checked_add(1).expect("start index out of bounds");
-                assert!(start < self.len, "start index out of bounds");
+                assert!(start <= self.len, "start index out of bounds");
                 Some(start)
             }
             Bound::Unbounded => None,
@@ -507,6 +558,43 @@ impl<T> RingBuf<T> {
             }
         }
     }
+
+    /// shorten to `len` elements, dropping elements at the back
+    ///
+    /// Does nothing if `len` is not less than the current length.
+    pub fn truncate_back(&mut self, len: usize) {
+        if len < self.len {
+            drop(self.drain(len..));
+        }
+    }
+
+    /// shorten to `len` elements, dropping elements at the front
+    ///
+    /// Does nothing if `len` is not less than the current length.
+    pub fn truncate_front(&mut self, len: usize) {
+        if len < self.len {
+            drop(self.drain(..self.len - len));
+        }
+    }
+
+    /// iterate over elements from front to back
+    pub fn iter(&self) -> Iter<'_, T> {
+        let (a, b) = unsafe { self.range_to_slices(0..self.len) };
+        Iter {
+            a: a.iter(),
+            b: b.unwrap_or_default().iter(),
+        }
+    }
+
+    /// iterate mutably over elements from front to back
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        // safety: the two slices never overlap
+        let (a, b) = unsafe { self.range_to_slices_mut(0..self.len) };
+        IterMut {
+            a: a.iter_mut(),
+            b: b.unwrap_or_default().iter_mut(),
+        }
+    }
 }
 
 impl<T> Default for RingBuf<T> {
@@ -515,6 +603,82 @@ impl<T> Default for RingBuf<T> {
     }
 }
 
+impl<T> Extend<T> for RingBuf<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        iter.for_each(|v| self.push_back(v));
+    }
+}
+
+impl<T> FromIterator<T> for RingBuf<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut buf = RingBuf::new();
+        buf.extend(iter);
+        buf
+    }
+}
+
+impl<T> Index<usize> for RingBuf<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        self.get(index).expect("index out of bounds")
+    }
+}
+
+impl<T> IndexMut<usize> for RingBuf<T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        self.get_mut(index).expect("index out of bounds")
+    }
+}
+
+impl<T: Clone> Clone for RingBuf<T> {
+    fn clone(&self) -> Self {
+        let mut buf = RingBuf::with_capacity(self.len);
+        buf.extend(self.iter().cloned());
+        buf
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for RingBuf<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: PartialEq<U>, U> PartialEq<RingBuf<U>> for RingBuf<T> {
+    fn eq(&self, other: &RingBuf<U>) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Eq> Eq for RingBuf<T> {}
+
+impl<T: PartialEq<U>, U> PartialEq<[U]> for RingBuf<T> {
+    fn eq(&self, other: &[U]) -> bool {
+        if self.len != other.len() {
+            return false;
+        }
+        // compare slice-wise to avoid per-element index mapping
+        let (a, b) = unsafe { self.range_to_slices(0..self.len) };
+        let (other_a, other_b) = other.split_at(a.len());
+        a == other_a && b.unwrap_or_default() == other_b
+    }
+}
+
+impl<T: PartialEq<U>, U> PartialEq<&[U]> for RingBuf<T> {
+    fn eq(&self, other: &&[U]) -> bool {
+        *self == **other
+    }
+}
+
+impl<T: PartialEq<U>, U, const N: usize> PartialEq<[U; N]> for RingBuf<T> {
+    fn eq(&self, other: &[U; N]) -> bool {
+        *self == other[..]
+    }
+}
+
 impl<T: Clone> RingBuf<T> {
     /// append `count` elements at back by cloning
     pub fn fill_at_back(&mut self, count: usize, value: T) {
@@ -532,6 +696,16 @@ impl<T: Clone> RingBuf<T> {
         self.len += count;
     }
 
+    /// resize to `len` elements, either filling at back with clones of `value`
+    /// or truncating at back
+    pub fn resize(&mut self, len: usize, value: T) {
+        if len > self.len {
+            self.fill_at_back(len - self.len, value);
+        } else {
+            self.truncate_back(len);
+        }
+    }
+
     /// prepend `count` elements at front by cloning
     pub fn fill_at_front(&mut self, count: usize, value: T) {
         self.reserve(count);
@@ -715,6 +889,16 @@ impl<'a, T> RingBufSlice<'a, T> {
     }
 }
 
+impl<'a> RingBufSlice<'a, u8> {
+    /// get IoSlices representing range, for use with vectored writes
+    ///
+    /// The second slice is empty if the range does not wrap.
+    pub fn as_io_slices(&self) -> [IoSlice<'a>; 2] {
+        let (a, b) = self.as_slices();
+        [IoSlice::new(a), IoSlice::new(b.unwrap_or_default())]
+    }
+}
+
 impl<'a, T: Copy> RingBufSlice<'a, T> {
     /// copy contents of range to a slice
     pub fn copy_to_slice(&self, slice: &mut [T]) {
@@ -754,20 +938,23 @@ impl<'a, T> RingBufSliceMut<'a, T> {
     }
 
     /// get slices representing range
-    pub fn as_slices(&self) -> (&'a [T], Option<&'a [T]>) {
+    pub fn as_slices(&self) -> (&[T], Option<&[T]>) {
         unsafe { (*self.buf).range_to_slices(self.start..self.end) }
     }
 
     /// get mutable slices representing range
-    pub fn as_mut_slices(&mut self) -> (&'a mut [T], Option<&'a mut [T]>) {
+    ///
+    /// Borrows the range mutably so the slices cannot alias other references
+    /// obtained from it.
+    pub fn as_mut_slices(&mut self) -> (&mut [T], Option<&mut [T]>) {
         unsafe { (*self.buf).range_to_slices_mut(self.start..self.end) }
     }
 
     /// get sub-range into range
-    pub fn range(&self, range: Range<usize>) -> RingBufSlice<'a, T> {
+    pub fn range(&self, range: Range<usize>) -> RingBufSlice<'_, T> {
         let new_range = validate_subrange(self.start..self.end, &range);
         unsafe {
-            // safety: write operations cannot be performed on RingBufSlice
+            // safety: the range cannot be written while the RingBufSlice exists
             RingBufSlice {
                 buf: &*self.buf,
                 start: new_range.start,
@@ -777,7 +964,7 @@ impl<'a, T> RingBufSliceMut<'a, T> {
     }
 
     /// get mutable sub-range into range
-    pub fn range_mut(&mut self, range: Range<usize>) -> RingBufSliceMut<'a, T> {
+    pub fn range_mut(&mut self, range: Range<usize>) -> RingBufSliceMut<'_, T> {
         let new_range = validate_subrange(self.start..self.end, &range);
         RingBufSliceMut {
             buf: self.buf,
@@ -971,14 +1158,107 @@ impl<'a, T> DoubleEndedIterator for Drain<'a, T> {
 
 impl<'a, T> ExactSizeIterator for Drain<'a, T> {}
 
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.a.next().or_else(|| self.b.next())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.a.len() + self.b.len();
+        (len, Some(len))
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.b.next_back().or_else(|| self.a.next_back())
+    }
+}
+
+impl<'a, T> ExactSizeIterator for Iter<'a, T> {}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.a.next().or_else(|| self.b.next())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.a.len() + self.b.len();
+        (len, Some(len))
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for IterMut<'a, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.b.next_back().or_else(|| self.a.next_back())
+    }
+}
+
+impl<'a, T> ExactSizeIterator for IterMut<'a, T> {}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.pop_front()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.buf.len(), Some(self.buf.len()))
+    }
+}
+
+impl<T> DoubleEndedIterator for IntoIter<T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.buf.pop_back()
+    }
+}
+
+impl<T> ExactSizeIterator for IntoIter<T> {}
+
+impl<T> IntoIterator for RingBuf<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { buf: self }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a RingBuf<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a mut RingBuf<T> {
+    type Item = &'a mut T;
+    type IntoIter = IterMut<'a, T>;
+
+    fn into_iter(self) -> IterMut<'a, T> {
+        self.iter_mut()
+    }
+}
+
 #[cfg(test)]
 mod test {
     // DISCLAIMER: this "test suite" is in absolutely no way exhaustive and
     // should not in any way, shape, or form serve as reassurance that the
     // RingBuf implementation above is safe for anything at all
 
+    use std::collections::VecDeque;
     use std::panic::catch_unwind;
 
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
@@ -1205,4 +1485,370 @@ mod test {
         assert_eq!(buf.get(48), Some(&5));
         assert_eq!(buf.get(95), Some(&5));
     }
+
+    #[test]
+    fn iterators() {
+        let mut buf: RingBuf<u32> = RingBuf::with_capacity(8);
+        for i in 4..8 {
+            buf.push_back(i);
+        }
+        for i in (0..4).rev() {
+            buf.push_front(i);
+        }
+        assert_eq!(buf.iter().len(), 8);
+        assert!(buf.iter().copied().eq(0..8));
+        assert!(buf.iter().rev().copied().eq((0..8).rev()));
+
+        for v in &mut buf {
+            *v *= 2;
+        }
+        let mut iter = buf.iter_mut();
+        *iter.next_back().unwrap() = 100;
+        assert_eq!(iter.len(), 7);
+        let sum: u32 = (&buf).into_iter().sum();
+        assert_eq!(sum, 2 * (0..7).sum::<u32>() + 100);
+
+        let mut strings: RingBuf<String> = RingBuf::new();
+        strings.push_back("b".to_string());
+        strings.push_front("a".to_string());
+        strings.push_back("c".to_string());
+        let mut owned = strings.into_iter();
+        assert_eq!(owned.next_back().as_deref(), Some("c"));
+        assert_eq!(owned.collect::<Vec<_>>(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn std_traits() {
+        let mut buf: RingBuf<u32> = (4..8).collect();
+        buf.fill_at_front(4, 0);
+        for i in 0..4 {
+            buf[i] = i as u32;
+        }
+        buf.extend(8..10);
+        assert_eq!(buf[9], 9);
+        assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(buf, &(0..10).collect::<Vec<_>>()[..]);
+        assert_ne!(buf, [0, 1, 2]);
+
+        let cloned = buf.clone();
+        assert_eq!(cloned, buf);
+        buf[0] = 100;
+        assert_ne!(cloned, buf);
+        let strings: RingBuf<String> = ["a", "b"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(format!("{:?}", strings.clone()), r#"["a", "b"]"#);
+    }
+
+    #[test]
+    fn resize_truncate() {
+        let mut buf: RingBuf<String> = RingBuf::with_capacity(8);
+        buf.fill_at_back(4, "b".to_string());
+        buf.fill_at_front(3, "a".to_string());
+        buf.resize(9, "c".to_string());
+        assert_eq!(buf, ["a", "a", "a", "b", "b", "b", "b", "c", "c"]);
+        buf.truncate_front(7);
+        assert_eq!(buf, ["a", "b", "b", "b", "b", "c", "c"]);
+        buf.resize(3, "d".to_string());
+        assert_eq!(buf, ["a", "b", "b"]);
+        buf.truncate_back(5);
+        assert_eq!(buf.len(), 3);
+        buf.truncate_front(0);
+        assert!(buf.is_empty());
+        buf.resize(2, "e".to_string());
+        assert_eq!(buf, ["e", "e"]);
+    }
+
+    #[test]
+    fn io_slices() {
+        let mut buf: RingBuf<u8> = RingBuf::with_capacity(16);
+        buf.push_back_copy_from_slice(b"world");
+        buf.push_front_copy_from_slice(b"hello ");
+        let slices = buf.range(0..buf.len()).as_io_slices();
+        let mut out = Vec::new();
+        std::io::Write::write_vectored(&mut out, &slices).unwrap();
+        assert_eq!(out, b"hello world");
+        let [a, b] = buf.range(6..9).as_io_slices();
+        assert_eq!((&*a, &*b), (&b"wor"[..], &b""[..]));
+    }
+
+    #[test]
+    fn full_capacity() {
+        // moving head by the full capacity used to trip index assertions
+        let mut buf: RingBuf<u32> = RingBuf::with_capacity(4);
+        buf.push_front_copy_from_slice(&[0, 1, 2, 3]);
+        assert_eq!(buf, [0, 1, 2, 3]);
+        let mut dest = [0; 4];
+        buf.pop_front_copy_to_slice(&mut dest);
+        assert_eq!(dest, [0, 1, 2, 3]);
+
+        let mut buf: RingBuf<u32> = RingBuf::with_capacity(1);
+        buf.push_back(5);
+        assert_eq!(buf.pop_front(), Some(5));
+        buf.push_front(6);
+        assert_eq!(buf.drain(1..).count(), 0);
+        assert_eq!(buf, [6]);
+    }
+
+    /// operation applied to both RingBuf and VecDeque in model tests
+    ///
+    /// Counts and indices are reduced to valid values when applied.
+    #[derive(Clone, Debug)]
+    enum Op {
+        PushBack(u16),
+        PushFront(u16),
+        PopBack,
+        PopFront,
+        /// drain count from front, consuming some of the iterator
+        DrainFront(usize, usize),
+        /// drain count from back, consuming some of the iterator from the back
+        DrainBack(usize, usize),
+        TruncateBack(usize),
+        TruncateFront(usize),
+        Resize(usize, u16),
+        FillBack(usize, u16),
+        FillFront(usize, u16),
+        Reserve(usize),
+        ReserveExact(usize),
+        ShrinkTo(usize),
+        Realign,
+        Clear,
+        Set(usize, u16),
+        PushBackSlice(Vec<u16>),
+        PushFrontSlice(Vec<u16>),
+        PopBackSlice(usize),
+        PopFrontSlice(usize),
+        /// read range and sub-range at start, length, sub-range start
+        ReadRange(usize, usize, usize),
+        /// overwrite range at start, split at index
+        WriteRange(usize, Vec<u16>, usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let count = 0..40usize;
+        let data = vec(any::<u16>(), 0..40);
+        prop_oneof![
+            any::<u16>().prop_map(Op::PushBack),
+            any::<u16>().prop_map(Op::PushFront),
+            Just(Op::PopBack),
+            Just(Op::PopFront),
+            (count.clone(), count.clone()).prop_map(|(n, t)| Op::DrainFront(n, t)),
+            (count.clone(), count.clone()).prop_map(|(n, t)| Op::DrainBack(n, t)),
+            count.clone().prop_map(Op::TruncateBack),
+            count.clone().prop_map(Op::TruncateFront),
+            (count.clone(), any::<u16>()).prop_map(|(n, v)| Op::Resize(n, v)),
+            (count.clone(), any::<u16>()).prop_map(|(n, v)| Op::FillBack(n, v)),
+            (count.clone(), any::<u16>()).prop_map(|(n, v)| Op::FillFront(n, v)),
+            count.clone().prop_map(Op::Reserve),
+            count.clone().prop_map(Op::ReserveExact),
+            any::<usize>().prop_map(Op::ShrinkTo),
+            Just(Op::Realign),
+            Just(Op::Clear),
+            (any::<usize>(), any::<u16>()).prop_map(|(i, v)| Op::Set(i, v)),
+            data.clone().prop_map(Op::PushBackSlice),
+            data.clone().prop_map(Op::PushFrontSlice),
+            count.clone().prop_map(Op::PopBackSlice),
+            count.clone().prop_map(Op::PopFrontSlice),
+            (any::<usize>(), any::<usize>(), any::<usize>())
+                .prop_map(|(s, l, i)| Op::ReadRange(s, l, i)),
+            (any::<usize>(), data, any::<usize>()).prop_map(|(s, d, i)| Op::WriteRange(s, d, i)),
+        ]
+    }
+
+    /// apply operation not requiring T: Copy, returning false if the
+    /// operation requires it
+    fn apply<T: Clone>(
+        buf: &mut RingBuf<T>,
+        model: &mut VecDeque<T>,
+        op: &Op,
+        value: fn(u16) -> T,
+    ) -> bool {
+        let len = model.len();
+        match *op {
+            Op::PushBack(v) => {
+                buf.push_back(value(v));
+                model.push_back(value(v));
+            }
+            Op::PushFront(v) => {
+                buf.push_front(value(v));
+                model.push_front(value(v));
+            }
+            Op::PopBack => assert_eq!(buf.pop_back().is_some(), model.pop_back().is_some()),
+            Op::PopFront => assert_eq!(buf.pop_front().is_some(), model.pop_front().is_some()),
+            Op::DrainFront(n, take) => {
+                let n = n.min(len);
+                buf.drain(..n).take(take).for_each(drop);
+                model.drain(..n);
+            }
+            Op::DrainBack(n, take) => {
+                let start = len - n.min(len);
+                buf.drain(start..).rev().take(take).for_each(drop);
+                model.drain(start..);
+            }
+            Op::TruncateBack(n) => {
+                buf.truncate_back(n);
+                model.truncate(n);
+            }
+            Op::TruncateFront(n) => {
+                buf.truncate_front(n);
+                model.drain(..len - n.min(len));
+            }
+            Op::Resize(n, v) => {
+                buf.resize(n, value(v));
+                model.resize(n, value(v));
+            }
+            Op::FillBack(n, v) => {
+                buf.fill_at_back(n, value(v));
+                model.extend(std::iter::repeat_n(value(v), n));
+            }
+            Op::FillFront(n, v) => {
+                buf.fill_at_front(n, value(v));
+                (0..n).for_each(|_| model.push_front(value(v)));
+            }
+            Op::Reserve(n) => {
+                buf.reserve(n);
+                assert!(buf.capacity() >= len + n);
+            }
+            Op::ReserveExact(n) => {
+                buf.reserve_exact(n);
+                assert!(buf.capacity() >= len + n);
+            }
+            Op::ShrinkTo(n) => buf.shrink_to(n % (buf.capacity() + 1)),
+            Op::Realign => {
+                buf.realign();
+                assert!(buf.is_contiguous());
+            }
+            Op::Clear => {
+                buf.clear();
+                model.clear();
+            }
+            Op::Set(i, v) if len > 0 => {
+                buf[i % len] = value(v);
+                model[i % len] = value(v);
+            }
+            Op::Set(..) => assert!(buf.get_mut(0).is_none()),
+            _ => return false,
+        }
+        true
+    }
+
+    /// apply operation requiring T: Copy
+    fn apply_copy(buf: &mut RingBuf<u16>, model: &mut VecDeque<u16>, op: &Op) {
+        let len = model.len();
+        match op {
+            Op::PushBackSlice(data) => {
+                buf.push_back_copy_from_slice(data);
+                model.extend(data);
+            }
+            Op::PushFrontSlice(data) => {
+                buf.push_front_copy_from_slice(data);
+                data.iter().rev().for_each(|&v| model.push_front(v));
+            }
+            Op::PopBackSlice(n) => {
+                let mut dest = vec![0; (*n).min(len)];
+                buf.pop_back_copy_to_slice(&mut dest);
+                let expected: Vec<u16> = model.drain(len - dest.len()..).collect();
+                assert_eq!(dest, expected);
+            }
+            Op::PopFrontSlice(n) => {
+                let mut dest = vec![0; (*n).min(len)];
+                buf.pop_front_copy_to_slice(&mut dest);
+                let expected: Vec<u16> = model.drain(..dest.len()).collect();
+                assert_eq!(dest, expected);
+            }
+            Op::ReadRange(start, count, sub_start) if len > 0 => {
+                let start = start % len;
+                let end = start + count % (len - start + 1);
+                let expected: Vec<u16> = model.range(start..end).copied().collect();
+                let range = buf.range(start..end);
+                let mut out = vec![0; range.len()];
+                range.copy_to_slice(&mut out);
+                assert_eq!(out, expected);
+                let (a, b) = range.as_slices();
+                assert_eq!([a, b.unwrap_or_default()].concat(), expected);
+                if !range.is_empty() {
+                    let sub_start = sub_start % range.len();
+                    let sub = range.range(sub_start..range.len());
+                    let mut out = vec![0; sub.len()];
+                    sub.copy_to_slice(&mut out);
+                    assert_eq!(out, expected[sub_start..]);
+                }
+            }
+            Op::WriteRange(start, data, split) if len > 0 => {
+                let start = start % len;
+                let data = &data[..data.len().min(len - start)];
+                let end = start + data.len();
+                let mut range = buf.range_mut(start..end);
+                if data.len() > 1 {
+                    let split = 1 + split % (data.len() - 1);
+                    let (mut a, mut b) = range.split_at_mut(split);
+                    a.copy_from_slice(&data[..split]);
+                    b.copy_from_slice(&data[split..]);
+                } else {
+                    range.copy_from_slice(data);
+                    let (a, b) = range.as_mut_slices();
+                    assert_eq!([&*a, &*b.unwrap_or_default()].concat(), data);
+                }
+                for (i, &v) in data.iter().enumerate() {
+                    model[start + i] = v;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// check RingBuf against model
+    fn check<T: PartialEq + fmt::Debug>(buf: &RingBuf<T>, model: &VecDeque<T>) {
+        assert_eq!(buf.len(), model.len());
+        assert!(buf.capacity() >= buf.len());
+        assert!(buf.iter().eq(model.iter()));
+        assert!(buf.iter().rev().eq(model.iter().rev()));
+        assert_eq!(buf.get(buf.len()), None);
+    }
+
+    /// fewer cases without failure persistence under miri, which is slow and
+    /// isolated from the file system
+    fn proptest_config() -> ProptestConfig {
+        if cfg!(miri) {
+            ProptestConfig {
+                cases: 8,
+                failure_persistence: None,
+                ..ProptestConfig::default()
+            }
+        } else {
+            ProptestConfig::default()
+        }
+    }
+
+    proptest! {
+        #![proptest_config(proptest_config())]
+
+        #[test]
+        fn model_copy(ops in vec(op(), 0..64)) {
+            let mut buf: RingBuf<u16> = RingBuf::new();
+            let mut model = VecDeque::new();
+            for op in &ops {
+                if !apply(&mut buf, &mut model, op, |v| v) {
+                    apply_copy(&mut buf, &mut model, op);
+                }
+                check(&buf, &model);
+            }
+            assert_eq!(buf.clone(), buf);
+            assert!(buf.into_iter().eq(model));
+        }
+
+        #[test]
+        fn model_drop(ops in vec(op(), 0..64), capacity in 0..16usize) {
+            // heap allocated elements catch leaks and double drops
+            let mut buf: RingBuf<String> = RingBuf::with_capacity(capacity);
+            let mut model = VecDeque::new();
+            for op in &ops {
+                apply(&mut buf, &mut model, op, |v| v.to_string());
+                check(&buf, &model);
+            }
+            let storage = buf.clone().into_storage();
+            assert!(storage.is_empty());
+            let mut buf = RingBuf::from_storage(storage);
+            buf.extend(model.iter().cloned());
+            assert!(buf.iter().eq(model.iter()));
+        }
+    }
 }
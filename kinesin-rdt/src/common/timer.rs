@@ -0,0 +1,109 @@
+//! Deadline-ordered timer queue
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// handle to an armed timer, used for cancellation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId {
+    /// expiry time
+    pub deadline: Instant,
+    /// sequence number, disambiguating timers with the same deadline
+    seq: u64,
+}
+
+/// queue of timers ordered by deadline
+///
+/// Timers with the same deadline expire in the order they were armed.
+pub struct TimerQueue<T> {
+    /// armed timers
+    timers: BTreeMap<TimerId, T>,
+    /// next sequence number
+    next_seq: u64,
+}
+
+impl<T> TimerQueue<T> {
+    /// create new instance
+    pub fn new() -> TimerQueue<T> {
+        TimerQueue {
+            timers: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// arm timer firing `event` at `deadline`
+    pub fn arm(&mut self, deadline: Instant, event: T) -> TimerId {
+        let id = TimerId {
+            deadline,
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        self.timers.insert(id, event);
+        id
+    }
+
+    /// cancel timer, returning its event if it had not expired
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        self.timers.remove(&id)
+    }
+
+    /// earliest armed deadline
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.first_key_value().map(|(id, _)| id.deadline)
+    }
+
+    /// number of armed timers
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// whether no timers are armed
+    pub fn is_empty(&self) -> bool {
+        self.timers.is_empty()
+    }
+
+    /// remove and return events of all timers expiring at or before `now`,
+    /// in deadline order
+    pub fn poll(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+        while let Some(entry) = self.timers.first_entry() {
+            if entry.key().deadline > now {
+                break;
+            }
+            expired.push(entry.remove());
+        }
+        expired
+    }
+}
+
+impl<T> Default for TimerQueue<T> {
+    fn default() -> Self {
+        TimerQueue::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::TimerQueue;
+
+    #[test]
+    fn poll_and_cancel() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut timers = TimerQueue::new();
+        timers.arm(start + ms(20), "b");
+        timers.arm(start + ms(10), "a");
+        let cancelled = timers.arm(start + ms(20), "c");
+        timers.arm(start + ms(20), "d");
+        assert_eq!(timers.next_deadline(), Some(start + ms(10)));
+
+        assert!(timers.poll(start).is_empty());
+        assert_eq!(timers.poll(start + ms(10)), vec!["a"]);
+        assert_eq!(timers.cancel(cancelled), Some("c"));
+        assert_eq!(timers.cancel(cancelled), None);
+        assert_eq!(timers.poll(start + ms(30)), vec!["b", "d"]);
+        assert!(timers.is_empty());
+    }
+}
@@ -0,0 +1,157 @@
+//! Unreliable datagram channel
+
+use std::collections::VecDeque;
+
+use tracing::trace;
+
+use crate::frame::encoding::varint8_size;
+use crate::frame::{Datagram, Frame, FrameType};
+
+/// default max size of a single datagram
+pub const DATAGRAM_DEFAULT_MAX_SIZE: usize = 1200;
+/// default max bytes buffered in each direction
+pub const DATAGRAM_DEFAULT_QUEUE_LIMIT: usize = 64 << 10; // 64 KB
+
+/// error queueing datagram for sending
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum DatagramSendError {
+    /// datagram larger than max datagram size
+    #[error("datagram too large")]
+    TooLarge,
+    /// send queue is full
+    #[error("datagram send queue full")]
+    QueueFull,
+}
+
+/// send and receive queues for unreliable datagrams
+///
+/// Datagrams are never retransmitted. Received datagrams are dropped if the
+/// application does not take them before the receive queue fills.
+pub struct DatagramQueue {
+    /// max size of a single datagram payload
+    pub max_datagram_size: usize,
+    /// max bytes held in each queue
+    pub queue_limit: usize,
+    /// datagrams waiting to be sent
+    outbound: VecDeque<Vec<u8>>,
+    /// total bytes in `outbound`
+    outbound_bytes: usize,
+    /// datagrams received but not yet taken
+    inbound: VecDeque<Vec<u8>>,
+    /// total bytes in `inbound`
+    inbound_bytes: usize,
+    /// number of received datagrams dropped due to a full queue
+    pub inbound_dropped: u64,
+}
+
+impl DatagramQueue {
+    /// create new instance
+    pub fn new(max_datagram_size: usize, queue_limit: usize) -> DatagramQueue {
+        DatagramQueue {
+            max_datagram_size,
+            queue_limit,
+            outbound: VecDeque::new(),
+            outbound_bytes: 0,
+            inbound: VecDeque::new(),
+            inbound_bytes: 0,
+            inbound_dropped: 0,
+        }
+    }
+
+    /// queue datagram for sending
+    pub fn send(&mut self, data: Vec<u8>) -> Result<(), DatagramSendError> {
+        if data.len() > self.max_datagram_size {
+            return Err(DatagramSendError::TooLarge);
+        }
+        if self.outbound_bytes + data.len() > self.queue_limit {
+            return Err(DatagramSendError::QueueFull);
+        }
+        self.outbound_bytes += data.len();
+        self.outbound.push_back(data);
+        Ok(())
+    }
+
+    /// whether datagrams are waiting to be sent
+    pub fn has_outbound(&self) -> bool {
+        !self.outbound.is_empty()
+    }
+
+    /// take next datagram to send as a frame, if it fits in `space` bytes
+    /// (including frame tag) at the end of a packet
+    pub fn next_frame(&mut self, space: usize) -> Option<Frame> {
+        let len = self.outbound.front()?.len();
+        let tag_len = varint8_size(FrameType::Datagram.tag(true));
+        if tag_len + len > space {
+            return None;
+        }
+        let data = self.outbound.pop_front().unwrap();
+        self.outbound_bytes -= data.len();
+        Some(Frame::Datagram(Datagram { data }))
+    }
+
+    /// handle received datagram frame
+    ///
+    /// Returns false if the datagram was dropped.
+    pub fn receive(&mut self, frame: Datagram) -> bool {
+        if self.inbound_bytes + frame.data.len() > self.queue_limit {
+            trace!(
+                len = frame.data.len(),
+                "inbound datagram queue full, dropping"
+            );
+            self.inbound_dropped += 1;
+            return false;
+        }
+        self.inbound_bytes += frame.data.len();
+        self.inbound.push_back(frame.data);
+        true
+    }
+
+    /// take next received datagram
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        let data = self.inbound.pop_front()?;
+        self.inbound_bytes -= data.len();
+        Some(data)
+    }
+}
+
+impl Default for DatagramQueue {
+    fn default() -> Self {
+        DatagramQueue::new(DATAGRAM_DEFAULT_MAX_SIZE, DATAGRAM_DEFAULT_QUEUE_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::{FrameReader, PacketBuilder};
+
+    #[test]
+    fn send_and_receive() {
+        let mut sender = DatagramQueue::new(16, 32);
+        assert_eq!(sender.send(vec![0; 17]), Err(DatagramSendError::TooLarge));
+        assert!(sender.send(vec![1; 16]).is_ok());
+        assert!(sender.send(vec![2; 16]).is_ok());
+        assert_eq!(sender.send(vec![3]), Err(DatagramSendError::QueueFull));
+
+        // first datagram fits with length prefix, second only at the end
+        let mut builder = PacketBuilder::new(35);
+        let frame = sender.next_frame(builder.remaining()).unwrap();
+        assert!(builder.push(frame).is_ok());
+        assert!(sender.next_frame(builder.remaining() - 1).is_none());
+        let frame = sender.next_frame(builder.remaining()).unwrap();
+        assert!(builder.push(frame).is_ok());
+        assert!(!sender.has_outbound());
+        let packet = builder.finish();
+
+        let mut receiver = DatagramQueue::new(16, 16);
+        for frame in FrameReader::new(&packet.data) {
+            let Ok(Frame::Datagram(datagram)) = frame else {
+                panic!("expected datagram");
+            };
+            receiver.receive(datagram);
+        }
+        assert_eq!(receiver.inbound_dropped, 1);
+        assert_eq!(receiver.recv(), Some(vec![1; 16]));
+        assert_eq!(receiver.recv(), None);
+    }
+}
@@ -0,0 +1,419 @@
+//! Acknowledgment frame types
+
+use std::ops::Range;
+
+use super::buffer_util::checked_read_varint8;
+use super::encoding::{varint8_size, write_varint8};
+use super::{FrameDecodeError, FrameType, Serialize, SerializeToEnd, SerializeVectored};
+use crate::common::range_set::{RangeSet, RangeStorage};
+use crate::stream::outbound::StreamOutboundState;
+
+/// most ranges a compressed acknowledgment frame may expand to
+pub const MAX_COMPRESSED_RANGES: usize = 4096;
+
+/// acknowledgment of received stream ranges
+///
+/// Encoded QUIC-style as the end of the highest range, the length of the
+/// highest range, then (gap, length) pairs for each lower range in descending
+/// order.
+#[derive(Debug, PartialEq)]
+pub struct AckRanges {
+    /// stream identifier
+    pub stream_id: u64,
+    /// acknowledged ranges, non-empty and in descending order
+    pub ranges: Vec<Range<u64>>,
+}
+
+impl AckRanges {
+    /// build from the highest `max_ranges` ranges of a set, or None if the
+    /// set is empty
+    pub fn from_range_set<S: RangeStorage>(
+        stream_id: u64,
+        set: &RangeSet<S>,
+        max_ranges: usize,
+    ) -> Option<Self> {
+        let mut ranges: Vec<Range<u64>> = set.iter().collect();
+        if ranges.is_empty() || max_ranges == 0 {
+            return None;
+        }
+        ranges.reverse();
+        ranges.truncate(max_ranges);
+        Some(AckRanges { stream_id, ranges })
+    }
+
+    /// end (exclusive) of highest acknowledged range
+    pub fn largest_acked(&self) -> u64 {
+        self.ranges.first().map_or(0, |r| r.end)
+    }
+
+    /// mark acknowledged ranges as delivered
+    pub fn apply(&self, outbound: &mut StreamOutboundState) {
+        for range in &self.ranges {
+            outbound.segment_delivered(range.clone());
+        }
+    }
+
+    /// iterate over (gap, length) pairs following the highest range
+    fn gaps(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges
+            .windows(2)
+            .map(|w| (w[0].start - w[1].end, w[1].end - w[1].start))
+    }
+}
+
+impl Serialize for AckRanges {
+    fn serialized_length(&self) -> usize {
+        let first = self.ranges.first().expect("ack frame has no ranges");
+        let mut len = varint8_size(self.stream_id)
+            + varint8_size(first.end)
+            + varint8_size(self.ranges.len() as u64 - 1)
+            + varint8_size(first.end - first.start);
+        for (gap, length) in self.gaps() {
+            len += varint8_size(gap) + varint8_size(length);
+        }
+        len
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        let first = self.ranges.first().expect("ack frame has no ranges");
+        let mut index = 0;
+        index += write_varint8(&mut buf[index..], self.stream_id);
+        index += write_varint8(&mut buf[index..], first.end);
+        index += write_varint8(&mut buf[index..], self.ranges.len() as u64 - 1);
+        index += write_varint8(&mut buf[index..], first.end - first.start);
+        for (gap, length) in self.gaps() {
+            index += write_varint8(&mut buf[index..], gap);
+            index += write_varint8(&mut buf[index..], length);
+        }
+        index
+    }
+
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
+        let (mut index, stream_id, first, range_count) = read_header(buf)?;
+        // each pair takes at least 2 bytes, don't trust range_count for allocation
+        let capacity = usize::min(range_count as usize, (buf.len() - index) / 2) + 1;
+        let mut ranges = Vec::with_capacity(capacity);
+        ranges.push(first);
+        for _ in 0..range_count {
+            let gap = checked_read_varint8(buf, &mut index)?;
+            let length = checked_read_varint8(buf, &mut index)?;
+            push_lower_range(&mut ranges, gap, length)?;
+        }
+        Ok((index, AckRanges { stream_id, ranges }))
+    }
+}
+
+impl SerializeToEnd for AckRanges {}
+
+impl SerializeVectored for AckRanges {}
+
+/// read stream id, highest range and pair or run count shared by
+/// acknowledgment frames, returning the length read first
+fn read_header(buf: &[u8]) -> Result<(usize, u64, Range<u64>, u64), FrameDecodeError> {
+    let mut index = 0;
+    let stream_id = checked_read_varint8(buf, &mut index)?;
+    let largest_acked = checked_read_varint8(buf, &mut index)?;
+    let count = checked_read_varint8(buf, &mut index)?;
+    let first_length = checked_read_varint8(buf, &mut index)?;
+    if first_length == 0 || first_length > largest_acked {
+        return Err(FrameDecodeError::InvalidValue);
+    }
+    let first = (largest_acked - first_length)..largest_acked;
+    Ok((index, stream_id, first, count))
+}
+
+/// append range `gap` below the lowest range
+fn push_lower_range(
+    ranges: &mut Vec<Range<u64>>,
+    gap: u64,
+    length: u64,
+) -> Result<(), FrameDecodeError> {
+    if gap == 0 || length == 0 {
+        return Err(FrameDecodeError::InvalidValue);
+    }
+    let prev_start = ranges.last().expect("no highest range").start;
+    let end = prev_start
+        .checked_sub(gap)
+        .ok_or(FrameDecodeError::InvalidValue)?;
+    let start = end
+        .checked_sub(length)
+        .ok_or(FrameDecodeError::InvalidValue)?;
+    ranges.push(start..end);
+    Ok(())
+}
+
+/// acknowledgment of received stream ranges, run-length encoded
+///
+/// Encoded like AckRanges, except that the (gap, length) pairs are grouped
+/// into runs of identical pairs, each encoded as (gap, length, count), and
+/// the count after the largest acknowledged offset is the number of runs.
+/// The regular loss patterns of high-loss links then take a few bytes no
+/// matter how many ranges they produce. Decoded frames expand to at most
+/// `MAX_COMPRESSED_RANGES` ranges.
+#[derive(Debug, PartialEq)]
+pub struct CompressedAckRanges(pub AckRanges);
+
+impl CompressedAckRanges {
+    /// split the highest ranges of a set into up to `max_frames` frames of
+    /// at most `max_frame_size` bytes each, including tag
+    ///
+    /// Frames cover consecutive ranges in descending order, and each can be
+    /// applied on its own.
+    pub fn from_range_set<S: RangeStorage>(
+        stream_id: u64,
+        set: &RangeSet<S>,
+        max_frame_size: usize,
+        max_frames: usize,
+    ) -> Vec<Self> {
+        let mut ranges: Vec<Range<u64>> = set.iter().collect();
+        let mut frames = Vec::new();
+        let mut builder: Option<CompressedAckBuilder> = None;
+        while let Some(range) = ranges.pop() {
+            if let Some(current) = &mut builder {
+                if current.push(range.clone(), max_frame_size) {
+                    continue;
+                }
+                frames.push(CompressedAckRanges(builder.take().unwrap().frame));
+            }
+            if frames.len() >= max_frames {
+                break;
+            }
+            let next = CompressedAckBuilder::new(stream_id, range);
+            if next.size > max_frame_size {
+                break;
+            }
+            builder = Some(next);
+        }
+        frames.extend(builder.map(|builder| CompressedAckRanges(builder.frame)));
+        frames
+    }
+
+    /// (gap, length, count) runs of pairs following the highest range
+    fn runs(&self) -> Vec<(u64, u64, u64)> {
+        let mut runs: Vec<(u64, u64, u64)> = Vec::new();
+        for (gap, length) in self.0.gaps() {
+            match runs.last_mut() {
+                Some(run) if (run.0, run.1) == (gap, length) => run.2 += 1,
+                _ => runs.push((gap, length, 1)),
+            }
+        }
+        runs
+    }
+}
+
+impl Serialize for CompressedAckRanges {
+    fn serialized_length(&self) -> usize {
+        let first = self.0.ranges.first().expect("ack frame has no ranges");
+        let runs = self.runs();
+        let mut len = varint8_size(self.0.stream_id)
+            + varint8_size(first.end)
+            + varint8_size(runs.len() as u64)
+            + varint8_size(first.end - first.start);
+        for (gap, length, count) in runs {
+            len += varint8_size(gap) + varint8_size(length) + varint8_size(count);
+        }
+        len
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        let first = self.0.ranges.first().expect("ack frame has no ranges");
+        let runs = self.runs();
+        let mut index = 0;
+        index += write_varint8(&mut buf[index..], self.0.stream_id);
+        index += write_varint8(&mut buf[index..], first.end);
+        index += write_varint8(&mut buf[index..], runs.len() as u64);
+        index += write_varint8(&mut buf[index..], first.end - first.start);
+        for (gap, length, count) in runs {
+            index += write_varint8(&mut buf[index..], gap);
+            index += write_varint8(&mut buf[index..], length);
+            index += write_varint8(&mut buf[index..], count);
+        }
+        index
+    }
+
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
+        let (mut index, stream_id, first, run_count) = read_header(buf)?;
+        let mut ranges = vec![first];
+        for _ in 0..run_count {
+            let gap = checked_read_varint8(buf, &mut index)?;
+            let length = checked_read_varint8(buf, &mut index)?;
+            let count = checked_read_varint8(buf, &mut index)?;
+            // checked before expanding, count is untrusted
+            if count == 0 || count > (MAX_COMPRESSED_RANGES - ranges.len()) as u64 {
+                return Err(FrameDecodeError::InvalidValue);
+            }
+            for _ in 0..count {
+                push_lower_range(&mut ranges, gap, length)?;
+            }
+        }
+        Ok((index, CompressedAckRanges(AckRanges { stream_id, ranges })))
+    }
+}
+
+impl SerializeToEnd for CompressedAckRanges {}
+
+impl SerializeVectored for CompressedAckRanges {}
+
+/// incremental builder of a compressed acknowledgment frame, tracking its
+/// encoded length
+struct CompressedAckBuilder {
+    /// ranges added so far
+    frame: AckRanges,
+    /// (gap, length, count) runs following the highest range
+    runs: Vec<(u64, u64, u64)>,
+    /// encoded length including tag
+    size: usize,
+}
+
+impl CompressedAckBuilder {
+    /// start frame with its highest range
+    fn new(stream_id: u64, first: Range<u64>) -> Self {
+        let tag = FrameType::CompressedAckRanges.tag(false);
+        let size = varint8_size(tag)
+            + varint8_size(stream_id)
+            + varint8_size(first.end)
+            + varint8_size(0)
+            + varint8_size(first.end - first.start);
+        CompressedAckBuilder {
+            frame: AckRanges {
+                stream_id,
+                ranges: vec![first],
+            },
+            runs: Vec::new(),
+            size,
+        }
+    }
+
+    /// add next lower range if the frame stays within `max_size` bytes,
+    /// returning whether it was added
+    fn push(&mut self, range: Range<u64>, max_size: usize) -> bool {
+        if self.frame.ranges.len() >= MAX_COMPRESSED_RANGES {
+            return false;
+        }
+        let prev_start = self.frame.ranges.last().unwrap().start;
+        let (gap, length) = (prev_start - range.end, range.end - range.start);
+        let extends_run = self
+            .runs
+            .last()
+            .is_some_and(|run| (run.0, run.1) == (gap, length));
+        let size = if extends_run {
+            let count = self.runs.last().unwrap().2;
+            self.size + varint8_size(count + 1) - varint8_size(count)
+        } else {
+            let run_count = self.runs.len() as u64;
+            self.size
+                + varint8_size(gap)
+                + varint8_size(length)
+                + varint8_size(1)
+                + varint8_size(run_count + 1)
+                - varint8_size(run_count)
+        };
+        if size > max_size {
+            return false;
+        }
+
+        if extends_run {
+            self.runs.last_mut().unwrap().2 += 1;
+        } else {
+            self.runs.push((gap, length, 1));
+        }
+        self.frame.ranges.push(range);
+        self.size = size;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::outbound::RetransmitStrategy;
+
+    #[test]
+    fn round_trip() {
+        let mut set = RangeSet::unlimited();
+        set.insert_range(0..100);
+        set.insert_range(200..250);
+        set.insert_range(100000..100001);
+        let frame = AckRanges::from_range_set(7, &set, 16).unwrap();
+        assert_eq!(frame.largest_acked(), 100001);
+        assert_eq!(frame.ranges, vec![100000..100001, 200..250, 0..100]);
+
+        let length = frame.serialized_length();
+        let mut buf = vec![0; length];
+        assert_eq!(frame.write(&mut buf), length);
+        let (length2, frame2) = AckRanges::read(&buf).unwrap();
+        assert_eq!(length, length2);
+        assert_eq!(frame, frame2);
+
+        // truncated to highest ranges
+        let frame = AckRanges::from_range_set(7, &set, 2).unwrap();
+        assert_eq!(frame.ranges, vec![100000..100001, 200..250]);
+
+        // gap of zero is invalid
+        assert!(AckRanges::read(&[7, 10, 1, 5, 0, 1]).is_err());
+        // range past zero is invalid
+        assert!(AckRanges::read(&[7, 10, 1, 5, 1, 10]).is_err());
+    }
+
+    #[test]
+    fn compressed() {
+        // regular loss pattern with one irregular range
+        let mut set = RangeSet::unlimited();
+        for i in 0..100 {
+            set.insert_range(i * 10..i * 10 + 5);
+        }
+        set.insert_range(2000..2001);
+        let mut expected: Vec<Range<u64>> = set.iter().collect();
+        expected.reverse();
+
+        let frames = CompressedAckRanges::from_range_set(3, &set, 1200, 4);
+        assert_eq!(frames.len(), 1);
+        let frame = &frames[0];
+        assert_eq!(frame.0.ranges, expected);
+        assert_eq!(frame.runs(), vec![(1005, 5, 1), (5, 5, 99)]);
+        let length = frame.serialized_length();
+        assert!(length < frame.0.serialized_length() / 10);
+        let mut buf = vec![0; length];
+        assert_eq!(frame.write(&mut buf), length);
+        let (length2, frame2) = CompressedAckRanges::read(&buf).unwrap();
+        assert_eq!(length, length2);
+        assert_eq!(frame, &frame2);
+
+        // irregular ranges split across frames within budget
+        let mut set = RangeSet::unlimited();
+        for i in 0..100 {
+            set.insert_range(i * i * 10..i * i * 10 + i + 1);
+        }
+        let mut expected: Vec<Range<u64>> = set.iter().collect();
+        expected.reverse();
+        let frames = CompressedAckRanges::from_range_set(3, &set, 32, 100);
+        assert!(frames.len() > 1);
+        let tag_length = varint8_size(FrameType::CompressedAckRanges.tag(false));
+        for frame in &frames {
+            assert!(tag_length + frame.serialized_length() <= 32);
+        }
+        let ranges: Vec<Range<u64>> = frames.iter().flat_map(|f| f.0.ranges.clone()).collect();
+        assert_eq!(ranges, expected);
+        let limited = CompressedAckRanges::from_range_set(3, &set, 32, 2);
+        assert_eq!(limited, frames[..2]);
+        assert!(CompressedAckRanges::from_range_set(3, &set, 4, 2).is_empty());
+
+        // empty runs and runs expanding past the limit are invalid
+        assert!(CompressedAckRanges::read(&[3, 20, 1, 5, 1, 1, 0]).is_err());
+        assert!(CompressedAckRanges::read(&[3, 0x7f, 0xff, 1, 5, 1, 1, 0x50, 0x00]).is_err());
+        assert!(CompressedAckRanges::read(&[3, 20, 1, 5, 1, 1, 3]).is_ok());
+    }
+
+    #[test]
+    fn apply() {
+        let mut outbound = StreamOutboundState::new(4096, RetransmitStrategy::Reliable);
+        outbound.write_direct(&[0u8; 64]);
+        let frame = AckRanges {
+            stream_id: 0,
+            ranges: vec![32..64, 0..16],
+        };
+        frame.apply(&mut outbound);
+        assert_eq!(outbound.next_segment(64).unwrap().start, 16);
+        assert!(outbound.delivered.has_range(32..64));
+    }
+}
@@ -0,0 +1,83 @@
+//! Bounds-checked reads for frame decoding
+//!
+//! Each helper reads at `*index` and advances it past the value read, failing
+//! with `FrameDecodeError::UnexpectedEof` instead of panicking if the buffer
+//! is too short.
+
+use super::encoding::read_varint8_checked;
+use super::FrameDecodeError;
+
+/// take `len` bytes starting at `*index`
+pub fn take_slice<'a>(
+    buf: &'a [u8],
+    index: &mut usize,
+    len: usize,
+) -> Result<&'a [u8], FrameDecodeError> {
+    let end = index
+        .checked_add(len)
+        .ok_or(FrameDecodeError::UnexpectedEof)?;
+    let slice = buf
+        .get(*index..end)
+        .ok_or(FrameDecodeError::UnexpectedEof)?;
+    *index = end;
+    Ok(slice)
+}
+
+/// read big-endian u16 at `*index`
+pub fn checked_read_u16(buf: &[u8], index: &mut usize) -> Result<u16, FrameDecodeError> {
+    let bytes = take_slice(buf, index, 2)?;
+    Ok(u16::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// read big-endian u32 at `*index`
+pub fn checked_read_u32(buf: &[u8], index: &mut usize) -> Result<u32, FrameDecodeError> {
+    let bytes = take_slice(buf, index, 4)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// read varint8 at `*index`
+pub fn checked_read_varint8(buf: &[u8], index: &mut usize) -> Result<u64, FrameDecodeError> {
+    let rest = buf.get(*index..).ok_or(FrameDecodeError::UnexpectedEof)?;
+    let (value, len) = read_varint8_checked(rest)?;
+    *index += len;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checked_reads() {
+        let buf = [0x12, 0x34, 0x56, 0x78, 0x9a, 0x41, 0x00];
+        let mut index = 0;
+        assert_eq!(checked_read_u16(&buf, &mut index), Ok(0x1234));
+        assert_eq!(checked_read_u32(&buf, &mut index), Ok(0x56789a41));
+        assert_eq!(index, 6);
+        // failed reads leave index unchanged
+        assert_eq!(
+            checked_read_u16(&buf, &mut index),
+            Err(FrameDecodeError::UnexpectedEof)
+        );
+        assert_eq!(
+            take_slice(&buf, &mut index, usize::MAX),
+            Err(FrameDecodeError::UnexpectedEof)
+        );
+        assert_eq!(index, 6);
+        assert_eq!(take_slice(&buf, &mut index, 1), Ok(&[0][..]));
+        assert_eq!(take_slice(&buf, &mut index, 0), Ok(&[][..]));
+
+        let mut index = 5;
+        assert_eq!(checked_read_varint8(&buf, &mut index), Ok(0x100));
+        assert_eq!(index, 7);
+        assert_eq!(
+            checked_read_varint8(&buf, &mut index),
+            Err(FrameDecodeError::UnexpectedEof)
+        );
+        let mut index = 8;
+        assert_eq!(
+            checked_read_varint8(&buf, &mut index),
+            Err(FrameDecodeError::UnexpectedEof)
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Frame types for connection control
+
+use super::buffer_util::checked_read_varint8;
+use super::encoding::{read_slice, read_varint8_checked, varint8_size, write_varint8};
+use super::{FrameDecodeError, Serialize, SerializeToEnd, SerializeVectored};
+
+/// connection parameter as (identifier, value)
+pub type ConnectionParameter = (u64, Vec<u8>);
+
+/// serialized length of a length-prefixed byte string
+fn bytes_length(data: &[u8]) -> usize {
+    varint8_size(data.len() as u64) + data.len()
+}
+
+/// write length-prefixed byte string, returning serialized length
+fn write_bytes(buf: &mut [u8], data: &[u8]) -> usize {
+    let index = write_varint8(buf, data.len() as u64);
+    buf[index..index + data.len()].copy_from_slice(data);
+    index + data.len()
+}
+
+/// read length-prefixed byte string, returning (data, serialized length)
+fn read_bytes(buf: &[u8]) -> Result<(&[u8], usize), FrameDecodeError> {
+    let (length, index) = read_varint8_checked(buf)?;
+    let length = usize::try_from(length).map_err(|_| FrameDecodeError::UnexpectedEof)?;
+    let data = read_slice(&buf[index..], length)?;
+    Ok((data, index + length))
+}
+
+/// serialized length of version and parameter list
+fn handshake_length(version: u64, parameters: &[ConnectionParameter]) -> usize {
+    let mut len = varint8_size(version) + varint8_size(parameters.len() as u64);
+    for (id, value) in parameters {
+        len += varint8_size(*id) + bytes_length(value);
+    }
+    len
+}
+
+/// write version and parameter list
+fn write_handshake(buf: &mut [u8], version: u64, parameters: &[ConnectionParameter]) -> usize {
+    let mut index = 0;
+    index += write_varint8(&mut buf[index..], version);
+    index += write_varint8(&mut buf[index..], parameters.len() as u64);
+    for (id, value) in parameters {
+        index += write_varint8(&mut buf[index..], *id);
+        index += write_bytes(&mut buf[index..], value);
+    }
+    index
+}
+
+/// read version and parameter list
+fn read_handshake(buf: &[u8]) -> Result<(usize, u64, Vec<ConnectionParameter>), FrameDecodeError> {
+    let mut index = 0;
+    let version = checked_read_varint8(buf, &mut index)?;
+    let count = checked_read_varint8(buf, &mut index)?;
+    // each parameter takes at least 2 bytes, don't trust count for allocation
+    let mut parameters = Vec::with_capacity(usize::min(count as usize, buf.len() / 2));
+    for _ in 0..count {
+        let id = checked_read_varint8(buf, &mut index)?;
+        let (value, len) = read_bytes(&buf[index..])?;
+        index += len;
+        parameters.push((id, value.to_vec()));
+    }
+    Ok((index, version, parameters))
+}
+
+/// connection initiation, sent by the client
+pub struct ConnectionInit {
+    /// protocol version requested
+    pub version: u64,
+    /// client connection parameters
+    pub parameters: Vec<ConnectionParameter>,
+}
+
+impl Serialize for ConnectionInit {
+    fn serialized_length(&self) -> usize {
+        handshake_length(self.version, &self.parameters)
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        write_handshake(buf, self.version, &self.parameters)
+    }
+
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
+        let (index, version, parameters) = read_handshake(buf)?;
+        Ok((
+            index,
+            ConnectionInit {
+                version,
+                parameters,
+            },
+        ))
+    }
+}
+
+impl SerializeToEnd for ConnectionInit {}
+
+impl SerializeVectored for ConnectionInit {}
+
+/// connection acceptance, sent by the server in response to ConnectionInit
+pub struct ConnectionAccept {
+    /// protocol version selected
+    pub version: u64,
+    /// server connection parameters
+    pub parameters: Vec<ConnectionParameter>,
+}
+
+impl Serialize for ConnectionAccept {
+    fn serialized_length(&self) -> usize {
+        handshake_length(self.version, &self.parameters)
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        write_handshake(buf, self.version, &self.parameters)
+    }
+
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
+        let (index, version, parameters) = read_handshake(buf)?;
+        Ok((
+            index,
+            ConnectionAccept {
+                version,
+                parameters,
+            },
+        ))
+    }
+}
+
+impl SerializeToEnd for ConnectionAccept {}
+
+impl SerializeVectored for ConnectionAccept {}
+
+/// connection close
+pub struct ConnectionClose {
+    /// error code, 0 if closed normally
+    pub error_code: u64,
+    /// human readable reason
+    pub reason: String,
+}
+
+impl Serialize for ConnectionClose {
+    fn serialized_length(&self) -> usize {
+        varint8_size(self.error_code) + bytes_length(self.reason.as_bytes())
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        let mut index = 0;
+        index += write_varint8(&mut buf[index..], self.error_code);
+        index += write_bytes(&mut buf[index..], self.reason.as_bytes());
+        index
+    }
+
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
+        let mut index = 0;
+        let error_code = checked_read_varint8(buf, &mut index)?;
+        let (reason, len) = read_bytes(&buf[index..])?;
+        index += len;
+        let reason =
+            String::from_utf8(reason.to_vec()).map_err(|_| FrameDecodeError::InvalidValue)?;
+        Ok((index, ConnectionClose { error_code, reason }))
+    }
+}
+
+impl SerializeToEnd for ConnectionClose {}
+
+impl SerializeVectored for ConnectionClose {}
+
+/// liveness check, answered with a Pong of the same sequence number
+pub struct Ping {
+    /// sequence number
+    pub sequence: u64,
+}
+
+/// reply to Ping
+pub struct Pong {
+    /// sequence number of the Ping being answered
+    pub sequence: u64,
+}
+
+/// connection-level flow control limit, total stream bytes the peer may send
+pub struct MaxData {
+    /// limit on sum of stream bytes across all streams
+    pub limit: u64,
+}
+
+/// sender has data to send but is blocked by the connection-level limit
+pub struct DataBlocked {
+    /// connection-level limit at which sender is blocked
+    pub limit: u64,
+}
+
+macro_rules! impl_varint_frame {
+    ($frame:ident, $field:ident) => {
+        impl Serialize for $frame {
+            fn serialized_length(&self) -> usize {
+                varint8_size(self.$field)
+            }
+
+            fn write(&self, buf: &mut [u8]) -> usize {
+                write_varint8(buf, self.$field)
+            }
+
+            fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
+                let ($field, len) = read_varint8_checked(buf)?;
+                Ok((len, $frame { $field }))
+            }
+        }
+
+        impl SerializeToEnd for $frame {}
+
+        impl SerializeVectored for $frame {}
+    };
+}
+
+impl_varint_frame!(Ping, sequence);
+impl_varint_frame!(Pong, sequence);
+impl_varint_frame!(MaxData, limit);
+impl_varint_frame!(DataBlocked, limit);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connection_init() {
+        let frame = ConnectionInit {
+            version: 1,
+            parameters: vec![(0, vec![1, 2, 3]), (300, vec![]), (4, vec![0; 100])],
+        };
+        let length = frame.serialized_length();
+        let mut buf = vec![0; length];
+        assert_eq!(frame.write(&mut buf), length);
+        let (length2, frame2) = ConnectionInit::read(&buf).unwrap();
+        assert_eq!(length, length2);
+        assert_eq!(frame.version, frame2.version);
+        assert_eq!(frame.parameters, frame2.parameters);
+
+        // truncated parameter value
+        assert_eq!(
+            ConnectionInit::read(&buf[..length - 1]).err(),
+            Some(FrameDecodeError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn connection_close() {
+        let frame = ConnectionClose {
+            error_code: 12345,
+            reason: "goodbye".into(),
+        };
+        let length = frame.serialized_length();
+        let mut buf = vec![0; length];
+        assert_eq!(frame.write(&mut buf), length);
+        let (length2, frame2) = ConnectionClose::read(&buf).unwrap();
+        assert_eq!(length, length2);
+        assert_eq!(frame.error_code, frame2.error_code);
+        assert_eq!(frame.reason, frame2.reason);
+
+        let (length, pong) = Pong::read(&[0x40, 0xff]).unwrap();
+        assert_eq!((length, pong.sequence), (2, 0xff));
+
+        let frame = MaxData { limit: 1 << 30 };
+        let mut buf = vec![0; frame.serialized_length()];
+        assert_eq!(frame.write(&mut buf), buf.len());
+        assert_eq!(MaxData::read(&buf).unwrap().1.limit, 1 << 30);
+        assert_eq!(
+            DataBlocked::read(&buf[..2]).err(),
+            Some(FrameDecodeError::UnexpectedEof)
+        );
+    }
+}
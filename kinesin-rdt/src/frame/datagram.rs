@@ -0,0 +1,85 @@
+//! Frame types for unreliable datagrams
+
+use super::encoding::{copy_split, read_slice, read_varint8_checked, varint8_size, write_varint8};
+use super::{FrameDecodeError, Serialize, SerializeToEnd, SerializeVectored};
+
+/// unreliable datagram, never retransmitted
+pub struct Datagram {
+    /// datagram payload
+    pub data: Vec<u8>,
+}
+
+impl Serialize for Datagram {
+    fn serialized_length(&self) -> usize {
+        varint8_size(self.data.len() as u64) + self.data.len()
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        let index = write_varint8(buf, self.data.len() as u64);
+        buf[index..index + self.data.len()].copy_from_slice(&self.data);
+        index + self.data.len()
+    }
+
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
+        let (length, index) = read_varint8_checked(buf)?;
+        let length = usize::try_from(length).map_err(|_| FrameDecodeError::UnexpectedEof)?;
+        let data = read_slice(&buf[index..], length)?.to_vec();
+        Ok((index + length, Datagram { data }))
+    }
+}
+
+impl SerializeToEnd for Datagram {
+    fn serialized_length_at_end(&self) -> usize {
+        self.data.len()
+    }
+
+    fn write_to_end(&self, buf: &mut [u8]) -> usize {
+        buf[..self.data.len()].copy_from_slice(&self.data);
+        self.data.len()
+    }
+
+    fn read_to_end(buf: &[u8]) -> Result<Self, FrameDecodeError> {
+        Ok(Datagram { data: buf.to_vec() })
+    }
+}
+
+impl SerializeVectored for Datagram {
+    fn write_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        let mut header = [0u8; 9];
+        let index = write_varint8(&mut header, self.data.len() as u64);
+        copy_split(&header[..index], first, second, 0);
+        copy_split(&self.data, first, second, index);
+        index + self.data.len()
+    }
+
+    fn write_to_end_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        copy_split(&self.data, first, second, 0);
+        self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn datagram() {
+        let frame = Datagram {
+            data: vec![1, 2, 3, 4],
+        };
+        let length = frame.serialized_length();
+        let mut buf = vec![0; length];
+        assert_eq!(frame.write(&mut buf), length);
+        let (length2, frame2) = Datagram::read(&buf).unwrap();
+        assert_eq!(length, length2);
+        assert_eq!(frame.data, frame2.data);
+
+        assert_eq!(frame.write_to_end(&mut buf), 4);
+        let frame2 = Datagram::read_to_end(&buf[..4]).unwrap();
+        assert_eq!(frame.data, frame2.data);
+
+        let (mut first, mut second) = ([0; 2], [0; 3]);
+        assert_eq!(frame.write_vectored(&mut first, &mut second), length);
+        assert_eq!((first, second), ([4, 1], [2, 3, 4]));
+    }
+}
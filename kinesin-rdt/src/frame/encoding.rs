@@ -1,51 +1,69 @@
 //! Frame encoding utilities
-#![allow(clippy::result_unit_err)] // todo
+
+use super::FrameDecodeError;
+
+/// first byte of the 9-byte varint8 form, followed by the full big-endian u64
+///
+/// Values whose 8-byte form would start with this byte use the 9-byte form
+/// instead, so any u64 can be encoded. This changes the encoding of values
+/// from `0x3f << 56` up to 2^62, which were previously written in the 8-byte
+/// form starting with `0xff`; encodings of all other values are unchanged.
+/// Only `read_varint8_checked` understands the 9-byte form.
+pub const VARINT8_ESCAPE: u8 = 0xff;
+
+/// smallest value requiring the 9-byte varint8 form
+const VARINT8_ESCAPE_MIN: u64 = ((VARINT8_ESCAPE & (u8::MAX >> 2)) as u64) << (64 - 8);
 
 /// determine how many bytes are required to encode a varint8
-pub fn varint8_size(n: u64) -> Option<usize> {
+pub fn varint8_size(n: u64) -> usize {
     if n < 2u64.pow(8 - 2) {
-        Some(1)
+        1
     } else if n < 2u64.pow(16 - 2) {
-        Some(2)
+        2
     } else if n < 2u64.pow(32 - 2) {
-        Some(4)
-    } else if n < 2u64.pow(64 - 2) {
-        Some(8)
+        4
+    } else if n < VARINT8_ESCAPE_MIN {
+        8
     } else {
-        None
+        9
     }
 }
 
 /// write varint8 to buffer, returning how many bytes were used
-pub fn write_varint8(buf: &mut [u8], n: u64) -> Option<usize> {
+pub fn write_varint8(buf: &mut [u8], n: u64) -> usize {
     if n < 2u64.pow(8 - 2) {
         let val = n as u8;
         buf[0] = val;
-        Some(1)
+        1
     } else if n < 2u64.pow(16 - 2) {
         let mut val = n as u16;
         val |= 0b01u16 << (16 - 2);
         buf[..2].copy_from_slice(&val.to_be_bytes());
-        Some(2)
+        2
     } else if n < 2u64.pow(32 - 2) {
         let mut val = n as u32;
         val |= 0b10u32 << (32 - 2);
         buf[..4].copy_from_slice(&val.to_be_bytes());
-        Some(4)
-    } else if n < 2u64.pow(64 - 2) {
+        4
+    } else if n < VARINT8_ESCAPE_MIN {
         let mut val = n;
         val |= 0b11u64 << (64 - 2);
         buf[..8].copy_from_slice(&val.to_be_bytes());
-        Some(8)
+        8
     } else {
-        None
+        buf[0] = VARINT8_ESCAPE;
+        buf[1..9].copy_from_slice(&n.to_be_bytes());
+        9
     }
 }
 
 /// read varint8 from buffer, returning (value, size)
-pub fn read_varint8(buf: &[u8]) -> Result<(u64, usize), ()> {
+///
+/// Reads only the 1, 2, 4 and 8-byte forms, limiting values to below 2^62.
+/// Use `read_varint8_checked` to read values written by `write_varint8`.
+pub fn read_varint8(buf: &[u8]) -> Result<(u64, usize), FrameDecodeError> {
     if buf.is_empty() {
-        return Err(());
+        return Err(FrameDecodeError::UnexpectedEof);
     }
     let length = buf[0] >> 6;
     match length {
@@ -55,7 +73,7 @@ pub fn read_varint8(buf: &[u8]) -> Result<(u64, usize), ()> {
                 let val = u16::from_be_bytes(buf[0..2].try_into().unwrap());
                 Ok(((val & (u16::MAX >> 2)) as u64, 2))
             } else {
-                Err(())
+                Err(FrameDecodeError::UnexpectedEof)
             }
         }
         2 => {
@@ -63,7 +81,7 @@ pub fn read_varint8(buf: &[u8]) -> Result<(u64, usize), ()> {
                 let val = u32::from_be_bytes(buf[0..4].try_into().unwrap());
                 Ok(((val & (u32::MAX >> 2)) as u64, 4))
             } else {
-                Err(())
+                Err(FrameDecodeError::UnexpectedEof)
             }
         }
         3 => {
@@ -71,13 +89,25 @@ pub fn read_varint8(buf: &[u8]) -> Result<(u64, usize), ()> {
                 let val = u64::from_be_bytes(buf[0..8].try_into().unwrap());
                 Ok((val & (u64::MAX >> 2), 8))
             } else {
-                Err(())
+                Err(FrameDecodeError::UnexpectedEof)
             }
         }
         _ => unreachable!(),
     }
 }
 
+/// read varint8 from buffer including the 9-byte form, returning
+/// (value, size)
+pub fn read_varint8_checked(buf: &[u8]) -> Result<(u64, usize), FrameDecodeError> {
+    match buf.first() {
+        Some(&VARINT8_ESCAPE) => {
+            let val = u64::from_be_bytes(read_slice(&buf[1..], 8)?.try_into().unwrap());
+            Ok((val, 9))
+        }
+        _ => read_varint8(buf),
+    }
+}
+
 /// determine how many bytes are required to encode a varint8
 pub fn varint4_size(n: u32) -> Option<usize> {
     if n < 2u32.pow(8 - 2) {
@@ -113,9 +143,9 @@ pub fn write_varint4(buf: &mut [u8], n: u32) -> Option<usize> {
 }
 
 /// read varint4 from buffer, returning (value, size)
-pub fn read_varint4(buf: &[u8]) -> Result<(u32, usize), ()> {
+pub fn read_varint4(buf: &[u8]) -> Result<(u32, usize), FrameDecodeError> {
     if buf.is_empty() {
-        return Err(());
+        return Err(FrameDecodeError::UnexpectedEof);
     }
     let length = buf[0] >> 6;
     match length {
@@ -125,7 +155,7 @@ pub fn read_varint4(buf: &[u8]) -> Result<(u32, usize), ()> {
                 let val = u16::from_be_bytes(buf[0..2].try_into().unwrap());
                 Ok(((val & (u16::MAX >> 2)) as u32, 2))
             } else {
-                Err(())
+                Err(FrameDecodeError::UnexpectedEof)
             }
         }
         0b10 | 0b11 => {
@@ -133,13 +163,72 @@ pub fn read_varint4(buf: &[u8]) -> Result<(u32, usize), ()> {
                 let val = u32::from_be_bytes(buf[0..4].try_into().unwrap());
                 Ok((val & (u32::MAX >> 1), 4))
             } else {
-                Err(())
+                Err(FrameDecodeError::UnexpectedEof)
             }
         }
         _ => unreachable!(),
     }
 }
 
+/// get `len` bytes from start of buffer
+pub fn read_slice(buf: &[u8], len: usize) -> Result<&[u8], FrameDecodeError> {
+    buf.get(..len).ok_or(FrameDecodeError::UnexpectedEof)
+}
+
+/// read big-endian u16 from start of buffer
+pub fn read_u16(buf: &[u8]) -> Result<u16, FrameDecodeError> {
+    Ok(u16::from_be_bytes(read_slice(buf, 2)?.try_into().unwrap()))
+}
+
+/// copy `src` to `offset` into `first` followed by `second`, treating both
+/// as one buffer
+pub fn copy_split(src: &[u8], first: &mut [u8], second: &mut [u8], offset: usize) {
+    if offset >= first.len() {
+        let offset = offset - first.len();
+        second[offset..offset + src.len()].copy_from_slice(src);
+        return;
+    }
+    let split = usize::min(first.len() - offset, src.len());
+    first[offset..offset + split].copy_from_slice(&src[..split]);
+    second[..src.len() - split].copy_from_slice(&src[split..]);
+}
+
+/// remainder of `first` followed by `second` after skipping `offset` bytes
+pub fn skip_split<'a>(
+    first: &'a mut [u8],
+    second: &'a mut [u8],
+    offset: usize,
+) -> (&'a mut [u8], &'a mut [u8]) {
+    if offset <= first.len() {
+        (&mut first[offset..], second)
+    } else {
+        (&mut second[offset - first.len()..], &mut [])
+    }
+}
+
+/// write `len` bytes with `write` to `first` followed by `second`, returning
+/// what `write` returned
+///
+/// Goes through a temporary buffer only if the write does not fit in either
+/// buffer alone.
+pub fn write_split(
+    first: &mut [u8],
+    second: &mut [u8],
+    len: usize,
+    write: impl FnOnce(&mut [u8]) -> usize,
+) -> usize {
+    if len <= first.len() {
+        return write(first);
+    }
+    if first.is_empty() {
+        return write(second);
+    }
+    let mut scratch = vec![0; len];
+    let written = write(&mut scratch);
+    copy_split(&scratch[..written], first, second, 0);
+    written
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -147,34 +236,96 @@ mod test {
     #[test]
     fn varint8_test() {
         let mut buf = [0u8, 5, 5, 5, 5, 5, 5, 5];
-        assert_eq!(varint8_size(0), Some(1));
-        assert_eq!(write_varint8(&mut buf, 0), Some(1));
+        assert_eq!(varint8_size(0), 1);
+        assert_eq!(write_varint8(&mut buf, 0), 1);
         assert_eq!(buf, [0, 5, 5, 5, 5, 5, 5, 5]);
         assert_eq!(read_varint8(&buf), Ok((0, 1)));
 
-        assert_eq!(varint8_size(16), Some(1));
-        assert_eq!(write_varint8(&mut buf, 16), Some(1));
+        assert_eq!(varint8_size(16), 1);
+        assert_eq!(write_varint8(&mut buf, 16), 1);
         assert_eq!(buf, [16, 5, 5, 5, 5, 5, 5, 5]);
         assert_eq!(read_varint8(&buf), Ok((16, 1)));
 
-        assert_eq!(varint8_size(128), Some(2));
-        assert_eq!(write_varint8(&mut buf, 128), Some(2));
+        assert_eq!(varint8_size(128), 2);
+        assert_eq!(write_varint8(&mut buf, 128), 2);
         assert_eq!(buf, [64, 128, 5, 5, 5, 5, 5, 5]);
         assert_eq!(read_varint8(&buf), Ok((128, 2)));
 
-        assert_eq!(varint8_size(57_829_138), Some(4));
-        assert_eq!(write_varint8(&mut buf, 57_829_138), Some(4));
+        assert_eq!(varint8_size(57_829_138), 4);
+        assert_eq!(write_varint8(&mut buf, 57_829_138), 4);
         assert_eq!(buf, [0x83, 0x72, 0x67, 0x12, 5, 5, 5, 5]);
         assert_eq!(read_varint8(&buf), Ok((57_829_138, 4)));
 
-        assert_eq!(varint8_size(3_933_194_752_826_327_366), Some(8));
-        assert_eq!(write_varint8(&mut buf, 3_933_194_752_826_327_366), Some(8));
+        assert_eq!(varint8_size(3_933_194_752_826_327_366), 8);
+        assert_eq!(write_varint8(&mut buf, 3_933_194_752_826_327_366), 8);
         assert_eq!(buf, [0xf6, 0x95, 0x83, 0xc9, 0xea, 0xa4, 0xc1, 0x46]);
         assert_eq!(read_varint8(&buf), Ok((3_933_194_752_826_327_366, 8)));
 
-        assert_eq!(varint8_size(9_000_000_000_000_000_000), None);
+        // full range through escape
+        let mut buf = [5u8; 9];
+        assert_eq!(varint8_size(9_000_000_000_000_000_000), 9);
+        assert_eq!(write_varint8(&mut buf, 9_000_000_000_000_000_000), 9);
+        assert_eq!(buf, [0xff, 0x7c, 0xe6, 0x6c, 0x50, 0xe2, 0x84, 0x00, 0x00]);
+        assert_eq!(
+            read_varint8_checked(&buf),
+            Ok((9_000_000_000_000_000_000, 9))
+        );
+        assert_eq!(
+            read_varint8_checked(&buf[..8]),
+            Err(FrameDecodeError::UnexpectedEof)
+        );
+
+        assert_eq!(read_varint8(&[0xf6]), Err(FrameDecodeError::UnexpectedEof));
+        assert_eq!(
+            read_varint8_checked(&[0xf6]),
+            Err(FrameDecodeError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn varint8_escape_boundaries() {
+        let cases = [
+            (VARINT8_ESCAPE_MIN - 1, 8),
+            (VARINT8_ESCAPE_MIN, 9),
+            (1 << 62, 9),
+            (u64::MAX, 9),
+        ];
+        for (value, size) in cases {
+            let mut buf = [0u8; 9];
+            assert_eq!(varint8_size(value), size);
+            assert_eq!(write_varint8(&mut buf, value), size);
+            assert_eq!(read_varint8_checked(&buf[..size]), Ok((value, size)));
+            for len in 0..size {
+                assert_eq!(
+                    read_varint8_checked(&buf[..len]),
+                    Err(FrameDecodeError::UnexpectedEof)
+                );
+            }
+        }
+
+        // 8-byte form below the escape is unchanged
+        let mut buf = [0u8; 8];
+        write_varint8(&mut buf, VARINT8_ESCAPE_MIN - 1);
+        assert_eq!(buf, [0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(read_varint8(&buf), Ok((VARINT8_ESCAPE_MIN - 1, 8)));
+    }
+
+    #[test]
+    fn split_writes() {
+        let mut first = [0u8; 3];
+        let mut second = [0u8; 4];
+        copy_split(&[1, 2], &mut first, &mut second, 2);
+        copy_split(&[3], &mut first, &mut second, 4);
+        assert_eq!((first, second), ([0, 0, 1], [2, 3, 0, 0]));
 
-        assert_eq!(read_varint8(&[0xf6]), Err(()))
+        let write = |buf: &mut [u8]| write_varint8(buf, 57_829_138);
+        assert_eq!(write_split(&mut first, &mut second, 4, write), 4);
+        assert_eq!((first, second), ([0x83, 0x72, 0x67], [0x12, 3, 0, 0]));
+        let (rest_first, rest_second) = skip_split(&mut first, &mut second, 5);
+        assert_eq!((rest_first.len(), rest_second.len()), (2, 0));
+        let write = |buf: &mut [u8]| write_varint8(buf, 128);
+        assert_eq!(write_split(rest_first, rest_second, 2, write), 2);
+        assert_eq!(second, [0x12, 3, 64, 128]);
     }
 
     #[test]
@@ -207,6 +358,6 @@ mod test {
 
         assert_eq!(varint4_size(2_147_483_648), None);
 
-        assert_eq!(read_varint4(&[0xfe]), Err(()));
+        assert_eq!(read_varint4(&[0xfe]), Err(FrameDecodeError::UnexpectedEof));
     }
 }
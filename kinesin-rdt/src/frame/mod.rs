@@ -1,12 +1,34 @@
-#![allow(clippy::result_unit_err)] // todo
+pub mod ack;
 pub mod buffer_util;
+pub mod connection;
+pub mod datagram;
 pub mod encoding;
 pub mod stream;
+pub mod tagged;
 
+pub use ack::*;
+pub use connection::*;
+pub use datagram::*;
 pub use stream::*;
+pub use tagged::*;
+
+use encoding::write_split;
 
 // TODO: helpers for serialization, maybe macros?
-// TODO: graceful error handling for too-short reads
+
+/// error reading frame from buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FrameDecodeError {
+    /// buffer ended before frame was complete
+    #[error("unexpected end of buffer")]
+    UnexpectedEof,
+    /// field has an invalid value
+    #[error("invalid value in frame")]
+    InvalidValue,
+    /// frame length does not match buffer or declared length
+    #[error("frame length mismatch")]
+    LengthMismatch,
+}
 
 /// frame serialization
 pub trait Serialize {
@@ -15,7 +37,7 @@ pub trait Serialize {
     /// write frame to buffer, returning serialized length
     fn write(&self, buf: &mut [u8]) -> usize;
     /// read frame from buffer, returning frame and serialized length
-    fn read(buf: &[u8]) -> Result<(usize, Self), ()>
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError>
     where
         Self: Sized;
 
@@ -41,11 +63,17 @@ pub trait SerializeToEnd: Serialize {
     }
 
     /// read last frame of packet from buffer, returning frame
-    fn read_to_end(buf: &[u8]) -> Result<Self, ()>
+    ///
+    /// The frame must take up the entire buffer.
+    fn read_to_end(buf: &[u8]) -> Result<Self, FrameDecodeError>
     where
         Self: Sized,
     {
-        Self::read(buf).map(|r| r.1)
+        let (len, frame) = Self::read(buf)?;
+        if len != buf.len() {
+            return Err(FrameDecodeError::LengthMismatch);
+        }
+        Ok(frame)
     }
 
     /// whether the frame has special "serialize to end" behavior
@@ -56,3 +84,26 @@ pub trait SerializeToEnd: Serialize {
         true
     }
 }
+
+/// frame serialization into an output region split across two buffers, such
+/// as the free space of a ring buffer
+///
+/// The default implementations only go through a temporary buffer when the
+/// frame straddles both buffers.
+pub trait SerializeVectored: SerializeToEnd {
+    /// write frame to `first` followed by `second`, returning serialized
+    /// length
+    fn write_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        write_split(first, second, self.serialized_length(), |buf| {
+            self.write(buf)
+        })
+    }
+
+    /// write last frame of packet to `first` followed by `second`, returning
+    /// serialized length
+    fn write_to_end_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        write_split(first, second, self.serialized_length_at_end(), |buf| {
+            self.write_to_end(buf)
+        })
+    }
+}
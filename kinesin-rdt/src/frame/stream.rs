@@ -1,7 +1,9 @@
 //! Frame types for streams
 
-use super::encoding::{read_varint8, varint8_size, write_varint8};
-use super::{Serialize, SerializeToEnd};
+use super::buffer_util::{checked_read_u16, checked_read_varint8, take_slice};
+use super::encoding::{copy_split, varint8_size, write_varint8};
+use super::{FrameDecodeError, Serialize, SerializeToEnd, SerializeVectored};
+use crate::common::ring_buffer::RingBufSlice;
 
 /// stream data frame
 pub struct StreamData {
@@ -15,62 +17,49 @@ pub struct StreamData {
     pub data: Vec<u8>,
 }
 
+impl StreamData {
+    /// borrow frame without copying data
+    pub fn as_borrowed(&self) -> StreamDataRef<'_> {
+        StreamDataRef {
+            stream_id: self.stream_id,
+            stream_offset: self.stream_offset,
+            message_offset: self.message_offset,
+            data: StreamPayload::Slice(&self.data),
+        }
+    }
+}
+
 impl Serialize for StreamData {
     fn serialized_length(&self) -> usize {
-        1 + varint8_size(self.stream_id).expect("stream id out of bounds")
-            + varint8_size(self.stream_offset).expect("stream offset out of bounds")
-            + if self.message_offset.is_some() { 2 } else { 0 }
-            + 2
-            + self.data.len()
+        self.as_borrowed().serialized_length()
     }
 
     fn write(&self, buf: &mut [u8]) -> usize {
-        let mut index = 0usize;
-        let mut flags = 0u8;
-        if self.message_offset.is_some() {
-            flags |= 1;
-        }
-        buf[index] = flags;
-        index += 1;
-        index += write_varint8(&mut buf[index..], self.stream_id).expect("stream id out of bounds");
-        index += write_varint8(&mut buf[index..], self.stream_offset)
-            .expect("stream offset out of bounds");
-        let length: u16 = self
-            .data
-            .len()
-            .try_into()
-            .expect("stream data length invalid");
-        buf[index..index + 2].copy_from_slice(&length.to_be_bytes());
-        index += 2;
-        if let Some(message_offset) = self.message_offset {
-            buf[index..index + 2].copy_from_slice(&message_offset.to_be_bytes());
-            index += 2;
-        }
-        buf[index..index + length as usize].copy_from_slice(&self.data);
-        index + length as usize
+        self.as_borrowed().write(buf)
     }
 
-    fn read(buf: &[u8]) -> Result<(usize, Self), ()> {
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
         let mut index = 0usize;
-        let flags = buf[index];
+        let flags = *buf.first().ok_or(FrameDecodeError::UnexpectedEof)?;
         index += 1;
+        if flags & !1 != 0 {
+            return Err(FrameDecodeError::InvalidValue);
+        }
         let has_message_offset = flags & 1 > 0;
-        let (stream_id, len) = read_varint8(&buf[index..])?;
-        index += len;
-        let (stream_offset, len) = read_varint8(&buf[index..])?;
-        index += len;
-        let data_length = u16::from_be_bytes(buf[index..index + 2].try_into().unwrap());
-        index += 2;
+        let stream_id = checked_read_varint8(buf, &mut index)?;
+        let stream_offset = checked_read_varint8(buf, &mut index)?;
+        let data_length = checked_read_u16(buf, &mut index)?;
         let message_offset = if has_message_offset {
-            let offset = u16::from_be_bytes(buf[index..index + 2].try_into().unwrap());
-            index += 2;
-            Some(offset)
+            Some(checked_read_u16(buf, &mut index)?)
         } else {
             None
         };
-        let mut data = Vec::with_capacity(data_length as usize);
-        data.extend_from_slice(&buf[index..index + data_length as usize]);
-        index += data_length as usize;
+        let data = take_slice(buf, &mut index, data_length as usize)?.to_vec();
+        if message_offset.is_some_and(|offset| offset as usize > data.len())
+            || stream_offset.checked_add(data.len() as u64).is_none()
+        {
+            return Err(FrameDecodeError::InvalidValue);
+        }
         let frame = StreamData {
             stream_id,
             stream_offset,
@@ -83,49 +72,35 @@ impl Serialize for StreamData {
 
 impl SerializeToEnd for StreamData {
     fn serialized_length_at_end(&self) -> usize {
-        1 + varint8_size(self.stream_id).expect("stream id out of bounds")
-            + varint8_size(self.stream_offset).expect("stream offset out of bounds")
-            + if self.message_offset.is_some() { 2 } else { 0 }
-            + self.data.len()
+        self.as_borrowed().serialized_length_at_end()
     }
 
     fn write_to_end(&self, buf: &mut [u8]) -> usize {
-        let mut index = 0usize;
-        let mut flags = 0u8;
-        if self.message_offset.is_some() {
-            flags |= 1;
-        }
-        buf[index] = flags;
-        index += 1;
-        index += write_varint8(&mut buf[index..], self.stream_id).expect("stream id out of bounds");
-        index += write_varint8(&mut buf[index..], self.stream_offset)
-            .expect("stream offset out of bounds");
-        if let Some(message_offset) = self.message_offset {
-            buf[index..index + 2].copy_from_slice(&message_offset.to_be_bytes());
-            index += 2;
-        }
-        buf[index..index + self.data.len()].copy_from_slice(&self.data);
-        index + self.data.len()
+        self.as_borrowed().write_to_end(buf)
     }
 
-    fn read_to_end(buf: &[u8]) -> Result<Self, ()> {
+    fn read_to_end(buf: &[u8]) -> Result<Self, FrameDecodeError> {
         let mut index = 0usize;
-        let flags = buf[index];
+        let flags = *buf.first().ok_or(FrameDecodeError::UnexpectedEof)?;
         index += 1;
+        if flags & !1 != 0 {
+            return Err(FrameDecodeError::InvalidValue);
+        }
         let has_message_offset = flags & 1 > 0;
-        let (stream_id, len) = read_varint8(&buf[index..])?;
-        index += len;
-        let (stream_offset, len) = read_varint8(&buf[index..])?;
-        index += len;
+        let stream_id = checked_read_varint8(buf, &mut index)?;
+        let stream_offset = checked_read_varint8(buf, &mut index)?;
         let message_offset = if has_message_offset {
-            let offset = u16::from_be_bytes(buf[index..index + 2].try_into().unwrap());
-            index += 2;
-            Some(offset)
+            Some(checked_read_u16(buf, &mut index)?)
         } else {
             None
         };
-        let mut data = Vec::with_capacity(buf.len() - index);
-        data.extend_from_slice(&buf[index..]);
+        // checked reads leave index within buffer
+        let data = buf[index..].to_vec();
+        if message_offset.is_some_and(|offset| offset as usize > data.len())
+            || stream_offset.checked_add(data.len() as u64).is_none()
+        {
+            return Err(FrameDecodeError::InvalidValue);
+        }
         let frame = StreamData {
             stream_id,
             stream_offset,
@@ -136,6 +111,165 @@ impl SerializeToEnd for StreamData {
     }
 }
 
+impl SerializeVectored for StreamData {
+    fn write_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        self.as_borrowed().write_vectored(first, second)
+    }
+
+    fn write_to_end_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        self.as_borrowed().write_to_end_vectored(first, second)
+    }
+}
+
+/// data of a borrowed stream data frame
+pub enum StreamPayload<'a> {
+    /// contiguous data
+    Slice(&'a [u8]),
+    /// range of a ring buffer, possibly wrapping around
+    RingBuf(RingBufSlice<'a, u8>),
+}
+
+impl<'a> StreamPayload<'a> {
+    /// length of data
+    pub fn len(&self) -> usize {
+        match self {
+            StreamPayload::Slice(slice) => slice.len(),
+            StreamPayload::RingBuf(slice) => slice.len(),
+        }
+    }
+
+    /// whether there is no data
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// copy data to start of `buf`
+    pub fn copy_to_slice(&self, buf: &mut [u8]) {
+        match self {
+            StreamPayload::Slice(slice) => buf[..slice.len()].copy_from_slice(slice),
+            StreamPayload::RingBuf(slice) => slice.copy_to_slice(&mut buf[..slice.len()]),
+        }
+    }
+
+    /// copy data to `offset` into `first` followed by `second`
+    pub fn copy_to_split(&self, first: &mut [u8], second: &mut [u8], offset: usize) {
+        let (head, tail) = match self {
+            StreamPayload::Slice(slice) => (*slice, None),
+            StreamPayload::RingBuf(slice) => slice.as_slices(),
+        };
+        copy_split(head, first, second, offset);
+        if let Some(tail) = tail {
+            copy_split(tail, first, second, offset + head.len());
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for StreamPayload<'a> {
+    fn from(slice: &'a [u8]) -> Self {
+        StreamPayload::Slice(slice)
+    }
+}
+
+impl<'a> From<RingBufSlice<'a, u8>> for StreamPayload<'a> {
+    fn from(slice: RingBufSlice<'a, u8>) -> Self {
+        StreamPayload::RingBuf(slice)
+    }
+}
+
+/// stream data frame borrowing its data
+///
+/// Serializes identically to `StreamData`, but writes data directly from the
+/// borrowed buffer. As decoded frames cannot borrow from the packet this only
+/// provides the writing half of `Serialize` and `SerializeToEnd`.
+pub struct StreamDataRef<'a> {
+    /// stream identifier
+    pub stream_id: u64,
+    /// offset into stream
+    pub stream_offset: u64,
+    /// message start as offset into segment
+    pub message_offset: Option<u16>,
+    /// segment data
+    pub data: StreamPayload<'a>,
+}
+
+impl<'a> StreamDataRef<'a> {
+    /// serialized length
+    pub fn serialized_length(&self) -> usize {
+        self.serialized_length_at_end() + 2
+    }
+
+    /// serialized length when last in packet
+    pub fn serialized_length_at_end(&self) -> usize {
+        1 + varint8_size(self.stream_id)
+            + varint8_size(self.stream_offset)
+            + if self.message_offset.is_some() { 2 } else { 0 }
+            + self.data.len()
+    }
+
+    /// write frame, returning serialized length
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        let index = self.write_header(buf, true);
+        self.data.copy_to_slice(&mut buf[index..]);
+        index + self.data.len()
+    }
+
+    /// write frame as last in packet, returning serialized length
+    pub fn write_to_end(&self, buf: &mut [u8]) -> usize {
+        let index = self.write_header(buf, false);
+        self.data.copy_to_slice(&mut buf[index..]);
+        index + self.data.len()
+    }
+
+    /// write frame to `first` followed by `second`, returning serialized
+    /// length
+    pub fn write_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        self.write_split(first, second, true)
+    }
+
+    /// write frame as last in packet to `first` followed by `second`,
+    /// returning serialized length
+    pub fn write_to_end_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        self.write_split(first, second, false)
+    }
+
+    /// write header through a temporary buffer, then data in place
+    fn write_split(&self, first: &mut [u8], second: &mut [u8], with_length: bool) -> usize {
+        let mut header = [0u8; 23];
+        let index = self.write_header(&mut header, with_length);
+        copy_split(&header[..index], first, second, 0);
+        self.data.copy_to_split(first, second, index);
+        index + self.data.len()
+    }
+
+    /// write everything but the data, with or without the length field,
+    /// returning length written
+    fn write_header(&self, buf: &mut [u8], with_length: bool) -> usize {
+        let mut index = 0usize;
+        let mut flags = 0u8;
+        if self.message_offset.is_some() {
+            flags |= 1;
+        }
+        buf[index] = flags;
+        index += 1;
+        index += write_varint8(&mut buf[index..], self.stream_id);
+        index += write_varint8(&mut buf[index..], self.stream_offset);
+        if with_length {
+            let length: u16 = self
+                .data
+                .len()
+                .try_into()
+                .expect("stream data length invalid");
+            buf[index..index + 2].copy_from_slice(&length.to_be_bytes());
+            index += 2;
+        }
+        if let Some(message_offset) = self.message_offset {
+            buf[index..index + 2].copy_from_slice(&message_offset.to_be_bytes());
+            index += 2;
+        }
+        index
+    }
+}
+
 /// stream window limit
 pub struct StreamWindowLimit {
     /// stream identifier
@@ -146,23 +280,20 @@ pub struct StreamWindowLimit {
 
 impl Serialize for StreamWindowLimit {
     fn serialized_length(&self) -> usize {
-        varint8_size(self.stream_id).expect("stream id out of bounds")
-            + varint8_size(self.limit).expect("limit out of bounds")
+        varint8_size(self.stream_id) + varint8_size(self.limit)
     }
 
     fn write(&self, buf: &mut [u8]) -> usize {
         let mut index = 0;
-        index += write_varint8(&mut buf[index..], self.stream_id).expect("stream id out of bounds");
-        index += write_varint8(&mut buf[index..], self.limit).expect("limit out of bounds");
+        index += write_varint8(&mut buf[index..], self.stream_id);
+        index += write_varint8(&mut buf[index..], self.limit);
         index
     }
 
-    fn read(buf: &[u8]) -> Result<(usize, Self), ()> {
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
         let mut index = 0;
-        let (stream_id, len) = read_varint8(&buf[index..])?;
-        index += len;
-        let (limit, len) = read_varint8(&buf[index..])?;
-        index += len;
+        let stream_id = checked_read_varint8(buf, &mut index)?;
+        let limit = checked_read_varint8(buf, &mut index)?;
         let frame = StreamWindowLimit { stream_id, limit };
         Ok((index, frame))
     }
@@ -170,6 +301,8 @@ impl Serialize for StreamWindowLimit {
 
 impl SerializeToEnd for StreamWindowLimit {}
 
+impl SerializeVectored for StreamWindowLimit {}
+
 /// stream final offset
 pub struct StreamFinal {
     /// stream identifier
@@ -180,24 +313,20 @@ pub struct StreamFinal {
 
 impl Serialize for StreamFinal {
     fn serialized_length(&self) -> usize {
-        varint8_size(self.stream_id).expect("stream id out of bounds")
-            + varint8_size(self.final_offset).expect("limit out of bounds")
+        varint8_size(self.stream_id) + varint8_size(self.final_offset)
     }
 
     fn write(&self, buf: &mut [u8]) -> usize {
         let mut index = 0;
-        index += write_varint8(&mut buf[index..], self.stream_id).expect("stream id out of bounds");
-        index += write_varint8(&mut buf[index..], self.final_offset)
-            .expect("final_offset out of bounds");
+        index += write_varint8(&mut buf[index..], self.stream_id);
+        index += write_varint8(&mut buf[index..], self.final_offset);
         index
     }
 
-    fn read(buf: &[u8]) -> Result<(usize, Self), ()> {
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
         let mut index = 0;
-        let (stream_id, len) = read_varint8(&buf[index..])?;
-        index += len;
-        let (final_offset, len) = read_varint8(&buf[index..])?;
-        index += len;
+        let stream_id = checked_read_varint8(buf, &mut index)?;
+        let final_offset = checked_read_varint8(buf, &mut index)?;
         let frame = StreamFinal {
             stream_id,
             final_offset,
@@ -208,6 +337,44 @@ impl Serialize for StreamFinal {
 
 impl SerializeToEnd for StreamFinal {}
 
+impl SerializeVectored for StreamFinal {}
+
+/// abrupt termination of stream by sender
+pub struct StreamReset {
+    /// stream identifier
+    pub stream_id: u64,
+    /// application-defined reason for reset
+    pub error_code: u64,
+}
+
+impl Serialize for StreamReset {
+    fn serialized_length(&self) -> usize {
+        varint8_size(self.stream_id) + varint8_size(self.error_code)
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        let mut index = 0;
+        index += write_varint8(&mut buf[index..], self.stream_id);
+        index += write_varint8(&mut buf[index..], self.error_code);
+        index
+    }
+
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
+        let mut index = 0;
+        let stream_id = checked_read_varint8(buf, &mut index)?;
+        let error_code = checked_read_varint8(buf, &mut index)?;
+        let frame = StreamReset {
+            stream_id,
+            error_code,
+        };
+        Ok((index, frame))
+    }
+}
+
+impl SerializeToEnd for StreamReset {}
+
+impl SerializeVectored for StreamReset {}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -228,6 +395,36 @@ mod test {
         assert_eq!(frame.stream_offset, frame2.stream_offset);
         assert_eq!(frame.message_offset, frame2.message_offset);
         assert_eq!(frame.data, frame2.data);
+
+        for truncated in 0..length {
+            assert_eq!(
+                StreamData::read(&buf[..truncated]).err(),
+                Some(FrameDecodeError::UnexpectedEof)
+            );
+        }
+        // message offset past end of data
+        assert_eq!(
+            StreamData::read(&[1, 0, 0, 0, 0, 0, 1]).err(),
+            Some(FrameDecodeError::InvalidValue)
+        );
+
+        // offsets use the full u64 range, but the end must not overflow
+        let frame = StreamData {
+            stream_id: 1,
+            stream_offset: u64::MAX - 2,
+            message_offset: None,
+            data: vec![1, 2],
+        };
+        let length = frame.serialized_length();
+        let mut buf = vec![0; length];
+        assert_eq!(frame.write(&mut buf), length);
+        let (_, frame2) = StreamData::read(&buf).unwrap();
+        assert_eq!(frame2.stream_offset, u64::MAX - 2);
+        buf[3..11].copy_from_slice(&(u64::MAX - 1).to_be_bytes());
+        assert_eq!(
+            StreamData::read(&buf).err(),
+            Some(FrameDecodeError::InvalidValue)
+        );
     }
 
     #[test]
@@ -243,5 +440,46 @@ mod test {
         assert_eq!(length, length2);
         assert_eq!(frame.stream_id, frame2.stream_id);
         assert_eq!(frame.limit, frame2.limit);
+
+        for truncated in 0..length {
+            assert_eq!(
+                StreamWindowLimit::read(&buf[..truncated]).err(),
+                Some(FrameDecodeError::UnexpectedEof)
+            );
+        }
+    }
+
+    #[test]
+    fn stream_final_reset() {
+        let frame = StreamFinal {
+            stream_id: 7,
+            final_offset: 1 << 40,
+        };
+        let length = frame.serialized_length();
+        let mut buf = vec![0; length];
+        assert_eq!(frame.write(&mut buf), length);
+        let (length2, frame2) = StreamFinal::read(&buf).unwrap();
+        assert_eq!(length, length2);
+        assert_eq!(frame.stream_id, frame2.stream_id);
+        assert_eq!(frame.final_offset, frame2.final_offset);
+
+        let frame = StreamReset {
+            stream_id: 7,
+            error_code: 300,
+        };
+        let length = frame.serialized_length();
+        let mut buf = vec![0; length];
+        assert_eq!(frame.write(&mut buf), length);
+        let (length2, frame2) = StreamReset::read(&buf).unwrap();
+        assert_eq!(length, length2);
+        assert_eq!(frame.stream_id, frame2.stream_id);
+        assert_eq!(frame.error_code, frame2.error_code);
+
+        for truncated in 0..length {
+            assert_eq!(
+                StreamReset::read(&buf[..truncated]).err(),
+                Some(FrameDecodeError::UnexpectedEof)
+            );
+        }
     }
 }
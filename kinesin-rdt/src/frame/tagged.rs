@@ -0,0 +1,347 @@
+//! Type-tagged frames, allowing different frame types to share a packet
+//!
+//! Each frame is prefixed with a varint8 tag of `(frame type << 1) | at_end`.
+//! The `at_end` bit is only set for the last frame of a packet when the frame
+//! type has an end optimization, in which case the frame extends to the end of
+//! the packet.
+
+use super::buffer_util::checked_read_varint8;
+use super::encoding::{copy_split, skip_split, varint8_size, write_varint8};
+use super::{
+    AckRanges, CompressedAckRanges, ConnectionAccept, ConnectionClose, ConnectionInit, DataBlocked,
+    Datagram, FrameDecodeError, MaxData, Ping, Pong, Serialize, SerializeToEnd, SerializeVectored,
+    StreamData, StreamFinal, StreamReset, StreamWindowLimit,
+};
+
+macro_rules! frame_types {
+    ($($name:ident = $value:literal => $end:literal,)*) => {
+        /// frame type identifier
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum FrameType {
+            $($name = $value,)*
+        }
+
+        impl FrameType {
+            /// get frame type from its numeric value
+            pub fn from_value(value: u64) -> Option<FrameType> {
+                match value {
+                    $($value => Some(FrameType::$name),)*
+                    _ => None,
+                }
+            }
+
+            /// whether frames of this type are shorter when last in a packet
+            pub fn has_end_optimization(self) -> bool {
+                match self {
+                    $(FrameType::$name => $end,)*
+                }
+            }
+        }
+
+        /// frame of any type
+        pub enum Frame {
+            $($name($name),)*
+        }
+
+        impl Frame {
+            /// type of this frame
+            pub fn frame_type(&self) -> FrameType {
+                match self {
+                    $(Frame::$name(_) => FrameType::$name,)*
+                }
+            }
+
+            /// serialized length of frame body, excluding tag
+            fn body_length(&self, at_end: bool) -> usize {
+                match self {
+                    $(Frame::$name(f) if at_end => f.serialized_length_at_end(),)*
+                    $(Frame::$name(f) => f.serialized_length(),)*
+                }
+            }
+
+            /// write frame body, excluding tag
+            fn write_body(&self, buf: &mut [u8], at_end: bool) -> usize {
+                match self {
+                    $(Frame::$name(f) if at_end => f.write_to_end(buf),)*
+                    $(Frame::$name(f) => f.write(buf),)*
+                }
+            }
+
+            /// write frame body to `first` followed by `second`, excluding tag
+            fn write_body_vectored(&self, first: &mut [u8], second: &mut [u8], at_end: bool) -> usize {
+                match self {
+                    $(Frame::$name(f) if at_end => f.write_to_end_vectored(first, second),)*
+                    $(Frame::$name(f) => f.write_vectored(first, second),)*
+                }
+            }
+
+            /// read frame body of the given type, returning serialized length
+            /// and frame
+            ///
+            /// If `at_end` is set, the frame extends to the end of the buffer.
+            pub fn read_body(
+                frame_type: FrameType,
+                buf: &[u8],
+                at_end: bool,
+            ) -> Result<(usize, Frame), FrameDecodeError> {
+                match frame_type {
+                    $(FrameType::$name if at_end => {
+                        $name::read_to_end(buf).map(|f| (buf.len(), Frame::$name(f)))
+                    })*
+                    $(FrameType::$name => $name::read(buf).map(|(len, f)| (len, Frame::$name(f))),)*
+                }
+            }
+        }
+
+        $(
+            impl From<$name> for Frame {
+                fn from(frame: $name) -> Frame {
+                    Frame::$name(frame)
+                }
+            }
+        )*
+    };
+}
+
+frame_types! {
+    StreamData = 1 => true,
+    StreamWindowLimit = 2 => false,
+    StreamFinal = 3 => false,
+    AckRanges = 4 => false,
+    ConnectionInit = 5 => false,
+    ConnectionAccept = 6 => false,
+    ConnectionClose = 7 => false,
+    Ping = 8 => false,
+    Pong = 9 => false,
+    Datagram = 10 => true,
+    StreamReset = 11 => false,
+    MaxData = 12 => false,
+    DataBlocked = 13 => false,
+    CompressedAckRanges = 14 => false,
+}
+
+impl FrameType {
+    /// tag value identifying this frame type, possibly at the end of a packet
+    pub fn tag(self, at_end: bool) -> u64 {
+        let at_end = at_end && self.has_end_optimization();
+        (self as u64) << 1 | at_end as u64
+    }
+
+    /// split tag into frame type and `at_end` flag
+    pub fn from_tag(tag: u64) -> Option<(FrameType, bool)> {
+        let frame_type = FrameType::from_value(tag >> 1)?;
+        let at_end = tag & 1 > 0;
+        if at_end && !frame_type.has_end_optimization() {
+            return None;
+        }
+        Some((frame_type, at_end))
+    }
+}
+
+impl Frame {
+    /// serialized length including tag
+    pub fn encoded_length(&self) -> usize {
+        let tag = self.frame_type().tag(false);
+        varint8_size(tag) + self.body_length(false)
+    }
+
+    /// serialized length including tag when last in packet
+    pub fn encoded_length_at_end(&self) -> usize {
+        let tag = self.frame_type().tag(true);
+        varint8_size(tag) + self.body_length(true)
+    }
+
+    /// write tag and frame, returning serialized length
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let index = write_varint8(buf, self.frame_type().tag(false));
+        index + self.write_body(&mut buf[index..], false)
+    }
+
+    /// write tag and frame as last frame of packet, returning serialized length
+    pub fn encode_to_end(&self, buf: &mut [u8]) -> usize {
+        let index = write_varint8(buf, self.frame_type().tag(true));
+        index + self.write_body(&mut buf[index..], true)
+    }
+
+    /// write tag and frame to `first` followed by `second`, returning
+    /// serialized length
+    pub fn encode_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        self.encode_split(first, second, false)
+    }
+
+    /// write tag and frame as last frame of packet to `first` followed by
+    /// `second`, returning serialized length
+    pub fn encode_to_end_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        self.encode_split(first, second, true)
+    }
+
+    /// write tag and frame to split buffers
+    fn encode_split(&self, first: &mut [u8], second: &mut [u8], at_end: bool) -> usize {
+        let mut tag = [0u8; 9];
+        let index = write_varint8(&mut tag, self.frame_type().tag(at_end));
+        copy_split(&tag[..index], first, second, 0);
+        let (first, second) = skip_split(first, second, index);
+        index + self.write_body_vectored(first, second, at_end)
+    }
+}
+
+/// frames with tag, dispatching on the tag when read
+impl Serialize for Frame {
+    fn serialized_length(&self) -> usize {
+        self.encoded_length()
+    }
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        self.encode(buf)
+    }
+
+    fn read(buf: &[u8]) -> Result<(usize, Self), FrameDecodeError> {
+        let mut index = 0;
+        let tag = checked_read_varint8(buf, &mut index)?;
+        let (frame_type, at_end) =
+            FrameType::from_tag(tag).ok_or(FrameDecodeError::InvalidValue)?;
+        let (len, frame) = Frame::read_body(frame_type, &buf[index..], at_end)?;
+        Ok((index + len, frame))
+    }
+
+    fn has_end_optimization() -> bool {
+        true
+    }
+}
+
+impl SerializeToEnd for Frame {
+    fn serialized_length_at_end(&self) -> usize {
+        self.encoded_length_at_end()
+    }
+
+    fn write_to_end(&self, buf: &mut [u8]) -> usize {
+        self.encode_to_end(buf)
+    }
+
+    fn has_end_optimization() -> bool {
+        true
+    }
+}
+
+impl SerializeVectored for Frame {
+    fn write_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        self.encode_vectored(first, second)
+    }
+
+    fn write_to_end_vectored(&self, first: &mut [u8], second: &mut [u8]) -> usize {
+        self.encode_to_end_vectored(first, second)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let frame: Frame = StreamData {
+            stream_id: 1,
+            stream_offset: 0,
+            message_offset: None,
+            data: vec![1, 2, 3],
+        }
+        .into();
+        assert_eq!(frame.encoded_length(), frame.encoded_length_at_end() + 2);
+        let mut buf = vec![0; frame.encoded_length_at_end()];
+        assert_eq!(frame.encode_to_end(&mut buf), buf.len());
+        assert_eq!(
+            FrameType::from_tag(buf[0] as u64),
+            Some((FrameType::StreamData, true))
+        );
+
+        // types without end optimization never set the flag
+        let frame: Frame = Ping { sequence: 5 }.into();
+        assert_eq!(frame.encoded_length(), frame.encoded_length_at_end());
+        assert_eq!(FrameType::Ping.tag(true), FrameType::Ping.tag(false));
+        assert_eq!(FrameType::from_tag(FrameType::Ping.tag(false) | 1), None);
+    }
+
+    #[test]
+    fn serialize() {
+        let frame: Frame = StreamWindowLimit {
+            stream_id: 4,
+            limit: 65536,
+        }
+        .into();
+        let mut buf = vec![0; frame.serialized_length()];
+        assert_eq!(Serialize::write(&frame, &mut buf), buf.len());
+        let (len, read) = <Frame as Serialize>::read(&buf).unwrap();
+        assert_eq!(len, buf.len());
+        let Frame::StreamWindowLimit(read) = read else {
+            panic!("wrong frame type");
+        };
+        assert_eq!((read.stream_id, read.limit), (4, 65536));
+
+        // frame with end optimization read back with read_to_end
+        let frame: Frame = Datagram {
+            data: vec![1, 2, 3],
+        }
+        .into();
+        let mut buf = vec![0; frame.serialized_length_at_end()];
+        assert_eq!(frame.write_to_end(&mut buf), buf.len());
+        let Ok(Frame::Datagram(read)) = Frame::read_to_end(&buf) else {
+            panic!("failed to read datagram");
+        };
+        assert_eq!(read.data, [1, 2, 3]);
+
+        assert_eq!(
+            <Frame as Serialize>::read(&[0x3f]).err(),
+            Some(FrameDecodeError::InvalidValue)
+        );
+        assert_eq!(
+            <Frame as Serialize>::read(&[]).err(),
+            Some(FrameDecodeError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn vectored() {
+        let frames: [Frame; 3] = [
+            StreamData {
+                stream_id: 300,
+                stream_offset: 70000,
+                message_offset: Some(2),
+                data: vec![1, 2, 3, 4, 5],
+            }
+            .into(),
+            StreamWindowLimit {
+                stream_id: 4,
+                limit: 65536,
+            }
+            .into(),
+            Datagram {
+                data: vec![1, 2, 3],
+            }
+            .into(),
+        ];
+        // identical to contiguous writes at every split point
+        for frame in &frames {
+            for at_end in [false, true] {
+                let (length, mut expected) = if at_end {
+                    let mut buf = vec![0; frame.encoded_length_at_end()];
+                    (frame.encode_to_end(&mut buf), buf)
+                } else {
+                    let mut buf = vec![0; frame.encoded_length()];
+                    (frame.encode(&mut buf), buf)
+                };
+                expected.push(0);
+                for split in 0..=length + 1 {
+                    let mut buf = vec![0; length + 1];
+                    let (first, second) = buf.split_at_mut(split);
+                    let written = if at_end {
+                        frame.write_to_end_vectored(first, second)
+                    } else {
+                        frame.write_vectored(first, second)
+                    };
+                    assert_eq!(written, length);
+                    assert_eq!(buf, expected);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Fuzzing support
+//!
+//! `Arbitrary` implementations for frames, generating only frames which can
+//! be serialized (stream data within u16 length and u64 offsets), and
+//! checks shared by the fuzz targets in `fuzz/`. Every check must hold for
+//! arbitrary input; any panic is a bug.
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::frame::encoding::{read_varint4, read_varint8_checked, write_varint4, write_varint8};
+use crate::frame::{
+    AckRanges, CompressedAckRanges, ConnectionAccept, ConnectionClose, ConnectionInit, DataBlocked,
+    Datagram, Frame, FrameType, MaxData, Ping, Pong, Serialize, SerializeToEnd, StreamData,
+    StreamFinal, StreamReset, StreamWindowLimit,
+};
+use crate::packet::{FrameReader, Packet, PacketBuilder};
+use crate::stream::container::StreamSet;
+
+/// largest number of ranges in generated AckRanges frames
+const MAX_ACK_RANGES: usize = 8;
+/// flow control window of streams in `check_decode_packet`
+const FUZZ_WINDOW: u64 = 1 << 16;
+
+/// generate varint8 value
+pub fn arbitrary_varint(u: &mut Unstructured<'_>) -> Result<u64> {
+    u.arbitrary()
+}
+
+/// generate connection parameter list
+fn arbitrary_parameters(u: &mut Unstructured<'_>) -> Result<Vec<(u64, Vec<u8>)>> {
+    let count = u.arbitrary_len::<(u64, Vec<u8>)>()?;
+    (0..count)
+        .map(|_| Ok((arbitrary_varint(u)?, u.arbitrary()?)))
+        .collect()
+}
+
+macro_rules! impl_arbitrary_varints {
+    ($($frame:ident { $($field:ident),* },)*) => {
+        $(
+            impl<'a> Arbitrary<'a> for $frame {
+                fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+                    Ok($frame {
+                        $($field: arbitrary_varint(u)?,)*
+                    })
+                }
+            }
+        )*
+    };
+}
+
+impl_arbitrary_varints! {
+    StreamWindowLimit { stream_id, limit },
+    StreamFinal { stream_id, final_offset },
+    StreamReset { stream_id, error_code },
+    Ping { sequence },
+    Pong { sequence },
+    MaxData { limit },
+    DataBlocked { limit },
+}
+
+impl<'a> Arbitrary<'a> for StreamData {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let stream_id = arbitrary_varint(u)?;
+        let mut data: Vec<u8> = u.arbitrary()?;
+        data.truncate(u16::MAX as usize);
+        let stream_offset = u.int_in_range(0..=u64::MAX - data.len() as u64)?;
+        let message_offset = if u.arbitrary()? {
+            Some(u.int_in_range(0..=data.len() as u16)?)
+        } else {
+            None
+        };
+        Ok(StreamData {
+            stream_id,
+            stream_offset,
+            message_offset,
+            data,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for AckRanges {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let stream_id = arbitrary_varint(u)?;
+        let count = u.int_in_range(1..=MAX_ACK_RANGES)?;
+        // build ascending with nonzero gaps and lengths, bounded well below
+        // the varint limit
+        let mut ranges = Vec::with_capacity(count);
+        let mut position = u64::from(u.arbitrary::<u32>()?);
+        for _ in 0..count {
+            let start = position + u64::from(u.int_in_range(1..=u32::MAX)?);
+            let end = start + u64::from(u.int_in_range(1..=u32::MAX)?);
+            ranges.push(start..end);
+            position = end;
+        }
+        ranges.reverse();
+        Ok(AckRanges { stream_id, ranges })
+    }
+}
+
+impl<'a> Arbitrary<'a> for CompressedAckRanges {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let stream_id = arbitrary_varint(u)?;
+        // high enough that the runs below never reach zero
+        let end = (1 << 32) + u64::from(u.arbitrary::<u32>()?);
+        let first = end - u64::from(u.int_in_range(1..=u16::MAX)?)..end;
+        let mut ranges = vec![first];
+        // repeat pairs so runs form
+        for _ in 0..u.int_in_range(0..=MAX_ACK_RANGES)? {
+            let gap = u64::from(u.int_in_range(1..=u16::MAX)?);
+            let length = u64::from(u.int_in_range(1..=u16::MAX)?);
+            for _ in 0..u.int_in_range(1..=MAX_ACK_RANGES)? {
+                let end = ranges.last().unwrap().start - gap;
+                ranges.push(end - length..end);
+            }
+        }
+        Ok(CompressedAckRanges(AckRanges { stream_id, ranges }))
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnectionInit {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(ConnectionInit {
+            version: arbitrary_varint(u)?,
+            parameters: arbitrary_parameters(u)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnectionAccept {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(ConnectionAccept {
+            version: arbitrary_varint(u)?,
+            parameters: arbitrary_parameters(u)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for ConnectionClose {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(ConnectionClose {
+            error_code: arbitrary_varint(u)?,
+            reason: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Datagram {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Datagram {
+            data: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Frame {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let frame_type = FrameType::from_value(u.int_in_range(1..=14)?).unwrap();
+        Ok(match frame_type {
+            FrameType::StreamData => Frame::StreamData(u.arbitrary()?),
+            FrameType::StreamWindowLimit => Frame::StreamWindowLimit(u.arbitrary()?),
+            FrameType::StreamFinal => Frame::StreamFinal(u.arbitrary()?),
+            FrameType::AckRanges => Frame::AckRanges(u.arbitrary()?),
+            FrameType::ConnectionInit => Frame::ConnectionInit(u.arbitrary()?),
+            FrameType::ConnectionAccept => Frame::ConnectionAccept(u.arbitrary()?),
+            FrameType::ConnectionClose => Frame::ConnectionClose(u.arbitrary()?),
+            FrameType::Ping => Frame::Ping(u.arbitrary()?),
+            FrameType::Pong => Frame::Pong(u.arbitrary()?),
+            FrameType::Datagram => Frame::Datagram(u.arbitrary()?),
+            FrameType::StreamReset => Frame::StreamReset(u.arbitrary()?),
+            FrameType::MaxData => Frame::MaxData(u.arbitrary()?),
+            FrameType::DataBlocked => Frame::DataBlocked(u.arbitrary()?),
+            FrameType::CompressedAckRanges => Frame::CompressedAckRanges(u.arbitrary()?),
+        })
+    }
+}
+
+/// decoded varints must fit the input and survive re-encoding
+pub fn check_varint(data: &[u8]) {
+    if let Ok((value, len)) = read_varint8_checked(data) {
+        assert!(len <= data.len());
+        let mut buf = [0; 9];
+        let written = write_varint8(&mut buf, value);
+        assert_eq!(read_varint8_checked(&buf[..written]), Ok((value, written)));
+    }
+    if let Ok((value, len)) = read_varint4(data) {
+        assert!(len <= data.len());
+        let mut buf = [0; 4];
+        let written = write_varint4(&mut buf, value).expect("decoded varint4 out of bounds");
+        assert_eq!(read_varint4(&buf[..written]), Ok((value, written)));
+    }
+}
+
+/// decoded stream data frames must fit the input and survive re-encoding
+pub fn check_stream_data(data: &[u8]) {
+    let check = |frame: StreamData, at_end: bool| {
+        let decoded = if at_end {
+            let mut buf = vec![0; frame.serialized_length_at_end()];
+            let written = frame.write_to_end(&mut buf);
+            StreamData::read_to_end(&buf[..written]).expect("re-encoded frame invalid")
+        } else {
+            let mut buf = vec![0; frame.serialized_length()];
+            let written = frame.write(&mut buf);
+            let (len, decoded) =
+                StreamData::read(&buf[..written]).expect("re-encoded frame invalid");
+            assert_eq!(len, written);
+            decoded
+        };
+        assert_eq!(decoded.stream_id, frame.stream_id);
+        assert_eq!(decoded.stream_offset, frame.stream_offset);
+        assert_eq!(decoded.message_offset, frame.message_offset);
+        assert_eq!(decoded.data, frame.data);
+    };
+    if let Ok((len, frame)) = StreamData::read(data) {
+        assert!(len <= data.len());
+        check(frame, false);
+    }
+    if let Ok(frame) = StreamData::read_to_end(data) {
+        check(frame, true);
+    }
+}
+
+/// read all frames of a packet and apply them to a fresh set of streams
+pub fn check_decode_packet(data: &[u8]) {
+    let mut streams = StreamSet::new(false, FUZZ_WINDOW);
+    let mut reader = FrameReader::new(data);
+    for frame in &mut reader {
+        let Ok(frame) = frame else {
+            break;
+        };
+        let _ = streams.route_frame(&frame);
+    }
+    assert!(reader.offset() <= data.len());
+    while streams.poll_event().is_some() {}
+    let stream_ids: Vec<u64> = streams.streams.keys().copied().collect();
+    for stream_id in stream_ids {
+        let inbound = &mut streams.get_mut(stream_id).unwrap().inbound;
+        while inbound.read_message().is_some() {}
+        if let Some(slice) = inbound.read_next(usize::MAX) {
+            let new_base = inbound.buffer_offset + slice.len() as u64;
+            inbound.advance_buffer(new_base);
+        }
+    }
+}
+
+/// assemble frames into a packet
+fn assemble(frames: &[Frame]) -> Packet {
+    let size = frames.iter().map(|frame| frame.encoded_length()).sum();
+    let mut builder = PacketBuilder::new(size);
+    for frame in frames {
+        if builder.push(clone_frame(frame)).is_err() {
+            panic!("frame does not fit in packet sized for it");
+        }
+    }
+    builder.finish()
+}
+
+/// copy frame by encoding and decoding it
+fn clone_frame(frame: &Frame) -> Frame {
+    let mut buf = vec![0; frame.encoded_length()];
+    let len = frame.encode(&mut buf);
+    let mut reader = FrameReader::new(&buf[..len]);
+    match reader.next() {
+        Some(Ok(frame)) => frame,
+        _ => panic!("encoded {:?} frame does not decode", frame.frame_type()),
+    }
+}
+
+/// frames assembled into a packet must decode to frames which assemble into
+/// the same packet
+pub fn check_round_trip(frames: &[Frame]) {
+    let packet = assemble(frames);
+    let decoded: Vec<Frame> = FrameReader::new(&packet.data)
+        .collect::<std::result::Result<_, _>>()
+        .expect("assembled packet does not decode");
+    assert_eq!(decoded.len(), frames.len());
+    for (decoded, frame) in decoded.iter().zip(frames) {
+        assert_eq!(decoded.frame_type(), frame.frame_type());
+    }
+    assert_eq!(assemble(&decoded).data, packet.data);
+}
+
+/// generate frames from arbitrary bytes and check their round trip
+pub fn check_frames_round_trip(data: &[u8]) {
+    let mut u = Unstructured::new(data);
+    if let Ok(frames) = Vec::<Frame>::arbitrary(&mut u) {
+        check_round_trip(&frames);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// deterministic pseudo-random bytes
+    fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn arbitrary_inputs() {
+        for seed in 0..500 {
+            let data = random_bytes(seed, seed as usize % 200);
+            check_varint(&data);
+            check_stream_data(&data);
+            check_decode_packet(&data);
+            check_frames_round_trip(&data);
+        }
+    }
+
+    #[test]
+    fn empty_stream_data() {
+        // empty segments used to be recorded as zero-length received ranges
+        let frame = Frame::StreamData(StreamData {
+            stream_id: 1,
+            stream_offset: 5,
+            message_offset: None,
+            data: Vec::new(),
+        });
+        let mut buf = vec![0; frame.encoded_length()];
+        let len = frame.encode(&mut buf);
+        check_decode_packet(&buf[..len]);
+        check_round_trip(&[frame]);
+    }
+}
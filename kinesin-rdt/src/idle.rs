@@ -0,0 +1,216 @@
+//! Keep-alive and idle timeout
+//!
+//! The connection is closed once nothing was received from the peer for the
+//! idle timeout. To keep an otherwise quiet connection (and any NAT bindings
+//! along the path) alive, a Ping frame is sent whenever nothing was sent for
+//! the keep-alive interval. The peer answers with a Pong, which in turn
+//! restarts the idle timeout. Either mechanism can be disabled.
+
+use std::time::{Duration, Instant};
+
+use tracing::{debug, trace};
+
+use crate::common::timer::{TimerId, TimerQueue};
+use crate::frame::Ping;
+
+/// default time without received packets before the connection is closed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// default time without sent packets before a Ping is sent
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// timer events of the idle tracker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IdleTimer {
+    /// nothing was sent for the keep-alive interval
+    KeepAlive,
+    /// nothing was received for the idle timeout
+    IdleTimeout,
+}
+
+/// action required after timers expired
+pub enum IdleEvent {
+    /// send ping to keep the connection alive
+    KeepAlive(Ping),
+    /// peer was silent for the idle timeout, connection should be closed
+    Timeout,
+}
+
+/// tracks connection activity for keep-alive and idle timeout
+pub struct IdleTracker {
+    /// time without received packets before the connection is closed
+    idle_timeout: Option<Duration>,
+    /// time without sent packets before a Ping is sent
+    keep_alive_interval: Option<Duration>,
+    /// armed timers
+    timers: TimerQueue<IdleTimer>,
+    /// armed idle timeout
+    idle_timer: Option<TimerId>,
+    /// armed keep-alive timer
+    keep_alive_timer: Option<TimerId>,
+    /// sequence number of next keep-alive ping
+    next_ping_sequence: u64,
+    /// whether the idle timeout expired
+    timed_out: bool,
+}
+
+impl IdleTracker {
+    /// create new instance, treating `now` as the time of last activity
+    pub fn new(
+        idle_timeout: Option<Duration>,
+        keep_alive_interval: Option<Duration>,
+        now: Instant,
+    ) -> IdleTracker {
+        let mut tracker = IdleTracker {
+            idle_timeout,
+            keep_alive_interval,
+            timers: TimerQueue::new(),
+            idle_timer: None,
+            keep_alive_timer: None,
+            next_ping_sequence: 0,
+            timed_out: false,
+        };
+        tracker.restart_idle_timer(now);
+        tracker.restart_keep_alive_timer(now);
+        tracker
+    }
+
+    /// time without received packets before the connection is closed
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// time without sent packets before a Ping is sent
+    pub fn keep_alive_interval(&self) -> Option<Duration> {
+        self.keep_alive_interval
+    }
+
+    /// set idle timeout, or None to disable, counting from `now`
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>, now: Instant) {
+        self.idle_timeout = idle_timeout;
+        self.restart_idle_timer(now);
+    }
+
+    /// set keep-alive interval, or None to disable, counting from `now`
+    pub fn set_keep_alive_interval(&mut self, interval: Option<Duration>, now: Instant) {
+        self.keep_alive_interval = interval;
+        self.restart_keep_alive_timer(now);
+    }
+
+    /// whether the idle timeout expired
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// record packet received from peer
+    pub fn on_packet_received(&mut self, now: Instant) {
+        self.restart_idle_timer(now);
+    }
+
+    /// record packet sent to peer
+    pub fn on_packet_sent(&mut self, now: Instant) {
+        self.restart_keep_alive_timer(now);
+    }
+
+    /// earliest time `poll` should be called
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    /// handle expired timers
+    ///
+    /// Returns `Timeout` once the idle timeout expires and on every call
+    /// after, otherwise `KeepAlive` with the ping to send if the keep-alive
+    /// interval expired.
+    pub fn poll(&mut self, now: Instant) -> Option<IdleEvent> {
+        let mut keep_alive = false;
+        for timer in self.timers.poll(now) {
+            match timer {
+                IdleTimer::IdleTimeout => {
+                    debug!("idle timeout expired");
+                    self.idle_timer = None;
+                    self.timed_out = true;
+                }
+                IdleTimer::KeepAlive => {
+                    self.keep_alive_timer = None;
+                    keep_alive = true;
+                }
+            }
+        }
+        if self.timed_out {
+            return Some(IdleEvent::Timeout);
+        }
+        if !keep_alive {
+            return None;
+        }
+        let sequence = self.next_ping_sequence;
+        self.next_ping_sequence += 1;
+        trace!(sequence, "sending keep-alive");
+        // sending the ping restarts the timer
+        self.restart_keep_alive_timer(now);
+        Some(IdleEvent::KeepAlive(Ping { sequence }))
+    }
+
+    /// rearm idle timeout to expire one timeout after `now`
+    fn restart_idle_timer(&mut self, now: Instant) {
+        if let Some(id) = self.idle_timer.take() {
+            self.timers.cancel(id);
+        }
+        if let Some(timeout) = self.idle_timeout {
+            if !self.timed_out {
+                let id = self.timers.arm(now + timeout, IdleTimer::IdleTimeout);
+                self.idle_timer = Some(id);
+            }
+        }
+    }
+
+    /// rearm keep-alive timer to expire one interval after `now`
+    fn restart_keep_alive_timer(&mut self, now: Instant) {
+        if let Some(id) = self.keep_alive_timer.take() {
+            self.timers.cancel(id);
+        }
+        if let Some(interval) = self.keep_alive_interval {
+            let id = self.timers.arm(now + interval, IdleTimer::KeepAlive);
+            self.keep_alive_timer = Some(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keep_alive_and_timeout() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut idle = IdleTracker::new(Some(ms(100)), Some(ms(30)), start);
+        assert_eq!(idle.next_deadline(), Some(start + ms(30)));
+        assert!(idle.poll(start + ms(20)).is_none());
+
+        // sending postpones keep-alive
+        idle.on_packet_sent(start + ms(20));
+        assert!(idle.poll(start + ms(30)).is_none());
+        assert!(matches!(
+            idle.poll(start + ms(50)),
+            Some(IdleEvent::KeepAlive(Ping { sequence: 0 }))
+        ));
+        assert_eq!(idle.next_deadline(), Some(start + ms(80)));
+
+        // receiving postpones timeout
+        idle.on_packet_received(start + ms(60));
+        idle.set_keep_alive_interval(None, start + ms(60));
+        assert_eq!(idle.next_deadline(), Some(start + ms(160)));
+        assert!(idle.poll(start + ms(150)).is_none());
+        assert!(matches!(
+            idle.poll(start + ms(160)),
+            Some(IdleEvent::Timeout)
+        ));
+        assert!(idle.timed_out());
+        idle.on_packet_received(start + ms(170));
+        assert_eq!(idle.next_deadline(), None);
+        assert!(matches!(
+            idle.poll(start + ms(170)),
+            Some(IdleEvent::Timeout)
+        ));
+    }
+}
@@ -1,4 +1,12 @@
 pub mod reliability;
 pub mod stream;
 pub mod common;
+pub mod datagram;
 pub mod frame;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+pub mod idle;
+#[cfg(feature = "tokio")]
+pub mod net;
+pub mod mtu;
+pub mod packet;
@@ -0,0 +1,217 @@
+//! Path MTU discovery
+//!
+//! Packets start out at `BASE_MTU`, which every path is assumed to carry.
+//! Larger sizes are probed with padded probe packets, first at the maximum
+//! size and then by binary search between the confirmed size and the smallest
+//! size found not to fit. Any packet larger than the confirmed size counts as
+//! a probe: acknowledgment confirms its size, and once `MAX_PROBES` probes of
+//! a size are lost that size becomes the new upper bound. If
+//! `BLACK_HOLE_THRESHOLD` consecutive packets larger than `BASE_MTU` but not
+//! larger than the confirmed size are lost, the path is assumed to have
+//! stopped carrying them and the size steps back down to `BASE_MTU`.
+
+use tracing::{debug, trace};
+
+use crate::frame::Ping;
+use crate::packet::{Packet, PacketBuilder};
+
+/// packet size assumed to be supported by every path
+pub const BASE_MTU: usize = 1200;
+/// default maximum packet size probed, ethernet MTU less IPv6 and UDP headers
+pub const DEFAULT_MAX_MTU: usize = 1500 - 40 - 8;
+/// lost probes of a size before it is considered too large
+pub const MAX_PROBES: u32 = 3;
+/// consecutive lost packets of confirmed size before stepping back down
+pub const BLACK_HOLE_THRESHOLD: u32 = 3;
+/// search stops once the confirmed size is this close to the upper bound
+pub const SEARCH_GRANULARITY: usize = 16;
+
+/// path MTU discovery state
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MtuDiscovery {
+    /// largest packet size confirmed to reach the peer
+    confirmed: usize,
+    /// smallest packet size found not to reach the peer
+    upper_bound: Option<usize>,
+    /// largest packet size probed
+    max_mtu: usize,
+    /// size of probe in flight
+    probe_in_flight: Option<usize>,
+    /// lost probes of the size currently probed
+    probe_losses: u32,
+    /// consecutive lost packets larger than BASE_MTU
+    large_losses: u32,
+}
+
+impl MtuDiscovery {
+    /// create new instance probing sizes up to `max_mtu`
+    pub fn new(max_mtu: usize) -> MtuDiscovery {
+        MtuDiscovery {
+            confirmed: BASE_MTU,
+            upper_bound: None,
+            max_mtu: usize::max(max_mtu, BASE_MTU),
+            probe_in_flight: None,
+            probe_losses: 0,
+            large_losses: 0,
+        }
+    }
+
+    /// current packet size
+    pub fn mtu(&self) -> usize {
+        self.confirmed
+    }
+
+    /// whether the search for a larger size is complete
+    pub fn is_complete(&self) -> bool {
+        let limit = self.upper_bound.unwrap_or(self.max_mtu + 1);
+        limit - self.confirmed <= SEARCH_GRANULARITY
+    }
+
+    /// size of next probe, if a probe should be sent
+    pub fn next_probe_size(&self) -> Option<usize> {
+        if self.probe_in_flight.is_some() || self.is_complete() {
+            return None;
+        }
+        match self.upper_bound {
+            None => Some(self.max_mtu),
+            Some(upper_bound) => Some((self.confirmed + upper_bound) / 2),
+        }
+    }
+
+    /// builder for regular packets of the current size
+    pub fn packet_builder(&self) -> PacketBuilder {
+        PacketBuilder::new(self.confirmed)
+    }
+
+    /// build probe packet if a probe should be sent
+    ///
+    /// The probe consists of a Ping frame with the given sequence number,
+    /// padded to the probe size.
+    pub fn build_probe(&self, sequence: u64) -> Option<Packet> {
+        let size = self.next_probe_size()?;
+        let mut builder = PacketBuilder::new(size);
+        let pushed = builder.push(Ping { sequence });
+        debug_assert!(pushed.is_ok(), "ping fits in probe");
+        Some(builder.finish_padded(size))
+    }
+
+    /// record packet of `size` bytes sent
+    pub fn on_packet_sent(&mut self, size: usize) {
+        if size > self.confirmed {
+            trace!(size, "mtu probe sent");
+            self.probe_in_flight = Some(size);
+        }
+    }
+
+    /// record packet of `size` bytes acknowledged
+    pub fn on_packet_acked(&mut self, size: usize) {
+        if size > self.confirmed {
+            debug!(size, "mtu confirmed");
+            self.confirmed = size;
+            self.probe_losses = 0;
+            if self
+                .upper_bound
+                .is_some_and(|upper_bound| upper_bound <= size)
+            {
+                self.upper_bound = None;
+            }
+        }
+        if self.probe_in_flight.is_some_and(|probe| probe <= size) {
+            self.probe_in_flight = None;
+        }
+        if size > BASE_MTU {
+            self.large_losses = 0;
+        }
+    }
+
+    /// record packet of `size` bytes lost
+    pub fn on_packet_lost(&mut self, size: usize) {
+        if size > self.confirmed {
+            if self.probe_in_flight != Some(size) {
+                // probe of size already given up on
+                return;
+            }
+            self.probe_in_flight = None;
+            self.probe_losses += 1;
+            if self.probe_losses >= MAX_PROBES {
+                trace!(size, "mtu probe failed");
+                self.upper_bound = Some(size);
+                self.probe_losses = 0;
+            }
+        } else if size > BASE_MTU {
+            self.large_losses += 1;
+            if self.large_losses >= BLACK_HOLE_THRESHOLD {
+                debug!(size, "mtu black hole detected, stepping down");
+                self.confirmed = BASE_MTU;
+                self.upper_bound = Some(size);
+                self.probe_in_flight = None;
+                self.probe_losses = 0;
+                self.large_losses = 0;
+            }
+        }
+    }
+}
+
+impl Default for MtuDiscovery {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_MTU)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn search() {
+        let mut mtu = MtuDiscovery::new(1400);
+        assert_eq!(mtu.mtu(), BASE_MTU);
+        let probe = mtu.build_probe(0).unwrap();
+        assert_eq!(probe.data.len(), 1400);
+
+        // maximum size never arrives
+        for _ in 0..MAX_PROBES {
+            assert_eq!(mtu.next_probe_size(), Some(1400));
+            mtu.on_packet_sent(1400);
+            assert_eq!(mtu.next_probe_size(), None);
+            mtu.on_packet_lost(1400);
+        }
+        assert_eq!(mtu.next_probe_size(), Some(1300));
+        mtu.on_packet_sent(1300);
+        mtu.on_packet_acked(1300);
+        assert_eq!(mtu.mtu(), 1300);
+        assert_eq!(mtu.packet_builder().remaining(), 1300);
+
+        let mut size = 1300;
+        while let Some(next) = mtu.next_probe_size() {
+            assert!(next > size && next < 1400);
+            mtu.on_packet_sent(next);
+            mtu.on_packet_acked(next);
+            size = next;
+        }
+        assert!(mtu.is_complete());
+        assert!(mtu.mtu() >= 1400 - SEARCH_GRANULARITY);
+    }
+
+    #[test]
+    fn black_hole() {
+        let mut mtu = MtuDiscovery::new(1400);
+        mtu.on_packet_sent(1400);
+        mtu.on_packet_acked(1400);
+        assert_eq!(mtu.mtu(), 1400);
+        assert!(mtu.is_complete());
+
+        // acknowledgment of a large packet resets the count
+        mtu.on_packet_lost(1400);
+        mtu.on_packet_lost(1400);
+        mtu.on_packet_acked(1400);
+        mtu.on_packet_lost(1400);
+        mtu.on_packet_lost(1400);
+        mtu.on_packet_lost(BASE_MTU);
+        assert_eq!(mtu.mtu(), 1400);
+
+        mtu.on_packet_lost(1400);
+        assert_eq!(mtu.mtu(), BASE_MTU);
+        assert_eq!(mtu.next_probe_size(), Some(1300));
+    }
+}
@@ -0,0 +1,489 @@
+//! Connection state shared between handles and the driver task
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::Instant;
+
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tracing::{debug, trace, warn};
+
+use super::{
+    IDLE_TIMEOUT_ERROR, INTERNAL_ERROR, MAX_ACK_FRAMES, MAX_ACK_FRAME_SIZE, MAX_PACKET_SIZE,
+    PROTOCOL_VERSION,
+};
+use crate::frame::{
+    CompressedAckRanges, ConnectionAccept, ConnectionClose, Frame, MaxData, Pong, StreamDataRef,
+    StreamFinal, StreamWindowLimit,
+};
+use crate::idle::{IdleEvent, IdleTracker, DEFAULT_IDLE_TIMEOUT, DEFAULT_KEEP_ALIVE_INTERVAL};
+use crate::packet::{FrameReader, Packet, PacketBuilder};
+use crate::stream::container::{StreamEvent, StreamSet, StreamState};
+use crate::stream::flow_control::DEFAULT_CONNECTION_WINDOW_LIMIT;
+
+/// max serialized length of a stream data frame excluding data
+const STREAM_FRAME_OVERHEAD: usize = 22;
+
+/// state of a connection, protected by the mutex in `Shared`
+pub(crate) struct ConnectionState {
+    /// streams of the connection
+    pub streams: StreamSet,
+    /// control frames waiting to be sent
+    pub control: VecDeque<Frame>,
+    /// streams with received data or final offset not yet acknowledged
+    pub ack_pending: BTreeSet<u64>,
+    /// streams with final offset not yet acknowledged by peer, with the time
+    /// the final offset was last sent (None if it should be sent again)
+    pub unacked_finals: HashMap<u64, Option<Instant>>,
+    /// stream bytes read by the application across all streams
+    pub consumed: u64,
+    /// tasks waiting to read, by stream id
+    pub read_wakers: HashMap<u64, Waker>,
+    /// tasks waiting to write, by stream id
+    pub write_wakers: HashMap<u64, Waker>,
+    /// task waiting for a new stream
+    pub accept_waker: Option<Waker>,
+    /// keep-alive and idle timeout
+    pub idle: IdleTracker,
+    /// error code once connection is closed, 0 if closed normally
+    pub closed: Option<u64>,
+}
+
+impl ConnectionState {
+    /// create new instance
+    pub fn new(is_initiator: bool, initial_window_limit: u64) -> ConnectionState {
+        ConnectionState {
+            streams: StreamSet::new(is_initiator, initial_window_limit),
+            control: VecDeque::new(),
+            ack_pending: BTreeSet::new(),
+            unacked_finals: HashMap::new(),
+            consumed: 0,
+            read_wakers: HashMap::new(),
+            write_wakers: HashMap::new(),
+            accept_waker: None,
+            idle: IdleTracker::new(
+                Some(DEFAULT_IDLE_TIMEOUT),
+                Some(DEFAULT_KEEP_ALIVE_INTERVAL),
+                Instant::now(),
+            ),
+            closed: None,
+        }
+    }
+
+    /// wake all waiting tasks
+    pub fn wake_all(&mut self) {
+        for (_, waker) in self.read_wakers.drain() {
+            waker.wake();
+        }
+        self.wake_writers();
+        if let Some(waker) = self.accept_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// wake all tasks waiting to write
+    fn wake_writers(&mut self) {
+        for (_, waker) in self.write_wakers.drain() {
+            waker.wake();
+        }
+    }
+
+    /// close connection with error code, queueing ConnectionClose for the
+    /// peer
+    pub fn close(&mut self, error_code: u64, reason: String) {
+        if self.closed.is_some() {
+            return;
+        }
+        debug!(error_code, reason, "close connection");
+        self.control
+            .push_back(ConnectionClose { error_code, reason }.into());
+        self.closed = Some(error_code);
+        self.wake_all();
+    }
+
+    /// whether all data written to streams has been delivered
+    pub fn all_delivered(&self) -> bool {
+        self.streams.streams.iter().all(|(stream_id, entry)| {
+            let outbound = &entry.outbound;
+            let end = outbound.buffer_offset + outbound.buffer.len() as u64;
+            let data_delivered = outbound.reset_code.is_some()
+                || end == 0
+                || outbound
+                    .delivered
+                    .peek_first()
+                    .is_some_and(|r| r.end >= end);
+            // empty streams cannot be acknowledged, their final offset is
+            // sent until the connection closes
+            let final_delivered =
+                outbound.final_offset == Some(0) || !self.unacked_finals.contains_key(stream_id);
+            data_delivered && final_delivered
+        })
+    }
+
+    /// advance stream and connection receive windows after the application
+    /// read `len` bytes from a stream
+    pub fn data_consumed(&mut self, stream_id: u64, len: usize) {
+        self.consumed += len as u64;
+        let window = self.streams.initial_window_limit;
+        if let Some(entry) = self.streams.get_mut(stream_id) {
+            let inbound = &mut entry.inbound;
+            let limit = inbound.buffer_offset + window;
+            if limit.saturating_sub(inbound.window_limit) >= window / 2 {
+                inbound.set_limit(limit);
+                self.control
+                    .push_back(StreamWindowLimit { stream_id, limit }.into());
+            }
+        }
+
+        let limit = self.consumed + DEFAULT_CONNECTION_WINDOW_LIMIT;
+        let flow_control = &mut self.streams.flow_control;
+        if limit.saturating_sub(flow_control.receive_limit) >= DEFAULT_CONNECTION_WINDOW_LIMIT / 2 {
+            if let Some(max_data) = flow_control.set_receive_limit(limit) {
+                self.control.push_back(max_data.into());
+            }
+        }
+    }
+
+    /// process received packet
+    pub fn handle_packet(&mut self, packet: &[u8], is_initiator: bool) {
+        for frame in FrameReader::new(packet) {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(error) => {
+                    debug!(%error, "discarding rest of packet");
+                    break;
+                }
+            };
+            match &frame {
+                Frame::ConnectionInit(_) if !is_initiator => {
+                    // accept may have been lost
+                    self.control.push_back(
+                        ConnectionAccept {
+                            version: PROTOCOL_VERSION,
+                            parameters: Vec::new(),
+                        }
+                        .into(),
+                    );
+                }
+                Frame::ConnectionClose(close) => {
+                    debug!(error_code = close.error_code, "closed by peer");
+                    self.closed = Some(close.error_code);
+                    self.wake_all();
+                    return;
+                }
+                Frame::Ping(ping) => {
+                    self.control.push_back(
+                        Pong {
+                            sequence: ping.sequence,
+                        }
+                        .into(),
+                    );
+                }
+                _ => {}
+            }
+
+            let stream_id = match self.streams.route_frame(&frame) {
+                Ok(Some(stream_id)) => stream_id,
+                Ok(None) => continue,
+                Err(error) => {
+                    warn!(%error, "protocol violation");
+                    self.close(INTERNAL_ERROR, error.to_string());
+                    return;
+                }
+            };
+            match &frame {
+                Frame::StreamData(_) | Frame::StreamFinal(_) => {
+                    self.ack_pending.insert(stream_id);
+                    if let Some(waker) = self.read_wakers.remove(&stream_id) {
+                        waker.wake();
+                    }
+                }
+                Frame::AckRanges(ack) | Frame::CompressedAckRanges(CompressedAckRanges(ack)) => {
+                    let entry = self.streams.get_mut(stream_id).unwrap();
+                    entry.outbound.try_advance_buffer();
+                    let final_offset = entry.outbound.final_offset.unwrap_or(0);
+                    if final_offset > 0
+                        && ack.largest_acked() >= final_offset
+                        && matches!(self.unacked_finals.get(&stream_id), Some(Some(_)))
+                    {
+                        trace!(stream_id, "final offset acknowledged");
+                        self.unacked_finals.remove(&stream_id);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // peer finishing or resetting a stream ends pending reads
+        while let Some(StreamEvent::StateChanged { stream_id, state }) = self.streams.poll_event() {
+            if matches!(state, StreamState::HalfClosedRemote | StreamState::Closed) {
+                if let Some(waker) = self.read_wakers.remove(&stream_id) {
+                    waker.wake();
+                }
+            }
+        }
+
+        // acknowledgments and window updates may allow more writes
+        self.wake_writers();
+        if let Some(waker) = self.accept_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// handle periodic tick, resending final offsets and window limits which
+    /// may have been lost
+    pub fn handle_tick(&mut self, now: Instant) {
+        let retransmit_timeout = self.streams.retransmit_timeout;
+        for sent in self.unacked_finals.values_mut() {
+            if sent.is_some_and(|sent| now >= sent + retransmit_timeout) {
+                *sent = None;
+            }
+        }
+        let initial_window_limit = self.streams.initial_window_limit;
+        for (stream_id, entry) in &self.streams.streams {
+            let inbound = &entry.inbound;
+            let finished = inbound
+                .final_offset
+                .is_some_and(|final_offset| inbound.buffer_offset >= final_offset);
+            if inbound.window_limit > initial_window_limit && !finished {
+                self.control.push_back(
+                    StreamWindowLimit {
+                        stream_id: *stream_id,
+                        limit: inbound.window_limit,
+                    }
+                    .into(),
+                );
+            }
+        }
+        let receive_limit = self.streams.flow_control.receive_limit;
+        if receive_limit > DEFAULT_CONNECTION_WINDOW_LIMIT {
+            self.control.push_back(
+                MaxData {
+                    limit: receive_limit,
+                }
+                .into(),
+            );
+        }
+    }
+
+    /// handle expired keep-alive and idle timers
+    pub fn handle_idle(&mut self, now: Instant) {
+        match self.idle.poll(now) {
+            Some(IdleEvent::KeepAlive(ping)) => self.control.push_back(ping.into()),
+            Some(IdleEvent::Timeout) => self.close(IDLE_TIMEOUT_ERROR, "idle timeout".into()),
+            None => {}
+        }
+    }
+
+    /// next time timers of streams or the idle tracker expire
+    pub fn next_timeout(&self) -> Option<Instant> {
+        [self.streams.next_timeout(), self.idle.next_deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// assemble queued frames and stream data into packets
+    pub fn build_packets(&mut self, now: Instant) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        let mut builder = PacketBuilder::new(MAX_PACKET_SIZE);
+
+        let mut frames: Vec<Frame> = self.control.drain(..).collect();
+        for stream_id in std::mem::take(&mut self.ack_pending) {
+            let Some(entry) = self.streams.get(stream_id) else {
+                continue;
+            };
+            let acks = CompressedAckRanges::from_range_set(
+                stream_id,
+                &entry.inbound.received,
+                MAX_ACK_FRAME_SIZE,
+                MAX_ACK_FRAMES,
+            );
+            frames.extend(acks.into_iter().map(Frame::from));
+        }
+        frames.extend(self.streams.flow_control.poll_blocked().map(Frame::from));
+        for frame in frames {
+            push_frame(&mut packets, &mut builder, frame);
+        }
+
+        while let Some(stream_id) = self.streams.next_sendable() {
+            let remaining = builder.remaining();
+            if remaining <= STREAM_FRAME_OVERHEAD {
+                finish_packet(&mut packets, &mut builder);
+                continue;
+            }
+            let entry = self.streams.get_mut(stream_id).unwrap();
+            let Some(segment) = entry
+                .outbound
+                .next_segment(remaining - STREAM_FRAME_OVERHEAD)
+            else {
+                break;
+            };
+            let (slice, marker) = entry
+                .outbound
+                .read_segment(segment.clone())
+                .expect("queued segment not in buffer");
+            let frame = StreamDataRef {
+                stream_id,
+                stream_offset: segment.start,
+                // next_segment keeps markers within range
+                message_offset: marker.map(|marker| (marker - segment.start) as u16),
+                data: slice.into(),
+            };
+            push_stream_data(&mut packets, &mut builder, frame);
+            self.streams.segment_sent(stream_id, segment, now);
+            // send final offset along with any data sent after it was set
+            if let Some(sent) = self.unacked_finals.get_mut(&stream_id) {
+                *sent = None;
+            }
+        }
+
+        for (stream_id, sent) in &mut self.unacked_finals {
+            if sent.is_some() {
+                continue;
+            }
+            let Some(entry) = self.streams.streams.get(stream_id) else {
+                continue;
+            };
+            let final_offset = entry.outbound.final_offset.expect("final offset not set");
+            let frame = StreamFinal {
+                stream_id: *stream_id,
+                final_offset,
+            };
+            push_frame(&mut packets, &mut builder, frame.into());
+            *sent = Some(now);
+        }
+
+        if !builder.is_empty() {
+            packets.push(builder.finish());
+        }
+        packets
+    }
+}
+
+/// add frame to packet, starting a new packet if it does not fit
+fn push_frame(packets: &mut Vec<Packet>, builder: &mut PacketBuilder, frame: Frame) {
+    if let Err(frame) = builder.push(frame) {
+        finish_packet(packets, builder);
+        if builder.push(frame).is_err() {
+            warn!("frame exceeds max packet size");
+        }
+    }
+}
+
+/// add borrowed stream data to packet, starting a new packet if full
+fn push_stream_data(packets: &mut Vec<Packet>, builder: &mut PacketBuilder, frame: StreamDataRef) {
+    if let Err(frame) = builder.push_stream_data(frame) {
+        finish_packet(packets, builder);
+        if builder.push_stream_data(frame).is_err() {
+            warn!("frame exceeds max packet size");
+        }
+    }
+}
+
+/// finish packet and start a new one
+fn finish_packet(packets: &mut Vec<Packet>, builder: &mut PacketBuilder) {
+    let full = std::mem::replace(builder, PacketBuilder::new(MAX_PACKET_SIZE));
+    if !full.is_empty() {
+        packets.push(full.finish());
+    }
+}
+
+/// connection state and notifications shared by handles and driver
+pub(crate) struct Shared {
+    /// connection state
+    pub state: Mutex<ConnectionState>,
+    /// wakes driver to send queued frames and data
+    pub send_notify: Notify,
+    /// notified by driver after each received packet or close
+    pub progress: Notify,
+    /// address of peer
+    pub peer: SocketAddr,
+    /// whether we initiated the connection
+    pub is_initiator: bool,
+}
+
+/// task sending and receiving packets of one connection
+pub(crate) struct Driver {
+    /// shared state
+    pub shared: Arc<Shared>,
+    /// socket to send packets on
+    pub socket: Arc<UdpSocket>,
+    /// packets received from peer
+    pub incoming: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Driver {
+    /// run until the connection is closed
+    pub async fn run(mut self) {
+        let retransmit_timeout = self.shared.state.lock().streams.retransmit_timeout;
+        let mut tick = tokio::time::interval(retransmit_timeout);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let timeout = self.shared.state.lock().next_timeout();
+            tokio::select! {
+                packet = self.incoming.recv() => match packet {
+                    Some(packet) => {
+                        let mut state = self.shared.state.lock();
+                        state.idle.on_packet_received(Instant::now());
+                        state.handle_packet(&packet, self.shared.is_initiator);
+                        drop(state);
+                        self.shared.progress.notify_waiters();
+                    }
+                    None => {
+                        self.shared
+                            .state
+                            .lock()
+                            .close(INTERNAL_ERROR, "socket closed".into());
+                    }
+                },
+                _ = self.shared.send_notify.notified() => {}
+                _ = sleep_until(timeout) => {
+                    let now = Instant::now();
+                    let mut state = self.shared.state.lock();
+                    state.streams.poll_timers(now);
+                    state.handle_idle(now);
+                }
+                _ = tick.tick() => self.shared.state.lock().handle_tick(Instant::now()),
+            }
+
+            if let Err(error) = self.flush().await {
+                debug!(%error, "failed to send packet");
+            }
+            if self.shared.state.lock().closed.is_some() {
+                self.shared.progress.notify_waiters();
+                break;
+            }
+        }
+        trace!(peer = %self.shared.peer, "driver exit");
+    }
+
+    /// send all queued frames and sendable stream data
+    async fn flush(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let packets = {
+            let mut state = self.shared.state.lock();
+            let packets = state.build_packets(now);
+            if !packets.is_empty() {
+                state.idle.on_packet_sent(now);
+            }
+            packets
+        };
+        for packet in packets {
+            self.socket.send_to(&packet.data, self.shared.peer).await?;
+        }
+        Ok(())
+    }
+}
+
+/// sleep until deadline, or forever if there is none
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
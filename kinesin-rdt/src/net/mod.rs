@@ -0,0 +1,443 @@
+//! Async transport over tokio UDP sockets
+//!
+//! Drives the sans-IO stream core from a background task per connection.
+//! `RdtListener` accepts connections on a bound socket, `RdtConnection`
+//! opens and accepts streams, and `RdtStream` implements `AsyncRead` and
+//! `AsyncWrite`. Connections should be closed with `RdtConnection::close`,
+//! which waits until all written data is delivered. Connections send
+//! keep-alive pings while quiet and are closed with `IDLE_TIMEOUT_ERROR` once
+//! the peer is silent for the idle timeout.
+
+mod connection;
+mod stream;
+
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use self::connection::{ConnectionState, Driver, Shared};
+pub use self::stream::RdtStream;
+use crate::frame::{ConnectionInit, Frame};
+use crate::packet::{FrameReader, PacketBuilder};
+use crate::stream::container::DEFAULT_STREAM_PRIORITY;
+use crate::stream::outbound::RetransmitStrategy;
+
+/// protocol version sent in handshake
+pub const PROTOCOL_VERSION: u64 = 1;
+/// max size of packets sent
+pub const MAX_PACKET_SIZE: usize = 1200;
+/// initial flow control window of each stream
+pub const DEFAULT_STREAM_WINDOW: u64 = 1 << 20; // 1 MB
+/// time to wait for the server to accept a connection
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// interval between handshake attempts
+pub const HANDSHAKE_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+/// time to wait for delivery of outstanding data on close
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// error code for connections closed by protocol violation or socket loss
+pub const INTERNAL_ERROR: u64 = 1;
+/// error code for connections closed after the peer was silent for the idle
+/// timeout
+pub const IDLE_TIMEOUT_ERROR: u64 = 2;
+
+/// max size of each acknowledgment frame
+const MAX_ACK_FRAME_SIZE: usize = 128;
+/// max acknowledgment frames per stream sent at once
+const MAX_ACK_FRAMES: usize = 4;
+/// size of buffer for received datagrams
+const RECEIVE_BUFFER_SIZE: usize = 65536;
+/// received packets queued per connection before dropping
+const CONNECTION_QUEUE: usize = 256;
+/// connections queued before `RdtListener::accept`
+const ACCEPT_BACKLOG: usize = 64;
+
+/// listener accepting connections on a UDP socket
+///
+/// Dropping the listener closes all connections accepted from it.
+pub struct RdtListener {
+    /// address socket is bound to
+    local_addr: SocketAddr,
+    /// connections not yet accepted
+    incoming: mpsc::Receiver<RdtConnection>,
+    /// task receiving packets and routing them to connections
+    task: JoinHandle<()>,
+}
+
+impl RdtListener {
+    /// bind listener to address
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<RdtListener> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let local_addr = socket.local_addr()?;
+        let (sender, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let task = tokio::spawn(listen(socket, sender));
+        Ok(RdtListener {
+            local_addr,
+            incoming,
+            task,
+        })
+    }
+
+    /// address listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// wait for next connection
+    pub async fn accept(&mut self) -> io::Result<RdtConnection> {
+        self.incoming
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::other("listener closed"))
+    }
+}
+
+impl Drop for RdtListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// whether packet starts with a ConnectionInit frame
+fn is_connection_init(packet: &[u8]) -> bool {
+    matches!(
+        FrameReader::new(packet).next(),
+        Some(Ok(Frame::ConnectionInit(_)))
+    )
+}
+
+/// receive packets on listening socket, routing them to connections by peer
+/// address and starting connections for new peers
+async fn listen(socket: Arc<UdpSocket>, accepted: mpsc::Sender<RdtConnection>) {
+    let mut peers: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(error) => {
+                debug!(%error, "receive failed");
+                continue;
+            }
+        };
+        let packet = buf[..len].to_vec();
+        if let Some(sender) = peers.get(&peer) {
+            if !sender.is_closed() {
+                // drop packet if connection is not keeping up
+                let _ = sender.try_send(packet);
+                continue;
+            }
+            peers.remove(&peer);
+        }
+
+        if !is_connection_init(&packet) {
+            continue;
+        }
+        debug!(%peer, "new connection");
+        let (sender, receiver) = mpsc::channel(CONNECTION_QUEUE);
+        let _ = sender.try_send(packet);
+        peers.insert(peer, sender);
+        let connection = RdtConnection::start(socket.clone(), peer, false, receiver);
+        if accepted.send(connection).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// connection to a peer
+pub struct RdtConnection {
+    /// state shared with driver and streams
+    shared: Arc<Shared>,
+}
+
+impl RdtConnection {
+    /// start driver for connection
+    fn start(
+        socket: Arc<UdpSocket>,
+        peer: SocketAddr,
+        is_initiator: bool,
+        incoming: mpsc::Receiver<Vec<u8>>,
+    ) -> RdtConnection {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(ConnectionState::new(is_initiator, DEFAULT_STREAM_WINDOW)),
+            send_notify: Notify::new(),
+            progress: Notify::new(),
+            peer,
+            is_initiator,
+        });
+        let driver = Driver {
+            shared: shared.clone(),
+            socket,
+            incoming,
+        };
+        tokio::spawn(driver.run());
+        RdtConnection { shared }
+    }
+
+    /// connect to listener at address
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<RdtConnection> {
+        let peer = lookup_host(addr).await?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+        })?;
+        let bind_addr: SocketAddr = if peer.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+
+        let mut builder = PacketBuilder::new(MAX_PACKET_SIZE);
+        let init = ConnectionInit {
+            version: PROTOCOL_VERSION,
+            parameters: Vec::new(),
+        };
+        if builder.push(init).is_err() {
+            unreachable!("empty handshake exceeds packet size");
+        }
+        let init = builder.finish();
+
+        let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+        let handshake = async {
+            loop {
+                socket.send_to(&init.data, peer).await?;
+                let retry = tokio::time::sleep(HANDSHAKE_RETRY_INTERVAL);
+                tokio::pin!(retry);
+                loop {
+                    tokio::select! {
+                        received = socket.recv_from(&mut buf) => {
+                            let (len, from) = received?;
+                            let accepted = matches!(
+                                FrameReader::new(&buf[..len]).next(),
+                                Some(Ok(Frame::ConnectionAccept(_)))
+                            );
+                            if from == peer && accepted {
+                                return io::Result::Ok(());
+                            }
+                        }
+                        _ = &mut retry => break,
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+        debug!(%peer, "connected");
+
+        let (sender, receiver) = mpsc::channel(CONNECTION_QUEUE);
+        tokio::spawn(receive_from_peer(socket.clone(), peer, sender));
+        Ok(RdtConnection::start(socket, peer, true, receiver))
+    }
+
+    /// address of peer
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.shared.peer
+    }
+
+    /// error code once the connection is closed, 0 if closed normally
+    pub fn close_code(&self) -> Option<u64> {
+        self.shared.state.lock().closed
+    }
+
+    /// set time without packets from the peer after which the connection is
+    /// closed with `IDLE_TIMEOUT_ERROR`, or None to disable
+    ///
+    /// Defaults to `DEFAULT_IDLE_TIMEOUT`.
+    pub fn set_idle_timeout(&self, idle_timeout: Option<Duration>) {
+        self.shared
+            .state
+            .lock()
+            .idle
+            .set_idle_timeout(idle_timeout, Instant::now());
+        self.shared.send_notify.notify_one();
+    }
+
+    /// set time without packets sent after which a Ping is sent to keep the
+    /// connection alive, or None to disable
+    ///
+    /// Defaults to `DEFAULT_KEEP_ALIVE_INTERVAL`.
+    pub fn set_keep_alive_interval(&self, interval: Option<Duration>) {
+        self.shared
+            .state
+            .lock()
+            .idle
+            .set_keep_alive_interval(interval, Instant::now());
+        self.shared.send_notify.notify_one();
+    }
+
+    /// open new stream
+    pub fn open_stream(&self) -> io::Result<RdtStream> {
+        let mut state = self.shared.state.lock();
+        if state.closed.is_some() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let stream_id = state
+            .streams
+            .open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        Ok(RdtStream::new(stream_id, self.shared.clone()))
+    }
+
+    /// wait for next stream opened by peer, or None if the connection is
+    /// closed
+    pub async fn accept_stream(&self) -> Option<RdtStream> {
+        poll_fn(|cx| {
+            let mut state = self.shared.state.lock();
+            if let Some(stream_id) = state.streams.accept() {
+                return Poll::Ready(Some(RdtStream::new(stream_id, self.shared.clone())));
+            }
+            if state.closed.is_some() {
+                return Poll::Ready(None);
+            }
+            state.accept_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// finish all streams, wait for outstanding data to be delivered and
+    /// close the connection
+    ///
+    /// Fails with `TimedOut` if data was not delivered within
+    /// `CLOSE_TIMEOUT`, in which case the connection is closed anyway.
+    pub async fn close(self) -> io::Result<()> {
+        {
+            let mut state = self.shared.state.lock();
+            let stream_ids: Vec<u64> = state.streams.streams.keys().copied().collect();
+            for stream_id in stream_ids {
+                if state.streams.finish(stream_id) {
+                    state.unacked_finals.insert(stream_id, None);
+                }
+            }
+        }
+        self.shared.send_notify.notify_one();
+
+        let delivered = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            loop {
+                let progress = self.shared.progress.notified();
+                tokio::pin!(progress);
+                progress.as_mut().enable();
+                {
+                    let state = self.shared.state.lock();
+                    if state.closed.is_some() || state.all_delivered() {
+                        return;
+                    }
+                }
+                progress.await;
+            }
+        })
+        .await;
+
+        self.shared.state.lock().close(0, String::new());
+        self.shared.send_notify.notify_one();
+        delivered.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "data not delivered"))
+    }
+}
+
+/// forward packets from peer to connection driver until it exits
+async fn receive_from_peer(
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    sender: mpsc::Sender<Vec<u8>>,
+) {
+    let mut buf = vec![0; RECEIVE_BUFFER_SIZE];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((len, from)) if from == peer => {
+                    let _ = sender.try_send(buf[..len].to_vec());
+                }
+                Ok(_) => {}
+                Err(error) => debug!(%error, "receive failed"),
+            },
+            _ = sender.closed() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn transfer() {
+        let mut listener = RdtListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        let expected = data.clone();
+        let server = tokio::spawn(async move {
+            let connection = listener.accept().await.unwrap();
+            let mut stream = connection.accept_stream().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+            stream.write_all(b"thanks").await.unwrap();
+            stream.shutdown().await.unwrap();
+            connection.close().await.unwrap();
+        });
+
+        let connection = RdtConnection::connect(addr).await.unwrap();
+        let mut stream = connection.open_stream().unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"thanks");
+        connection.close().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reset() {
+        let mut listener = RdtListener::bind("127.0.0.1:0").await.unwrap();
+        let connection = RdtConnection::connect(listener.local_addr()).await.unwrap();
+        let server = listener.accept().await.unwrap();
+
+        let mut stream = connection.open_stream().unwrap();
+        stream.write_all(b"partial").await.unwrap();
+        stream.reset(7);
+        assert_eq!(
+            stream.write_all(b"more").await.unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+        let mut accepted = server.accept_stream().await.unwrap();
+        let mut received = Vec::new();
+        let error = accepted.read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+
+        connection.close().await.unwrap();
+        assert!(server.accept_stream().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let mut listener = RdtListener::bind("127.0.0.1:0").await.unwrap();
+        let connection = RdtConnection::connect(listener.local_addr()).await.unwrap();
+        let server = listener.accept().await.unwrap();
+        connection.set_keep_alive_interval(None);
+        connection.set_idle_timeout(Some(Duration::from_millis(200)));
+
+        // pings from the server keep the connection alive
+        server.set_keep_alive_interval(Some(Duration::from_millis(50)));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(connection.close_code(), None);
+
+        server.set_keep_alive_interval(None);
+        assert!(connection.accept_stream().await.is_none());
+        assert_eq!(connection.close_code(), Some(IDLE_TIMEOUT_ERROR));
+        assert!(matches!(
+            connection.open_stream(),
+            Err(error) if error.kind() == io::ErrorKind::NotConnected
+        ));
+    }
+}
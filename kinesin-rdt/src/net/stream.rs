@@ -0,0 +1,154 @@
+//! Stream handles implementing AsyncRead and AsyncWrite
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::connection::Shared;
+use super::IDLE_TIMEOUT_ERROR;
+
+/// one stream of a connection
+///
+/// Reads return end of file once the peer finished the stream. Shutting down
+/// the writing half sends the final offset to the peer without waiting for
+/// delivery; `RdtConnection::close` waits for all data to be delivered.
+pub struct RdtStream {
+    /// stream identifier
+    stream_id: u64,
+    /// connection state
+    shared: Arc<Shared>,
+}
+
+impl RdtStream {
+    /// create handle for existing stream
+    pub(crate) fn new(stream_id: u64, shared: Arc<Shared>) -> RdtStream {
+        RdtStream { stream_id, shared }
+    }
+
+    /// stream identifier
+    pub fn id(&self) -> u64 {
+        self.stream_id
+    }
+
+    /// abandon sending on stream, discarding data not yet delivered
+    pub fn reset(&self, error_code: u64) {
+        let mut state = self.shared.state.lock();
+        let Some(frame) = state.streams.reset(self.stream_id, error_code) else {
+            return;
+        };
+        state.unacked_finals.remove(&self.stream_id);
+        state.control.push_back(frame.into());
+        if let Some(waker) = state.write_wakers.remove(&self.stream_id) {
+            waker.wake();
+        }
+        self.shared.send_notify.notify_one();
+    }
+}
+
+impl AsyncRead for RdtStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.shared.state.lock();
+        let Some(entry) = state.streams.get_mut(self.stream_id) else {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        };
+        let inbound = &mut entry.inbound;
+        if let Some(slice) = inbound.read_next(buf.remaining()) {
+            let len = slice.len();
+            slice.copy_to_slice(buf.initialize_unfilled_to(len));
+            buf.advance(len);
+            let new_base = inbound.buffer_offset + len as u64;
+            inbound.advance_buffer(new_base);
+            state.data_consumed(self.stream_id, len);
+            if !state.control.is_empty() {
+                self.shared.send_notify.notify_one();
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        if inbound.reset_code.is_some() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "stream reset by peer",
+            )));
+        }
+        let finished = inbound
+            .final_offset
+            .is_some_and(|final_offset| inbound.buffer_offset >= final_offset);
+        match state.closed {
+            _ if finished => Poll::Ready(Ok(())),
+            Some(0) => Poll::Ready(Ok(())),
+            Some(IDLE_TIMEOUT_ERROR) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+            Some(_) => Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into())),
+            None => {
+                state.read_wakers.insert(self.stream_id, cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for RdtStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.shared.state.lock();
+        if state.closed.is_some() {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
+        let Some(entry) = state.streams.get(self.stream_id) else {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        };
+        if entry.outbound.reset_code.is_some() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "stream was reset",
+            )));
+        }
+        if entry.outbound.final_offset.is_some() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "write after shutdown",
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let written = state.streams.write(self.stream_id, buf);
+        if written == 0 {
+            state
+                .write_wakers
+                .insert(self.stream_id, cx.waker().clone());
+            Poll::Pending
+        } else {
+            self.shared.send_notify.notify_one();
+            Poll::Ready(Ok(written))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // written data is sent by the driver as soon as windows allow
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.shared.state.lock();
+        if state.streams.get(self.stream_id).is_none() {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
+        if state.streams.finish(self.stream_id) {
+            state.unacked_finals.insert(self.stream_id, None);
+            self.shared.send_notify.notify_one();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,411 @@
+//! Packet assembly from frames and frame parsing from packets
+//!
+//! A zero byte where a frame tag is expected starts padding, which extends to
+//! the end of the packet.
+
+use std::ops::Range;
+
+use crate::frame::encoding::{read_varint8_checked, varint8_size, write_varint8};
+use crate::frame::{Frame, FrameDecodeError, FrameType, StreamDataRef};
+
+/// assembled packet
+pub struct Packet {
+    /// serialized frames
+    pub data: Vec<u8>,
+    /// stream data included in packet as (stream id, range), in order
+    pub stream_ranges: Vec<(u64, Range<u64>)>,
+}
+
+/// greedily packs frames into a packet of fixed maximum size
+///
+/// The most recently pushed frame is held back until either another frame is
+/// pushed or the packet is finished, so the last frame of the packet can be
+/// written with its end-of-packet optimization. Borrowed stream data frames
+/// are written immediately instead and shortened in place if they turn out
+/// to be last.
+pub struct PacketBuilder {
+    /// packet buffer, sized to the max packet size
+    buf: Vec<u8>,
+    /// bytes written to buffer
+    len: usize,
+    /// frame not yet written
+    pending: Option<Frame>,
+    /// borrowed stream data frame written last, if nothing was pushed since
+    written_last: Option<WrittenStreamData>,
+    /// stream ranges of frames pushed so far
+    stream_ranges: Vec<(u64, Range<u64>)>,
+}
+
+impl PacketBuilder {
+    /// create builder for packets of at most `size` bytes
+    pub fn new(size: usize) -> PacketBuilder {
+        PacketBuilder {
+            buf: vec![0; size],
+            len: 0,
+            pending: None,
+            written_last: None,
+            stream_ranges: Vec::new(),
+        }
+    }
+
+    /// space available for another frame, assuming it will be the last
+    ///
+    /// This is 0 if the held back frame only fits as the last frame.
+    pub fn remaining(&self) -> usize {
+        if let Some(WrittenStreamData::AtEnd) = self.written_last {
+            return 0;
+        }
+        let pending_length = self.pending.as_ref().map_or(0, |f| f.encoded_length());
+        (self.buf.len() - self.len).saturating_sub(pending_length)
+    }
+
+    /// whether no frames have been pushed
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_none() && self.len == 0
+    }
+
+    /// add frame to packet, returning the frame back if it does not fit
+    pub fn push(&mut self, frame: impl Into<Frame>) -> Result<(), Frame> {
+        let frame = frame.into();
+        if frame.encoded_length_at_end() > self.remaining() {
+            return Err(frame);
+        }
+        if let Frame::StreamData(data) = &frame {
+            let start = data.stream_offset;
+            let end = start + data.data.len() as u64;
+            self.stream_ranges.push((data.stream_id, start..end));
+        }
+        if let Some(prev) = self.pending.replace(frame) {
+            self.len += prev.encode(&mut self.buf[self.len..]);
+        }
+        self.written_last = None;
+        Ok(())
+    }
+
+    /// add borrowed stream data frame to packet, returning the frame back if
+    /// it does not fit
+    ///
+    /// The frame is written immediately, so the packet does not borrow data.
+    pub fn push_stream_data<'a>(
+        &mut self,
+        frame: StreamDataRef<'a>,
+    ) -> Result<(), StreamDataRef<'a>> {
+        let tag = FrameType::StreamData.tag(false);
+        let tag_len = varint8_size(tag);
+        if tag_len + frame.serialized_length_at_end() > self.remaining() {
+            return Err(frame);
+        }
+        let start = frame.stream_offset;
+        let end = start + frame.data.len() as u64;
+        self.stream_ranges.push((frame.stream_id, start..end));
+        if let Some(prev) = self.pending.take() {
+            self.len += prev.encode(&mut self.buf[self.len..]);
+        }
+
+        let tag_offset = self.len;
+        if tag_len + frame.serialized_length() > self.buf.len() - self.len {
+            // only fits as last frame
+            let tag = FrameType::StreamData.tag(true);
+            self.len += write_varint8(&mut self.buf[self.len..], tag);
+            self.len += frame.write_to_end(&mut self.buf[self.len..]);
+            self.written_last = Some(WrittenStreamData::AtEnd);
+        } else {
+            self.len += write_varint8(&mut self.buf[self.len..], tag);
+            // length field follows flags, stream id and offset
+            let length_offset =
+                self.len + 1 + varint8_size(frame.stream_id) + varint8_size(frame.stream_offset);
+            self.len += frame.write(&mut self.buf[self.len..]);
+            self.written_last = Some(WrittenStreamData::Full {
+                tag_offset,
+                length_offset,
+            });
+        }
+        Ok(())
+    }
+
+    /// write last frame and return packet
+    pub fn finish(mut self) -> Packet {
+        if let Some(last) = self.pending.take() {
+            self.len += last.encode_to_end(&mut self.buf[self.len..]);
+        }
+        if let Some(WrittenStreamData::Full {
+            tag_offset,
+            length_offset,
+        }) = self.written_last
+        {
+            // drop length field of last frame
+            let tag = FrameType::StreamData.tag(true);
+            write_varint8(&mut self.buf[tag_offset..], tag);
+            self.buf
+                .copy_within(length_offset + 2..self.len, length_offset);
+            self.len -= 2;
+        }
+        self.buf.truncate(self.len);
+        Packet {
+            data: self.buf,
+            stream_ranges: self.stream_ranges,
+        }
+    }
+
+    /// write last frame and pad packet to `size` bytes, or to the maximum
+    /// packet size if smaller
+    ///
+    /// The last frame is written without its end-of-packet optimization.
+    pub fn finish_padded(mut self, size: usize) -> Packet {
+        if let Some(last) = self.pending.take() {
+            self.len += last.encode(&mut self.buf[self.len..]);
+        }
+        let size = usize::min(size, self.buf.len());
+        if size > self.len {
+            self.buf[self.len..size].fill(0);
+            self.len = size;
+        }
+        self.buf.truncate(self.len);
+        Packet {
+            data: self.buf,
+            stream_ranges: self.stream_ranges,
+        }
+    }
+}
+
+/// stream data frame written by `PacketBuilder::push_stream_data`
+enum WrittenStreamData {
+    /// written with length field at the given offsets into the packet
+    Full {
+        tag_offset: usize,
+        length_offset: usize,
+    },
+    /// written without length field, nothing may follow
+    AtEnd,
+}
+
+/// error reading frames from a packet
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum FrameReadError {
+    /// packet ended in the middle of a frame tag
+    #[error("truncated frame tag at offset {offset}")]
+    TruncatedTag { offset: usize },
+    /// frame tag does not correspond to a known frame type
+    #[error("unknown frame tag {tag} at offset {offset}")]
+    UnknownTag { offset: usize, tag: u64 },
+    /// frame body could not be read
+    #[error("invalid {frame_type:?} frame at offset {offset}")]
+    InvalidFrame {
+        offset: usize,
+        frame_type: FrameType,
+        #[source]
+        error: FrameDecodeError,
+    },
+}
+
+/// iterator over frames in a received packet
+///
+/// Iteration stops after the first error.
+pub struct FrameReader<'a> {
+    /// packet data
+    buf: &'a [u8],
+    /// offset of next frame
+    offset: usize,
+    /// whether an error was returned
+    failed: bool,
+}
+
+impl<'a> FrameReader<'a> {
+    /// create reader over packet data
+    pub fn new(buf: &'a [u8]) -> FrameReader<'a> {
+        FrameReader {
+            buf,
+            offset: 0,
+            failed: false,
+        }
+    }
+
+    /// offset of next frame in packet
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// read next frame
+    fn read_frame(&mut self) -> Result<Frame, FrameReadError> {
+        let offset = self.offset;
+        let (tag, tag_len) = read_varint8_checked(&self.buf[offset..])
+            .map_err(|_| FrameReadError::TruncatedTag { offset })?;
+        let (frame_type, at_end) =
+            FrameType::from_tag(tag).ok_or(FrameReadError::UnknownTag { offset, tag })?;
+        let body = &self.buf[offset + tag_len..];
+        let (len, frame) = Frame::read_body(frame_type, body, at_end).map_err(|error| {
+            FrameReadError::InvalidFrame {
+                offset,
+                frame_type,
+                error,
+            }
+        })?;
+        self.offset = offset + tag_len + len;
+        Ok(frame)
+    }
+}
+
+impl<'a> Iterator for FrameReader<'a> {
+    type Item = Result<Frame, FrameReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.buf.len() {
+            return None;
+        }
+        if self.buf[self.offset] == 0 {
+            // padding to end of packet
+            self.offset = self.buf.len();
+            return None;
+        }
+        let result = self.read_frame();
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::ring_buffer::RingBuf;
+    use crate::frame::{Ping, StreamData, StreamWindowLimit};
+
+    fn stream_data(stream_offset: u64, len: usize) -> StreamData {
+        StreamData {
+            stream_id: 3,
+            stream_offset,
+            message_offset: None,
+            data: vec![0; len],
+        }
+    }
+
+    #[test]
+    fn pack_frames() {
+        let mut builder = PacketBuilder::new(64);
+        assert!(builder.is_empty());
+        assert!(builder.push(Ping { sequence: 1 }).is_ok());
+        assert!(builder.push(stream_data(0, 20)).is_ok());
+        assert_eq!(builder.remaining(), 64 - 2 - 26);
+
+        // stream data sized to exactly fill the packet, written without length
+        let len = builder.remaining() - 4;
+        assert!(builder.push(stream_data(20, len)).is_ok());
+        assert_eq!(builder.remaining(), 0);
+        let result = builder.push(StreamWindowLimit {
+            stream_id: 3,
+            limit: 1000,
+        });
+        assert!(matches!(result, Err(Frame::StreamWindowLimit(_))));
+
+        let packet = builder.finish();
+        assert_eq!(packet.data.len(), 64);
+        assert_eq!(
+            packet.stream_ranges,
+            vec![(3, 0..20), (3, 20..20 + len as u64)]
+        );
+
+        let frames: Vec<Frame> = FrameReader::new(&packet.data)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(frames.len(), 3);
+        assert!(matches!(frames[0], Frame::Ping(Ping { sequence: 1 })));
+        match &frames[2] {
+            Frame::StreamData(data) => {
+                assert_eq!(data.stream_offset, 20);
+                assert_eq!(data.data.len(), len);
+            }
+            _ => panic!("expected stream data"),
+        }
+    }
+
+    #[test]
+    fn borrowed_stream_data() {
+        let mut ring = RingBuf::with_capacity(16);
+        ring.push_back_copy_from_slice(&[0; 12]);
+        ring.drain(..12);
+        ring.push_back_copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let borrowed = |stream_offset, len| StreamDataRef {
+            stream_id: 3,
+            stream_offset,
+            message_offset: Some(1),
+            data: ring.range(0..len).into(),
+        };
+        let owned = |stream_offset, len| StreamData {
+            stream_id: 3,
+            stream_offset,
+            message_offset: Some(1),
+            data: (1..=len as u8).collect(),
+        };
+
+        // identical to owned frames, whether last or followed by another frame
+        for size in [64, 32, 30, 28] {
+            let mut builder = PacketBuilder::new(size);
+            let mut expected = PacketBuilder::new(size);
+            assert!(builder.push(Ping { sequence: 1 }).is_ok());
+            assert!(expected.push(Ping { sequence: 1 }).is_ok());
+            assert!(builder.push_stream_data(borrowed(0, 8)).is_ok());
+            assert!(expected.push(owned(0, 8)).is_ok());
+            assert_eq!(builder.remaining(), expected.remaining());
+            let result = builder.push_stream_data(borrowed(8, 6));
+            assert_eq!(result.is_ok(), expected.push(owned(8, 6)).is_ok());
+            assert_eq!(builder.remaining(), expected.remaining());
+            let (packet, expected) = (builder.finish(), expected.finish());
+            assert_eq!(packet.data, expected.data);
+            assert_eq!(packet.stream_ranges, expected.stream_ranges);
+        }
+
+        // only fits without length field, leaving a byte unused
+        let mut builder = PacketBuilder::new(15);
+        assert!(builder.push_stream_data(borrowed(0, 8)).is_ok());
+        assert_eq!(builder.remaining(), 0);
+        assert!(builder.push(Ping { sequence: 1 }).is_err());
+        let packet = builder.finish();
+        assert_eq!(packet.data.len(), 14);
+        match FrameReader::new(&packet.data).next() {
+            Some(Ok(Frame::StreamData(data))) => assert_eq!(data.data, owned(0, 8).data),
+            _ => panic!("expected stream data"),
+        }
+    }
+
+    #[test]
+    fn padding() {
+        let mut builder = PacketBuilder::new(100);
+        assert!(builder.push(Ping { sequence: 1 }).is_ok());
+        assert!(builder.push(stream_data(0, 10)).is_ok());
+        let packet = builder.finish_padded(80);
+        assert_eq!(packet.data.len(), 80);
+
+        let mut reader = FrameReader::new(&packet.data);
+        assert!(matches!(reader.next(), Some(Ok(Frame::Ping(_)))));
+        match reader.next() {
+            Some(Ok(Frame::StreamData(data))) => assert_eq!(data.data.len(), 10),
+            _ => panic!("expected stream data"),
+        }
+        assert!(reader.next().is_none());
+        assert_eq!(reader.offset(), 80);
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut reader = FrameReader::new(&[0x10, 0x01, 0x3f, 0x10]);
+        assert!(matches!(reader.next(), Some(Ok(Frame::Ping(_)))));
+        assert_eq!(
+            reader.next().unwrap().err(),
+            Some(FrameReadError::UnknownTag {
+                offset: 2,
+                tag: 0x3f
+            })
+        );
+        assert!(reader.next().is_none());
+
+        let mut reader = FrameReader::new(&[0x10, 0x40]);
+        assert_eq!(
+            reader.next().unwrap().err(),
+            Some(FrameReadError::InvalidFrame {
+                offset: 0,
+                frame_type: FrameType::Ping,
+                error: FrameDecodeError::UnexpectedEof,
+            })
+        );
+        assert!(reader.next().is_none());
+    }
+}
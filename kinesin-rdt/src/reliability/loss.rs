@@ -0,0 +1,267 @@
+//! Sent packet ledger and loss detection
+//!
+//! Every packet sent is recorded by packet number together with the stream
+//! ranges it carried. Acknowledged packets mark their ranges delivered.
+//! Packets not yet acknowledged are declared lost, as in QUIC (RFC 9002),
+//! once a packet sent `packet_threshold` packets later is acknowledged, or
+//! once they were sent more than the loss delay before the latest
+//! acknowledged packet. Ranges of lost packets are queued for retransmission.
+//! Packet sizes are reported to path MTU discovery, if enabled.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use tracing::trace;
+
+use crate::common::range_set::RangeSet;
+use crate::mtu::MtuDiscovery;
+use crate::packet::Packet;
+use crate::stream::container::StreamSet;
+
+/// default reordering threshold in packets
+pub const DEFAULT_PACKET_THRESHOLD: u64 = 3;
+/// minimum loss delay, also used before any RTT sample is available
+pub const MIN_LOSS_DELAY: Duration = Duration::from_millis(1);
+
+/// packet recorded at send time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SentPacket {
+    /// time packet was sent
+    pub time_sent: Instant,
+    /// packet size in bytes
+    pub size: usize,
+    /// stream data carried by packet as (stream id, range)
+    pub stream_ranges: Vec<(u64, Range<u64>)>,
+}
+
+/// ledger of packets sent and not yet acknowledged or lost
+pub struct SentPacketLedger {
+    /// unacknowledged packets by packet number
+    sent: BTreeMap<u64, SentPacket>,
+    /// number of next packet sent
+    next_packet_number: u64,
+    /// largest packet number acknowledged by peer
+    pub largest_acked: Option<u64>,
+    /// RTT measured from the latest acknowledgment of a new largest packet
+    pub latest_rtt: Option<Duration>,
+    /// packets sent later which must be acknowledged to declare a packet lost
+    pub packet_threshold: u64,
+    /// path MTU discovery fed with sizes of acknowledged and lost packets
+    pub mtu: Option<MtuDiscovery>,
+}
+
+impl SentPacketLedger {
+    /// create new instance
+    pub fn new() -> SentPacketLedger {
+        SentPacketLedger {
+            sent: BTreeMap::new(),
+            next_packet_number: 0,
+            largest_acked: None,
+            latest_rtt: None,
+            packet_threshold: DEFAULT_PACKET_THRESHOLD,
+            mtu: None,
+        }
+    }
+
+    /// number of packets in flight
+    pub fn in_flight(&self) -> usize {
+        self.sent.len()
+    }
+
+    /// record packet sent at `now`, returning its packet number
+    pub fn on_packet_sent(&mut self, packet: &Packet, now: Instant) -> u64 {
+        let packet_number = self.next_packet_number;
+        self.next_packet_number += 1;
+        if let Some(mtu) = &mut self.mtu {
+            mtu.on_packet_sent(packet.data.len());
+        }
+        self.sent.insert(
+            packet_number,
+            SentPacket {
+                time_sent: now,
+                size: packet.data.len(),
+                stream_ranges: packet.stream_ranges.clone(),
+            },
+        );
+        packet_number
+    }
+
+    /// time after which an unacknowledged packet older than the latest
+    /// acknowledged packet is lost, 9/8 of the latest RTT
+    pub fn loss_delay(&self) -> Duration {
+        let rtt = self.latest_rtt.unwrap_or_default();
+        Duration::max(rtt + rtt / 8, MIN_LOSS_DELAY)
+    }
+
+    /// handle acknowledgment of packet numbers in `acked`
+    ///
+    /// Stream ranges of newly acknowledged packets are marked delivered and
+    /// those of packets now considered lost are requeued. Returns
+    /// (stream id, segment) for segments of lost packets.
+    pub fn on_ack(
+        &mut self,
+        acked: &RangeSet,
+        now: Instant,
+        streams: &mut StreamSet,
+    ) -> Vec<(u64, Range<u64>)> {
+        for range in acked.iter() {
+            let packet_numbers: Vec<u64> = self.sent.range(range).map(|(n, _)| *n).collect();
+            for packet_number in packet_numbers {
+                let packet = self.sent.remove(&packet_number).unwrap();
+                if self
+                    .largest_acked
+                    .is_none_or(|largest| packet_number > largest)
+                {
+                    self.largest_acked = Some(packet_number);
+                    self.latest_rtt = Some(now.saturating_duration_since(packet.time_sent));
+                }
+                if let Some(mtu) = &mut self.mtu {
+                    mtu.on_packet_acked(packet.size);
+                }
+                for (stream_id, segment) in packet.stream_ranges {
+                    if let Some(entry) = streams.get_mut(stream_id) {
+                        entry.outbound.segment_delivered(segment);
+                    }
+                }
+            }
+        }
+        self.detect_lost(now, streams)
+    }
+
+    /// declare packets lost by packet and time threshold
+    ///
+    /// Should also be called at `next_loss_time`. Returns (stream id,
+    /// segment) for segments of lost packets.
+    pub fn detect_lost(&mut self, now: Instant, streams: &mut StreamSet) -> Vec<(u64, Range<u64>)> {
+        let Some(largest_acked) = self.largest_acked else {
+            return Vec::new();
+        };
+        let loss_delay = self.loss_delay();
+        let lost_packets: Vec<u64> = self
+            .sent
+            .range(..largest_acked)
+            .filter(|(packet_number, packet)| {
+                largest_acked - **packet_number >= self.packet_threshold
+                    || now.saturating_duration_since(packet.time_sent) >= loss_delay
+            })
+            .map(|(n, _)| *n)
+            .collect();
+
+        let mut lost = Vec::new();
+        for packet_number in lost_packets {
+            trace!(packet_number, "packet lost");
+            let packet = self.sent.remove(&packet_number).unwrap();
+            if let Some(mtu) = &mut self.mtu {
+                mtu.on_packet_lost(packet.size);
+            }
+            for (stream_id, segment) in packet.stream_ranges {
+                if let Some(entry) = streams.get_mut(stream_id) {
+                    entry.outbound.segment_lost(segment.clone());
+                    lost.push((stream_id, segment));
+                }
+            }
+        }
+        lost
+    }
+
+    /// earliest time at which an unacknowledged packet would be declared lost
+    /// by time threshold
+    pub fn next_loss_time(&self) -> Option<Instant> {
+        let largest_acked = self.largest_acked?;
+        let (_, oldest) = self.sent.range(..largest_acked).next()?;
+        Some(oldest.time_sent + self.loss_delay())
+    }
+}
+
+impl Default for SentPacketLedger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::container::DEFAULT_STREAM_PRIORITY;
+    use crate::stream::outbound::RetransmitStrategy;
+
+    fn packet(stream_id: u64, segment: Range<u64>) -> Packet {
+        Packet {
+            data: Vec::new(),
+            stream_ranges: vec![(stream_id, segment)],
+        }
+    }
+
+    fn acked(packet_numbers: Range<u64>) -> RangeSet {
+        let mut set = RangeSet::unlimited();
+        set.insert_range(packet_numbers);
+        set
+    }
+
+    #[test]
+    fn packet_threshold() {
+        let start = Instant::now();
+        let mut streams = StreamSet::new(true, 4096);
+        let id = streams.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        streams.get_mut(id).unwrap().outbound.write_direct(&[0; 50]);
+        let mut ledger = SentPacketLedger::new();
+        for i in 0..5 {
+            let segment = i * 10..(i + 1) * 10;
+            streams
+                .get_mut(id)
+                .unwrap()
+                .outbound
+                .segment_sent(segment.clone());
+            assert_eq!(ledger.on_packet_sent(&packet(id, segment), start), i);
+        }
+
+        // packet 0 missing, but only 2 later packets acknowledged
+        let lost = ledger.on_ack(&acked(1..3), start, &mut streams);
+        assert!(lost.is_empty());
+        assert_eq!(ledger.largest_acked, Some(2));
+        let outbound = &streams.get(id).unwrap().outbound;
+        assert!(outbound.delivered.has_range(10..30));
+
+        let lost = ledger.on_ack(&acked(3..4), start, &mut streams);
+        assert_eq!(lost, vec![(id, 0..10)]);
+        assert_eq!(ledger.in_flight(), 1);
+        let outbound = &streams.get(id).unwrap().outbound;
+        assert_eq!(outbound.queued.peek_first(), Some(0..10));
+    }
+
+    #[test]
+    fn time_threshold() {
+        let start = Instant::now();
+        let mut streams = StreamSet::new(true, 4096);
+        let id = streams.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        streams.get_mut(id).unwrap().outbound.write_direct(&[0; 20]);
+        let mut ledger = SentPacketLedger::new();
+        ledger.on_packet_sent(&packet(id, 0..10), start);
+        ledger.on_packet_sent(&packet(id, 10..20), start + Duration::from_millis(10));
+        assert_eq!(ledger.next_loss_time(), None);
+
+        // packet 0 sent 110ms ago is not yet older than 9/8 of the 100ms RTT
+        let now = start + Duration::from_millis(110);
+        assert!(ledger.on_ack(&acked(1..2), now, &mut streams).is_empty());
+        assert_eq!(ledger.latest_rtt, Some(Duration::from_millis(100)));
+        let loss_time = start + Duration::from_micros(112_500);
+        assert_eq!(ledger.next_loss_time(), Some(loss_time));
+        let lost = ledger.detect_lost(loss_time, &mut streams);
+        assert_eq!(lost, vec![(id, 0..10)]);
+        assert_eq!(ledger.next_loss_time(), None);
+    }
+
+    #[test]
+    fn mtu_probe() {
+        let start = Instant::now();
+        let mut streams = StreamSet::new(true, 4096);
+        let mut ledger = SentPacketLedger::new();
+        ledger.mtu = Some(MtuDiscovery::new(1400));
+        let probe = ledger.mtu.as_ref().unwrap().build_probe(0).unwrap();
+        ledger.on_packet_sent(&probe, start);
+        assert_eq!(ledger.mtu.as_ref().unwrap().next_probe_size(), None);
+        ledger.on_ack(&acked(0..1), start, &mut streams);
+        assert_eq!(ledger.mtu.as_ref().unwrap().mtu(), 1400);
+    }
+}
@@ -0,0 +1,794 @@
+//! Container for all streams of a connection
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tracing::trace;
+
+use crate::common::buffer_pool::BufferPool;
+use crate::common::timer::TimerQueue;
+use crate::frame::{CompressedAckRanges, Frame, StreamData, StreamReset};
+use crate::stream::flow_control::{ConnectionFlowControl, DEFAULT_CONNECTION_WINDOW_LIMIT};
+use crate::stream::inbound::{ReceiveSegmentResult, StreamInboundState};
+use crate::stream::outbound::{RetransmitStrategy, StreamOutboundState};
+
+/// default stream priority
+pub const DEFAULT_STREAM_PRIORITY: u8 = 128;
+/// default stream weight within its priority level
+pub const DEFAULT_STREAM_WEIGHT: u8 = 1;
+/// default time after which unacknowledged segments are considered lost
+pub const DEFAULT_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// lifecycle state of a stream
+///
+/// Each direction closes independently: locally once we finish or reset the
+/// stream, remotely once the peer sent its final offset or reset the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamState {
+    /// both directions open
+    Open,
+    /// we finished sending, peer may still send
+    HalfClosedLocal,
+    /// peer finished sending, we may still send
+    HalfClosedRemote,
+    /// both directions closed
+    Closed,
+}
+
+/// stream event for the application
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// stream moved to a new state
+    StateChanged { stream_id: u64, state: StreamState },
+}
+
+/// state of a single stream
+pub struct StreamEntry {
+    /// inbound half
+    pub inbound: StreamInboundState,
+    /// outbound half
+    pub outbound: StreamOutboundState,
+    /// send priority, lower values are sent first
+    pub priority: u8,
+    /// consecutive picks per round-robin turn within priority level
+    pub weight: u8,
+    /// picks left in current round-robin turn
+    credit: u8,
+    /// state last reported in an event
+    reported_state: StreamState,
+}
+
+impl StreamEntry {
+    /// current lifecycle state
+    pub fn state(&self) -> StreamState {
+        let local_closed =
+            self.outbound.final_offset.is_some() || self.outbound.reset_code.is_some();
+        let remote_closed =
+            self.inbound.final_offset.is_some() || self.inbound.reset_code.is_some();
+        match (local_closed, remote_closed) {
+            (false, false) => StreamState::Open,
+            (true, false) => StreamState::HalfClosedLocal,
+            (false, true) => StreamState::HalfClosedRemote,
+            (true, true) => StreamState::Closed,
+        }
+    }
+}
+
+/// error routing frame to stream
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum StreamRouteError {
+    /// frame refers to a locally initiated stream that does not exist
+    #[error("unknown stream {0}")]
+    UnknownStream(u64),
+    /// peer sent data beyond the window limit
+    #[error("stream {0} data exceeds window limit")]
+    ExceedsWindow(u64),
+    /// peer sent data beyond the connection window limit
+    #[error("stream {0} data exceeds connection window limit")]
+    ExceedsConnectionWindow(u64),
+    /// peer sent a final offset different from the previous one
+    #[error("stream {0} final offset changed")]
+    FinalOffsetChanged(u64),
+}
+
+/// set of streams multiplexed over one connection
+///
+/// Stream ids are allocated QUIC-style, with the lowest bit indicating the
+/// initiator (0 for the side that initiated the connection).
+pub struct StreamSet {
+    /// streams by id
+    pub streams: HashMap<u64, StreamEntry>,
+    /// whether we initiated the connection
+    pub is_initiator: bool,
+    /// initial flow control window for new streams in both directions
+    pub initial_window_limit: u64,
+    /// time after which unacknowledged segments are considered lost
+    pub retransmit_timeout: Duration,
+    /// pool to allocate inbound buffers of new streams from, if any
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    /// aggregate window across all streams
+    pub flow_control: ConnectionFlowControl,
+    /// next locally initiated stream id
+    next_local_id: u64,
+    /// peer initiated streams not yet accepted
+    pending_accept: VecDeque<u64>,
+    /// round-robin send order by priority
+    schedule: BTreeMap<u8, VecDeque<u64>>,
+    /// retransmission timers for sent segments as (stream id, segment)
+    retransmit_timers: TimerQueue<(u64, Range<u64>)>,
+    /// events not yet taken by the application
+    events: VecDeque<StreamEvent>,
+}
+
+impl StreamSet {
+    /// create new instance
+    pub fn new(is_initiator: bool, initial_window_limit: u64) -> StreamSet {
+        StreamSet {
+            streams: HashMap::new(),
+            is_initiator,
+            initial_window_limit,
+            retransmit_timeout: DEFAULT_RETRANSMIT_TIMEOUT,
+            buffer_pool: None,
+            flow_control: ConnectionFlowControl::new(
+                DEFAULT_CONNECTION_WINDOW_LIMIT,
+                DEFAULT_CONNECTION_WINDOW_LIMIT,
+            ),
+            next_local_id: if is_initiator { 0 } else { 1 },
+            pending_accept: VecDeque::new(),
+            schedule: BTreeMap::new(),
+            retransmit_timers: TimerQueue::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// whether stream id was initiated locally
+    pub fn is_local(&self, stream_id: u64) -> bool {
+        (stream_id & 1 == 0) == self.is_initiator
+    }
+
+    /// insert stream with given id
+    fn insert(&mut self, stream_id: u64, strategy: RetransmitStrategy, priority: u8) {
+        let is_reliable = matches!(strategy, RetransmitStrategy::Reliable);
+        let inbound = match &self.buffer_pool {
+            Some(pool) => {
+                StreamInboundState::with_pool(self.initial_window_limit, is_reliable, pool.clone())
+            }
+            None => StreamInboundState::new(self.initial_window_limit, is_reliable),
+        };
+        let entry = StreamEntry {
+            inbound,
+            outbound: StreamOutboundState::new(self.initial_window_limit, strategy),
+            priority,
+            weight: DEFAULT_STREAM_WEIGHT,
+            credit: 0,
+            reported_state: StreamState::Open,
+        };
+        self.streams.insert(stream_id, entry);
+        self.schedule
+            .entry(priority)
+            .or_default()
+            .push_back(stream_id);
+    }
+
+    /// open new locally initiated stream, returning its id
+    pub fn open(&mut self, strategy: RetransmitStrategy, priority: u8) -> u64 {
+        let stream_id = self.next_local_id;
+        self.next_local_id += 2;
+        trace!(stream_id, "open stream");
+        self.insert(stream_id, strategy, priority);
+        stream_id
+    }
+
+    /// take next peer initiated stream
+    pub fn accept(&mut self) -> Option<u64> {
+        self.pending_accept.pop_front()
+    }
+
+    /// get stream by id
+    pub fn get(&self, stream_id: u64) -> Option<&StreamEntry> {
+        self.streams.get(&stream_id)
+    }
+
+    /// get stream by id
+    pub fn get_mut(&mut self, stream_id: u64) -> Option<&mut StreamEntry> {
+        self.streams.get_mut(&stream_id)
+    }
+
+    /// remove stream from set
+    pub fn remove(&mut self, stream_id: u64) -> Option<StreamEntry> {
+        let entry = self.streams.remove(&stream_id)?;
+        self.unschedule(stream_id, entry.priority);
+        self.pending_accept.retain(|id| *id != stream_id);
+        Some(entry)
+    }
+
+    /// remove stream from schedule
+    fn unschedule(&mut self, stream_id: u64, priority: u8) {
+        if let Some(queue) = self.schedule.get_mut(&priority) {
+            queue.retain(|id| *id != stream_id);
+            if queue.is_empty() {
+                self.schedule.remove(&priority);
+            }
+        }
+    }
+
+    /// change send priority of stream
+    pub fn set_priority(&mut self, stream_id: u64, priority: u8) {
+        let Some(entry) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        let old_priority = entry.priority;
+        if old_priority == priority {
+            return;
+        }
+        entry.priority = priority;
+        entry.credit = 0;
+        self.unschedule(stream_id, old_priority);
+        self.schedule
+            .entry(priority)
+            .or_default()
+            .push_back(stream_id);
+    }
+
+    /// finish sending on stream, setting its final offset
+    ///
+    /// The stream may still receive until the peer finishes. Streams may be
+    /// finished without any data written. Returns false if the stream does
+    /// not exist or was already finished or reset.
+    pub fn finish(&mut self, stream_id: u64) -> bool {
+        let Some(entry) = self.streams.get_mut(&stream_id) else {
+            return false;
+        };
+        let outbound = &mut entry.outbound;
+        if outbound.final_offset.is_some() || outbound.reset_code.is_some() {
+            return false;
+        }
+        outbound.finish();
+        self.update_state(stream_id);
+        true
+    }
+
+    /// abandon sending on stream, returning the frame to send to the peer
+    ///
+    /// Returns None if the stream does not exist or was already reset.
+    pub fn reset(&mut self, stream_id: u64, error_code: u64) -> Option<StreamReset> {
+        let entry = self.streams.get_mut(&stream_id)?;
+        if entry.outbound.reset_code.is_some() {
+            return None;
+        }
+        let frame = StreamReset {
+            stream_id,
+            error_code,
+        };
+        entry.outbound.apply_reset(&frame);
+        self.update_state(stream_id);
+        Some(frame)
+    }
+
+    /// take next stream event
+    pub fn poll_event(&mut self) -> Option<StreamEvent> {
+        self.events.pop_front()
+    }
+
+    /// queue event if the state of a stream changed since last reported
+    fn update_state(&mut self, stream_id: u64) {
+        let Some(entry) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        let state = entry.state();
+        if state != entry.reported_state {
+            trace!(stream_id, ?state, "stream state changed");
+            entry.reported_state = state;
+            self.events
+                .push_back(StreamEvent::StateChanged { stream_id, state });
+        }
+    }
+
+    /// write to stream, respecting stream and connection windows
+    ///
+    /// Returns the number of bytes written, which is 0 if the stream does
+    /// not exist.
+    pub fn write(&mut self, stream_id: u64, buf: &[u8]) -> usize {
+        let Some(entry) = self.streams.get_mut(&stream_id) else {
+            return 0;
+        };
+        let limit = u64::min(self.flow_control.writable(), buf.len() as u64) as usize;
+        let written = entry.outbound.write_limited(&buf[..limit]);
+        self.flow_control.record_sent(written as u64);
+        written
+    }
+
+    /// write whole message to stream, respecting stream and connection
+    /// windows
+    ///
+    /// Returns false if the stream does not exist or the message does not
+    /// fit entirely.
+    pub fn write_message(&mut self, stream_id: u64, buf: &[u8]) -> bool {
+        let Some(entry) = self.streams.get_mut(&stream_id) else {
+            return false;
+        };
+        if buf.len() as u64 > self.flow_control.writable() || !entry.outbound.write_message(buf) {
+            return false;
+        }
+        self.flow_control.record_sent(buf.len() as u64);
+        true
+    }
+
+    /// change weight of stream within its priority level
+    ///
+    /// Weights of 0 are treated as 1. Takes effect from the next turn of the
+    /// stream.
+    pub fn set_weight(&mut self, stream_id: u64, weight: u8) {
+        if let Some(entry) = self.streams.get_mut(&stream_id) {
+            entry.weight = u8::max(weight, 1);
+        }
+    }
+
+    /// pick next stream with sendable data
+    ///
+    /// Streams of lower priority values are always picked first. Streams of
+    /// the same priority are picked weighted round-robin: each turn, a stream
+    /// is picked up to `weight` times in a row while it has sendable data.
+    pub fn next_sendable(&mut self) -> Option<u64> {
+        for queue in self.schedule.values_mut() {
+            let position = queue.iter().position(|id| {
+                self.streams
+                    .get(id)
+                    .is_some_and(|entry| entry.outbound.readable())
+            });
+            if let Some(position) = position {
+                let stream_id = queue.remove(position).unwrap();
+                let entry = self.streams.get_mut(&stream_id).unwrap();
+                if entry.credit == 0 {
+                    entry.credit = u8::max(entry.weight, 1);
+                }
+                entry.credit -= 1;
+                if entry.credit > 0 {
+                    // turn continues
+                    queue.push_front(stream_id);
+                } else {
+                    queue.push_back(stream_id);
+                }
+                return Some(stream_id);
+            }
+        }
+        None
+    }
+
+    /// mark segment of stream as sent at `now`, arming its retransmission
+    /// timer if the stream retransmits
+    pub fn segment_sent(&mut self, stream_id: u64, segment: Range<u64>, now: Instant) {
+        let Some(entry) = self.streams.get_mut(&stream_id) else {
+            return;
+        };
+        entry.outbound.segment_sent(segment.clone());
+        if !matches!(
+            entry.outbound.retransmit_strategy,
+            RetransmitStrategy::Unreliable
+        ) {
+            self.retransmit_timers
+                .arm(now + self.retransmit_timeout, (stream_id, segment));
+        }
+    }
+
+    /// earliest time at which a retransmission timer expires
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.retransmit_timers.next_deadline()
+    }
+
+    /// handle expired retransmission timers, marking segments not yet
+    /// delivered as lost
+    ///
+    /// Returns (stream id, segment) for segments requeued for retransmission.
+    pub fn poll_timers(&mut self, now: Instant) -> Vec<(u64, Range<u64>)> {
+        let mut lost = Vec::new();
+        for (stream_id, segment) in self.retransmit_timers.poll(now) {
+            let Some(entry) = self.streams.get_mut(&stream_id) else {
+                continue;
+            };
+            if entry.outbound.delivered.has_range(segment.clone()) {
+                continue;
+            }
+            trace!(stream_id, ?segment, "retransmission timeout");
+            entry.outbound.segment_lost(segment.clone());
+            lost.push((stream_id, segment));
+        }
+        lost
+    }
+
+    /// get stream for incoming frame, creating it if peer initiated
+    fn get_or_accept(&mut self, stream_id: u64) -> Result<&mut StreamEntry, StreamRouteError> {
+        if !self.streams.contains_key(&stream_id) {
+            if self.is_local(stream_id) {
+                return Err(StreamRouteError::UnknownStream(stream_id));
+            }
+            trace!(stream_id, "accept stream");
+            self.insert(
+                stream_id,
+                RetransmitStrategy::Reliable,
+                DEFAULT_STREAM_PRIORITY,
+            );
+            self.pending_accept.push_back(stream_id);
+        }
+        Ok(self.streams.get_mut(&stream_id).unwrap())
+    }
+
+    /// handle incoming stream data
+    fn receive_data(&mut self, frame: &StreamData) -> Result<(), StreamRouteError> {
+        let entry = self.get_or_accept(frame.stream_id)?;
+        let end = frame.stream_offset + frame.data.len() as u64;
+        // segments rejected by the stream are not charged to the connection
+        if end > entry.inbound.window_limit {
+            return Err(StreamRouteError::ExceedsWindow(frame.stream_id));
+        }
+        let previous_end = entry.inbound.received.peek_last().map_or(0, |r| r.end);
+        if end > previous_end && !self.flow_control.record_received(end - previous_end) {
+            return Err(StreamRouteError::ExceedsConnectionWindow(frame.stream_id));
+        }
+        let entry = self.streams.get_mut(&frame.stream_id).unwrap();
+        let result = entry
+            .inbound
+            .receive_segment(frame.stream_offset, &frame.data);
+        if result == ReceiveSegmentResult::ExceedsWindow {
+            return Err(StreamRouteError::ExceedsWindow(frame.stream_id));
+        }
+        if let Some(message_offset) = frame.message_offset {
+            entry
+                .inbound
+                .set_message_marker(frame.stream_offset + message_offset as u64);
+        }
+        Ok(())
+    }
+
+    /// route incoming frame to its stream
+    ///
+    /// Returns the stream id if the frame belongs to a stream, or None if the
+    /// frame is not stream related. Connection window updates are applied,
+    /// other frames which are not stream related are ignored.
+    pub fn route_frame(&mut self, frame: &Frame) -> Result<Option<u64>, StreamRouteError> {
+        match frame {
+            Frame::StreamData(data) => {
+                self.receive_data(data)?;
+                Ok(Some(data.stream_id))
+            }
+            Frame::StreamWindowLimit(limit) => {
+                let entry = self.get_or_accept(limit.stream_id)?;
+                entry.outbound.update_remote_limit(limit.limit);
+                Ok(Some(limit.stream_id))
+            }
+            Frame::StreamFinal(fin) => {
+                let entry = self.get_or_accept(fin.stream_id)?;
+                if !entry.inbound.apply_final(fin) {
+                    return Err(StreamRouteError::FinalOffsetChanged(fin.stream_id));
+                }
+                self.update_state(fin.stream_id);
+                Ok(Some(fin.stream_id))
+            }
+            Frame::StreamReset(reset) => {
+                let entry = self.get_or_accept(reset.stream_id)?;
+                entry.inbound.apply_reset(reset);
+                self.update_state(reset.stream_id);
+                Ok(Some(reset.stream_id))
+            }
+            Frame::AckRanges(ack) | Frame::CompressedAckRanges(CompressedAckRanges(ack)) => {
+                let entry = self.get_or_accept(ack.stream_id)?;
+                ack.apply(&mut entry.outbound);
+                Ok(Some(ack.stream_id))
+            }
+            Frame::MaxData(max_data) => {
+                self.flow_control.apply_max_data(max_data);
+                Ok(None)
+            }
+            Frame::DataBlocked(blocked) => {
+                trace!(limit = blocked.limit, "peer blocked by connection window");
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::frame::{MaxData, StreamFinal, StreamReset, StreamWindowLimit};
+
+    #[test]
+    fn schedule() {
+        let mut set = StreamSet::new(true, 4096);
+        let a = set.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        let b = set.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        let urgent = set.open(RetransmitStrategy::Reliable, 0);
+        assert_eq!((a, b, urgent), (0, 2, 4));
+        assert_eq!(set.next_sendable(), None);
+
+        for id in [a, b, urgent] {
+            set.get_mut(id).unwrap().outbound.write_direct(b"hello");
+        }
+        assert_eq!(set.next_sendable(), Some(urgent));
+        assert_eq!(set.next_sendable(), Some(urgent));
+        let segment = set
+            .get(urgent)
+            .unwrap()
+            .outbound
+            .queued
+            .peek_first()
+            .unwrap();
+        set.get_mut(urgent).unwrap().outbound.segment_sent(segment);
+
+        // round-robin within priority
+        assert_eq!(set.next_sendable(), Some(a));
+        assert_eq!(set.next_sendable(), Some(b));
+        assert_eq!(set.next_sendable(), Some(a));
+
+        set.set_priority(b, 0);
+        assert_eq!(set.next_sendable(), Some(b));
+        assert!(set.remove(b).is_some());
+        assert_eq!(set.next_sendable(), Some(a));
+    }
+
+    #[test]
+    fn weighted_schedule() {
+        let mut set = StreamSet::new(true, 4096);
+        let bulk = set.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        let control = set.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        set.set_weight(bulk, 3);
+        for id in [bulk, control] {
+            set.get_mut(id).unwrap().outbound.write_direct(b"hello");
+        }
+        let picks: Vec<_> = (0..8).map(|_| set.next_sendable().unwrap()).collect();
+        assert_eq!(
+            picks,
+            [bulk, bulk, bulk, control, bulk, bulk, bulk, control]
+        );
+
+        // reprioritized stream preempts the rest of the turn
+        assert_eq!(set.next_sendable(), Some(bulk));
+        set.set_priority(control, 0);
+        assert_eq!(set.next_sendable(), Some(control));
+        assert_eq!(set.next_sendable(), Some(control));
+    }
+
+    #[test]
+    fn retransmit_timeout() {
+        let start = Instant::now();
+        let mut set = StreamSet::new(true, 4096);
+        let id = set.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        set.get_mut(id).unwrap().outbound.write_direct(&[0; 64]);
+        set.segment_sent(id, 0..32, start);
+        set.segment_sent(id, 32..64, start);
+        assert_eq!(set.next_sendable(), None);
+        assert_eq!(set.next_timeout(), Some(start + DEFAULT_RETRANSMIT_TIMEOUT));
+
+        set.get_mut(id).unwrap().outbound.segment_delivered(0..32);
+        assert!(set.poll_timers(start).is_empty());
+        let lost = set.poll_timers(start + DEFAULT_RETRANSMIT_TIMEOUT);
+        assert_eq!(lost, vec![(id, 32..64)]);
+        assert_eq!(set.next_sendable(), Some(id));
+        assert_eq!(set.next_timeout(), None);
+    }
+
+    #[test]
+    fn route() {
+        let mut set = StreamSet::new(false, 4096);
+        let data = Frame::StreamData(StreamData {
+            stream_id: 0,
+            stream_offset: 0,
+            message_offset: Some(0),
+            data: b"hello".to_vec(),
+        });
+        assert_eq!(set.route_frame(&data), Ok(Some(0)));
+        assert_eq!(set.accept(), Some(0));
+        assert_eq!(set.accept(), None);
+        assert!(set.get(0).unwrap().inbound.received.has_range(0..5));
+
+        let limit = Frame::StreamWindowLimit(StreamWindowLimit {
+            stream_id: 0,
+            limit: 8192,
+        });
+        assert_eq!(set.route_frame(&limit), Ok(Some(0)));
+        assert_eq!(set.get(0).unwrap().outbound.window_limit, 8192);
+
+        let fin = |final_offset| {
+            Frame::StreamFinal(StreamFinal {
+                stream_id: 0,
+                final_offset,
+            })
+        };
+        assert_eq!(set.route_frame(&fin(5)), Ok(Some(0)));
+        assert_eq!(set.route_frame(&fin(5)), Ok(Some(0)));
+        assert_eq!(
+            set.route_frame(&fin(6)),
+            Err(StreamRouteError::FinalOffsetChanged(0))
+        );
+        let reset = Frame::StreamReset(StreamReset {
+            stream_id: 0,
+            error_code: 2,
+        });
+        assert_eq!(set.route_frame(&reset), Ok(Some(0)));
+        assert_eq!(set.get(0).unwrap().inbound.reset_code, Some(2));
+        assert!(set.get(0).unwrap().inbound.finished());
+
+        // streams we would have opened must already exist
+        let limit = Frame::StreamWindowLimit(StreamWindowLimit {
+            stream_id: 1,
+            limit: 8192,
+        });
+        assert_eq!(
+            set.route_frame(&limit),
+            Err(StreamRouteError::UnknownStream(1))
+        );
+    }
+
+    #[test]
+    fn half_close() {
+        let mut set = StreamSet::new(true, 4096);
+        let id = set.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        assert_eq!(set.get(id).unwrap().state(), StreamState::Open);
+        assert_eq!(set.write(id, b"request"), 7);
+        assert!(set.finish(id));
+        assert!(!set.finish(id));
+        assert_eq!(
+            set.poll_event(),
+            Some(StreamEvent::StateChanged {
+                stream_id: id,
+                state: StreamState::HalfClosedLocal
+            })
+        );
+        assert_eq!(set.poll_event(), None);
+
+        // still receiving after finishing
+        let data = Frame::StreamData(StreamData {
+            stream_id: id,
+            stream_offset: 0,
+            message_offset: None,
+            data: b"response".to_vec(),
+        });
+        assert_eq!(set.route_frame(&data), Ok(Some(id)));
+        assert_eq!(set.poll_event(), None);
+        let fin = Frame::StreamFinal(StreamFinal {
+            stream_id: id,
+            final_offset: 8,
+        });
+        assert_eq!(set.route_frame(&fin), Ok(Some(id)));
+        assert_eq!(
+            set.poll_event(),
+            Some(StreamEvent::StateChanged {
+                stream_id: id,
+                state: StreamState::Closed
+            })
+        );
+
+        // peer opens and immediately finishes a stream without data
+        let fin = Frame::StreamFinal(StreamFinal {
+            stream_id: 1,
+            final_offset: 0,
+        });
+        assert_eq!(set.route_frame(&fin), Ok(Some(1)));
+        assert_eq!(set.accept(), Some(1));
+        assert_eq!(
+            set.poll_event(),
+            Some(StreamEvent::StateChanged {
+                stream_id: 1,
+                state: StreamState::HalfClosedRemote
+            })
+        );
+        let entry = set.get(1).unwrap();
+        assert_eq!(entry.state(), StreamState::HalfClosedRemote);
+        assert!(entry.inbound.finished());
+        assert_eq!(set.write(1, b"reply"), 5);
+        assert!(set.reset(1, 3).is_some());
+        assert!(set.reset(1, 3).is_none());
+        assert_eq!(
+            set.poll_event(),
+            Some(StreamEvent::StateChanged {
+                stream_id: 1,
+                state: StreamState::Closed
+            })
+        );
+
+        let empty = set.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        assert!(set.finish(empty));
+        assert!(set.get(empty).unwrap().outbound.finished());
+    }
+
+    #[test]
+    fn messages() {
+        let mut sender = StreamSet::new(true, 4096);
+        let mut receiver = StreamSet::new(false, 4096);
+        let id = sender.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        let messages: [&[u8]; 3] = [b"first message", b"second", b"third message"];
+        for message in messages {
+            assert!(sender.write_message(id, message));
+        }
+        assert!(!sender.write_message(id, b""));
+        assert!(sender.finish(id));
+
+        // send in small segments, delivering the first one last
+        let mut frames = Vec::new();
+        let mut segments = Vec::new();
+        while let Some(segment) = sender.get_mut(id).unwrap().outbound.next_segment(16) {
+            segments.push(segment.clone());
+            let outbound = &sender.get(id).unwrap().outbound;
+            let (slice, marker) = outbound.read_segment(segment.clone()).unwrap();
+            let mut data = vec![0; slice.len()];
+            slice.copy_to_slice(&mut data);
+            frames.push(Frame::StreamData(StreamData {
+                stream_id: id,
+                stream_offset: segment.start,
+                message_offset: marker.map(|m| (m - segment.start) as u16),
+                data,
+            }));
+            sender.segment_sent(id, segment, Instant::now());
+        }
+        // each segment carries at most one marker
+        assert_eq!(segments, [0..13, 13..19, 19..32]);
+        let first = frames.remove(0);
+        for frame in &frames {
+            receiver.route_frame(frame).unwrap();
+        }
+        assert_eq!(receiver.get_mut(id).unwrap().inbound.read_message(), None);
+        receiver.route_frame(&first).unwrap();
+        let inbound = &mut receiver.get_mut(id).unwrap().inbound;
+        assert_eq!(inbound.read_message().unwrap(), messages[0]);
+        assert_eq!(inbound.read_message().unwrap(), messages[1]);
+
+        // last message ends at the final offset
+        assert_eq!(inbound.read_message(), None);
+        let fin = Frame::StreamFinal(StreamFinal {
+            stream_id: id,
+            final_offset: 32,
+        });
+        receiver.route_frame(&fin).unwrap();
+        let inbound = &mut receiver.get_mut(id).unwrap().inbound;
+        assert_eq!(inbound.read_message().unwrap(), messages[2]);
+        assert_eq!(inbound.read_message(), None);
+        assert!(inbound.finished());
+    }
+
+    #[test]
+    fn connection_window() {
+        let mut set = StreamSet::new(true, 4096);
+        set.flow_control = ConnectionFlowControl::new(100, 10);
+        let a = set.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        let b = set.open(RetransmitStrategy::Reliable, DEFAULT_STREAM_PRIORITY);
+        assert_eq!(set.write(a, &[0; 60]), 60);
+        assert_eq!(set.write(b, &[0; 60]), 40);
+        assert_eq!(set.write(a, &[0; 10]), 0);
+        assert_eq!(set.flow_control.poll_blocked().map(|f| f.limit), Some(100));
+        let max_data = Frame::MaxData(MaxData { limit: 110 });
+        assert_eq!(set.route_frame(&max_data), Ok(None));
+        assert_eq!(set.write(a, &[0; 20]), 10);
+
+        // retransmissions and holes count only once
+        let data = |offset, len| {
+            Frame::StreamData(StreamData {
+                stream_id: 1,
+                stream_offset: offset,
+                message_offset: None,
+                data: vec![0; len],
+            })
+        };
+        assert_eq!(set.route_frame(&data(4, 4)), Ok(Some(1)));
+        assert_eq!(set.route_frame(&data(0, 4)), Ok(Some(1)));
+        assert_eq!(set.route_frame(&data(4, 4)), Ok(Some(1)));
+        assert_eq!(set.flow_control.received, 8);
+        assert_eq!(
+            set.route_frame(&data(8, 4)),
+            Err(StreamRouteError::ExceedsConnectionWindow(1))
+        );
+        assert_eq!(set.route_frame(&data(8, 2)), Ok(Some(1)));
+
+        // segments beyond the stream window leave the connection window alone
+        let mut set = StreamSet::new(true, 16);
+        assert_eq!(
+            set.route_frame(&data(8, 12)),
+            Err(StreamRouteError::ExceedsWindow(1))
+        );
+        assert_eq!(set.flow_control.received, 0);
+        assert_eq!(set.route_frame(&data(8, 8)), Ok(Some(1)));
+        assert_eq!(set.flow_control.received, 16);
+    }
+}
@@ -0,0 +1,129 @@
+//! Connection-level flow control
+//!
+//! Stream windows limit each stream separately. The connection window limits
+//! the sum of stream bytes across all streams, QUIC-style: the sending side
+//! counts bytes written to any stream, the receiving side counts how far the
+//! highest received offset of each stream has advanced. Limits are raised
+//! with `MaxData` frames, and a sender blocked by the limit reports it once
+//! per limit with a `DataBlocked` frame.
+
+use tracing::trace;
+
+use crate::frame::{DataBlocked, MaxData};
+
+/// default connection window limit in both directions
+pub const DEFAULT_CONNECTION_WINDOW_LIMIT: u64 = 16 << 20; // 16 MB
+
+/// aggregate flow control state of all streams of a connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionFlowControl {
+    /// stream bytes written across all streams
+    pub sent: u64,
+    /// peer limit on stream bytes written across all streams
+    pub send_limit: u64,
+    /// send limit at which DataBlocked was last reported
+    blocked_reported: Option<u64>,
+    /// stream bytes received across all streams
+    pub received: u64,
+    /// our limit on stream bytes received across all streams
+    pub receive_limit: u64,
+}
+
+impl ConnectionFlowControl {
+    /// create new instance
+    pub fn new(send_limit: u64, receive_limit: u64) -> ConnectionFlowControl {
+        ConnectionFlowControl {
+            sent: 0,
+            send_limit,
+            blocked_reported: None,
+            received: 0,
+            receive_limit,
+        }
+    }
+
+    /// bytes which may still be written across all streams
+    pub fn writable(&self) -> u64 {
+        self.send_limit.saturating_sub(self.sent)
+    }
+
+    /// record bytes written to any stream
+    pub fn record_sent(&mut self, len: u64) {
+        debug_assert!(len <= self.writable(), "connection window exceeded");
+        self.sent += len;
+    }
+
+    /// apply limit update from peer, returns true if the window advanced
+    pub fn apply_max_data(&mut self, frame: &MaxData) -> bool {
+        if frame.limit > self.send_limit {
+            trace!(limit = frame.limit, "connection window advanced");
+            self.send_limit = frame.limit;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// get DataBlocked frame to send if writes are blocked by the connection
+    /// window and this was not yet reported for the current limit
+    pub fn poll_blocked(&mut self) -> Option<DataBlocked> {
+        if self.writable() > 0 || self.blocked_reported == Some(self.send_limit) {
+            return None;
+        }
+        self.blocked_reported = Some(self.send_limit);
+        Some(DataBlocked {
+            limit: self.send_limit,
+        })
+    }
+
+    /// record advance of the highest received offset of a stream by `len`
+    /// bytes
+    ///
+    /// Returns false without recording anything if this would exceed the
+    /// receive limit.
+    pub fn record_received(&mut self, len: u64) -> bool {
+        match self.received.checked_add(len) {
+            Some(received) if received <= self.receive_limit => {
+                self.received = received;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// raise receive limit, returning MaxData frame to send to the peer if
+    /// the limit advanced
+    pub fn set_receive_limit(&mut self, limit: u64) -> Option<MaxData> {
+        if limit > self.receive_limit {
+            self.receive_limit = limit;
+            Some(MaxData { limit })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let mut flow = ConnectionFlowControl::new(100, 50);
+        flow.record_sent(60);
+        assert_eq!(flow.writable(), 40);
+        assert!(flow.poll_blocked().is_none());
+        flow.record_sent(40);
+        assert_eq!(flow.poll_blocked().map(|f| f.limit), Some(100));
+        assert!(flow.poll_blocked().is_none());
+        assert!(!flow.apply_max_data(&MaxData { limit: 80 }));
+        assert!(flow.apply_max_data(&MaxData { limit: 150 }));
+        assert_eq!(flow.writable(), 50);
+
+        assert!(flow.record_received(50));
+        assert!(!flow.record_received(1));
+        assert!(flow.set_receive_limit(40).is_none());
+        assert_eq!(flow.set_receive_limit(60).map(|f| f.limit), Some(60));
+        assert!(flow.record_received(10));
+        assert_eq!(flow.received, 60);
+    }
+}
@@ -1,12 +1,16 @@
 //! Stream inbound implementation
 
 use std::collections::BTreeMap;
+use std::io::IoSlice;
 use std::ops::Range;
+use std::sync::Arc;
 
 use tracing::trace;
 
-use crate::common::range_set::RangeSet;
+use crate::common::buffer_pool::BufferPool;
+use crate::common::range_set::{RangeSet, VecRanges};
 use crate::common::ring_buffer::{RingBuf, RingBufSlice};
+use crate::frame::{StreamFinal, StreamReset};
 
 /// stream inbound buffer
 pub struct StreamInboundState {
@@ -16,7 +20,7 @@ pub struct StreamInboundState {
     pub buffer_offset: u64,
 
     /// received segments
-    pub received: RangeSet,
+    pub received: RangeSet<VecRanges>,
     /// offsets into the stream where messages begin, if applicable
     pub message_offsets: BTreeMap<u64, Option<u32>>,
     /// whether stream is operating in reliable mode
@@ -25,6 +29,104 @@ pub struct StreamInboundState {
     pub window_limit: u64,
     /// final length of stream (offset of final byte + 1)
     pub final_offset: Option<u64>,
+    /// error code if stream was reset by sender
+    pub reset_code: Option<u64>,
+    /// pool to allocate buffer from, if any
+    pub pool: Option<Arc<BufferPool>>,
+    /// handling of received data overlapping already received data
+    pub overlap_policy: OverlapPolicy,
+    /// overlaps with differing content found by `receive_segment`, to be
+    /// drained by the caller
+    pub conflicts: Vec<OverlapConflict>,
+    /// segment each buffered range was taken from, keyed by start of the
+    /// range as (end of range, segment). Only kept for overlap policies
+    /// comparing segment boundaries
+    pub segment_origins: BTreeMap<u64, (u64, Range<u64>)>,
+}
+
+/// handling of segments overlapping already received data with different
+/// content
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OverlapPolicy {
+    /// keep the data received first, without comparing
+    #[default]
+    FirstWins,
+    /// replace buffered data with the data received last and record conflicts
+    LastWins,
+    /// keep the data received first and record conflicts
+    Record,
+    /// keep the data received first unless the new segment starts before
+    /// it, and record conflicts
+    Bsd,
+    /// as `Bsd`, but the new segment also wins if it starts at the same
+    /// offset and is not shorter
+    Linux,
+    /// keep the data received first unless the new segment starts before
+    /// and ends at or after it, and record conflicts
+    Windows,
+}
+
+impl OverlapPolicy {
+    /// whether the policy depends on the segment buffered data came from
+    fn uses_segments(self) -> bool {
+        matches!(
+            self,
+            OverlapPolicy::Bsd | OverlapPolicy::Linux | OverlapPolicy::Windows
+        )
+    }
+
+    /// whether data of `segment` replaces data of the segment `existing` it
+    /// overlaps
+    ///
+    /// If the segment buffered data came from is not known, e.g. after
+    /// restoring from a snapshot, `existing` spans all contiguous data
+    /// received around the overlap.
+    fn new_wins(self, existing: &Range<u64>, segment: &Range<u64>) -> bool {
+        match self {
+            OverlapPolicy::FirstWins | OverlapPolicy::Record => false,
+            OverlapPolicy::LastWins => true,
+            OverlapPolicy::Bsd => segment.start < existing.start,
+            OverlapPolicy::Linux => {
+                segment.start < existing.start
+                    || (segment.start == existing.start && segment.end >= existing.end)
+            }
+            OverlapPolicy::Windows => segment.start < existing.start && segment.end >= existing.end,
+        }
+    }
+}
+
+/// range of a received segment which differs from data received earlier
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OverlapConflict {
+    /// stream offset of first differing byte
+    pub offset: u64,
+    /// data received earlier, from first to last differing byte
+    pub original: Vec<u8>,
+    /// data of the new segment at the same offsets
+    pub conflicting: Vec<u8>,
+}
+
+/// inbound stream metadata, excluding buffered data
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StreamInboundSnapshot {
+    /// stream offset at which buffer starts
+    pub buffer_offset: u64,
+    /// received segments
+    pub received: RangeSet<VecRanges>,
+    /// offsets into the stream where messages begin, if applicable
+    pub message_offsets: BTreeMap<u64, Option<u32>>,
+    /// whether stream is operating in reliable mode
+    pub is_reliable: bool,
+    /// flow control limit
+    pub window_limit: u64,
+    /// final length of stream (offset of final byte + 1)
+    pub final_offset: Option<u64>,
+    /// error code if stream was reset by sender
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reset_code: Option<u64>,
 }
 
 /// result enum of StreamInboundState::receive_segment
@@ -54,11 +156,70 @@ impl StreamInboundState {
         StreamInboundState {
             buffer: RingBuf::new(),
             buffer_offset: 0,
-            received: RangeSet::unlimited(),
+            received: RangeSet::default(),
             message_offsets: BTreeMap::new(),
             is_reliable,
             window_limit: initial_window_limit,
             final_offset: None,
+            reset_code: None,
+            pool: None,
+            overlap_policy: OverlapPolicy::FirstWins,
+            conflicts: Vec::new(),
+            segment_origins: BTreeMap::new(),
+        }
+    }
+
+    /// create new instance allocating its buffer from a shared pool
+    ///
+    /// The buffer is taken from the pool when data is first received and
+    /// given back whenever it is fully consumed.
+    pub fn with_pool(
+        initial_window_limit: u64,
+        is_reliable: bool,
+        pool: Arc<BufferPool>,
+    ) -> StreamInboundState {
+        let mut state = StreamInboundState::new(initial_window_limit, is_reliable);
+        state.pool = Some(pool);
+        state
+    }
+
+    /// capture stream metadata for checkpointing
+    pub fn snapshot(&self) -> StreamInboundSnapshot {
+        StreamInboundSnapshot {
+            buffer_offset: self.buffer_offset,
+            received: self.received.clone(),
+            message_offsets: self.message_offsets.clone(),
+            is_reliable: self.is_reliable,
+            window_limit: self.window_limit,
+            final_offset: self.final_offset,
+            reset_code: self.reset_code,
+        }
+    }
+
+    /// restore stream from snapshot and buffer contents starting at
+    /// `buffer_offset`
+    ///
+    /// Received segments past the end of `buffer` are discarded.
+    pub fn restore(snapshot: StreamInboundSnapshot, buffer: &[u8]) -> StreamInboundState {
+        let mut state = StreamInboundState::new(snapshot.window_limit, snapshot.is_reliable);
+        let buffer_end = snapshot.buffer_offset + buffer.len() as u64;
+        state.buffer.push_back_copy_from_slice(buffer);
+        state.buffer_offset = snapshot.buffer_offset;
+        state.received = snapshot.received;
+        state.received.remove_range(buffer_end..);
+        state.message_offsets = snapshot.message_offsets;
+        state.final_offset = snapshot.final_offset;
+        state.reset_code = snapshot.reset_code;
+        state
+    }
+
+    /// give buffer back to pool, if any
+    fn release_buffer(&mut self) {
+        if let Some(pool) = &self.pool {
+            if self.buffer.capacity() > 0 {
+                let buffer = std::mem::take(&mut self.buffer);
+                pool.give(buffer.into_storage());
+            }
         }
     }
 
@@ -69,8 +230,15 @@ impl StreamInboundState {
         if tail > self.window_limit {
             return ReceiveSegmentResult::ExceedsWindow;
         }
+        if data.is_empty() {
+            // nothing new, and empty ranges cannot be recorded
+            return ReceiveSegmentResult::Duplicate;
+        }
 
         let segment = offset..tail;
+        if self.overlap_policy != OverlapPolicy::FirstWins {
+            self.compare_overlap(offset, data);
+        }
         if self.received.has_range(segment.clone()) {
             return ReceiveSegmentResult::Duplicate;
         }
@@ -79,11 +247,17 @@ impl StreamInboundState {
         let buffer_end: usize = (segment.end - self.buffer_offset)
             .try_into()
             .expect("window limit invalid");
+        if self.buffer.capacity() == 0 {
+            if let Some(pool) = &self.pool {
+                self.buffer = RingBuf::from_storage(pool.take(buffer_end));
+            }
+        }
         if buffer_end > self.buffer.len() {
             self.buffer.fill_at_back(buffer_end - self.buffer.len(), 0);
         }
 
         // copy new ranges
+        let track_origins = self.overlap_policy.uses_segments();
         for to_copy in self.received.range_complement(segment.clone()) {
             let len: usize = (to_copy.end - to_copy.start).try_into().unwrap();
             let buffer_index: usize = to_copy
@@ -99,6 +273,10 @@ impl StreamInboundState {
             self.buffer
                 .range_mut(buffer_index..buffer_index + len)
                 .copy_from_slice(data_slice);
+            if track_origins {
+                self.segment_origins
+                    .insert(to_copy.start, (to_copy.end, segment.clone()));
+            }
         }
 
         self.received.insert_range(segment);
@@ -106,6 +284,122 @@ impl StreamInboundState {
         ReceiveSegmentResult::Received
     }
 
+    /// compare new segment with buffered data it overlaps, recording
+    /// conflicts and applying the overlap policy
+    ///
+    /// Data already removed from the buffer cannot be compared.
+    fn compare_overlap(&mut self, offset: u64, data: &[u8]) {
+        let full_segment = offset..offset + data.len() as u64;
+        let segment = u64::max(offset, self.buffer_offset)..full_segment.end;
+        if segment.is_empty() {
+            return;
+        }
+        let mut overlaps = Vec::new();
+        for r in self.received.iter_range(segment.clone()) {
+            let overlap = u64::max(r.start, segment.start)..u64::min(r.end, segment.end);
+            if !overlap.is_empty() {
+                self.split_by_origin(overlap, &r, &mut overlaps);
+            }
+        }
+        for (overlap, origin) in overlaps {
+            let new_wins = self.overlap_policy.new_wins(&origin, &full_segment);
+            if new_wins && self.overlap_policy.uses_segments() {
+                self.set_origin(overlap.clone(), full_segment.clone());
+            }
+            let len = (overlap.end - overlap.start) as usize;
+            let buffer_index = (overlap.start - self.buffer_offset) as usize;
+            let data_index = (overlap.start - offset) as usize;
+            let new = &data[data_index..data_index + len];
+            let mut existing = vec![0; len];
+            self.buffer
+                .range(buffer_index..buffer_index + len)
+                .copy_to_slice(&mut existing);
+            let Some(first) = (0..len).find(|&i| existing[i] != new[i]) else {
+                continue;
+            };
+            let last = (first..len).rfind(|&i| existing[i] != new[i]).unwrap();
+            trace!(
+                "conflicting overlap of {} bytes at offset {}",
+                last + 1 - first,
+                overlap.start + first as u64
+            );
+            self.conflicts.push(OverlapConflict {
+                offset: overlap.start + first as u64,
+                original: existing[first..=last].to_vec(),
+                conflicting: new[first..=last].to_vec(),
+            });
+            if new_wins {
+                self.buffer
+                    .range_mut(buffer_index..buffer_index + len)
+                    .copy_from_slice(new);
+            }
+        }
+    }
+
+    /// split buffered range into parts taken from different segments, pushing
+    /// each part with its segment to `out`
+    ///
+    /// Parts without known segment are attributed to `merged`, the received
+    /// range containing them.
+    fn split_by_origin(
+        &self,
+        range: Range<u64>,
+        merged: &Range<u64>,
+        out: &mut Vec<(Range<u64>, Range<u64>)>,
+    ) {
+        let first = self
+            .segment_origins
+            .range(..=range.start)
+            .next_back()
+            .map_or(range.start, |(&start, _)| start);
+        let mut pos = range.start;
+        for (&start, (end, origin)) in self.segment_origins.range(first..range.end) {
+            if *end <= pos {
+                continue;
+            }
+            let start = u64::max(start, pos);
+            if start > pos {
+                out.push((pos..start, merged.clone()));
+            }
+            let end = u64::min(*end, range.end);
+            out.push((start..end, origin.clone()));
+            pos = end;
+        }
+        if pos < range.end {
+            out.push((pos..range.end, merged.clone()));
+        }
+    }
+
+    /// record that buffered range now holds data of `segment`
+    fn set_origin(&mut self, range: Range<u64>, segment: Range<u64>) {
+        // split range starting before
+        if let Some((&start, (end, origin))) = self.segment_origins.range(..range.start).next_back()
+        {
+            if *end > range.start {
+                let (end, origin) = (*end, origin.clone());
+                self.segment_origins
+                    .insert(start, (range.start, origin.clone()));
+                if end > range.end {
+                    self.segment_origins.insert(range.end, (end, origin));
+                }
+            }
+        }
+        // replace ranges starting within, keeping the part past the end
+        let within: Vec<u64> = self
+            .segment_origins
+            .range(range.clone())
+            .map(|(&start, _)| start)
+            .collect();
+        for start in within {
+            let (end, origin) = self.segment_origins.remove(&start).unwrap();
+            if end > range.end {
+                self.segment_origins.insert(range.end, (end, origin));
+            }
+        }
+        self.segment_origins
+            .insert(range.start, (range.end, segment));
+    }
+
     /// advance window limit
     pub fn set_limit(&mut self, new_limit: u64) {
         assert!(new_limit >= self.window_limit, "limit cannot go backwards");
@@ -144,6 +438,25 @@ impl StreamInboundState {
         }
     }
 
+    /// apply final offset frame from sender
+    ///
+    /// Returns false if a different final offset was already set.
+    pub fn apply_final(&mut self, frame: &StreamFinal) -> bool {
+        self.set_final_offset(frame.final_offset) || self.final_offset == Some(frame.final_offset)
+    }
+
+    /// apply reset frame from sender
+    ///
+    /// Data received so far remains readable, but no further data is
+    /// expected and the stream is considered finished. Only the first error
+    /// code is kept.
+    pub fn apply_reset(&mut self, frame: &StreamReset) {
+        if self.reset_code.is_none() {
+            trace!(error_code = frame.error_code, "stream reset");
+            self.reset_code = Some(frame.error_code);
+        }
+    }
+
     /// advance buffer, discarding data lower than the new base offset
     pub fn advance_buffer(&mut self, new_base: u64) {
         if new_base < self.buffer_offset {
@@ -163,6 +476,9 @@ impl StreamInboundState {
             self.buffer.drain(..(delta as usize));
         }
         self.buffer_offset += delta;
+        if self.buffer.is_empty() {
+            self.release_buffer();
+        }
 
         trace!(delta, "advance buffer");
 
@@ -170,6 +486,16 @@ impl StreamInboundState {
         if !self.message_offsets.is_empty() {
             self.message_offsets = self.message_offsets.split_off(&new_base);
         }
+        // discard segments of data no longer buffered
+        if !self.segment_origins.is_empty() {
+            let mut kept = self.segment_origins.split_off(&new_base);
+            if let Some((_, (end, origin))) = self.segment_origins.pop_last() {
+                if end > new_base {
+                    kept.insert(new_base, (end, origin));
+                }
+            }
+            self.segment_origins = kept;
+        }
 
         // mark everything prior as received
         self.received.insert_range(0..new_base);
@@ -204,7 +530,10 @@ impl StreamInboundState {
     /// return the highest offset into the stream for which no gaps exist
     /// between it and `buffer_offset`
     pub fn max_contiguous_offset(&self) -> Option<u64> {
-        self.received.peek_first().map(|r| r.end)
+        self.received
+            .peek_first()
+            .filter(|r| r.start <= self.buffer_offset)
+            .map(|r| r.end)
     }
 
     /// read available bytes from start of buffer
@@ -216,23 +545,54 @@ impl StreamInboundState {
         if self.buffer_offset == available {
             None
         } else {
-            let len = u64::min(available, limit as u64) as usize;
+            let len = u64::min(available - self.buffer_offset, limit as u64) as usize;
             Some(self.buffer.range(0..len))
         }
     }
 
+    /// read next complete message and advance past it
+    ///
+    /// The stream is assumed to start with a message. Messages end at the
+    /// next message marker, or at the final offset for the last message.
+    /// Returns None until the whole message has been received. Only makes
+    /// sense when `is_reliable = true`.
+    pub fn read_message(&mut self) -> Option<Vec<u8>> {
+        let start = self.buffer_offset;
+        let end = match self.message_offsets.range(start + 1..).next() {
+            Some((&marker, _)) => marker,
+            None => self.final_offset.filter(|&end| end > start)?,
+        };
+        if self.max_contiguous_offset()? < end {
+            return None;
+        }
+        // cast safety: message is in buffer
+        let slice = self.buffer.range(0..(end - start) as usize);
+        let mut message = vec![0; slice.len()];
+        slice.copy_to_slice(&mut message);
+        self.advance_buffer(end);
+        Some(message)
+    }
+
+    /// read available bytes from start of buffer as IoSlices, for writing
+    /// directly out of the buffer with vectored writes
+    pub fn read_next_vectored(&self, limit: usize) -> Option<[IoSlice<'_>; 2]> {
+        self.read_next(limit).map(|slice| slice.as_io_slices())
+    }
+
     /// check if stream is fully received
     ///
     /// If unreliable, will return true as soon as a final offset is received,
-    /// even if more segments are in transit.
+    /// even if more segments are in transit. Reset streams are always
+    /// finished.
     pub fn finished(&self) -> bool {
-        if let Some(final_offset) = self.final_offset {
+        if self.reset_code.is_some() {
+            true
+        } else if let Some(final_offset) = self.final_offset {
             if !self.is_reliable {
                 true
-            } else if let Some(max_received) = self.max_contiguous_offset() {
-                max_received >= final_offset
             } else {
-                false
+                let max_received = self.max_contiguous_offset().unwrap_or(self.buffer_offset);
+                max_received >= final_offset
             }
         } else {
             false
@@ -240,11 +600,20 @@ impl StreamInboundState {
     }
 }
 
+impl Drop for StreamInboundState {
+    fn drop(&mut self) {
+        self.release_buffer();
+    }
+}
+
 #[cfg(test)]
 pub mod test {
+    use std::sync::Arc;
+
+    use crate::common::buffer_pool::BufferPool;
     use crate::stream::inbound::ReceiveSegmentResult;
 
-    use super::StreamInboundState;
+    use super::{OverlapConflict, OverlapPolicy, StreamInboundState};
 
     #[test]
     fn receive() {
@@ -267,6 +636,10 @@ pub mod test {
             inbound.receive_segment(3, &[3]),
             ReceiveSegmentResult::Duplicate
         );
+        assert_eq!(
+            inbound.receive_segment(100, &[]),
+            ReceiveSegmentResult::Duplicate
+        );
         assert!(inbound.set_final_offset((hello.len() + world.len()) as u64));
         let slice = inbound.read_next(64).unwrap();
         let mut read = vec![0; slice.len()];
@@ -275,4 +648,197 @@ pub mod test {
         assert_eq!(hello2, hello + &world);
         assert!(inbound.finished());
     }
+
+    #[test]
+    fn read_after_advance() {
+        let mut inbound = StreamInboundState::new(4096, true);
+        // nothing readable while the start of the stream is missing
+        assert_eq!(
+            inbound.receive_segment(5, b"world"),
+            ReceiveSegmentResult::Received
+        );
+        assert_eq!(inbound.max_contiguous_offset(), None);
+        assert!(inbound.read_next(64).is_none());
+
+        assert_eq!(
+            inbound.receive_segment(0, b"hello"),
+            ReceiveSegmentResult::Received
+        );
+        assert_eq!(inbound.max_contiguous_offset(), Some(10));
+        inbound.advance_buffer(3);
+        // length is relative to the buffer offset
+        assert_eq!(inbound.read_next(64).unwrap().len(), 7);
+        assert_eq!(inbound.read_next(4).unwrap().len(), 4);
+        let [a, b] = inbound.read_next_vectored(64).unwrap();
+        assert_eq!(a.len() + b.len(), 7);
+        inbound.advance_buffer(10);
+        assert!(inbound.read_next(64).is_none());
+    }
+
+    #[test]
+    fn pooled_buffer() {
+        let pool = Arc::new(BufferPool::new(64, 4));
+        let mut inbound = StreamInboundState::with_pool(4096, true, pool.clone());
+        assert_eq!(inbound.buffer.capacity(), 0);
+        assert_eq!(
+            inbound.receive_segment(0, b"hello"),
+            ReceiveSegmentResult::Received
+        );
+        assert!(inbound.buffer.capacity() >= 64);
+        inbound.advance_buffer(5);
+        assert_eq!(inbound.buffer.capacity(), 0);
+        assert_eq!(pool.metrics().free, 1);
+
+        // reuses pooled buffer, returns it on drop
+        assert_eq!(
+            inbound.receive_segment(5, b"world"),
+            ReceiveSegmentResult::Received
+        );
+        assert_eq!(pool.metrics().reused, 1);
+        drop(inbound);
+        assert_eq!(pool.metrics().free, 1);
+        assert_eq!(pool.metrics().allocated, 1);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut inbound = StreamInboundState::new(4096, true);
+        assert_eq!(
+            inbound.receive_segment(0, b"hello"),
+            ReceiveSegmentResult::Received
+        );
+        assert_eq!(
+            inbound.receive_segment(8, b"world"),
+            ReceiveSegmentResult::Received
+        );
+        inbound.advance_buffer(2);
+        let snapshot = inbound.snapshot();
+
+        let restored = StreamInboundState::restore(snapshot.clone(), b"llo\0\0\0wo");
+        assert_eq!(restored.buffer_offset, 2);
+        assert!(restored.received.has_range(8..10));
+        assert!(!restored.received.has_value(10));
+        let mut read = [0; 3];
+        restored.read_next(3).unwrap().copy_to_slice(&mut read);
+        assert_eq!(&read, b"llo");
+        assert_eq!(restored.snapshot().received.peek_last(), Some(8..10));
+        assert_eq!(
+            StreamInboundState::restore(snapshot, &[])
+                .received
+                .peek_last(),
+            Some(0..2)
+        );
+    }
+
+    #[test]
+    fn overlap_policy() {
+        for policy in [OverlapPolicy::Record, OverlapPolicy::LastWins] {
+            let mut inbound = StreamInboundState::new(4096, true);
+            inbound.overlap_policy = policy;
+            assert_eq!(
+                inbound.receive_segment(0, b"hello world"),
+                ReceiveSegmentResult::Received
+            );
+            // identical retransmission is not a conflict
+            assert_eq!(
+                inbound.receive_segment(0, b"hello"),
+                ReceiveSegmentResult::Duplicate
+            );
+            assert!(inbound.conflicts.is_empty());
+            // partially new segment with conflicting overlap
+            assert_eq!(
+                inbound.receive_segment(6, b"wXrlZ!!"),
+                ReceiveSegmentResult::Received
+            );
+            assert_eq!(
+                inbound.conflicts,
+                [OverlapConflict {
+                    offset: 7,
+                    original: b"orld".to_vec(),
+                    conflicting: b"XrlZ".to_vec(),
+                }]
+            );
+            let mut read = [0; 13];
+            inbound.read_next(13).unwrap().copy_to_slice(&mut read);
+            let expected: &[u8] = match policy {
+                OverlapPolicy::LastWins => b"hello wXrlZ!!",
+                _ => b"hello world!!",
+            };
+            assert_eq!(&read, expected);
+        }
+    }
+
+    #[test]
+    fn os_overlap_policies() {
+        let cases: [(OverlapPolicy, [&[u8]; 3]); 3] = [
+            // starting before, starting at same offset and longer, within
+            (OverlapPolicy::Bsd, [b"YYYY", b"abcd", b"abcd"]),
+            (OverlapPolicy::Linux, [b"YYYY", b"ZZZZ", b"abcd"]),
+            (OverlapPolicy::Windows, [b"YYYY", b"abcd", b"abcd"]),
+        ];
+        for (policy, expected) in cases {
+            let segments: [(u64, &[u8]); 3] = [(2, b"XXYYYY"), (4, b"ZZZZzz"), (5, b"WW")];
+            for ((offset, data), expected) in segments.into_iter().zip(expected) {
+                let mut inbound = StreamInboundState::new(4096, true);
+                inbound.overlap_policy = policy;
+                assert_eq!(
+                    inbound.receive_segment(4, b"abcd"),
+                    ReceiveSegmentResult::Received
+                );
+                let _ = inbound.receive_segment(offset, data);
+                assert_eq!(inbound.conflicts.len(), 1);
+                let mut read = [0; 4];
+                inbound.buffer.range(4..8).copy_to_slice(&mut read);
+                assert_eq!(&read, expected, "{policy:?} at offset {offset}");
+            }
+        }
+    }
+
+    #[test]
+    fn overlap_policy_segment_boundaries() {
+        let cases: [(OverlapPolicy, &[u8], &[u8]); 3] = [
+            (OverlapPolicy::Bsd, b"XXXXXXXXXX", b"aaaaaaaaaaXXXXXbbbbb"),
+            (OverlapPolicy::Linux, b"XXXXXXXXXX", b"aaaaaaaaaaXXXXXbbbbb"),
+            (
+                OverlapPolicy::Windows,
+                b"XXXXXXXXXXXXXXX",
+                b"aaaaaaaaaaXXXXXXXXXX",
+            ),
+        ];
+        for (policy, data, expected) in cases {
+            let mut inbound = StreamInboundState::new(4096, true);
+            inbound.overlap_policy = policy;
+            assert_eq!(
+                inbound.receive_segment(0, b"aaaaaaaaaa"),
+                ReceiveSegmentResult::Received
+            );
+            assert_eq!(
+                inbound.receive_segment(10, b"bbbbbbbbbb"),
+                ReceiveSegmentResult::Received
+            );
+            // starts within the first segment but before the second
+            let _ = inbound.receive_segment(5, data);
+            assert_eq!(inbound.conflicts.len(), 2, "{policy:?}");
+            let mut read = [0; 20];
+            inbound.read_next(20).unwrap().copy_to_slice(&mut read);
+            assert_eq!(&read, expected, "{policy:?}");
+        }
+
+        // replaced data belongs to the segment which replaced it
+        let mut inbound = StreamInboundState::new(4096, true);
+        inbound.overlap_policy = OverlapPolicy::Bsd;
+        let _ = inbound.receive_segment(0, b"aaaaaaaaaa");
+        let _ = inbound.receive_segment(10, b"bbbbbbbbbb");
+        let _ = inbound.receive_segment(5, b"XXXXXXXXXX");
+        let _ = inbound.receive_segment(7, b"YYYYYY");
+        let mut read = [0; 20];
+        inbound.read_next(20).unwrap().copy_to_slice(&mut read);
+        assert_eq!(&read, b"aaaaaaaaaaXXXXXbbbbb");
+
+        inbound.advance_buffer(12);
+        assert_eq!(
+            inbound.segment_origins.first_key_value(),
+            Some((&12, &(15, 5..15)))
+        );
+    }
 }
@@ -1,6 +1,8 @@
 pub mod container;
+pub mod flow_control;
 pub mod inbound;
 pub mod outbound;
+pub mod pacing;
 
 #[cfg(test)]
 mod tests;
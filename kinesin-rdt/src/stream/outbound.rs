@@ -2,18 +2,50 @@
 
 use std::collections::BTreeSet;
 use std::ops::Range;
+use std::time::Instant;
 
 use tracing::trace;
 
 use crate::common::range_set::RangeSet;
 use crate::common::ring_buffer::{RingBuf, RingBufSlice};
+use crate::frame::StreamReset;
+use crate::stream::pacing::TokenBucket;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RetransmitStrategy {
     Reliable,
     Unreliable,
     Deadline { limit: u64 },
 }
 
+/// outbound stream metadata, excluding buffered data and pacing state
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StreamOutboundSnapshot {
+    /// stream offset at which buffer starts
+    pub buffer_offset: u64,
+    /// outbound buffer size limit
+    pub buffer_limit: usize,
+    /// segments queued for (re)transmission
+    pub queued: RangeSet,
+    /// segments successfully delivered
+    pub delivered: RangeSet,
+    /// offsets into the stream where messages begin, if applicable
+    pub message_offsets: BTreeSet<u64>,
+    /// if we're still in the initial state (window limit not received yet)
+    pub is_initial_window: bool,
+    /// peer inbound flow control receive limit
+    pub window_limit: u64,
+    /// retransmission strategy on packet loss
+    pub retransmit_strategy: RetransmitStrategy,
+    /// final length of stream (offset of final byte + 1)
+    pub final_offset: Option<u64>,
+    /// error code if stream was reset
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub reset_code: Option<u64>,
+}
+
 /// default outbound buffer size limit
 pub const OUTBOUND_BUFFER_DEFAULT_LIMIT: usize = 256 << 20; // 256 MB
 
@@ -43,6 +75,10 @@ pub struct StreamOutboundState {
     pub retransmit_strategy: RetransmitStrategy,
     /// final length of stream (offset of final byte + 1)
     pub final_offset: Option<u64>,
+    /// error code if stream was reset
+    pub reset_code: Option<u64>,
+    /// rate limiter for sent segments, if pacing is enabled
+    pub pacer: Option<TokenBucket>,
 }
 
 // Invariants:
@@ -64,11 +100,81 @@ impl StreamOutboundState {
             window_limit: initial_window_limit,
             retransmit_strategy,
             final_offset: None,
+            reset_code: None,
+            pacer: None,
         }
     }
 
+    /// capture stream metadata for checkpointing
+    pub fn snapshot(&self) -> StreamOutboundSnapshot {
+        StreamOutboundSnapshot {
+            buffer_offset: self.buffer_offset,
+            buffer_limit: self.buffer_limit,
+            queued: self.queued.clone(),
+            delivered: self.delivered.clone(),
+            message_offsets: self.message_offsets.clone(),
+            is_initial_window: self.is_initial_window,
+            window_limit: self.window_limit,
+            retransmit_strategy: self.retransmit_strategy,
+            final_offset: self.final_offset,
+            reset_code: self.reset_code,
+        }
+    }
+
+    /// restore stream from snapshot and buffer contents starting at
+    /// `buffer_offset`
+    ///
+    /// Queued segments past the end of `buffer` are discarded. Pacing must be
+    /// enabled again if it was in use.
+    pub fn restore(snapshot: StreamOutboundSnapshot, buffer: &[u8]) -> StreamOutboundState {
+        let mut state =
+            StreamOutboundState::new(snapshot.window_limit, snapshot.retransmit_strategy);
+        let buffer_end = snapshot.buffer_offset + buffer.len() as u64;
+        state.buffer.push_back_copy_from_slice(buffer);
+        state.buffer_offset = snapshot.buffer_offset;
+        state.buffer_limit = snapshot.buffer_limit;
+        state.queued = snapshot.queued;
+        state.queued.remove_range(buffer_end..);
+        state.delivered = snapshot.delivered;
+        state.message_offsets = snapshot.message_offsets;
+        state.is_initial_window = snapshot.is_initial_window;
+        state.final_offset = snapshot.final_offset;
+        state.reset_code = snapshot.reset_code;
+        state
+    }
+
+    /// enable pacing at `rate` bytes per second with bursts of up to `burst`
+    /// bytes, or update parameters if already enabled
+    pub fn set_pacing(&mut self, rate: u64, burst: u64) {
+        match &mut self.pacer {
+            Some(pacer) => pacer.set_rate(rate, burst),
+            None => self.pacer = Some(TokenBucket::new(rate, burst)),
+        }
+    }
+
+    /// update current time, call before next_segment when pacing is enabled
+    pub fn update_time(&mut self, now: Instant) {
+        if let Some(pacer) = &mut self.pacer {
+            pacer.refill(now);
+        }
+    }
+
+    /// earliest time at which the next queued segment (up to
+    /// `data_size_limit` bytes) may be sent
+    ///
+    /// Returns None if nothing is queued or pacing is disabled.
+    pub fn next_send_time(&self, data_size_limit: usize) -> Option<Instant> {
+        let pacer = self.pacer.as_ref()?;
+        let next_queued = self.queued.peek_first()?;
+        let len = u64::min(next_queued.end - next_queued.start, data_size_limit as u64);
+        pacer.next_send_time(len)
+    }
+
     /// gets how many bytes are currently writable to the stream
     pub fn writable(&self) -> u64 {
+        if self.reset_code.is_some() {
+            return 0;
+        }
         let rwnd_limit = self.window_limit.saturating_sub(self.buffer_offset);
         let real_limit = u64::min(rwnd_limit, self.buffer_limit as u64);
         real_limit.saturating_sub(self.buffer.len() as u64)
@@ -90,9 +196,14 @@ impl StreamOutboundState {
     /// retransmit mode, segments are considered delivered as soon as they are
     /// sent. In the deadline transmission mode, segments prior to the deadline
     /// are considered delivered even if they have not been acknowledged.
+    /// Reset streams and streams finished without data are always finished.
     pub fn finished(&self) -> bool {
-        if let Some(final_offset) = self.final_offset {
-            if let Some(delivered) = self.delivered.peek_first() {
+        if self.reset_code.is_some() {
+            true
+        } else if let Some(final_offset) = self.final_offset {
+            if final_offset == 0 {
+                true
+            } else if let Some(delivered) = self.delivered.peek_first() {
                 delivered.end >= final_offset
             } else {
                 false
@@ -130,7 +241,7 @@ impl StreamOutboundState {
     /// write segment to stream, respecting window and buffer limit
     pub fn write_limited(&mut self, buf: &[u8]) -> usize {
         let writable = self.writable();
-        if writable == 0 {
+        if writable == 0 || buf.is_empty() {
             0
         } else {
             let limit = usize::min(u64::min(usize::MAX as u64, writable) as usize, buf.len());
@@ -139,6 +250,20 @@ impl StreamOutboundState {
         }
     }
 
+    /// write whole message to stream, setting a message marker at its start
+    ///
+    /// The message is only written if it fits entirely within the window and
+    /// buffer limit. Returns false if it does not fit or is empty, as empty
+    /// messages cannot be delimited.
+    pub fn write_message(&mut self, buf: &[u8]) -> bool {
+        if buf.is_empty() || buf.len() as u64 > self.writable() {
+            return false;
+        }
+        let segment = self.write_direct(buf);
+        self.set_message_marker(segment.start);
+        true
+    }
+
     /// mark end of stream
     pub fn finish(&mut self) {
         assert!(self.final_offset.is_none(), "stream already finished");
@@ -146,6 +271,18 @@ impl StreamOutboundState {
         self.final_offset = Some(self.buffer_offset + self.buffer.len() as u64);
     }
 
+    /// apply reset frame, abandoning the stream
+    ///
+    /// Queued segments are dropped and no further data may be written. Only
+    /// the first error code is kept.
+    pub fn apply_reset(&mut self, frame: &StreamReset) {
+        if self.reset_code.is_none() {
+            trace!(error_code = frame.error_code, "stream reset");
+            self.reset_code = Some(frame.error_code);
+            self.queued.remove_range(..);
+        }
+    }
+
     /// set message marker at offset
     pub fn set_message_marker(&mut self, offset: u64) {
         if offset < self.buffer_offset {
@@ -214,6 +351,11 @@ impl StreamOutboundState {
     }
 
     /// get next queued segment
+    ///
+    /// If pacing is enabled, the segment is limited to the bytes the pacer
+    /// currently allows, and None is returned if it allows none. Segments
+    /// are split so that each contains at most one message marker, within
+    /// the range of a frame's message offset.
     pub fn next_segment(&mut self, data_size_limit: usize) -> Option<Range<u64>> {
         let mut next_queued = self.queued.peek_first()?;
         if let RetransmitStrategy::Deadline { limit } = self.retransmit_strategy {
@@ -224,7 +366,27 @@ impl StreamOutboundState {
             }
         }
         let start = next_queued.start;
-        let len = u64::min(next_queued.end, data_size_limit as u64);
+        let end = u64::min(next_queued.end, self.window_limit);
+        if end <= start {
+            trace!("next_segment: window limited");
+            return None;
+        }
+        let mut len = u64::min(end - start, data_size_limit as u64);
+        if let Some(pacer) = &self.pacer {
+            len = u64::min(len, pacer.available());
+            if len == 0 {
+                trace!("next_segment: paced");
+                return None;
+            }
+        }
+        let mut markers = self.message_offsets.range(start..start + len);
+        if let Some(&first) = markers.next() {
+            if first - start > u16::MAX as u64 {
+                len = first - start;
+            } else if let Some(&second) = markers.next() {
+                len = second - start;
+            }
+        }
         Some(start..start + len)
     }
 
@@ -245,7 +407,7 @@ impl StreamOutboundState {
         if buf_start > buf_end {
             return None;
         }
-        if buf_end >= self.buffer.len() {
+        if buf_end > self.buffer.len() {
             return None;
         }
         let first_marker = self.message_offsets.range(segment).next().copied();
@@ -254,6 +416,9 @@ impl StreamOutboundState {
 
     /// mark segment as sent
     pub fn segment_sent(&mut self, segment: Range<u64>) {
+        if let Some(pacer) = &mut self.pacer {
+            pacer.consume(segment.end - segment.start);
+        }
         self.queued.remove_range(segment.clone());
         if matches!(self.retransmit_strategy, RetransmitStrategy::Unreliable) {
             // no need to retransmit segments
@@ -263,6 +428,9 @@ impl StreamOutboundState {
 
     /// mark segment as lost
     pub fn segment_lost(&mut self, segment: Range<u64>) {
+        if self.reset_code.is_some() {
+            return;
+        }
         for to_queue in self.delivered.range_complement(segment) {
             self.queued.insert_range(to_queue);
         }
@@ -319,4 +487,72 @@ pub mod test {
         }
         assert!(outbound.finished());
     }
+
+    #[test]
+    fn window_limited_segment() {
+        let mut outbound = StreamOutboundState::new(0, RetransmitStrategy::Reliable);
+        outbound.write_direct(&[1u8; 40]);
+        outbound.segment_sent(0..40);
+        outbound.update_remote_limit(60);
+        outbound.write_direct(&[2u8; 40]);
+        assert_eq!(outbound.next_segment(64), Some(40..60));
+        assert_eq!(outbound.next_segment(8), Some(40..48));
+        outbound.segment_sent(40..60);
+        assert_eq!(outbound.next_segment(64), None);
+    }
+
+    #[test]
+    fn read_segment_at_buffer_end() {
+        let mut outbound = StreamOutboundState::new(4096, RetransmitStrategy::Reliable);
+        outbound.write_direct(&[1u8; 40]);
+        // segment ending exactly at the end of the buffer
+        let (slice, _) = outbound.read_segment(32..40).unwrap();
+        assert_eq!(slice.len(), 8);
+        assert!(outbound.read_segment(32..41).is_none());
+    }
+
+    #[test]
+    fn paced_segments() {
+        use std::time::{Duration, Instant};
+
+        let start = Instant::now();
+        let mut outbound = StreamOutboundState::new(4096, RetransmitStrategy::Reliable);
+        outbound.set_pacing(1000, 16);
+        outbound.update_time(start);
+        outbound.write_direct(&[1u8; 64]);
+
+        let segment = outbound.next_segment(32).unwrap();
+        assert_eq!(segment, 0..16);
+        outbound.segment_sent(segment);
+        assert_eq!(outbound.next_segment(32), None);
+        assert_eq!(
+            outbound.next_send_time(32),
+            Some(start + Duration::from_millis(16))
+        );
+
+        outbound.update_time(start + Duration::from_millis(8));
+        assert_eq!(outbound.next_segment(32).unwrap(), 16..24);
+    }
+
+    #[test]
+    fn reset() {
+        let mut outbound = StreamOutboundState::new(4096, RetransmitStrategy::Reliable);
+        outbound.write_direct(&[0u8; 64]);
+        let segment = outbound.next_segment(32).unwrap();
+        outbound.segment_sent(segment.clone());
+        outbound.apply_reset(&StreamReset {
+            stream_id: 0,
+            error_code: 5,
+        });
+        outbound.apply_reset(&StreamReset {
+            stream_id: 0,
+            error_code: 6,
+        });
+        assert_eq!(outbound.reset_code, Some(5));
+        assert!(!outbound.readable());
+        outbound.segment_lost(segment);
+        assert_eq!(outbound.next_segment(64), None);
+        assert_eq!(outbound.writable(), 0);
+        assert!(outbound.finished());
+    }
 }
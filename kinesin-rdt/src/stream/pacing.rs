@@ -0,0 +1,119 @@
+//! Token bucket pacing for outbound streams
+
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// token bucket rate limiter
+///
+/// Tokens are bytes. The bucket fills at `rate` bytes per second up to
+/// `burst` bytes. Fractional tokens are kept so slow rates are not rounded
+/// away between refills.
+pub struct TokenBucket {
+    /// fill rate in bytes per second
+    pub rate: u64,
+    /// max tokens held, in bytes
+    pub burst: u64,
+    /// available tokens, scaled by NANOS_PER_SEC
+    tokens_scaled: u128,
+    /// time of last refill
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// create bucket, initially full
+    pub fn new(rate: u64, burst: u64) -> TokenBucket {
+        assert!(rate > 0, "pacing rate must be nonzero");
+        TokenBucket {
+            rate,
+            burst,
+            tokens_scaled: burst as u128 * NANOS_PER_SEC,
+            last_refill: None,
+        }
+    }
+
+    /// change rate and burst, keeping current tokens (capped at new burst)
+    pub fn set_rate(&mut self, rate: u64, burst: u64) {
+        assert!(rate > 0, "pacing rate must be nonzero");
+        self.rate = rate;
+        self.burst = burst;
+        self.tokens_scaled = self.tokens_scaled.min(burst as u128 * NANOS_PER_SEC);
+    }
+
+    /// add tokens accumulated since last refill
+    pub fn refill(&mut self, now: Instant) {
+        if let Some(last) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last).as_nanos();
+            let max = self.burst as u128 * NANOS_PER_SEC;
+            self.tokens_scaled = max.min(self.tokens_scaled + elapsed * self.rate as u128);
+        }
+        if self.last_refill.is_none_or(|last| now > last) {
+            self.last_refill = Some(now);
+        }
+    }
+
+    /// bytes currently sendable
+    pub fn available(&self) -> u64 {
+        (self.tokens_scaled / NANOS_PER_SEC) as u64
+    }
+
+    /// take tokens for bytes sent
+    ///
+    /// Sending more than is available (e.g. retransmissions that must not be
+    /// delayed) empties the bucket.
+    pub fn consume(&mut self, bytes: u64) {
+        self.tokens_scaled = self
+            .tokens_scaled
+            .saturating_sub(bytes as u128 * NANOS_PER_SEC);
+    }
+
+    /// earliest time at which `bytes` (capped at burst) can be sent
+    ///
+    /// Returns the time of the last refill if enough tokens are already
+    /// available, or None if the bucket was never refilled.
+    pub fn next_send_time(&self, bytes: u64) -> Option<Instant> {
+        let last = self.last_refill?;
+        let needed = bytes.min(self.burst) as u128 * NANOS_PER_SEC;
+        if self.tokens_scaled >= needed {
+            return Some(last);
+        }
+        let missing = needed - self.tokens_scaled;
+        let wait_nanos = missing.div_ceil(self.rate as u128);
+        Some(last + Duration::from_nanos(wait_nanos.min(u64::MAX as u128) as u64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn refill_and_consume() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, 100);
+        bucket.refill(start);
+        assert_eq!(bucket.available(), 100);
+        bucket.consume(100);
+        assert_eq!(bucket.available(), 0);
+        assert_eq!(
+            bucket.next_send_time(10),
+            Some(start + Duration::from_millis(10))
+        );
+
+        // fractional tokens accumulate across refills
+        bucket.refill(start + Duration::from_micros(500));
+        assert_eq!(bucket.available(), 0);
+        bucket.refill(start + Duration::from_micros(1000));
+        assert_eq!(bucket.available(), 1);
+
+        // capped at burst
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.available(), 100);
+        assert_eq!(
+            bucket.next_send_time(1000),
+            Some(start + Duration::from_secs(10))
+        );
+    }
+}
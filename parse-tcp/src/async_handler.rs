@@ -0,0 +1,470 @@
+//! Asynchronous connection handlers
+//!
+//! Handlers doing network or database writes would block packet processing
+//! if run as a `ConnectionHandler`. An `AsyncConnectionHandler` instead
+//! receives owned events, with reassembled data copied out of the streams,
+//! and runs as a task on a tokio runtime. `AsyncBridge` is the
+//! `ConnectionHandler` forwarding events to the task of each connection
+//! through a bounded queue.
+//!
+//! Once the queue of a connection is full, the bridge blocks packet
+//! processing until its handler catches up, the same as writes to a full
+//! `WriterPool` queue. The bridge may be driven from outside the runtime or
+//! from a worker of a multi-threaded runtime, but not from a current-thread
+//! runtime. How often handlers fell behind is available from
+//! `AsyncBridgeShared::stalls`.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::Notify;
+use tracing::{debug, error, trace, warn};
+use uuid::Uuid;
+
+use crate::connection::{Connection, Direction};
+use crate::flow_table::Flow;
+use crate::serialized::{ConnInfo, PacketExtra};
+use crate::stream::{SegmentInfo, StreamGap};
+use crate::ConnectionHandler;
+
+/// default number of events queued per connection before the bridge blocks
+pub const ASYNC_QUEUE_DEPTH: usize = 64;
+
+/// event delivered to an async handler
+pub enum AsyncEvent {
+    /// handshake completed, or connection picked up mid-stream
+    HandshakeDone { flow: Flow },
+    /// client was misidentified, forward direction now refers to `flow`
+    DirectionChanged { flow: Flow },
+    /// reassembled data in one direction
+    Data {
+        direction: Direction,
+        /// stream offset of first byte
+        offset: u64,
+        /// data, with gaps zero-filled
+        data: Vec<u8>,
+        /// ranges within the data which were never captured
+        gaps: Vec<Range<u64>>,
+    },
+    /// data in one direction declared lost
+    Gap {
+        direction: Direction,
+        range: Range<u64>,
+    },
+    /// FIN received
+    Fin { direction: Direction },
+    /// RST received
+    Rst { direction: Direction },
+    /// connection removed from flow table, no further events follow
+    Close { info: Box<ConnInfo> },
+}
+
+/// handler of one connection, run as a task
+pub trait AsyncConnectionHandler: Send + 'static {
+    /// data shared by all handlers
+    type InitialData: Clone + Send + Sync + 'static;
+
+    /// create handler for new connection
+    fn new(init: Self::InitialData, id: Uuid, flow: &Flow) -> Self;
+
+    /// handle event, events of a connection are handled in order
+    fn handle(&mut self, event: AsyncEvent) -> impl Future<Output = ()> + Send;
+}
+
+struct AsyncBridgeInner<A: AsyncConnectionHandler> {
+    runtime: Handle,
+    init: A::InitialData,
+    /// capacity of the event queue of each connection
+    queue_depth: usize,
+    /// number of handler tasks still running
+    active: AtomicUsize,
+    /// notified when the last task exits
+    idle: Notify,
+    /// number of times an event was queued to a full queue
+    stalls: AtomicU64,
+}
+
+/// shared state of async handlers, initial data of `AsyncBridge`
+pub struct AsyncBridgeShared<A: AsyncConnectionHandler> {
+    inner: Arc<AsyncBridgeInner<A>>,
+}
+
+impl<A: AsyncConnectionHandler> Clone for AsyncBridgeShared<A> {
+    fn clone(&self) -> Self {
+        AsyncBridgeShared {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<A: AsyncConnectionHandler> AsyncBridgeShared<A> {
+    /// create new instance running handlers on `runtime`, queueing up to
+    /// `queue_depth` events per connection
+    pub fn new(runtime: Handle, init: A::InitialData, queue_depth: usize) -> Self {
+        AsyncBridgeShared {
+            inner: Arc::new(AsyncBridgeInner {
+                runtime,
+                init,
+                queue_depth: queue_depth.max(1),
+                active: AtomicUsize::new(0),
+                idle: Notify::new(),
+                stalls: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// number of times packet processing waited for a handler to catch up
+    pub fn stalls(&self) -> u64 {
+        self.inner.stalls.load(Ordering::Relaxed)
+    }
+
+    /// number of handler tasks still running
+    pub fn active_tasks(&self) -> usize {
+        self.inner.active.load(Ordering::Acquire)
+    }
+
+    /// block until all handler tasks exited, call after the flow table was
+    /// closed. Must not be called from within the runtime
+    pub fn wait(&self) {
+        let inner = &self.inner;
+        inner.runtime.block_on(async {
+            loop {
+                let idle = inner.idle.notified();
+                if inner.active.load(Ordering::Acquire) == 0 {
+                    break;
+                }
+                idle.await;
+            }
+        });
+    }
+}
+
+/// ConnectionHandler forwarding events to an async handler task
+pub struct AsyncBridge<A: AsyncConnectionHandler> {
+    pub shared: AsyncBridgeShared<A>,
+    /// queue of handler task, None once closed
+    sender: Option<Sender<AsyncEvent>>,
+    gaps: Vec<StreamGap>,
+    segments: Vec<SegmentInfo>,
+}
+
+impl<A: AsyncConnectionHandler> AsyncBridge<A> {
+    /// queue event, blocking while the queue is full
+    fn send(&mut self, event: AsyncEvent) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let result = match sender.try_send(event) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(event)) => {
+                self.shared.inner.stalls.fetch_add(1, Ordering::Relaxed);
+                trace!("async handler backlogged, waiting");
+                if Handle::try_current().is_ok() {
+                    tokio::task::block_in_place(|| sender.blocking_send(event))
+                } else {
+                    sender.blocking_send(event)
+                }
+                .map_err(|_| ())
+            }
+            Err(TrySendError::Closed(_)) => Err(()),
+        };
+        if result.is_err() {
+            warn!("async handler task exited, dropping further events");
+            self.sender = None;
+        }
+    }
+
+    /// copy `len` bytes out of stream and queue them as a data event
+    pub fn consume_stream(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        len: usize,
+    ) {
+        if len == 0 {
+            return;
+        }
+        self.gaps.clear();
+        self.segments.clear();
+        let stream = connection.get_stream(direction);
+        let offset = stream.buffer_start();
+        let Some(data) = stream.read_next(
+            offset + len as u64,
+            &mut self.segments,
+            &mut self.gaps,
+            |slice| {
+                let (a, b) = slice.as_slices();
+                let mut data = Vec::with_capacity(len);
+                data.extend_from_slice(a);
+                if let Some(b) = b {
+                    data.extend_from_slice(b);
+                }
+                data
+            },
+        ) else {
+            error!("async handler: {direction} stream cannot fulfill range, skipping");
+            return;
+        };
+        let gaps = self.gaps.iter().map(|gap| gap.range.clone()).collect();
+        self.send(AsyncEvent::Data {
+            direction,
+            offset,
+            data,
+            gaps,
+        });
+    }
+}
+
+impl<A: AsyncConnectionHandler> ConnectionHandler for AsyncBridge<A> {
+    type InitialData = AsyncBridgeShared<A>;
+    type ConstructError = Infallible;
+    fn new(
+        shared: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> Result<Self, Self::ConstructError> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        let inner = shared.inner.clone();
+        let mut handler = A::new(
+            inner.init.clone(),
+            connection.uuid,
+            &connection.forward_flow,
+        );
+        let (sender, mut receiver) = mpsc::channel(inner.queue_depth);
+        inner.active.fetch_add(1, Ordering::AcqRel);
+        shared.inner.runtime.spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                handler.handle(event).await;
+            }
+            drop(handler);
+            if inner.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+                inner.idle.notify_waiters();
+            }
+        });
+        Ok(AsyncBridge {
+            shared,
+            sender: Some(sender),
+            gaps: Vec::new(),
+            segments: Vec::new(),
+        })
+    }
+
+    fn handshake_done(&mut self, connection: &mut Connection<Self>) {
+        let flow = connection.forward_flow.clone();
+        self.send(AsyncEvent::HandshakeDone { flow });
+    }
+
+    fn direction_changed(&mut self, connection: &mut Connection<Self>) {
+        let flow = connection.forward_flow.clone();
+        self.send(AsyncEvent::DirectionChanged { flow });
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        // forward readable data immediately, only buffer out-of-order data
+        let config = connection.config.clone();
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        if readable_len > 0 {
+            self.consume_stream(connection, direction, readable_len);
+        } else if stream.total_buffered_length() > config.flush_total_threshold {
+            self.consume_stream(connection, direction, config.flush_total_advance);
+        }
+    }
+
+    fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let buffered = connection.get_stream(direction).total_buffered_length();
+        self.consume_stream(connection, direction, buffered);
+    }
+
+    fn gap_detected(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        direction: Direction,
+        range: Range<u64>,
+    ) {
+        self.send(AsyncEvent::Gap { direction, range });
+    }
+
+    fn fin_received(&mut self, _connection: &mut Connection<Self>, direction: Direction) {
+        self.send(AsyncEvent::Fin { direction });
+    }
+
+    fn rst_received(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        direction: Direction,
+        _extra: PacketExtra,
+    ) {
+        self.send(AsyncEvent::Rst { direction });
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        for direction in [Direction::Forward, Direction::Reverse] {
+            let remaining = connection.get_stream(direction).total_buffered_length();
+            self.consume_stream(connection, direction, remaining);
+        }
+        let info = Box::new(ConnInfo::from_connection(connection));
+        self.send(AsyncEvent::Close { info });
+        // task exits once the queue is drained
+        self.sender = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+    use uuid::Uuid;
+
+    use super::{AsyncBridge, AsyncBridgeShared, AsyncConnectionHandler, AsyncEvent};
+    use crate::flow_table::{Flow, FlowTable};
+    use crate::serialized::PacketExtra;
+    use crate::{TcpFlags, TcpMeta};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    struct LogHandler {
+        log: Log,
+    }
+
+    impl AsyncConnectionHandler for LogHandler {
+        type InitialData = Log;
+
+        fn new(log: Log, _id: Uuid, _flow: &Flow) -> Self {
+            LogHandler { log }
+        }
+
+        async fn handle(&mut self, event: AsyncEvent) {
+            // be slower than the packet loop so the queue fills up
+            std::thread::sleep(Duration::from_millis(5));
+            let line = match event {
+                AsyncEvent::HandshakeDone { flow } => format!("open {}", flow.dst_port),
+                AsyncEvent::Data {
+                    direction, data, ..
+                } => format!("{direction} {}", String::from_utf8(data).unwrap()),
+                AsyncEvent::Close { info } => format!("close {}", info.id),
+                _ => return,
+            };
+            self.log.lock().push(line);
+        }
+    }
+
+    #[test]
+    fn bridge() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        let log = Log::default();
+        let shared = AsyncBridgeShared::<LogHandler>::new(runtime.handle().clone(), log.clone(), 1);
+        let mut table: FlowTable<AsyncBridge<LogHandler>> = FlowTable::new(shared.clone());
+
+        let mut meta = TcpMeta {
+            src_addr: [10, 5, 0, 1].into(),
+            src_port: 40011,
+            dst_addr: [10, 5, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 5000,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        for word in ["a", "bb", "ccc", "dddd"] {
+            assert!(table
+                .handle_packet(&meta, word.as_bytes(), &PacketExtra::None)
+                .unwrap());
+            meta.seq_number += word.len() as u32;
+        }
+        let id = table.map.values().next().unwrap().uuid;
+        table.close();
+        shared.wait();
+        assert_eq!(shared.active_tasks(), 0);
+        // the handler is slower than the packet loop, so its queue filled
+        assert!(shared.stalls() > 0);
+        assert_eq!(
+            log.lock().as_slice(),
+            [
+                "open 80".to_string(),
+                "forward a".into(),
+                "forward bb".into(),
+                "forward ccc".into(),
+                "forward dddd".into(),
+                format!("close {id}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn bridge_within_runtime() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        let log = Log::default();
+        let shared = AsyncBridgeShared::<LogHandler>::new(runtime.handle().clone(), log.clone(), 1);
+        let mut table: FlowTable<AsyncBridge<LogHandler>> = FlowTable::new(shared.clone());
+
+        let mut meta = TcpMeta {
+            src_addr: [10, 5, 0, 3].into(),
+            src_port: 40012,
+            dst_addr: [10, 5, 0, 4].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 5000,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        // packets are handled on the only worker, which hands off handler
+        // tasks while waiting for queue space
+        let task = runtime.spawn(async move {
+            for word in ["a", "bb", "ccc"] {
+                assert!(table
+                    .handle_packet(&meta, word.as_bytes(), &PacketExtra::None)
+                    .unwrap());
+                meta.seq_number += word.len() as u32;
+            }
+            table.close();
+        });
+        runtime.block_on(task).unwrap();
+        shared.wait();
+        assert_eq!(log.lock().len(), 5);
+    }
+}
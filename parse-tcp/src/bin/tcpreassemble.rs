@@ -1,42 +1,653 @@
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{self, BufWriter, IsTerminal, Read};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use clap::Parser as ClapParser;
+use clap::{Parser as ClapParser, Subcommand, ValueEnum};
 use eyre::Context;
+use kinesin_rdt::common::buffer_pool::{BufferPool, BUFFER_POOL_DEFAULT_MAX_FREE};
+use kinesin_rdt::stream::inbound::OverlapPolicy;
+#[cfg(feature = "parquet")]
+use parse_tcp::columnar::{ParquetOutputHandler, ParquetOutputSharedInfo};
+use parse_tcp::config::ReassemblyConfig;
+use parse_tcp::dedup::DEFAULT_DEDUP_BLOCK_SIZE;
+use parse_tcp::direction::DIRECTION_INFERENCE_PACKETS;
+use parse_tcp::dispatch::{DispatchHandler, FlowDispatch, HandlerFactory};
+use parse_tcp::dns::{DnsExtractHandler, DnsExtractSharedInfo};
+use parse_tcp::duplicate::DuplicateFilter;
+use parse_tcp::events::{EventOutputHandler, EventSink};
+use parse_tcp::extract::{ExtractHandler, ExtractSharedInfo};
+use parse_tcp::filter::FilterExpr;
 use parse_tcp::flow_table::FlowTable;
-use parse_tcp::handler::{DirectoryOutputHandler, DirectoryOutputSharedInfo, DumpHandler};
-use parse_tcp::parser::{ParseLayer, TcpParser};
+use parse_tcp::follow::{FollowFormat, FollowHandler, FollowSharedInfo};
+use parse_tcp::handler::{
+    DataLayout, DirectoryOutputHandler, DirectoryOutputSharedInfo, DumpHandler, ErrorReceiver,
+    PcapSplitHandler, PcapSplitSharedInfo, SegmentFormat, BUFFER_READABLE_THRESHOLD,
+    BUFFER_SEGMENTS_THRESHOLD, BUFFER_TOTAL_THRESHOLD, BUFFER_TOTAL_THRESHOLD_ADVANCE,
+};
+use parse_tcp::http::{HttpExtractHandler, HttpExtractSharedInfo};
+use parse_tcp::http2::{Http2ExtractHandler, Http2ExtractSharedInfo};
+use parse_tcp::log_format::LogFormat;
+use parse_tcp::matching::{Pattern, PatternSet};
+use parse_tcp::memory::MemoryBudget;
+use parse_tcp::metrics::{serve_prometheus, Metrics};
+use parse_tcp::parser::{ChecksumPolicy, ParseLayer, ParsedPacket, TcpParser};
+use parse_tcp::pcap_recovery::{RecordCheck, RecordHeader, Resync};
+use parse_tcp::pcap_writer::{PcapWriter, RawFrame};
+use parse_tcp::progress::{ProgressCounter, ProgressMode, ProgressReporter, PROGRESS_LOG_INTERVAL};
+use parse_tcp::report::{DirectoryReport, REPORT_INTERVAL_US, REPORT_TOP_TALKERS};
+use parse_tcp::resync::RESYNC_PACKETS;
 use parse_tcp::serialized::PacketExtra;
-use parse_tcp::{initialize_logging, TcpMeta};
+#[cfg(feature = "sqlite")]
+use parse_tcp::sqlite::{SqliteOutputHandler, SqliteOutputSharedInfo};
+use parse_tcp::stats::StatsCollector;
+use parse_tcp::stream::{
+    GAP_WAIT_BYTES, GAP_WAIT_PACKETS, LATE_DATA_BYTES, LATE_DATA_PACKETS, MAX_ALLOWED_BUFFER_SIZE,
+    MAX_SEGMENTS_INFO_COUNT, PAWS_MAX_REGRESSION, SEQ_WINDOW_ADVANCE_BY,
+    SEQ_WINDOW_ADVANCE_THRESHOLD, SEQ_WINDOW_SIZE,
+};
+use parse_tcp::synth::{synthesize_dir, SYNTH_LINKTYPE};
+use parse_tcp::tls::TlsMetadataHandler;
+#[cfg(feature = "tls-decrypt")]
+use parse_tcp::tls_decrypt::{KeyLog, TlsDecryptHandler, TlsDecryptSharedInfo};
+use parse_tcp::ttl::TTL_ANOMALY_THRESHOLD;
+use parse_tcp::udp::{UdpDirectoryOutputHandler, UdpFlowTable};
+use parse_tcp::writer::{
+    Compression, DEFAULT_GZIP_LEVEL, DEFAULT_WRITER_QUEUE_DEPTH, DEFAULT_WRITER_THREADS,
+    DEFAULT_ZSTD_LEVEL,
+};
+use parse_tcp::{initialize_logging_with, ConnectionHandler, TcpMeta};
 use pcap_parser::traits::PcapReaderIterator;
-use pcap_parser::{LegacyPcapReader, Linktype, PcapBlockOwned, PcapError};
-use tracing::{debug, error, info, trace, warn};
+use pcap_parser::{
+    create_reader, Block, InterfaceDescriptionBlock, Linktype, OptionCode, PcapBlockOwned,
+    PcapError, PcapNGOption,
+};
+use tracing::{debug, debug_span, error, field, info, info_span, trace, warn};
 
 const PCAP_READER_BUFFER_SIZE: usize = 4 << 20; // 4 MB
 
 /// Reassemble TCP streams in a packet capture
 #[derive(ClapParser, Debug)]
-#[command(about, version)]
+#[command(
+    about,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// Input capture file, supports pcap only (not yet pcapng)
-    #[arg(index = 1)]
-    input: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Input capture files, in pcap or pcapng format. Multiple files (e.g. rotated captures) are processed in order as one capture.
+    /// Glob patterns are expanded in sorted order. Use `-` for stdin.
+    #[arg(index = 1, required = true)]
+    input: Vec<PathBuf>,
     /// Directory to write stream data. If not provided, will dump to stdout.
     #[arg(short = 'd', long)]
     output_dir: Option<PathBuf>,
+    /// Write original packets of each connection to separate pcap files in
+    /// the output directory instead of stream data
+    #[arg(short = 'p', long, requires = "output_dir")]
+    split_pcap: bool,
+    /// Extract HTTP/1.x transactions to the output directory instead of
+    /// stream data
+    #[arg(long, requires = "output_dir", conflicts_with = "split_pcap")]
+    http: bool,
+    /// Extract HTTP/1.x transactions only from connections matching this
+    /// filter expression (e.g. `port 80`), writing stream data for all others
+    #[arg(long, value_name = "FILTER", requires = "output_dir", conflicts_with_all = ["split_pcap", "http"])]
+    http_filter: Option<FilterExpr>,
+    /// Record TLS hello metadata of each connection to connections.json
+    /// instead of writing stream data
+    #[arg(long, requires = "output_dir", conflicts_with_all = ["split_pcap", "http", "http_filter"])]
+    tls: bool,
+    /// Decode cleartext HTTP/2 connections to the output directory, indexed in
+    /// http2.json, instead of writing stream data
+    #[arg(long, requires = "output_dir", conflicts_with_all = ["split_pcap", "http", "http_filter", "tls"])]
+    http2: bool,
+    /// Decrypt TLS connections with secrets from this NSS key log
+    /// (SSLKEYLOGFILE), then extract with `--http` or `--http2` or write
+    /// decrypted data as stream data
+    #[cfg(feature = "tls-decrypt")]
+    #[arg(long, value_name = "FILE", requires = "output_dir", conflicts_with_all = ["split_pcap", "http_filter", "tls", "dns", "extract", "follow"])]
+    tls_keylog: Option<PathBuf>,
+    /// Decode DNS messages of DNS over TCP connections to dns.jsonl in the
+    /// output directory instead of writing stream data
+    #[arg(long, requires = "output_dir", conflicts_with_all = ["split_pcap", "http", "http_filter", "tls", "http2"])]
+    dns: bool,
+    /// Extract files and mail from FTP, SMTP and POP3 sessions to the output
+    /// directory, listed in extracted.json, instead of writing stream data
+    #[arg(long, requires = "output_dir", conflicts_with_all = ["split_pcap", "http", "http_filter", "tls", "http2", "dns"])]
+    extract: bool,
+    /// Write each connection as an interleaved conversation to
+    /// `<uuid>.follow.txt`, like Wireshark's "Follow TCP Stream"
+    #[arg(long, value_enum, requires = "output_dir", conflicts_with_all = ["split_pcap", "http", "http_filter", "tls", "http2", "dns", "extract"])]
+    follow: Option<FollowArg>,
+    /// Format of stream data written to the output directory
+    #[arg(long, value_enum, default_value_t = OutputFormat::Files, requires = "output_dir")]
+    output_format: OutputFormat,
+    /// With `--output-format sqlite`, also store stream data in the database
+    #[cfg(feature = "sqlite")]
+    #[arg(long)]
+    sqlite_data: bool,
+    /// Emit NDJSON connection events instead of stream data. Target is `-` for
+    /// stdout, `unix:<path>` for a unix socket, or `<host>:<port>` for TCP.
+    #[arg(long, value_name = "TARGET", conflicts_with = "output_dir")]
+    events: Option<String>,
+    /// Report occurrences of a pattern in reassembled streams as `match`
+    /// events, numbered in order given. Patterns are `re:<regex>`,
+    /// `hex:<bytes>`, or literal text. May be repeated.
+    #[arg(long = "match", value_name = "PATTERN", requires = "events")]
+    matches: Vec<Pattern>,
+    /// Also write datagrams of UDP flows to the output directory
+    #[arg(long, requires = "output_dir", conflicts_with_all = ["split_pcap", "http", "http_filter", "tls", "http2", "dns", "extract"])]
+    udp: bool,
+    /// Compress stream data and segment files written to the output directory
+    #[arg(long, value_enum, default_value_t = CompressArg::None)]
+    compress: CompressArg,
+    /// Compression level (gzip 0-9, default 6; zstd 1-22, default 3)
+    #[arg(long, requires = "compress")]
+    compress_level: Option<i32>,
+    /// Store stream data as deduplicated blocks under `blocks/`, writing
+    /// per-stream manifests instead of data files
+    #[arg(long)]
+    dedup: bool,
+    /// Block size for deduplication, rounded to a power of two
+    #[arg(long, default_value_t = DEFAULT_DEDUP_BLOCK_SIZE, requires = "dedup")]
+    dedup_block_size: usize,
+    /// Write stream data of both directions to a single `<uuid>.i.data` file
+    /// per connection, as records in conversation order, instead of
+    /// `.f.data`/`.r.data` pairs
+    #[arg(long, requires = "output_dir", conflicts_with = "dedup")]
+    interleave: bool,
+    /// Write the state transitions of each connection to
+    /// `<uuid>.states.jsonl` in the output directory
+    #[arg(long, requires = "output_dir")]
+    record_states: bool,
+    /// Encoding of segment files written to the output directory
+    #[arg(long, value_enum, default_value_t = SegmentFormatArg::Jsonl)]
+    segment_format: SegmentFormatArg,
+    /// Number of threads writing stream data to the output directory
+    #[arg(short = 'j', long, default_value_t = DEFAULT_WRITER_THREADS)]
+    writer_threads: usize,
+    /// Write per-connection and summary statistics to file. Written as CSV if
+    /// the file name ends in `.csv`, otherwise as JSON.
+    #[arg(long)]
+    stats_out: Option<PathBuf>,
+    /// Max bytes buffered per stream direction
+    #[arg(long, default_value_t = MAX_ALLOWED_BUFFER_SIZE)]
+    max_buffer_size: u64,
+    /// Max number of packet metadata records buffered per stream direction
+    #[arg(long, default_value_t = MAX_SEGMENTS_INFO_COUNT)]
+    max_segments_info: usize,
+    /// Size of the accepted sequence number window
+    #[arg(long, default_value_t = SEQ_WINDOW_SIZE)]
+    seq_window_size: u32,
+    /// Distance from the start of the sequence number window at which the
+    /// window is advanced
+    #[arg(long, default_value_t = SEQ_WINDOW_ADVANCE_THRESHOLD)]
+    seq_window_advance_threshold: u32,
+    /// Distance behind the current sequence number to advance the window to
+    #[arg(long, default_value_t = SEQ_WINDOW_ADVANCE_BY)]
+    seq_window_advance_by: u32,
+    /// Readable bytes buffered per stream direction before output handlers
+    /// write them out
+    #[arg(long, default_value_t = BUFFER_READABLE_THRESHOLD)]
+    flush_readable_threshold: usize,
+    /// Packet metadata records buffered per stream direction before output
+    /// handlers write them out
+    #[arg(long, default_value_t = BUFFER_SEGMENTS_THRESHOLD)]
+    flush_segments_threshold: usize,
+    /// Total bytes buffered per stream direction, including missing data,
+    /// before output handlers write out part of the buffer. Must be less
+    /// than --max-buffer-size
+    #[arg(long, default_value_t = BUFFER_TOTAL_THRESHOLD)]
+    flush_total_threshold: usize,
+    /// Bytes written out when --flush-total-threshold is reached, at most
+    /// the threshold
+    #[arg(long, default_value_t = BUFFER_TOTAL_THRESHOLD_ADVANCE)]
+    flush_total_advance: usize,
+    /// Number of data packets received past missing data before it is
+    /// declared lost
+    #[arg(long, default_value_t = GAP_WAIT_PACKETS)]
+    gap_wait_packets: u32,
+    /// Number of bytes buffered past missing data before it is declared lost
+    #[arg(long, default_value_t = GAP_WAIT_BYTES)]
+    gap_wait_bytes: u64,
+    /// Number of packets after missing data was written out as a gap during
+    /// which data arriving late for it is still accepted, 0 to disable. Late
+    /// data is patched into data files where possible, and recorded in
+    /// segment files
+    #[arg(long, default_value_t = LATE_DATA_PACKETS)]
+    late_data_packets: u32,
+    /// Number of bytes written past a gap after which late data for it is no
+    /// longer accepted
+    #[arg(long, default_value_t = LATE_DATA_BYTES)]
+    late_data_bytes: u64,
+    /// Number of packets observed after an unexpected SYN on an established
+    /// connection before deciding whether to continue, resynchronize or
+    /// recreate the connection, 0 to always recreate
+    #[arg(long, default_value_t = RESYNC_PACKETS)]
+    resync_packets: u32,
+    /// Drop segments whose TCP timestamp falls behind the highest timestamp
+    /// seen in their direction by more than this, 0 to disable
+    #[arg(long, default_value_t = PAWS_MAX_REGRESSION)]
+    paws_max_regression: u32,
+    /// Number of packets used to infer which side is the client when the
+    /// handshake was not captured, 0 to assume the first sender is the client
+    #[arg(long, default_value_t = DIRECTION_INFERENCE_PACKETS)]
+    direction_inference_packets: u32,
+    /// Report packets whose TTL differs from the most common TTL of their
+    /// direction by more than this, as possibly injected by a middlebox
+    #[arg(long, default_value_t = TTL_ANOMALY_THRESHOLD)]
+    ttl_anomaly_threshold: u8,
+    /// Allocate stream buffers of this size from a shared pool, reducing
+    /// allocator churn with many concurrent connections
+    #[arg(long)]
+    buffer_pool_chunk_size: Option<usize>,
+    /// Only process connections matching a BPF-like filter expression, e.g.
+    /// `host 10.0.0.1 and (port 80 or port 443)`. Supports host, net, port
+    /// and portrange (optionally prefixed with src or dst), tcp, udp, ip,
+    /// ip6, combined with and, or, not and parentheses.
+    #[arg(long)]
+    filter: Option<FilterExpr>,
+    /// Verify IP and TCP/UDP checksums, dropping packets that fail or marking
+    /// them in segment metadata. Captures taken with checksum offloading
+    /// usually contain incorrect checksums for outgoing packets.
+    #[arg(long, value_enum, default_value_t = ChecksumArg::Ignore)]
+    checksum: ChecksumArg,
+    /// Drop TCP packets identical to one of this many preceding packets, as
+    /// found in captures from SPAN ports (e.g. 256), 0 to keep all packets
+    #[arg(long, default_value_t = 0)]
+    duplicate_window: usize,
+    /// Also drop duplicate IPv6 packets. IPv6 lacks an IP identification, so
+    /// retransmissions without TCP timestamps are dropped as well
+    #[arg(long)]
+    duplicate_ipv6: bool,
+    /// Skip damaged regions of pcap files, resuming at the next plausible
+    /// packet record, instead of stopping with an error
+    #[arg(long)]
+    lenient: bool,
+    /// Seconds between progress log messages, 0 to disable
+    #[arg(long, default_value_t = PROGRESS_LOG_INTERVAL.as_secs())]
+    progress_interval: u64,
+    /// Log progress instead of drawing a progress bar when stderr is a
+    /// terminal
+    #[arg(long)]
+    no_progress_bar: bool,
+    /// Write log messages to stderr as JSON lines, including the capture
+    /// file of each message. Packet index, flow and connection id are
+    /// included at debug level (RUST_LOG=parse_tcp=debug)
+    #[arg(long, global = true)]
+    log_json: bool,
+    /// Handling of retransmissions whose content differs from data received
+    /// earlier: keep the first copy, replace it with the last copy, keep
+    /// the first copy and report conflicts (both variants are included in
+    /// event output), or resolve the overlap as the given operating system
+    /// would
+    #[arg(long, value_enum, default_value_t = OverlapArg::First)]
+    overlap_policy: OverlapArg,
+    /// Use an overlap policy for connections matching a filter expression,
+    /// e.g. `windows:dst host 10.0.0.5`. The first matching rule applies.
+    /// May be repeated.
+    #[arg(long, value_name = "POLICY:FILTER")]
+    overlap_policy_rule: Vec<OverlapRule>,
+    /// Bytes buffered across all connections before the largest streams are
+    /// written out early, bounding memory use with many concurrent
+    /// connections
+    #[arg(long)]
+    memory_budget: Option<u64>,
+    /// Serve flow table metrics in Prometheus text format over HTTP on this
+    /// address, e.g. 127.0.0.1:9184
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+}
+
+/// format of stream output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// data and segment files per connection, plus connections.json
+    Files,
+    /// single SQLite database `connections.sqlite`
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    /// segment and connection metadata as Parquet files partitioned by
+    /// capture hour, without stream data
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+/// format of follow-stream output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum FollowArg {
+    Ascii,
+    Hex,
+}
+
+impl From<FollowArg> for FollowFormat {
+    fn from(arg: FollowArg) -> Self {
+        match arg {
+            FollowArg::Ascii => FollowFormat::Ascii,
+            FollowArg::Hex => FollowFormat::Hex,
+        }
+    }
+}
+
+/// handling of packets with incorrect checksums
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ChecksumArg {
+    Ignore,
+    Drop,
+    Annotate,
+}
+
+impl From<ChecksumArg> for ChecksumPolicy {
+    fn from(arg: ChecksumArg) -> Self {
+        match arg {
+            ChecksumArg::Ignore => ChecksumPolicy::Ignore,
+            ChecksumArg::Drop => ChecksumPolicy::Drop,
+            ChecksumArg::Annotate => ChecksumPolicy::Annotate,
+        }
+    }
+}
+
+/// modes other than reassembling captures
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write TCP connections from a directory written with `-d` to a pcap
+    /// file of clean, gapless packets, for tools which only accept pcap.
+    /// Retransmissions are left out and lost data is skipped.
+    Synth {
+        /// Directory written by a previous run with `-d`
+        input_dir: PathBuf,
+        /// Pcap file to write
+        output: PathBuf,
+    },
+    /// Print aggregate statistics of a directory written with `-d`: top
+    /// talkers, throughput, retransmit rates and connection durations
+    Stats {
+        /// Directory written by a previous run with `-d`
+        input_dir: PathBuf,
+        /// Number of top talkers to list
+        #[arg(long, default_value_t = REPORT_TOP_TALKERS)]
+        top: usize,
+        /// Length of throughput intervals, in milliseconds
+        #[arg(long, default_value_t = REPORT_INTERVAL_US / 1000,
+            value_parser = clap::value_parser!(u64).range(1..))]
+        interval_ms: u64,
+        /// Also write the report as JSON to this file
+        #[arg(long)]
+        json: Option<PathBuf>,
+    },
+}
+
+/// handling of conflicting retransmissions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OverlapArg {
+    First,
+    Last,
+    Record,
+    Bsd,
+    Linux,
+    Windows,
+}
+
+impl From<OverlapArg> for OverlapPolicy {
+    fn from(arg: OverlapArg) -> Self {
+        match arg {
+            OverlapArg::First => OverlapPolicy::FirstWins,
+            OverlapArg::Last => OverlapPolicy::LastWins,
+            OverlapArg::Record => OverlapPolicy::Record,
+            OverlapArg::Bsd => OverlapPolicy::Bsd,
+            OverlapArg::Linux => OverlapPolicy::Linux,
+            OverlapArg::Windows => OverlapPolicy::Windows,
+        }
+    }
+}
+
+/// overlap policy for connections matching a filter
+#[derive(Clone, Debug)]
+struct OverlapRule {
+    policy: OverlapArg,
+    filter: FilterExpr,
+}
+
+impl FromStr for OverlapRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (policy, filter) = s.split_once(':').ok_or("expected <policy>:<filter>")?;
+        Ok(OverlapRule {
+            policy: OverlapArg::from_str(policy.trim(), true)?,
+            filter: FilterExpr::parse(filter).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+/// encoding of segment files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SegmentFormatArg {
+    /// `<uuid>.f.jsonl`/`<uuid>.r.jsonl`, one JSON object per line
+    Jsonl,
+    /// `<uuid>.f.seg`/`<uuid>.r.seg`, compact binary records
+    Binary,
+}
+
+impl From<SegmentFormatArg> for SegmentFormat {
+    fn from(arg: SegmentFormatArg) -> Self {
+        match arg {
+            SegmentFormatArg::Jsonl => SegmentFormat::Jsonl,
+            SegmentFormatArg::Binary => SegmentFormat::Binary,
+        }
+    }
+}
+
+/// codec for compressing output files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum CompressArg {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Args {
+    /// compression settings from arguments
+    fn compression(&self) -> eyre::Result<Compression> {
+        Ok(match (self.compress, self.compress_level) {
+            (CompressArg::None, _) => Compression::None,
+            (CompressArg::Gzip, None) => Compression::Gzip {
+                level: DEFAULT_GZIP_LEVEL,
+            },
+            (CompressArg::Gzip, Some(level @ 0..=9)) => Compression::Gzip {
+                level: level as u32,
+            },
+            (CompressArg::Zstd, None) => Compression::Zstd {
+                level: DEFAULT_ZSTD_LEVEL,
+            },
+            (CompressArg::Zstd, Some(level @ 1..=22)) => Compression::Zstd { level },
+            (codec, Some(level)) => {
+                eyre::bail!("invalid compression level {level} for {codec:?}")
+            }
+        })
+    }
+}
+
+/// options for stream data files written to the output directory
+struct FileOptions {
+    writer_threads: usize,
+    compression: Compression,
+    dedup_block_size: Option<usize>,
+    layout: DataLayout,
+    segment_format: SegmentFormat,
+}
+
+impl FileOptions {
+    /// create shared state for DirectoryOutputHandler
+    fn shared_info(
+        &self,
+        out_dir: PathBuf,
+    ) -> eyre::Result<(DirectoryOutputSharedInfo, ErrorReceiver)> {
+        DirectoryOutputSharedInfo::with_writer_threads(
+            out_dir,
+            self.writer_threads.max(1),
+            DEFAULT_WRITER_QUEUE_DEPTH,
+            self.compression,
+            self.dedup_block_size,
+            self.layout,
+            self.segment_format,
+        )
+        .wrap_err("writing connections information file")
+    }
+}
+
+/// options shared by all output modes
+struct RunOptions<'a> {
+    /// reassembly limits
+    config: ReassemblyConfig,
+    /// statistics output file
+    stats_out: Option<&'a Path>,
+    /// filter applied before creating flows
+    filter: Option<FilterExpr>,
+    /// handling of packets with incorrect checksums
+    checksum: ChecksumPolicy,
+    /// number of preceding packets checked for duplicates
+    duplicate_window: usize,
+    /// whether IPv6 packets are checked for duplicates
+    duplicate_ipv6: bool,
+    /// skip damaged regions of pcap files
+    lenient: bool,
+    /// keep captured frames of packets in `PacketExtra`
+    raw_frames: bool,
+    /// how progress of reading inputs is reported, if at all
+    progress: Option<ProgressMode>,
+    /// secrets for decrypting TLS
+    #[cfg(feature = "tls-decrypt")]
+    tls_keylog: Option<Arc<KeyLog>>,
 }
 
 fn main() -> eyre::Result<()> {
-    initialize_logging();
-    info!("Hello, world!");
     let args = Args::parse();
-    let input = if args.input == PathBuf::from("-") {
-        FileOrStdinReader::Stdin
+    initialize_logging_with(if args.log_json {
+        LogFormat::Json
     } else {
-        FileOrStdinReader::File(File::open(args.input).wrap_err("cannot open file")?)
+        LogFormat::Text
+    });
+    info!("Hello, world!");
+    match &args.command {
+        Some(Command::Synth { input_dir, output }) => return synth_pcap(input_dir, output),
+        Some(Command::Stats {
+            input_dir,
+            top,
+            interval_ms,
+            json,
+        }) => return print_report(input_dir, *top, *interval_ms, json.as_deref()),
+        None => {}
+    }
+    let inputs = expand_inputs(&args.input)?;
+    let config = ReassemblyConfig {
+        max_buffer_size: args.max_buffer_size,
+        max_segments_info: args.max_segments_info,
+        seq_window_size: args.seq_window_size,
+        seq_window_advance_threshold: args.seq_window_advance_threshold,
+        seq_window_advance_by: args.seq_window_advance_by,
+        flush_readable_threshold: args.flush_readable_threshold,
+        flush_segments_threshold: args.flush_segments_threshold,
+        flush_total_threshold: args.flush_total_threshold,
+        flush_total_advance: args.flush_total_advance,
+        gap_wait_packets: args.gap_wait_packets,
+        gap_wait_bytes: args.gap_wait_bytes,
+        late_data_packets: args.late_data_packets,
+        late_data_bytes: args.late_data_bytes,
+        resync_packets: args.resync_packets,
+        paws_max_regression: args.paws_max_regression,
+        direction_inference_packets: args.direction_inference_packets,
+        ttl_anomaly_threshold: args.ttl_anomaly_threshold,
+        overlap_policy: args.overlap_policy.into(),
+        overlap_policy_rules: args
+            .overlap_policy_rule
+            .iter()
+            .map(|rule| (rule.filter.clone(), rule.policy.into()))
+            .collect(),
+        buffer_pool: args
+            .buffer_pool_chunk_size
+            .map(|chunk_size| Arc::new(BufferPool::new(chunk_size, BUFFER_POOL_DEFAULT_MAX_FREE))),
+        memory_budget: args
+            .memory_budget
+            .map(|limit| Arc::new(MemoryBudget::new(limit))),
+        metrics: args.metrics_listen.map(|_| Arc::new(Metrics::new())),
+        patterns: (!args.matches.is_empty())
+            .then(|| Arc::new(PatternSet::new(args.matches.clone()))),
+        record_transitions: args.record_states,
+        ..Default::default()
+    };
+    if let Err(e) = config.validate() {
+        eyre::bail!("invalid reassembly limits: {e}");
+    }
+    if let (Some(addr), Some(metrics)) = (args.metrics_listen, &config.metrics) {
+        let listener = TcpListener::bind(addr)
+            .wrap_err_with(|| format!("failed to listen for metrics on {addr}"))?;
+        info!(
+            "serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+        let metrics = metrics.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve_prometheus(metrics, listener) {
+                error!("metrics server failed: {e}");
+            }
+        });
+    }
+    let file_opts = FileOptions {
+        writer_threads: args.writer_threads,
+        compression: args.compression()?,
+        dedup_block_size: args.dedup.then_some(args.dedup_block_size),
+        layout: if args.interleave {
+            DataLayout::Interleaved
+        } else {
+            DataLayout::Split
+        },
+        segment_format: args.segment_format.into(),
     };
-    if let Some(out_dir) = args.output_dir {
+    #[cfg(feature = "tls-decrypt")]
+    let tls_keylog = match &args.tls_keylog {
+        Some(path) => {
+            let keylog = KeyLog::load(path).wrap_err("failed to read tls key log")?;
+            info!("loaded {} secrets from tls key log", keylog.len());
+            Some(Arc::new(keylog))
+        }
+        None => None,
+    };
+    let opts = RunOptions {
+        config,
+        stats_out: args.stats_out.as_deref(),
+        filter: args.filter,
+        checksum: args.checksum.into(),
+        duplicate_window: args.duplicate_window,
+        duplicate_ipv6: args.duplicate_ipv6,
+        lenient: args.lenient,
+        raw_frames: args.split_pcap,
+        progress: if !args.no_progress_bar && io::stderr().is_terminal() {
+            Some(ProgressMode::Bar)
+        } else if args.progress_interval > 0 {
+            Some(ProgressMode::Log(Duration::from_secs(
+                args.progress_interval,
+            )))
+        } else {
+            None
+        },
+        #[cfg(feature = "tls-decrypt")]
+        tls_keylog,
+    };
+    #[cfg(feature = "tls-decrypt")]
+    let decrypt_tls = opts.tls_keylog.is_some();
+    #[cfg(not(feature = "tls-decrypt"))]
+    let decrypt_tls = false;
+    if let Some(target) = args.events {
+        write_events(&inputs, &target, &opts)?;
+    } else if let Some(out_dir) = args.output_dir {
         #[cfg(any(target_os = "linux", target_os = "macos"))]
         unsafe {
             info!("attempting to raise file limit");
@@ -45,13 +656,110 @@ fn main() -> eyre::Result<()> {
                 Err(e) => warn!("failed to raise file limit: {e:?}"),
             }
         }
-        write_to_dir(input, out_dir)?;
+        if args.split_pcap {
+            write_pcaps_to_dir(&inputs, out_dir, &opts)?;
+        } else if args.http {
+            write_http_to_dir(&inputs, out_dir, &opts)?;
+        } else if let Some(http_filter) = args.http_filter {
+            write_http_filtered_to_dir(&inputs, out_dir, http_filter, &file_opts, &opts)?;
+        } else if args.tls {
+            write_tls_to_dir(&inputs, out_dir, &opts)?;
+        } else if args.http2 {
+            write_http2_to_dir(&inputs, out_dir, &opts)?;
+        } else if decrypt_tls {
+            #[cfg(feature = "tls-decrypt")]
+            if let Some(keylog) = opts.tls_keylog.clone() {
+                write_decrypted_streams_to_dir(&inputs, out_dir, keylog, &file_opts, &opts)?;
+            }
+        } else if args.dns {
+            write_dns_to_dir(&inputs, out_dir, &opts)?;
+        } else if args.extract {
+            write_extracted_to_dir(&inputs, out_dir, &opts)?;
+        } else if let Some(format) = args.follow {
+            write_follow_to_dir(&inputs, out_dir, format.into(), &opts)?;
+        } else {
+            match args.output_format {
+                OutputFormat::Files => write_to_dir(&inputs, out_dir, &file_opts, args.udp, &opts)?,
+                #[cfg(feature = "sqlite")]
+                OutputFormat::Sqlite => {
+                    if args.udp {
+                        eyre::bail!("UDP flows are not supported with SQLite output");
+                    }
+                    write_sqlite_to_dir(&inputs, out_dir, args.sqlite_data, &opts)?;
+                }
+                #[cfg(feature = "parquet")]
+                OutputFormat::Parquet => {
+                    if args.udp {
+                        eyre::bail!("UDP flows are not supported with Parquet output");
+                    }
+                    write_parquet_to_dir(&inputs, out_dir, &opts)?;
+                }
+            }
+        }
     } else {
-        dump_to_stdout(input)?;
+        dump_to_stdout(&inputs, &opts)?;
+    }
+    if let Some(budget) = &opts.config.memory_budget {
+        info!(
+            "memory budget: peak {} of {} bytes buffered, {} streams written out early",
+            budget.peak(),
+            budget.limit(),
+            budget.pressure_calls()
+        );
     }
     Ok(())
 }
 
+/// expand glob patterns in input paths
+fn expand_inputs(patterns: &[PathBuf]) -> eyre::Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    for pattern in patterns {
+        let pattern_str = pattern.to_string_lossy();
+        if !pattern_str.contains(['*', '?', '[']) {
+            inputs.push(pattern.clone());
+            continue;
+        }
+        let mut matched: Vec<PathBuf> = glob::glob(&pattern_str)
+            .wrap_err_with(|| format!("invalid glob pattern {pattern_str}"))?
+            .collect::<Result<_, _>>()
+            .wrap_err("reading glob matches")?;
+        if matched.is_empty() {
+            eyre::bail!("no files match {pattern_str}");
+        }
+        matched.sort();
+        inputs.append(&mut matched);
+    }
+    if inputs.len() > 1 && inputs.iter().any(|p| p.as_os_str() == "-") {
+        eyre::bail!("stdin cannot be combined with other inputs");
+    }
+    Ok(inputs)
+}
+
+/// open input file, or stdin if the path is `-`
+fn open_input(path: &Path) -> eyre::Result<FileOrStdinReader> {
+    if path.as_os_str() == "-" {
+        Ok(FileOrStdinReader::Stdin)
+    } else {
+        let file =
+            File::open(path).wrap_err_with(|| format!("cannot open file {}", path.display()))?;
+        Ok(FileOrStdinReader::File(file))
+    }
+}
+
+/// total size of input files, if all are files
+fn inputs_size(inputs: &[PathBuf]) -> Option<u64> {
+    inputs
+        .iter()
+        .map(|path| {
+            if path.as_os_str() == "-" {
+                None
+            } else {
+                std::fs::metadata(path).ok().map(|meta| meta.len())
+            }
+        })
+        .sum()
+}
+
 enum FileOrStdinReader {
     File(File),
     Stdin,
@@ -75,24 +783,229 @@ impl Read for FileOrStdinReader {
     impl_read_method!(fn read_to_string(&mut self, buf: &mut String) -> std::io::Result<usize>);
 }
 
-fn dump_to_stdout(input: FileOrStdinReader) -> eyre::Result<()> {
-    let mut flowtable: FlowTable<DumpHandler> = FlowTable::new(());
+fn dump_to_stdout(inputs: &[PathBuf], opts: &RunOptions) -> eyre::Result<()> {
+    let mut flowtable: FlowTable<DumpHandler> = new_flowtable((), opts);
 
-    parse_packets(input, |meta, data, extra| {
+    parse_packets(inputs, opts, |meta, data, extra| {
         let _ = flowtable.handle_packet(&meta, data, &extra);
         Ok(())
     })?;
 
     flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
     Ok(())
 }
 
-fn write_to_dir(input: FileOrStdinReader, out_dir: PathBuf) -> eyre::Result<()> {
-    let (shared_info, errors_rx) =
-        DirectoryOutputSharedInfo::new(out_dir).wrap_err("writing connections information file")?;
-    let mut flowtable: FlowTable<DirectoryOutputHandler> = FlowTable::new(shared_info.clone());
+fn write_events(inputs: &[PathBuf], target: &str, opts: &RunOptions) -> eyre::Result<()> {
+    let sink =
+        EventSink::open(target).wrap_err_with(|| format!("opening event target {target}"))?;
+    let mut flowtable: FlowTable<EventOutputHandler> = new_flowtable(sink.clone(), opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    sink.flush().wrap_err("flushing events")?;
+    Ok(())
+}
+
+fn write_to_dir(
+    inputs: &[PathBuf],
+    out_dir: PathBuf,
+    file_opts: &FileOptions,
+    udp: bool,
+    opts: &RunOptions,
+) -> eyre::Result<()> {
+    let (shared_info, errors_rx) = file_opts.shared_info(out_dir)?;
+    let mut flowtable: FlowTable<DirectoryOutputHandler> = new_flowtable(shared_info.clone(), opts);
+    let mut udp_flowtable: UdpFlowTable<UdpDirectoryOutputHandler> =
+        UdpFlowTable::new(shared_info.clone());
+    udp_flowtable.filter = opts.filter.clone();
+
+    parse_all_packets(inputs, udp, opts, |packet, extra| {
+        match packet {
+            ParsedPacket::Tcp(meta, data) => {
+                flowtable.handle_packet(&meta, data, &extra)?;
+            }
+            ParsedPacket::Udp(meta, data) => udp_flowtable.handle_packet(&meta, data, &extra)?,
+        }
+        if let Ok(e) = errors_rx.try_recv() {
+            return Err(e);
+        }
+        Ok(())
+    })?;
+
+    flowtable.close();
+    udp_flowtable.close();
+    drop(udp_flowtable);
+    write_stats(&mut flowtable, opts.stats_out)?;
+    drop(flowtable);
+    shared_info.close()?;
+    // report errors from writes completed during close
+    if let Ok(e) = errors_rx.try_recv() {
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn write_pcaps_to_dir(inputs: &[PathBuf], out_dir: PathBuf, opts: &RunOptions) -> eyre::Result<()> {
+    let shared_info = PcapSplitSharedInfo::new(out_dir);
+    let mut flowtable: FlowTable<PcapSplitHandler> = new_flowtable(shared_info.clone(), opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        let _ = flowtable.handle_packet(&meta, data, &extra);
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    Ok(())
+}
+
+fn write_http_to_dir(inputs: &[PathBuf], out_dir: PathBuf, opts: &RunOptions) -> eyre::Result<()> {
+    let shared_info = HttpExtractSharedInfo::new(out_dir).wrap_err("creating http index file")?;
+    #[cfg(feature = "tls-decrypt")]
+    if let Some(keylog) = &opts.tls_keylog {
+        let inner = shared_info.clone();
+        write_decrypted_to_dir::<HttpExtractHandler>(inputs, keylog.clone(), inner, opts)?;
+        shared_info.close()?;
+        return Ok(());
+    }
+    let mut flowtable: FlowTable<HttpExtractHandler> = new_flowtable(shared_info.clone(), opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    drop(flowtable);
+    shared_info.close()?;
+    Ok(())
+}
+
+fn write_http2_to_dir(inputs: &[PathBuf], out_dir: PathBuf, opts: &RunOptions) -> eyre::Result<()> {
+    let shared_info = Http2ExtractSharedInfo::new(out_dir).wrap_err("creating http2 index file")?;
+    #[cfg(feature = "tls-decrypt")]
+    if let Some(keylog) = &opts.tls_keylog {
+        let inner = shared_info.clone();
+        write_decrypted_to_dir::<Http2ExtractHandler>(inputs, keylog.clone(), inner, opts)?;
+        shared_info.close()?;
+        return Ok(());
+    }
+    let mut flowtable: FlowTable<Http2ExtractHandler> = new_flowtable(shared_info.clone(), opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    drop(flowtable);
+    shared_info.close()?;
+    Ok(())
+}
+
+/// decrypt TLS connections and handle decrypted data with `H`
+#[cfg(feature = "tls-decrypt")]
+fn write_decrypted_to_dir<H: ConnectionHandler<ConstructError = eyre::Report>>(
+    inputs: &[PathBuf],
+    keylog: Arc<KeyLog>,
+    inner: H::InitialData,
+    opts: &RunOptions,
+) -> eyre::Result<()>
+where
+    H::InitialData: Clone,
+{
+    let shared_info = TlsDecryptSharedInfo { keylog, inner };
+    let mut flowtable: FlowTable<TlsDecryptHandler<H>> = new_flowtable(shared_info, opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    Ok(())
+}
+
+/// decrypt TLS connections and write decrypted data as stream data
+#[cfg(feature = "tls-decrypt")]
+fn write_decrypted_streams_to_dir(
+    inputs: &[PathBuf],
+    out_dir: PathBuf,
+    keylog: Arc<KeyLog>,
+    file_opts: &FileOptions,
+    opts: &RunOptions,
+) -> eyre::Result<()> {
+    let (shared_info, errors_rx) = file_opts.shared_info(out_dir)?;
+    write_decrypted_to_dir::<DirectoryOutputHandler>(inputs, keylog, shared_info.clone(), opts)?;
+    shared_info.close()?;
+    if let Ok(e) = errors_rx.try_recv() {
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn write_dns_to_dir(inputs: &[PathBuf], out_dir: PathBuf, opts: &RunOptions) -> eyre::Result<()> {
+    let shared_info = DnsExtractSharedInfo::new(&out_dir).wrap_err("creating dns output file")?;
+    let mut flowtable: FlowTable<DnsExtractHandler> = new_flowtable(shared_info.clone(), opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    drop(flowtable);
+    shared_info.close()?;
+    Ok(())
+}
+
+fn write_extracted_to_dir(
+    inputs: &[PathBuf],
+    out_dir: PathBuf,
+    opts: &RunOptions,
+) -> eyre::Result<()> {
+    let shared_info = ExtractSharedInfo::new(out_dir).wrap_err("creating extraction index file")?;
+    let mut flowtable: FlowTable<ExtractHandler> = new_flowtable(shared_info.clone(), opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    drop(flowtable);
+    shared_info.close()?;
+    Ok(())
+}
 
-    parse_packets(input, |meta, data: &[u8], extra| {
+/// extract HTTP from connections matching filter, write stream data for others
+fn write_http_filtered_to_dir(
+    inputs: &[PathBuf],
+    out_dir: PathBuf,
+    http_filter: FilterExpr,
+    file_opts: &FileOptions,
+    opts: &RunOptions,
+) -> eyre::Result<()> {
+    let http_info =
+        HttpExtractSharedInfo::new(out_dir.clone()).wrap_err("creating http index file")?;
+    let (dir_info, errors_rx) = file_opts.shared_info(out_dir)?;
+    let dispatch = FlowDispatch::new::<DirectoryOutputHandler>(dir_info.clone())
+        .route::<HttpExtractHandler>(move |flow| http_filter.matches(flow), http_info.clone());
+    let factory: Arc<dyn HandlerFactory> = Arc::new(dispatch);
+    let mut flowtable: FlowTable<DispatchHandler> = new_flowtable(factory, opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
         flowtable.handle_packet(&meta, data, &extra)?;
         if let Ok(e) = errors_rx.try_recv() {
             return Err(e);
@@ -101,69 +1014,506 @@ fn write_to_dir(input: FileOrStdinReader, out_dir: PathBuf) -> eyre::Result<()>
     })?;
 
     flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    drop(flowtable);
+    http_info.close()?;
+    dir_info.close()?;
+    if let Ok(e) = errors_rx.try_recv() {
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn write_follow_to_dir(
+    inputs: &[PathBuf],
+    out_dir: PathBuf,
+    format: FollowFormat,
+    opts: &RunOptions,
+) -> eyre::Result<()> {
+    let shared_info = FollowSharedInfo::new(out_dir, format);
+    let mut flowtable: FlowTable<FollowHandler> = new_flowtable(shared_info, opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    Ok(())
+}
+
+fn write_tls_to_dir(inputs: &[PathBuf], out_dir: PathBuf, opts: &RunOptions) -> eyre::Result<()> {
+    let (shared_info, _errors_rx) =
+        DirectoryOutputSharedInfo::new(out_dir).wrap_err("writing connections information file")?;
+    let mut flowtable: FlowTable<TlsMetadataHandler> = new_flowtable(shared_info.clone(), opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
     drop(flowtable);
     shared_info.close()?;
     Ok(())
 }
 
+#[cfg(feature = "sqlite")]
+fn write_sqlite_to_dir(
+    inputs: &[PathBuf],
+    out_dir: PathBuf,
+    store_data: bool,
+    opts: &RunOptions,
+) -> eyre::Result<()> {
+    let shared_info = SqliteOutputSharedInfo::new(&out_dir.join("connections.sqlite"), store_data)
+        .wrap_err("opening database")?;
+    let mut flowtable: FlowTable<SqliteOutputHandler> = new_flowtable(shared_info.clone(), opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    drop(flowtable);
+    shared_info.close().wrap_err("closing database")?;
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+fn write_parquet_to_dir(
+    inputs: &[PathBuf],
+    out_dir: PathBuf,
+    opts: &RunOptions,
+) -> eyre::Result<()> {
+    let shared_info = ParquetOutputSharedInfo::new(out_dir);
+    let mut flowtable: FlowTable<ParquetOutputHandler> = new_flowtable(shared_info.clone(), opts);
+
+    parse_packets(inputs, opts, |meta, data, extra| {
+        flowtable.handle_packet(&meta, data, &extra)?;
+        Ok(())
+    })?;
+
+    flowtable.close();
+    write_stats(&mut flowtable, opts.stats_out)?;
+    drop(flowtable);
+    shared_info.close().wrap_err("writing parquet files")?;
+    Ok(())
+}
+
+/// create flowtable, collecting connection statistics if an output file was
+/// requested
+fn new_flowtable<H: ConnectionHandler>(init_data: H::InitialData, opts: &RunOptions) -> FlowTable<H>
+where
+    H::InitialData: Clone,
+{
+    let mut flowtable = FlowTable::with_config(init_data, opts.config.clone());
+    flowtable.filter = opts.filter.clone();
+    if opts.stats_out.is_some() {
+        flowtable.stats = Some(StatsCollector::new());
+    }
+    flowtable
+}
+
+/// write collected statistics, call after the flowtable is closed
+fn write_stats<H: ConnectionHandler>(
+    flowtable: &mut FlowTable<H>,
+    stats_out: Option<&Path>,
+) -> eyre::Result<()>
+where
+    H::InitialData: Clone,
+{
+    if flowtable.filter.is_some() {
+        info!(
+            "{} packets did not match filter",
+            flowtable.filtered_packets
+        );
+    }
+    if flowtable.reused_flows > 0 {
+        info!(
+            "{} connections replaced by a new connection on the same port pair",
+            flowtable.reused_flows
+        );
+    }
+    let (Some(path), Some(stats)) = (stats_out, flowtable.stats.take()) else {
+        return Ok(());
+    };
+    let summary = stats.summary();
+    info!(
+        "{} connections, {} bytes ({} retransmitted, {} lost to gaps)",
+        summary.connections,
+        summary.total.bytes,
+        summary.total.retransmit_bytes,
+        summary.total.gap_bytes
+    );
+    let file = File::create(path).wrap_err("creating statistics file")?;
+    let writer = BufWriter::new(file);
+    if path.extension().is_some_and(|ext| ext == "csv") {
+        stats.write_csv(writer)
+    } else {
+        stats.write_json(writer).map_err(Into::into)
+    }
+    .wrap_err("writing statistics file")
+}
+
+/// write connections of directory output as a synthesized pcap
+fn synth_pcap(input_dir: &Path, output: &Path) -> eyre::Result<()> {
+    let file = File::create(output).wrap_err("creating output pcap")?;
+    let mut writer = PcapWriter::new(BufWriter::new(file), SYNTH_LINKTYPE)?;
+    let summary = synthesize_dir(input_dir, &mut writer)
+        .wrap_err_with(|| format!("failed to read output in {}", input_dir.display()))?;
+    writer.flush().wrap_err("writing output pcap")?;
+    info!(
+        "wrote {} packets of {} connections ({} skipped)",
+        summary.packets, summary.connections, summary.skipped
+    );
+    Ok(())
+}
+
+/// print report over directory output, optionally writing it as JSON
+fn print_report(
+    input_dir: &Path,
+    top: usize,
+    interval_ms: u64,
+    json: Option<&Path>,
+) -> eyre::Result<()> {
+    let report = DirectoryReport::from_dir(input_dir, top, interval_ms * 1000)
+        .wrap_err_with(|| format!("failed to read output in {}", input_dir.display()))?;
+    report
+        .write_text(io::stdout().lock())
+        .wrap_err("writing report")?;
+    if let Some(path) = json {
+        let file = File::create(path).wrap_err("creating report file")?;
+        report
+            .write_json(BufWriter::new(file))
+            .wrap_err("writing report file")?;
+    }
+    Ok(())
+}
+
+/// pcapng capture interface, from an interface description block
+struct PcapngInterface {
+    linktype: Linktype,
+    layer: ParseLayer,
+    name: Option<Arc<str>>,
+    /// timestamp units per second
+    ts_resolution: u64,
+    /// seconds added to timestamps
+    ts_offset: i64,
+    /// drop counter from the latest interface statistics block
+    dropped: Option<u64>,
+}
+
+impl PcapngInterface {
+    fn new(idb: &InterfaceDescriptionBlock<'_>) -> eyre::Result<Self> {
+        let name = find_option(&idb.options, OPTION_IF_NAME)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .map(|name| name.trim_end_matches('\0').into());
+        let Some(ts_resolution) = idb.ts_resolution() else {
+            eyre::bail!("pcapng interface: invalid timestamp resolution");
+        };
+        Ok(PcapngInterface {
+            linktype: idb.linktype,
+            layer: parse_layer(idb.linktype)?,
+            name,
+            ts_resolution,
+            ts_offset: idb.ts_offset(),
+            dropped: None,
+        })
+    }
+
+    /// convert raw timestamp to nanoseconds since epoch
+    fn timestamp_nanos(&self, ts_high: u32, ts_low: u32) -> u64 {
+        let ts = ((ts_high as u64) << 32) | ts_low as u64;
+        let nanos = ts as u128 * 1_000_000_000 / self.ts_resolution as u128;
+        let offset = self.ts_offset as i128 * 1_000_000_000;
+        (nanos as i128 + offset).clamp(0, u64::MAX as i128) as u64
+    }
+}
+
+/// pcapng option code for interface name
+const OPTION_IF_NAME: OptionCode = OptionCode(2);
+/// pcapng option code for packets dropped since the previous packet
+const OPTION_EPB_DROPCOUNT: OptionCode = OptionCode(4);
+/// pcapng option code for packets dropped by the interface
+const OPTION_ISB_IFDROP: OptionCode = OptionCode(5);
+
+/// find value of pcapng option
+fn find_option<'a>(options: &'a [PcapNGOption<'_>], code: OptionCode) -> Option<&'a [u8]> {
+    options
+        .iter()
+        .find(|option| option.code == code)
+        .and_then(|option| option.as_bytes())
+}
+
+/// read u64 pcapng option
+fn find_option_u64(options: &[PcapNGOption<'_>], code: OptionCode) -> Option<u64> {
+    find_option(options, code)
+        .and_then(|value| value.try_into().ok())
+        .map(u64::from_le_bytes)
+}
+
+/// determine parse layer for link type
+fn parse_layer(linktype: Linktype) -> eyre::Result<ParseLayer> {
+    Ok(match linktype {
+        Linktype::ETHERNET => ParseLayer::Link,
+        Linktype::RAW => ParseLayer::IP,
+        Linktype::IPV4 => ParseLayer::IP,
+        Linktype::IPV6 => ParseLayer::IP,
+        Linktype::NULL => ParseLayer::BsdLoopback,
+        _ => eyre::bail!("unknown link type {:?}", linktype),
+    })
+}
+
 fn parse_packets(
-    reader: impl Read,
+    inputs: &[PathBuf],
+    opts: &RunOptions,
     mut handler: impl FnMut(TcpMeta, &[u8], PacketExtra) -> eyre::Result<()>,
+) -> eyre::Result<()> {
+    parse_all_packets(inputs, false, opts, |packet, extra| match packet {
+        ParsedPacket::Tcp(meta, data) => handler(meta, data, extra),
+        ParsedPacket::Udp(..) => unreachable!("udp not requested"),
+    })
+}
+
+/// parse TCP packets, and UDP packets if `parse_udp` is set
+///
+/// Inputs are read in order as one capture: packet indexes continue across
+/// files and connection state is carried over. Each input may be pcap or
+/// pcapng.
+fn parse_all_packets(
+    inputs: &[PathBuf],
+    parse_udp: bool,
+    opts: &RunOptions,
+    mut handler: impl FnMut(ParsedPacket<'_>, PacketExtra) -> eyre::Result<()>,
 ) -> eyre::Result<()> {
     let mut parser = TcpParser::new();
+    parser.checksum_policy = opts.checksum;
+    let mut duplicates = DuplicateFilter::new(opts.duplicate_window);
+    duplicates.check_ipv6 = opts.duplicate_ipv6;
+    let mut linktype = Linktype::NULL;
+    let mut interfaces: Vec<PcapngInterface> = Vec::new();
     let mut packet_counter = 0u64;
-    read_pcap_legacy(reader, |block| match block {
-        PcapBlockOwned::LegacyHeader(hdr) => {
-            debug!("pcap linktype: {:?}", hdr.network);
-            let layer = match hdr.network {
-                Linktype::ETHERNET => ParseLayer::Link,
-                Linktype::RAW => ParseLayer::IP,
-                Linktype::IPV4 => ParseLayer::IP,
-                Linktype::IPV6 => ParseLayer::IP,
-                Linktype::NULL => ParseLayer::BsdLoopback,
-                _ => eyre::bail!("pcap header: unknown link type {:?}", hdr.network),
-            };
-            parser.layer = layer;
-            Ok(())
-        }
-        PcapBlockOwned::Legacy(packet) => {
-            let index = packet_counter;
-            packet_counter += 1;
-            let extra = PacketExtra::LegacyPcap {
-                index,
-                ts_sec: packet.ts_sec,
-                ts_usec: packet.ts_usec,
-            };
-
-            if let Some((meta, data)) = parser.parse_packet(packet.data) {
-                handler(meta, data, extra)?;
-            };
-            Ok(())
-        }
-        PcapBlockOwned::NG(_) => unreachable!("read pcapng block in plain pcap"),
-    })
+    let progress = ProgressCounter::new();
+    let _reporter = opts
+        .progress
+        .map(|mode| ProgressReporter::start(progress.clone(), inputs_size(inputs), mode));
+    for path in inputs {
+        let _capture = info_span!("capture", file = %path.display()).entered();
+        info!("reading {}", path.display());
+        let reader = progress.wrap(open_input(path)?);
+        read_pcap(reader, opts.lenient, |block| match block {
+            PcapBlockOwned::LegacyHeader(hdr) => {
+                debug!("pcap linktype: {:?}", hdr.network);
+                parser.layer = parse_layer(hdr.network).wrap_err("pcap header")?;
+                linktype = hdr.network;
+                Ok(())
+            }
+            PcapBlockOwned::Legacy(packet) => {
+                let index = packet_counter;
+                packet_counter += 1;
+                let packet_span = debug_span!("packet", index, flow = field::Empty).entered();
+                parser.set_current_time(Duration::new(
+                    packet.ts_sec as u64,
+                    packet.ts_usec.saturating_mul(1000),
+                ));
+
+                let mut frames = Vec::new();
+                if opts.raw_frames {
+                    frames.push(RawFrame {
+                        linktype: linktype.0 as u32,
+                        ts_sec: packet.ts_sec,
+                        ts_usec: packet.ts_usec,
+                        orig_len: packet.origlen,
+                        data: packet.data.to_vec(),
+                    });
+                }
+                let parsed = parser.parse_packet_frames(packet.data, parse_udp, &mut frames);
+                let parsed = parsed.filter(|p| !is_duplicate(&mut duplicates, p));
+                if let Some(parsed) = parsed {
+                    packet_span.record("flow", field::display(parsed.flow()));
+                    let extra = PacketExtra::LegacyPcap {
+                        index,
+                        ts_sec: packet.ts_sec,
+                        ts_usec: packet.ts_usec,
+                        vlan_id: parsed.vlan_id(),
+                        frames: (!frames.is_empty()).then(|| frames.into()),
+                        ttl: Some(parsed.ttl()),
+                        bad_checksum: parsed.bad_checksum(),
+                    };
+                    handler(parsed, extra)?;
+                };
+                record_parse_failures(opts, &parser);
+                Ok(())
+            }
+            PcapBlockOwned::NG(Block::SectionHeader(_)) => {
+                // interface ids are scoped to the section
+                interfaces.clear();
+                Ok(())
+            }
+            PcapBlockOwned::NG(Block::InterfaceDescription(idb)) => {
+                let interface = PcapngInterface::new(&idb)
+                    .wrap_err_with(|| format!("pcapng interface {}", interfaces.len()))?;
+                debug!(
+                    "pcapng interface {}: linktype {:?}, name {:?}",
+                    interfaces.len(),
+                    interface.linktype,
+                    interface.name
+                );
+                interfaces.push(interface);
+                Ok(())
+            }
+            PcapBlockOwned::NG(Block::InterfaceStatistics(isb)) => {
+                if let Some(interface) = interfaces.get_mut(isb.if_id as usize) {
+                    if let Some(dropped) = find_option_u64(&isb.options, OPTION_ISB_IFDROP) {
+                        interface.dropped = Some(dropped);
+                    }
+                }
+                Ok(())
+            }
+            PcapBlockOwned::NG(Block::EnhancedPacket(epb)) => {
+                let index = packet_counter;
+                packet_counter += 1;
+                let packet_span = debug_span!("packet", index, flow = field::Empty).entered();
+                let Some(interface) = interfaces.get(epb.if_id as usize) else {
+                    eyre::bail!("pcapng packet {index}: unknown interface {}", epb.if_id);
+                };
+                let ts_nsec = interface.timestamp_nanos(epb.ts_high, epb.ts_low);
+                let timestamp = Duration::from_nanos(ts_nsec);
+                parser.set_current_time(timestamp);
+                parser.layer = interface.layer;
+
+                // block data includes padding
+                let data = &epb.data[..usize::min(epb.caplen as usize, epb.data.len())];
+                let mut frames = Vec::new();
+                if opts.raw_frames {
+                    frames.push(RawFrame {
+                        linktype: interface.linktype.0 as u32,
+                        ts_sec: timestamp.as_secs() as u32,
+                        ts_usec: timestamp.subsec_micros(),
+                        orig_len: epb.origlen,
+                        data: data.to_vec(),
+                    });
+                }
+                let parsed = parser.parse_packet_frames(data, parse_udp, &mut frames);
+                let parsed = parsed.filter(|p| !is_duplicate(&mut duplicates, p));
+                if let Some(parsed) = parsed {
+                    packet_span.record("flow", field::display(parsed.flow()));
+                    let extra = PacketExtra::Pcapng {
+                        index,
+                        interface_id: epb.if_id,
+                        interface_name: interface.name.clone(),
+                        ts_nsec,
+                        dropped: find_option_u64(&epb.options, OPTION_EPB_DROPCOUNT),
+                        interface_dropped: interface.dropped,
+                        vlan_id: parsed.vlan_id(),
+                        frames: (!frames.is_empty()).then(|| frames.into()),
+                        ttl: Some(parsed.ttl()),
+                        bad_checksum: parsed.bad_checksum(),
+                    };
+                    handler(parsed, extra)?;
+                }
+                record_parse_failures(opts, &parser);
+                Ok(())
+            }
+            PcapBlockOwned::NG(block) => {
+                trace!("skipping pcapng block {:08x}", block.magic());
+                Ok(())
+            }
+        })?;
+    }
+    if opts.duplicate_window > 0 {
+        info!(
+            "duplicates: suppressed {} of {} packets ({} payload bytes)",
+            duplicates.suppressed, duplicates.checked, duplicates.suppressed_bytes
+        );
+    }
+    if opts.checksum != ChecksumPolicy::Ignore {
+        info!(
+            "checksums: {} bad IPv4 header, {} bad TCP/UDP",
+            parser.bad_ip_checksum, parser.bad_transport_checksum
+        );
+    }
+    Ok(())
+}
+
+/// update parse failure count in metrics, if enabled
+fn record_parse_failures(opts: &RunOptions, parser: &TcpParser) {
+    if let Some(metrics) = &opts.config.metrics {
+        metrics.set_parse_failures(parser.failed_parse as u64);
+    }
 }
 
-fn read_pcap_legacy(
+/// whether packet duplicates a recent TCP packet
+fn is_duplicate(filter: &mut DuplicateFilter, packet: &ParsedPacket<'_>) -> bool {
+    match packet {
+        ParsedPacket::Tcp(meta, data) => filter.is_duplicate(meta, data.len()),
+        ParsedPacket::Udp(..) => false,
+    }
+}
+
+/// read blocks of a pcap or pcapng capture
+///
+/// If `lenient` is set, damaged regions of pcap files are skipped by scanning
+/// for the next plausible record. Damage in pcapng files is still an error.
+fn read_pcap(
     reader: impl Read,
+    lenient: bool,
     mut handler: impl FnMut(PcapBlockOwned<'_>) -> eyre::Result<()>,
 ) -> eyre::Result<()> {
-    let mut pcap_reader = LegacyPcapReader::new(PCAP_READER_BUFFER_SIZE, reader)
-        .wrap_err("failed to create LegacyPcapReader")?;
+    let mut pcap_reader = match create_reader(PCAP_READER_BUFFER_SIZE, reader) {
+        Ok(reader) => reader,
+        Err(PcapError::Eof) => {
+            debug!("empty capture");
+            return Ok(());
+        }
+        Err(PcapError::HeaderNotRecognized) => {
+            eyre::bail!("header not recognized (invalid pcap file?)");
+        }
+        Err(e) => eyre::bail!("failed to create pcap reader: {e}"),
+    };
+    // set for legacy pcap files in lenient mode
+    let mut record_check: Option<RecordCheck> = None;
+    let (mut damaged_regions, mut skipped_bytes) = (0u64, 0u64);
     loop {
-        match pcap_reader.next() {
+        let damaged = match pcap_reader.next() {
             Ok((offset, block)) => {
-                handler(block)?;
-                pcap_reader.consume(offset);
+                let plausible = match (&mut record_check, &block) {
+                    (Some(check), PcapBlockOwned::Legacy(packet)) => check.accept(&RecordHeader {
+                        ts_sec: packet.ts_sec,
+                        ts_frac: packet.ts_usec,
+                        caplen: packet.caplen,
+                        origlen: packet.origlen,
+                    }),
+                    (check, PcapBlockOwned::LegacyHeader(header)) if lenient => {
+                        *check = Some(RecordCheck::new(header));
+                        true
+                    }
+                    _ => true,
+                };
+                if plausible {
+                    handler(block)?;
+                    pcap_reader.consume(offset);
+                }
+                !plausible
             }
             Err(PcapError::Eof) => {
                 debug!("eof");
                 break;
             }
+            Err(PcapError::UnexpectedEof) if record_check.is_some() => true,
             Err(PcapError::UnexpectedEof) => {
                 error!("unexpected eof while reading pcap");
                 break;
             }
+            Err(PcapError::Incomplete(needed))
+                if needed > PCAP_READER_BUFFER_SIZE / 2 && record_check.is_some() =>
+            {
+                true
+            }
             Err(PcapError::Incomplete(needed)) => {
                 trace!("refilling pcap reader buffer (needed {needed} bytes)");
                 if needed > PCAP_READER_BUFFER_SIZE / 2 {
@@ -171,14 +1521,8 @@ fn read_pcap_legacy(
                         "packet size exceeded limit! pcap-parser wanted {needed} more bytes"
                     );
                 }
-                match pcap_reader.refill() {
-                    Ok(()) => {}
-                    // only valid result is ReadError
-                    Err(PcapError::ReadError) => {
-                        eyre::bail!("read error occured while reading pcap");
-                    }
-                    _ => unreachable!(),
-                }
+                refill(&mut *pcap_reader)?;
+                false
             }
             Err(PcapError::HeaderNotRecognized) => {
                 eyre::bail!("header not recognized (invalid pcap file?)");
@@ -189,14 +1533,75 @@ fn read_pcap_legacy(
             Err(PcapError::ReadError) => {
                 eyre::bail!("read error occured while reading pcap");
             }
+            Err(PcapError::BufferTooSmall) if record_check.is_some() => true,
             Err(PcapError::BufferTooSmall) => {
                 eyre::bail!("pcap read buffer too small");
             }
+        };
+        if let (true, Some(check)) = (damaged, &record_check) {
+            if pcap_reader.data().is_empty() {
+                // nothing left after a record cut short by the end of file
+                break;
+            }
+            let position = pcap_reader.consumed();
+            let skipped = resync(&mut *pcap_reader, check)?;
+            warn!("skipped {skipped} damaged bytes at offset {position} of pcap");
+            damaged_regions += 1;
+            skipped_bytes += skipped as u64;
         }
     }
+    if damaged_regions > 0 {
+        warn!("skipped {damaged_regions} damaged regions of pcap ({skipped_bytes} bytes)");
+    }
     Ok(())
 }
 
+/// read more data into pcap reader buffer
+fn refill(pcap_reader: &mut dyn PcapReaderIterator) -> eyre::Result<()> {
+    match pcap_reader.refill() {
+        Ok(()) => Ok(()),
+        // only valid result is ReadError
+        Err(PcapError::ReadError) => {
+            eyre::bail!("read error occured while reading pcap");
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// skip damaged record at start of buffer and data up to the next plausible
+/// record, returning number of bytes skipped
+fn resync(pcap_reader: &mut dyn PcapReaderIterator, check: &RecordCheck) -> eyre::Result<usize> {
+    pcap_reader.consume(1);
+    let mut skipped = 1;
+    loop {
+        let at_eof = pcap_reader.reader_exhausted();
+        match check.find_record(pcap_reader.data(), at_eof) {
+            Resync::Found(offset) => {
+                pcap_reader.consume(offset);
+                return Ok(skipped + offset);
+            }
+            Resync::Unconfirmed(offset) => {
+                pcap_reader.consume(offset);
+                skipped += offset;
+                let available = pcap_reader.data().len();
+                refill(pcap_reader)?;
+                if pcap_reader.data().len() == available && !pcap_reader.reader_exhausted() {
+                    // buffer is full, accept the record as is
+                    return Ok(skipped);
+                }
+            }
+            Resync::Skip(offset) => {
+                pcap_reader.consume(offset);
+                skipped += offset;
+                if at_eof {
+                    return Ok(skipped);
+                }
+                refill(pcap_reader)?;
+            }
+        }
+    }
+}
+
 /// raise RLIMIT_NOFILE so we can open more files
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 unsafe fn i_want_more_files(more_files: u64) -> eyre::Result<u64> {
@@ -224,3 +1629,128 @@ unsafe fn i_want_more_files(more_files: u64) -> eyre::Result<u64> {
     }
     Ok(new_limit.rlim_cur)
 }
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::fs;
+
+    use etherparse::PacketBuilder;
+    use parse_tcp::connection::{Connection, ConnectionState};
+
+    use super::*;
+
+    struct NullHandler;
+    impl ConnectionHandler for NullHandler {
+        type InitialData = ();
+        type ConstructError = Infallible;
+        fn new(_init: (), _conn: &mut Connection<Self>) -> Result<Self, Infallible> {
+            Ok(NullHandler)
+        }
+    }
+
+    fn test_options() -> RunOptions<'static> {
+        RunOptions {
+            config: ReassemblyConfig::default(),
+            stats_out: None,
+            filter: None,
+            checksum: ChecksumPolicy::Ignore,
+            duplicate_window: 0,
+            duplicate_ipv6: false,
+            lenient: false,
+            raw_frames: false,
+            progress: None,
+            #[cfg(feature = "tls-decrypt")]
+            tls_keylog: None,
+        }
+    }
+
+    /// ethernet frame of a TCP packet from client (`forward`) or server
+    fn frame(forward: bool, seq: u32, ack: Option<u32>, syn: bool, payload: &[u8]) -> Vec<u8> {
+        let (client, server) = (([10, 9, 0, 1], 40090), ([10, 9, 0, 2], 80));
+        let (src, dst) = if forward {
+            (client, server)
+        } else {
+            (server, client)
+        };
+        let mut tcp = PacketBuilder::ethernet2([2, 0, 0, 0, 0, 1], [2, 0, 0, 0, 0, 2])
+            .ipv4(src.0, dst.0, 64)
+            .tcp(src.1, dst.1, seq, 65535);
+        if syn {
+            tcp = tcp.syn();
+        }
+        if let Some(ack) = ack {
+            tcp = tcp.ack(ack);
+        }
+        let mut packet = Vec::new();
+        tcp.write(&mut packet, payload).unwrap();
+        packet
+    }
+
+    fn write_capture(path: &Path, frames: &[(u32, Vec<u8>)]) {
+        let mut writer = PcapWriter::new(File::create(path).unwrap(), SYNTH_LINKTYPE).unwrap();
+        for (ts_sec, data) in frames {
+            writer
+                .write_packet(*ts_sec, 0, data.len() as u32, data)
+                .unwrap();
+        }
+    }
+
+    /// read inputs into a flow table, returning indexes of packets seen
+    fn run(inputs: &[PathBuf]) -> (Vec<u64>, FlowTable<NullHandler>) {
+        let opts = test_options();
+        let mut flowtable: FlowTable<NullHandler> = new_flowtable((), &opts);
+        let mut indexes = Vec::new();
+        parse_packets(inputs, &opts, |meta, data, extra| {
+            indexes.push(extra.index().unwrap());
+            flowtable.handle_packet(&meta, data, &extra).unwrap();
+            Ok(())
+        })
+        .unwrap();
+        (indexes, flowtable)
+    }
+
+    #[test]
+    fn split_capture_matches_single_file() {
+        let frames = vec![
+            (100, frame(true, 1000, None, true, b"")),
+            (101, frame(false, 5000, Some(1001), true, b"")),
+            (102, frame(true, 1001, Some(5001), false, b"")),
+            (103, frame(true, 1001, Some(5001), false, b"hello ")),
+            (104, frame(true, 1007, Some(5001), false, b"world")),
+            (105, frame(false, 5001, Some(1012), false, b"ok")),
+        ];
+        let dir = std::env::temp_dir().join(format!("tcpreassemble-split-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let whole = dir.join("cap.pcap");
+        let parts = [dir.join("cap-0001.pcap"), dir.join("cap-0002.pcap")];
+        write_capture(&whole, &frames);
+        // split after the first data packet
+        write_capture(&parts[0], &frames[..4]);
+        write_capture(&parts[1], &frames[4..]);
+
+        let (single_indexes, single) = run(&[whole]);
+        let pattern = dir.join("cap-*.pcap");
+        let inputs = expand_inputs(&[pattern]).unwrap();
+        assert_eq!(inputs, parts);
+        let (split_indexes, split) = run(&inputs);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(single_indexes, (0..6).collect::<Vec<u64>>());
+        assert_eq!(split_indexes, single_indexes);
+        // the connection continues in the second file
+        assert_eq!(split.map.len(), 1);
+        assert_eq!(single.map.len(), 1);
+        let split_conn = split.map.values().next().unwrap();
+        let single_conn = single.map.values().next().unwrap();
+        assert!(matches!(
+            split_conn.conn_state,
+            ConnectionState::Established { .. }
+        ));
+        assert_eq!(split_conn.conn_state, single_conn.conn_state);
+        assert_eq!(split_conn.forward_stream.readable_buffered_length(), 11);
+        assert_eq!(split_conn.reverse_stream.readable_buffered_length(), 2);
+        assert_eq!(split_conn.last_packet_time, Some(Duration::from_secs(105)));
+        assert_eq!(split_conn.first_packet_time, single_conn.first_packet_time);
+    }
+}
@@ -0,0 +1,653 @@
+//! Checkpointing of flow table state
+//!
+//! A checkpoint is written as JSON lines: a header followed by one line per
+//! active connection. Connection handlers are not persisted; on restore, new
+//! handlers are constructed for every connection and may pick up state saved
+//! through `ConnectionHandler::checkpoint_state`. RTT estimators start over.
+
+use std::collections::BinaryHeap;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use kinesin_rdt::stream::inbound::{OverlapPolicy, StreamInboundSnapshot, StreamInboundState};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::classify::Classification;
+use crate::config::ReassemblyConfig;
+use crate::connection::{CloseReason, Connection, ConnectionState, StateTransition};
+use crate::direction::DirectionInference;
+use crate::flow_table::{Flow, FlowTable};
+use crate::handshake::HandshakeInfo;
+use crate::matching::StreamScanner;
+use crate::resync::Resync;
+use crate::rtt::RttEstimator;
+use crate::serialized::PacketRef;
+use crate::stream::{SegmentInfo, SeqOffset, SkippedGap, Stream, StreamGap};
+use crate::ttl::TtlTracker;
+use crate::ConnectionHandler;
+
+/// checkpoint format version
+pub const CHECKPOINT_VERSION: u32 = 2;
+
+/// first line of a checkpoint
+#[derive(Serialize, Deserialize)]
+pub struct CheckpointHeader {
+    /// format version
+    pub version: u32,
+    /// number of connection lines following
+    pub connection_count: usize,
+}
+
+/// serde helper writing byte buffers as base64 strings
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// persisted state of a Stream
+#[derive(Serialize, Deserialize)]
+pub struct StreamCheckpoint {
+    pub initial_sequence_number: u32,
+    pub seq_offset: SeqOffset,
+    pub window_scale: u8,
+    pub got_window_scale: bool,
+    /// reassembly state, excluding buffer
+    pub state: StreamInboundSnapshot,
+    /// buffered bytes starting at `state.buffer_offset`, base64 encoded
+    #[serde(with = "base64_bytes")]
+    pub buffer: Vec<u8>,
+    pub seq_window_start: u32,
+    pub seq_window_end: u32,
+    pub highest_acked: u64,
+    pub reverse_acked: u64,
+    pub had_reset: bool,
+    pub has_ended: bool,
+    pub gaps_length: u64,
+    #[serde(default)]
+    pub declared_gap_end: u64,
+    #[serde(default)]
+    pub pending_gap: Option<(u64, u32)>,
+    #[serde(default)]
+    pub pending_gap_edges: (Option<PacketRef>, Option<PacketRef>),
+    #[serde(default)]
+    pub declared_gaps: Vec<StreamGap>,
+    #[serde(default)]
+    pub skipped_gaps: Vec<SkippedGap>,
+    #[serde(default)]
+    pub late_data_bytes: u64,
+    #[serde(default)]
+    pub last_data_packet: Option<PacketRef>,
+    #[serde(default)]
+    pub previous_data_packet: Option<PacketRef>,
+    pub retransmit_count: usize,
+    pub packet_count: u64,
+    #[serde(default)]
+    pub first_packet_time: Option<Duration>,
+    #[serde(default)]
+    pub last_packet_time: Option<Duration>,
+    pub data_bytes: u64,
+    pub retransmit_bytes: u64,
+    pub fin_count: usize,
+    pub rst_count: usize,
+    #[serde(default)]
+    pub ts_recent: Option<u32>,
+    #[serde(default)]
+    pub paws_rejected: usize,
+    /// pending segment metadata, in no particular order
+    pub segments_info: Vec<SegmentInfo>,
+    pub segments_info_dropped: usize,
+    #[serde(default)]
+    pub overlap_policy: Option<OverlapPolicy>,
+}
+
+/// persisted state of a Connection
+#[derive(Serialize, Deserialize)]
+pub struct ConnectionCheckpoint {
+    pub uuid: Uuid,
+    pub forward_flow: Flow,
+    pub conn_state: ConnectionState,
+    pub observed_handshake: bool,
+    pub observed_close: bool,
+    #[serde(default)]
+    pub close_reason: Option<CloseReason>,
+    #[serde(default)]
+    pub transitions: Vec<StateTransition>,
+    pub forward_stream: StreamCheckpoint,
+    pub reverse_stream: StreamCheckpoint,
+    pub first_packet_time: Option<Duration>,
+    pub last_packet_time: Option<Duration>,
+    #[serde(default)]
+    pub classification: Classification,
+    #[serde(default)]
+    pub direction_inference: DirectionInference,
+    #[serde(default)]
+    pub handshake: HandshakeInfo,
+    /// resynchronization in progress, with the packets held back for it
+    #[serde(default)]
+    pub resync: Option<Resync>,
+    /// state saved by the connection handler
+    #[serde(default)]
+    pub handler_state: Option<serde_json::Value>,
+}
+
+/// error restoring checkpoint
+#[derive(Debug)]
+pub enum RestoreError<E> {
+    /// checkpoint could not be read or parsed
+    Format(serde_json::Error),
+    /// checkpoint was written by an incompatible version
+    Version(u32),
+    /// connection handler could not be constructed
+    Construct(E),
+}
+
+impl<E> From<serde_json::Error> for RestoreError<E> {
+    fn from(value: serde_json::Error) -> Self {
+        RestoreError::Format(value)
+    }
+}
+
+impl Stream {
+    /// capture stream state, including buffered data
+    pub fn checkpoint(&self) -> StreamCheckpoint {
+        StreamCheckpoint {
+            initial_sequence_number: self.initial_sequence_number,
+            seq_offset: self.seq_offset.clone(),
+            window_scale: self.window_scale,
+            got_window_scale: self.got_window_scale,
+            state: self.state.snapshot(),
+            buffer: self.state.buffer.iter().copied().collect(),
+            seq_window_start: self.seq_window_start,
+            seq_window_end: self.seq_window_end,
+            highest_acked: self.highest_acked,
+            reverse_acked: self.reverse_acked,
+            had_reset: self.had_reset,
+            has_ended: self.has_ended,
+            gaps_length: self.gaps_length,
+            declared_gap_end: self.declared_gap_end,
+            pending_gap: self.pending_gap,
+            pending_gap_edges: self.pending_gap_edges,
+            declared_gaps: self.declared_gaps.iter().cloned().collect(),
+            skipped_gaps: self.skipped_gaps.iter().cloned().collect(),
+            late_data_bytes: self.late_data_bytes,
+            last_data_packet: self.last_data_packet,
+            previous_data_packet: self.previous_data_packet,
+            retransmit_count: self.retransmit_count,
+            packet_count: self.packet_count,
+            first_packet_time: self.first_packet_time,
+            last_packet_time: self.last_packet_time,
+            data_bytes: self.data_bytes,
+            retransmit_bytes: self.retransmit_bytes,
+            fin_count: self.fin_count,
+            rst_count: self.rst_count,
+            ts_recent: self.ts_recent,
+            paws_rejected: self.paws_rejected,
+            segments_info: self.segments_info.clone().into_vec(),
+            segments_info_dropped: self.segments_info_dropped,
+            overlap_policy: Some(self.state.overlap_policy),
+        }
+    }
+
+    /// restore stream from checkpoint
+    pub fn from_checkpoint(config: Arc<ReassemblyConfig>, checkpoint: StreamCheckpoint) -> Self {
+        let mut state = StreamInboundState::restore(checkpoint.state, &checkpoint.buffer);
+        state.pool = config.buffer_pool.clone();
+        state.overlap_policy = checkpoint.overlap_policy.unwrap_or(config.overlap_policy);
+        let mut stream = Stream {
+            config,
+            initial_sequence_number: checkpoint.initial_sequence_number,
+            seq_offset: checkpoint.seq_offset,
+            window_scale: checkpoint.window_scale,
+            got_window_scale: checkpoint.got_window_scale,
+            state,
+            seq_window_start: checkpoint.seq_window_start,
+            seq_window_end: checkpoint.seq_window_end,
+            highest_acked: checkpoint.highest_acked,
+            reverse_acked: checkpoint.reverse_acked,
+            had_reset: checkpoint.had_reset,
+            has_ended: checkpoint.has_ended,
+            gaps_length: checkpoint.gaps_length,
+            declared_gap_end: checkpoint.declared_gap_end,
+            pending_gap: checkpoint.pending_gap,
+            pending_gap_edges: checkpoint.pending_gap_edges,
+            declared_gaps: checkpoint.declared_gaps.into(),
+            skipped_gaps: checkpoint.skipped_gaps.into(),
+            late_data: Vec::new(),
+            late_data_bytes: checkpoint.late_data_bytes,
+            last_data_packet: checkpoint.last_data_packet,
+            previous_data_packet: checkpoint.previous_data_packet,
+            retransmit_count: checkpoint.retransmit_count,
+            packet_count: checkpoint.packet_count,
+            first_packet_time: checkpoint.first_packet_time,
+            last_packet_time: checkpoint.last_packet_time,
+            data_bytes: checkpoint.data_bytes,
+            retransmit_bytes: checkpoint.retransmit_bytes,
+            fin_count: checkpoint.fin_count,
+            rst_count: checkpoint.rst_count,
+            ts_recent: checkpoint.ts_recent,
+            paws_rejected: checkpoint.paws_rejected,
+            segments_info: BinaryHeap::from(checkpoint.segments_info),
+            segments_info_dropped: checkpoint.segments_info_dropped,
+            memory_accounted: 0,
+            memory_pressure_unrelieved: None,
+        };
+        stream.update_memory_usage();
+        stream
+    }
+}
+
+impl<H: ConnectionHandler> Connection<H> {
+    /// capture connection state, excluding the event handler
+    pub fn checkpoint(&self) -> ConnectionCheckpoint {
+        ConnectionCheckpoint {
+            uuid: self.uuid,
+            forward_flow: self.forward_flow.clone(),
+            conn_state: self.conn_state.clone(),
+            observed_handshake: self.observed_handshake,
+            observed_close: self.observed_close,
+            close_reason: self.close_reason,
+            transitions: self.transitions.clone(),
+            forward_stream: self.forward_stream.checkpoint(),
+            reverse_stream: self.reverse_stream.checkpoint(),
+            first_packet_time: self.first_packet_time,
+            last_packet_time: self.last_packet_time,
+            classification: self.classification.clone(),
+            direction_inference: self.direction_inference.clone(),
+            handshake: self.handshake.clone(),
+            resync: self.resync.clone(),
+            handler_state: self
+                .event_handler
+                .as_ref()
+                .and_then(|handler| handler.checkpoint_state()),
+        }
+    }
+
+    /// restore connection from checkpoint, constructing a new event handler
+    ///
+    /// If the connection is past its handshake, the handler is notified with
+    /// `ConnectionHandler::restored`.
+    pub fn from_checkpoint(
+        config: Arc<ReassemblyConfig>,
+        checkpoint: ConnectionCheckpoint,
+        handler_init_data: H::InitialData,
+    ) -> Result<Connection<H>, H::ConstructError> {
+        let mut conn = Connection {
+            uuid: checkpoint.uuid,
+            forward_flow: checkpoint.forward_flow,
+            conn_state: checkpoint.conn_state,
+            config: config.clone(),
+            observed_handshake: checkpoint.observed_handshake,
+            observed_close: checkpoint.observed_close,
+            close_reason: checkpoint.close_reason,
+            transitions: checkpoint.transitions,
+            current_packet_index: None,
+            forward_stream: Stream::from_checkpoint(config.clone(), checkpoint.forward_stream),
+            reverse_stream: Stream::from_checkpoint(config, checkpoint.reverse_stream),
+            forward_rtt: RttEstimator::new(),
+            reverse_rtt: RttEstimator::new(),
+            forward_ttl: TtlTracker::new(),
+            reverse_ttl: TtlTracker::new(),
+            first_packet_time: checkpoint.first_packet_time,
+            last_packet_time: checkpoint.last_packet_time,
+            classification: checkpoint.classification,
+            scanners: Default::default(),
+            direction_inference: checkpoint.direction_inference,
+            handshake: checkpoint.handshake,
+            resync: checkpoint.resync,
+            // only set while handling a packet
+            desync_packets: Vec::new(),
+            event_handler: None,
+        };
+        // buffered data was scanned before the checkpoint was taken
+        let streams = [&conn.forward_stream, &conn.reverse_stream];
+        for (scanner, stream) in conn.scanners.iter_mut().zip(streams) {
+            let contiguous_end = stream.buffer_start() + stream.readable_buffered_length() as u64;
+            *scanner = StreamScanner::starting_at(contiguous_end);
+        }
+        let handler = H::new(handler_init_data, &mut conn)?;
+        conn.event_handler = Some(Box::new(handler));
+        if matches!(
+            conn.conn_state,
+            ConnectionState::Established { .. } | ConnectionState::Closed
+        ) {
+            let state = checkpoint.handler_state;
+            conn.call_handler(|conn, h| h.restored(conn, state));
+        }
+        Ok(conn)
+    }
+}
+
+impl<H: ConnectionHandler> FlowTable<H>
+where
+    H::InitialData: Clone,
+{
+    /// write checkpoint of all active connections
+    ///
+    /// Retired connections and statistics are not included.
+    pub fn snapshot(&self, mut writer: impl Write) -> serde_json::Result<()> {
+        let header = CheckpointHeader {
+            version: CHECKPOINT_VERSION,
+            connection_count: self.map.len(),
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n").map_err(serde_json::Error::io)?;
+        for conn in self.map.values() {
+            serde_json::to_writer(&mut writer, &conn.checkpoint())?;
+            writer.write_all(b"\n").map_err(serde_json::Error::io)?;
+        }
+        writer.flush().map_err(serde_json::Error::io)
+    }
+
+    /// restore connections from checkpoint, returning the number restored
+    ///
+    /// Restored connections replace existing connections with the same flow.
+    pub fn restore(
+        &mut self,
+        reader: impl BufRead,
+    ) -> Result<usize, RestoreError<H::ConstructError>> {
+        let mut lines = reader.lines();
+        let mut next_line = || -> serde_json::Result<String> {
+            match lines.next() {
+                Some(line) => line.map_err(serde_json::Error::io),
+                None => Err(serde_json::Error::io(
+                    std::io::ErrorKind::UnexpectedEof.into(),
+                )),
+            }
+        };
+        let header: CheckpointHeader = serde_json::from_str(&next_line()?)?;
+        if header.version != CHECKPOINT_VERSION {
+            return Err(RestoreError::Version(header.version));
+        }
+        for _ in 0..header.connection_count {
+            let checkpoint: ConnectionCheckpoint = serde_json::from_str(&next_line()?)?;
+            let conn = Connection::from_checkpoint(
+                self.config.clone(),
+                checkpoint,
+                self.handler_init_data.clone(),
+            )
+            .map_err(RestoreError::Construct)?;
+            debug!("restored flow: {} {}", conn.uuid, conn.forward_flow);
+            self.map.insert(conn.forward_flow.clone(), conn);
+        }
+        Ok(header.connection_count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use crate::config::ReassemblyConfig;
+    use crate::connection::Connection;
+    use crate::flow_table::FlowTable;
+    use crate::handler::{DirectoryOutputHandler, DirectoryOutputSharedInfo};
+    use crate::serialized::{OutputProgress, PacketExtra};
+    use crate::{ConnectionHandler, TcpFlags, TcpMeta};
+
+    struct NullHandler;
+    impl ConnectionHandler for NullHandler {
+        type InitialData = ();
+        type ConstructError = Infallible;
+        fn new(_init: (), _conn: &mut Connection<Self>) -> Result<Self, Infallible> {
+            Ok(NullHandler)
+        }
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 500,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut table: FlowTable<NullHandler> = FlowTable::new(());
+        assert!(table
+            .handle_packet(&meta, b"hello", &PacketExtra::None)
+            .unwrap());
+
+        let mut checkpoint = Vec::new();
+        table.snapshot(&mut checkpoint).unwrap();
+        // buffered data is stored as base64
+        assert!(String::from_utf8_lossy(&checkpoint).contains(r#""buffer":"aGVsbG8=""#));
+        let mut restored: FlowTable<NullHandler> = FlowTable::new(());
+        assert_eq!(restored.restore(&checkpoint[..]).unwrap(), 1);
+        assert!(FlowTable::<NullHandler>::new(())
+            .restore(&checkpoint[..10])
+            .is_err());
+
+        // continue processing in both tables
+        meta.seq_number += 5;
+        for table in [&mut table, &mut restored] {
+            assert!(table
+                .handle_packet(&meta, b" world", &PacketExtra::None)
+                .unwrap());
+        }
+        let flow = (&meta).into();
+        let original = &table.map[&flow];
+        let conn = &restored.map[&flow];
+        assert_eq!(conn.uuid, original.uuid);
+        assert_eq!(conn.conn_state, original.conn_state);
+        let stream = &conn.forward_stream;
+        assert_eq!(stream.packet_count, 2);
+        assert_eq!(
+            stream.segments_info.len(),
+            original.forward_stream.segments_info.len()
+        );
+        assert_eq!(stream.readable_buffered_length(), 11);
+        assert!(stream.state.buffer.iter().eq(b"hello world"));
+    }
+
+    #[test]
+    fn snapshot_during_resync() {
+        let syn = TcpMeta {
+            src_addr: [10, 0, 0, 3].into(),
+            src_port: 1001,
+            dst_addr: [10, 0, 0, 4].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let syn_ack = TcpMeta {
+            src_addr: syn.dst_addr,
+            src_port: syn.dst_port,
+            dst_addr: syn.src_addr,
+            dst_port: syn.src_port,
+            seq_number: 5000,
+            ack_number: 1001,
+            flags: TcpFlags {
+                syn: true,
+                ack: true,
+                ..Default::default()
+            },
+            ..syn.clone()
+        };
+        let ack = TcpMeta {
+            seq_number: 1001,
+            ack_number: 5001,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            ..syn.clone()
+        };
+        let mut table: FlowTable<NullHandler> = FlowTable::new(());
+        for (meta, payload) in [(&syn, &b""[..]), (&syn_ack, b""), (&ack, b"aaaa")] {
+            table
+                .handle_packet(meta, payload, &PacketExtra::None)
+                .unwrap();
+        }
+        // spurious SYN holds back the following packets
+        let mut spurious = syn.clone();
+        spurious.seq_number = 900_000;
+        table
+            .handle_packet(&spurious, &[], &PacketExtra::None)
+            .unwrap();
+        let flow = (&ack).into();
+        assert!(table.map[&flow].resync.is_some());
+
+        let mut checkpoint = Vec::new();
+        table.snapshot(&mut checkpoint).unwrap();
+        let mut restored: FlowTable<NullHandler> = FlowTable::new(());
+        assert_eq!(restored.restore(&checkpoint[..]).unwrap(), 1);
+        let resync = restored.map[&flow].resync.as_ref().unwrap();
+        assert_eq!(resync.held.len(), 1);
+
+        // resolved the same way in both tables
+        for table in [&mut table, &mut restored] {
+            for i in 1..=4 {
+                let mut data = ack.clone();
+                data.seq_number = 1001 + 4 * i;
+                table
+                    .handle_packet(&data, b"bbbb", &PacketExtra::None)
+                    .unwrap();
+            }
+            let conn = &table.map[&flow];
+            assert!(conn.resync.is_none());
+            assert_eq!(conn.forward_stream.readable_buffered_length(), 20);
+        }
+    }
+
+    #[test]
+    fn restore_directory_output() {
+        let dir = std::env::temp_dir().join(format!("parse-tcp-checkpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // write out data as soon as it is readable
+        let config = ReassemblyConfig {
+            flush_readable_threshold: 0,
+            ..Default::default()
+        };
+        let syn = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let syn_ack = TcpMeta {
+            src_addr: syn.dst_addr,
+            src_port: syn.dst_port,
+            dst_addr: syn.src_addr,
+            dst_port: syn.src_port,
+            seq_number: 500,
+            ack_number: 101,
+            flags: TcpFlags {
+                syn: true,
+                ack: true,
+                ..Default::default()
+            },
+            ..syn.clone()
+        };
+        let mut ack = TcpMeta {
+            seq_number: 101,
+            ack_number: 501,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            ..syn.clone()
+        };
+
+        // first run stops after taking a checkpoint
+        let (shared_info, _errors) = DirectoryOutputSharedInfo::new(dir.clone()).unwrap();
+        let mut table: FlowTable<DirectoryOutputHandler> =
+            FlowTable::with_config(shared_info.clone(), config.clone());
+        for (meta, payload) in [(&syn, &b""[..]), (&syn_ack, b""), (&ack, b"hello")] {
+            assert!(table
+                .handle_packet(meta, payload, &PacketExtra::None)
+                .unwrap());
+        }
+        let id = table.map.values().next().unwrap().uuid;
+        let mut checkpoint = Vec::new();
+        table.snapshot(&mut checkpoint).unwrap();
+        drop(table);
+        shared_info.close().unwrap();
+
+        // second run continues the files of the first
+        let (shared_info, errors) = DirectoryOutputSharedInfo::new(dir.clone()).unwrap();
+        let mut table: FlowTable<DirectoryOutputHandler> =
+            FlowTable::with_config(shared_info.clone(), config);
+        assert_eq!(table.restore(&checkpoint[..]).unwrap(), 1);
+        ack.seq_number += 5;
+        assert!(table
+            .handle_packet(&ack, b" world", &PacketExtra::None)
+            .unwrap());
+        table.close();
+        drop(table);
+        shared_info.close().unwrap();
+        assert!(errors.try_recv().is_err());
+
+        let data = std::fs::read(dir.join(format!("{id}.f.data"))).unwrap();
+        let sidecar = std::fs::read(dir.join(format!("{id}.meta.json"))).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(data, b"hello world");
+        let progress: OutputProgress = serde_json::from_slice(&sidecar).unwrap();
+        assert!(progress.complete);
+        assert_eq!(progress.forward.data_bytes, 11);
+        assert_eq!(progress.forward.end_offset, 11);
+    }
+}
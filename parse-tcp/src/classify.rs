@@ -0,0 +1,249 @@
+//! Application protocol classification
+//!
+//! Connections are first tagged from well-known ports, then from signatures
+//! in the first bytes of each reassembled stream. Payload matches take
+//! precedence over port matches.
+
+use serde::{Deserialize, Serialize};
+
+use crate::connection::Direction;
+use crate::flow_table::Flow;
+use crate::stream::Stream;
+
+/// number of leading stream bytes inspected for signatures
+pub const SIGNATURE_LENGTH: usize = 16;
+
+/// guessed application protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AppProtocol {
+    Http,
+    Tls,
+    Ssh,
+    Smtp,
+    Ftp,
+    Pop3,
+    Imap,
+    /// DNS over TCP
+    Dns,
+}
+
+/// well-known server port ranges
+const PORT_RANGES: &[(u16, u16, AppProtocol)] = &[
+    (21, 21, AppProtocol::Ftp),
+    (22, 22, AppProtocol::Ssh),
+    (25, 25, AppProtocol::Smtp),
+    (53, 53, AppProtocol::Dns),
+    (80, 80, AppProtocol::Http),
+    (110, 110, AppProtocol::Pop3),
+    (143, 143, AppProtocol::Imap),
+    (443, 443, AppProtocol::Tls),
+    (465, 465, AppProtocol::Tls),
+    (587, 587, AppProtocol::Smtp),
+    (853, 853, AppProtocol::Tls),
+    (993, 995, AppProtocol::Tls),
+    (8000, 8001, AppProtocol::Http),
+    (8080, 8088, AppProtocol::Http),
+    (8443, 8443, AppProtocol::Tls),
+];
+
+/// HTTP request methods, including trailing space
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+];
+
+/// guess protocol from a single port
+pub fn classify_port(port: u16) -> Option<AppProtocol> {
+    PORT_RANGES
+        .iter()
+        .find(|(low, high, _)| (*low..=*high).contains(&port))
+        .map(|(_, _, protocol)| *protocol)
+}
+
+/// guess protocol from the ports of a flow, preferring the destination
+pub fn classify_flow(flow: &Flow) -> Option<AppProtocol> {
+    classify_port(flow.dst_port).or_else(|| classify_port(flow.src_port))
+}
+
+/// guess protocol from the first bytes of a stream in either direction
+pub fn classify_payload(data: &[u8]) -> Option<AppProtocol> {
+    if HTTP_METHODS.iter().any(|m| data.starts_with(m)) || data.starts_with(b"HTTP/1.") {
+        return Some(AppProtocol::Http);
+    }
+    // handshake record, SSL 3.0 through TLS 1.3
+    if let [0x16, 0x03, 0..=0x04, ..] = data {
+        return Some(AppProtocol::Tls);
+    }
+    if data.starts_with(b"SSH-") {
+        return Some(AppProtocol::Ssh);
+    }
+    if data.starts_with(b"EHLO ") || data.starts_with(b"HELO ") {
+        return Some(AppProtocol::Smtp);
+    }
+    if data.starts_with(b"220") {
+        // SMTP and FTP share the greeting code, look for a hint in the banner
+        let banner = &data[3..];
+        if contains(banner, b"SMTP") {
+            return Some(AppProtocol::Smtp);
+        }
+        if contains(banner, b"FTP") {
+            return Some(AppProtocol::Ftp);
+        }
+    }
+    if data.starts_with(b"+OK") {
+        return Some(AppProtocol::Pop3);
+    }
+    if data.starts_with(b"* OK") {
+        return Some(AppProtocol::Imap);
+    }
+    if is_dns_message(data) {
+        return Some(AppProtocol::Dns);
+    }
+    None
+}
+
+/// guess which side sent the first bytes of a stream: `Some(true)` if they
+/// look like a client request, `Some(false)` if they look like a server
+/// response or greeting
+pub fn client_hint(data: &[u8]) -> Option<bool> {
+    if HTTP_METHODS.iter().any(|m| data.starts_with(m)) {
+        return Some(true);
+    }
+    if data.starts_with(b"HTTP/1.") {
+        return Some(false);
+    }
+    // handshake record containing ClientHello or ServerHello
+    if let [0x16, 0x03, 0..=0x04, _, _, kind, ..] = data {
+        match kind {
+            1 => return Some(true),
+            2 => return Some(false),
+            _ => {}
+        }
+    }
+    if data.starts_with(b"EHLO ") || data.starts_with(b"HELO ") {
+        return Some(true);
+    }
+    if data.starts_with(b"220") || data.starts_with(b"+OK") || data.starts_with(b"* OK") {
+        return Some(false);
+    }
+    None
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// check for a length-prefixed DNS message with a plausible header
+fn is_dns_message(data: &[u8]) -> bool {
+    if data.len() < 14 {
+        return false;
+    }
+    let length = u16::from_be_bytes([data[0], data[1]]);
+    let flags = u16::from_be_bytes([data[4], data[5]]);
+    let opcode = (flags >> 11) & 0xf;
+    let z = flags & 0x0040;
+    let qdcount = u16::from_be_bytes([data[6], data[7]]);
+    length >= 12 && opcode <= 5 && z == 0 && qdcount == 1
+}
+
+/// classification state of a connection
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Classification {
+    /// guess from well-known ports
+    pub by_port: Option<AppProtocol>,
+    /// guess from stream contents
+    pub by_payload: Option<AppProtocol>,
+    /// whether each direction (forward, reverse) has been inspected
+    pub inspected: [bool; 2],
+}
+
+impl Classification {
+    /// create new instance for flow
+    pub fn new(flow: &Flow) -> Self {
+        Classification {
+            by_port: classify_flow(flow),
+            ..Default::default()
+        }
+    }
+
+    /// best guess of application protocol
+    pub fn protocol(&self) -> Option<AppProtocol> {
+        self.by_payload.or(self.by_port)
+    }
+
+    /// inspect leading bytes of stream after new data was received
+    pub fn inspect(&mut self, direction: Direction, stream: &Stream) {
+        let idx = match direction {
+            Direction::Forward => 0,
+            Direction::Reverse => 1,
+        };
+        if self.by_payload.is_some() || self.inspected[idx] {
+            return;
+        }
+        if stream.buffer_start() > 0 {
+            // start of stream already consumed
+            self.inspected[idx] = true;
+            return;
+        }
+        let len = usize::min(stream.readable_buffered_length(), SIGNATURE_LENGTH);
+        if len == 0 {
+            return;
+        }
+        let mut head = [0u8; SIGNATURE_LENGTH];
+        for (dst, src) in head.iter_mut().zip(stream.state.buffer.iter()) {
+            *dst = *src;
+        }
+        self.by_payload = classify_payload(&head[..len]);
+        // short prefixes may still match once more data arrives
+        self.inspected[idx] = self.by_payload.is_some() || len == SIGNATURE_LENGTH;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{classify_payload, classify_port, client_hint, AppProtocol};
+
+    #[test]
+    fn signatures() {
+        let cases: &[(&[u8], Option<AppProtocol>)] = &[
+            (b"GET / HTTP/1.1\r\n", Some(AppProtocol::Http)),
+            (b"HTTP/1.1 200 OK\r\n", Some(AppProtocol::Http)),
+            (b"\x16\x03\x01\x02\x00\x01", Some(AppProtocol::Tls)),
+            (b"SSH-2.0-OpenSSH", Some(AppProtocol::Ssh)),
+            (b"220 mx ESMTP", Some(AppProtocol::Smtp)),
+            (b"220 (vsFTPd 3.0)", Some(AppProtocol::Ftp)),
+            (b"220 hello", None),
+            (b"+OK ready\r\n", Some(AppProtocol::Pop3)),
+            (b"* OK IMAP4rev1", Some(AppProtocol::Imap)),
+            (
+                b"\x00\x1d\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00",
+                Some(AppProtocol::Dns),
+            ),
+            (b"\x00\x00\x00\x00", None),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(classify_payload(data), *expected, "{data:?}");
+        }
+        assert_eq!(classify_port(8081), Some(AppProtocol::Http));
+        assert_eq!(classify_port(994), Some(AppProtocol::Tls));
+        assert_eq!(classify_port(12345), None);
+    }
+
+    #[test]
+    fn client_hints() {
+        assert_eq!(client_hint(b"POST /x HTTP/1.1"), Some(true));
+        assert_eq!(client_hint(b"HTTP/1.0 404"), Some(false));
+        assert_eq!(client_hint(b"\x16\x03\x01\x02\x00\x01\x00"), Some(true));
+        assert_eq!(client_hint(b"\x16\x03\x03\x00\x7a\x02\x00"), Some(false));
+        assert_eq!(client_hint(b"220 mx ESMTP"), Some(false));
+        assert_eq!(client_hint(b"SSH-2.0-OpenSSH"), None);
+    }
+}
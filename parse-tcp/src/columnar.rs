@@ -0,0 +1,487 @@
+//! Parquet output of segment metadata and connection summaries
+//!
+//! Records are buffered per capture hour and written as Parquet files under
+//! `segments/hour=<YYYY-MM-DDTHH>/` and `connections/hour=<YYYY-MM-DDTHH>/`
+//! (`hour=unknown` for records without capture timestamps). Stream data is
+//! not written.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, RecordBatch, StringArray, TimestampMicrosecondArray, UInt16Array,
+    UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parking_lot::Mutex;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::errors::Result as ParquetResult;
+use parquet::file::properties::WriterProperties;
+use tracing::{debug, error, info, trace};
+use uuid::Uuid;
+
+use crate::classify::AppProtocol;
+use crate::connection::{Connection, Direction};
+use crate::stats::ConnectionStats;
+use crate::stream::{SegmentInfo, SegmentType, StreamGap};
+use crate::ConnectionHandler;
+
+/// number of buffered rows per partition before a file is written
+pub const PARQUET_ROWS_PER_FILE: usize = 128 << 10;
+
+/// convert days since the unix epoch to (year, month, day)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// partition directory name for hours since the unix epoch
+pub fn hour_partition(hour: Option<u64>) -> String {
+    match hour {
+        Some(hour) => {
+            let (year, month, day) = civil_from_days((hour / 24) as i64);
+            format!("hour={year:04}-{month:02}-{day:02}T{:02}", hour % 24)
+        }
+        None => "hour=unknown".into(),
+    }
+}
+
+fn timestamp_field(name: &str) -> Field {
+    Field::new(
+        name,
+        DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+        true,
+    )
+}
+
+fn segment_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("conn_id", DataType::Utf8, false),
+        Field::new("direction", DataType::Utf8, false),
+        Field::new("type", DataType::Utf8, false),
+        Field::new("offset", DataType::UInt64, false),
+        Field::new("len", DataType::UInt64, true),
+        Field::new("is_retransmit", DataType::Boolean, true),
+        Field::new("window", DataType::UInt64, true),
+        Field::new("reverse_acked", DataType::UInt64, true),
+        Field::new("packet_index", DataType::UInt64, true),
+        timestamp_field("ts"),
+    ]))
+}
+
+fn connection_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("src_addr", DataType::Utf8, false),
+        Field::new("src_port", DataType::UInt16, false),
+        Field::new("dst_addr", DataType::Utf8, false),
+        Field::new("dst_port", DataType::UInt16, false),
+        Field::new("protocol", DataType::Utf8, true),
+        timestamp_field("start"),
+        Field::new("duration_us", DataType::UInt64, true),
+        Field::new("observed_handshake", DataType::Boolean, false),
+        Field::new("observed_close", DataType::Boolean, false),
+        Field::new("forward_packets", DataType::UInt64, false),
+        Field::new("forward_bytes", DataType::UInt64, false),
+        Field::new("reverse_packets", DataType::UInt64, false),
+        Field::new("reverse_bytes", DataType::UInt64, false),
+    ]))
+}
+
+/// buffered segment record
+pub struct SegmentRow {
+    pub conn_id: Uuid,
+    pub direction: Direction,
+    pub kind: &'static str,
+    pub offset: u64,
+    pub len: Option<u64>,
+    pub is_retransmit: Option<bool>,
+    pub window: Option<u64>,
+    pub reverse_acked: Option<u64>,
+    pub packet_index: Option<u64>,
+    pub ts_us: Option<i64>,
+}
+
+impl SegmentRow {
+    fn from_segment(conn_id: Uuid, direction: Direction, segment: &SegmentInfo) -> Self {
+        let (kind, offset, len, is_retransmit, window) = match segment.data {
+            SegmentType::Data { len, is_retransmit } => (
+                "data",
+                segment.offset,
+                Some(len as u64),
+                Some(is_retransmit),
+                None,
+            ),
+            SegmentType::Ack { window } => ("ack", segment.offset, None, None, Some(window as u64)),
+            SegmentType::Fin { end_offset } => ("fin", end_offset, None, None, None),
+            SegmentType::Rst => ("rst", segment.offset, None, None, None),
+        };
+        SegmentRow {
+            conn_id,
+            direction,
+            kind,
+            offset,
+            len,
+            is_retransmit,
+            window,
+            reverse_acked: Some(segment.reverse_acked),
+            packet_index: segment.extra.index(),
+            ts_us: segment.extra.timestamp().map(|t| t.as_micros() as i64),
+        }
+    }
+
+    /// gap row, with the packet it was declared at
+    fn from_gap(conn_id: Uuid, direction: Direction, gap: &StreamGap) -> Self {
+        let declared = gap.info.declared.unwrap_or_default();
+        SegmentRow {
+            conn_id,
+            direction,
+            kind: "gap",
+            offset: gap.range.start,
+            len: Some(gap.range.end - gap.range.start),
+            is_retransmit: None,
+            window: None,
+            reverse_acked: None,
+            packet_index: declared.index,
+            ts_us: declared.ts_us.map(|t| t as i64),
+        }
+    }
+}
+
+fn segment_batch(rows: &[SegmentRow]) -> ParquetResult<RecordBatch> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|r| r.conn_id.to_string()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|r| r.direction.to_string()),
+        )),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|r| r.kind))),
+        Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| r.offset))),
+        Arc::new(UInt64Array::from_iter(rows.iter().map(|r| r.len))),
+        Arc::new(BooleanArray::from_iter(
+            rows.iter().map(|r| r.is_retransmit),
+        )),
+        Arc::new(UInt64Array::from_iter(rows.iter().map(|r| r.window))),
+        Arc::new(UInt64Array::from_iter(rows.iter().map(|r| r.reverse_acked))),
+        Arc::new(UInt64Array::from_iter(rows.iter().map(|r| r.packet_index))),
+        Arc::new(
+            TimestampMicrosecondArray::from_iter(rows.iter().map(|r| r.ts_us)).with_timezone("UTC"),
+        ),
+    ];
+    Ok(RecordBatch::try_new(segment_schema(), columns)?)
+}
+
+/// buffered connection summary
+pub struct ConnectionRow {
+    pub stats: ConnectionStats,
+    pub protocol: Option<AppProtocol>,
+}
+
+fn connection_batch(rows: &[ConnectionRow]) -> ParquetResult<RecordBatch> {
+    let stats = || rows.iter().map(|r| &r.stats);
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            stats().map(|r| r.id.to_string()),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            stats().map(|r| r.src_addr.to_string()),
+        )),
+        Arc::new(UInt16Array::from_iter_values(stats().map(|r| r.src_port))),
+        Arc::new(StringArray::from_iter_values(
+            stats().map(|r| r.dst_addr.to_string()),
+        )),
+        Arc::new(UInt16Array::from_iter_values(stats().map(|r| r.dst_port))),
+        Arc::new(StringArray::from_iter(rows.iter().map(|r| {
+            r.protocol
+                .map(|p| serde_json::to_value(p).expect("failed to serialize protocol"))
+                .and_then(|p| p.as_str().map(str::to_owned))
+        }))),
+        Arc::new(
+            TimestampMicrosecondArray::from_iter(stats().map(|r| r.start_us.map(|t| t as i64)))
+                .with_timezone("UTC"),
+        ),
+        Arc::new(UInt64Array::from_iter(stats().map(|r| r.duration_us))),
+        Arc::new(BooleanArray::from_iter(
+            stats().map(|r| Some(r.observed_handshake)),
+        )),
+        Arc::new(BooleanArray::from_iter(
+            stats().map(|r| Some(r.observed_close)),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            stats().map(|r| r.forward.packets),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            stats().map(|r| r.forward.bytes),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            stats().map(|r| r.reverse.packets),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            stats().map(|r| r.reverse.bytes),
+        )),
+    ];
+    Ok(RecordBatch::try_new(connection_schema(), columns)?)
+}
+
+/// hour since the unix epoch of a microsecond timestamp
+fn hour_of(ts_us: Option<i64>) -> Option<u64> {
+    ts_us.map(|t| (t.max(0) / 3_600_000_000) as u64)
+}
+
+/// buffered records, keyed by capture hour
+#[derive(Default)]
+pub struct ParquetOutputState {
+    pub segments: HashMap<Option<u64>, Vec<SegmentRow>>,
+    pub connections: HashMap<Option<u64>, Vec<ConnectionRow>>,
+    /// sequence number for output file names
+    pub file_counter: u64,
+}
+
+/// shared state for ParquetOutputHandler
+pub struct ParquetOutputSharedInfoInner {
+    pub base_dir: PathBuf,
+    pub state: Mutex<ParquetOutputState>,
+}
+
+#[derive(Clone)]
+pub struct ParquetOutputSharedInfo {
+    pub inner: Arc<ParquetOutputSharedInfoInner>,
+}
+
+impl ParquetOutputSharedInfo {
+    /// create with output path
+    pub fn new(base_dir: PathBuf) -> Self {
+        ParquetOutputSharedInfo {
+            inner: Arc::new(ParquetOutputSharedInfoInner {
+                base_dir,
+                state: Mutex::new(ParquetOutputState::default()),
+            }),
+        }
+    }
+
+    /// write batch to a new file in partition directory
+    fn write_file(
+        &self,
+        state: &mut ParquetOutputState,
+        table: &str,
+        hour: Option<u64>,
+        batch: &RecordBatch,
+    ) -> ParquetResult<()> {
+        let dir = self.inner.base_dir.join(table).join(hour_partition(hour));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("part-{:06}.parquet", state.file_counter));
+        state.file_counter += 1;
+        trace!("writing {} rows to {}", batch.num_rows(), path.display());
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let mut writer = ArrowWriter::try_new(File::create(path)?, batch.schema(), Some(props))?;
+        writer.write(batch)?;
+        writer.close()?;
+        Ok(())
+    }
+
+    /// buffer segment rows, writing out full partitions
+    pub fn add_segments(&self, rows: impl IntoIterator<Item = SegmentRow>) -> ParquetResult<()> {
+        let mut state = self.inner.state.lock();
+        let mut full = Vec::new();
+        for row in rows {
+            let hour = hour_of(row.ts_us);
+            let partition = state.segments.entry(hour).or_default();
+            partition.push(row);
+            if partition.len() == PARQUET_ROWS_PER_FILE {
+                full.push(hour);
+            }
+        }
+        for hour in full {
+            let rows = state.segments.remove(&hour).unwrap_or_default();
+            self.write_file(&mut state, "segments", hour, &segment_batch(&rows)?)?;
+        }
+        Ok(())
+    }
+
+    /// buffer connection summary, writing out full partitions
+    pub fn add_connection(&self, row: ConnectionRow) -> ParquetResult<()> {
+        let mut state = self.inner.state.lock();
+        let hour = hour_of(row.stats.start_us.map(|t| t as i64));
+        let partition = state.connections.entry(hour).or_default();
+        partition.push(row);
+        if partition.len() >= PARQUET_ROWS_PER_FILE {
+            let rows = state.connections.remove(&hour).unwrap_or_default();
+            self.write_file(&mut state, "connections", hour, &connection_batch(&rows)?)?;
+        }
+        Ok(())
+    }
+
+    /// write out all buffered records
+    pub fn close(self) -> ParquetResult<()> {
+        let mut state = self.inner.state.lock();
+        let segments = std::mem::take(&mut state.segments);
+        for (hour, rows) in segments {
+            self.write_file(&mut state, "segments", hour, &segment_batch(&rows)?)?;
+        }
+        let connections = std::mem::take(&mut state.connections);
+        for (hour, rows) in connections {
+            self.write_file(&mut state, "connections", hour, &connection_batch(&rows)?)?;
+        }
+        info!("wrote {} parquet files", state.file_counter);
+        Ok(())
+    }
+}
+
+/// ConnectionHandler to write segment metadata and connection summaries as
+/// Parquet
+pub struct ParquetOutputHandler {
+    pub shared_info: ParquetOutputSharedInfo,
+    pub id: Uuid,
+    pub gaps: Vec<StreamGap>,
+    pub segments: Vec<SegmentInfo>,
+    /// whether we received the handshake_done event
+    pub got_handshake_done: bool,
+}
+
+impl ParquetOutputHandler {
+    /// record segments and gaps up to `maybe_dump_len` bytes, or everything
+    /// remaining, discarding stream data
+    pub fn write_segments(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        maybe_dump_len: Option<usize>,
+    ) -> ParquetResult<()> {
+        self.gaps.clear();
+        self.segments.clear();
+        let stream = connection.get_stream(direction);
+        let dump_len = if let Some(dump_len) = maybe_dump_len {
+            dump_len
+        } else {
+            stream.pop_segments_until(None, &mut self.segments);
+            stream.total_buffered_length()
+        };
+        if dump_len > 0 {
+            let end_offset = stream.buffer_start() + dump_len as u64;
+            let read = stream.read_next(end_offset, &mut self.segments, &mut self.gaps, |_| ());
+            if read.is_none() {
+                error!("parquet: {direction} stream cannot fulfill range, skipping");
+                return Ok(());
+            }
+        }
+        let id = self.id;
+        let gaps = self
+            .gaps
+            .iter()
+            .map(|gap| SegmentRow::from_gap(id, direction, gap));
+        let segments = self
+            .segments
+            .iter()
+            .map(|segment| SegmentRow::from_segment(id, direction, segment));
+        self.shared_info.add_segments(gaps.chain(segments))
+    }
+}
+
+fn log_error(result: ParquetResult<()>, what: &str) {
+    if let Err(e) = result {
+        error!("{what}: {e:?}");
+    }
+}
+
+impl ConnectionHandler for ParquetOutputHandler {
+    type InitialData = ParquetOutputSharedInfo;
+    type ConstructError = eyre::Report;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> eyre::Result<Self> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(ParquetOutputHandler {
+            shared_info,
+            id: connection.uuid,
+            gaps: Vec::new(),
+            segments: Vec::new(),
+            got_handshake_done: false,
+        })
+    }
+
+    fn handshake_done(&mut self, _connection: &mut Connection<Self>) {
+        self.got_handshake_done = true;
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let config = connection.config.clone();
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        if readable_len > config.flush_readable_threshold
+            || stream.segments_info.len() > config.flush_segments_threshold
+        {
+            log_error(
+                self.write_segments(connection, direction, Some(readable_len)),
+                "failed to write segments",
+            );
+        } else if stream.total_buffered_length() > config.flush_total_threshold {
+            log_error(
+                self.write_segments(connection, direction, Some(config.flush_total_advance)),
+                "failed to write segments",
+            );
+        }
+    }
+
+    fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        log_error(
+            self.write_segments(connection, direction, None),
+            "failed to write segments",
+        );
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        if !self.got_handshake_done {
+            // nothing to write if no data
+            return;
+        }
+        for direction in [Direction::Forward, Direction::Reverse] {
+            log_error(
+                self.write_segments(connection, direction, None),
+                "failed to write final segments",
+            );
+        }
+        let row = ConnectionRow {
+            stats: ConnectionStats::from_connection(connection),
+            protocol: connection.classification.protocol(),
+        };
+        log_error(
+            self.shared_info.add_connection(row),
+            "failed to write connection summary",
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::hour_partition;
+
+    #[test]
+    fn partition_names() {
+        assert_eq!(hour_partition(Some(0)), "hour=1970-01-01T00");
+        // 2024-02-29 13:00 UTC
+        assert_eq!(
+            hour_partition(Some(1709211600 / 3600)),
+            "hour=2024-02-29T13"
+        );
+        assert_eq!(hour_partition(None), "hour=unknown");
+    }
+}
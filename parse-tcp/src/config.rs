@@ -0,0 +1,409 @@
+use std::sync::Arc;
+
+use kinesin_rdt::common::buffer_pool::BufferPool;
+use kinesin_rdt::stream::inbound::OverlapPolicy;
+
+use crate::direction::DIRECTION_INFERENCE_PACKETS;
+use crate::filter::FilterExpr;
+use crate::flow_table::Flow;
+use crate::handler::{
+    BUFFER_READABLE_THRESHOLD, BUFFER_SEGMENTS_THRESHOLD, BUFFER_TOTAL_THRESHOLD,
+    BUFFER_TOTAL_THRESHOLD_ADVANCE,
+};
+use crate::matching::PatternSet;
+use crate::memory::MemoryBudget;
+use crate::metrics::Metrics;
+use crate::resync::RESYNC_PACKETS;
+use crate::stream::{
+    GAP_WAIT_BYTES, GAP_WAIT_PACKETS, LATE_DATA_BYTES, LATE_DATA_PACKETS, MAX_ALLOWED_BUFFER_SIZE,
+    MAX_SEGMENTS_INFO_COUNT, PAWS_MAX_REGRESSION, RESET_MAX_LOOKAHEAD, RESET_MAX_LOOKBEHIND,
+    SEQ_WINDOW_ADVANCE_BY, SEQ_WINDOW_ADVANCE_THRESHOLD, SEQ_WINDOW_SIZE,
+};
+use crate::ttl::TTL_ANOMALY_THRESHOLD;
+
+/// tunable limits for stream reassembly, shared by all connections of a
+/// flowtable
+#[derive(Clone, Debug)]
+pub struct ReassemblyConfig {
+    /// size of the sequence number sliding window
+    pub seq_window_size: u32,
+    /// threshold for advancing the sequence number window
+    pub seq_window_advance_threshold: u32,
+    /// how much to advance the sequence number window by
+    pub seq_window_advance_by: u32,
+    /// max allowed size of stream buffer
+    pub max_buffer_size: u64,
+    /// max number of segment info objects held per stream
+    pub max_segments_info: usize,
+    /// how far forward to allow reset packets
+    pub reset_max_lookahead: u32,
+    /// how far back to allow reset packets
+    pub reset_max_lookbehind: u32,
+    /// data packets received past missing data before it is declared a gap
+    pub gap_wait_packets: u32,
+    /// bytes buffered past missing data before it is declared a gap
+    pub gap_wait_bytes: u64,
+    /// packets received after a gap was read past during which late data for
+    /// it is still accepted, 0 to disable
+    pub late_data_packets: u32,
+    /// how far behind the read offset a gap may be while still accepting
+    /// late data
+    pub late_data_bytes: u64,
+    /// how far TSval may fall behind the highest accepted TSval of a stream
+    /// before segments are rejected, 0 to disable
+    pub paws_max_regression: u32,
+    /// shared pool to allocate stream buffers from, if any
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    /// budget for bytes buffered by all streams, if any
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// counters for monitoring, if any
+    pub metrics: Option<Arc<Metrics>>,
+    /// packets to collect direction evidence for when the handshake was not
+    /// observed, 0 to always treat the first sender as the client
+    pub direction_inference_packets: u32,
+    /// handling of retransmitted data differing from data received earlier
+    pub overlap_policy: OverlapPolicy,
+    /// overlap policies for flows matching a filter, the first matching rule
+    /// overrides `overlap_policy`
+    pub overlap_policy_rules: Vec<(FilterExpr, OverlapPolicy)>,
+    /// patterns to search reassembled streams for, if any
+    pub patterns: Option<Arc<PatternSet>>,
+    /// difference from the most common TTL of a direction reported as an
+    /// anomaly
+    pub ttl_anomaly_threshold: u8,
+    /// packets to observe after a SYN for an established connection before
+    /// deciding whether to keep, rebase or recreate it, 0 to always recreate
+    pub resync_packets: u32,
+    /// whether connections keep a journal of state transitions
+    pub record_transitions: bool,
+
+    /// readable bytes buffered before handlers write out
+    pub flush_readable_threshold: usize,
+    /// segment info objects buffered before handlers write out
+    pub flush_segments_threshold: usize,
+    /// total bytes buffered (including gaps) before handlers write out
+    pub flush_total_threshold: usize,
+    /// how many bytes to write out when hitting `flush_total_threshold`
+    pub flush_total_advance: usize,
+}
+
+impl ReassemblyConfig {
+    /// check that parameters are consistent, returning a description of the
+    /// problem otherwise
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.seq_window_size > 1 << 31 {
+            return Err("sequence window size must be at most 2^31");
+        }
+        if self.seq_window_advance_threshold >= self.seq_window_size {
+            return Err("sequence window advance threshold must be less than window size");
+        }
+        if self.seq_window_advance_by > self.seq_window_advance_threshold {
+            return Err("sequence window advance amount must not exceed advance threshold");
+        }
+        if self.max_buffer_size == 0 {
+            return Err("max buffer size must not be zero");
+        }
+        if self.flush_total_advance == 0 {
+            return Err("flush advance amount must not be zero");
+        }
+        if self.flush_total_advance > self.flush_total_threshold {
+            return Err("flush advance amount must not exceed flush total threshold");
+        }
+        if self.flush_total_threshold as u64 >= self.max_buffer_size {
+            return Err("flush total threshold must be less than max buffer size");
+        }
+        Ok(())
+    }
+
+    /// overlap policy for streams of a new connection with flow
+    pub fn overlap_policy_for(&self, flow: &Flow) -> OverlapPolicy {
+        self.overlap_policy_rules
+            .iter()
+            .find(|(filter, _)| filter.matches(flow))
+            .map_or(self.overlap_policy, |(_, policy)| *policy)
+    }
+}
+
+impl Default for ReassemblyConfig {
+    fn default() -> Self {
+        ReassemblyConfig {
+            seq_window_size: SEQ_WINDOW_SIZE,
+            seq_window_advance_threshold: SEQ_WINDOW_ADVANCE_THRESHOLD,
+            seq_window_advance_by: SEQ_WINDOW_ADVANCE_BY,
+            max_buffer_size: MAX_ALLOWED_BUFFER_SIZE,
+            max_segments_info: MAX_SEGMENTS_INFO_COUNT,
+            reset_max_lookahead: RESET_MAX_LOOKAHEAD,
+            reset_max_lookbehind: RESET_MAX_LOOKBEHIND,
+            gap_wait_packets: GAP_WAIT_PACKETS,
+            gap_wait_bytes: GAP_WAIT_BYTES,
+            late_data_packets: LATE_DATA_PACKETS,
+            late_data_bytes: LATE_DATA_BYTES,
+            paws_max_regression: PAWS_MAX_REGRESSION,
+            buffer_pool: None,
+            memory_budget: None,
+            metrics: None,
+            direction_inference_packets: DIRECTION_INFERENCE_PACKETS,
+            overlap_policy: OverlapPolicy::FirstWins,
+            overlap_policy_rules: Vec::new(),
+            patterns: None,
+            ttl_anomaly_threshold: TTL_ANOMALY_THRESHOLD,
+            resync_packets: RESYNC_PACKETS,
+            record_transitions: false,
+            flush_readable_threshold: BUFFER_READABLE_THRESHOLD,
+            flush_segments_threshold: BUFFER_SEGMENTS_THRESHOLD,
+            flush_total_threshold: BUFFER_TOTAL_THRESHOLD,
+            flush_total_advance: BUFFER_TOTAL_THRESHOLD_ADVANCE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use kinesin_rdt::stream::inbound::OverlapPolicy;
+
+    use super::ReassemblyConfig;
+    use crate::flow_table::{Flow, IPPROTO_TCP};
+    use crate::serialized::{GapCause, GapInfo, PacketExtra, PacketRef, SerializedSegment};
+    use crate::stream::{SegmentInfo, SegmentType, Stream, StreamGap};
+
+    #[test]
+    fn validate() {
+        assert!(ReassemblyConfig::default().validate().is_ok());
+        let config = ReassemblyConfig {
+            seq_window_advance_by: 1 << 20,
+            seq_window_advance_threshold: 1 << 10,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_flush_advance() {
+        let config = ReassemblyConfig {
+            flush_total_threshold: 1 << 10,
+            flush_total_advance: 1 << 12,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_flush_threshold() {
+        let config = ReassemblyConfig {
+            max_buffer_size: 1 << 16,
+            flush_total_threshold: 1 << 16,
+            flush_total_advance: 1 << 10,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn overlap_policy_rules() {
+        let config = ReassemblyConfig {
+            overlap_policy_rules: vec![
+                ("dst host 10.0.0.5".parse().unwrap(), OverlapPolicy::Windows),
+                ("port 80".parse().unwrap(), OverlapPolicy::Linux),
+            ],
+            ..Default::default()
+        };
+        let mut flow = Flow {
+            proto: IPPROTO_TCP,
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 40000,
+            dst_addr: [10, 0, 0, 5].into(),
+            dst_port: 80,
+        };
+        assert_eq!(config.overlap_policy_for(&flow), OverlapPolicy::Windows);
+        flow.dst_addr = [10, 0, 0, 6].into();
+        assert_eq!(config.overlap_policy_for(&flow), OverlapPolicy::Linux);
+        flow.dst_port = 443;
+        assert_eq!(config.overlap_policy_for(&flow), OverlapPolicy::FirstWins);
+    }
+
+    #[test]
+    fn segments_info_limit() {
+        let config = ReassemblyConfig {
+            max_segments_info: 1,
+            ..Default::default()
+        };
+        let mut stream = Stream::new(Arc::new(config));
+        let info = || SegmentInfo {
+            offset: 0,
+            reverse_acked: 0,
+            extra: PacketExtra::None,
+            tcp: Default::default(),
+            data: SegmentType::Rst,
+        };
+        assert!(stream.add_segment_info(info()));
+        assert!(!stream.add_segment_info(info()));
+        assert_eq!(stream.segments_info_dropped, 1);
+    }
+
+    #[test]
+    fn gap_detection() {
+        let config = ReassemblyConfig {
+            gap_wait_packets: 2,
+            gap_wait_bytes: 100,
+            ..Default::default()
+        };
+        let mut stream = Stream::new(Arc::new(config));
+        stream.set_isn(1000, 0);
+        stream.state.set_limit(1 << 20);
+        let extra = PacketExtra::None;
+        assert!(stream.handle_data_packet(1000, b"hello", &extra, Default::default()));
+        assert_eq!(stream.detect_gap(), None);
+        // 5 bytes missing, declared after the second packet past them
+        assert!(stream.handle_data_packet(1010, b"world", &extra, Default::default()));
+        assert_eq!(stream.detect_gap(), None);
+        assert!(stream.handle_data_packet(1015, b"!", &extra, Default::default()));
+        assert_eq!(stream.detect_gap(), Some(5..10));
+        assert!(stream.handle_data_packet(1016, b"?", &extra, Default::default()));
+        assert_eq!(stream.detect_gap(), None);
+        // enough data past a new gap declares it immediately
+        assert!(stream.handle_data_packet(1027, &[0; 100], &extra, Default::default()));
+        assert_eq!(stream.detect_gap(), Some(17..27));
+    }
+
+    #[test]
+    fn gap_annotation() {
+        let config = ReassemblyConfig {
+            gap_wait_packets: 2,
+            ..Default::default()
+        };
+        let mut stream = Stream::new(Arc::new(config));
+        stream.set_isn(1000, 0);
+        stream.state.set_limit(1 << 20);
+        let packet = |index: u64| PacketExtra::LegacyPcap {
+            index,
+            ts_sec: 100 + index as u32,
+            ts_usec: 0,
+            vlan_id: None,
+            ttl: None,
+            bad_checksum: false,
+            frames: None,
+        };
+        let packet_ref = |index: u64| packet(index).packet_ref();
+        let mut receive = |index: u64, seq: u32, data: &[u8]| {
+            assert!(stream.handle_data_packet(seq, data, &packet(index), Default::default()));
+            stream.detect_gap()
+        };
+        assert_eq!(receive(0, 1000, b"hello"), None);
+        assert_eq!(receive(1, 1010, b"world"), None);
+        assert_eq!(receive(2, 1015, b"!"), Some(5..10));
+        // read past before it is declared
+        assert_eq!(receive(3, 1020, b"abc"), None);
+
+        let (mut segments, mut gaps) = (Vec::new(), Vec::new());
+        stream.read_next(23, &mut segments, &mut gaps, |_| ());
+        assert_eq!(
+            gaps,
+            [
+                StreamGap {
+                    range: 5..10,
+                    info: GapInfo {
+                        cause: Some(GapCause::Loss),
+                        before: packet_ref(0),
+                        after: packet_ref(1),
+                        declared: packet_ref(2),
+                        packets_skipped: 2,
+                    },
+                },
+                StreamGap {
+                    range: 16..20,
+                    info: GapInfo {
+                        cause: Some(GapCause::BufferOverrun),
+                        before: packet_ref(2),
+                        after: packet_ref(3),
+                        declared: packet_ref(3),
+                        packets_skipped: 1,
+                    },
+                },
+            ]
+        );
+        assert_eq!(stream.gaps_length, 9);
+
+        // missing data is lost once the connection ends
+        assert!(stream.handle_data_packet(1030, b"?", &packet(4), Default::default()));
+        stream.declare_remaining_gaps();
+        gaps.clear();
+        stream.read_next(31, &mut segments, &mut gaps, |_| ());
+        assert_eq!(gaps[0].range, 23..30);
+        assert_eq!(gaps[0].info.cause, Some(GapCause::Loss));
+        assert_eq!(
+            serde_json::to_string(&SerializedSegment::from(&gaps[0])).unwrap(),
+            r#"{"type":"gap","offset":23,"len":7,"cause":"loss","declared":{"index":4,"ts_us":104000000}}"#
+        );
+        assert_eq!(
+            gaps[0].info.declared,
+            Some(PacketRef {
+                index: Some(4),
+                ts_us: Some(104_000_000),
+            })
+        );
+    }
+
+    #[test]
+    fn late_data() {
+        let config = ReassemblyConfig {
+            gap_wait_packets: 1,
+            late_data_packets: 2,
+            ..Default::default()
+        };
+        let mut stream = Stream::new(Arc::new(config));
+        stream.set_isn(1000, 0);
+        stream.state.set_limit(1 << 20);
+        let mut receive = |seq: u32, data: &[u8]| {
+            stream.packet_count += 1;
+            stream.handle_data_packet(seq, data, &PacketExtra::None, Default::default());
+            stream.detect_gap();
+        };
+        receive(1000, b"ab");
+        receive(1010, b"cd");
+        receive(1020, b"ef");
+        let (mut segments, mut gaps) = (Vec::new(), Vec::new());
+        stream.read_next(22, &mut segments, &mut gaps, |_| ());
+        assert_eq!(gaps.len(), 2);
+
+        // retransmission covering parts of both gaps and the data between
+        stream.handle_data_packet(1005, b"12345678", &PacketExtra::None, Default::default());
+        let late: Vec<_> = stream
+            .late_data
+            .drain(..)
+            .map(|late| (late.offset, late.data))
+            .collect();
+        assert_eq!(late, [(5, b"12345".to_vec()), (12, b"8".to_vec())]);
+        assert_eq!(stream.skipped_gaps[0].range, 2..5);
+        assert_eq!(stream.skipped_gaps[1].range, 13..20);
+        assert_eq!(stream.late_data_bytes, 6);
+
+        // no longer accepted after late_data_packets
+        stream.packet_count += 3;
+        stream.handle_data_packet(1002, b"xyz", &PacketExtra::None, Default::default());
+        assert!(stream.late_data.is_empty());
+        assert!(stream.skipped_gaps.is_empty());
+    }
+
+    #[test]
+    fn read_gaps_without_consuming() {
+        let config = ReassemblyConfig {
+            late_data_packets: 2,
+            ..Default::default()
+        };
+        let mut stream = Stream::new(Arc::new(config));
+        stream.set_isn(1000, 0);
+        stream.state.set_limit(1 << 20);
+        stream.handle_data_packet(1000, b"ab", &PacketExtra::None, Default::default());
+        stream.handle_data_packet(1010, b"cd", &PacketExtra::None, Default::default());
+
+        // gaps may be read again before the buffer is consumed past them
+        let mut gaps = Vec::new();
+        stream.read_gaps_until(12, &mut gaps);
+        stream.read_gaps_until(12, &mut gaps);
+        assert_eq!(gaps.len(), 2);
+        assert_eq!(gaps[0].range, 2..10);
+        assert_eq!(stream.skipped_gaps[0].range, 2..10);
+    }
+}
@@ -1,16 +1,30 @@
 use std::fmt::Display;
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
 
+use kinesin_rdt::stream::inbound::OverlapPolicy;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, info_span, trace, warn};
 use uuid::Uuid;
 
+use crate::classify::Classification;
+use crate::config::ReassemblyConfig;
+use crate::direction::DirectionInference;
 use crate::flow_table::{Flow, FlowCompare};
+use crate::handshake::{HandshakeEvent, HandshakeInfo, SynPayload};
+use crate::log_throttle::{warn_throttled, WarnCategory};
+use crate::matching::StreamScanner;
+use crate::resync::{HeldPacket, Resync, ResyncOutcome};
+use crate::rtt::RttEstimator;
 use crate::serialized::PacketExtra;
-use crate::stream::{in_range_wrapping, Stream, RESET_MAX_LOOKAHEAD};
+use crate::stream::{in_range_wrapping, SegmentTcpInfo, Stream};
+use crate::ttl::TtlTracker;
 use crate::ConnectionHandler;
 use crate::TcpMeta;
 
 /// TCP handshake state
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ConnectionState {
     /// not yet initialized
     None,
@@ -30,6 +44,18 @@ pub enum ConnectionState {
         /// whether or not we saw the first SYN
         syn_seen: bool,
     },
+    /// SYN read in both directions (simultaneous open), expect SYN/ACK from
+    /// both sides
+    SimultaneousOpen {
+        /// sequence number of forward SYN
+        forward_seq: u32,
+        /// sequence number of reverse SYN
+        reverse_seq: u32,
+        /// window size of reverse SYN/ACK, once seen
+        forward_window: Option<u16>,
+        /// window size of forward SYN/ACK, once seen
+        reverse_window: Option<u16>,
+    },
     /// handshake complete, connection established
     Established {
         /// initial sequence number of forward direction
@@ -43,8 +69,63 @@ pub enum ConnectionState {
     Desync,
 }
 
+/// why a connection or flow was retired
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CloseReason {
+    /// both directions ended with an acknowledged FIN
+    Fin,
+    /// reset by either side
+    Rst,
+    /// no packets seen within the idle timeout
+    Timeout,
+    /// capture ended while still open
+    Eof,
+    /// connection desynchronized and was replaced
+    Desync,
+    /// port pair reused by a new connection
+    Reused,
+}
+
+/// cause of a connection state transition
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionReason {
+    /// SYN opening the connection
+    Syn,
+    /// SYN/ACK, with or without a preceding SYN
+    SynAck,
+    /// first packet after SYN/ACK
+    Ack,
+    /// packet without SYN before the handshake was complete
+    Midstream,
+    /// FIN of the second direction acknowledged
+    Fin,
+    /// reset by either side
+    Rst,
+    /// SYN received for established connection
+    UnexpectedSyn,
+    /// streams continued in a new sequence numbering after an unexpected SYN
+    Resync,
+}
+
+/// entry of the connection state journal
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StateTransition {
+    pub from: ConnectionState,
+    pub to: ConnectionState,
+    pub reason: TransitionReason,
+    /// index in capture of packet causing the transition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packet_index: Option<u64>,
+    /// capture time of packet (microseconds since epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_us: Option<u64>,
+}
+
 /// packet direction
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Direction {
     /// forward direction: client -> server, assuming client is whoever sent the
     /// first SYN
@@ -81,17 +162,54 @@ pub struct Connection<H: ConnectionHandler> {
     pub forward_flow: Flow,
     /// state of connection handshake
     pub conn_state: ConnectionState,
+    /// reassembly limits
+    pub config: Arc<ReassemblyConfig>,
 
     /// whether the full 3-way handshake was observed
     pub observed_handshake: bool,
     /// whether the connection close was observed (either by FIN or RST)
     pub observed_close: bool,
+    /// why the connection was closed, if it was
+    pub close_reason: Option<CloseReason>,
+    /// state changes, if enabled by `ReassemblyConfig::record_transitions`
+    pub transitions: Vec<StateTransition>,
+    /// capture index of the packet being handled, if known
+    pub current_packet_index: Option<u64>,
 
     /// forward direction stream
     pub forward_stream: Stream,
     /// reverse direction stream
     pub reverse_stream: Stream,
 
+    /// RTT estimate for segments sent in forward direction
+    pub forward_rtt: RttEstimator,
+    /// RTT estimate for segments sent in reverse direction
+    pub reverse_rtt: RttEstimator,
+    /// TTLs of packets sent in forward direction
+    pub forward_ttl: TtlTracker,
+    /// TTLs of packets sent in reverse direction
+    pub reverse_ttl: TtlTracker,
+
+    /// capture time of first packet, if known
+    pub first_packet_time: Option<Duration>,
+    /// capture time of most recent packet, if known
+    pub last_packet_time: Option<Duration>,
+
+    /// guessed application protocol
+    pub classification: Classification,
+    /// pattern scan state (forward, reverse), see `crate::matching`
+    pub scanners: [StreamScanner; 2],
+    /// evidence for direction if the handshake was not observed
+    pub direction_inference: DirectionInference,
+    /// handshake counts and timing
+    pub handshake: HandshakeInfo,
+    /// resynchronization in progress after an unexpected SYN, see
+    /// `crate::resync`
+    pub resync: Option<Resync>,
+    /// packets to be handled by the connection replacing this one after it
+    /// desynchronized
+    pub desync_packets: Vec<HeldPacket>,
+
     /// event handler object
     pub event_handler: Option<Box<H>>,
 }
@@ -108,19 +226,86 @@ impl<H: ConnectionHandler> Connection<H> {
         forward_flow: Flow,
         handler_init_data: H::InitialData,
     ) -> Result<Connection<H>, H::ConstructError> {
+        Self::with_config(forward_flow, Arc::default(), handler_init_data)
+    }
+
+    /// create new connection with flow and reassembly limits
+    pub fn with_config(
+        forward_flow: Flow,
+        config: Arc<ReassemblyConfig>,
+        handler_init_data: H::InitialData,
+    ) -> Result<Connection<H>, H::ConstructError> {
+        let mut conn = Self::without_handler(forward_flow, config);
+        let handler = H::new(handler_init_data, &mut conn)?;
+        conn.event_handler = Some(Box::new(handler));
+        Ok(conn)
+    }
+
+    /// create new connection without constructing an event handler
+    pub fn without_handler(forward_flow: Flow, config: Arc<ReassemblyConfig>) -> Connection<H> {
+        let overlap_policy = config.overlap_policy_for(&forward_flow);
         let mut conn = Connection {
             uuid: Uuid::new_v4(),
+            classification: Classification::new(&forward_flow),
+            scanners: Default::default(),
+            direction_inference: DirectionInference::default(),
+            handshake: HandshakeInfo::default(),
+            resync: None,
+            desync_packets: Vec::new(),
             forward_flow,
             conn_state: ConnectionState::None,
+            config: config.clone(),
             observed_handshake: false,
             observed_close: false,
-            forward_stream: Stream::new(),
-            reverse_stream: Stream::new(),
+            close_reason: None,
+            transitions: Vec::new(),
+            current_packet_index: None,
+            forward_stream: Stream::new(config.clone()),
+            reverse_stream: Stream::new(config),
+            forward_rtt: RttEstimator::new(),
+            reverse_rtt: RttEstimator::new(),
+            forward_ttl: TtlTracker::new(),
+            reverse_ttl: TtlTracker::new(),
+            first_packet_time: None,
+            last_packet_time: None,
             event_handler: None,
         };
-        let handler = H::new(handler_init_data, &mut conn)?;
-        conn.event_handler = Some(Box::new(handler));
-        Ok(conn)
+        conn.set_overlap_policy(overlap_policy);
+        conn
+    }
+
+    /// exchange all state except the event handler with a connection of
+    /// another handler type
+    pub fn swap_state<O: ConnectionHandler>(&mut self, other: &mut Connection<O>) {
+        mem::swap(&mut self.uuid, &mut other.uuid);
+        mem::swap(&mut self.forward_flow, &mut other.forward_flow);
+        mem::swap(&mut self.conn_state, &mut other.conn_state);
+        mem::swap(&mut self.config, &mut other.config);
+        mem::swap(&mut self.observed_handshake, &mut other.observed_handshake);
+        mem::swap(&mut self.observed_close, &mut other.observed_close);
+        mem::swap(&mut self.close_reason, &mut other.close_reason);
+        mem::swap(&mut self.transitions, &mut other.transitions);
+        mem::swap(
+            &mut self.current_packet_index,
+            &mut other.current_packet_index,
+        );
+        mem::swap(&mut self.forward_stream, &mut other.forward_stream);
+        mem::swap(&mut self.reverse_stream, &mut other.reverse_stream);
+        mem::swap(&mut self.forward_rtt, &mut other.forward_rtt);
+        mem::swap(&mut self.reverse_rtt, &mut other.reverse_rtt);
+        mem::swap(&mut self.forward_ttl, &mut other.forward_ttl);
+        mem::swap(&mut self.reverse_ttl, &mut other.reverse_ttl);
+        mem::swap(&mut self.first_packet_time, &mut other.first_packet_time);
+        mem::swap(&mut self.last_packet_time, &mut other.last_packet_time);
+        mem::swap(&mut self.classification, &mut other.classification);
+        mem::swap(&mut self.scanners, &mut other.scanners);
+        mem::swap(
+            &mut self.direction_inference,
+            &mut other.direction_inference,
+        );
+        mem::swap(&mut self.handshake, &mut other.handshake);
+        mem::swap(&mut self.resync, &mut other.resync);
+        mem::swap(&mut self.desync_packets, &mut other.desync_packets);
     }
 
     /// get stream in direction
@@ -131,37 +316,400 @@ impl<H: ConnectionHandler> Connection<H> {
         }
     }
 
+    /// set handling of conflicting retransmissions for both streams, e.g. to
+    /// mimic the operating system of the receiving host
+    pub fn set_overlap_policy(&mut self, policy: OverlapPolicy) {
+        self.forward_stream.state.overlap_policy = policy;
+        self.reverse_stream.state.overlap_policy = policy;
+    }
+
+    /// get RTT estimator for segments sent in direction
+    pub fn get_rtt(&mut self, direction: Direction) -> &mut RttEstimator {
+        match direction {
+            Direction::Forward => &mut self.forward_rtt,
+            Direction::Reverse => &mut self.reverse_rtt,
+        }
+    }
+
+    /// get TTL tracker for packets sent in direction
+    pub fn get_ttl(&mut self, direction: Direction) -> &mut TtlTracker {
+        match direction {
+            Direction::Forward => &mut self.forward_ttl,
+            Direction::Reverse => &mut self.reverse_ttl,
+        }
+    }
+
     /// handle a packet supposedly belonging to this connection
-    #[tracing::instrument(name = "conn", skip_all, fields(id = %self.uuid))]
+    #[tracing::instrument(name = "conn", level = "debug", skip_all, fields(id = %self.uuid))]
     pub fn handle_packet(&mut self, meta: &TcpMeta, data: &[u8], extra: &PacketExtra) -> bool {
         debug_assert_ne!(self.forward_flow.compare_tcp_meta(meta), FlowCompare::None);
-        if meta.flags.syn {
-            self.handle_syn(meta)
+        if self.resync.is_some() {
+            // handled once resolved
+            return self.handle_resync(meta, data, extra);
+        }
+        // update before handling so handlers see the time of the current packet
+        let now = extra.timestamp();
+        if let Some(now) = now {
+            self.first_packet_time.get_or_insert(now);
+            self.last_packet_time = Some(now);
+        }
+        self.current_packet_index = extra.index();
+        // packet starting inference is observed during handling
+        let inferring = self.direction_inference.active;
+        let did_something = if meta.flags.syn {
+            self.handle_syn(meta, data, extra)
         } else if meta.flags.rst {
             self.handle_rst(meta, extra)
         } else {
             // FIN packets handled here too, as they may carry data
             self.handle_data(meta, data, extra)
+        };
+
+        // direction may only be known after handling (forward_flow may be reversed)
+        let dir = match self.forward_flow.compare_tcp_meta(meta) {
+            FlowCompare::Forward => Direction::Forward,
+            _ => Direction::Reverse,
+        };
+        self.get_stream(dir).packet_count += 1;
+        if let Some(now) = now {
+            let stream = self.get_stream(dir);
+            stream.first_packet_time.get_or_insert(now);
+            stream.last_packet_time = Some(now);
+            self.get_rtt(dir).on_segment_sent(meta, data.len(), now);
+            self.get_rtt(dir.swap()).on_ack_received(meta, now);
+        }
+        let dir = if inferring {
+            self.infer_direction(dir, meta, data)
+        } else {
+            dir
+        };
+        if let Some(ttl) = extra.ttl() {
+            let threshold = self.config.ttl_anomaly_threshold;
+            if let Some(expected) = self.get_ttl(dir).record(ttl, threshold) {
+                debug!("{dir} packet has TTL {ttl}, expected around {expected}");
+                self.call_handler(|conn, h| h.ttl_anomaly(conn, dir, expected, ttl));
+            }
+        }
+        self.call_handler(|conn, h| h.packet_received(conn, dir, extra));
+        did_something
+    }
+
+    /// collect direction evidence from packet, reversing the connection if
+    /// the client was misidentified, returns the updated packet direction
+    pub fn infer_direction(&mut self, dir: Direction, meta: &TcpMeta, data: &[u8]) -> Direction {
+        let inference = &mut self.direction_inference;
+        inference.observe(dir, meta, data);
+        if inference.packets >= self.config.direction_inference_packets {
+            trace!("direction inference finished");
+            inference.active = false;
+        }
+        if !matches!(self.conn_state, ConnectionState::Established { .. }) {
+            return dir;
+        }
+        if self.direction_inference.should_reverse(
+            &self.forward_flow,
+            self.forward_stream.data_bytes,
+            self.reverse_stream.data_bytes,
+        ) {
+            self.reverse_direction();
+            dir.swap()
+        } else {
+            dir
+        }
+    }
+
+    /// swap forward and reverse direction after the client was misidentified,
+    /// notifying the handler
+    pub fn reverse_direction(&mut self) {
+        self.forward_flow.reverse();
+        debug!(
+            "revising direction, forward flow is now {}",
+            self.forward_flow
+        );
+        mem::swap(&mut self.forward_stream, &mut self.reverse_stream);
+        mem::swap(&mut self.forward_rtt, &mut self.reverse_rtt);
+        mem::swap(&mut self.forward_ttl, &mut self.reverse_ttl);
+        if let ConnectionState::Established {
+            forward_isn,
+            reverse_isn,
+        } = &mut self.conn_state
+        {
+            mem::swap(forward_isn, reverse_isn);
+        }
+        self.classification.inspected.swap(0, 1);
+        self.scanners.swap(0, 1);
+        self.direction_inference.reversed();
+        self.direction_inference.revisions += 1;
+        self.call_handler(|conn, h| h.direction_changed(conn));
+    }
+
+    /// hold back packet while resynchronizing, resolving once enough packets
+    /// were observed
+    fn handle_resync(&mut self, meta: &TcpMeta, data: &[u8], extra: &PacketExtra) -> bool {
+        let dir = match self.forward_flow.compare_tcp_meta(meta) {
+            FlowCompare::Forward => Direction::Forward,
+            _ => Direction::Reverse,
+        };
+        let current = self.get_stream(dir).is_near_position(meta.seq_number);
+        let resync = self.resync.as_mut().expect("not resynchronizing");
+        resync.observe(dir, meta, data, extra, current);
+        match resync.outcome(self.config.resync_packets) {
+            Some(outcome) => self.finish_resync(outcome),
+            None => true,
+        }
+    }
+
+    /// apply outcome of resynchronization, then handle held packets unless
+    /// the connection is to be recreated
+    pub fn finish_resync(&mut self, outcome: ResyncOutcome) -> bool {
+        let Some(resync) = self.resync.take() else {
+            return false;
+        };
+        let dir = resync.syn_direction;
+        debug!(
+            "resync: {outcome:?} after {} packets ({} current, {} new)",
+            resync.observed, resync.current_packets, resync.new_packets
+        );
+        match outcome {
+            ResyncOutcome::Kept => {}
+            ResyncOutcome::Rebased => {
+                let ConnectionState::Established {
+                    mut forward_isn,
+                    mut reverse_isn,
+                } = self.conn_state
+                else {
+                    unreachable!("resynchronizing connection not established");
+                };
+                if let Some(numbering) = resync.numbering[0] {
+                    self.forward_stream.rebase(numbering.base);
+                    forward_isn = numbering.base;
+                }
+                if let Some(numbering) = resync.numbering[1] {
+                    self.reverse_stream.rebase(numbering.base);
+                    reverse_isn = numbering.base;
+                }
+                self.set_state(
+                    ConnectionState::Established {
+                        forward_isn,
+                        reverse_isn,
+                    },
+                    TransitionReason::Resync,
+                );
+            }
+            ResyncOutcome::Recreated => {
+                self.set_state(ConnectionState::Desync, TransitionReason::UnexpectedSyn);
+                self.close_reason = Some(CloseReason::Desync);
+                self.desync_packets = resync.held;
+                self.call_handler(|conn, h| h.connection_desync(conn, dir));
+                return false;
+            }
+        }
+        self.call_handler(|conn, h| h.connection_resync(conn, dir, outcome));
+        let mut did_something = false;
+        for packet in resync.held {
+            if !packet.meta.flags.syn {
+                did_something |= self.handle_packet(&packet.meta, &packet.data, &packet.extra);
+            }
+        }
+        did_something
+    }
+
+    /// report handshake event for packet to handler
+    fn handshake_event(&mut self, meta: &TcpMeta, event: HandshakeEvent) {
+        let dir = self
+            .forward_flow
+            .compare_tcp_meta(meta)
+            .to_direction()
+            .expect("got unrelated flow");
+        self.call_handler(|conn, h| h.handshake_event(conn, dir, event));
+    }
+
+    /// whether packet is a SYN opening a new connection on the same port pair
+    /// (its sequence number is far from the current position of its stream),
+    /// rather than belonging to this connection
+    pub fn is_reused_by(&mut self, meta: &TcpMeta) -> bool {
+        if !meta.flags.syn || meta.flags.ack {
+            return false;
+        }
+        let Some(dir) = self.forward_flow.compare_tcp_meta(meta).to_direction() else {
+            return false;
+        };
+        match self.conn_state {
+            ConnectionState::Established { .. } | ConnectionState::Closed => {
+                !self.get_stream(dir).is_near_position(meta.seq_number)
+            }
+            _ => false,
         }
     }
 
     /// handle packet with SYN flag
-    pub fn handle_syn(&mut self, meta: &TcpMeta) -> bool {
+    pub fn handle_syn(&mut self, meta: &TcpMeta, data: &[u8], extra: &PacketExtra) -> bool {
         debug_assert!(meta.flags.syn);
         if meta.flags.rst {
             // probably shouldn't happen
-            warn!("received strange packet with flags {:?}", meta.flags);
+            warn_throttled!(
+                WarnCategory::Unexpected,
+                "received strange packet with flags {:?}",
+                meta.flags
+            );
+        }
+        let handled = self.handle_syn_state(meta, data, extra);
+        if !handled {
+            return false;
+        }
+        let dir = self
+            .forward_flow
+            .compare_tcp_meta(meta)
+            .to_direction()
+            .expect("got unrelated flow");
+        if let Some((tsval, _)) = meta.option_timestamp {
+            // TS.Recent starts from the SYN (RFC 7323)
+            self.get_stream(dir).ts_recent = Some(tsval);
+        }
+        if meta.flags.ack {
+            self.handshake.syn_ack_mss = self.handshake.syn_ack_mss.or(meta.option_mss);
+            // ECE without CWR accepts ECN (RFC 3168)
+            self.handshake.syn_ack_ecn |= meta.flags.ece && !meta.flags.cwr;
+        } else {
+            self.handshake.syn_mss = self.handshake.syn_mss.or(meta.option_mss);
+            // ECE and CWR request ECN
+            self.handshake.syn_ecn |= meta.flags.ece && meta.flags.cwr;
+        }
+        if let Some(cookie) = &meta.option_tfo_cookie {
+            let slot = if meta.flags.ack {
+                &mut self.handshake.syn_ack_tfo_cookie
+            } else {
+                &mut self.handshake.syn_tfo_cookie
+            };
+            if slot.is_none() {
+                trace!("handle_syn: got TFO cookie option ({} bytes)", cookie.len());
+                *slot = Some(cookie.clone());
+            }
+        }
+        if !data.is_empty() {
+            debug!("handle_syn: SYN carries {} bytes of data", data.len());
+            let slot = match dir {
+                Direction::Forward => &mut self.handshake.forward_syn_data,
+                Direction::Reverse => &mut self.handshake.reverse_syn_data,
+            };
+            if slot.is_none() {
+                // held until stream ISNs are known
+                *slot = Some(SynPayload {
+                    seq_number: meta.seq_number.wrapping_add(1),
+                    data: data.to_vec(),
+                });
+            }
+            self.handshake_event(meta, HandshakeEvent::SynData { len: data.len() });
+        }
+        if let ConnectionState::SimultaneousOpen {
+            forward_window: Some(_),
+            reverse_window: Some(_),
+            ..
+        } = self.conn_state
+        {
+            self.observed_handshake = true;
+            self.finish_simultaneous_open(TransitionReason::SynAck, extra);
         }
+        true
+    }
+
+    /// establish connection opened from both sides, with ISNs from both SYNs
+    fn finish_simultaneous_open(&mut self, reason: TransitionReason, extra: &PacketExtra) {
+        let ConnectionState::SimultaneousOpen {
+            forward_seq,
+            reverse_seq,
+            forward_window,
+            reverse_window,
+        } = self.conn_state
+        else {
+            panic!("finish_simultaneous_open: wrong state");
+        };
+        let (forward_isn, reverse_isn) =
+            self.syn_data_isns(forward_seq.wrapping_add(1), reverse_seq.wrapping_add(1));
+        debug!(
+            "finish_simultaneous_open: SimultaneousOpen -> Established \
+            (forward_isn: {forward_isn}, reverse_isn: {reverse_isn})"
+        );
+        self.set_state(
+            ConnectionState::Established {
+                forward_isn,
+                reverse_isn,
+            },
+            reason,
+        );
+        self.forward_stream
+            .set_isn(forward_isn, forward_window.unwrap_or(0));
+        self.reverse_stream
+            .set_isn(reverse_isn, reverse_window.unwrap_or(0));
+        self.call_handler(|conn, h| h.handshake_done(conn));
+        self.deliver_syn_data(extra);
+    }
+
+    /// ISNs of streams, corrected to the start of SYN payload if any
+    fn syn_data_isns(&self, forward_isn: u32, reverse_isn: u32) -> (u32, u32) {
+        let forward_isn = match &self.handshake.forward_syn_data {
+            Some(payload) => payload.seq_number,
+            None => forward_isn,
+        };
+        let reverse_isn = match &self.handshake.reverse_syn_data {
+            Some(payload) => payload.seq_number,
+            None => reverse_isn,
+        };
+        (forward_isn, reverse_isn)
+    }
+
+    /// deliver payload held from SYN packets once stream ISNs are set
+    fn deliver_syn_data(&mut self, extra: &PacketExtra) {
+        for dir in [Direction::Forward, Direction::Reverse] {
+            let (payload, stream) = match dir {
+                Direction::Forward => (
+                    self.handshake.forward_syn_data.take(),
+                    &mut self.forward_stream,
+                ),
+                Direction::Reverse => (
+                    self.handshake.reverse_syn_data.take(),
+                    &mut self.reverse_stream,
+                ),
+            };
+            let Some(payload) = payload else {
+                continue;
+            };
+            let sp = info_span!("stream", %dir);
+            let accepted = sp.in_scope(|| {
+                stream.handle_data_packet(
+                    payload.seq_number,
+                    &payload.data,
+                    extra,
+                    SegmentTcpInfo::default(),
+                )
+            });
+            if accepted {
+                trace!("delivered {} bytes of SYN data ({dir})", payload.data.len());
+                self.classification.inspect(dir, stream);
+                self.scan_matches(dir);
+                self.call_handler(|conn, h| h.data_received(conn, dir));
+            }
+        }
+    }
+
+    /// update handshake state from packet with SYN flag
+    fn handle_syn_state(&mut self, meta: &TcpMeta, data: &[u8], extra: &PacketExtra) -> bool {
+        let now = self.last_packet_time;
         match self.conn_state {
             ConnectionState::None => {
                 if meta.flags.ack {
                     // SYN/ACK
-                    self.conn_state = ConnectionState::SynReceived {
-                        seq_no: meta.seq_number,
-                        ack_no: meta.ack_number,
-                        window_size: meta.window,
-                        syn_seen: false,
-                    };
+                    self.handshake.syn_ack_count = 1;
+                    self.handshake.syn_ack_time = now;
+                    self.set_state(
+                        ConnectionState::SynReceived {
+                            seq_no: meta.seq_number,
+                            ack_no: meta.ack_number,
+                            window_size: meta.window,
+                            syn_seen: false,
+                        },
+                        TransitionReason::SynAck,
+                    );
                     debug!(
                         "handle_syn: got SYN/ACK (no SYN), None -> SynReceived (seq {}, ack {})",
                         meta.seq_number, meta.ack_number
@@ -178,9 +726,14 @@ impl<H: ConnectionHandler> Connection<H> {
                     true
                 } else {
                     // first SYN
-                    self.conn_state = ConnectionState::SynSent {
-                        seq_no: meta.seq_number,
-                    };
+                    self.handshake.syn_count = 1;
+                    self.handshake.syn_time = now;
+                    self.set_state(
+                        ConnectionState::SynSent {
+                            seq_no: meta.seq_number,
+                        },
+                        TransitionReason::Syn,
+                    );
                     debug!(
                         "handle_syn: got SYN, None -> SynSent (seq {})",
                         meta.seq_number
@@ -205,19 +758,29 @@ impl<H: ConnectionHandler> Connection<H> {
                         debug!("handle_syn: dropped SYN/ACK in wrong direction (state SynSent)");
                         false
                     } else {
-                        if meta.ack_number != seq_no + 1 {
-                            warn!(
+                        let expected = seq_no.wrapping_add(1);
+                        let syn_data_len = self.handshake.syn_data_len(Direction::Forward);
+                        if meta.ack_number != expected
+                            && meta.ack_number != expected.wrapping_add(syn_data_len)
+                        {
+                            warn_throttled!(
+                                WarnCategory::Handshake,
                                 "SYN/ACK packet ack number mismatch: expected {}, found {}",
-                                seq_no + 1,
+                                expected,
                                 meta.ack_number
                             );
                         }
-                        self.conn_state = ConnectionState::SynReceived {
-                            seq_no: meta.seq_number,
-                            ack_no: meta.ack_number,
-                            window_size: meta.window,
-                            syn_seen: true,
-                        };
+                        self.set_state(
+                            ConnectionState::SynReceived {
+                                seq_no: meta.seq_number,
+                                ack_no: meta.ack_number,
+                                window_size: meta.window,
+                                syn_seen: true,
+                            },
+                            TransitionReason::SynAck,
+                        );
+                        self.handshake.syn_ack_count = 1;
+                        self.handshake.syn_ack_time = now;
                         debug!(
                             "handle_syn: received SYN/ACK, SynSent -> SynReceived (seq {}, ack {})",
                             meta.seq_number, meta.ack_number
@@ -228,24 +791,103 @@ impl<H: ConnectionHandler> Connection<H> {
                         }
                         true
                     }
+                } else if self.forward_flow.compare_tcp_meta(meta) == FlowCompare::Reverse {
+                    // both sides opening at the same time, SYN/ACKs follow
+                    debug!(
+                        "handle_syn: got SYN in reverse direction, SynSent -> SimultaneousOpen (seq {})",
+                        meta.seq_number
+                    );
+                    self.handshake.simultaneous_open = true;
+                    self.set_state(
+                        ConnectionState::SimultaneousOpen {
+                            forward_seq: seq_no,
+                            reverse_seq: meta.seq_number,
+                            forward_window: None,
+                            reverse_window: None,
+                        },
+                        TransitionReason::Syn,
+                    );
+                    if let Some(scale) = meta.option_window_scale {
+                        self.reverse_stream.set_window_scale(scale);
+                    }
+                    self.handshake_event(meta, HandshakeEvent::SimultaneousOpen);
+                    true
                 } else {
                     // likely duplicate SYN
+                    self.syn_retransmitted(meta);
                     false
                 }
             }
             ConnectionState::SynReceived { .. } => {
                 // either duplicate SYN or SYN/ACK, ignore
+                self.syn_retransmitted(meta);
                 false
             }
+            ConnectionState::SimultaneousOpen {
+                forward_seq,
+                reverse_seq,
+                mut forward_window,
+                mut reverse_window,
+            } => {
+                // expect: SYN/ACK from both sides, each acknowledging the other SYN
+                let dir = self
+                    .forward_flow
+                    .compare_tcp_meta(meta)
+                    .to_direction()
+                    .expect("connection got unrelated packet");
+                let (seq_no, peer_seq, window) = match dir {
+                    Direction::Forward => (forward_seq, reverse_seq, &mut reverse_window),
+                    Direction::Reverse => (reverse_seq, forward_seq, &mut forward_window),
+                };
+                if !meta.flags.ack || window.is_some() {
+                    // SYN or SYN/ACK sent again
+                    return false;
+                }
+                let expected = peer_seq.wrapping_add(1);
+                let syn_data_len = self.handshake.syn_data_len(dir.swap());
+                if meta.seq_number != seq_no
+                    || (meta.ack_number != expected
+                        && meta.ack_number != expected.wrapping_add(syn_data_len))
+                {
+                    warn_throttled!(
+                        WarnCategory::Handshake,
+                        "simultaneous open SYN/ACK ({dir}) mismatch: expected seq {seq_no}, \
+                        ack {expected}, found seq {}, ack {}",
+                        meta.seq_number,
+                        meta.ack_number
+                    );
+                }
+                *window = Some(meta.window);
+                self.handshake.syn_ack_count += 1;
+                self.handshake.syn_ack_time = self.handshake.syn_ack_time.or(now);
+                debug!("handle_syn: got SYN/ACK ({dir}) in state SimultaneousOpen");
+                // not a transition, connection is established once both arrived
+                self.conn_state = ConnectionState::SimultaneousOpen {
+                    forward_seq,
+                    reverse_seq,
+                    forward_window,
+                    reverse_window,
+                };
+                true
+            }
             ConnectionState::Established { .. } => {
                 // ???
-                warn!("received SYN for established connection?");
-                self.conn_state = ConnectionState::Desync;
+                warn_throttled!(
+                    WarnCategory::Handshake,
+                    "received SYN for established connection?"
+                );
                 let dir = self
                     .forward_flow
                     .compare_tcp_meta(meta)
                     .to_direction()
                     .expect("connection got unrelated packet");
+                if self.config.resync_packets > 0 {
+                    debug!("handle_syn: holding back packets to resynchronize");
+                    self.resync = Some(Resync::new(dir, meta, data, extra));
+                    return false;
+                }
+                self.set_state(ConnectionState::Desync, TransitionReason::UnexpectedSyn);
+                self.close_reason = Some(CloseReason::Desync);
                 self.call_handler(|conn, h| h.connection_desync(conn, dir));
                 false
             }
@@ -253,6 +895,23 @@ impl<H: ConnectionHandler> Connection<H> {
         }
     }
 
+    /// count retransmitted SYN or SYN/ACK
+    fn syn_retransmitted(&mut self, meta: &TcpMeta) {
+        if self.handshake.simultaneous_open {
+            // every SYN and SYN/ACK is expected twice
+            return;
+        }
+        let count = if meta.flags.ack {
+            self.handshake.syn_ack_count += 1;
+            self.handshake.syn_ack_count
+        } else {
+            self.handshake.syn_count += 1;
+            self.handshake.syn_count
+        };
+        trace!("handle_syn: retransmission, count {count}");
+        self.handshake_event(meta, HandshakeEvent::SynRetransmit { count });
+    }
+
     /// handle packet with RST flag
     pub fn handle_rst(&mut self, meta: &TcpMeta, extra: &PacketExtra) -> bool {
         debug_assert!(meta.flags.rst);
@@ -273,7 +932,8 @@ impl<H: ConnectionHandler> Connection<H> {
             ConnectionState::SynSent { .. } => {
                 if dir == Direction::Forward {
                     // reset in response to nothing?
-                    warn!(
+                    warn_throttled!(
+                        WarnCategory::InvalidReset,
                         "received likely invalid reset in state SynSent with same direction as SYN"
                     );
                     return false;
@@ -289,30 +949,60 @@ impl<H: ConnectionHandler> Connection<H> {
                     Direction::Reverse => ack_no,
                 };
 
-                if in_range_wrapping(base, 0, RESET_MAX_LOOKAHEAD, meta.seq_number) {
+                if in_range_wrapping(base, 0, self.config.reset_max_lookahead, meta.seq_number) {
                     debug!("handle_rst: got reset ({dir}) in state SynReceived");
                 } else {
-                    warn!(
+                    warn_throttled!(
+                        WarnCategory::InvalidReset,
                         "got likely invalid reset ({dir}) in state SynReceived (seq {}, base {})",
-                        meta.seq_number, base
+                        meta.seq_number,
+                        base
+                    );
+                    return false;
+                }
+            }
+            ConnectionState::SimultaneousOpen {
+                forward_seq,
+                reverse_seq,
+                ..
+            } => {
+                // reset should have seq after seq of own SYN
+                let base = match dir {
+                    Direction::Forward => forward_seq,
+                    Direction::Reverse => reverse_seq,
+                };
+                if in_range_wrapping(base, 0, self.config.reset_max_lookahead, meta.seq_number) {
+                    debug!("handle_rst: got reset ({dir}) in state SimultaneousOpen");
+                } else {
+                    warn_throttled!(
+                        WarnCategory::InvalidReset,
+                        "got likely invalid reset ({dir}) in state SimultaneousOpen (seq {}, base {})",
+                        meta.seq_number,
+                        base
                     );
                     return false;
                 }
             }
             ConnectionState::Established { .. } => {
+                if !self.check_paws(dir, meta) {
+                    return false;
+                }
                 // let the stream handle it
                 let sp = info_span!("stream", %dir);
                 let accepted = sp.in_scope(|| match dir {
-                    Direction::Forward => self
-                        .forward_stream
-                        .handle_rst_packet(meta.seq_number, extra),
-                    Direction::Reverse => self
-                        .reverse_stream
-                        .handle_rst_packet(meta.seq_number, extra),
+                    Direction::Forward => {
+                        self.forward_stream
+                            .handle_rst_packet(meta.seq_number, extra, meta.into())
+                    }
+                    Direction::Reverse => {
+                        self.reverse_stream
+                            .handle_rst_packet(meta.seq_number, extra, meta.into())
+                    }
                 });
                 if !accepted {
                     return false;
                 }
+                self.update_ts_recent(dir, meta);
             }
             ConnectionState::Closed | ConnectionState::Desync => {
                 // connection already dead
@@ -320,36 +1010,78 @@ impl<H: ConnectionHandler> Connection<H> {
             }
         }
 
-        match dir {
-            Direction::Forward => {
-                self.forward_stream.had_reset = true;
-            }
-            Direction::Reverse => {
-                self.reverse_stream.had_reset = true;
-            }
-        }
-        self.conn_state = ConnectionState::Closed;
+        let stream = self.get_stream(dir);
+        stream.had_reset = true;
+        stream.rst_count += 1;
+        self.set_state(ConnectionState::Closed, TransitionReason::Rst);
         self.observed_close = true;
+        self.close_reason = Some(CloseReason::Rst);
         self.call_handler(|conn, h| h.rst_received(conn, dir, extra.clone()));
         true
     }
 
+    /// validate timestamp of segment sent in direction, notifying the handler
+    /// if it was rejected
+    fn check_paws(&mut self, dir: Direction, meta: &TcpMeta) -> bool {
+        let Some((tsval, _)) = meta.option_timestamp else {
+            return true;
+        };
+        let stream = self.get_stream(dir);
+        let ts_recent = stream.ts_recent.unwrap_or(tsval);
+        if stream.check_paws(tsval) {
+            return true;
+        }
+        warn_throttled!(
+            WarnCategory::Paws,
+            "dropping {dir} segment with TSval {tsval} far behind {ts_recent}"
+        );
+        self.call_handler(|conn, h| h.paws_rejected(conn, dir, tsval, ts_recent));
+        false
+    }
+
+    /// record timestamp of segment sent in direction after it was accepted
+    fn update_ts_recent(&mut self, dir: Direction, meta: &TcpMeta) {
+        if let Some((tsval, _)) = meta.option_timestamp {
+            self.get_stream(dir).update_ts_recent(tsval);
+        }
+    }
+
     /// handle data packet received before SYN/ACK
     pub fn handle_data_hs1(&mut self, meta: &TcpMeta, data: &[u8], extra: &PacketExtra) -> bool {
         debug!(
             "handle_data_hs1: received data before handshake completion, {:?} -> Established",
             self.conn_state
         );
+        if self.conn_state == ConnectionState::None && self.config.direction_inference_packets > 0 {
+            // no SYN seen, the first packet may well be from the server
+            let dir = match self.forward_flow.compare_tcp_meta(meta) {
+                FlowCompare::Forward => Direction::Forward,
+                _ => Direction::Reverse,
+            };
+            let inference = &mut self.direction_inference;
+            inference.active = true;
+            inference.observe(dir, meta, data);
+            if inference.should_reverse(&self.forward_flow, 0, 0) {
+                debug!("handle_data_hs1: first packet likely sent by server, reversing flow");
+                self.forward_flow.reverse();
+                self.direction_inference.reversed();
+            }
+        }
         let (forward_isn, reverse_isn) = match self.forward_flow.compare_tcp_meta(meta) {
             FlowCompare::Forward => (meta.seq_number, meta.ack_number),
             FlowCompare::Reverse => (meta.ack_number, meta.seq_number),
             _ => unreachable!("got unrelated flow"),
         };
 
-        self.conn_state = ConnectionState::Established {
-            forward_isn,
-            reverse_isn,
-        };
+        let (forward_isn, reverse_isn) = self.syn_data_isns(forward_isn, reverse_isn);
+
+        self.set_state(
+            ConnectionState::Established {
+                forward_isn,
+                reverse_isn,
+            },
+            TransitionReason::Midstream,
+        );
 
         self.forward_stream.set_isn(forward_isn, 0);
         self.reverse_stream.set_isn(reverse_isn, 0);
@@ -357,6 +1089,7 @@ impl<H: ConnectionHandler> Connection<H> {
         debug!("handle_data_hs1: assuming forward isn: {forward_isn}, reverse isn: {reverse_isn}");
 
         self.call_handler(|conn, h| h.handshake_done(conn));
+        self.deliver_syn_data(extra);
 
         if !data.is_empty() {
             self.handle_data_established(meta, data, extra)
@@ -378,9 +1111,12 @@ impl<H: ConnectionHandler> Connection<H> {
         };
 
         let mut reverse_window: u16 = 0;
+        let syn_ack_end = seq_no
+            .wrapping_add(1)
+            .wrapping_add(self.handshake.syn_data_len(Direction::Reverse));
         let (forward_isn, reverse_isn) = match self.forward_flow.compare_tcp_meta(meta) {
             FlowCompare::Forward => {
-                if meta.flags.ack && meta.seq_number == ack_no && meta.ack_number == seq_no + 1 {
+                if meta.flags.ack && meta.seq_number == ack_no && meta.ack_number == syn_ack_end {
                     if syn_seen {
                         self.observed_handshake = true;
                         reverse_window = meta.window;
@@ -388,6 +1124,11 @@ impl<H: ConnectionHandler> Connection<H> {
                     } else {
                         debug!("handle_data_hs2: got SYN/ACK and ACK of handshake");
                     }
+                    self.handshake.ack_time = self.last_packet_time;
+                    if let Some(client) = self.handshake.client_rtt() {
+                        let server = self.handshake.server_rtt();
+                        self.handshake_event(meta, HandshakeEvent::Rtt { server, client });
+                    }
                 } else {
                     debug!("handle_data_hs2: probably lost final packet of handshake")
                 }
@@ -399,18 +1140,23 @@ impl<H: ConnectionHandler> Connection<H> {
             }
             _ => unreachable!("got unrelated flow"),
         };
+        let (forward_isn, reverse_isn) = self.syn_data_isns(forward_isn, reverse_isn);
         debug!(
             "handle_data_hs2: received data packet, SynReceived -> Established \
             (forward_isn: {forward_isn}, reverse_isn: {reverse_isn})"
         );
 
-        self.conn_state = ConnectionState::Established {
-            forward_isn,
-            reverse_isn,
-        };
+        self.set_state(
+            ConnectionState::Established {
+                forward_isn,
+                reverse_isn,
+            },
+            TransitionReason::Ack,
+        );
         self.forward_stream.set_isn(forward_isn, forward_window);
         self.reverse_stream.set_isn(reverse_isn, reverse_window);
         self.call_handler(|conn, h| h.handshake_done(conn));
+        self.deliver_syn_data(extra);
 
         if !data.is_empty() {
             self.handle_data_established(meta, data, extra)
@@ -426,18 +1172,21 @@ impl<H: ConnectionHandler> Connection<H> {
         data: &[u8],
         extra: &PacketExtra,
     ) -> bool {
-        let dir;
-        let (data_stream, ack_stream) = match self.forward_flow.compare_tcp_meta(meta) {
-            FlowCompare::Forward => {
-                dir = Direction::Forward;
-                (&mut self.forward_stream, &mut self.reverse_stream)
-            }
-            FlowCompare::Reverse => {
-                dir = Direction::Reverse;
-                (&mut self.reverse_stream, &mut self.forward_stream)
-            }
+        let dir = match self.forward_flow.compare_tcp_meta(meta) {
+            FlowCompare::Forward => Direction::Forward,
+            FlowCompare::Reverse => Direction::Reverse,
             _ => unreachable!("got unrelated flow"),
         };
+        if !self.check_paws(dir, meta) {
+            return false;
+        }
+        let (data_stream, ack_stream) = match dir {
+            Direction::Forward => (&mut self.forward_stream, &mut self.reverse_stream),
+            Direction::Reverse => (&mut self.reverse_stream, &mut self.forward_stream),
+        };
+
+        // only segments within the window may update TS.Recent
+        let in_window = data_stream.update_offset(meta.seq_number, false).is_some();
 
         let mut did_something = false;
         let mut got_ack = false;
@@ -447,8 +1196,9 @@ impl<H: ConnectionHandler> Connection<H> {
             let was_ended = ack_stream.has_ended;
             // send ack to the stream in the opposite direction
             let sp = info_span!("stream", dir = %dir.swap());
-            got_ack |=
-                sp.in_scope(|| ack_stream.handle_ack_packet(meta.ack_number, meta.window, extra));
+            got_ack |= sp.in_scope(|| {
+                ack_stream.handle_ack_packet(meta.ack_number, meta.window, extra, meta.into())
+            });
             did_something |= got_ack;
             // set ack offset on stream to correlate directions
             data_stream.reverse_acked = ack_stream.highest_acked;
@@ -462,7 +1212,9 @@ impl<H: ConnectionHandler> Connection<H> {
         if !data.is_empty() {
             // write data to stream
             let sp = info_span!("stream", %dir);
-            got_data = sp.in_scope(|| data_stream.handle_data_packet(meta.seq_number, data, extra));
+            got_data = sp.in_scope(|| {
+                data_stream.handle_data_packet(meta.seq_number, data, extra, meta.into())
+            });
             did_something |= got_data;
         }
         let data_stream_has_ended = data_stream.has_ended;
@@ -470,13 +1222,45 @@ impl<H: ConnectionHandler> Connection<H> {
         if meta.flags.fin {
             // notify stream of fin
             let sp = info_span!("stream", %dir);
-            got_fin =
-                sp.in_scope(|| data_stream.handle_fin_packet(meta.seq_number, data.len(), extra));
+            got_fin = sp.in_scope(|| {
+                data_stream.handle_fin_packet(meta.seq_number, data.len(), extra, meta.into())
+            });
             did_something |= got_fin;
         }
 
+        if in_window && did_something {
+            self.update_ts_recent(dir, meta);
+        }
+
         // call event handlers
+        let stream = match dir {
+            Direction::Forward => &mut self.forward_stream,
+            Direction::Reverse => &mut self.reverse_stream,
+        };
+        // retransmissions may conflict even if they carry no new data
+        let conflicts = mem::take(&mut stream.state.conflicts);
+        for conflict in conflicts {
+            self.call_handler(|conn, h| h.overlap_conflict(conn, dir, conflict));
+        }
+        let stream = match dir {
+            Direction::Forward => &mut self.forward_stream,
+            Direction::Reverse => &mut self.reverse_stream,
+        };
+        let late_data = mem::take(&mut stream.late_data);
+        for late in late_data {
+            self.call_handler(|conn, h| h.late_data(conn, dir, &late));
+        }
         if got_data {
+            let stream = match dir {
+                Direction::Forward => &mut self.forward_stream,
+                Direction::Reverse => &mut self.reverse_stream,
+            };
+            let gap = stream.detect_gap();
+            self.classification.inspect(dir, stream);
+            if let Some(gap) = gap {
+                self.call_handler(|conn, h| h.gap_detected(conn, dir, gap));
+            }
+            self.scan_matches(dir);
             self.call_handler(|conn, h| h.data_received(conn, dir));
         }
         if got_ack {
@@ -491,8 +1275,9 @@ impl<H: ConnectionHandler> Connection<H> {
 
             // update state if both sides closed
             if data_stream_has_ended {
-                self.conn_state = ConnectionState::Closed;
+                self.set_state(ConnectionState::Closed, TransitionReason::Fin);
                 self.observed_close = true;
+                self.close_reason = Some(CloseReason::Fin);
             }
         }
 
@@ -506,6 +1291,15 @@ impl<H: ConnectionHandler> Connection<H> {
                 self.handle_data_hs1(meta, data, extra)
             }
             ConnectionState::SynReceived { .. } => self.handle_data_hs2(meta, data, extra),
+            ConnectionState::SimultaneousOpen { .. } => {
+                // SYN/ACK lost, but both ISNs are known
+                self.finish_simultaneous_open(TransitionReason::Ack, extra);
+                if !data.is_empty() {
+                    self.handle_data_established(meta, data, extra)
+                } else {
+                    true
+                }
+            }
             _ => {
                 // established or (closed but more data)
                 self.handle_data_established(meta, data, extra)
@@ -513,6 +1307,20 @@ impl<H: ConnectionHandler> Connection<H> {
         }
     }
 
+    /// change connection state, recording the transition if enabled
+    fn set_state(&mut self, state: ConnectionState, reason: TransitionReason) {
+        if self.config.record_transitions {
+            self.transitions.push(StateTransition {
+                from: self.conn_state.clone(),
+                to: state.clone(),
+                reason,
+                packet_index: self.current_packet_index,
+                timestamp_us: self.last_packet_time.map(|t| t.as_micros() as u64),
+            });
+        }
+        self.conn_state = state;
+    }
+
     /// call the event handler, if one exists
     pub fn call_handler(&mut self, do_thing: impl FnOnce(&mut Self, &mut H)) {
         if let Some(mut handler) = self.event_handler.take() {
@@ -521,21 +1329,57 @@ impl<H: ConnectionHandler> Connection<H> {
         }
     }
 
+    /// search newly contiguous data for configured patterns, reporting
+    /// matches to the handler
+    pub(crate) fn scan_matches(&mut self, dir: Direction) {
+        let Some(patterns) = &self.config.patterns else {
+            return;
+        };
+        let (stream, scanner) = match dir {
+            Direction::Forward => (&self.forward_stream, &mut self.scanners[0]),
+            Direction::Reverse => (&self.reverse_stream, &mut self.scanners[1]),
+        };
+        let mut found = Vec::new();
+        scanner.scan(patterns, stream, &mut found);
+        for m in found {
+            self.call_handler(|conn, h| h.stream_match(conn, dir, m));
+        }
+    }
+
     /// called before connection is removed from hashtable
     pub fn will_retire(&mut self) {
+        if let Some(resync) = &self.resync {
+            // too late to recreate
+            let outcome = match resync.decide() {
+                ResyncOutcome::Recreated => ResyncOutcome::Kept,
+                outcome => outcome,
+            };
+            self.finish_resync(outcome);
+        }
+        // data still missing will not arrive anymore
+        self.forward_stream.declare_remaining_gaps();
+        self.reverse_stream.declare_remaining_gaps();
         self.call_handler(|conn, h| h.will_retire(conn));
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::serialized::PacketExtra;
+    use crate::classify::AppProtocol;
+    use crate::config::ReassemblyConfig;
+    use crate::handshake::HandshakeEvent;
+    use crate::resync::ResyncOutcome;
+    use crate::serialized::{ConnInfo, GapCause, GapInfo, PacketExtra};
+    use crate::stream::{SegmentType, StreamGap};
     use crate::{initialize_logging, ConnectionHandler, TcpFlags, TcpMeta};
+    use kinesin_rdt::stream::inbound::{OverlapConflict, OverlapPolicy};
     use parking_lot::Mutex;
     use std::convert::Infallible;
     use std::mem;
+    use std::sync::Arc;
+    use std::time::Duration;
 
-    use super::{Connection, Direction};
+    use super::{CloseReason, Connection, ConnectionState, Direction, TransitionReason};
 
     /// swap src/dest ip/port and seq/ack
     fn swap_meta(meta: &TcpMeta) -> TcpMeta {
@@ -558,18 +1402,35 @@ mod test {
     static RST_RECEIVED: Mutex<Option<Direction>> = Mutex::new(None);
     static STREAM_END: Mutex<Option<Direction>> = Mutex::new(None);
     static WILL_RETIRE: Mutex<bool> = Mutex::new(false);
+    static DIRECTION_CHANGED: Mutex<bool> = Mutex::new(false);
+
+    /// records events which tests check per connection
+    #[derive(Default)]
+    struct TestHandler {
+        handshake_events: Vec<(Direction, HandshakeEvent)>,
+        overlap_conflicts: Vec<(Direction, OverlapConflict)>,
+        paws_rejected: Vec<(Direction, u32, u32)>,
+        resync_outcomes: Vec<ResyncOutcome>,
+    }
 
-    struct TestHandler;
     impl ConnectionHandler for TestHandler {
         type InitialData = ();
         type ConstructError = Infallible;
         fn new(_init: (), _conn: &mut Connection<Self>) -> Result<Self, Infallible> {
-            Ok(TestHandler)
+            Ok(TestHandler::default())
         }
         fn handshake_done(&mut self, _conn: &mut Connection<Self>) {
             let mut guard = HANDSHAKE_DONE.lock();
             *guard = true;
         }
+        fn handshake_event(
+            &mut self,
+            _connection: &mut Connection<Self>,
+            direction: Direction,
+            event: HandshakeEvent,
+        ) {
+            self.handshake_events.push((direction, event));
+        }
         fn data_received(&mut self, _connection: &mut Connection<Self>, direction: Direction) {
             let mut guard = DATA_RECEIVED.lock();
             *guard = Some(direction);
@@ -595,6 +1456,35 @@ mod test {
             let mut guard = WILL_RETIRE.lock();
             *guard = true;
         }
+        fn direction_changed(&mut self, _connection: &mut Connection<Self>) {
+            let mut guard = DIRECTION_CHANGED.lock();
+            *guard = true;
+        }
+        fn overlap_conflict(
+            &mut self,
+            _connection: &mut Connection<Self>,
+            direction: Direction,
+            conflict: OverlapConflict,
+        ) {
+            self.overlap_conflicts.push((direction, conflict));
+        }
+        fn paws_rejected(
+            &mut self,
+            _connection: &mut Connection<Self>,
+            direction: Direction,
+            tsval: u32,
+            ts_recent: u32,
+        ) {
+            self.paws_rejected.push((direction, tsval, ts_recent));
+        }
+        fn connection_resync(
+            &mut self,
+            _connection: &mut Connection<Self>,
+            _direction: Direction,
+            outcome: ResyncOutcome,
+        ) {
+            self.resync_outcomes.push(outcome);
+        }
     }
 
     #[test]
@@ -613,8 +1503,17 @@ mod test {
                 ..Default::default()
             },
             window: 256,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
             option_window_scale: Some(2),
             option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
         };
 
         let mut conn: Connection<TestHandler> = Connection::new((&hs1).into(), ()).unwrap();
@@ -634,7 +1533,905 @@ mod test {
         *hs_done = false;
 
         let data1 = hs3.clone();
-        assert!(conn.handle_packet(&data1, b"test", &PacketExtra::None));
-        assert_eq!(conn.forward_stream.readable_buffered_length(), 4);
+        let extra = PacketExtra::LegacyPcap {
+            index: 3,
+            ts_sec: 5,
+            ts_usec: 0,
+            vlan_id: None,
+            frames: None,
+            ttl: None,
+            bad_checksum: false,
+        };
+        assert!(conn.handle_packet(&data1, b"test", &extra));
+        assert_eq!(conn.classification.protocol(), None);
+        let mut data2 = data1.clone();
+        data2.seq_number += 4;
+        assert!(conn.handle_packet(&data2, b" / HTTP/1.1\r\n", &extra));
+        assert_eq!(conn.classification.protocol(), None);
+        assert_eq!(conn.forward_stream.readable_buffered_length(), 17);
+        assert_eq!(
+            conn.forward_stream.first_packet_time,
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(conn.reverse_stream.last_packet_time, None);
+        let info = ConnInfo::from_connection(&conn);
+        assert_eq!(info.first_packet_us, Some(5_000_000));
+        assert_eq!(info.last_packet_us, Some(5_000_000));
+        assert_eq!(info.forward_stats.as_ref().unwrap().bytes, 17);
+        assert_eq!(info.close_reason, None);
+
+        let mut reply = swap_meta(&data1);
+        reply.seq_number = hs2.seq_number + 1;
+        reply.ack_number = data2.seq_number + 15;
+        assert!(conn.handle_packet(&reply, b"HTTP/1.1 200 OK\r\n", &extra));
+        assert_eq!(conn.classification.protocol(), Some(AppProtocol::Http));
+    }
+
+    #[test]
+    fn packet_times() {
+        let at = |index: u64, ts_sec: u32, ts_usec: u32| PacketExtra::LegacyPcap {
+            index,
+            ts_sec,
+            ts_usec,
+            vlan_id: None,
+            frames: None,
+            ttl: None,
+            bad_checksum: false,
+        };
+        assert_eq!(PacketExtra::None.timestamp(), None);
+        assert_eq!(
+            at(0, 100, 250_000).timestamp(),
+            Some(Duration::from_millis(100_250))
+        );
+        let pcapng = PacketExtra::Pcapng {
+            index: 4,
+            interface_id: 0,
+            interface_name: None,
+            ts_nsec: 103_000_000_500,
+            dropped: None,
+            interface_dropped: None,
+            vlan_id: None,
+            frames: None,
+            ttl: None,
+            bad_checksum: false,
+        };
+        assert_eq!(pcapng.timestamp(), Some(Duration::new(103, 500)));
+
+        let syn = TcpMeta {
+            src_addr: [10, 5, 0, 1].into(),
+            src_port: 40050,
+            dst_addr: [10, 5, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut conn: Connection<TestHandler> = Connection::new((&syn).into(), ()).unwrap();
+        assert_eq!(conn.first_packet_time, None);
+        assert!(conn.handle_packet(&syn, &[], &at(0, 100, 250_000)));
+        let mut syn_ack = swap_meta(&syn);
+        syn_ack.seq_number = 5000;
+        syn_ack.ack_number = 1001;
+        syn_ack.flags.ack = true;
+        assert!(conn.handle_packet(&syn_ack, &[], &at(1, 101, 0)));
+        let mut ack = swap_meta(&syn_ack);
+        ack.flags.syn = false;
+        ack.ack_number = 5001;
+        assert!(conn.handle_packet(&ack, &[], &at(2, 102, 0)));
+        // packets without timestamps leave the times alone
+        assert!(conn.handle_packet(&ack, b"test", &PacketExtra::None));
+        let mut data = ack.clone();
+        data.seq_number += 4;
+        assert!(conn.handle_packet(&data, b"more", &pcapng));
+
+        assert_eq!(conn.first_packet_time, Some(Duration::from_millis(100_250)));
+        assert_eq!(conn.last_packet_time, Some(Duration::new(103, 500)));
+        assert_eq!(
+            conn.forward_stream.first_packet_time,
+            Some(Duration::from_millis(100_250))
+        );
+        assert_eq!(
+            conn.forward_stream.last_packet_time,
+            Some(Duration::new(103, 500))
+        );
+        assert_eq!(
+            conn.reverse_stream.first_packet_time,
+            Some(Duration::from_secs(101))
+        );
+        assert_eq!(
+            conn.reverse_stream.last_packet_time,
+            Some(Duration::from_secs(101))
+        );
+        let info = ConnInfo::from_connection(&conn);
+        assert_eq!(info.first_packet_us, Some(100_250_000));
+        assert_eq!(info.last_packet_us, Some(103_000_000));
+    }
+
+    #[test]
+    fn missing_handshake() {
+        // capture starts with an ACK from the server, ports are inconclusive
+        let server_ack = TcpMeta {
+            src_addr: [10, 0, 0, 2].into(),
+            src_port: 40000,
+            dst_addr: [10, 0, 0, 1].into(),
+            dst_port: 50000,
+            seq_number: 7000,
+            ack_number: 3000,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 512,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut conn: Connection<TestHandler> = Connection::new((&server_ack).into(), ()).unwrap();
+        assert!(conn.handle_packet(&server_ack, &[], &PacketExtra::None));
+        assert_eq!(conn.forward_flow.src_port, 40000);
+
+        // request reveals the client
+        let request = swap_meta(&server_ack);
+        assert!(conn.handle_packet(&request, b"GET / HTTP/1.1\r\n", &PacketExtra::None));
+        assert!(*DIRECTION_CHANGED.lock());
+        assert_eq!(conn.forward_flow.src_port, 50000);
+        assert_eq!(conn.forward_stream.readable_buffered_length(), 16);
+        assert_eq!(conn.forward_stream.packet_count, 1);
+        assert_eq!(conn.reverse_stream.packet_count, 1);
+        assert_eq!(conn.direction_inference.revisions, 1);
+
+        // server port is recognized from the first packet
+        let mut response = server_ack.clone();
+        response.src_port = 8080;
+        let mut conn: Connection<TestHandler> = Connection::new((&response).into(), ()).unwrap();
+        assert!(conn.handle_packet(&response, b"HTTP/1.1 200 OK\r\n", &PacketExtra::None));
+        assert_eq!(conn.forward_flow.dst_port, 8080);
+        assert_eq!(conn.reverse_stream.readable_buffered_length(), 17);
+        assert_eq!(conn.direction_inference.revisions, 0);
+    }
+
+    #[test]
+    fn handshake_events() {
+        let at = |ms: u32| PacketExtra::LegacyPcap {
+            index: 0,
+            ts_sec: 100,
+            ts_usec: ms * 1000,
+            vlan_id: None,
+            frames: None,
+            ttl: None,
+            bad_checksum: false,
+        };
+        let syn = TcpMeta {
+            src_addr: [10, 1, 0, 1].into(),
+            src_port: 40001,
+            dst_addr: [10, 1, 0, 2].into(),
+            dst_port: 443,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ece: true,
+                cwr: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: Some(1460),
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut conn: Connection<TestHandler> = Connection::new((&syn).into(), ()).unwrap();
+        // SYN with data, then retransmitted
+        assert!(conn.handle_packet(&syn, b"early", &at(0)));
+        assert!(!conn.handle_packet(&syn, b"early", &at(100)));
+        let mut syn_ack = swap_meta(&syn);
+        syn_ack.seq_number = 5000;
+        syn_ack.ack_number = 1001;
+        syn_ack.flags.ack = true;
+        syn_ack.flags.cwr = false;
+        syn_ack.option_mss = Some(1400);
+        assert!(conn.handle_packet(&syn_ack, &[], &at(130)));
+        let mut ack = swap_meta(&syn_ack);
+        ack.flags.syn = false;
+        ack.flags.ece = false;
+        ack.option_mss = None;
+        ack.ack_number = 5001;
+        assert!(conn.handle_packet(&ack, &[], &at(150)));
+        assert!(conn.observed_handshake);
+        assert_eq!(conn.handshake.syn_count, 2);
+        assert_eq!(conn.handshake.syn_mss, Some(1460));
+        assert_eq!(conn.handshake.syn_ack_mss, Some(1400));
+        assert!(conn.handshake.ecn_negotiated());
+
+        // congestion experienced mark is recorded on the segment
+        let mut data = ack.clone();
+        data.ip_ecn = 3;
+        assert!(conn.handle_packet(&data, b"test", &at(160)));
+        let segment = conn
+            .forward_stream
+            .segments_info
+            .iter()
+            .find(|s| matches!(s.data, SegmentType::Data { len: 4, .. }))
+            .unwrap();
+        assert!(segment.tcp.congestion_experienced());
+
+        assert_eq!(
+            conn.event_handler.as_ref().unwrap().handshake_events,
+            [
+                (Direction::Forward, HandshakeEvent::SynData { len: 5 }),
+                (
+                    Direction::Forward,
+                    HandshakeEvent::SynRetransmit { count: 2 }
+                ),
+                (
+                    Direction::Forward,
+                    HandshakeEvent::Rtt {
+                        server: Some(Duration::from_millis(130)),
+                        client: Duration::from_millis(20),
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn fast_open() {
+        let syn = TcpMeta {
+            src_addr: [10, 2, 0, 1].into(),
+            src_port: 40002,
+            dst_addr: [10, 2, 0, 2].into(),
+            dst_port: 80,
+            seq_number: u32::MAX - 2,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: Some(vec![7; 8]),
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        // no handler, events would interfere with handshake_events
+        let mut conn: Connection<TestHandler> =
+            Connection::without_handler((&syn).into(), Arc::default());
+        assert!(conn.handle_packet(&syn, b"GET / ", &PacketExtra::None));
+        assert_eq!(conn.handshake.syn_tfo_cookie, Some(vec![7; 8]));
+        // server accepts SYN data and sends data on SYN/ACK
+        let mut syn_ack = swap_meta(&syn);
+        syn_ack.seq_number = 5000;
+        syn_ack.ack_number = syn.seq_number.wrapping_add(7);
+        syn_ack.flags.ack = true;
+        syn_ack.option_tfo_cookie = None;
+        assert!(conn.handle_packet(&syn_ack, b"hi", &PacketExtra::None));
+        let mut ack = swap_meta(&syn_ack);
+        ack.flags.syn = false;
+        ack.ack_number = 5003;
+        assert!(conn.handle_packet(&ack, b"HTTP/1.1\r\n", &PacketExtra::None));
+        assert!(conn.observed_handshake);
+        assert!(conn.handshake.forward_syn_data.is_none());
+        assert_eq!(conn.forward_stream.readable_buffered_length(), 16);
+        assert_eq!(conn.reverse_stream.readable_buffered_length(), 2);
+        assert_eq!(conn.classification.protocol(), Some(AppProtocol::Http));
+    }
+
+    #[test]
+    fn simultaneous_open() {
+        static DONE: Mutex<u32> = Mutex::new(0);
+        // separate handler, events would interfere with handshake_events
+        struct OpenHandler;
+        impl ConnectionHandler for OpenHandler {
+            type InitialData = ();
+            type ConstructError = Infallible;
+            fn new(_init: (), _conn: &mut Connection<Self>) -> Result<Self, Infallible> {
+                Ok(OpenHandler)
+            }
+            fn handshake_done(&mut self, _conn: &mut Connection<Self>) {
+                *DONE.lock() += 1;
+            }
+        }
+
+        let syn = TcpMeta {
+            src_addr: [10, 2, 0, 3].into(),
+            src_port: 40003,
+            dst_addr: [10, 2, 0, 4].into(),
+            dst_port: 40004,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let config = ReassemblyConfig {
+            record_transitions: true,
+            ..Default::default()
+        };
+        let mut conn: Connection<OpenHandler> =
+            Connection::with_config((&syn).into(), Arc::new(config), ()).unwrap();
+        // SYNs cross, then both sides send SYN/ACK
+        assert!(conn.handle_packet(&syn, &[], &PacketExtra::None));
+        let mut peer_syn = swap_meta(&syn);
+        peer_syn.seq_number = 7000;
+        assert!(conn.handle_packet(&peer_syn, &[], &PacketExtra::None));
+        assert!(conn.handshake.simultaneous_open);
+        let mut syn_ack = syn.clone();
+        syn_ack.flags.ack = true;
+        syn_ack.ack_number = 7001;
+        assert!(conn.handle_packet(&syn_ack, &[], &PacketExtra::None));
+        assert!(!conn.handle_packet(&syn_ack, &[], &PacketExtra::None));
+        let mut peer_syn_ack = swap_meta(&syn_ack);
+        peer_syn_ack.seq_number = 7000;
+        peer_syn_ack.ack_number = 1001;
+        assert!(conn.handle_packet(&peer_syn_ack, &[], &PacketExtra::None));
+        assert_eq!(
+            conn.conn_state,
+            ConnectionState::Established {
+                forward_isn: 1001,
+                reverse_isn: 7001,
+            }
+        );
+        assert!(conn.observed_handshake);
+        assert_eq!(*DONE.lock(), 1);
+        assert_eq!(conn.handshake.syn_ack_count, 2);
+        let reasons: Vec<_> = conn.transitions.iter().map(|t| t.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                TransitionReason::Syn,
+                TransitionReason::Syn,
+                TransitionReason::SynAck
+            ]
+        );
+
+        let mut data = syn_ack.clone();
+        data.flags.syn = false;
+        data.seq_number = 1001;
+        assert!(conn.handle_packet(&data, b"hello", &PacketExtra::None));
+        let mut reply = swap_meta(&data);
+        reply.seq_number = 7001;
+        reply.ack_number = 1006;
+        assert!(conn.handle_packet(&reply, b"world", &PacketExtra::None));
+        assert_eq!(conn.forward_stream.readable_buffered_length(), 5);
+        assert_eq!(conn.reverse_stream.readable_buffered_length(), 5);
+    }
+
+    #[test]
+    fn overlap_conflict() {
+        let syn = TcpMeta {
+            src_addr: [10, 3, 0, 1].into(),
+            src_port: 40003,
+            dst_addr: [10, 3, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let config = ReassemblyConfig {
+            overlap_policy: OverlapPolicy::Record,
+            ..Default::default()
+        };
+        let mut conn: Connection<TestHandler> =
+            Connection::with_config((&syn).into(), Arc::new(config), ()).unwrap();
+        assert!(conn.handle_packet(&syn, &[], &PacketExtra::None));
+        let mut syn_ack = swap_meta(&syn);
+        syn_ack.seq_number = 5000;
+        syn_ack.ack_number = 1001;
+        syn_ack.flags.ack = true;
+        assert!(conn.handle_packet(&syn_ack, &[], &PacketExtra::None));
+        let mut ack = swap_meta(&syn_ack);
+        ack.flags.syn = false;
+        ack.ack_number = 5001;
+        assert!(conn.handle_packet(&ack, b"GET /a", &PacketExtra::None));
+        // retransmission with injected content
+        conn.handle_packet(&ack, b"GET /b", &PacketExtra::None);
+        assert_eq!(
+            conn.event_handler.as_ref().unwrap().overlap_conflicts,
+            [(
+                Direction::Forward,
+                OverlapConflict {
+                    offset: 5,
+                    original: b"a".to_vec(),
+                    conflicting: b"b".to_vec(),
+                }
+            )]
+        );
+        // first copy is kept
+        let mut data = [0; 6];
+        conn.forward_stream
+            .state
+            .read_next(6)
+            .unwrap()
+            .copy_to_slice(&mut data);
+        assert_eq!(&data, b"GET /a");
+    }
+
+    #[test]
+    fn read_next() {
+        let syn = TcpMeta {
+            src_addr: [10, 3, 0, 3].into(),
+            src_port: 40004,
+            dst_addr: [10, 3, 0, 4].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut conn: Connection<TestHandler> = Connection::new((&syn).into(), ()).unwrap();
+        assert!(conn.handle_packet(&syn, &[], &PacketExtra::None));
+        let mut syn_ack = swap_meta(&syn);
+        syn_ack.seq_number = 5000;
+        syn_ack.ack_number = 1001;
+        syn_ack.flags.ack = true;
+        assert!(conn.handle_packet(&syn_ack, &[], &PacketExtra::None));
+        let mut ack = swap_meta(&syn_ack);
+        ack.flags.syn = false;
+        ack.ack_number = 5001;
+        assert!(conn.handle_packet(&ack, b"hello", &PacketExtra::None));
+        // skip offsets 5..10
+        ack.seq_number = 1011;
+        assert!(conn.handle_packet(&ack, b"world", &PacketExtra::None));
+
+        let stream = &mut conn.forward_stream;
+        let mut segments = Vec::new();
+        let mut gaps = Vec::new();
+        // past end of buffer, nothing is consumed
+        assert!(stream
+            .read_next(16, &mut segments, &mut gaps, |_| ())
+            .is_none());
+        assert!(segments.is_empty());
+        assert_eq!(stream.buffer_start(), 0);
+
+        let data = stream
+            .read_next(15, &mut segments, &mut gaps, |slice| {
+                let mut data = [0; 15];
+                slice.copy_to_slice(&mut data);
+                data
+            })
+            .unwrap();
+        assert_eq!(&data, b"hello\0\0\0\0\0world");
+        assert_eq!(
+            gaps,
+            vec![StreamGap {
+                range: 5..10,
+                info: GapInfo {
+                    cause: Some(GapCause::BufferOverrun),
+                    packets_skipped: 1,
+                    ..Default::default()
+                },
+            }]
+        );
+        let data_offsets: Vec<u64> = segments
+            .iter()
+            .filter(|s| matches!(s.data, SegmentType::Data { .. }))
+            .map(|s| s.offset)
+            .collect();
+        assert_eq!(data_offsets, [0, 10]);
+        assert_eq!(stream.buffer_start(), 15);
+        assert!(stream
+            .read_next(15, &mut segments, &mut gaps, |_| ())
+            .is_none());
+    }
+
+    #[test]
+    fn rst_close() {
+        let syn = TcpMeta {
+            src_addr: [10, 3, 0, 5].into(),
+            src_port: 40005,
+            dst_addr: [10, 3, 0, 6].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let config = ReassemblyConfig {
+            record_transitions: true,
+            ..Default::default()
+        };
+        let mut conn: Connection<TestHandler> =
+            Connection::with_config((&syn).into(), Arc::new(config), ()).unwrap();
+        assert!(conn.handle_packet(&syn, &[], &PacketExtra::None));
+        let mut syn_ack = swap_meta(&syn);
+        syn_ack.seq_number = 5000;
+        syn_ack.ack_number = 1001;
+        syn_ack.flags.ack = true;
+        assert!(conn.handle_packet(&syn_ack, &[], &PacketExtra::None));
+        let mut ack = swap_meta(&syn_ack);
+        ack.flags.syn = false;
+        ack.ack_number = 5001;
+        assert!(conn.handle_packet(&ack, b"hello", &PacketExtra::None));
+        let mut rst = swap_meta(&ack);
+        rst.flags.ack = false;
+        rst.flags.rst = true;
+        let extra = PacketExtra::LegacyPcap {
+            index: 3,
+            ts_sec: 1,
+            ts_usec: 0,
+            vlan_id: None,
+            ttl: None,
+            bad_checksum: false,
+            frames: None,
+        };
+        assert!(conn.handle_packet(&rst, &[], &extra));
+        assert_eq!(conn.close_reason, Some(CloseReason::Rst));
+
+        let reasons: Vec<_> = conn.transitions.iter().map(|t| t.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                TransitionReason::Syn,
+                TransitionReason::SynAck,
+                TransitionReason::Ack,
+                TransitionReason::Rst
+            ]
+        );
+        let last = conn.transitions.last().unwrap();
+        assert!(matches!(last.from, ConnectionState::Established { .. }));
+        assert_eq!(last.to, ConnectionState::Closed);
+        assert_eq!(last.packet_index, Some(3));
+        assert_eq!(last.timestamp_us, Some(1_000_000));
+
+        let info = ConnInfo::from_connection(&conn);
+        assert_eq!(info.close_reason, Some(CloseReason::Rst));
+        let forward = info.forward_stats.unwrap();
+        assert_eq!(forward.bytes, 5);
+        assert_eq!(info.reverse_stats.unwrap().rst_count, 1);
+        let serialized = serde_json::to_string(&conn.checkpoint()).unwrap();
+        assert!(serialized.contains(r#""close_reason":"rst""#));
+    }
+
+    #[test]
+    fn state_transitions() {
+        let at = |index: u64| PacketExtra::LegacyPcap {
+            index,
+            ts_sec: 200,
+            ts_usec: index as u32,
+            vlan_id: None,
+            frames: None,
+            ttl: None,
+            bad_checksum: false,
+        };
+        let syn = TcpMeta {
+            src_addr: [10, 6, 0, 1].into(),
+            src_port: 40060,
+            dst_addr: [10, 6, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let run = |config: ReassemblyConfig| {
+            let mut conn: Connection<TestHandler> =
+                Connection::with_config((&syn).into(), Arc::new(config), ()).unwrap();
+            assert!(conn.handle_packet(&syn, &[], &at(0)));
+            let mut syn_ack = swap_meta(&syn);
+            syn_ack.seq_number = 5000;
+            syn_ack.ack_number = 1001;
+            syn_ack.flags.ack = true;
+            assert!(conn.handle_packet(&syn_ack, &[], &at(1)));
+            let mut ack = swap_meta(&syn_ack);
+            ack.flags.syn = false;
+            ack.ack_number = 5001;
+            assert!(conn.handle_packet(&ack, b"hello", &at(2)));
+            let mut fin = ack.clone();
+            fin.seq_number += 5;
+            fin.flags.fin = true;
+            assert!(conn.handle_packet(&fin, &[], &at(3)));
+            let mut fin_ack = swap_meta(&fin);
+            fin_ack.ack_number += 1;
+            assert!(conn.handle_packet(&fin_ack, &[], &at(4)));
+            let mut last_ack = swap_meta(&fin_ack);
+            last_ack.flags.fin = false;
+            last_ack.ack_number += 1;
+            assert!(conn.handle_packet(&last_ack, &[], &at(5)));
+            assert_eq!(conn.conn_state, ConnectionState::Closed);
+            conn
+        };
+
+        let conn = run(ReassemblyConfig {
+            record_transitions: true,
+            ..Default::default()
+        });
+        let steps: Vec<_> = conn
+            .transitions
+            .iter()
+            .map(|t| (t.reason, t.packet_index))
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                (TransitionReason::Syn, Some(0)),
+                (TransitionReason::SynAck, Some(1)),
+                (TransitionReason::Ack, Some(2)),
+                (TransitionReason::Fin, Some(5)),
+            ]
+        );
+        let states = &conn.transitions;
+        assert_eq!(states[0].from, ConnectionState::None);
+        assert!(matches!(states[0].to, ConnectionState::SynSent { .. }));
+        assert_eq!(states[1].from, states[0].to);
+        assert!(matches!(states[1].to, ConnectionState::SynReceived { .. }));
+        assert_eq!(states[2].from, states[1].to);
+        assert_eq!(
+            states[2].to,
+            ConnectionState::Established {
+                forward_isn: 1001,
+                reverse_isn: 5001
+            }
+        );
+        assert_eq!(states[3].from, states[2].to);
+        assert_eq!(states[3].to, ConnectionState::Closed);
+        assert_eq!(states[3].timestamp_us, Some(200_000_005));
+
+        // journal is off by default
+        let conn = run(ReassemblyConfig::default());
+        assert!(conn.transitions.is_empty());
+    }
+
+    #[test]
+    fn paws() {
+        let syn = TcpMeta {
+            src_addr: [10, 3, 0, 7].into(),
+            src_port: 40007,
+            dst_addr: [10, 3, 0, 8].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: Some((100_000, 0)),
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut conn: Connection<TestHandler> = Connection::new((&syn).into(), ()).unwrap();
+        assert!(conn.handle_packet(&syn, &[], &PacketExtra::None));
+        let mut syn_ack = swap_meta(&syn);
+        syn_ack.seq_number = 5000;
+        syn_ack.ack_number = 1001;
+        syn_ack.flags.ack = true;
+        syn_ack.option_timestamp = Some((7000, 100_000));
+        assert!(conn.handle_packet(&syn_ack, &[], &PacketExtra::None));
+        let mut ack = swap_meta(&syn_ack);
+        ack.flags.syn = false;
+        ack.ack_number = 5001;
+        ack.option_timestamp = Some((100_010, 7000));
+        assert!(conn.handle_packet(&ack, b"hello", &PacketExtra::None));
+        assert_eq!(conn.forward_stream.ts_recent, Some(100_010));
+
+        // slightly reordered segment is accepted
+        let mut data = ack.clone();
+        data.seq_number = 1006;
+        data.option_timestamp = Some((100_005, 7000));
+        assert!(conn.handle_packet(&data, b"world", &PacketExtra::None));
+        assert_eq!(conn.forward_stream.ts_recent, Some(100_010));
+
+        // injected segment outside the window does not advance TS.Recent
+        let mut injected = ack.clone();
+        injected.seq_number = 1011u32.wrapping_add(1 << 31);
+        injected.option_timestamp = Some((100_010 + (1 << 30), 7000));
+        conn.handle_packet(&injected, b"bogus", &PacketExtra::None);
+        assert_eq!(conn.forward_stream.ts_recent, Some(100_010));
+        let mut next = ack.clone();
+        next.seq_number = 1011;
+        next.option_timestamp = Some((100_020, 7000));
+        assert!(conn.handle_packet(&next, b"!", &PacketExtra::None));
+        assert_eq!(conn.forward_stream.ts_recent, Some(100_020));
+        assert_eq!(conn.forward_stream.paws_rejected, 0);
+
+        // injected reset with TSval from long ago is dropped
+        let mut rst = swap_meta(&ack);
+        rst.flags.ack = false;
+        rst.flags.rst = true;
+        rst.option_timestamp = Some(((7000u32).wrapping_sub(1 << 24), 0));
+        assert!(!conn.handle_packet(&rst, &[], &PacketExtra::None));
+        assert!(matches!(
+            conn.conn_state,
+            ConnectionState::Established { .. }
+        ));
+        assert_eq!(conn.reverse_stream.paws_rejected, 1);
+        assert_eq!(
+            conn.event_handler.as_ref().unwrap().paws_rejected,
+            [(Direction::Reverse, 7000u32.wrapping_sub(1 << 24), 7000)]
+        );
+    }
+
+    #[test]
+    fn resync() {
+        let syn = TcpMeta {
+            src_addr: [10, 3, 0, 9].into(),
+            src_port: 40009,
+            dst_addr: [10, 3, 0, 10].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut conn: Connection<TestHandler> = Connection::new((&syn).into(), ()).unwrap();
+        assert!(conn.handle_packet(&syn, &[], &PacketExtra::None));
+        let mut syn_ack = swap_meta(&syn);
+        syn_ack.seq_number = 5000;
+        syn_ack.ack_number = 1001;
+        syn_ack.flags.ack = true;
+        assert!(conn.handle_packet(&syn_ack, &[], &PacketExtra::None));
+        let mut ack = swap_meta(&syn_ack);
+        ack.flags.syn = false;
+        ack.ack_number = 5001;
+        assert!(conn.handle_packet(&ack, b"aaaa", &PacketExtra::None));
+
+        // spurious SYN, connection continues with the current numbering
+        let mut spurious = syn.clone();
+        spurious.seq_number = 900_000;
+        assert!(!conn.handle_packet(&spurious, &[], &PacketExtra::None));
+        assert!(conn.resync.is_some());
+        for i in 1..=4 {
+            let mut data = ack.clone();
+            data.seq_number = 1001 + 4 * i;
+            assert!(conn.handle_packet(&data, b"bbbb", &PacketExtra::None));
+        }
+        assert!(conn.resync.is_none());
+        assert_eq!(conn.forward_stream.readable_buffered_length(), 20);
+
+        // connection restarted on the same flow, streams continue
+        let mut restart = syn.clone();
+        restart.seq_number = 70_000;
+        assert!(!conn.handle_packet(&restart, &[], &PacketExtra::None));
+        let mut restart_ack = syn_ack.clone();
+        restart_ack.seq_number = 30_000;
+        restart_ack.ack_number = 70_001;
+        assert!(conn.handle_packet(&restart_ack, &[], &PacketExtra::None));
+        let mut data = swap_meta(&restart_ack);
+        data.flags.syn = false;
+        for _ in 0..4 {
+            assert!(conn.handle_packet(&data, b"cccc", &PacketExtra::None));
+            data.seq_number += 4;
+        }
+        assert!(matches!(
+            conn.conn_state,
+            ConnectionState::Established {
+                forward_isn: 70_001,
+                reverse_isn: 30_001,
+            }
+        ));
+        assert_eq!(conn.forward_stream.readable_buffered_length(), 36);
+        assert_eq!(
+            conn.event_handler.as_ref().unwrap().resync_outcomes,
+            [ResyncOutcome::Kept, ResyncOutcome::Rebased]
+        );
     }
 }
@@ -0,0 +1,265 @@
+//! Content-addressed deduplication of stream data
+//!
+//! Stream data is split into blocks using content-defined chunking (a gear
+//! rolling hash), so identical payloads produce identical blocks regardless of
+//! their offset within the stream. Blocks are between a quarter and four times
+//! the configured block size. Each block is stored
+//! once as `blocks/<hh>/<hash>`, where `<hash>` is the hex BLAKE3 hash of the
+//! block and `<hh>` its first two characters. Instead of data files, each
+//! stream direction gets a manifest (`<uuid>.f.manifest.jsonl`) listing its
+//! blocks in order.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::serialized::ManifestEntry;
+use crate::writer::{WriterFileId, WriterMessage, WriterPool};
+
+/// default size of deduplicated blocks
+pub const DEFAULT_DEDUP_BLOCK_SIZE: usize = 64 << 10;
+
+/// random values for the gear hash, generated with splitmix64
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// store of unique blocks, shared by all connections
+pub struct DedupStore {
+    pub blocks_dir: PathBuf,
+    /// minimum block size
+    pub min_size: usize,
+    /// maximum block size
+    pub max_size: usize,
+    /// hash mask selecting block boundaries
+    pub mask: u64,
+    /// hashes of blocks already written
+    pub seen: Mutex<HashSet<[u8; 32]>>,
+    /// count of blocks processed
+    pub total_blocks: AtomicU64,
+    /// count of blocks written
+    pub unique_blocks: AtomicU64,
+    /// bytes not written because the block already existed
+    pub duplicate_bytes: AtomicU64,
+}
+
+impl DedupStore {
+    /// create block directories under `base_dir`, `block_size` is rounded to
+    /// a power of two
+    pub fn new(base_dir: &Path, block_size: usize) -> std::io::Result<Self> {
+        assert!(block_size >= 64, "block size too small");
+        let block_size = block_size.next_power_of_two();
+        let blocks_dir = base_dir.join("blocks");
+        for prefix in 0..=255u8 {
+            std::fs::create_dir_all(blocks_dir.join(format!("{prefix:02x}")))?;
+        }
+        Ok(DedupStore {
+            blocks_dir,
+            min_size: block_size / 4,
+            max_size: block_size * 4,
+            // boundary on average every block_size bytes past the minimum
+            mask: block_size as u64 - 1,
+            seen: Mutex::new(HashSet::new()),
+            total_blocks: AtomicU64::new(0),
+            unique_blocks: AtomicU64::new(0),
+            duplicate_bytes: AtomicU64::new(0),
+        })
+    }
+
+    /// write block if it was not seen before, returning its hash
+    pub fn store(&self, writer: &WriterPool, block: Vec<u8>) -> blake3::Hash {
+        let hash = blake3::hash(&block);
+        self.total_blocks.fetch_add(1, Ordering::Relaxed);
+        if !self.seen.lock().insert(*hash.as_bytes()) {
+            self.duplicate_bytes
+                .fetch_add(block.len() as u64, Ordering::Relaxed);
+            return hash;
+        }
+        self.unique_blocks.fetch_add(1, Ordering::Relaxed);
+        let hex = hash.to_hex();
+        let id = writer.allocate_id();
+        writer.send(WriterMessage::Create {
+            id,
+            path: self.blocks_dir.join(&hex[..2]).join(hex.as_str()),
+        });
+        writer.send(WriterMessage::Write { id, data: block });
+        writer.send(WriterMessage::Close { id });
+        hash
+    }
+
+    /// log deduplication statistics
+    pub fn log_stats(&self) {
+        info!(
+            "dedup: {} blocks, {} unique, {} duplicate bytes not written",
+            self.total_blocks.load(Ordering::Relaxed),
+            self.unique_blocks.load(Ordering::Relaxed),
+            self.duplicate_bytes.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// block assembly state for one stream direction
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct DedupStream {
+    /// data of the current incomplete block
+    pub buf: Vec<u8>,
+    /// stream offset of the start of `buf`
+    pub offset: u64,
+    /// rolling hash state
+    pub hash: u64,
+    /// scratch buffer for serialized manifest entries
+    #[serde(skip)]
+    pub manifest_buf: Vec<u8>,
+}
+
+impl DedupStream {
+    /// add data at stream offset `offset`, writing completed blocks
+    pub fn push(
+        &mut self,
+        store: &DedupStore,
+        writer: &WriterPool,
+        manifest: WriterFileId,
+        offset: u64,
+        mut data: &[u8],
+    ) {
+        if self.buf.is_empty() {
+            self.offset = offset;
+        }
+        debug_assert_eq!(self.offset + self.buf.len() as u64, offset);
+        while !data.is_empty() {
+            // skip hashing bytes before the minimum size
+            let skip = usize::min(store.min_size.saturating_sub(self.buf.len()), data.len());
+            self.buf.extend_from_slice(&data[..skip]);
+            data = &data[skip..];
+            let limit = usize::min(store.max_size - self.buf.len(), data.len());
+            let mut cut = None;
+            for (i, &b) in data[..limit].iter().enumerate() {
+                self.hash = (self.hash << 1).wrapping_add(GEAR[b as usize]);
+                if self.hash & store.mask == 0 {
+                    cut = Some(i + 1);
+                    break;
+                }
+            }
+            let take = cut.unwrap_or(limit);
+            self.buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if cut.is_some() || self.buf.len() == store.max_size {
+                self.write_block(store, writer);
+            }
+        }
+        self.send_manifest(writer, manifest);
+    }
+
+    /// write remaining partial block
+    pub fn finish(&mut self, store: &DedupStore, writer: &WriterPool, manifest: WriterFileId) {
+        if !self.buf.is_empty() {
+            self.write_block(store, writer);
+        }
+        self.send_manifest(writer, manifest);
+    }
+
+    fn write_block(&mut self, store: &DedupStore, writer: &WriterPool) {
+        let block = std::mem::replace(&mut self.buf, Vec::with_capacity(store.min_size * 2));
+        self.hash = 0;
+        let offset = self.offset;
+        let len = block.len();
+        self.offset += len as u64;
+        let entry = ManifestEntry {
+            offset,
+            len,
+            hash: store.store(writer, block).to_hex().to_string(),
+        };
+        serde_json::to_writer(&mut self.manifest_buf, &entry)
+            .expect("failed to serialize ManifestEntry");
+        self.manifest_buf.push(b'\n');
+    }
+
+    fn send_manifest(&mut self, writer: &WriterPool, manifest: WriterFileId) {
+        if !self.manifest_buf.is_empty() {
+            writer.send(WriterMessage::Write {
+                id: manifest,
+                data: std::mem::take(&mut self.manifest_buf),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use super::{DedupStore, DedupStream};
+    use crate::serialized::ManifestEntry;
+    use crate::writer::{WriterMessage, WriterPool};
+
+    #[test]
+    fn shifted_payload() {
+        let dir = std::env::temp_dir().join(format!("parse-tcp-dedup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (errors_tx, errors_rx) = crossbeam_channel::unbounded();
+        let pool = WriterPool::new(1, 64, errors_tx).unwrap();
+        let store = DedupStore::new(&dir, 4096).unwrap();
+
+        // same pseudorandom payload behind headers of different lengths
+        let mut state = 1u32;
+        let payload: Vec<u8> = (0..256 << 10)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        for (i, header) in [&b"short header"[..], b"a somewhat longer header"]
+            .iter()
+            .enumerate()
+        {
+            let id = pool.allocate_id();
+            let path = dir.join(format!("{i}.manifest.jsonl"));
+            pool.send(WriterMessage::Create { id, path });
+            let mut stream = DedupStream::default();
+            stream.push(&store, &pool, id, 0, header);
+            // split writes should not affect block boundaries
+            let mut offset = header.len() as u64;
+            for part in payload.chunks(10000) {
+                stream.push(&store, &pool, id, offset, part);
+                offset += part.len() as u64;
+            }
+            stream.finish(&store, &pool, id);
+            pool.send(WriterMessage::Close { id });
+        }
+        pool.close();
+        assert!(errors_rx.try_recv().is_err());
+
+        let duplicate = store.duplicate_bytes.load(Ordering::Relaxed);
+        assert!(duplicate > payload.len() as u64 * 9 / 10, "{duplicate}");
+        // manifest reconstructs the stream
+        let manifest = std::fs::read_to_string(dir.join("1.manifest.jsonl")).unwrap();
+        let mut data = Vec::new();
+        for line in manifest.lines() {
+            let entry: ManifestEntry = serde_json::from_str(line).unwrap();
+            assert_eq!(entry.offset, data.len() as u64);
+            let block =
+                std::fs::read(store.blocks_dir.join(&entry.hash[..2]).join(&entry.hash)).unwrap();
+            assert_eq!(block.len(), entry.len);
+            data.extend_from_slice(&block);
+        }
+        assert_eq!(&data[24..], payload);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+//! Connection direction inference
+//!
+//! Without an observed handshake, whoever sent the first captured packet is
+//! assumed to be the client, which is wrong whenever the capture starts with a
+//! packet from the server. For such connections, evidence about which side is
+//! the client is collected over the first packets:
+//! - ports: servers tend to use well-known or registered ports, clients
+//!   ephemeral ones
+//! - payload: requests (HTTP methods, TLS ClientHello) come from clients,
+//!   responses and greetings from servers
+//! - sequence activity: servers usually send more data than clients
+//! - window behavior: the side receiving bulk data (usually the client)
+//!   advertises larger windows
+//!
+//! If the evidence favors the opposite labeling, the connection is reversed
+//! and handlers are notified with `ConnectionHandler::direction_changed`.
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::classify::{classify_port, client_hint};
+use crate::connection::Direction;
+use crate::flow_table::Flow;
+use crate::TcpMeta;
+
+/// default number of packets evidence is collected for
+pub const DIRECTION_INFERENCE_PACKETS: u32 = 16;
+/// score below which the current labeling is considered wrong
+pub const DIRECTION_REVISE_THRESHOLD: i32 = -2;
+
+/// how likely a port belongs to a server
+pub fn port_rank(port: u16) -> i32 {
+    if port < 1024 || classify_port(port).is_some() {
+        2
+    } else if port < 32768 {
+        // registered ports, below common ephemeral ranges
+        1
+    } else {
+        0
+    }
+}
+
+/// evidence about which side of a connection is the client
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectionInference {
+    /// whether evidence is still being collected
+    pub active: bool,
+    /// packets observed so far
+    pub packets: u32,
+    /// payload signatures suggesting the forward side is the client (positive)
+    /// or the server (negative)
+    pub payload_score: i32,
+    /// largest unscaled window advertised (forward, reverse)
+    pub max_window: [u16; 2],
+    /// number of times the direction was revised
+    pub revisions: u32,
+}
+
+impl DirectionInference {
+    /// record packet sent in direction
+    pub fn observe(&mut self, direction: Direction, meta: &TcpMeta, data: &[u8]) {
+        self.packets += 1;
+        let idx = match direction {
+            Direction::Forward => 0,
+            Direction::Reverse => 1,
+        };
+        self.max_window[idx] = u16::max(self.max_window[idx], meta.window);
+        if let Some(is_client) = client_hint(data) {
+            if is_client == (direction == Direction::Forward) {
+                self.payload_score += 1;
+            } else {
+                self.payload_score -= 1;
+            }
+        }
+    }
+
+    /// score of the current labeling, positive if the forward side looks like
+    /// the client
+    pub fn score(&self, forward_flow: &Flow, forward_bytes: u64, reverse_bytes: u64) -> i32 {
+        let mut score = 2 * (port_rank(forward_flow.dst_port) - port_rank(forward_flow.src_port));
+        // payload signatures are the strongest evidence
+        score += 4 * self.payload_score.signum();
+        if reverse_bytes > forward_bytes.saturating_mul(2) {
+            score += 1;
+        } else if forward_bytes > reverse_bytes.saturating_mul(2) {
+            score -= 1;
+        }
+        match self.max_window[0].cmp(&self.max_window[1]) {
+            Ordering::Greater => score += 1,
+            Ordering::Less => score -= 1,
+            Ordering::Equal => {}
+        }
+        score
+    }
+
+    /// whether the current labeling should be reversed
+    pub fn should_reverse(
+        &self,
+        forward_flow: &Flow,
+        forward_bytes: u64,
+        reverse_bytes: u64,
+    ) -> bool {
+        self.score(forward_flow, forward_bytes, reverse_bytes) <= DIRECTION_REVISE_THRESHOLD
+    }
+
+    /// update evidence after the connection was reversed
+    pub fn reversed(&mut self) {
+        self.payload_score = -self.payload_score;
+        self.max_window.swap(0, 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{port_rank, DirectionInference};
+    use crate::connection::Direction;
+    use crate::flow_table::{Flow, IPPROTO_TCP};
+    use crate::{TcpFlags, TcpMeta};
+
+    #[test]
+    fn scoring() {
+        let flow = Flow {
+            proto: IPPROTO_TCP,
+            src_addr: [10, 0, 0, 2].into(),
+            src_port: 443,
+            dst_addr: [10, 0, 0, 1].into(),
+            dst_port: 51000,
+        };
+        assert!(port_rank(443) > port_rank(8999));
+        assert!(port_rank(8999) > port_rank(51000));
+
+        let mut inference = DirectionInference::default();
+        // ports alone suggest the forward side is the server
+        assert!(inference.should_reverse(&flow, 0, 0));
+        // client request sent by the forward side outweighs ports
+        let meta = TcpMeta {
+            src_addr: flow.src_addr,
+            src_port: flow.src_port,
+            dst_addr: flow.dst_addr,
+            dst_port: flow.dst_port,
+            seq_number: 1,
+            ack_number: 1,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 100,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        inference.observe(Direction::Forward, &meta, b"GET / HTTP/1.1\r\n");
+        assert_eq!(inference.payload_score, 1);
+        assert!(!inference.should_reverse(&flow, 16, 0));
+
+        inference.reversed();
+        assert_eq!(inference.payload_score, -1);
+        assert_eq!(inference.max_window, [0, 100]);
+    }
+}
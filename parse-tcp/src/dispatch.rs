@@ -0,0 +1,834 @@
+//! Per-connection handler selection
+//!
+//! `ConnectionHandler` is generic over its own connection type and cannot be
+//! used as a trait object. `DispatchHandler` is a regular handler which owns a
+//! `DynConnectionHandler` chosen for each connection by a `HandlerFactory`.
+//! Any existing handler can be wrapped with `HandlerAdapter`, which runs it
+//! against a scratch connection whose state is swapped in for every call.
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use kinesin_rdt::stream::inbound::OverlapConflict;
+
+use crate::connection::{Connection, Direction};
+use crate::flow_table::Flow;
+use crate::handshake::HandshakeEvent;
+use crate::matching::StreamMatch;
+use crate::resync::ResyncOutcome;
+use crate::serialized::PacketExtra;
+use crate::stream::LateData;
+use crate::ConnectionHandler;
+
+/// object-safe counterpart of ConnectionHandler for dispatched connections
+pub trait DynConnectionHandler {
+    /// see `ConnectionHandler::packet_received`
+    fn packet_received(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        extra: &PacketExtra,
+    );
+    /// see `ConnectionHandler::handshake_done`
+    fn handshake_done(&mut self, connection: &mut Connection<DispatchHandler>);
+    /// see `ConnectionHandler::handshake_event`
+    fn handshake_event(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        event: HandshakeEvent,
+    );
+    /// see `ConnectionHandler::data_received`
+    fn data_received(&mut self, connection: &mut Connection<DispatchHandler>, direction: Direction);
+    /// see `ConnectionHandler::ack_received`
+    fn ack_received(&mut self, connection: &mut Connection<DispatchHandler>, direction: Direction);
+    /// see `ConnectionHandler::fin_received`
+    fn fin_received(&mut self, connection: &mut Connection<DispatchHandler>, direction: Direction);
+    /// see `ConnectionHandler::rst_received`
+    fn rst_received(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        extra: PacketExtra,
+    );
+    /// see `ConnectionHandler::stream_end`
+    fn stream_end(&mut self, connection: &mut Connection<DispatchHandler>, direction: Direction);
+    /// see `ConnectionHandler::connection_desync`
+    fn connection_desync(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+    );
+    /// see `ConnectionHandler::connection_resync`
+    fn connection_resync(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        outcome: ResyncOutcome,
+    );
+    /// see `ConnectionHandler::gap_detected`
+    fn gap_detected(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        range: Range<u64>,
+    );
+    /// see `ConnectionHandler::overlap_conflict`
+    fn overlap_conflict(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        conflict: OverlapConflict,
+    );
+    /// see `ConnectionHandler::late_data`
+    fn late_data(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        late: &LateData,
+    );
+    /// see `ConnectionHandler::stream_match`
+    fn stream_match(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        found: StreamMatch,
+    );
+    /// see `ConnectionHandler::paws_rejected`
+    fn paws_rejected(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        tsval: u32,
+        ts_recent: u32,
+    );
+    /// see `ConnectionHandler::ttl_anomaly`
+    fn ttl_anomaly(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        expected: u8,
+        observed: u8,
+    );
+    /// see `ConnectionHandler::memory_pressure`
+    fn memory_pressure(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+    );
+    /// see `ConnectionHandler::direction_changed`
+    fn direction_changed(&mut self, connection: &mut Connection<DispatchHandler>);
+    /// see `ConnectionHandler::will_retire`
+    fn will_retire(&mut self, connection: &mut Connection<DispatchHandler>);
+    /// see `ConnectionHandler::checkpoint_state`
+    fn checkpoint_state(&self) -> Option<serde_json::Value>;
+    /// see `ConnectionHandler::restored`
+    fn restored(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        state: Option<serde_json::Value>,
+    );
+}
+
+/// runs a ConnectionHandler as a DynConnectionHandler
+pub struct HandlerAdapter<H: ConnectionHandler> {
+    pub handler: H,
+    /// connection the handler sees, holds the dispatched connection's state
+    /// only during calls
+    pub scratch: Connection<H>,
+}
+
+impl<H: ConnectionHandler> HandlerAdapter<H> {
+    /// construct handler for dispatched connection
+    pub fn new(
+        init_data: H::InitialData,
+        connection: &mut Connection<DispatchHandler>,
+    ) -> Result<Self, H::ConstructError> {
+        let mut scratch =
+            Connection::without_handler(connection.forward_flow.clone(), connection.config.clone());
+        connection.swap_state(&mut scratch);
+        let result = H::new(init_data, &mut scratch);
+        connection.swap_state(&mut scratch);
+        Ok(HandlerAdapter {
+            handler: result?,
+            scratch,
+        })
+    }
+
+    /// call handler with the state of the dispatched connection
+    fn call(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        do_thing: impl FnOnce(&mut H, &mut Connection<H>),
+    ) {
+        connection.swap_state(&mut self.scratch);
+        do_thing(&mut self.handler, &mut self.scratch);
+        connection.swap_state(&mut self.scratch);
+    }
+}
+
+impl<H: ConnectionHandler> DynConnectionHandler for HandlerAdapter<H> {
+    fn packet_received(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        extra: &PacketExtra,
+    ) {
+        self.call(connection, |h, conn| {
+            h.packet_received(conn, direction, extra)
+        });
+    }
+
+    fn handshake_done(&mut self, connection: &mut Connection<DispatchHandler>) {
+        self.call(connection, |h, conn| h.handshake_done(conn));
+    }
+
+    fn handshake_event(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        event: HandshakeEvent,
+    ) {
+        self.call(connection, |h, conn| {
+            h.handshake_event(conn, direction, event)
+        });
+    }
+
+    fn data_received(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+    ) {
+        self.call(connection, |h, conn| h.data_received(conn, direction));
+    }
+
+    fn ack_received(&mut self, connection: &mut Connection<DispatchHandler>, direction: Direction) {
+        self.call(connection, |h, conn| h.ack_received(conn, direction));
+    }
+
+    fn fin_received(&mut self, connection: &mut Connection<DispatchHandler>, direction: Direction) {
+        self.call(connection, |h, conn| h.fin_received(conn, direction));
+    }
+
+    fn rst_received(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        extra: PacketExtra,
+    ) {
+        self.call(connection, |h, conn| h.rst_received(conn, direction, extra));
+    }
+
+    fn stream_end(&mut self, connection: &mut Connection<DispatchHandler>, direction: Direction) {
+        self.call(connection, |h, conn| h.stream_end(conn, direction));
+    }
+
+    fn connection_desync(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+    ) {
+        self.call(connection, |h, conn| h.connection_desync(conn, direction));
+    }
+
+    fn connection_resync(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        outcome: ResyncOutcome,
+    ) {
+        self.call(connection, |h, conn| {
+            h.connection_resync(conn, direction, outcome)
+        });
+    }
+
+    fn gap_detected(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        range: Range<u64>,
+    ) {
+        self.call(connection, |h, conn| h.gap_detected(conn, direction, range));
+    }
+
+    fn overlap_conflict(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        conflict: OverlapConflict,
+    ) {
+        self.call(connection, |h, conn| {
+            h.overlap_conflict(conn, direction, conflict)
+        });
+    }
+
+    fn late_data(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        late: &LateData,
+    ) {
+        self.call(connection, |h, conn| h.late_data(conn, direction, late));
+    }
+
+    fn stream_match(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        found: StreamMatch,
+    ) {
+        self.call(connection, |h, conn| h.stream_match(conn, direction, found));
+    }
+
+    fn paws_rejected(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        tsval: u32,
+        ts_recent: u32,
+    ) {
+        self.call(connection, |h, conn| {
+            h.paws_rejected(conn, direction, tsval, ts_recent)
+        });
+    }
+
+    fn ttl_anomaly(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+        expected: u8,
+        observed: u8,
+    ) {
+        self.call(connection, |h, conn| {
+            h.ttl_anomaly(conn, direction, expected, observed)
+        });
+    }
+
+    fn memory_pressure(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        direction: Direction,
+    ) {
+        self.call(connection, |h, conn| h.memory_pressure(conn, direction));
+    }
+
+    fn direction_changed(&mut self, connection: &mut Connection<DispatchHandler>) {
+        self.call(connection, |h, conn| h.direction_changed(conn));
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<DispatchHandler>) {
+        self.call(connection, |h, conn| h.will_retire(conn));
+    }
+
+    fn checkpoint_state(&self) -> Option<serde_json::Value> {
+        self.handler.checkpoint_state()
+    }
+
+    fn restored(
+        &mut self,
+        connection: &mut Connection<DispatchHandler>,
+        state: Option<serde_json::Value>,
+    ) {
+        self.call(connection, |h, conn| h.restored(conn, state));
+    }
+}
+
+/// chooses the handler for each new connection
+pub trait HandlerFactory: Send + Sync {
+    /// create handler for connection
+    fn create(
+        &self,
+        connection: &mut Connection<DispatchHandler>,
+    ) -> eyre::Result<Box<dyn DynConnectionHandler>>;
+}
+
+/// constructor of a wrapped handler
+pub type HandlerConstructor = Box<
+    dyn Fn(&mut Connection<DispatchHandler>) -> eyre::Result<Box<dyn DynConnectionHandler>>
+        + Send
+        + Sync,
+>;
+
+/// create constructor for handler `H` from its initial data
+pub fn handler_constructor<H>(init_data: H::InitialData) -> HandlerConstructor
+where
+    H: ConnectionHandler + 'static,
+    H::InitialData: Clone + Send + Sync + 'static,
+    H::ConstructError: Into<eyre::Report>,
+{
+    Box::new(move |connection| {
+        let adapter =
+            HandlerAdapter::<H>::new(init_data.clone(), connection).map_err(Into::into)?;
+        Ok(Box::new(adapter))
+    })
+}
+
+/// predicate selecting flows for a route
+pub type FlowPredicate = Box<dyn Fn(&Flow) -> bool + Send + Sync>;
+
+/// HandlerFactory choosing by flow, first matching route wins
+pub struct FlowDispatch {
+    pub routes: Vec<(FlowPredicate, HandlerConstructor)>,
+    /// used for flows not matching any route
+    pub default: HandlerConstructor,
+}
+
+impl FlowDispatch {
+    /// create with handler `H` for flows not matching any route
+    pub fn new<H>(init_data: H::InitialData) -> Self
+    where
+        H: ConnectionHandler + 'static,
+        H::InitialData: Clone + Send + Sync + 'static,
+        H::ConstructError: Into<eyre::Report>,
+    {
+        FlowDispatch {
+            routes: Vec::new(),
+            default: handler_constructor::<H>(init_data),
+        }
+    }
+
+    /// use handler `H` for flows matching predicate
+    pub fn route<H>(
+        mut self,
+        predicate: impl Fn(&Flow) -> bool + Send + Sync + 'static,
+        init_data: H::InitialData,
+    ) -> Self
+    where
+        H: ConnectionHandler + 'static,
+        H::InitialData: Clone + Send + Sync + 'static,
+        H::ConstructError: Into<eyre::Report>,
+    {
+        self.routes
+            .push((Box::new(predicate), handler_constructor::<H>(init_data)));
+        self
+    }
+
+    /// use handler `H` for flows with either port in `ports`
+    pub fn route_ports<H>(self, ports: &[u16], init_data: H::InitialData) -> Self
+    where
+        H: ConnectionHandler + 'static,
+        H::InitialData: Clone + Send + Sync + 'static,
+        H::ConstructError: Into<eyre::Report>,
+    {
+        let ports = ports.to_vec();
+        self.route::<H>(
+            move |flow| ports.contains(&flow.src_port) || ports.contains(&flow.dst_port),
+            init_data,
+        )
+    }
+}
+
+impl HandlerFactory for FlowDispatch {
+    fn create(
+        &self,
+        connection: &mut Connection<DispatchHandler>,
+    ) -> eyre::Result<Box<dyn DynConnectionHandler>> {
+        let constructor = self
+            .routes
+            .iter()
+            .find(|(predicate, _)| predicate(&connection.forward_flow))
+            .map_or(&self.default, |(_, constructor)| constructor);
+        constructor(connection)
+    }
+}
+
+/// ConnectionHandler delegating to a handler chosen per connection
+pub struct DispatchHandler {
+    pub inner: Box<dyn DynConnectionHandler>,
+}
+
+impl ConnectionHandler for DispatchHandler {
+    type InitialData = Arc<dyn HandlerFactory>;
+    type ConstructError = eyre::Report;
+    fn new(factory: Self::InitialData, connection: &mut Connection<Self>) -> eyre::Result<Self> {
+        Ok(DispatchHandler {
+            inner: factory.create(connection)?,
+        })
+    }
+
+    fn packet_received(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        extra: &PacketExtra,
+    ) {
+        self.inner.packet_received(connection, direction, extra);
+    }
+
+    fn handshake_done(&mut self, connection: &mut Connection<Self>) {
+        self.inner.handshake_done(connection);
+    }
+
+    fn handshake_event(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        event: HandshakeEvent,
+    ) {
+        self.inner.handshake_event(connection, direction, event);
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        self.inner.data_received(connection, direction);
+    }
+
+    fn ack_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        self.inner.ack_received(connection, direction);
+    }
+
+    fn fin_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        self.inner.fin_received(connection, direction);
+    }
+
+    fn rst_received(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        extra: PacketExtra,
+    ) {
+        self.inner.rst_received(connection, direction, extra);
+    }
+
+    fn stream_end(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        self.inner.stream_end(connection, direction);
+    }
+
+    fn connection_desync(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        self.inner.connection_desync(connection, direction);
+    }
+
+    fn connection_resync(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        outcome: ResyncOutcome,
+    ) {
+        self.inner.connection_resync(connection, direction, outcome);
+    }
+
+    fn gap_detected(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        range: Range<u64>,
+    ) {
+        self.inner.gap_detected(connection, direction, range);
+    }
+
+    fn overlap_conflict(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        conflict: OverlapConflict,
+    ) {
+        self.inner.overlap_conflict(connection, direction, conflict);
+    }
+
+    fn late_data(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        late: &LateData,
+    ) {
+        self.inner.late_data(connection, direction, late);
+    }
+
+    fn stream_match(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        found: StreamMatch,
+    ) {
+        self.inner.stream_match(connection, direction, found);
+    }
+
+    fn paws_rejected(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        tsval: u32,
+        ts_recent: u32,
+    ) {
+        self.inner
+            .paws_rejected(connection, direction, tsval, ts_recent);
+    }
+
+    fn ttl_anomaly(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        expected: u8,
+        observed: u8,
+    ) {
+        self.inner
+            .ttl_anomaly(connection, direction, expected, observed);
+    }
+
+    fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        self.inner.memory_pressure(connection, direction);
+    }
+
+    fn direction_changed(&mut self, connection: &mut Connection<Self>) {
+        self.inner.direction_changed(connection);
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        self.inner.will_retire(connection);
+    }
+
+    fn checkpoint_state(&self) -> Option<serde_json::Value> {
+        self.inner.checkpoint_state()
+    }
+
+    fn restored(&mut self, connection: &mut Connection<Self>, state: Option<serde_json::Value>) {
+        self.inner.restored(connection, state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::{DispatchHandler, FlowDispatch, HandlerFactory};
+    use crate::connection::{Connection, Direction};
+    use crate::flow_table::FlowTable;
+    use crate::serialized::PacketExtra;
+    use crate::{ConnectionHandler, TcpFlags, TcpMeta};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// consumes readable data, logging it with a name
+    struct NamedHandler<const N: char> {
+        log: Log,
+    }
+
+    impl<const N: char> ConnectionHandler for NamedHandler<N> {
+        type InitialData = Log;
+        type ConstructError = Infallible;
+        fn new(log: Log, connection: &mut Connection<Self>) -> Result<Self, Infallible> {
+            log.lock()
+                .push(format!("{N} new {}", connection.forward_flow.dst_port));
+            Ok(NamedHandler { log })
+        }
+
+        fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+            let stream = connection.get_stream(direction);
+            let len = stream.readable_buffered_length();
+            let end_offset = stream.buffer_start() + len as u64;
+            let (a, b) = stream.read_buffer_until(end_offset).unwrap().as_slices();
+            let mut data = a.to_vec();
+            data.extend_from_slice(b.unwrap_or_default());
+            stream.consume_until(end_offset);
+            self.log.lock().push(format!(
+                "{N} {direction} {}",
+                String::from_utf8_lossy(&data)
+            ));
+        }
+
+        fn paws_rejected(
+            &mut self,
+            _connection: &mut Connection<Self>,
+            direction: Direction,
+            tsval: u32,
+            ts_recent: u32,
+        ) {
+            self.log
+                .lock()
+                .push(format!("{N} {direction} paws {tsval} {ts_recent}"));
+        }
+
+        fn ttl_anomaly(
+            &mut self,
+            _connection: &mut Connection<Self>,
+            direction: Direction,
+            expected: u8,
+            observed: u8,
+        ) {
+            self.log
+                .lock()
+                .push(format!("{N} {direction} ttl {expected} {observed}"));
+        }
+    }
+
+    #[test]
+    fn dispatch_by_port() {
+        let log = Log::default();
+        let dispatch = FlowDispatch::new::<NamedHandler<'b'>>(log.clone())
+            .route_ports::<NamedHandler<'a'>>(&[80], log.clone());
+        let factory: Arc<dyn HandlerFactory> = Arc::new(dispatch);
+        let mut table: FlowTable<DispatchHandler> = FlowTable::new(factory);
+
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 500,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        table
+            .handle_packet(&meta, b"hello", &PacketExtra::None)
+            .unwrap();
+        meta.dst_port = 443;
+        table
+            .handle_packet(&meta, b"world", &PacketExtra::None)
+            .unwrap();
+
+        assert_eq!(
+            *log.lock(),
+            [
+                "a new 80",
+                "a forward hello",
+                "b new 443",
+                "b forward world"
+            ]
+        );
+        // data consumed by the wrapped handlers is gone from the connections
+        for conn in table.map.values() {
+            assert_eq!(conn.forward_stream.buffer_start(), 5);
+            assert_eq!(conn.forward_stream.total_buffered_length(), 0);
+        }
+        table.close();
+    }
+
+    #[test]
+    fn forwards_ttl_anomaly() {
+        let log = Log::default();
+        let dispatch = FlowDispatch::new::<NamedHandler<'a'>>(log.clone());
+        let factory: Arc<dyn HandlerFactory> = Arc::new(dispatch);
+        let mut table: FlowTable<DispatchHandler> = FlowTable::new(factory);
+
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 500,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        for (ttl, data) in [(64, b"a"), (64, b"b"), (64, b"c"), (128, b"d")] {
+            let extra = PacketExtra::LegacyPcap {
+                index: 0,
+                ts_sec: 0,
+                ts_usec: 0,
+                vlan_id: None,
+                frames: None,
+                ttl: Some(ttl),
+                bad_checksum: false,
+            };
+            table.handle_packet(&meta, data, &extra).unwrap();
+            meta.seq_number += 1;
+        }
+
+        assert_eq!(log.lock().last().unwrap(), "a forward ttl 64 128");
+        table.close();
+    }
+
+    #[test]
+    fn forwards_paws_rejected() {
+        let log = Log::default();
+        let dispatch = FlowDispatch::new::<NamedHandler<'a'>>(log.clone());
+        let factory: Arc<dyn HandlerFactory> = Arc::new(dispatch);
+        let mut table: FlowTable<DispatchHandler> = FlowTable::new(factory);
+
+        let syn = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: Some((100_000, 0)),
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let syn_ack = TcpMeta {
+            src_addr: syn.dst_addr,
+            src_port: syn.dst_port,
+            dst_addr: syn.src_addr,
+            dst_port: syn.src_port,
+            seq_number: 500,
+            ack_number: 101,
+            flags: TcpFlags {
+                syn: true,
+                ack: true,
+                ..Default::default()
+            },
+            option_timestamp: Some((7000, 100_000)),
+            ..syn.clone()
+        };
+        let ack = TcpMeta {
+            seq_number: 101,
+            ack_number: 501,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            option_timestamp: Some((100_010, 7000)),
+            ..syn.clone()
+        };
+        for meta in [&syn, &syn_ack, &ack] {
+            table.handle_packet(meta, &[], &PacketExtra::None).unwrap();
+        }
+        // segment with TSval from long ago is dropped
+        let mut old = ack.clone();
+        old.option_timestamp = Some((100_010u32.wrapping_sub(1 << 24), 7000));
+        table
+            .handle_packet(&old, b"bogus", &PacketExtra::None)
+            .unwrap();
+
+        assert_eq!(
+            log.lock().last().unwrap(),
+            &format!("a forward paws {} 100000", 100_010u32.wrapping_sub(1 << 24))
+        );
+        table.close();
+    }
+}
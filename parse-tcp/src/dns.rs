@@ -0,0 +1,684 @@
+//! DNS over TCP message extraction
+//!
+//! Each message on a TCP DNS connection is prefixed with its length as a
+//! 16-bit big-endian integer. `DnsExtractHandler` reassembles messages from
+//! connections classified as DNS and writes each decoded message as a line of
+//! `dns.jsonl`. A direction is no longer parsed after data was lost, as message
+//! boundaries cannot be recovered.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, trace};
+use uuid::Uuid;
+
+use crate::classify::AppProtocol;
+use crate::connection::{Connection, Direction};
+use crate::stream::{SegmentInfo, SegmentType, StreamGap};
+use crate::ConnectionHandler;
+
+/// length of the message header
+const HEADER_LENGTH: usize = 12;
+/// max compression pointers followed while reading a name
+const MAX_POINTERS: usize = 32;
+
+/// decoded DNS message
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsMessage {
+    /// id chosen by the client
+    pub transaction_id: u16,
+    pub response: bool,
+    pub opcode: u8,
+    pub rcode: String,
+    /// authoritative answer
+    pub aa: bool,
+    /// truncated
+    pub tc: bool,
+    /// recursion desired
+    pub rd: bool,
+    /// recursion available
+    pub ra: bool,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authority: Vec<DnsRecord>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional: Vec<DnsRecord>,
+}
+
+/// entry of the question section
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsQuestion {
+    pub qname: String,
+    pub qtype: String,
+    pub qclass: u16,
+}
+
+/// resource record
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsRecord {
+    pub name: String,
+    pub rtype: String,
+    pub class: u16,
+    pub ttl: u32,
+    /// record data in presentation format, or hex if not understood
+    pub data: String,
+}
+
+/// line of dns.jsonl
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DnsMessageInfo {
+    /// connection id
+    pub id: Uuid,
+    pub direction: Direction,
+    /// stream offset of the length prefix
+    pub offset: u64,
+    /// capture time of the segment starting the message (microseconds since
+    /// epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_us: Option<u64>,
+    #[serde(flatten)]
+    pub message: DnsMessage,
+}
+
+/// name of record type, in RFC 3597 form if unknown
+pub fn type_name(rtype: u16) -> String {
+    let name = match rtype {
+        1 => "A",
+        2 => "NS",
+        5 => "CNAME",
+        6 => "SOA",
+        12 => "PTR",
+        15 => "MX",
+        16 => "TXT",
+        28 => "AAAA",
+        33 => "SRV",
+        39 => "DNAME",
+        41 => "OPT",
+        43 => "DS",
+        46 => "RRSIG",
+        47 => "NSEC",
+        48 => "DNSKEY",
+        64 => "SVCB",
+        65 => "HTTPS",
+        251 => "IXFR",
+        252 => "AXFR",
+        255 => "ANY",
+        257 => "CAA",
+        _ => return format!("TYPE{rtype}"),
+    };
+    name.to_string()
+}
+
+/// name of response code
+pub fn rcode_name(rcode: u8) -> String {
+    let name = match rcode {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => return format!("RCODE{rcode}"),
+    };
+    name.to_string()
+}
+
+/// big-endian reader over a message, keeping the whole message for
+/// compression pointers
+struct Reader<'a> {
+    message: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let data = self.message.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(data)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// read possibly compressed domain name
+    fn name(&mut self) -> Option<String> {
+        let mut name = String::new();
+        let mut pos = self.pos;
+        let mut pointers = 0;
+        loop {
+            let len = *self.message.get(pos)? as usize;
+            match len & 0xc0 {
+                0xc0 => {
+                    let low = *self.message.get(pos + 1)? as usize;
+                    if pointers == 0 {
+                        self.pos = pos + 2;
+                    }
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return None;
+                    }
+                    pos = (len & 0x3f) << 8 | low;
+                }
+                0 if len == 0 => {
+                    if pointers == 0 {
+                        self.pos = pos + 1;
+                    }
+                    break;
+                }
+                0 => {
+                    let label = self.message.get(pos + 1..pos + 1 + len)?;
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    for &b in label {
+                        if b.is_ascii_graphic() && b != b'.' && b != b'\\' {
+                            name.push(b as char);
+                        } else {
+                            let _ = write!(name, "\\{b:03}");
+                        }
+                    }
+                    pos += 1 + len;
+                }
+                // extended label types are obsolete
+                _ => return None,
+            }
+        }
+        if name.is_empty() {
+            name.push('.');
+        }
+        Some(name)
+    }
+
+    fn question(&mut self) -> Option<DnsQuestion> {
+        Some(DnsQuestion {
+            qname: self.name()?,
+            qtype: type_name(self.u16()?),
+            qclass: self.u16()?,
+        })
+    }
+
+    fn record(&mut self) -> Option<DnsRecord> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let start = self.pos;
+        let rdata = self.bytes(len)?;
+        // names in record data may point anywhere in the message
+        let mut inner = Reader {
+            message: &self.message[..start + len],
+            pos: start,
+        };
+        let data = inner
+            .record_data(rtype)
+            .filter(|_| inner.pos == start + len)
+            .unwrap_or_else(|| hex(rdata));
+        Some(DnsRecord {
+            name,
+            rtype: type_name(rtype),
+            class,
+            ttl,
+            data,
+        })
+    }
+
+    /// format record data of known types
+    fn record_data(&mut self, rtype: u16) -> Option<String> {
+        let data = match rtype {
+            1 => {
+                let b: [u8; 4] = self.bytes(4)?.try_into().ok()?;
+                Ipv4Addr::from(b).to_string()
+            }
+            28 => {
+                let b: [u8; 16] = self.bytes(16)?.try_into().ok()?;
+                Ipv6Addr::from(b).to_string()
+            }
+            2 | 5 | 12 | 39 => self.name()?,
+            15 => format!("{} {}", self.u16()?, self.name()?),
+            16 => {
+                let mut strings = Vec::new();
+                while self.pos < self.message.len() {
+                    let len = self.u8()? as usize;
+                    let string = String::from_utf8_lossy(self.bytes(len)?);
+                    strings.push(format!("{string:?}"));
+                }
+                strings.join(" ")
+            }
+            6 => format!(
+                "{} {} {} {} {} {} {}",
+                self.name()?,
+                self.name()?,
+                self.u32()?,
+                self.u32()?,
+                self.u32()?,
+                self.u32()?,
+                self.u32()?
+            ),
+            33 => format!(
+                "{} {} {} {}",
+                self.u16()?,
+                self.u16()?,
+                self.u16()?,
+                self.name()?
+            ),
+            _ => return None,
+        };
+        Some(data)
+    }
+}
+
+/// format bytes as lowercase hex
+fn hex(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() * 2);
+    for b in data {
+        let _ = write!(out, "{b:02x}");
+    }
+    out
+}
+
+/// parse a DNS message (without length prefix)
+pub fn parse_message(message: &[u8]) -> Option<DnsMessage> {
+    if message.len() < HEADER_LENGTH {
+        return None;
+    }
+    let mut r = Reader { message, pos: 0 };
+    let transaction_id = r.u16()?;
+    let flags = r.u16()?;
+    let counts = [r.u16()?, r.u16()?, r.u16()?, r.u16()?];
+    let mut parsed = DnsMessage {
+        transaction_id,
+        response: flags & 0x8000 != 0,
+        opcode: ((flags >> 11) & 0xf) as u8,
+        rcode: rcode_name((flags & 0xf) as u8),
+        aa: flags & 0x0400 != 0,
+        tc: flags & 0x0200 != 0,
+        rd: flags & 0x0100 != 0,
+        ra: flags & 0x0080 != 0,
+        questions: Vec::new(),
+        answers: Vec::new(),
+        authority: Vec::new(),
+        additional: Vec::new(),
+    };
+    // counts are not trusted for allocation, parsing fails at end of message
+    for _ in 0..counts[0] {
+        parsed.questions.push(r.question()?);
+    }
+    for (count, section) in counts[1..].iter().zip([
+        &mut parsed.answers,
+        &mut parsed.authority,
+        &mut parsed.additional,
+    ]) {
+        for _ in 0..*count {
+            section.push(r.record()?);
+        }
+    }
+    Some(parsed)
+}
+
+/// message framing state for one direction
+#[derive(Default)]
+pub struct DnsStreamState {
+    /// buffered data of incomplete message
+    pub buf: Vec<u8>,
+    /// stream offset of start of buf
+    pub offset: u64,
+    /// start offsets and timestamps of data segments not yet passed
+    pub timestamps: VecDeque<(u64, Option<u64>)>,
+    /// timestamp of the segment containing `offset`
+    pub current_timestamp: Option<u64>,
+    /// whether parsing stopped for this direction
+    pub done: bool,
+}
+
+impl DnsStreamState {
+    /// append stream data, returning complete messages with their offsets and
+    /// timestamps
+    pub fn feed(&mut self, data: &[u8], out: &mut Vec<(u64, Option<u64>, Vec<u8>)>) {
+        if self.done {
+            return;
+        }
+        self.buf.extend_from_slice(data);
+        let mut pos = 0;
+        while let [high, low, ..] = self.buf[pos..] {
+            let len = u16::from_be_bytes([high, low]) as usize;
+            let end = pos + 2 + len;
+            if self.buf.len() < end {
+                break;
+            }
+            let offset = self.offset + pos as u64;
+            while let Some(&(start, timestamp)) = self.timestamps.front() {
+                if start > offset {
+                    break;
+                }
+                self.current_timestamp = timestamp;
+                self.timestamps.pop_front();
+            }
+            out.push((
+                offset,
+                self.current_timestamp,
+                self.buf[pos + 2..end].to_vec(),
+            ));
+            pos = end;
+        }
+        self.buf.drain(..pos);
+        self.offset += pos as u64;
+    }
+
+    /// stop parsing
+    pub fn fail(&mut self) {
+        self.done = true;
+        self.buf = Vec::new();
+        self.timestamps.clear();
+    }
+}
+
+/// shared state for DnsExtractHandler
+pub struct DnsExtractSharedInfoInner {
+    pub output: Mutex<BufWriter<File>>,
+}
+
+#[derive(Clone)]
+pub struct DnsExtractSharedInfo {
+    pub inner: Arc<DnsExtractSharedInfoInner>,
+}
+
+impl DnsExtractSharedInfo {
+    /// create dns.jsonl in output directory
+    pub fn new(base_dir: &Path) -> std::io::Result<Self> {
+        let output = File::create(base_dir.join("dns.jsonl"))?;
+        Ok(DnsExtractSharedInfo {
+            inner: Arc::new(DnsExtractSharedInfoInner {
+                output: Mutex::new(BufWriter::new(output)),
+            }),
+        })
+    }
+
+    /// write message to output
+    pub fn record_message(&self, info: &DnsMessageInfo) -> std::io::Result<()> {
+        let mut serialized =
+            serde_json::to_string(info).expect("failed to serialize DnsMessageInfo");
+        serialized.push('\n');
+        self.inner.output.lock().write_all(serialized.as_bytes())
+    }
+
+    /// flush output
+    pub fn close(self) -> std::io::Result<()> {
+        Arc::into_inner(self.inner)
+            .unwrap()
+            .output
+            .into_inner()
+            .flush()
+    }
+}
+
+/// ConnectionHandler to extract DNS messages from TCP connections
+///
+/// Connections not classified as DNS are discarded.
+pub struct DnsExtractHandler {
+    pub shared_info: DnsExtractSharedInfo,
+    pub id: Uuid,
+    /// whether the connection carries DNS, decided on first data
+    pub is_dns: Option<bool>,
+    pub forward: DnsStreamState,
+    pub reverse: DnsStreamState,
+
+    messages: Vec<(u64, Option<u64>, Vec<u8>)>,
+    buf: Vec<u8>,
+    gaps: Vec<StreamGap>,
+    segments: Vec<SegmentInfo>,
+}
+
+impl DnsExtractHandler {
+    /// consume data from stream, writing complete messages
+    pub fn read_stream(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        len: usize,
+    ) {
+        if len == 0 {
+            return;
+        }
+        let is_dns = *self
+            .is_dns
+            .get_or_insert_with(|| connection.classification.protocol() == Some(AppProtocol::Dns));
+        self.gaps.clear();
+        self.segments.clear();
+        let stream = connection.get_stream(direction);
+        let end_offset = stream.buffer_start() + len as u64;
+        let state = match direction {
+            Direction::Forward => &mut self.forward,
+            Direction::Reverse => &mut self.reverse,
+        };
+        let buf = &mut self.buf;
+        buf.clear();
+        let read = stream.read_next(end_offset, &mut self.segments, &mut self.gaps, |slice| {
+            if !is_dns || state.done {
+                return;
+            }
+            let (a, b) = slice.as_slices();
+            buf.extend_from_slice(a);
+            if let Some(b) = b {
+                buf.extend_from_slice(b);
+            }
+        });
+        if read.is_none() {
+            error!("dns: {direction} stream cannot fulfill range, skipping");
+            return;
+        }
+        if !state.done && !self.gaps.is_empty() {
+            debug!("dns: {direction} stream has gaps, no longer parsing");
+            state.fail();
+        }
+        if !is_dns || state.done {
+            return;
+        }
+        for segment in &self.segments {
+            if let SegmentType::Data { len, .. } = segment.data {
+                if len > 0 {
+                    let timestamp = segment.extra.timestamp();
+                    let timestamp = timestamp.map(|t| t.as_micros() as u64);
+                    state.timestamps.push_back((segment.offset, timestamp));
+                }
+            }
+        }
+        state.feed(&self.buf, &mut self.messages);
+
+        for (offset, timestamp_us, data) in self.messages.drain(..) {
+            let Some(message) = parse_message(&data) else {
+                debug!("dns: malformed {direction} message at offset {offset}");
+                continue;
+            };
+            trace!(
+                "dns: {} {:?}",
+                if message.response {
+                    "response"
+                } else {
+                    "query"
+                },
+                message.questions.first().map(|q| &q.qname)
+            );
+            let info = DnsMessageInfo {
+                id: connection.uuid,
+                direction,
+                offset,
+                timestamp_us,
+                message,
+            };
+            if let Err(e) = self.shared_info.record_message(&info) {
+                tracing::error!("failed to write dns message: {e:?}");
+            }
+        }
+    }
+}
+
+impl ConnectionHandler for DnsExtractHandler {
+    type InitialData = DnsExtractSharedInfo;
+    type ConstructError = eyre::Report;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> eyre::Result<Self> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(DnsExtractHandler {
+            shared_info,
+            id: connection.uuid,
+            is_dns: None,
+            forward: DnsStreamState::default(),
+            reverse: DnsStreamState::default(),
+            messages: Vec::new(),
+            buf: Vec::new(),
+            gaps: Vec::new(),
+            segments: Vec::new(),
+        })
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        let total_len = stream.total_buffered_length();
+        if readable_len > 0 {
+            self.read_stream(connection, direction, readable_len);
+        } else if total_len > u16::MAX as usize + 2 {
+            // stuck behind a gap, skip ahead
+            self.read_stream(connection, direction, total_len);
+        }
+    }
+
+    fn gap_detected(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        direction: Direction,
+        _range: Range<u64>,
+    ) {
+        let state = match direction {
+            Direction::Forward => &mut self.forward,
+            Direction::Reverse => &mut self.reverse,
+        };
+        if !state.done && self.is_dns == Some(true) {
+            debug!("dns: {direction} stream lost data, no longer parsing");
+        }
+        state.fail();
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        info!(
+            "removing connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        for direction in [Direction::Forward, Direction::Reverse] {
+            let len = connection.get_stream(direction).total_buffered_length();
+            self.read_stream(connection, direction, len);
+        }
+        for state in [&self.forward, &self.reverse] {
+            if !state.buf.is_empty() {
+                debug!(
+                    "dns: {} bytes of incomplete message at offset {}",
+                    state.buf.len(),
+                    state.offset
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_message, DnsStreamState};
+
+    /// response for example.com A with a compressed answer name and a CNAME
+    fn response() -> Vec<u8> {
+        let mut m = vec![
+            0x12, 0x34, // id
+            0x81, 0x80, // response, rd, ra, NOERROR
+            0, 1, 0, 2, 0, 0, 0, 0,
+        ];
+        m.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+        // www.example.com CNAME example.com (pointer to offset 16)
+        m.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0x0e, 0x10, 0, 2, 0xc0, 16]);
+        // example.com A 93.184.216.34
+        m.extend_from_slice(&[0xc0, 16, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        m
+    }
+
+    #[test]
+    fn parse_response() {
+        let message = parse_message(&response()).unwrap();
+        assert_eq!(message.transaction_id, 0x1234);
+        assert!(message.response && message.rd && message.ra);
+        assert_eq!(message.rcode, "NOERROR");
+        assert_eq!(message.questions[0].qname, "www.example.com");
+        assert_eq!(message.questions[0].qtype, "A");
+        let answers: Vec<_> = message
+            .answers
+            .iter()
+            .map(|a| (a.name.as_str(), a.rtype.as_str(), a.ttl, a.data.as_str()))
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                ("www.example.com", "CNAME", 3600, "example.com"),
+                ("example.com", "A", 60, "93.184.216.34"),
+            ]
+        );
+
+        let mut truncated = response();
+        truncated.pop();
+        assert_eq!(parse_message(&truncated), None);
+        // pointer loop
+        let mut looped = response();
+        looped[12..14].copy_from_slice(&[0xc0, 12]);
+        assert_eq!(parse_message(&looped), None);
+    }
+
+    #[test]
+    fn split_messages() {
+        let message = response();
+        let mut stream = Vec::new();
+        for _ in 0..2 {
+            stream.extend_from_slice(&(message.len() as u16).to_be_bytes());
+            stream.extend_from_slice(&message);
+        }
+        let mut state = DnsStreamState::default();
+        state.timestamps.push_back((0, Some(1)));
+        state.timestamps.push_back((30, Some(2)));
+        state.timestamps.push_back((70, Some(3)));
+        let mut out = Vec::new();
+        for b in &stream {
+            state.feed(std::slice::from_ref(b), &mut out);
+        }
+        let expected_second = 2 + message.len() as u64;
+        let found: Vec<_> = out.iter().map(|(o, t, d)| (*o, *t, d.len())).collect();
+        assert_eq!(
+            found,
+            [
+                (0, Some(1), message.len()),
+                (expected_second, Some(2), message.len())
+            ]
+        );
+        assert!(state.buf.is_empty());
+    }
+}
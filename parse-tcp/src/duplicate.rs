@@ -0,0 +1,189 @@
+//! Suppression of duplicate packets
+//!
+//! Captures taken on SPAN ports or at several points of a path often contain
+//! the same packet more than once, which would otherwise be counted as
+//! retransmissions. Each TCP packet is reduced to a key over its flow, IP
+//! identification, sequence and acknowledgment numbers, TCP checksum and
+//! payload length. A packet is dropped if the same key was seen within the
+//! last `window` distinct packets. Genuine retransmissions usually differ in
+//! IP identification or timestamp option (and thus checksum), so they are
+//! kept.
+//!
+//! IPv6 has no identification field in the base header, so a retransmission
+//! without timestamp option is indistinguishable from a copy. IPv6 packets
+//! are therefore only checked if `check_ipv6` is set.
+
+use std::collections::{HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use crate::TcpMeta;
+
+/// default number of recent packets compared against
+pub const DUPLICATE_WINDOW: usize = 256;
+
+/// sliding window of recently seen packets
+pub struct DuplicateFilter {
+    /// number of recent packets compared against, 0 to disable
+    pub window: usize,
+    /// whether IPv6 packets are checked, retransmissions without timestamp
+    /// option are dropped as well if set
+    pub check_ipv6: bool,
+    /// keys of recent packets, oldest first
+    recent: VecDeque<u64>,
+    /// keys in `recent`
+    seen: HashSet<u64>,
+    /// count of packets checked
+    pub checked: u64,
+    /// count of packets suppressed as duplicates
+    pub suppressed: u64,
+    /// payload bytes of suppressed packets
+    pub suppressed_bytes: u64,
+}
+
+impl DuplicateFilter {
+    /// create filter comparing against the last `window` packets
+    pub fn new(window: usize) -> Self {
+        DuplicateFilter {
+            window,
+            check_ipv6: false,
+            recent: VecDeque::with_capacity(window),
+            seen: HashSet::with_capacity(window),
+            checked: 0,
+            suppressed: 0,
+            suppressed_bytes: 0,
+        }
+    }
+
+    /// key identifying a packet
+    pub fn packet_key(meta: &TcpMeta, data_len: usize) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        meta.src_addr.hash(&mut hasher);
+        meta.src_port.hash(&mut hasher);
+        meta.dst_addr.hash(&mut hasher);
+        meta.dst_port.hash(&mut hasher);
+        meta.ip_id.hash(&mut hasher);
+        meta.seq_number.hash(&mut hasher);
+        meta.ack_number.hash(&mut hasher);
+        meta.checksum.hash(&mut hasher);
+        data_len.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// check packet, returns true if it duplicates a recent packet
+    pub fn is_duplicate(&mut self, meta: &TcpMeta, data_len: usize) -> bool {
+        if self.window == 0 || (meta.src_addr.is_ipv6() && !self.check_ipv6) {
+            return false;
+        }
+        self.checked += 1;
+        let key = Self::packet_key(meta, data_len);
+        if self.seen.contains(&key) {
+            self.suppressed += 1;
+            self.suppressed_bytes += data_len as u64;
+            return true;
+        }
+        if self.recent.len() == self.window {
+            let oldest = self.recent.pop_front().expect("window is not empty");
+            self.seen.remove(&oldest);
+        }
+        self.recent.push_back(key);
+        self.seen.insert(key);
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use super::DuplicateFilter;
+    use crate::{TcpFlags, TcpMeta};
+
+    #[test]
+    fn window() {
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 500,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0x1234,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 1,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut filter = DuplicateFilter::new(2);
+        assert!(!filter.is_duplicate(&meta, 5));
+        assert!(filter.is_duplicate(&meta, 5));
+        // mirrored copy with a different VLAN is still a duplicate
+        meta.vlan_id = Some(10);
+        assert!(filter.is_duplicate(&meta, 5));
+        // retransmission has a new IP id
+        meta.ip_id = 2;
+        assert!(!filter.is_duplicate(&meta, 5));
+        meta.ip_id = 3;
+        assert!(!filter.is_duplicate(&meta, 5));
+        // first packet has left the window
+        meta.ip_id = 1;
+        assert!(!filter.is_duplicate(&meta, 5));
+        assert_eq!(filter.checked, 6);
+        assert_eq!(filter.suppressed, 2);
+        assert_eq!(filter.suppressed_bytes, 10);
+
+        let mut disabled = DuplicateFilter::new(0);
+        assert!(!disabled.is_duplicate(&meta, 5));
+        assert!(!disabled.is_duplicate(&meta, 5));
+    }
+
+    #[test]
+    fn ipv6_retransmission() {
+        let src = "2001:db8::1".parse::<Ipv6Addr>().unwrap();
+        let dst = "2001:db8::2".parse::<Ipv6Addr>().unwrap();
+        let meta = TcpMeta {
+            src_addr: src.into(),
+            src_port: 1000,
+            dst_addr: dst.into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 500,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0x1234,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        // retransmission is identical to the original
+        let mut filter = DuplicateFilter::new(2);
+        assert!(!filter.is_duplicate(&meta, 5));
+        assert!(!filter.is_duplicate(&meta, 5));
+        assert_eq!(filter.checked, 0);
+        assert_eq!(filter.suppressed, 0);
+
+        filter.check_ipv6 = true;
+        assert!(!filter.is_duplicate(&meta, 5));
+        assert!(filter.is_duplicate(&meta, 5));
+    }
+}
@@ -0,0 +1,586 @@
+//! Streaming NDJSON event output
+//!
+//! Emits one JSON object per line as the capture is processed, suitable for
+//! piping into jq or log shippers. Stream data itself is not included, only
+//! offsets and byte counts, except for the differing bytes of conflicting
+//! retransmissions and pattern matches, which are hex encoded.
+
+use std::io::{self, LineWriter, Write};
+use std::net::{IpAddr, TcpStream};
+use std::ops::Range;
+use std::sync::Arc;
+
+use kinesin_rdt::stream::inbound::OverlapConflict;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::classify::AppProtocol;
+use crate::connection::{Connection, Direction};
+use crate::matching::StreamMatch;
+use crate::serialized::PacketExtra;
+use crate::stream::{SegmentInfo, StreamGap};
+use crate::ConnectionHandler;
+
+/// kind of event and its fields
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// first packet of connection seen
+    ConnectionOpen {
+        src_addr: IpAddr,
+        src_port: u16,
+        dst_addr: IpAddr,
+        dst_port: u16,
+        /// protocol guessed from ports
+        #[serde(skip_serializing_if = "Option::is_none")]
+        protocol: Option<AppProtocol>,
+    },
+    /// client was misidentified, forward direction now refers to the new
+    /// source and destination
+    DirectionChanged {
+        src_addr: IpAddr,
+        src_port: u16,
+        dst_addr: IpAddr,
+        dst_port: u16,
+    },
+    /// reassembled data available in one direction
+    Data {
+        direction: Direction,
+        /// stream offset of first byte
+        offset: u64,
+        len: u64,
+        /// bytes within the range which were never captured
+        #[serde(skip_serializing_if = "is_zero")]
+        gap_bytes: u64,
+    },
+    /// data in one direction declared lost
+    Gap {
+        direction: Direction,
+        /// stream offset of first missing byte
+        offset: u64,
+        len: u64,
+    },
+    /// retransmitted data differed from data received earlier
+    OverlapConflict {
+        direction: Direction,
+        /// stream offset of first differing byte
+        offset: u64,
+        /// hex encoded data received earlier
+        original: String,
+        /// hex encoded data of the retransmission
+        conflicting: String,
+    },
+    /// segment dropped as its TSval fell far behind
+    PawsRejected {
+        direction: Direction,
+        tsval: u32,
+        /// highest TSval accepted before
+        ts_recent: u32,
+    },
+    /// packet TTL differed from the most common TTL in its direction
+    TtlAnomaly {
+        direction: Direction,
+        /// most common TTL seen before
+        expected: u8,
+        observed: u8,
+    },
+    /// data matching a configured pattern received
+    Match {
+        direction: Direction,
+        /// index of pattern
+        pattern: usize,
+        /// stream offset of first matched byte
+        offset: u64,
+        /// hex encoded matched data
+        data: String,
+    },
+    /// FIN received
+    Fin { direction: Direction },
+    /// RST received
+    Rst { direction: Direction },
+    /// connection removed from flow table
+    ConnectionClose {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        protocol: Option<AppProtocol>,
+        /// whether both sides of the connection were seen closing
+        observed_close: bool,
+        forward_packets: u64,
+        reverse_packets: u64,
+        forward_bytes: u64,
+        reverse_bytes: u64,
+        forward_gap_bytes: u64,
+        reverse_gap_bytes: u64,
+    },
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// single event line
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Event {
+    /// connection id
+    pub id: Uuid,
+    /// timestamp of the packet causing the event (microseconds since epoch)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts_us: Option<u64>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// destination of event lines
+pub struct EventSinkInner {
+    pub writer: Mutex<EventWriter>,
+}
+
+pub struct EventWriter {
+    pub output: LineWriter<Box<dyn Write + Send>>,
+    /// set after a write fails, further events are dropped
+    pub failed: bool,
+}
+
+#[derive(Clone)]
+pub struct EventSink {
+    pub inner: Arc<EventSinkInner>,
+}
+
+impl EventSink {
+    /// create sink writing to arbitrary output
+    pub fn new(output: Box<dyn Write + Send>) -> Self {
+        EventSink {
+            inner: Arc::new(EventSinkInner {
+                writer: Mutex::new(EventWriter {
+                    output: LineWriter::new(output),
+                    failed: false,
+                }),
+            }),
+        }
+    }
+
+    /// open target: `-` for stdout, `unix:<path>` for a unix socket, or
+    /// `<host>:<port>` for a TCP connection
+    pub fn open(target: &str) -> io::Result<Self> {
+        let output: Box<dyn Write + Send> = if target == "-" {
+            Box::new(io::stdout())
+        } else if let Some(path) = target.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                Box::new(std::os::unix::net::UnixStream::connect(path)?)
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = path;
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unix sockets are not supported on this platform",
+                ));
+            }
+        } else {
+            let stream = TcpStream::connect(target)?;
+            stream.set_nodelay(true)?;
+            Box::new(stream)
+        };
+        info!("writing events to {target}");
+        Ok(Self::new(output))
+    }
+
+    /// write event as a single line
+    pub fn emit(&self, event: &Event) {
+        let mut writer = self.inner.writer.lock();
+        if writer.failed {
+            return;
+        }
+        let result = serde_json::to_writer(&mut writer.output, event)
+            .map_err(io::Error::from)
+            .and_then(|_| writer.output.write_all(b"\n"));
+        if let Err(e) = result {
+            error!("failed to write event, further events will be dropped: {e}");
+            writer.failed = true;
+        }
+    }
+
+    /// flush buffered output
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.writer.lock().output.flush()
+    }
+}
+
+/// ConnectionHandler emitting NDJSON events
+pub struct EventOutputHandler {
+    pub sink: EventSink,
+    pub id: Uuid,
+    pub gaps: Vec<StreamGap>,
+    pub segments: Vec<SegmentInfo>,
+    /// whether connection_open was emitted
+    pub opened: bool,
+}
+
+impl EventOutputHandler {
+    fn emit(&mut self, connection: &Connection<Self>, kind: EventKind) {
+        if !self.opened {
+            self.emit_open(connection);
+        }
+        self.send(connection, kind);
+    }
+
+    fn send(&self, connection: &Connection<Self>, kind: EventKind) {
+        self.sink.emit(&Event {
+            id: self.id,
+            ts_us: connection.last_packet_time.map(|t| t.as_micros() as u64),
+            kind,
+        });
+    }
+
+    /// emit connection_open, deferred until the first packet was handled as
+    /// the direction of the flow may change during the handshake
+    fn emit_open(&mut self, connection: &Connection<Self>) {
+        self.opened = true;
+        let flow = &connection.forward_flow;
+        self.send(
+            connection,
+            EventKind::ConnectionOpen {
+                src_addr: flow.src_addr,
+                src_port: flow.src_port,
+                dst_addr: flow.dst_addr,
+                dst_port: flow.dst_port,
+                protocol: connection.classification.by_port,
+            },
+        );
+    }
+
+    /// consume `len` bytes of stream and emit a data event for them
+    pub fn consume_stream(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        len: usize,
+    ) {
+        if len == 0 {
+            return;
+        }
+        self.gaps.clear();
+        self.segments.clear();
+        let stream = connection.get_stream(direction);
+        let offset = stream.buffer_start();
+        let end_offset = offset + len as u64;
+        let read = stream.read_next(end_offset, &mut self.segments, &mut self.gaps, |_| ());
+        if read.is_none() {
+            error!("events: {direction} stream cannot fulfill range, skipping");
+            return;
+        }
+        let gap_bytes = self
+            .gaps
+            .iter()
+            .map(|gap| gap.range.end - gap.range.start)
+            .sum();
+        self.emit(
+            connection,
+            EventKind::Data {
+                direction,
+                offset,
+                len: len as u64,
+                gap_bytes,
+            },
+        );
+    }
+}
+
+impl ConnectionHandler for EventOutputHandler {
+    type InitialData = EventSink;
+    type ConstructError = std::convert::Infallible;
+    fn new(
+        sink: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> Result<Self, Self::ConstructError> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(EventOutputHandler {
+            sink,
+            id: connection.uuid,
+            gaps: Vec::new(),
+            segments: Vec::new(),
+            opened: false,
+        })
+    }
+
+    fn packet_received(
+        &mut self,
+        connection: &mut Connection<Self>,
+        _direction: Direction,
+        _extra: &PacketExtra,
+    ) {
+        if !self.opened {
+            self.emit_open(connection);
+        }
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        // emit readable data immediately, only buffer out-of-order data
+        let config = connection.config.clone();
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        if readable_len > 0 {
+            self.consume_stream(connection, direction, readable_len);
+        } else if stream.total_buffered_length() > config.flush_total_threshold {
+            self.consume_stream(connection, direction, config.flush_total_advance);
+        }
+    }
+
+    fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let buffered = connection.get_stream(direction).total_buffered_length();
+        self.consume_stream(connection, direction, buffered);
+    }
+
+    fn gap_detected(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        range: Range<u64>,
+    ) {
+        let kind = EventKind::Gap {
+            direction,
+            offset: range.start,
+            len: range.end - range.start,
+        };
+        self.emit(connection, kind);
+    }
+
+    fn overlap_conflict(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        conflict: OverlapConflict,
+    ) {
+        let kind = EventKind::OverlapConflict {
+            direction,
+            offset: conflict.offset,
+            original: to_hex(&conflict.original),
+            conflicting: to_hex(&conflict.conflicting),
+        };
+        self.emit(connection, kind);
+    }
+
+    fn paws_rejected(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        tsval: u32,
+        ts_recent: u32,
+    ) {
+        let kind = EventKind::PawsRejected {
+            direction,
+            tsval,
+            ts_recent,
+        };
+        self.emit(connection, kind);
+    }
+
+    fn ttl_anomaly(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        expected: u8,
+        observed: u8,
+    ) {
+        let kind = EventKind::TtlAnomaly {
+            direction,
+            expected,
+            observed,
+        };
+        self.emit(connection, kind);
+    }
+
+    fn stream_match(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        found: StreamMatch,
+    ) {
+        let kind = EventKind::Match {
+            direction,
+            pattern: found.pattern,
+            offset: found.offset,
+            data: to_hex(&found.data),
+        };
+        self.emit(connection, kind);
+    }
+
+    fn fin_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        self.emit(connection, EventKind::Fin { direction });
+    }
+
+    fn rst_received(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        _extra: PacketExtra,
+    ) {
+        self.emit(connection, EventKind::Rst { direction });
+    }
+
+    fn direction_changed(&mut self, connection: &mut Connection<Self>) {
+        if !self.opened {
+            // connection_open will have the new direction
+            return;
+        }
+        let flow = &connection.forward_flow;
+        let kind = EventKind::DirectionChanged {
+            src_addr: flow.src_addr,
+            src_port: flow.src_port,
+            dst_addr: flow.dst_addr,
+            dst_port: flow.dst_port,
+        };
+        self.send(connection, kind);
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        for direction in [Direction::Forward, Direction::Reverse] {
+            let remaining = connection.get_stream(direction).total_buffered_length();
+            self.consume_stream(connection, direction, remaining);
+        }
+        let kind = EventKind::ConnectionClose {
+            protocol: connection.classification.protocol(),
+            observed_close: connection.observed_close,
+            forward_packets: connection.forward_stream.packet_count,
+            reverse_packets: connection.reverse_stream.packet_count,
+            forward_bytes: connection.forward_stream.data_bytes,
+            reverse_bytes: connection.reverse_stream.data_bytes,
+            forward_gap_bytes: connection.forward_stream.gaps_length,
+            reverse_gap_bytes: connection.reverse_stream.gaps_length,
+        };
+        self.emit(connection, kind);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::{EventOutputHandler, EventSink};
+    use crate::config::ReassemblyConfig;
+    use crate::flow_table::FlowTable;
+    use crate::matching::PatternSet;
+    use crate::serialized::PacketExtra;
+    use crate::{TcpFlags, TcpMeta};
+
+    /// writer appending to shared buffer
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn connection_events() {
+        let buf = SharedBuf::default();
+        let sink = EventSink::new(Box::new(buf.clone()));
+        let config = ReassemblyConfig {
+            patterns: Some(Arc::new(PatternSet::new(vec!["llo".parse().unwrap()]))),
+            ..Default::default()
+        };
+        let mut table: FlowTable<EventOutputHandler> = FlowTable::with_config(sink.clone(), config);
+
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let extra = PacketExtra::LegacyPcap {
+            index: 0,
+            ts_sec: 1,
+            ts_usec: 0,
+            vlan_id: None,
+            frames: None,
+            ttl: None,
+            bad_checksum: false,
+        };
+        let mut syn_ack = meta.clone();
+        std::mem::swap(&mut syn_ack.src_addr, &mut syn_ack.dst_addr);
+        std::mem::swap(&mut syn_ack.src_port, &mut syn_ack.dst_port);
+        syn_ack.seq_number = 500;
+        syn_ack.ack_number = 101;
+        syn_ack.flags.ack = true;
+        table.handle_packet(&meta, &[], &extra).unwrap();
+        table.handle_packet(&syn_ack, &[], &extra).unwrap();
+        meta.seq_number = 101;
+        meta.ack_number = 501;
+        meta.flags = TcpFlags {
+            ack: true,
+            ..Default::default()
+        };
+        table.handle_packet(&meta, &[], &extra).unwrap();
+        table.handle_packet(&meta, b"hello", &extra).unwrap();
+        meta.seq_number += 5;
+        meta.flags.fin = true;
+        table.handle_packet(&meta, &[], &extra).unwrap();
+        table.close();
+        drop(table);
+        sink.flush().unwrap();
+
+        let output = String::from_utf8(buf.0.lock().clone()).unwrap();
+        let events: Vec<serde_json::Value> = output
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "connection_open",
+                "match",
+                "data",
+                "fin",
+                "connection_close"
+            ]
+        );
+        assert_eq!(events[0]["dst_port"], 80);
+        assert_eq!(events[1]["offset"], 2);
+        assert_eq!(events[1]["data"], "6c6c6f");
+        assert_eq!(events[2]["direction"], "forward");
+        assert_eq!(events[2]["len"], 5);
+        assert_eq!(events[4]["forward_bytes"], 5);
+        assert_eq!(events[4]["ts_us"], 1_000_000);
+    }
+}
@@ -0,0 +1,1143 @@
+//! File and mail extraction from plaintext protocols
+//!
+//! `ExtractHandler` follows FTP control connections and SMTP and POP3
+//! sessions, writing transferred files and messages to the output directory
+//! and listing them in `extracted.json`.
+//!
+//! FTP data connections are paired with their control connection through the
+//! addresses announced by `PORT`/`EPRT` commands and `227`/`229` replies, which
+//! are kept in `ExtractSharedInfo` until the data connection shows up. Data is
+//! written to `{control id}.ftp.{n}`. Messages sent with SMTP `DATA` or `BDAT`
+//! and retrieved with POP3 `RETR` or `TOP` are written to `{id}.{n}.eml` with
+//! dot-stuffing removed.
+//!
+//! Sessions are no longer parsed after `STARTTLS`, `STLS` or `AUTH TLS`, and a
+//! direction is no longer parsed after data was lost. Data connections are only
+//! paired if the control connection data announcing them was handled first.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use eyre::Context;
+use parking_lot::Mutex;
+use tracing::{debug, error, info, trace};
+use uuid::Uuid;
+
+use crate::classify::AppProtocol;
+use crate::connection::{Connection, Direction};
+use crate::serialized::ExtractedFileInfo;
+use crate::stream::{SegmentInfo, StreamGap};
+use crate::ConnectionHandler;
+
+/// max length of a command, reply or message line
+pub const MAX_LINE_LENGTH: usize = 64 << 10; // 64 KB
+
+/// event emitted by session parsers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExtractEvent {
+    /// user name sent by client
+    User(String),
+    /// FTP data connection expected to this address
+    DataChannel(SocketAddr),
+    /// FTP transfer command, using the last announced data connection
+    Transfer(String),
+    /// start of a message
+    MessageStart {
+        command: String,
+        mail_from: Option<String>,
+        rcpt_to: Vec<String>,
+    },
+    /// message data with dot-stuffing removed
+    MessageData(Vec<u8>),
+    /// end of message
+    MessageEnd,
+    /// session switched to TLS
+    Encrypted,
+}
+
+/// splits one direction of a session into lines
+#[derive(Default)]
+pub struct LineReader {
+    /// unconsumed input
+    buf: Vec<u8>,
+    /// start of unconsumed input in buf
+    pos: usize,
+    failed: bool,
+}
+
+impl LineReader {
+    /// whether the reader can accept more data
+    pub fn is_active(&self) -> bool {
+        !self.failed
+    }
+
+    /// stop reading (e.g. on stream gaps)
+    pub fn fail(&mut self) {
+        self.failed = true;
+        self.buf = Vec::new();
+        self.pos = 0;
+    }
+
+    /// append stream data
+    pub fn push(&mut self, data: &[u8]) {
+        if self.failed {
+            return;
+        }
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(data);
+    }
+
+    /// take next complete line, including terminator
+    pub fn next_line(&mut self) -> Option<Vec<u8>> {
+        if self.failed {
+            return None;
+        }
+        let rest = &self.buf[self.pos..];
+        match memchr::memchr(b'\n', rest) {
+            Some(newline) => {
+                let line = rest[..=newline].to_vec();
+                self.pos += newline + 1;
+                Some(line)
+            }
+            None => {
+                if rest.len() > MAX_LINE_LENGTH {
+                    debug!("extract: line too long");
+                    self.fail();
+                }
+                None
+            }
+        }
+    }
+
+    /// take up to `max` bytes
+    pub fn next_bytes(&mut self, max: u64) -> Option<Vec<u8>> {
+        let available = self.buf.len() - self.pos;
+        if self.failed || available == 0 || max == 0 {
+            return None;
+        }
+        let len = u64::min(available as u64, max) as usize;
+        let data = self.buf[self.pos..self.pos + len].to_vec();
+        self.pos += len;
+        Some(data)
+    }
+}
+
+/// strip line terminator
+fn trim_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// split command line into uppercase verb and argument
+fn split_command(line: &[u8]) -> (String, String) {
+    let line = String::from_utf8_lossy(trim_line(line));
+    let (verb, arg) = line.split_once(' ').unwrap_or((&line, ""));
+    (verb.to_ascii_uppercase(), arg.trim().to_string())
+}
+
+/// remove dot-stuffing from a line of a message, returning None for the line
+/// ending the message
+pub fn unstuff(line: &[u8]) -> Option<&[u8]> {
+    if trim_line(line) == b"." {
+        return None;
+    }
+    Some(line.strip_prefix(b".").unwrap_or(line))
+}
+
+/// address from SMTP `FROM:<path>` or `TO:<path>` argument
+fn mail_path(arg: &str, prefix: &str) -> String {
+    let arg = match arg.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => &arg[prefix.len()..],
+        _ => arg,
+    };
+    let path = arg.split_whitespace().next().unwrap_or_default();
+    path.trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_string()
+}
+
+/// parse `h1,h2,h3,h4,p1,p2` host and port, as in `PORT` and `227` replies
+pub fn parse_host_port(text: &str) -> Option<SocketAddr> {
+    // 227 replies may place the numbers anywhere in the text
+    text.split(|c: char| !c.is_ascii_digit() && c != ',')
+        .filter_map(|candidate| {
+            let numbers = candidate
+                .split(',')
+                .map(str::parse::<u8>)
+                .collect::<Result<Vec<_>, _>>()
+                .ok()?;
+            let [a, b, c, d, p1, p2] = numbers[..] else {
+                return None;
+            };
+            let port = u16::from_be_bytes([p1, p2]);
+            Some(SocketAddr::new(Ipv4Addr::new(a, b, c, d).into(), port))
+        })
+        .next()
+}
+
+/// parse `EPRT` argument, `|proto|address|port|` with any delimiter
+pub fn parse_eprt(arg: &str) -> Option<SocketAddr> {
+    let delimiter = arg.chars().next()?;
+    let mut parts = arg[delimiter.len_utf8()..].split(delimiter);
+    let _proto = parts.next()?;
+    let addr: IpAddr = parts.next()?.parse().ok()?;
+    let port: u16 = parts.next()?.parse().ok()?;
+    Some(SocketAddr::new(addr, port))
+}
+
+/// parse port from `229` reply text, `(|||port|)` with any delimiter
+pub fn parse_epsv(text: &str) -> Option<u16> {
+    let start = text.find('(')? + 1;
+    let end = start + text[start..].find(')')?;
+    let inner = &text[start..end];
+    let delimiter = inner.chars().next()?;
+    inner
+        .split(delimiter)
+        .nth(3)
+        .and_then(|port| port.parse().ok())
+}
+
+/// FTP control connection parser
+pub struct FtpControlParser {
+    /// server address, for `229` replies
+    pub server_addr: IpAddr,
+    client: LineReader,
+    server: LineReader,
+}
+
+impl FtpControlParser {
+    pub fn new(server_addr: IpAddr) -> Self {
+        FtpControlParser {
+            server_addr,
+            client: LineReader::default(),
+            server: LineReader::default(),
+        }
+    }
+
+    /// feed stream data, appending events to `events`
+    pub fn feed(&mut self, direction: Direction, data: &[u8], events: &mut Vec<ExtractEvent>) {
+        match direction {
+            Direction::Forward => {
+                self.client.push(data);
+                while let Some(line) = self.client.next_line() {
+                    self.command(&line, events);
+                }
+            }
+            Direction::Reverse => {
+                self.server.push(data);
+                while let Some(line) = self.server.next_line() {
+                    self.reply(&line, events);
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, line: &[u8], events: &mut Vec<ExtractEvent>) {
+        let (verb, arg) = split_command(line);
+        match verb.as_str() {
+            "USER" => events.push(ExtractEvent::User(arg)),
+            "PORT" | "EPRT" => {
+                let addr = if verb == "PORT" {
+                    parse_host_port(&arg)
+                } else {
+                    parse_eprt(&arg)
+                };
+                match addr {
+                    Some(addr) => events.push(ExtractEvent::DataChannel(addr)),
+                    None => debug!("ftp: invalid {verb} argument {arg:?}"),
+                }
+            }
+            "RETR" | "STOR" | "STOU" | "APPE" | "LIST" | "NLST" | "MLSD" => {
+                let command = String::from_utf8_lossy(trim_line(line)).into_owned();
+                trace!("ftp: transfer {command}");
+                events.push(ExtractEvent::Transfer(command));
+            }
+            "AUTH" => {
+                events.push(ExtractEvent::Encrypted);
+                self.client.fail();
+                self.server.fail();
+            }
+            _ => {}
+        }
+    }
+
+    fn reply(&mut self, line: &[u8], events: &mut Vec<ExtractEvent>) {
+        let line = String::from_utf8_lossy(trim_line(line));
+        let addr = if let Some(text) = line.strip_prefix("227 ") {
+            parse_host_port(text)
+        } else if let Some(text) = line.strip_prefix("229 ") {
+            parse_epsv(text).map(|port| SocketAddr::new(self.server_addr, port))
+        } else {
+            return;
+        };
+        match addr {
+            Some(addr) => events.push(ExtractEvent::DataChannel(addr)),
+            None => debug!("ftp: invalid passive mode reply {line:?}"),
+        }
+    }
+}
+
+/// SMTP client state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SmtpState {
+    Command,
+    /// reading message after `DATA`
+    Data,
+    /// reading `BDAT` chunk
+    Chunk {
+        remaining: u64,
+        last: bool,
+    },
+}
+
+/// SMTP session parser
+///
+/// Only commands are parsed, a message is assumed to follow `DATA`.
+pub struct SmtpParser {
+    client: LineReader,
+    state: SmtpState,
+    mail_from: Option<String>,
+    rcpt_to: Vec<String>,
+    /// whether a message sent in `BDAT` chunks is open
+    in_chunked_message: bool,
+}
+
+impl Default for SmtpParser {
+    fn default() -> Self {
+        SmtpParser {
+            client: LineReader::default(),
+            state: SmtpState::Command,
+            mail_from: None,
+            rcpt_to: Vec::new(),
+            in_chunked_message: false,
+        }
+    }
+}
+
+impl SmtpParser {
+    /// feed stream data, appending events to `events`
+    pub fn feed(&mut self, direction: Direction, data: &[u8], events: &mut Vec<ExtractEvent>) {
+        if direction == Direction::Reverse {
+            // replies are not needed
+            return;
+        }
+        self.client.push(data);
+        loop {
+            match self.state {
+                SmtpState::Command => {
+                    let Some(line) = self.client.next_line() else {
+                        break;
+                    };
+                    self.command(&line, events);
+                }
+                SmtpState::Data => {
+                    let Some(line) = self.client.next_line() else {
+                        break;
+                    };
+                    match unstuff(&line) {
+                        Some(data) => events.push(ExtractEvent::MessageData(data.to_vec())),
+                        None => self.end_message(events),
+                    }
+                }
+                SmtpState::Chunk { remaining: 0, last } => {
+                    if last {
+                        self.end_message(events);
+                    }
+                    self.state = SmtpState::Command;
+                }
+                SmtpState::Chunk { remaining, last } => {
+                    let Some(data) = self.client.next_bytes(remaining) else {
+                        break;
+                    };
+                    let remaining = remaining - data.len() as u64;
+                    events.push(ExtractEvent::MessageData(data));
+                    self.state = SmtpState::Chunk { remaining, last };
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, line: &[u8], events: &mut Vec<ExtractEvent>) {
+        let (verb, arg) = split_command(line);
+        match verb.as_str() {
+            "MAIL" => {
+                self.mail_from = Some(mail_path(&arg, "FROM:"));
+                self.rcpt_to.clear();
+            }
+            "RCPT" => self.rcpt_to.push(mail_path(&arg, "TO:")),
+            "RSET" => {
+                self.mail_from = None;
+                self.rcpt_to.clear();
+            }
+            "DATA" => {
+                self.start_message(verb, events);
+                self.state = SmtpState::Data;
+            }
+            "BDAT" => {
+                let mut args = arg.split_whitespace();
+                let Some(size) = args.next().and_then(|s| s.parse().ok()) else {
+                    debug!("smtp: invalid BDAT argument {arg:?}");
+                    self.client.fail();
+                    return;
+                };
+                let last = args.next().is_some_and(|a| a.eq_ignore_ascii_case("LAST"));
+                if !self.in_chunked_message {
+                    self.start_message(verb, events);
+                    self.in_chunked_message = true;
+                }
+                self.state = SmtpState::Chunk {
+                    remaining: size,
+                    last,
+                };
+            }
+            "STARTTLS" => {
+                events.push(ExtractEvent::Encrypted);
+                self.client.fail();
+            }
+            _ => {}
+        }
+    }
+
+    fn start_message(&mut self, command: String, events: &mut Vec<ExtractEvent>) {
+        events.push(ExtractEvent::MessageStart {
+            command,
+            mail_from: self.mail_from.clone(),
+            rcpt_to: self.rcpt_to.clone(),
+        });
+    }
+
+    fn end_message(&mut self, events: &mut Vec<ExtractEvent>) {
+        events.push(ExtractEvent::MessageEnd);
+        self.state = SmtpState::Command;
+        self.in_chunked_message = false;
+        self.mail_from = None;
+        self.rcpt_to.clear();
+    }
+}
+
+/// POP3 session parser
+///
+/// Replies are paired with commands in order, each command receiving one
+/// status line (or `+` continuation).
+#[derive(Default)]
+pub struct Pop3Parser {
+    client: LineReader,
+    server: LineReader,
+    /// commands awaiting a reply
+    commands: VecDeque<(String, String)>,
+    /// reading multi-line reply, containing a message if true
+    multi_line: Option<bool>,
+}
+
+impl Pop3Parser {
+    /// feed stream data, appending events to `events`
+    pub fn feed(&mut self, direction: Direction, data: &[u8], events: &mut Vec<ExtractEvent>) {
+        match direction {
+            Direction::Forward => {
+                self.client.push(data);
+                while let Some(line) = self.client.next_line() {
+                    let (verb, arg) = split_command(&line);
+                    match verb.as_str() {
+                        "USER" => events.push(ExtractEvent::User(arg.clone())),
+                        "STLS" => {
+                            events.push(ExtractEvent::Encrypted);
+                            self.client.fail();
+                            self.server.fail();
+                            return;
+                        }
+                        _ => {}
+                    }
+                    self.commands.push_back((verb, arg));
+                }
+            }
+            Direction::Reverse => {
+                self.server.push(data);
+                while let Some(line) = self.server.next_line() {
+                    self.reply_line(&line, events);
+                }
+            }
+        }
+    }
+
+    fn reply_line(&mut self, line: &[u8], events: &mut Vec<ExtractEvent>) {
+        if let Some(is_message) = self.multi_line {
+            match unstuff(line) {
+                Some(data) if is_message => events.push(ExtractEvent::MessageData(data.to_vec())),
+                Some(_) => {}
+                None => {
+                    if is_message {
+                        events.push(ExtractEvent::MessageEnd);
+                    }
+                    self.multi_line = None;
+                }
+            }
+            return;
+        }
+        // greeting is received before any command
+        let command = self.commands.pop_front();
+        let Some((verb, arg)) = command.filter(|_| line.starts_with(b"+OK")) else {
+            return;
+        };
+        let is_multi_line = match verb.as_str() {
+            "RETR" | "TOP" | "CAPA" => true,
+            "LIST" | "UIDL" | "AUTH" => arg.is_empty(),
+            _ => false,
+        };
+        if !is_multi_line {
+            return;
+        }
+        let is_message = matches!(verb.as_str(), "RETR" | "TOP");
+        if is_message {
+            events.push(ExtractEvent::MessageStart {
+                command: format!("{verb} {arg}"),
+                mail_from: None,
+                rcpt_to: Vec::new(),
+            });
+        }
+        self.multi_line = Some(is_message);
+    }
+}
+
+/// parser for a session of a supported protocol
+pub enum SessionParser {
+    Ftp(FtpControlParser),
+    Smtp(SmtpParser),
+    Pop3(Pop3Parser),
+}
+
+impl SessionParser {
+    /// create parser for protocol, if supported
+    pub fn new(protocol: AppProtocol, server_addr: IpAddr) -> Option<Self> {
+        match protocol {
+            AppProtocol::Ftp => Some(SessionParser::Ftp(FtpControlParser::new(server_addr))),
+            AppProtocol::Smtp => Some(SessionParser::Smtp(SmtpParser::default())),
+            AppProtocol::Pop3 => Some(SessionParser::Pop3(Pop3Parser::default())),
+            _ => None,
+        }
+    }
+
+    pub fn protocol(&self) -> AppProtocol {
+        match self {
+            SessionParser::Ftp(_) => AppProtocol::Ftp,
+            SessionParser::Smtp(_) => AppProtocol::Smtp,
+            SessionParser::Pop3(_) => AppProtocol::Pop3,
+        }
+    }
+
+    /// feed stream data, appending events to `events`
+    pub fn feed(&mut self, direction: Direction, data: &[u8], events: &mut Vec<ExtractEvent>) {
+        match self {
+            SessionParser::Ftp(parser) => parser.feed(direction, data, events),
+            SessionParser::Smtp(parser) => parser.feed(direction, data, events),
+            SessionParser::Pop3(parser) => parser.feed(direction, data, events),
+        }
+    }
+
+    /// stop parsing direction
+    pub fn fail(&mut self, direction: Direction) {
+        let reader = match (self, direction) {
+            (SessionParser::Ftp(parser), Direction::Forward) => &mut parser.client,
+            (SessionParser::Ftp(parser), Direction::Reverse) => &mut parser.server,
+            (SessionParser::Smtp(parser), Direction::Forward) => &mut parser.client,
+            (SessionParser::Smtp(_), Direction::Reverse) => return,
+            (SessionParser::Pop3(parser), Direction::Forward) => &mut parser.client,
+            (SessionParser::Pop3(parser), Direction::Reverse) => &mut parser.server,
+        };
+        if reader.is_active() {
+            debug!("extract: {direction} stream has gaps, no longer parsing");
+        }
+        reader.fail();
+    }
+}
+
+/// FTP data connection announced on a control connection
+#[derive(Clone, Debug)]
+pub struct FtpDataChannel {
+    pub control_id: Uuid,
+    /// index of transfer within control connection
+    pub index: u64,
+    pub user: Option<String>,
+    /// transfer command, once seen
+    pub command: Option<String>,
+}
+
+/// shared state for ExtractHandler
+pub struct ExtractSharedInfoInner {
+    pub base_dir: PathBuf,
+    pub index_file: Mutex<File>,
+    /// announced FTP data connections by listening address
+    pub ftp_channels: Mutex<HashMap<SocketAddr, FtpDataChannel>>,
+}
+
+#[derive(Clone)]
+pub struct ExtractSharedInfo {
+    pub inner: Arc<ExtractSharedInfoInner>,
+}
+
+impl ExtractSharedInfo {
+    /// create with output path
+    pub fn new(base_dir: PathBuf) -> std::io::Result<Self> {
+        let mut index_file = File::create(base_dir.join("extracted.json"))?;
+        index_file.write_all(b"[\n")?;
+        Ok(ExtractSharedInfo {
+            inner: Arc::new(ExtractSharedInfoInner {
+                base_dir,
+                index_file: Mutex::new(index_file),
+                ftp_channels: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// write extracted file to index
+    pub fn record_file(&self, info: &ExtractedFileInfo) -> std::io::Result<()> {
+        let mut serialized =
+            serde_json::to_string(info).expect("failed to serialize ExtractedFileInfo");
+        serialized += ",\n";
+        let mut file = self.inner.index_file.lock();
+        file.write_all(serialized.as_bytes())
+    }
+
+    /// find announced data connection listening at address
+    pub fn find_channel(&self, addr: SocketAddr) -> Option<FtpDataChannel> {
+        self.inner.ftp_channels.lock().get(&addr).cloned()
+    }
+
+    /// remove data connection, returning its latest state if it was not
+    /// replaced by another announcement
+    pub fn release_channel(
+        &self,
+        addr: SocketAddr,
+        channel: &FtpDataChannel,
+    ) -> Option<FtpDataChannel> {
+        let mut channels = self.inner.ftp_channels.lock();
+        let current = channels.get(&addr)?;
+        if current.control_id != channel.control_id || current.index != channel.index {
+            return None;
+        }
+        channels.remove(&addr)
+    }
+
+    /// close index file
+    pub fn close(self) -> std::io::Result<()> {
+        let mut index_file = Arc::into_inner(self.inner).unwrap().index_file.into_inner();
+        let current_pos = index_file.stream_position()?;
+        if current_pos > 2 {
+            // overwrite trailing comma and close array
+            index_file.seek(SeekFrom::Current(-2))?;
+            index_file.write_all(b"\n]\n")?;
+        } else {
+            index_file.write_all(b"]\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// file being extracted
+pub struct ExtractedFile {
+    pub info: ExtractedFileInfo,
+    pub file: BufWriter<File>,
+}
+
+impl ExtractedFile {
+    /// create file named by `info.file` in `base_dir`
+    pub fn create(base_dir: &Path, info: ExtractedFileInfo) -> eyre::Result<Self> {
+        let file = File::create(base_dir.join(&info.file)).wrap_err("creating extracted file")?;
+        Ok(ExtractedFile {
+            info,
+            file: BufWriter::new(file),
+        })
+    }
+
+    /// append data
+    pub fn write(&mut self, data: &[u8]) -> eyre::Result<()> {
+        self.file
+            .write_all(data)
+            .wrap_err("writing extracted file")?;
+        self.info.len += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// what a connection carries
+pub enum ExtractRole {
+    /// not decided until data is received
+    Unknown,
+    Session(SessionParser),
+    /// FTP data connection
+    FtpData {
+        /// listening address the connection was announced with
+        addr: SocketAddr,
+        channel: FtpDataChannel,
+        /// whether data was lost
+        lost: bool,
+    },
+    Ignored,
+}
+
+/// ConnectionHandler to extract files and mail from FTP, SMTP and POP3
+///
+/// Forward direction is assumed to be sent by the client. Extracted files are
+/// recorded in `extracted.json`.
+pub struct ExtractHandler {
+    pub shared_info: ExtractSharedInfo,
+    pub id: Uuid,
+    pub role: ExtractRole,
+    /// file currently being written
+    pub current: Option<ExtractedFile>,
+    /// index of next file or data connection
+    pub next_index: u64,
+    /// user name sent by client
+    pub user: Option<String>,
+    /// data connection announced last, awaiting a transfer command
+    pub last_channel: Option<SocketAddr>,
+
+    events: Vec<ExtractEvent>,
+    gaps: Vec<StreamGap>,
+    segments: Vec<SegmentInfo>,
+    buf: Vec<u8>,
+}
+
+impl ExtractHandler {
+    /// read data from stream and feed to parser or data file
+    pub fn read_stream(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        len: usize,
+    ) -> eyre::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        self.gaps.clear();
+        self.segments.clear();
+        self.buf.clear();
+        let stream = connection.get_stream(direction);
+        let end_offset = stream.buffer_start() + len as u64;
+        let buf = &mut self.buf;
+        let read = stream.read_next(end_offset, &mut self.segments, &mut self.gaps, |slice| {
+            let (a, b) = slice.as_slices();
+            buf.extend_from_slice(a);
+            if let Some(b) = b {
+                buf.extend_from_slice(b);
+            }
+        });
+        if read.is_none() {
+            error!("extract: {direction} stream cannot fulfill range, skipping");
+            return Ok(());
+        }
+
+        if matches!(self.role, ExtractRole::Unknown) {
+            self.role = self.choose_role(connection);
+        }
+        match &mut self.role {
+            ExtractRole::Session(parser) => {
+                if !self.gaps.is_empty() {
+                    parser.fail(direction);
+                }
+                parser.feed(direction, &self.buf, &mut self.events);
+                self.handle_events()
+            }
+            ExtractRole::FtpData { channel, lost, .. } => {
+                // gaps are zero-filled, keeping file offsets intact
+                *lost |= !self.gaps.is_empty();
+                let file = match &mut self.current {
+                    Some(file) => file,
+                    None => {
+                        let info = ExtractedFileInfo {
+                            id: self.id,
+                            protocol: AppProtocol::Ftp,
+                            index: channel.index,
+                            control_id: Some(channel.control_id),
+                            command: channel.command.clone(),
+                            user: channel.user.clone(),
+                            mail_from: None,
+                            rcpt_to: Vec::new(),
+                            file: format!("{}.ftp.{}", channel.control_id, channel.index),
+                            len: 0,
+                            complete: false,
+                        };
+                        let base_dir = &self.shared_info.inner.base_dir;
+                        self.current.insert(ExtractedFile::create(base_dir, info)?)
+                    }
+                };
+                file.write(&self.buf)
+            }
+            ExtractRole::Unknown | ExtractRole::Ignored => Ok(()),
+        }
+    }
+
+    /// decide role from announced data connections and protocol guess
+    fn choose_role(&self, connection: &Connection<Self>) -> ExtractRole {
+        let flow = &connection.forward_flow;
+        let dst = SocketAddr::new(flow.dst_addr, flow.dst_port);
+        let src = SocketAddr::new(flow.src_addr, flow.src_port);
+        for addr in [dst, src] {
+            if let Some(channel) = self.shared_info.find_channel(addr) {
+                debug!(
+                    "extract: {} is data connection {} of {}",
+                    connection.uuid, channel.index, channel.control_id
+                );
+                return ExtractRole::FtpData {
+                    addr,
+                    channel,
+                    lost: false,
+                };
+            }
+        }
+        connection
+            .classification
+            .protocol()
+            .and_then(|protocol| SessionParser::new(protocol, flow.dst_addr))
+            .map_or(ExtractRole::Ignored, ExtractRole::Session)
+    }
+
+    /// process events from session parser
+    fn handle_events(&mut self) -> eyre::Result<()> {
+        let mut events = std::mem::take(&mut self.events);
+        for event in events.drain(..) {
+            match event {
+                ExtractEvent::User(user) => self.user = Some(user),
+                ExtractEvent::DataChannel(addr) => {
+                    let channel = FtpDataChannel {
+                        control_id: self.id,
+                        index: self.next_index,
+                        user: self.user.clone(),
+                        command: None,
+                    };
+                    trace!("ftp: expecting data connection to {addr}");
+                    self.next_index += 1;
+                    self.last_channel = Some(addr);
+                    self.shared_info
+                        .inner
+                        .ftp_channels
+                        .lock()
+                        .insert(addr, channel);
+                }
+                ExtractEvent::Transfer(command) => {
+                    let Some(addr) = self.last_channel.take() else {
+                        debug!("ftp: {command} without data connection");
+                        continue;
+                    };
+                    let mut channels = self.shared_info.inner.ftp_channels.lock();
+                    if let Some(channel) = channels.get_mut(&addr) {
+                        if channel.control_id == self.id {
+                            channel.command = Some(command);
+                        }
+                    }
+                }
+                ExtractEvent::MessageStart {
+                    command,
+                    mail_from,
+                    rcpt_to,
+                } => {
+                    if let Some(previous) = self.current.take() {
+                        self.finish_file(previous)?;
+                    }
+                    let ExtractRole::Session(parser) = &self.role else {
+                        continue;
+                    };
+                    let protocol = parser.protocol();
+                    let index = self.next_index;
+                    self.next_index += 1;
+                    let info = ExtractedFileInfo {
+                        id: self.id,
+                        protocol,
+                        index,
+                        control_id: None,
+                        command: Some(command),
+                        user: self.user.clone(),
+                        mail_from,
+                        rcpt_to,
+                        file: format!("{}.{index}.eml", self.id),
+                        len: 0,
+                        complete: false,
+                    };
+                    let base_dir = &self.shared_info.inner.base_dir;
+                    self.current = Some(ExtractedFile::create(base_dir, info)?);
+                }
+                ExtractEvent::MessageData(data) => {
+                    if let Some(file) = &mut self.current {
+                        file.write(&data)?;
+                    }
+                }
+                ExtractEvent::MessageEnd => {
+                    if let Some(mut file) = self.current.take() {
+                        file.info.complete = true;
+                        self.finish_file(file)?;
+                    }
+                }
+                ExtractEvent::Encrypted => debug!("extract: {} switched to TLS", self.id),
+            }
+        }
+        self.events = events;
+        Ok(())
+    }
+
+    /// flush file and record it in index
+    fn finish_file(&mut self, mut file: ExtractedFile) -> eyre::Result<()> {
+        file.file.flush().wrap_err("flushing extracted file")?;
+        self.shared_info
+            .record_file(&file.info)
+            .wrap_err("writing extraction index")
+    }
+
+    /// read all remaining data and record unfinished files
+    pub fn write_remaining(&mut self, connection: &mut Connection<Self>) -> eyre::Result<()> {
+        for direction in [Direction::Forward, Direction::Reverse] {
+            let len = connection.get_stream(direction).total_buffered_length();
+            self.read_stream(connection, direction, len)?;
+        }
+        match &mut self.role {
+            ExtractRole::FtpData {
+                addr,
+                channel,
+                lost,
+            } => {
+                if let Some(latest) = self.shared_info.release_channel(*addr, channel) {
+                    *channel = latest;
+                }
+                let ended =
+                    connection.forward_stream.has_ended || connection.reverse_stream.has_ended;
+                if let Some(file) = &mut self.current {
+                    // transfer command may have been seen after the file was created
+                    file.info.command = channel.command.clone();
+                    file.info.complete = ended && !*lost;
+                }
+            }
+            ExtractRole::Session(SessionParser::Ftp(_)) => {
+                // forget data connections that never showed up
+                let mut channels = self.shared_info.inner.ftp_channels.lock();
+                channels.retain(|_, channel| channel.control_id != self.id);
+            }
+            _ => {}
+        }
+        if let Some(file) = self.current.take() {
+            self.finish_file(file)?;
+        }
+        Ok(())
+    }
+}
+
+impl ConnectionHandler for ExtractHandler {
+    type InitialData = ExtractSharedInfo;
+    type ConstructError = eyre::Report;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> eyre::Result<Self> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(ExtractHandler {
+            shared_info,
+            id: connection.uuid,
+            role: ExtractRole::Unknown,
+            current: None,
+            next_index: 0,
+            user: None,
+            last_channel: None,
+            events: Vec::new(),
+            gaps: Vec::new(),
+            segments: Vec::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        let result = if readable_len > 0 {
+            self.read_stream(connection, direction, readable_len)
+        } else if stream.total_buffered_length() > MAX_LINE_LENGTH {
+            // stuck behind a gap, skip ahead
+            let len = stream.total_buffered_length();
+            self.read_stream(connection, direction, len)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            tracing::error!("failed to extract data: {e:?}");
+        }
+    }
+
+    fn gap_detected(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        direction: Direction,
+        _range: Range<u64>,
+    ) {
+        match &mut self.role {
+            ExtractRole::Session(parser) => parser.fail(direction),
+            ExtractRole::FtpData { lost, .. } => *lost = true,
+            ExtractRole::Unknown | ExtractRole::Ignored => {}
+        }
+    }
+
+    fn direction_changed(&mut self, _connection: &mut Connection<Self>) {
+        if self.next_index == 0 && matches!(self.role, ExtractRole::Session(_)) {
+            // nothing extracted yet, data so far went to the wrong parsers
+            self.role = ExtractRole::Unknown;
+        }
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        info!(
+            "removing connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        if let Err(e) = self.write_remaining(connection) {
+            tracing::error!("failed to write remaining extracted data: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use super::{ExtractEvent, FtpControlParser, Pop3Parser, SmtpParser};
+    use crate::connection::Direction;
+
+    fn message_data(events: &[ExtractEvent]) -> Vec<u8> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                ExtractEvent::MessageData(data) => Some(data.as_slice()),
+                _ => None,
+            })
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn ftp_data_channels() {
+        let mut parser = FtpControlParser::new("10.0.0.2".parse().unwrap());
+        let mut events = Vec::new();
+        parser.feed(
+            Direction::Reverse,
+            b"220 FTP ready\r\n227 Entering Passive Mode (10,0,0,2,19,137).\r\n",
+            &mut events,
+        );
+        parser.feed(Direction::Forward, b"USER anon\r\nretr notes", &mut events);
+        parser.feed(
+            Direction::Forward,
+            b".txt\r\nPORT 10,0,0,1,4,1\r\nEPRT |2|::1|5282|\r\n",
+            &mut events,
+        );
+        parser.feed(
+            Direction::Reverse,
+            b"229 Entering Extended Passive Mode (|||6446|)\r\n",
+            &mut events,
+        );
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            events,
+            [
+                ExtractEvent::DataChannel(addr("10.0.0.2:5001")),
+                ExtractEvent::User("anon".into()),
+                ExtractEvent::Transfer("retr notes.txt".into()),
+                ExtractEvent::DataChannel(addr("10.0.0.1:1025")),
+                ExtractEvent::DataChannel(addr("[::1]:5282")),
+                ExtractEvent::DataChannel(addr("10.0.0.2:6446")),
+            ]
+        );
+
+        events.clear();
+        parser.feed(
+            Direction::Forward,
+            b"AUTH TLS\r\nPORT 1,2,3,4,5,6\r\n",
+            &mut events,
+        );
+        assert_eq!(events, [ExtractEvent::Encrypted]);
+    }
+
+    #[test]
+    fn smtp_messages() {
+        let mut parser = SmtpParser::default();
+        let mut events = Vec::new();
+        parser.feed(
+            Direction::Forward,
+            b"EHLO a\r\nMAIL FROM:<a@example.com> SIZE=20\r\nRCPT TO:<b@example.com>\r\nDATA\r\n",
+            &mut events,
+        );
+        parser.feed(
+            Direction::Forward,
+            b"Subject: x\r\n\r\n..dot\r\n",
+            &mut events,
+        );
+        parser.feed(Direction::Forward, b".\r\nQUIT\r\n", &mut events);
+        assert_eq!(
+            events[0],
+            ExtractEvent::MessageStart {
+                command: "DATA".into(),
+                mail_from: Some("a@example.com".into()),
+                rcpt_to: vec!["b@example.com".into()],
+            }
+        );
+        assert_eq!(message_data(&events), b"Subject: x\r\n\r\n.dot\r\n");
+        assert_eq!(events.last(), Some(&ExtractEvent::MessageEnd));
+
+        // chunks are not dot-stuffed and may contain anything
+        let mut parser = SmtpParser::default();
+        let mut events = Vec::new();
+        parser.feed(
+            Direction::Forward,
+            b"BDAT 5\r\n.\r\nabBDAT 3 LAST\r\n",
+            &mut events,
+        );
+        parser.feed(Direction::Forward, b"xyzNOOP\r\n", &mut events);
+        let starts = events
+            .iter()
+            .filter(|e| matches!(e, ExtractEvent::MessageStart { .. }))
+            .count();
+        assert_eq!(starts, 1);
+        assert_eq!(message_data(&events), b".\r\nabxyz");
+        assert_eq!(events.last(), Some(&ExtractEvent::MessageEnd));
+    }
+
+    #[test]
+    fn pop3_retrieve() {
+        let mut parser = Pop3Parser::default();
+        let mut events = Vec::new();
+        parser.feed(Direction::Reverse, b"+OK ready\r\n", &mut events);
+        parser.feed(
+            Direction::Forward,
+            b"USER bob\r\nPASS x\r\nLIST\r\n",
+            &mut events,
+        );
+        parser.feed(
+            Direction::Reverse,
+            b"+OK\r\n+OK\r\n+OK 1 messages\r\n1 120\r\n.\r\n",
+            &mut events,
+        );
+        parser.feed(Direction::Forward, b"RETR 1\r\nRETR 2\r\n", &mut events);
+        parser.feed(
+            Direction::Reverse,
+            b"+OK\r\nFrom: a\r\n\r\n..hi\r\n.\r\n-ERR no such message\r\n",
+            &mut events,
+        );
+        assert_eq!(
+            events,
+            [
+                ExtractEvent::User("bob".into()),
+                ExtractEvent::MessageStart {
+                    command: "RETR 1".into(),
+                    mail_from: None,
+                    rcpt_to: Vec::new(),
+                },
+                ExtractEvent::MessageData(b"From: a\r\n".to_vec()),
+                ExtractEvent::MessageData(b"\r\n".to_vec()),
+                ExtractEvent::MessageData(b".hi\r\n".to_vec()),
+                ExtractEvent::MessageEnd,
+            ]
+        );
+    }
+}
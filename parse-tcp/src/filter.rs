@@ -0,0 +1,358 @@
+//! BPF-like flow filter expressions
+//!
+//! Supported primitives, optionally prefixed by `src` or `dst`:
+//! - `host <addr>`
+//! - `net <addr>/<prefix length>`
+//! - `port <port>`
+//! - `portrange <low>-<high>`
+//!
+//! as well as the protocol predicates `tcp`, `udp`, `ip` and `ip6`.
+//! Primitives can be combined with `and`/`&&`, `or`/`||`, `not`/`!` and
+//! parentheses. `not` binds tightest, followed by `and`, then `or`.
+//!
+//! Filters are evaluated against the flow of the first packet seen for a
+//! connection, so `src` refers to the sender of that packet.
+
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::flow_table::{Flow, IPPROTO_TCP, IPPROTO_UDP};
+
+/// which end of a flow a predicate applies to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterDirection {
+    /// either source or destination
+    Any,
+    /// source only
+    Src,
+    /// destination only
+    Dst,
+}
+
+/// parsed filter expression
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterExpr {
+    And(Box<FilterExpr>, Box<FilterExpr>),
+    Or(Box<FilterExpr>, Box<FilterExpr>),
+    Not(Box<FilterExpr>),
+    /// address within network (`host` is a network with full prefix length)
+    Net {
+        direction: FilterDirection,
+        addr: IpAddr,
+        prefix_len: u8,
+    },
+    /// port within range
+    Port {
+        direction: FilterDirection,
+        ports: RangeInclusive<u16>,
+    },
+    /// IP protocol number
+    Proto(u8),
+    /// IPv4 flows
+    Ipv4,
+    /// IPv6 flows
+    Ipv6,
+}
+
+/// error parsing filter expression
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterParseError {
+    pub message: String,
+}
+
+impl Display for FilterParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid filter: {}", self.message)
+    }
+}
+
+impl std::error::Error for FilterParseError {}
+
+fn parse_error<T>(message: impl Into<String>) -> Result<T, FilterParseError> {
+    Err(FilterParseError {
+        message: message.into(),
+    })
+}
+
+/// test whether `addr` is within `net`/`prefix_len`
+fn in_network(addr: IpAddr, net: IpAddr, prefix_len: u8) -> bool {
+    match (addr, net) {
+        (IpAddr::V4(addr), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            u32::from(addr) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(addr), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            u128::from(addr) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+impl FilterExpr {
+    /// parse filter expression
+    pub fn parse(input: &str) -> Result<FilterExpr, FilterParseError> {
+        let tokens = tokenize(input);
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => parse_error(format!("unexpected `{token}`")),
+        }
+    }
+
+    /// test whether flow matches the filter
+    pub fn matches(&self, flow: &Flow) -> bool {
+        let check = |direction: FilterDirection, test: &dyn Fn(IpAddr, u16) -> bool| {
+            let src = test(flow.src_addr, flow.src_port);
+            let dst = test(flow.dst_addr, flow.dst_port);
+            match direction {
+                FilterDirection::Any => src || dst,
+                FilterDirection::Src => src,
+                FilterDirection::Dst => dst,
+            }
+        };
+        match self {
+            FilterExpr::And(a, b) => a.matches(flow) && b.matches(flow),
+            FilterExpr::Or(a, b) => a.matches(flow) || b.matches(flow),
+            FilterExpr::Not(a) => !a.matches(flow),
+            FilterExpr::Net {
+                direction,
+                addr,
+                prefix_len,
+            } => check(*direction, &|a, _| in_network(a, *addr, *prefix_len)),
+            FilterExpr::Port { direction, ports } => check(*direction, &|_, p| ports.contains(&p)),
+            FilterExpr::Proto(proto) => flow.proto == *proto,
+            FilterExpr::Ipv4 => flow.src_addr.is_ipv4(),
+            FilterExpr::Ipv6 => flow.src_addr.is_ipv6(),
+        }
+    }
+}
+
+impl FromStr for FilterExpr {
+    type Err = FilterParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FilterExpr::parse(s)
+    }
+}
+
+/// split input into words and parentheses
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for c in input.chars() {
+        if c.is_whitespace() || c == '(' || c == ')' || c == '!' {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// recursive descent parser over tokens
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&str, FilterParseError> {
+        let Some(token) = self.tokens.get(self.pos) else {
+            return parse_error("unexpected end of expression");
+        };
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn parse_or(&mut self) -> Result<FilterExpr, FilterParseError> {
+        let mut expr = self.parse_and()?;
+        while matches!(self.peek(), Some("or" | "||")) {
+            self.pos += 1;
+            expr = FilterExpr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<FilterExpr, FilterParseError> {
+        let mut expr = self.parse_unary()?;
+        while matches!(self.peek(), Some("and" | "&&")) {
+            self.pos += 1;
+            expr = FilterExpr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<FilterExpr, FilterParseError> {
+        match self.peek() {
+            Some("not" | "!") => {
+                self.pos += 1;
+                Ok(FilterExpr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some("(") => {
+                self.pos += 1;
+                let expr = self.parse_or()?;
+                match self.next()? {
+                    ")" => Ok(expr),
+                    token => parse_error(format!("expected `)`, got `{token}`")),
+                }
+            }
+            _ => self.parse_primitive(),
+        }
+    }
+
+    fn parse_primitive(&mut self) -> Result<FilterExpr, FilterParseError> {
+        let direction = match self.peek() {
+            Some("src") => FilterDirection::Src,
+            Some("dst") => FilterDirection::Dst,
+            _ => FilterDirection::Any,
+        };
+        if direction != FilterDirection::Any {
+            self.pos += 1;
+        }
+        let keyword = self.next()?.to_owned();
+        match keyword.as_str() {
+            "host" => {
+                let value = self.next()?;
+                let Ok(addr) = value.parse::<IpAddr>() else {
+                    return parse_error(format!("invalid address `{value}`"));
+                };
+                let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+                Ok(FilterExpr::Net {
+                    direction,
+                    addr,
+                    prefix_len,
+                })
+            }
+            "net" => {
+                let value = self.next()?;
+                let (addr, prefix_len) = value.split_once('/').unwrap_or((value, ""));
+                let Ok(addr) = addr.parse::<IpAddr>() else {
+                    return parse_error(format!("invalid network `{value}`"));
+                };
+                let max_len = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix_len = match prefix_len {
+                    "" => max_len,
+                    len => match len.parse::<u8>() {
+                        Ok(len) if len <= max_len => len,
+                        _ => return parse_error(format!("invalid prefix length in `{value}`")),
+                    },
+                };
+                Ok(FilterExpr::Net {
+                    direction,
+                    addr,
+                    prefix_len,
+                })
+            }
+            "port" => {
+                let value = self.next()?;
+                let Ok(port) = value.parse::<u16>() else {
+                    return parse_error(format!("invalid port `{value}`"));
+                };
+                Ok(FilterExpr::Port {
+                    direction,
+                    ports: port..=port,
+                })
+            }
+            "portrange" => {
+                let value = self.next()?;
+                let range = value
+                    .split_once('-')
+                    .and_then(|(low, high)| Some((low.parse().ok()?, high.parse().ok()?)));
+                match range {
+                    Some((low, high)) if low <= high => Ok(FilterExpr::Port {
+                        direction,
+                        ports: low..=high,
+                    }),
+                    _ => parse_error(format!("invalid port range `{value}`")),
+                }
+            }
+            _ if direction != FilterDirection::Any => parse_error(format!(
+                "expected host, net, port or portrange, got `{keyword}`"
+            )),
+            "tcp" => Ok(FilterExpr::Proto(IPPROTO_TCP)),
+            "udp" => Ok(FilterExpr::Proto(IPPROTO_UDP)),
+            "ip" => Ok(FilterExpr::Ipv4),
+            "ip6" => Ok(FilterExpr::Ipv6),
+            _ => parse_error(format!("unknown primitive `{keyword}`")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FilterExpr;
+    use crate::flow_table::{Flow, IPPROTO_TCP, IPPROTO_UDP};
+
+    fn flow(proto: u8, src: &str, src_port: u16, dst: &str, dst_port: u16) -> Flow {
+        Flow {
+            proto,
+            src_addr: src.parse().unwrap(),
+            src_port,
+            dst_addr: dst.parse().unwrap(),
+            dst_port,
+        }
+    }
+
+    #[test]
+    fn parse_and_match() {
+        let web = flow(IPPROTO_TCP, "10.0.0.1", 40000, "192.168.1.5", 443);
+        let dns = flow(IPPROTO_UDP, "10.0.0.1", 5353, "8.8.8.8", 53);
+        let v6 = flow(IPPROTO_TCP, "2001:db8::1", 40000, "2001:db8::2", 80);
+        let check = |filter: &str, expected: [bool; 3]| {
+            let filter: FilterExpr = filter.parse().unwrap();
+            assert_eq!(
+                [&web, &dns, &v6].map(|f| filter.matches(f)),
+                expected,
+                "{filter:?}"
+            );
+        };
+        check("host 10.0.0.1", [true, true, false]);
+        check("dst host 10.0.0.1", [false, false, false]);
+        check(
+            "net 192.168.0.0/16 or dst net 2001:db8::/32",
+            [true, false, true],
+        );
+        check("tcp and port 443", [true, false, false]);
+        check(
+            "src portrange 5000-6000 || dst port 80",
+            [false, true, true],
+        );
+        check("not (udp or ip6)", [true, false, false]);
+        check("!tcp && ip", [false, true, false]);
+        check("ip6 or tcp and port 53", [false, false, true]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for filter in [
+            "",
+            "host",
+            "host nope",
+            "net 10.0.0.0/33",
+            "port 65536",
+            "portrange 10-5",
+            "src tcp",
+            "(tcp",
+            "tcp udp",
+            "bogus",
+        ] {
+            assert!(FilterExpr::parse(filter).is_err(), "{filter}");
+        }
+    }
+}
@@ -1,16 +1,25 @@
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::mem;
 use std::net::IpAddr;
+use std::sync::Arc;
 
 use kinesin_rdt::common::ring_buffer::RingBuf;
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 use tracing::warn;
 
+use crate::config::ReassemblyConfig;
+use crate::connection::CloseReason;
 use crate::connection::Connection;
 use crate::connection::ConnectionState;
 use crate::connection::Direction;
+use crate::filter::FilterExpr;
+use crate::log_throttle;
+use crate::memory::MEMORY_PRESSURE_BACKOFF;
 use crate::serialized::PacketExtra;
+use crate::stats::StatsCollector;
 use crate::ConnectionHandler;
 use crate::TcpMeta;
 
@@ -18,7 +27,7 @@ use crate::TcpMeta;
 pub const IPPROTO_TCP: u8 = 6;
 pub const IPPROTO_UDP: u8 = 17;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Flow {
     pub proto: u8,
     pub src_addr: IpAddr,
@@ -176,6 +185,19 @@ where
     pub retired: RingBuf<Connection<H>>,
     /// whether retired connections should be saved
     pub save_retired: bool,
+    /// statistics of retired connections, if enabled
+    pub stats: Option<StatsCollector>,
+    /// only create connections for flows matching this filter
+    pub filter: Option<FilterExpr>,
+    /// number of packets dropped because they did not match the filter
+    pub filtered_packets: u64,
+    /// number of connections retired because a new connection reused their
+    /// port pair
+    pub reused_flows: u64,
+    /// reassembly limits for new connections
+    pub config: Arc<ReassemblyConfig>,
+    /// packets left before streams are scanned for memory pressure again
+    pub memory_pressure_backoff: u32,
     /// initial data for ConnectionHandler
     pub handler_init_data: H::InitialData,
 }
@@ -198,10 +220,21 @@ where
 {
     /// create new instance
     pub fn new(handler_init_data: H::InitialData) -> Self {
+        Self::with_config(handler_init_data, ReassemblyConfig::default())
+    }
+
+    /// create new instance with reassembly limits
+    pub fn with_config(handler_init_data: H::InitialData, config: ReassemblyConfig) -> Self {
         Self {
             map: HashMap::new(),
             retired: RingBuf::new(),
             save_retired: false,
+            stats: None,
+            filter: None,
+            filtered_packets: 0,
+            reused_flows: 0,
+            config: Arc::new(config),
+            memory_pressure_backoff: 0,
             handler_init_data,
         }
     }
@@ -213,12 +246,30 @@ where
         data: &[u8],
         extra: &PacketExtra,
     ) -> Result<bool, H::ConstructError> {
+        if let Some(metrics) = &self.config.metrics {
+            metrics.packet_handled();
+        }
+        let flow: Flow = meta.into();
+        if let Some(conn) = self.map.get_mut(&flow) {
+            if conn.is_reused_by(meta) {
+                // retire old connection, new one is created below
+                debug!("handle_packet: SYN outside of sequence space, flow reused");
+                conn.close_reason.get_or_insert(CloseReason::Reused);
+                self.retire_flow(flow);
+                self.reused_flows += 1;
+            }
+        }
         match self.handle_packet_direct(meta, data, extra) {
             HandlePacketResult::Ok => Ok(true),
             HandlePacketResult::Dropped => Ok(false),
             HandlePacketResult::NotFound => {
+                let flow: Flow = meta.into();
+                if self.filter.as_ref().is_some_and(|f| !f.matches(&flow)) {
+                    self.filtered_packets += 1;
+                    return Ok(false);
+                }
                 // create the flow, then process again
-                self.create_flow(meta.into(), self.handler_init_data.clone())?;
+                self.create_flow(flow, self.handler_init_data.clone())?;
                 match self.handle_packet_direct(meta, data, extra) {
                     HandlePacketResult::Ok => Ok(true),
                     HandlePacketResult::Dropped => Ok(false),
@@ -226,20 +277,58 @@ where
                 }
             }
             HandlePacketResult::Desync => {
-                // remove flow, then recreate and try again
                 debug!("handle_packet: got desync, recreating flow");
-                let flow: Flow = meta.into();
-                self.retire_flow(flow.clone());
-                self.create_flow(flow, self.handler_init_data.clone())?;
-                match self.handle_packet_direct(meta, data, extra) {
-                    HandlePacketResult::Ok => Ok(true),
-                    HandlePacketResult::Dropped => Ok(false),
-                    _ => unreachable!("result not possible"),
-                }
+                self.recreate_flow(meta, data, extra)
             }
         }
     }
 
+    /// remove desynchronized flow, then recreate and handle packets held
+    /// back by it, or the current packet if none were
+    fn recreate_flow(
+        &mut self,
+        meta: &TcpMeta,
+        data: &[u8],
+        extra: &PacketExtra,
+    ) -> Result<bool, H::ConstructError> {
+        let flow: Flow = meta.into();
+        let held = self
+            .map
+            .get_mut(&flow)
+            .map(|conn| mem::take(&mut conn.desync_packets))
+            .unwrap_or_default();
+        self.retire_flow(flow.clone());
+        self.create_flow(flow, self.handler_init_data.clone())?;
+        if held.is_empty() {
+            return self.replay_packet(meta, data, extra);
+        }
+        // held packets end with the current one
+        let mut handled = false;
+        for packet in held {
+            handled = self.replay_packet(&packet.meta, &packet.data, &packet.extra)?;
+        }
+        Ok(handled)
+    }
+
+    /// handle a packet for a recreated flow
+    fn replay_packet(
+        &mut self,
+        meta: &TcpMeta,
+        data: &[u8],
+        extra: &PacketExtra,
+    ) -> Result<bool, H::ConstructError> {
+        match self.handle_packet_direct(meta, data, extra) {
+            HandlePacketResult::Ok => Ok(true),
+            HandlePacketResult::Dropped => Ok(false),
+            HandlePacketResult::NotFound => {
+                // connection closed by an earlier held packet
+                self.create_flow(meta.into(), self.handler_init_data.clone())?;
+                self.replay_packet(meta, data, extra)
+            }
+            HandlePacketResult::Desync => self.recreate_flow(meta, data, extra),
+        }
+    }
+
     /// handle a packet, return Err if flow does not exist (and return args)
     pub fn handle_packet_direct(
         &mut self,
@@ -260,6 +349,7 @@ where
                     }
                     _ => {}
                 }
+                self.relieve_memory_pressure();
                 if did_something {
                     HandlePacketResult::Ok
                 } else {
@@ -276,9 +366,13 @@ where
         flow: Flow,
         init_data: H::InitialData,
     ) -> Result<Option<Connection<H>>, H::ConstructError> {
-        let conn = Connection::new(flow.clone(), init_data)?;
+        let conn = Connection::with_config(flow.clone(), self.config.clone(), init_data)?;
         debug!("new flow: {} {flow}", conn.uuid);
-        Ok(self.map.insert(flow, conn))
+        let old = self.map.insert(flow, conn);
+        if let (None, Some(metrics)) = (&old, &self.config.metrics) {
+            metrics.flow_created();
+        }
+        Ok(old)
     }
 
     pub fn retire_flow(&mut self, flow: Flow) {
@@ -289,30 +383,114 @@ where
 
         debug!("remove flow: {} {flow}", conn.uuid);
         conn.will_retire();
+        if let Some(stats) = &mut self.stats {
+            stats.record(&conn);
+        }
+        if let Some(metrics) = &self.config.metrics {
+            metrics.flow_retired();
+        }
         if self.save_retired {
             self.retired.push_back(conn);
         }
     }
 
+    /// while the memory budget is exceeded, ask handlers of the largest
+    /// streams to write out buffered data
+    ///
+    /// Streams whose handler did not reduce their usage on the previous call
+    /// are skipped until they grow. If the budget is still exceeded after all
+    /// other streams were asked, the next `MEMORY_PRESSURE_BACKOFF` packets
+    /// over budget do not scan again.
+    pub fn relieve_memory_pressure(&mut self) {
+        let Some(budget) = self.config.memory_budget.clone() else {
+            return;
+        };
+        if !budget.exceeded() {
+            return;
+        }
+        if self.memory_pressure_backoff > 0 {
+            self.memory_pressure_backoff -= 1;
+            return;
+        }
+        let mut streams: Vec<(Flow, Direction)> = Vec::new();
+        let mut sizes: Vec<(u64, usize)> = Vec::new();
+        for (flow, conn) in &self.map {
+            for (stream, direction) in [
+                (&conn.forward_stream, Direction::Forward),
+                (&conn.reverse_stream, Direction::Reverse),
+            ] {
+                let size = stream.memory_accounted;
+                if size == 0 || stream.memory_pressure_unrelieved >= Some(size) {
+                    continue;
+                }
+                sizes.push((size, streams.len()));
+                streams.push((flow.clone(), direction));
+            }
+        }
+        // heapify is linear, only streams actually flushed are popped
+        let mut sizes = BinaryHeap::from(sizes);
+        while budget.exceeded() {
+            let Some((_, index)) = sizes.pop() else {
+                break;
+            };
+            let (flow, direction) = &streams[index];
+            let direction = *direction;
+            let conn = self.map.get_mut(flow).expect("flow exists");
+            debug!(
+                "memory budget exceeded ({} of {} bytes), flushing {direction} stream of {}",
+                budget.used(),
+                budget.limit(),
+                conn.uuid
+            );
+            budget.record_pressure_call();
+            let before = conn.get_stream(direction).memory_accounted;
+            conn.call_handler(|conn, h| h.memory_pressure(conn, direction));
+            let stream = conn.get_stream(direction);
+            stream.memory_pressure_unrelieved =
+                (stream.memory_accounted >= before).then_some(stream.memory_accounted);
+        }
+        if budget.exceeded() {
+            debug!(
+                "memory budget still exceeded after flushing ({} of {} bytes)",
+                budget.used(),
+                budget.limit()
+            );
+            self.memory_pressure_backoff = MEMORY_PRESSURE_BACKOFF;
+        }
+    }
+
     /// close flowtable and retire all flows
     pub fn close(&mut self) {
         debug!("flowtable closing");
         for (flow, mut conn) in self.map.drain() {
             debug!("remove flow: {} {flow}", conn.uuid);
+            conn.close_reason.get_or_insert(CloseReason::Eof);
             conn.will_retire();
+            if let Some(stats) = &mut self.stats {
+                stats.record(&conn);
+            }
+            if let Some(metrics) = &self.config.metrics {
+                metrics.flow_retired();
+            }
             if self.save_retired {
                 self.retired.push_back(conn);
             }
         }
+        log_throttle::flush();
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::mem;
     use std::net::Ipv4Addr;
 
-    use super::{Flow, IPPROTO_TCP};
+    use super::{Flow, FlowTable, IPPROTO_TCP};
+    use crate::connection::{CloseReason, Connection, ConnectionState};
+    use crate::serialized::PacketExtra;
+    use crate::{ConnectionHandler, TcpFlags, TcpMeta};
 
     #[test]
     fn hash_map() {
@@ -354,4 +532,83 @@ mod test {
         assert_eq!(map.get(&forward), Some(&"test 2".into()));
         assert_eq!(map.get(&unrelated), Some(&"test 3".into()));
     }
+
+    #[test]
+    fn reuse() {
+        struct NullHandler;
+        impl ConnectionHandler for NullHandler {
+            type InitialData = ();
+            type ConstructError = Infallible;
+            fn new(_init: (), _conn: &mut Connection<Self>) -> Result<Self, Infallible> {
+                Ok(NullHandler)
+            }
+        }
+
+        let syn = TcpMeta {
+            src_addr: [10, 4, 0, 1].into(),
+            src_port: 40010,
+            dst_addr: [10, 4, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut table: FlowTable<NullHandler> = FlowTable::new(());
+        table.save_retired = true;
+        let mut syn_ack = syn.clone();
+        mem::swap(&mut syn_ack.src_addr, &mut syn_ack.dst_addr);
+        mem::swap(&mut syn_ack.src_port, &mut syn_ack.dst_port);
+        syn_ack.seq_number = 5000;
+        syn_ack.ack_number = 1001;
+        syn_ack.flags.ack = true;
+        let mut data = syn.clone();
+        data.seq_number = 1001;
+        data.ack_number = 5001;
+        data.flags = TcpFlags {
+            ack: true,
+            ..Default::default()
+        };
+        for (meta, payload) in [(&syn, &b""[..]), (&syn_ack, b""), (&data, b"hello")] {
+            assert!(table
+                .handle_packet(meta, payload, &PacketExtra::None)
+                .unwrap());
+        }
+        let first = table.map.get(&(&syn).into()).unwrap().uuid;
+
+        // new handshake with an unrelated ISN starts a new connection
+        let mut new_syn = syn.clone();
+        new_syn.seq_number = 0x8000_0000;
+        assert!(table
+            .handle_packet(&new_syn, &[], &PacketExtra::None)
+            .unwrap());
+        assert_eq!(table.reused_flows, 1);
+        assert_eq!(table.retired.len(), 1);
+        let old = &table.retired[0];
+        assert_eq!(old.uuid, first);
+        assert_eq!(old.close_reason, Some(CloseReason::Reused));
+        assert_eq!(old.forward_stream.readable_buffered_length(), 5);
+        let conn = table.map.get(&(&syn).into()).unwrap();
+        assert_ne!(conn.uuid, first);
+        assert_eq!(
+            conn.conn_state,
+            ConnectionState::SynSent {
+                seq_no: 0x8000_0000
+            }
+        );
+    }
 }
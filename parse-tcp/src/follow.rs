@@ -0,0 +1,480 @@
+//! Follow-stream export
+//!
+//! Renders connections as an interleaved conversation in the text format of
+//! Wireshark's "Follow TCP Stream" (as printed by `tshark -z follow`). Data of
+//! the two directions is ordered using `reverse_acked` of each segment: a
+//! segment is placed after all data of the other direction that its sender had
+//! acknowledged. Missing (gap) data is omitted.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use crate::connection::{Connection, Direction};
+use crate::flow_table::Flow;
+use crate::stream::{SegmentInfo, SegmentType, StreamGap};
+use crate::ConnectionHandler;
+
+/// separator line around output
+const SEPARATOR: &str = "===================================================================";
+
+/// output format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FollowFormat {
+    /// printable characters, others replaced by `.`
+    Ascii,
+    /// hexdump with offsets
+    Hex,
+}
+
+impl FollowFormat {
+    fn name(self) -> &'static str {
+        match self {
+            FollowFormat::Ascii => "ascii",
+            FollowFormat::Hex => "hex",
+        }
+    }
+}
+
+/// data of a single segment
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FollowChunk {
+    /// stream offsets of data, excluding data already seen in earlier segments
+    pub range: Range<u64>,
+    /// offset acknowledged in the other direction when this segment was sent
+    pub reverse_acked: u64,
+    /// capture time of segment
+    pub timestamp: Option<Duration>,
+}
+
+/// collected data of one direction
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FollowStream {
+    /// stream offset of first byte in `data`
+    pub base_offset: u64,
+    pub data: Vec<u8>,
+    pub chunks: Vec<FollowChunk>,
+    /// end of data covered by chunks, to skip retransmitted data
+    pub covered: u64,
+}
+
+impl FollowStream {
+    /// append data starting at `offset`
+    pub fn add_data(&mut self, offset: u64, data: &[u8]) {
+        if self.data.is_empty() {
+            self.base_offset = offset;
+            self.covered = self.covered.max(offset);
+        }
+        debug_assert_eq!(self.base_offset + self.data.len() as u64, offset);
+        self.data.extend_from_slice(data);
+    }
+
+    /// record data segment, segments must be added in order of offset
+    pub fn add_segment(&mut self, segment: &SegmentInfo) {
+        let SegmentType::Data { len, .. } = segment.data else {
+            return;
+        };
+        let start = segment.offset.max(self.covered);
+        let end = segment.offset + len as u64;
+        if start >= end {
+            return;
+        }
+        self.covered = end;
+        self.chunks.push(FollowChunk {
+            range: start..end,
+            reverse_acked: segment.reverse_acked,
+            timestamp: segment.extra.timestamp(),
+        });
+    }
+
+    /// data of chunk, truncated to available data
+    pub fn chunk_data(&self, chunk: &FollowChunk) -> &[u8] {
+        let clamp =
+            |offset: u64| (offset.saturating_sub(self.base_offset) as usize).min(self.data.len());
+        &self.data[clamp(chunk.range.start)..clamp(chunk.range.end)]
+    }
+
+    /// whether all data of chunk has been added
+    pub fn has_data(&self, chunk: &FollowChunk) -> bool {
+        chunk.range.end <= self.base_offset + self.data.len() as u64
+    }
+
+    /// drop the first `count` chunks and data not needed by the rest
+    pub fn consume(&mut self, count: usize) {
+        self.chunks.drain(..count);
+        let keep_from = self
+            .chunks
+            .first()
+            .map_or(self.covered, |chunk| chunk.range.start);
+        let drop = (keep_from.saturating_sub(self.base_offset) as usize).min(self.data.len());
+        self.data.drain(..drop);
+        self.base_offset += drop as u64;
+    }
+}
+
+/// whether forward chunk `a` goes before reverse chunk `b` in conversation
+/// order
+pub fn goes_first(a: &FollowChunk, b: &FollowChunk) -> bool {
+    // ready once all acknowledged data of the other side was placed
+    let a_ready = a.reverse_acked <= b.range.start;
+    let b_ready = b.reverse_acked <= a.range.start;
+    match (a_ready, b_ready) {
+        (true, false) => true,
+        (false, true) => false,
+        // sent concurrently or inconsistent, use capture time
+        _ => match (a.timestamp, b.timestamp) {
+            (Some(ta), Some(tb)) => ta <= tb,
+            _ => true,
+        },
+    }
+}
+
+/// order chunks of both directions into conversation order
+pub fn interleave<'a>(
+    forward: &'a [FollowChunk],
+    reverse: &'a [FollowChunk],
+) -> Vec<(Direction, &'a FollowChunk)> {
+    let mut out = Vec::with_capacity(forward.len() + reverse.len());
+    let (mut f, mut r) = (0, 0);
+    loop {
+        let take_forward = match (forward.get(f), reverse.get(r)) {
+            (None, None) => break,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some(a), Some(b)) => goes_first(a, b),
+        };
+        if take_forward {
+            out.push((Direction::Forward, &forward[f]));
+            f += 1;
+        } else {
+            out.push((Direction::Reverse, &reverse[r]));
+            r += 1;
+        }
+    }
+    out
+}
+
+/// display filter matching the flow
+fn display_filter(flow: &Flow) -> String {
+    let ip = if flow.src_addr.is_ipv4() {
+        "ip"
+    } else {
+        "ipv6"
+    };
+    format!(
+        "{ip}.addr eq {} and tcp.port eq {} and {ip}.addr eq {} and tcp.port eq {}",
+        flow.src_addr, flow.src_port, flow.dst_addr, flow.dst_port
+    )
+}
+
+fn printable(b: u8) -> char {
+    if b.is_ascii_graphic() || b == b' ' {
+        b as char
+    } else {
+        '.'
+    }
+}
+
+/// write chunk as hexdump, offsets relative to start of stream
+fn write_hex(out: &mut impl Write, prefix: &str, offset: u64, data: &[u8]) -> io::Result<()> {
+    for (i, line) in data.chunks(16).enumerate() {
+        let mut hex = String::with_capacity(49);
+        let mut ascii = String::with_capacity(17);
+        for (j, b) in line.iter().enumerate() {
+            if j == 8 {
+                hex.push(' ');
+                ascii.push(' ');
+            }
+            hex += &format!("{b:02x} ");
+            ascii.push(printable(*b));
+        }
+        writeln!(
+            out,
+            "{prefix}{:08x}  {hex:<49} {ascii}",
+            offset + i as u64 * 16
+        )?;
+    }
+    Ok(())
+}
+
+/// render connection in follow-stream format
+pub fn render(
+    out: &mut impl Write,
+    format: FollowFormat,
+    flow: &Flow,
+    forward: &FollowStream,
+    reverse: &FollowStream,
+) -> io::Result<()> {
+    writeln!(out, "{SEPARATOR}")?;
+    writeln!(out, "Follow: tcp,{}", format.name())?;
+    writeln!(out, "Filter: {}", display_filter(flow))?;
+    writeln!(out, "Node 0: {}:{}", flow.src_addr, flow.src_port)?;
+    writeln!(out, "Node 1: {}:{}", flow.dst_addr, flow.dst_port)?;
+    for (direction, chunk) in interleave(&forward.chunks, &reverse.chunks) {
+        let (stream, prefix) = match direction {
+            Direction::Forward => (forward, ""),
+            Direction::Reverse => (reverse, "\t"),
+        };
+        let data = stream.chunk_data(chunk);
+        if data.is_empty() {
+            continue;
+        }
+        match format {
+            FollowFormat::Ascii => {
+                writeln!(out, "{prefix}{}", data.len())?;
+                let text: String = data
+                    .iter()
+                    .map(|&b| if b == b'\n' { '\n' } else { printable(b) })
+                    .collect();
+                writeln!(out, "{text}")?;
+            }
+            FollowFormat::Hex => write_hex(out, prefix, chunk.range.start, data)?,
+        }
+    }
+    writeln!(out, "{SEPARATOR}")?;
+    Ok(())
+}
+
+/// shared state for FollowHandler
+pub struct FollowSharedInfoInner {
+    pub base_dir: PathBuf,
+    pub format: FollowFormat,
+}
+
+#[derive(Clone)]
+pub struct FollowSharedInfo {
+    pub inner: Arc<FollowSharedInfoInner>,
+}
+
+impl FollowSharedInfo {
+    /// create with output path and format
+    pub fn new(base_dir: PathBuf, format: FollowFormat) -> Self {
+        FollowSharedInfo {
+            inner: Arc::new(FollowSharedInfoInner { base_dir, format }),
+        }
+    }
+}
+
+/// ConnectionHandler writing `<uuid>.follow.txt` for each connection
+///
+/// Stream data is held in memory until the connection is retired.
+pub struct FollowHandler {
+    pub shared_info: FollowSharedInfo,
+    pub id: Uuid,
+    pub forward: FollowStream,
+    pub reverse: FollowStream,
+    pub gaps: Vec<StreamGap>,
+    pub segments: Vec<SegmentInfo>,
+}
+
+impl FollowHandler {
+    /// move data and segments from stream into handler, up to
+    /// `maybe_dump_len` bytes or everything remaining
+    pub fn collect(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        maybe_dump_len: Option<usize>,
+    ) {
+        self.gaps.clear();
+        self.segments.clear();
+        let follow = match direction {
+            Direction::Forward => &mut self.forward,
+            Direction::Reverse => &mut self.reverse,
+        };
+        let stream = connection.get_stream(direction);
+        let dump_len = if let Some(dump_len) = maybe_dump_len {
+            dump_len
+        } else {
+            stream.pop_segments_until(None, &mut self.segments);
+            stream.total_buffered_length()
+        };
+        if dump_len > 0 {
+            let start_offset = stream.buffer_start();
+            let end_offset = start_offset + dump_len as u64;
+            // gaps are skipped as they are not covered by segments
+            let read = stream.read_next(end_offset, &mut self.segments, &mut self.gaps, |slice| {
+                let (a, b) = slice.as_slices();
+                follow.add_data(start_offset, a);
+                if let Some(b) = b {
+                    follow.add_data(start_offset + a.len() as u64, b);
+                }
+            });
+            if read.is_none() {
+                error!("follow: {direction} stream cannot fulfill range, skipping");
+                return;
+            }
+        }
+        // popped in order of offset
+        for segment in &self.segments {
+            follow.add_segment(segment);
+        }
+        self.gaps.clear();
+        self.segments.clear();
+    }
+
+    /// write follow file for connection
+    pub fn write(&self, flow: &Flow) -> io::Result<()> {
+        let path = self
+            .shared_info
+            .inner
+            .base_dir
+            .join(format!("{}.follow.txt", self.id));
+        let mut out = BufWriter::new(File::create(path)?);
+        render(
+            &mut out,
+            self.shared_info.inner.format,
+            flow,
+            &self.forward,
+            &self.reverse,
+        )?;
+        out.flush()
+    }
+}
+
+impl ConnectionHandler for FollowHandler {
+    type InitialData = FollowSharedInfo;
+    type ConstructError = std::convert::Infallible;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> Result<Self, Self::ConstructError> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(FollowHandler {
+            shared_info,
+            id: connection.uuid,
+            forward: FollowStream::default(),
+            reverse: FollowStream::default(),
+            gaps: Vec::new(),
+            segments: Vec::new(),
+        })
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let config = connection.config.clone();
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        if readable_len > config.flush_readable_threshold
+            || stream.segments_info.len() > config.flush_segments_threshold
+        {
+            self.collect(connection, direction, Some(readable_len));
+        } else if stream.total_buffered_length() > config.flush_total_threshold {
+            self.collect(connection, direction, Some(config.flush_total_advance));
+        }
+    }
+
+    fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        self.collect(connection, direction, None);
+    }
+
+    fn direction_changed(&mut self, _connection: &mut Connection<Self>) {
+        std::mem::swap(&mut self.forward, &mut self.reverse);
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        self.collect(connection, Direction::Forward, None);
+        self.collect(connection, Direction::Reverse, None);
+        if self.forward.chunks.is_empty() && self.reverse.chunks.is_empty() {
+            // nothing to write if no data
+            return;
+        }
+        info!(
+            "writing follow output for connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        if let Err(e) = self.write(&connection.forward_flow) {
+            error!("failed to write follow output: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{render, FollowFormat, FollowStream};
+    use crate::flow_table::{Flow, IPPROTO_TCP};
+    use crate::serialized::PacketExtra;
+    use crate::stream::{SegmentInfo, SegmentType};
+
+    fn stream(data: &[u8], segments: &[(u64, usize, u64)]) -> FollowStream {
+        let mut stream = FollowStream::default();
+        stream.add_data(0, data);
+        for &(offset, len, reverse_acked) in segments {
+            stream.add_segment(&SegmentInfo {
+                offset,
+                reverse_acked,
+                extra: PacketExtra::None,
+                tcp: Default::default(),
+                data: SegmentType::Data {
+                    len,
+                    is_retransmit: false,
+                },
+            });
+        }
+        stream
+    }
+
+    #[test]
+    fn interleaved_render() {
+        let flow = Flow {
+            proto: IPPROTO_TCP,
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+        };
+        // request, retransmitted request, second request after the reply
+        let forward = stream(b"GET\nBYE\n", &[(0, 4, 0), (0, 4, 0), (4, 4, 5)]);
+        // reply split over two segments, sent after the first request
+        let reverse = stream(b"OK\x00\x01\n", &[(0, 3, 4), (3, 2, 4)]);
+
+        let mut out = Vec::new();
+        render(&mut out, FollowFormat::Ascii, &flow, &forward, &reverse).unwrap();
+        let expected = "\
+===================================================================
+Follow: tcp,ascii
+Filter: ip.addr eq 10.0.0.1 and tcp.port eq 1000 and ip.addr eq 10.0.0.2 and tcp.port eq 80
+Node 0: 10.0.0.1:1000
+Node 1: 10.0.0.2:80
+4
+GET
+
+\t3
+OK.
+\t2
+.
+
+4
+BYE
+
+===================================================================
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let mut out = Vec::new();
+        render(&mut out, FollowFormat::Hex, &flow, &forward, &reverse).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[5],
+            "00000000  47 45 54 0a                                       GET."
+        );
+        assert_eq!(
+            lines[6],
+            "\t00000000  4f 4b 00                                          OK."
+        );
+        assert_eq!(
+            lines[8],
+            "00000004  42 59 45 0a                                       BYE."
+        );
+    }
+}
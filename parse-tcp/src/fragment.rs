@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::mem;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use kinesin_rdt::common::range_set::RangeSet;
+use tracing::{debug, trace, warn};
+
+use crate::pcap_writer::RawFrame;
+
+/// max size of a reassembled IP payload
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+/// max total size of all incomplete datagrams held at once
+pub const MAX_FRAGMENT_BUFFER_SIZE: usize = 16 << 20; // 16 MB
+/// how long to hold incomplete datagrams before discarding them
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(60);
+/// how often to scan for expired datagrams
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// identifies the datagram a fragment belongs to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    /// source address
+    pub src_addr: IpAddr,
+    /// destination address
+    pub dst_addr: IpAddr,
+    /// identification field (16 bits for IPv4, 32 bits for IPv6)
+    pub id: u32,
+    /// protocol of the fragmented payload
+    pub proto: u8,
+}
+
+/// a single fragment of an IP datagram
+pub struct Fragment<'a> {
+    /// offset of fragment data into the original payload
+    pub offset: usize,
+    /// whether more fragments follow (false for the last fragment)
+    pub more_fragments: bool,
+    /// fragment data
+    pub data: &'a [u8],
+    /// captured frame carrying the fragment, if it should be kept
+    pub frame: Option<RawFrame>,
+}
+
+/// an incomplete datagram
+struct PendingDatagram {
+    /// payload buffer
+    buffer: Vec<u8>,
+    /// received ranges of the payload
+    received: RangeSet,
+    /// total payload length, known once the last fragment is seen
+    total_len: Option<usize>,
+    /// time the first fragment was received
+    first_seen: Duration,
+    /// insertion counter, used for eviction when no timestamps are available
+    sequence: u64,
+    /// captured frames of the fragments received, if kept
+    frames: Vec<RawFrame>,
+}
+
+impl PendingDatagram {
+    /// bytes held by the datagram
+    fn held_bytes(&self) -> usize {
+        self.buffer.len() + self.frames.iter().map(|f| f.data.len()).sum::<usize>()
+    }
+}
+
+/// reassembles fragmented IP datagrams
+pub struct FragmentReassembler {
+    /// incomplete datagrams
+    pending: HashMap<FragmentKey, PendingDatagram>,
+    /// total bytes held in pending buffers
+    pub buffered_bytes: usize,
+    /// max total bytes held in pending buffers
+    pub max_buffered_bytes: usize,
+    /// timeout for incomplete datagrams
+    pub timeout: Duration,
+    /// current capture time, if known
+    current_time: Duration,
+    /// capture time of the last expiry scan
+    last_expiry_check: Duration,
+    /// insertion counter for pending datagrams
+    next_sequence: u64,
+    /// captured frames of the most recently completed datagram
+    completed_frames: Vec<RawFrame>,
+
+    /// number of fragments received
+    pub fragments_received: usize,
+    /// number of datagrams successfully reassembled
+    pub datagrams_reassembled: usize,
+    /// number of datagrams discarded due to timeout
+    pub datagrams_expired: usize,
+    /// number of datagrams discarded due to buffer limits or invalid fragments
+    pub datagrams_dropped: usize,
+}
+
+impl FragmentReassembler {
+    /// create new instance
+    pub fn new() -> Self {
+        FragmentReassembler {
+            pending: HashMap::new(),
+            buffered_bytes: 0,
+            max_buffered_bytes: MAX_FRAGMENT_BUFFER_SIZE,
+            timeout: FRAGMENT_TIMEOUT,
+            current_time: Duration::ZERO,
+            last_expiry_check: Duration::ZERO,
+            next_sequence: 0,
+            completed_frames: Vec::new(),
+            fragments_received: 0,
+            datagrams_reassembled: 0,
+            datagrams_expired: 0,
+            datagrams_dropped: 0,
+        }
+    }
+
+    /// number of incomplete datagrams currently held
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// update current capture time, discarding expired datagrams
+    pub fn set_current_time(&mut self, now: Duration) {
+        if now < self.current_time {
+            // capture timestamps are not always monotonic, ignore
+            return;
+        }
+        self.current_time = now;
+        if now.saturating_sub(self.last_expiry_check) < EXPIRY_CHECK_INTERVAL {
+            return;
+        }
+        self.last_expiry_check = now;
+
+        let timeout = self.timeout;
+        let mut expired_bytes = 0;
+        let mut expired_count = 0;
+        self.pending.retain(|key, datagram| {
+            if now.saturating_sub(datagram.first_seen) > timeout {
+                debug!("fragment reassembly timed out: {key:?}");
+                expired_bytes += datagram.held_bytes();
+                expired_count += 1;
+                false
+            } else {
+                true
+            }
+        });
+        self.buffered_bytes -= expired_bytes;
+        self.datagrams_expired += expired_count;
+    }
+
+    /// remove the oldest pending datagram
+    fn evict_oldest(&mut self) -> bool {
+        let Some(oldest) = self
+            .pending
+            .iter()
+            .min_by_key(|(_, d)| d.sequence)
+            .map(|(k, _)| k.clone())
+        else {
+            return false;
+        };
+        self.discard(&oldest);
+        true
+    }
+
+    /// discard a pending datagram
+    fn discard(&mut self, key: &FragmentKey) {
+        if let Some(datagram) = self.pending.remove(key) {
+            self.buffered_bytes -= datagram.held_bytes();
+            self.datagrams_dropped += 1;
+        }
+    }
+
+    /// process a fragment, writing the reassembled payload to `out` and
+    /// returning true once the datagram is complete
+    pub fn process(
+        &mut self,
+        key: FragmentKey,
+        mut fragment: Fragment<'_>,
+        out: &mut Vec<u8>,
+    ) -> bool {
+        self.fragments_received += 1;
+        let end = fragment.offset + fragment.data.len();
+        let frame = fragment.frame.take();
+        let frame_len = frame.as_ref().map_or(0, |f| f.data.len());
+        if end > MAX_DATAGRAM_SIZE {
+            warn!(
+                "fragment exceeds max datagram size (offset {}, len {})",
+                fragment.offset,
+                fragment.data.len()
+            );
+            self.discard(&key);
+            return false;
+        }
+
+        let Some(datagram) = self.pending.get_mut(&key) else {
+            // first fragment seen for this datagram
+            if !self.reserve(end + frame_len) {
+                warn!("fragment buffer full, dropping fragment for {key:?}");
+                self.datagrams_dropped += 1;
+                return false;
+            }
+            trace!("new fragmented datagram: {key:?}");
+            let mut datagram = PendingDatagram {
+                buffer: Vec::new(),
+                received: RangeSet::unlimited(),
+                total_len: None,
+                first_seen: self.current_time,
+                sequence: self.next_sequence,
+                frames: Vec::new(),
+            };
+            self.next_sequence += 1;
+            self.buffered_bytes += Self::write_fragment(&mut datagram, &fragment);
+            self.buffered_bytes += frame_len;
+            datagram.frames.extend(frame);
+            self.pending.insert(key.clone(), datagram);
+            return self.try_complete(&key, out);
+        };
+
+        if !fragment.more_fragments {
+            if let Some(total_len) = datagram.total_len {
+                if total_len != end {
+                    warn!("conflicting final fragments for {key:?} ({total_len} vs {end})");
+                    self.discard(&key);
+                    return false;
+                }
+            }
+        }
+        if let Some(total_len) = datagram.total_len {
+            if end > total_len {
+                warn!("fragment extends past end of datagram for {key:?}");
+                self.discard(&key);
+                return false;
+            }
+        }
+
+        let growth = end.saturating_sub(datagram.buffer.len()) + frame_len;
+        if self.buffered_bytes + growth > self.max_buffered_bytes {
+            warn!("fragment buffer full, dropping datagram {key:?}");
+            self.discard(&key);
+            return false;
+        }
+        let datagram = self.pending.get_mut(&key).unwrap();
+        self.buffered_bytes += Self::write_fragment(datagram, &fragment);
+        self.buffered_bytes += frame_len;
+        datagram.frames.extend(frame);
+        self.try_complete(&key, out)
+    }
+
+    /// ensure `len` more bytes can be buffered, evicting old datagrams if needed
+    fn reserve(&mut self, len: usize) -> bool {
+        if len > self.max_buffered_bytes {
+            return false;
+        }
+        while self.buffered_bytes + len > self.max_buffered_bytes {
+            if !self.evict_oldest() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// copy fragment data into datagram, returning how much the buffer grew
+    fn write_fragment(datagram: &mut PendingDatagram, fragment: &Fragment<'_>) -> usize {
+        let start = fragment.offset;
+        let end = start + fragment.data.len();
+        if !fragment.more_fragments {
+            datagram.total_len = Some(end);
+        }
+        if start == end {
+            return 0;
+        }
+
+        let old_len = datagram.buffer.len();
+        if end > old_len {
+            datagram.buffer.resize(end, 0);
+        }
+        // first received copy wins on overlap
+        for range in datagram.received.range_complement(start as u64..end as u64) {
+            let (r_start, r_end) = (range.start as usize, range.end as usize);
+            datagram.buffer[r_start..r_end]
+                .copy_from_slice(&fragment.data[r_start - start..r_end - start]);
+        }
+        datagram.received.insert_range(start as u64..end as u64);
+        datagram.buffer.len() - old_len
+    }
+
+    /// check if datagram is complete, moving it to `out` if so
+    fn try_complete(&mut self, key: &FragmentKey, out: &mut Vec<u8>) -> bool {
+        let datagram = &self.pending[key];
+        let Some(total_len) = datagram.total_len else {
+            return false;
+        };
+        if total_len > 0 && !datagram.received.has_range(0..total_len as u64) {
+            return false;
+        }
+
+        let mut datagram = self.pending.remove(key).unwrap();
+        self.buffered_bytes -= datagram.held_bytes();
+        self.datagrams_reassembled += 1;
+        datagram.buffer.truncate(total_len);
+        debug!("reassembled datagram {key:?} ({total_len} bytes)");
+        *out = datagram.buffer;
+        self.completed_frames = datagram.frames;
+        true
+    }
+
+    /// take captured frames of the most recently completed datagram, in the
+    /// order they were received
+    pub fn take_frames(&mut self) -> Vec<RawFrame> {
+        mem::take(&mut self.completed_frames)
+    }
+}
+
+impl Default for FragmentReassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use super::{Fragment, FragmentKey, FragmentReassembler};
+
+    fn key(id: u32) -> FragmentKey {
+        FragmentKey {
+            src_addr: Ipv4Addr::new(10, 0, 0, 1).into(),
+            dst_addr: Ipv4Addr::new(10, 0, 0, 2).into(),
+            id,
+            proto: 6,
+        }
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut reassembler = FragmentReassembler::new();
+        let mut out = Vec::new();
+        let last = Fragment {
+            offset: 16,
+            more_fragments: false,
+            data: b"world",
+            frame: None,
+        };
+        assert!(!reassembler.process(key(1), last, &mut out));
+        let middle = Fragment {
+            offset: 8,
+            more_fragments: true,
+            data: b", there ",
+            frame: None,
+        };
+        assert!(!reassembler.process(key(1), middle, &mut out));
+        let first = Fragment {
+            offset: 0,
+            more_fragments: true,
+            data: b"hello!!!",
+            frame: None,
+        };
+        assert!(reassembler.process(key(1), first, &mut out));
+        assert_eq!(out, b"hello!!!, there world");
+        assert_eq!(reassembler.pending_count(), 0);
+        assert_eq!(reassembler.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn limits() {
+        let mut reassembler = FragmentReassembler::new();
+        reassembler.max_buffered_bytes = 32;
+        let mut out = Vec::new();
+        let data = [0u8; 24];
+        let frag = |offset| Fragment {
+            offset,
+            more_fragments: true,
+            data: &data,
+            frame: None,
+        };
+        assert!(!reassembler.process(key(1), frag(0), &mut out));
+        // evicts datagram 1
+        assert!(!reassembler.process(key(2), frag(0), &mut out));
+        assert_eq!(reassembler.pending_count(), 1);
+        assert_eq!(reassembler.datagrams_dropped, 1);
+        // exceeds buffer, drops datagram 2
+        assert!(!reassembler.process(key(2), frag(24), &mut out));
+        assert_eq!(reassembler.pending_count(), 0);
+        assert_eq!(reassembler.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut reassembler = FragmentReassembler::new();
+        let mut out = Vec::new();
+        reassembler.set_current_time(std::time::Duration::from_secs(10));
+        let frag = Fragment {
+            offset: 0,
+            more_fragments: true,
+            data: b"test",
+            frame: None,
+        };
+        assert!(!reassembler.process(key(1), frag, &mut out));
+        reassembler.set_current_time(std::time::Duration::from_secs(100));
+        assert_eq!(reassembler.pending_count(), 0);
+        assert_eq!(reassembler.datagrams_expired, 1);
+    }
+}
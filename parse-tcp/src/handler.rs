@@ -1,29 +1,42 @@
 use std::convert::Infallible;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Seek, SeekFrom, Write};
-use std::ops::Range;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use eyre::Context;
 use parking_lot::Mutex;
-use tracing::{debug, info, trace};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, trace, warn};
 use uuid::Uuid;
 
 use crate::connection::{Connection, Direction};
+use crate::dedup::{DedupStore, DedupStream};
 use crate::flow_table::Flow;
-use crate::serialized::{ConnInfo, PacketExtra, SerializedSegment};
-use crate::stream::{SegmentInfo, SegmentType};
+use crate::interleave::Interleaver;
+use crate::pcap_writer::{PcapWriter, RawFrame};
+use crate::segment_file;
+use crate::serialized::{
+    ConnInfo, DirectionProgress, OutputProgress, PacketExtra, SerializedSegment,
+};
+use crate::stream::{LateData, SegmentInfo, SegmentType, StreamGap};
+use crate::writer::{
+    Compression, WriterFileId, WriterMessage, WriterPool, DEFAULT_WRITER_QUEUE_DEPTH,
+    DEFAULT_WRITER_THREADS,
+};
 use crate::ConnectionHandler;
 
-/// threshold for buffered readable bytes before writing out
-const BUFFER_READABLE_THRESHOLD: usize = 64 << 10;
-/// threshold for buffered segment info objects before writing out
-const BUFFER_SEGMENTS_THRESHOLD: usize = 16 << 10;
-/// threshold for total buffered bytes before writing out
-const BUFFER_TOTAL_THRESHOLD: usize = 256 << 10;
-/// how many bytes to advance when hitting BUFFER_TOTAL_THRESHOLD
-const BUFFER_TOTAL_THRESHOLD_ADVANCE: usize = 64 << 10;
+/// default threshold for buffered readable bytes before writing out
+pub const BUFFER_READABLE_THRESHOLD: usize = 64 << 10;
+/// default threshold for buffered segment info objects before writing out
+pub const BUFFER_SEGMENTS_THRESHOLD: usize = 16 << 10;
+/// default threshold for total buffered bytes before writing out
+pub const BUFFER_TOTAL_THRESHOLD: usize = 256 << 10;
+/// default for how many bytes to advance when hitting BUFFER_TOTAL_THRESHOLD
+pub const BUFFER_TOTAL_THRESHOLD_ADVANCE: usize = 64 << 10;
+/// pending pcap records of a connection before appending to its file
+pub const PCAP_SPLIT_BUFFER_SIZE: usize = 16 << 10;
+/// bytes written to a connection's files between progress sidecar updates
+pub const SIDECAR_UPDATE_BYTES: u64 = 1 << 20;
 
 pub fn dump_as_readable_ascii(buf: &[u8], newline: bool) {
     let mut writer = BufWriter::new(std::io::stdout());
@@ -44,7 +57,7 @@ pub fn dump_as_readable_ascii(buf: &[u8], newline: bool) {
 
 /// ConnectionHandler to dump data to stdout
 pub struct DumpHandler {
-    pub gaps: Vec<Range<u64>>,
+    pub gaps: Vec<StreamGap>,
     pub segments: Vec<SegmentInfo>,
     pub buf: Vec<u8>,
     pub forward_has_data: bool,
@@ -109,22 +122,21 @@ impl DumpHandler {
         let end_offset = start_offset + dump_len as u64;
         if dump_len > 0 {
             trace!("requesting {dump_len} bytes for direction {direction}");
-            stream.pop_segments_until(Some(end_offset), &mut self.segments);
-            stream.read_gaps_until(end_offset, &mut self.gaps);
-            let slice = stream
-                .read_buffer_until(end_offset)
+            let buf = &mut self.buf;
+            stream
+                .read_next(end_offset, &mut self.segments, &mut self.gaps, |slice| {
+                    let (a, b) = slice.as_slices();
+                    buf.extend_from_slice(a);
+                    if let Some(b) = b {
+                        buf.extend_from_slice(b);
+                    }
+                })
                 .expect("stream cannot fulfill range");
-            let (a, b) = slice.as_slices();
-            self.buf.extend_from_slice(a);
-            if let Some(b) = b {
-                self.buf.extend_from_slice(b);
-            }
-            stream.consume_until(end_offset);
 
             if !self.gaps.is_empty() {
                 debug!("gaps (length {})", self.gaps.len());
                 for gap in &self.gaps {
-                    debug!(" gap {} -> {}", gap.start, gap.end);
+                    debug!(" gap {} -> {}", gap.range.start, gap.range.end);
                 }
             }
             self.dump_stream_segments();
@@ -134,7 +146,11 @@ impl DumpHandler {
             println!("  offset: {start_offset}");
             println!("  length: {dump_len}\n");
             if !self.gaps.is_empty() {
-                let gaps_len: u64 = self.gaps.iter().map(|r| r.end - r.start).sum();
+                let gaps_len: u64 = self
+                    .gaps
+                    .iter()
+                    .map(|gap| gap.range.end - gap.range.start)
+                    .sum();
                 println!("  gap bytes: {gaps_len}");
             }
             dump_as_readable_ascii(&self.buf, true);
@@ -187,15 +203,16 @@ impl ConnectionHandler for DumpHandler {
         }
 
         // dump forward stream if limits hit
+        let config = connection.config.clone();
         let fwd_stream = connection.get_stream(direction);
-        if fwd_readable_len > BUFFER_READABLE_THRESHOLD
-            || fwd_stream.segments_info.len() > BUFFER_SEGMENTS_THRESHOLD
+        if fwd_readable_len > config.flush_readable_threshold
+            || fwd_stream.segments_info.len() > config.flush_segments_threshold
         {
             trace!("forward stream exceeded threshold, will dump");
             self.dump_stream(connection, direction, Some(fwd_readable_len));
-        } else if fwd_stream.total_buffered_length() > BUFFER_TOTAL_THRESHOLD {
+        } else if fwd_stream.total_buffered_length() > config.flush_total_threshold {
             trace!("forward stream exceeded total buffer size threshold, will dump");
-            self.dump_stream(connection, direction, Some(BUFFER_TOTAL_THRESHOLD_ADVANCE));
+            self.dump_stream(connection, direction, Some(config.flush_total_advance));
         }
     }
 
@@ -218,10 +235,84 @@ impl ConnectionHandler for DumpHandler {
     }
 }
 
+/// how DirectoryOutputHandler writes stream data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DataLayout {
+    /// `<id>.f.data` and `<id>.r.data` per connection
+    #[default]
+    Split,
+    /// `<id>.i.data` holding records of both directions in conversation
+    /// order, see `crate::interleave`
+    Interleaved,
+}
+
+/// encoding of segment files written by DirectoryOutputHandler
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SegmentFormat {
+    /// `<id>.f.jsonl` and `<id>.r.jsonl`, one JSON object per line
+    #[default]
+    Jsonl,
+    /// `<id>.f.seg` and `<id>.r.seg`, see `crate::segment_file`
+    Binary,
+}
+
+impl SegmentFormat {
+    /// file suffixes (forward, reverse)
+    pub fn suffixes(self) -> [&'static str; 2] {
+        match self {
+            SegmentFormat::Jsonl => ["f.jsonl", "r.jsonl"],
+            SegmentFormat::Binary => ["f.seg", "r.seg"],
+        }
+    }
+
+    /// bytes written at the start of each file
+    pub fn file_header(self) -> Vec<u8> {
+        let mut header = Vec::new();
+        if self == SegmentFormat::Binary {
+            segment_file::write_file_header(&mut header);
+        }
+        header
+    }
+
+    /// append serialized segment to `out`
+    pub fn write(self, out: &mut Vec<u8>, segment: &SerializedSegment) {
+        match self {
+            SegmentFormat::Jsonl => {
+                serde_json::to_writer(&mut *out, segment).expect("failed to serialize segment");
+                out.push(b'\n');
+            }
+            SegmentFormat::Binary => segment_file::encode_segment(out, segment),
+        }
+    }
+}
+
 /// shared state for DirectoryOutputHandler
 pub struct DirectoryOutputSharedInfoInner {
     pub base_dir: PathBuf,
     pub conn_info_file: Mutex<File>,
+    /// threads performing stream file writes
+    pub writer: WriterPool,
+    /// compression of files written by `writer`
+    pub compression: Compression,
+    /// block store if stream data is deduplicated
+    pub dedup: Option<DedupStore>,
+    /// layout of stream data files, deduplication only applies to `Split`
+    pub layout: DataLayout,
+    /// encoding of segment files
+    pub segment_format: SegmentFormat,
+}
+
+impl DirectoryOutputSharedInfoInner {
+    /// suffixes of data files (forward, reverse) for the configured layout
+    pub fn data_suffixes(&self) -> [&'static str; 2] {
+        if self.layout == DataLayout::Interleaved {
+            ["i.data", "i.data"]
+        } else if self.dedup.is_some() {
+            ["f.manifest.jsonl", "r.manifest.jsonl"]
+        } else {
+            ["f.data", "r.data"]
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -234,14 +325,52 @@ pub type ErrorReceiver = crossbeam_channel::Receiver<eyre::Report>;
 impl DirectoryOutputSharedInfo {
     /// create with output path
     pub fn new(base_dir: PathBuf) -> std::io::Result<(Self, ErrorReceiver)> {
+        Self::with_writer_threads(
+            base_dir,
+            DEFAULT_WRITER_THREADS,
+            DEFAULT_WRITER_QUEUE_DEPTH,
+            Compression::None,
+            None,
+            DataLayout::Split,
+            SegmentFormat::Jsonl,
+        )
+    }
+
+    /// create with output path and writer pool configuration, compressing
+    /// stream data and segment files with `compression`. If
+    /// `dedup_block_size` is provided, stream data is deduplicated into a
+    /// block store and manifests are written instead of data files.
+    pub fn with_writer_threads(
+        base_dir: PathBuf,
+        writer_threads: usize,
+        queue_depth: usize,
+        compression: Compression,
+        dedup_block_size: Option<usize>,
+        layout: DataLayout,
+        segment_format: SegmentFormat,
+    ) -> std::io::Result<(Self, ErrorReceiver)> {
+        let dedup = dedup_block_size
+            .map(|block_size| DedupStore::new(&base_dir, block_size))
+            .transpose()?;
         let mut conn_info_file = File::create(base_dir.join("connections.json"))?;
         conn_info_file.write_all(b"[\n")?;
         let (error_tx, error_rx) = crossbeam_channel::unbounded();
+        let writer = WriterPool::with_compression(
+            writer_threads,
+            queue_depth,
+            compression,
+            error_tx.clone(),
+        )?;
         Ok((
             DirectoryOutputSharedInfo {
                 inner: Arc::new(DirectoryOutputSharedInfoInner {
                     base_dir,
                     conn_info_file: Mutex::new(conn_info_file),
+                    writer,
+                    compression,
+                    dedup,
+                    layout,
+                    segment_format,
                 }),
                 errors: error_tx,
             },
@@ -251,19 +380,25 @@ impl DirectoryOutputSharedInfo {
 
     /// write connection info
     pub fn record_conn_info(&self, uuid: Uuid, flow: &Flow) -> std::io::Result<()> {
-        let mut serialized = serde_json::to_string(&ConnInfo::new(uuid, flow))
-            .expect("failed to serialize ConnInfo");
+        self.write_conn_info(&ConnInfo::new(uuid, flow))
+    }
+
+    /// write connection info object
+    pub fn write_conn_info(&self, info: &ConnInfo) -> std::io::Result<()> {
+        let mut serialized = serde_json::to_string(info).expect("failed to serialize ConnInfo");
         serialized += ",\n";
         let mut file = self.inner.conn_info_file.lock();
         file.write_all(serialized.as_bytes())
     }
 
-    /// close connection info file
+    /// wait for pending writes and close connection info file
     pub fn close(self) -> std::io::Result<()> {
-        let mut conn_info_file = Arc::into_inner(self.inner)
-            .unwrap()
-            .conn_info_file
-            .into_inner();
+        let inner = Arc::into_inner(self.inner).unwrap();
+        inner.writer.close();
+        if let Some(dedup) = &inner.dedup {
+            dedup.log_stats();
+        }
+        let mut conn_info_file = inner.conn_info_file.into_inner();
         let current_pos = conn_info_file.stream_position()?;
         if current_pos > 2 {
             // overwrite trailing comma and close array
@@ -288,23 +423,255 @@ impl DirectoryOutputSharedInfo {
     }
 }
 
-/// stream files for DirectoryOutputHandler
+/// stream files for DirectoryOutputHandler, owned by the writer pool
+///
+/// All files of a connection are serviced by the same writer thread, so the
+/// progress sidecar is only updated after preceding writes are flushed.
 pub struct DirectoryOutputHandlerFiles {
-    pub forward_data: File,
-    pub forward_segments: File,
-    pub reverse_data: File,
-    pub reverse_segments: File,
+    /// data file, or manifest if deduplicating
+    pub forward_data: WriterFileId,
+    pub forward_segments: WriterFileId,
+    pub reverse_data: WriterFileId,
+    pub reverse_segments: WriterFileId,
+    /// progress sidecar
+    pub sidecar: WriterFileId,
+    /// late data files by label (forward, reverse), created on first use
+    pub late_data: [WriterFileId; 2],
+    pub late_data_created: [bool; 2],
+    pub sidecar_path: PathBuf,
+    /// progress as of the last write, by file label
+    pub progress: OutputProgress,
+    /// bytes written since the sidecar was last updated
+    pub unreported_bytes: u64,
+    /// whether forward files hold the reverse direction, see
+    /// `swap_directions`
+    pub swapped: bool,
+}
+
+impl DirectoryOutputHandlerFiles {
+    /// create files for connection `id` in `base_dir`, with data files named
+    /// by `data_suffixes` (forward, reverse)
+    ///
+    /// If both suffixes are equal, both directions share one data file.
+    pub fn create(
+        writer: &WriterPool,
+        base_dir: &Path,
+        id: Uuid,
+        data_suffixes: [&str; 2],
+        segment_format: SegmentFormat,
+    ) -> Self {
+        let mut files = Self::open(writer, base_dir, id, data_suffixes, segment_format, false);
+        let header = segment_format.file_header();
+        if !header.is_empty() {
+            for file_id in [files.forward_segments, files.reverse_segments] {
+                writer.send(WriterMessage::Write {
+                    id: file_id,
+                    data: header.clone(),
+                });
+            }
+        }
+        files.progress.forward.segments_bytes = header.len() as u64;
+        files.progress.reverse.segments_bytes = header.len() as u64;
+        files.write_sidecar(writer);
+        files
+    }
+
+    /// reopen files of connection `id` restored from a checkpoint, appending
+    /// to the output of the previous run
+    pub fn reopen(
+        writer: &WriterPool,
+        base_dir: &Path,
+        id: Uuid,
+        data_suffixes: [&str; 2],
+        segment_format: SegmentFormat,
+        checkpoint: &DirectoryOutputCheckpoint,
+    ) -> Self {
+        let mut files = Self::open(writer, base_dir, id, data_suffixes, segment_format, true);
+        files.progress = OutputProgress {
+            id,
+            complete: false,
+            ..checkpoint.progress.clone()
+        };
+        if checkpoint.swapped {
+            files.swap_directions();
+        }
+        for (index, suffix) in [(0, "f"), (1, "r")] {
+            if checkpoint.late_data_created[index] {
+                writer.send(WriterMessage::Append {
+                    id: files.late_data[index],
+                    path: base_dir.join(format!("{id}.{suffix}.late")),
+                });
+                files.late_data_created[index] = true;
+            }
+        }
+        files.write_sidecar(writer);
+        files
+    }
+
+    /// allocate files of connection `id` and open data and segments files
+    fn open(
+        writer: &WriterPool,
+        base_dir: &Path,
+        id: Uuid,
+        data_suffixes: [&str; 2],
+        segment_format: SegmentFormat,
+        append: bool,
+    ) -> Self {
+        let ids = writer.allocate_group(7);
+        let [forward_segments, reverse_segments] = segment_format.suffixes();
+        let mut create = vec![
+            (ids[0], data_suffixes[0]),
+            (ids[1], forward_segments),
+            (ids[3], reverse_segments),
+        ];
+        let reverse_data = if data_suffixes[0] == data_suffixes[1] {
+            ids[0]
+        } else {
+            create.push((ids[2], data_suffixes[1]));
+            ids[2]
+        };
+        // errors opening files are reported by the writer thread
+        for (file_id, suffix) in create {
+            let path = base_dir.join(format!("{id}.{suffix}"));
+            writer.send(if append {
+                WriterMessage::Append { id: file_id, path }
+            } else {
+                WriterMessage::Create { id: file_id, path }
+            });
+        }
+        DirectoryOutputHandlerFiles {
+            forward_data: ids[0],
+            forward_segments: ids[1],
+            reverse_data,
+            reverse_segments: ids[3],
+            sidecar: ids[4],
+            late_data: [ids[5], ids[6]],
+            late_data_created: [false; 2],
+            sidecar_path: base_dir.join(format!("{id}.meta.json")),
+            progress: OutputProgress {
+                id,
+                complete: false,
+                forward: DirectionProgress::default(),
+                reverse: DirectionProgress::default(),
+            },
+            unreported_bytes: 0,
+            swapped: false,
+        }
+    }
+
+    /// account for output written in direction, updating the sidecar every
+    /// `SIDECAR_UPDATE_BYTES`
+    pub fn record_progress(
+        &mut self,
+        writer: &WriterPool,
+        direction: Direction,
+        end_offset: u64,
+        data_bytes: u64,
+        segments_bytes: u64,
+    ) {
+        let label = if self.swapped {
+            direction.swap()
+        } else {
+            direction
+        };
+        let progress = match label {
+            Direction::Forward => &mut self.progress.forward,
+            Direction::Reverse => &mut self.progress.reverse,
+        };
+        progress.end_offset = end_offset;
+        progress.data_bytes += data_bytes;
+        progress.segments_bytes += segments_bytes;
+        self.unreported_bytes += data_bytes + segments_bytes;
+        if self.unreported_bytes >= SIDECAR_UPDATE_BYTES {
+            self.write_sidecar(writer);
+            self.unreported_bytes = 0;
+        }
+    }
+
+    /// flush files, then replace the sidecar with current progress
+    fn write_sidecar(&self, writer: &WriterPool) {
+        let flush = if self.progress.complete {
+            Vec::new()
+        } else {
+            vec![
+                self.forward_data,
+                self.forward_segments,
+                self.reverse_data,
+                self.reverse_segments,
+            ]
+        };
+        let data = serde_json::to_vec(&self.progress).expect("failed to serialize progress");
+        writer.send(WriterMessage::Replace {
+            id: self.sidecar,
+            flush,
+            path: self.sidecar_path.clone(),
+            data,
+        });
+    }
+
+    /// write the opposite direction's files from now on
+    pub fn swap_directions(&mut self) {
+        std::mem::swap(&mut self.forward_data, &mut self.reverse_data);
+        std::mem::swap(&mut self.forward_segments, &mut self.reverse_segments);
+        self.swapped = !self.swapped;
+    }
+
+    /// close files and mark output complete
+    pub fn close(&mut self, writer: &WriterPool) {
+        let mut close = vec![
+            self.forward_data,
+            self.forward_segments,
+            self.reverse_segments,
+        ];
+        if self.reverse_data != self.forward_data {
+            close.push(self.reverse_data);
+        }
+        for (id, created) in self.late_data.into_iter().zip(self.late_data_created) {
+            if created {
+                close.push(id);
+            }
+        }
+        for id in close {
+            writer.send(WriterMessage::Close { id });
+        }
+        self.progress.complete = true;
+        self.write_sidecar(writer);
+    }
 }
 
 /// ConnectionHandler to write data to a directory
 pub struct DirectoryOutputHandler {
     pub shared_info: DirectoryOutputSharedInfo,
     pub id: Uuid,
-    pub gaps: Vec<Range<u64>>,
+    pub gaps: Vec<StreamGap>,
     pub segments: Vec<SegmentInfo>,
     /// whether we received the handshake_done event
     pub got_handshake_done: bool,
     pub files: Option<DirectoryOutputHandlerFiles>,
+    /// scratch buffer for serialized segments
+    pub segments_buf: Vec<u8>,
+    /// block assembly state (forward, reverse) if deduplicating
+    pub dedup_streams: [DedupStream; 2],
+    /// whether anything was sent to the writer
+    pub wrote_data: bool,
+    /// whether output keeps the labeling from before a direction change
+    pub labels_swapped: bool,
+    /// data waiting to be ordered, if writing interleaved output
+    pub interleaver: Option<Interleaver>,
+}
+
+/// state of DirectoryOutputHandler saved in checkpoints, so a restored
+/// connection continues writing its files
+#[derive(Serialize, Deserialize)]
+pub struct DirectoryOutputCheckpoint {
+    pub progress: OutputProgress,
+    /// see `DirectoryOutputHandlerFiles::swapped`
+    pub swapped: bool,
+    pub wrote_data: bool,
+    pub labels_swapped: bool,
+    pub late_data_created: [bool; 2],
+    pub dedup_streams: [DedupStream; 2],
+    pub interleaver: Option<Interleaver>,
 }
 
 impl DirectoryOutputHandler {
@@ -317,17 +684,28 @@ impl DirectoryOutputHandler {
         self.gaps.clear();
         self.segments.clear();
 
-        let files = self.files.as_mut().expect("files not available!");
-        let (data_file, mut segments_file) = match direction {
+        let files = self.files.as_ref().expect("files not available!");
+        let (data_file, segments_file, dedup_stream) = match direction {
             Direction::Forward => (
-                &mut files.forward_data,
-                BufWriter::new(&mut files.forward_segments),
+                files.forward_data,
+                files.forward_segments,
+                &mut self.dedup_streams[0],
             ),
             Direction::Reverse => (
-                &mut files.reverse_data,
-                BufWriter::new(&mut files.reverse_segments),
+                files.reverse_data,
+                files.reverse_segments,
+                &mut self.dedup_streams[1],
             ),
         };
+        let writer = &self.shared_info.inner.writer;
+        let dedup = self.shared_info.inner.dedup.as_ref();
+        let interleaver = &mut self.interleaver;
+        // output is labeled by file
+        let label = if self.labels_swapped {
+            direction.swap()
+        } else {
+            direction
+        };
 
         let stream = connection.get_stream(direction);
         let dump_len = if let Some(dump_len) = maybe_dump_len {
@@ -343,22 +721,42 @@ impl DirectoryOutputHandler {
             trace!("write_stream_data: requesting {dump_len} bytes from stream for {direction}");
             let start_offset = stream.buffer_start();
             let end_offset = start_offset + dump_len as u64;
-            stream.pop_segments_until(Some(end_offset), &mut self.segments);
-            stream.read_gaps_until(end_offset, &mut self.gaps);
-            let slice = stream
-                .read_buffer_until(end_offset)
+            stream
+                .read_next(end_offset, &mut self.segments, &mut self.gaps, |slice| {
+                    let (a, b) = slice.as_slices();
+                    if let Some(interleaver) = interleaver {
+                        let follow = interleaver.stream_mut(label);
+                        follow.add_data(start_offset, a);
+                        if let Some(b) = b {
+                            follow.add_data(start_offset + a.len() as u64, b);
+                        }
+                    } else if let Some(dedup) = dedup {
+                        // data_file is the manifest
+                        dedup_stream.push(dedup, writer, data_file, start_offset, a);
+                        if let Some(b) = b {
+                            let offset = start_offset + a.len() as u64;
+                            dedup_stream.push(dedup, writer, data_file, offset, b);
+                        }
+                    } else {
+                        let mut data = Vec::with_capacity(dump_len);
+                        data.extend_from_slice(a);
+                        if let Some(b) = b {
+                            data.extend_from_slice(b);
+                        }
+                        trace!("write_stream_data: queueing {} data bytes", data.len());
+                        writer.send(WriterMessage::Write {
+                            id: data_file,
+                            data,
+                        });
+                    }
+                })
                 .expect("stream cannot fulfill range");
-            let (a, b) = slice.as_slices();
-            trace!("write_stream_data: writing {} data bytes", a.len());
-            data_file.write_all(a)?;
-            if let Some(b) = b {
-                trace!("write_stream_data: writing {} data bytes", b.len());
-                data_file.write_all(b)?;
-            }
-            stream.consume_until(end_offset);
         }
 
         // write gaps and segments in order
+        let segments_buf = &mut self.segments_buf;
+        let segment_format = self.shared_info.inner.segment_format;
+        segments_buf.clear();
         let mut gaps_iter = self.gaps.iter().peekable();
         let mut segments_iter = self.segments.iter().peekable();
         loop {
@@ -372,7 +770,7 @@ impl DirectoryOutputHandler {
                 (None, Some(_)) => WhichNext::Segment,
                 (Some(_), None) => WhichNext::Gap,
                 (Some(&gap), Some(&segment)) => {
-                    if gap.start < segment.offset {
+                    if gap.range.start < segment.offset {
                         WhichNext::Gap
                     } else {
                         WhichNext::Segment
@@ -384,23 +782,169 @@ impl DirectoryOutputHandler {
             match which {
                 WhichNext::Gap => {
                     let gap = gaps_iter.next().unwrap();
-                    let info = SerializedSegment::new_gap(gap.start, gap.end - gap.start);
-                    serde_json::to_writer(&mut segments_file, &info)?;
-                    segments_file.write_all(b"\n")?;
+                    let info: SerializedSegment = gap.into();
+                    segment_format.write(segments_buf, &info);
                 }
                 WhichNext::Segment => {
                     let segment = segments_iter.next().unwrap();
                     let info: SerializedSegment = segment.into();
-                    serde_json::to_writer(&mut segments_file, &info)?;
-                    segments_file.write_all(b"\n")?;
+                    segment_format.write(segments_buf, &info);
                 }
             }
         }
 
+        if dump_len > 0 || !segments_buf.is_empty() {
+            self.wrote_data = true;
+        }
+        if !segments_buf.is_empty() {
+            writer.send(WriterMessage::Write {
+                id: segments_file,
+                data: segments_buf.clone(),
+            });
+        }
+        if let Some(interleaver) = &mut self.interleaver {
+            // popped in order of offset
+            let follow = interleaver.stream_mut(label);
+            for segment in &self.segments {
+                follow.add_segment(segment);
+            }
+            let mut records = Vec::new();
+            interleaver.write_ready(&mut records, false);
+            if !records.is_empty() {
+                writer.send(WriterMessage::Write {
+                    id: data_file,
+                    data: records,
+                });
+            }
+        }
+        if let Some(files) = &mut self.files {
+            files.record_progress(
+                writer,
+                direction,
+                stream.buffer_start(),
+                dump_len as u64,
+                segments_buf.len() as u64,
+            );
+        }
+
         self.gaps.clear();
         self.segments.clear();
         Ok(())
     }
+
+    /// write data received late for a gap which was already written out
+    ///
+    /// The data file is patched in place if it holds stream data as is,
+    /// otherwise data is appended to `<id>.<f|r>.late`. Either way a
+    /// correction record is written to the segments file.
+    pub fn write_late_data(&mut self, direction: Direction, late: &LateData) {
+        let Some(files) = &mut self.files else {
+            return;
+        };
+        let inner = &self.shared_info.inner;
+        let writer = &inner.writer;
+        let (data_file, segments_file) = match direction {
+            Direction::Forward => (files.forward_data, files.forward_segments),
+            Direction::Reverse => (files.reverse_data, files.reverse_segments),
+        };
+        // progress and late data files are by label
+        let label = if self.labels_swapped {
+            direction.swap()
+        } else {
+            direction
+        };
+        let (index, suffix, progress) = match label {
+            Direction::Forward => (0, "f", &files.progress.forward),
+            Direction::Reverse => (1, "r", &files.progress.reverse),
+        };
+        let end_offset = progress.end_offset;
+        // stream offset of the start of the data file
+        let data_start = end_offset - progress.data_bytes;
+        let patched = inner.compression == Compression::None
+            && inner.dedup.is_none()
+            && inner.layout == DataLayout::Split
+            && late.offset >= data_start
+            && late.offset + late.data.len() as u64 <= end_offset;
+        if patched {
+            trace!(
+                "write_late_data: patching {} bytes at offset {}",
+                late.data.len(),
+                late.offset
+            );
+            writer.send(WriterMessage::WriteAt {
+                id: data_file,
+                offset: late.offset - data_start,
+                data: late.data.clone(),
+            });
+        } else {
+            let late_file = files.late_data[index];
+            if !files.late_data_created[index] {
+                writer.send(WriterMessage::Create {
+                    id: late_file,
+                    path: inner.base_dir.join(format!("{}.{suffix}.late", self.id)),
+                });
+                files.late_data_created[index] = true;
+            }
+            writer.send(WriterMessage::Write {
+                id: late_file,
+                data: late.data.clone(),
+            });
+        }
+
+        let mut record = Vec::new();
+        let segment = SerializedSegment::new_late_data(late, patched);
+        inner.segment_format.write(&mut record, &segment);
+        let record_len = record.len() as u64;
+        writer.send(WriterMessage::Write {
+            id: segments_file,
+            data: record,
+        });
+        files.record_progress(writer, direction, end_offset, 0, record_len);
+        self.wrote_data = true;
+    }
+
+    /// write state transition journal to `<id>.states.jsonl`, if any
+    pub fn write_transitions(&self, connection: &Connection<Self>) {
+        if connection.transitions.is_empty() {
+            return;
+        }
+        let mut data = Vec::new();
+        for transition in &connection.transitions {
+            serde_json::to_writer(&mut data, transition).expect("failed to serialize transition");
+            data.push(b'\n');
+        }
+        let inner = &self.shared_info.inner;
+        let id = inner.writer.allocate_id();
+        inner.writer.send(WriterMessage::Create {
+            id,
+            path: inner.base_dir.join(format!("{}.states.jsonl", self.id)),
+        });
+        inner.writer.send(WriterMessage::Write { id, data });
+        inner.writer.send(WriterMessage::Close { id });
+    }
+
+    /// close stream files
+    pub fn close_files(&mut self) {
+        let Some(mut files) = self.files.take() else {
+            return;
+        };
+        let writer = &self.shared_info.inner.writer;
+        if let Some(dedup) = &self.shared_info.inner.dedup {
+            self.dedup_streams[0].finish(dedup, writer, files.forward_data);
+            self.dedup_streams[1].finish(dedup, writer, files.reverse_data);
+        }
+        if let Some(mut interleaver) = self.interleaver.take() {
+            let mut records = Vec::new();
+            interleaver.write_ready(&mut records, true);
+            if !records.is_empty() {
+                writer.send(WriterMessage::Write {
+                    id: files.forward_data,
+                    data: records,
+                });
+            }
+        }
+        files.close(writer);
+    }
 }
 
 macro_rules! log_error {
@@ -429,6 +973,11 @@ impl ConnectionHandler for DirectoryOutputHandler {
             segments: Vec::new(),
             got_handshake_done: false,
             files: None,
+            segments_buf: Vec::new(),
+            dedup_streams: Default::default(),
+            wrote_data: false,
+            labels_swapped: false,
+            interleaver: None,
         })
     }
 
@@ -440,57 +989,78 @@ impl ConnectionHandler for DirectoryOutputHandler {
         if !self.got_handshake_done {
             self.got_handshake_done = true;
         }
-        log_error!(
-            self.shared_info
-                .record_conn_info(connection.uuid, &connection.forward_flow),
-            "failed to write connection info"
-        );
 
-        self.shared_info.capture_errors(|| {
-            let id = connection.uuid;
-            let base_dir = &self.shared_info.inner.base_dir;
-            trace!("creating files for connection {id}");
-            let forward_data = File::create(base_dir.join(format!("{id}.f.data")))
-                .wrap_err("creating forward data file")?;
-            let forward_segments = File::create(base_dir.join(format!("{id}.f.jsonl")))
-                .wrap_err("creating forward segments file")?;
-            let reverse_data = File::create(base_dir.join(format!("{id}.r.data")))
-                .wrap_err("creating reverse data file")?;
-            let reverse_segments = File::create(base_dir.join(format!("{id}.r.jsonl")))
-                .wrap_err("creating reverse segments file")?;
-            self.files = Some(DirectoryOutputHandlerFiles {
-                forward_data,
-                forward_segments,
-                reverse_data,
-                reverse_segments,
-            });
-            Ok(())
-        });
+        self.close_files();
+        let id = connection.uuid;
+        let inner = &self.shared_info.inner;
+        trace!("creating files for connection {id}");
+        self.interleaver = (inner.layout == DataLayout::Interleaved).then(Interleaver::default);
+        self.files = Some(DirectoryOutputHandlerFiles::create(
+            &inner.writer,
+            &inner.base_dir,
+            id,
+            inner.data_suffixes(),
+            inner.segment_format,
+        ));
     }
 
     fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let config = connection.config.clone();
         let stream = connection.get_stream(direction);
         let readable_len = stream.readable_buffered_length();
-        if readable_len > BUFFER_READABLE_THRESHOLD
-            || stream.segments_info.len() > BUFFER_SEGMENTS_THRESHOLD
+        if readable_len > config.flush_readable_threshold
+            || stream.segments_info.len() > config.flush_segments_threshold
         {
             log_error!(
                 self.write_stream_data(connection, direction, Some(readable_len)),
                 "failed to write stream data"
             );
-        } else if stream.total_buffered_length() > BUFFER_TOTAL_THRESHOLD {
+        } else if stream.total_buffered_length() > config.flush_total_threshold {
             log_error!(
-                self.write_stream_data(connection, direction, Some(BUFFER_TOTAL_THRESHOLD_ADVANCE)),
+                self.write_stream_data(connection, direction, Some(config.flush_total_advance)),
                 "failed to write stream data"
             );
         }
     }
 
+    fn late_data(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        direction: Direction,
+        late: &LateData,
+    ) {
+        self.write_late_data(direction, late);
+    }
+
+    fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        log_error!(
+            self.write_stream_data(connection, direction, None),
+            "failed to write stream data"
+        );
+    }
+
+    fn direction_changed(&mut self, connection: &mut Connection<Self>) {
+        if !self.wrote_data {
+            // files will be written from the reversed streams
+            return;
+        }
+        warn!(
+            "direction of connection {} changed after data was written, keeping previous labeling",
+            connection.uuid
+        );
+        if let Some(files) = &mut self.files {
+            files.swap_directions();
+        }
+        self.dedup_streams.swap(0, 1);
+        self.labels_swapped = !self.labels_swapped;
+    }
+
     fn will_retire(&mut self, connection: &mut Connection<Self>) {
         info!(
             "removing connection: {} ({})",
             connection.forward_flow, connection.uuid
         );
+        self.write_transitions(connection);
         if !self.got_handshake_done {
             // nothing to write if no data
             return;
@@ -503,5 +1073,192 @@ impl ConnectionHandler for DirectoryOutputHandler {
             self.write_stream_data(connection, Direction::Reverse, None),
             "failed to write final reverse stream data"
         );
+        self.close_files();
+        // written on retire so connection statistics are complete
+        let mut info = ConnInfo::from_connection(connection);
+        if self.labels_swapped {
+            info.reverse();
+        }
+        log_error!(
+            self.shared_info.write_conn_info(&info),
+            "failed to write connection info"
+        );
+    }
+
+    fn checkpoint_state(&self) -> Option<serde_json::Value> {
+        let files = self.files.as_ref()?;
+        let checkpoint = DirectoryOutputCheckpoint {
+            progress: files.progress.clone(),
+            swapped: files.swapped,
+            wrote_data: self.wrote_data,
+            labels_swapped: self.labels_swapped,
+            late_data_created: files.late_data_created,
+            dedup_streams: self.dedup_streams.clone(),
+            interleaver: self.interleaver.clone(),
+        };
+        Some(serde_json::to_value(checkpoint).expect("failed to serialize handler state"))
+    }
+
+    fn restored(&mut self, connection: &mut Connection<Self>, state: Option<serde_json::Value>) {
+        let checkpoint = match state.map(serde_json::from_value::<DirectoryOutputCheckpoint>) {
+            Some(Ok(checkpoint)) => checkpoint,
+            Some(Err(e)) => {
+                warn!(
+                    "discarding handler state of restored connection {}: {e}",
+                    connection.uuid
+                );
+                self.handshake_done(connection);
+                return;
+            }
+            None => {
+                self.handshake_done(connection);
+                return;
+            }
+        };
+        info!(
+            "continuing data for restored connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        self.got_handshake_done = true;
+        let inner = &self.shared_info.inner;
+        self.files = Some(DirectoryOutputHandlerFiles::reopen(
+            &inner.writer,
+            &inner.base_dir,
+            connection.uuid,
+            inner.data_suffixes(),
+            inner.segment_format,
+            &checkpoint,
+        ));
+        self.wrote_data = checkpoint.wrote_data;
+        self.labels_swapped = checkpoint.labels_swapped;
+        self.dedup_streams = checkpoint.dedup_streams;
+        self.interleaver = checkpoint.interleaver;
+    }
+}
+
+/// shared state for PcapSplitHandler
+#[derive(Clone)]
+pub struct PcapSplitSharedInfo {
+    pub base_dir: Arc<PathBuf>,
+}
+
+impl PcapSplitSharedInfo {
+    /// create with output path
+    pub fn new(base_dir: PathBuf) -> Self {
+        PcapSplitSharedInfo {
+            base_dir: Arc::new(base_dir),
+        }
+    }
+}
+
+/// ConnectionHandler to write the original packets of each connection to
+/// separate pcap files
+///
+/// Packets are taken from `PacketExtra::frames`, so the parser must be asked
+/// to keep captured frames. Records are collected in memory and appended to
+/// the file once `PCAP_SPLIT_BUFFER_SIZE` is reached, so no file is held open
+/// between writes. Reassembled stream data is not used and is discarded.
+pub struct PcapSplitHandler {
+    pub shared_info: PcapSplitSharedInfo,
+    pub id: Uuid,
+    /// pending records not yet written to the file
+    pub writer: Option<PcapWriter<Vec<u8>>>,
+    /// whether the file was created already
+    pub created: bool,
+}
+
+impl PcapSplitHandler {
+    /// write frames of packet to connection pcap, creating it if necessary
+    pub fn write_frames(&mut self, frames: &[RawFrame]) -> std::io::Result<()> {
+        let Some(first) = frames.first() else {
+            return Ok(());
+        };
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => self
+                .writer
+                .insert(PcapWriter::new(Vec::new(), first.linktype)?),
+        };
+        for frame in frames {
+            writer.write_frame(frame)?;
+        }
+        if writer.writer.len() >= PCAP_SPLIT_BUFFER_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// append pending records to the connection pcap
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        let Some(writer) = &mut self.writer else {
+            return Ok(());
+        };
+        if writer.writer.is_empty() {
+            return Ok(());
+        }
+        let path = self.shared_info.base_dir.join(format!("{}.pcap", self.id));
+        let mut file = if self.created {
+            OpenOptions::new().append(true).open(path)?
+        } else {
+            trace!("creating pcap file for connection {}", self.id);
+            File::create(path)?
+        };
+        self.created = true;
+        file.write_all(&writer.writer)?;
+        writer.writer.clear();
+        Ok(())
+    }
+
+    /// drop buffered stream data and segment info of a direction
+    fn discard_stream(connection: &mut Connection<Self>, direction: Direction) {
+        let stream = connection.get_stream(direction);
+        let end = stream.buffer_start() + stream.total_buffered_length() as u64;
+        stream.consume_until(end);
+        stream.segments_info.clear();
+    }
+}
+
+impl ConnectionHandler for PcapSplitHandler {
+    type InitialData = PcapSplitSharedInfo;
+    type ConstructError = Infallible;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> Result<Self, Infallible> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(PcapSplitHandler {
+            shared_info,
+            id: connection.uuid,
+            writer: None,
+            created: false,
+        })
+    }
+
+    fn packet_received(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        extra: &PacketExtra,
+    ) {
+        log_error!(self.write_frames(extra.frames()), "failed to write packet");
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        Self::discard_stream(connection, direction);
+    }
+
+    fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        Self::discard_stream(connection, direction);
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        info!(
+            "removing connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        log_error!(self.flush(), "failed to write pcap file");
     }
 }
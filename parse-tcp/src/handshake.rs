@@ -0,0 +1,111 @@
+//! Handshake observations
+//!
+//! Counts and timing of the SYN, SYN/ACK and final ACK of a connection, and
+//! notable occurrences reported to handlers through
+//! `ConnectionHandler::handshake_event`.
+//!
+//! Payload carried on the SYN or SYN/ACK (TCP Fast Open) is held until the
+//! handshake resolves, then delivered to the stream at offset 0.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::connection::Direction;
+
+/// notable handshake occurrence
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HandshakeEvent {
+    /// both sides sent a SYN without ACK
+    SimultaneousOpen,
+    /// SYN or SYN/ACK sent again, `count` includes the original
+    SynRetransmit { count: u32 },
+    /// SYN or SYN/ACK carried payload (e.g. TCP Fast Open), which is
+    /// delivered once the handshake resolves
+    SynData { len: usize },
+    /// handshake completed, with round trip times as seen from the capture
+    /// point
+    Rtt {
+        /// time from SYN to SYN/ACK, if the SYN was seen
+        server: Option<Duration>,
+        /// time from SYN/ACK to the final ACK
+        client: Duration,
+    },
+}
+
+/// payload carried on a SYN or SYN/ACK
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SynPayload {
+    /// sequence number of first byte (sequence number of the SYN plus one)
+    pub seq_number: u32,
+    /// payload data
+    pub data: Vec<u8>,
+}
+
+/// handshake counts, timing, and SYN payload
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandshakeInfo {
+    /// number of SYN packets without ACK, including retransmissions
+    pub syn_count: u32,
+    /// number of SYN/ACK packets, including retransmissions
+    pub syn_ack_count: u32,
+    /// whether both sides sent a SYN without ACK
+    pub simultaneous_open: bool,
+    /// capture time of first SYN
+    pub syn_time: Option<Duration>,
+    /// capture time of first SYN/ACK
+    pub syn_ack_time: Option<Duration>,
+    /// capture time of final ACK
+    pub ack_time: Option<Duration>,
+    /// MSS option of first SYN
+    #[serde(default)]
+    pub syn_mss: Option<u16>,
+    /// MSS option of first SYN/ACK
+    #[serde(default)]
+    pub syn_ack_mss: Option<u16>,
+    /// whether the SYN requested ECN
+    #[serde(default)]
+    pub syn_ecn: bool,
+    /// whether the SYN/ACK accepted ECN
+    #[serde(default)]
+    pub syn_ack_ecn: bool,
+    /// TCP Fast Open cookie option of first SYN, empty for a cookie request
+    #[serde(default)]
+    pub syn_tfo_cookie: Option<Vec<u8>>,
+    /// TCP Fast Open cookie option of first SYN/ACK
+    #[serde(default)]
+    pub syn_ack_tfo_cookie: Option<Vec<u8>>,
+    /// undelivered payload of SYN in forward direction
+    #[serde(default)]
+    pub forward_syn_data: Option<SynPayload>,
+    /// undelivered payload of SYN or SYN/ACK in reverse direction
+    #[serde(default)]
+    pub reverse_syn_data: Option<SynPayload>,
+}
+
+impl HandshakeInfo {
+    /// round trip time between capture point and server
+    pub fn server_rtt(&self) -> Option<Duration> {
+        self.syn_ack_time?.checked_sub(self.syn_time?)
+    }
+
+    /// round trip time between capture point and client
+    pub fn client_rtt(&self) -> Option<Duration> {
+        self.ack_time?.checked_sub(self.syn_ack_time?)
+    }
+
+    /// whether ECN was negotiated
+    pub fn ecn_negotiated(&self) -> bool {
+        self.syn_ecn && self.syn_ack_ecn
+    }
+
+    /// length of undelivered SYN payload in direction
+    pub fn syn_data_len(&self, direction: Direction) -> u32 {
+        let payload = match direction {
+            Direction::Forward => &self.forward_syn_data,
+            Direction::Reverse => &self.reverse_syn_data,
+        };
+        payload.as_ref().map_or(0, |p| p.data.len() as u32)
+    }
+}
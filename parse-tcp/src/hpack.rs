@@ -0,0 +1,656 @@
+//! HPACK header block decoding (RFC 7541)
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::sync::OnceLock;
+
+/// initial size of the dynamic table
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+/// largest dynamic table size accepted from size updates
+pub const MAX_TABLE_SIZE: usize = 1 << 20;
+/// overhead added to the size of each dynamic table entry
+const ENTRY_OVERHEAD: usize = 32;
+
+/// error decoding a header block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HpackError {
+    Truncated,
+    IntegerOverflow,
+    InvalidIndex(usize),
+    InvalidHuffman,
+    TableSizeTooLarge(usize),
+}
+
+impl Display for HpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HpackError::Truncated => write!(f, "truncated header block"),
+            HpackError::IntegerOverflow => write!(f, "integer overflow"),
+            HpackError::InvalidIndex(index) => write!(f, "invalid table index {index}"),
+            HpackError::InvalidHuffman => write!(f, "invalid huffman code"),
+            HpackError::TableSizeTooLarge(size) => write!(f, "table size {size} too large"),
+        }
+    }
+}
+
+impl std::error::Error for HpackError {}
+
+/// static table, indexed from 1
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// huffman code and length in bits of each symbol, with EOS last
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+/// canonical decoding table built from `HUFFMAN_CODES`
+struct HuffmanTable {
+    /// symbols ordered by code length, then code
+    symbols: Vec<u16>,
+    /// first code of each length
+    first_code: [u32; 31],
+    /// index in `symbols` of first code of each length
+    first_index: [usize; 31],
+    /// number of codes of each length
+    count: [u32; 31],
+}
+
+fn huffman_table() -> &'static HuffmanTable {
+    static TABLE: OnceLock<HuffmanTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut symbols: Vec<u16> = (0..HUFFMAN_CODES.len() as u16).collect();
+        symbols.sort_by_key(|&s| {
+            let (code, len) = HUFFMAN_CODES[s as usize];
+            (len, code)
+        });
+        let mut table = HuffmanTable {
+            symbols: Vec::new(),
+            first_code: [0; 31],
+            first_index: [0; 31],
+            count: [0; 31],
+        };
+        for (index, &symbol) in symbols.iter().enumerate() {
+            let (code, len) = HUFFMAN_CODES[symbol as usize];
+            let len = len as usize;
+            if table.count[len] == 0 {
+                table.first_code[len] = code;
+                table.first_index[len] = index;
+            }
+            table.count[len] += 1;
+        }
+        table.symbols = symbols;
+        table
+    })
+}
+
+/// decode huffman-encoded string
+pub fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let table = huffman_table();
+    let mut out = Vec::with_capacity(data.len() * 8 / 5);
+    let mut code: u32 = 0;
+    let mut len = 0;
+    for &byte in data {
+        for shift in (0..8).rev() {
+            code = code << 1 | (byte >> shift & 1) as u32;
+            len += 1;
+            if len > 30 {
+                return Err(HpackError::InvalidHuffman);
+            }
+            let offset = code.wrapping_sub(table.first_code[len]);
+            if offset < table.count[len] {
+                let symbol = table.symbols[table.first_index[len] + offset as usize];
+                if symbol == 256 {
+                    // EOS may not appear in strings
+                    return Err(HpackError::InvalidHuffman);
+                }
+                out.push(symbol as u8);
+                code = 0;
+                len = 0;
+            }
+        }
+    }
+    // padding is a prefix of EOS, shorter than a byte
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(HpackError::InvalidHuffman);
+    }
+    Ok(out)
+}
+
+/// reader over a header block
+struct BlockReader<'a> {
+    data: &'a [u8],
+}
+
+impl BlockReader<'_> {
+    /// read integer with `prefix` bits in the first byte
+    fn integer(&mut self, prefix: u8) -> Result<usize, HpackError> {
+        let (&first, rest) = self.data.split_first().ok_or(HpackError::Truncated)?;
+        self.data = rest;
+        let max = (1usize << prefix) - 1;
+        let mut value = first as usize & max;
+        if value < max {
+            return Ok(value);
+        }
+        let mut shift = 0;
+        loop {
+            let (&byte, rest) = self.data.split_first().ok_or(HpackError::Truncated)?;
+            self.data = rest;
+            if shift > 28 {
+                return Err(HpackError::IntegerOverflow);
+            }
+            value += ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// read string literal
+    fn string(&mut self) -> Result<Vec<u8>, HpackError> {
+        let huffman = self.data.first().ok_or(HpackError::Truncated)? & 0x80 != 0;
+        let len = self.integer(7)?;
+        if self.data.len() < len {
+            return Err(HpackError::Truncated);
+        }
+        let (raw, rest) = self.data.split_at(len);
+        self.data = rest;
+        if huffman {
+            huffman_decode(raw)
+        } else {
+            Ok(raw.to_vec())
+        }
+    }
+}
+
+/// HPACK decoder for one direction of a connection
+pub struct HpackDecoder {
+    /// dynamic table, most recent entry first
+    table: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// size of dynamic table entries
+    size: usize,
+    /// current max size of dynamic table
+    max_size: usize,
+}
+
+impl Default for HpackDecoder {
+    fn default() -> Self {
+        HpackDecoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+impl HpackDecoder {
+    /// decode a complete header block
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, HpackError> {
+        let mut reader = BlockReader { data: block };
+        let mut headers = Vec::new();
+        while let Some(&first) = reader.data.first() {
+            let (name, value) = if first & 0x80 != 0 {
+                // indexed field
+                let index = reader.integer(7)?;
+                self.lookup(index)?
+            } else if first & 0x40 != 0 {
+                // literal with incremental indexing
+                let (name, value) = self.read_literal(&mut reader, 6)?;
+                self.insert(name.clone(), value.clone());
+                (name, value)
+            } else if first & 0x20 != 0 {
+                let size = reader.integer(5)?;
+                if size > MAX_TABLE_SIZE {
+                    return Err(HpackError::TableSizeTooLarge(size));
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // literal without indexing or never indexed
+                self.read_literal(&mut reader, 4)?
+            };
+            headers.push((
+                String::from_utf8_lossy(&name).into_owned(),
+                String::from_utf8_lossy(&value).into_owned(),
+            ));
+        }
+        Ok(headers)
+    }
+
+    /// get entry from static or dynamic table
+    fn lookup(&self, index: usize) -> Result<(Vec<u8>, Vec<u8>), HpackError> {
+        match index {
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self
+                .table
+                .get(index.wrapping_sub(STATIC_TABLE.len() + 1))
+                .cloned()
+                .ok_or(HpackError::InvalidIndex(index)),
+        }
+    }
+
+    /// read literal field with name index of `prefix` bits
+    fn read_literal(
+        &self,
+        reader: &mut BlockReader<'_>,
+        prefix: u8,
+    ) -> Result<(Vec<u8>, Vec<u8>), HpackError> {
+        let name_index = reader.integer(prefix)?;
+        let name = if name_index == 0 {
+            reader.string()?
+        } else {
+            self.lookup(name_index)?.0
+        };
+        Ok((name, reader.string()?))
+    }
+
+    /// add entry to dynamic table
+    fn insert(&mut self, name: Vec<u8>, value: Vec<u8>) {
+        let entry_size = name.len() + value.len() + ENTRY_OVERHEAD;
+        self.evict(entry_size);
+        if entry_size <= self.max_size {
+            self.size += entry_size;
+            self.table.push_front((name, value));
+        }
+    }
+
+    /// evict entries until `extra` bytes fit
+    fn evict(&mut self, extra: usize) {
+        while self.size + extra > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{huffman_decode, HpackDecoder, HpackError, HUFFMAN_CODES};
+
+    fn owned(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn huffman() {
+        // RFC 7541 C.4.1
+        let encoded = [
+            0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff,
+        ];
+        assert_eq!(huffman_decode(&encoded).unwrap(), b"www.example.com");
+        // every symbol decodes from its code
+        for (symbol, &(code, len)) in HUFFMAN_CODES[..256].iter().enumerate() {
+            let padding = (8 - len as u32 % 8) % 8;
+            let bits = (code as u64) << padding | ((1 << padding) - 1);
+            let bytes = bits.to_be_bytes();
+            let encoded = &bytes[8 - (len as usize + padding as usize) / 8..];
+            assert_eq!(huffman_decode(encoded).unwrap(), [symbol as u8]);
+        }
+        assert_eq!(
+            huffman_decode(&[0xff, 0xff]),
+            Err(HpackError::InvalidHuffman)
+        );
+    }
+
+    #[test]
+    fn request_sequence() {
+        // RFC 7541 C.4, requests with huffman coding sharing a dynamic table
+        let mut decoder = HpackDecoder::default();
+        let first = [
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab,
+            0x90, 0xf4, 0xff,
+        ];
+        assert_eq!(
+            decoder.decode(&first).unwrap(),
+            owned(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        let second = [
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf,
+        ];
+        assert_eq!(
+            decoder.decode(&second).unwrap(),
+            owned(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+        assert_eq!(decoder.size, 110);
+        assert_eq!(decoder.decode(&[0xc0]), Err(HpackError::InvalidIndex(64)));
+        assert_eq!(decoder.decode(&[0x41, 0x85]), Err(HpackError::Truncated));
+    }
+}
@@ -0,0 +1,818 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use eyre::Context;
+use parking_lot::Mutex;
+use tracing::{debug, error, info, trace, warn};
+use uuid::Uuid;
+
+use crate::connection::{Connection, Direction};
+use crate::serialized::HttpTransactionInfo;
+use crate::stream::{SegmentInfo, StreamGap};
+use crate::ConnectionHandler;
+
+/// max size of a message head (start line and headers)
+pub const MAX_HEAD_SIZE: usize = 64 << 10; // 64 KB
+/// max number of headers in a message
+pub const MAX_HEADERS: usize = 128;
+/// max length of a chunk size or trailer line
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// type of message parsed by HttpParser
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpMessageKind {
+    Request,
+    Response,
+}
+
+/// parsed request head
+#[derive(Clone, Debug)]
+pub struct HttpRequestHead {
+    pub method: String,
+    pub uri: String,
+    /// minor version (HTTP/1.x)
+    pub version: u8,
+    pub headers: Vec<(String, String)>,
+}
+
+/// parsed response head
+#[derive(Clone, Debug)]
+pub struct HttpResponseHead {
+    pub status: u16,
+    pub reason: String,
+    /// minor version (HTTP/1.x)
+    pub version: u8,
+    pub headers: Vec<(String, String)>,
+}
+
+/// event emitted by HttpParser
+#[derive(Debug)]
+pub enum HttpEvent {
+    /// request head parsed
+    Request(HttpRequestHead),
+    /// response head parsed
+    Response(HttpResponseHead),
+    /// (decoded) body data
+    Body(Vec<u8>),
+    /// end of message
+    End,
+}
+
+/// parser state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ParseState {
+    /// reading message head
+    Head,
+    /// reading body with known remaining length
+    Body(u64),
+    /// reading chunk size line
+    ChunkSize,
+    /// reading chunk data with remaining length
+    ChunkData(u64),
+    /// reading CRLF after chunk data
+    ChunkDataEnd,
+    /// reading trailer section after last chunk
+    Trailers,
+    /// reading body until end of stream
+    UntilClose,
+    /// connection switched protocols, no longer HTTP
+    Upgraded,
+    /// unrecoverable parse error
+    Failed,
+}
+
+/// incremental HTTP/1.x message parser for one direction of a connection
+pub struct HttpParser {
+    pub kind: HttpMessageKind,
+    state: ParseState,
+    /// unconsumed input
+    buf: Vec<u8>,
+    /// methods of requests awaiting a response (response parser only)
+    pub request_methods: VecDeque<String>,
+}
+
+impl HttpParser {
+    /// create new parser
+    pub fn new(kind: HttpMessageKind) -> Self {
+        HttpParser {
+            kind,
+            state: ParseState::Head,
+            buf: Vec::new(),
+            request_methods: VecDeque::new(),
+        }
+    }
+
+    /// whether the parser can accept more data
+    pub fn is_active(&self) -> bool {
+        !matches!(self.state, ParseState::Upgraded | ParseState::Failed)
+    }
+
+    /// whether the parser is between messages
+    pub fn is_idle(&self) -> bool {
+        self.state == ParseState::Head && self.buf.is_empty()
+    }
+
+    /// stop parsing (e.g. on stream gaps)
+    pub fn fail(&mut self) {
+        self.state = ParseState::Failed;
+        self.buf = Vec::new();
+    }
+
+    /// stop parsing as connection is no longer HTTP
+    pub fn set_upgraded(&mut self) {
+        if self.is_active() {
+            self.state = ParseState::Upgraded;
+            self.buf = Vec::new();
+        }
+    }
+
+    /// feed stream data, appending parsed events to `events`
+    pub fn feed(&mut self, data: &[u8], events: &mut Vec<HttpEvent>) {
+        if !self.is_active() {
+            return;
+        }
+        self.buf.extend_from_slice(data);
+        let mut pos = 0;
+        while pos < self.buf.len() {
+            match self.state {
+                ParseState::Head => {
+                    // skip empty lines between messages
+                    while pos < self.buf.len() && matches!(self.buf[pos], b'\r' | b'\n') {
+                        pos += 1;
+                    }
+                    if pos == self.buf.len() {
+                        break;
+                    }
+                    match self.parse_head(pos, events) {
+                        Ok(Some(len)) => pos += len,
+                        Ok(None) => {
+                            if self.buf.len() - pos > MAX_HEAD_SIZE {
+                                debug!("http: message head too large");
+                                self.fail();
+                                return;
+                            }
+                            break;
+                        }
+                        Err(e) => {
+                            debug!("http: failed to parse {:?} head: {e}", self.kind);
+                            self.fail();
+                            return;
+                        }
+                    }
+                }
+                ParseState::Body(remaining) | ParseState::ChunkData(remaining) => {
+                    let available = (self.buf.len() - pos) as u64;
+                    let len = remaining.min(available) as usize;
+                    events.push(HttpEvent::Body(self.buf[pos..pos + len].to_vec()));
+                    pos += len;
+                    let remaining = remaining - len as u64;
+                    self.state = match (self.state, remaining) {
+                        (ParseState::Body(_), 0) => {
+                            events.push(HttpEvent::End);
+                            ParseState::Head
+                        }
+                        (ParseState::Body(_), _) => ParseState::Body(remaining),
+                        (_, 0) => ParseState::ChunkDataEnd,
+                        (_, _) => ParseState::ChunkData(remaining),
+                    };
+                }
+                ParseState::ChunkSize => {
+                    let Some((line, len)) = self.read_line(pos) else {
+                        break;
+                    };
+                    let size_str = line.split(|&b| b == b';').next().unwrap_or_default();
+                    let size = std::str::from_utf8(size_str)
+                        .ok()
+                        .and_then(|s| u64::from_str_radix(s.trim(), 16).ok());
+                    let Some(size) = size else {
+                        debug!("http: invalid chunk size");
+                        self.fail();
+                        return;
+                    };
+                    pos += len;
+                    self.state = if size == 0 {
+                        ParseState::Trailers
+                    } else {
+                        ParseState::ChunkData(size)
+                    };
+                }
+                ParseState::ChunkDataEnd => {
+                    let Some((line, len)) = self.read_line(pos) else {
+                        break;
+                    };
+                    if !line.is_empty() {
+                        debug!("http: missing CRLF after chunk data");
+                        self.fail();
+                        return;
+                    }
+                    pos += len;
+                    self.state = ParseState::ChunkSize;
+                }
+                ParseState::Trailers => {
+                    let Some((line, len)) = self.read_line(pos) else {
+                        break;
+                    };
+                    pos += len;
+                    if line.is_empty() {
+                        events.push(HttpEvent::End);
+                        self.state = ParseState::Head;
+                    }
+                }
+                ParseState::UntilClose => {
+                    events.push(HttpEvent::Body(self.buf[pos..].to_vec()));
+                    pos = self.buf.len();
+                }
+                ParseState::Upgraded | ParseState::Failed => {
+                    pos = self.buf.len();
+                }
+            }
+        }
+        let in_line = matches!(
+            self.state,
+            ParseState::ChunkSize | ParseState::ChunkDataEnd | ParseState::Trailers
+        );
+        if in_line && self.buf.len() - pos > MAX_LINE_LENGTH {
+            debug!("http: line too long");
+            self.fail();
+            return;
+        }
+        self.buf.drain(..pos);
+    }
+
+    /// signal end of stream, completing messages delimited by close
+    pub fn finish(&mut self, events: &mut Vec<HttpEvent>) {
+        if self.state == ParseState::UntilClose {
+            events.push(HttpEvent::End);
+            self.state = ParseState::Head;
+        }
+    }
+
+    /// read a line starting at `pos`, returning line without terminator and
+    /// total length consumed
+    fn read_line(&self, pos: usize) -> Option<(&[u8], usize)> {
+        let rest = &self.buf[pos..];
+        let newline = rest.iter().position(|&b| b == b'\n')?;
+        let line = &rest[..newline];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        Some((line, newline + 1))
+    }
+
+    /// parse message head at `pos`, returning length consumed if complete
+    fn parse_head(
+        &mut self,
+        pos: usize,
+        events: &mut Vec<HttpEvent>,
+    ) -> Result<Option<usize>, httparse::Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let input = &self.buf[pos..];
+        match self.kind {
+            HttpMessageKind::Request => {
+                let mut req = httparse::Request::new(&mut headers);
+                let httparse::Status::Complete(len) = req.parse(input)? else {
+                    return Ok(None);
+                };
+                let head = HttpRequestHead {
+                    method: req.method.unwrap_or_default().to_string(),
+                    uri: req.path.unwrap_or_default().to_string(),
+                    version: req.version.unwrap_or_default(),
+                    headers: collect_headers(req.headers),
+                };
+                trace!("http: request {} {}", head.method, head.uri);
+                let body = request_body_state(&head.headers);
+                events.push(HttpEvent::Request(head));
+                self.start_body(body, events);
+                Ok(Some(len))
+            }
+            HttpMessageKind::Response => {
+                let mut res = httparse::Response::new(&mut headers);
+                let httparse::Status::Complete(len) = res.parse(input)? else {
+                    return Ok(None);
+                };
+                let head = HttpResponseHead {
+                    status: res.code.unwrap_or_default(),
+                    reason: res.reason.unwrap_or_default().to_string(),
+                    version: res.version.unwrap_or_default(),
+                    headers: collect_headers(res.headers),
+                };
+                trace!("http: response {} {}", head.status, head.reason);
+                let body = if head.status == 101 {
+                    ParseState::Upgraded
+                } else if (100..200).contains(&head.status) {
+                    // interim response, does not answer the request
+                    ParseState::Head
+                } else {
+                    let method = self.request_methods.pop_front();
+                    response_body_state(method.as_deref(), &head)
+                };
+                events.push(HttpEvent::Response(head));
+                self.start_body(body, events);
+                Ok(Some(len))
+            }
+        }
+    }
+
+    /// move to body state after head
+    fn start_body(&mut self, state: ParseState, events: &mut Vec<HttpEvent>) {
+        self.state = state;
+        match state {
+            ParseState::Head | ParseState::Body(0) => {
+                events.push(HttpEvent::End);
+                self.state = ParseState::Head;
+            }
+            ParseState::Upgraded => events.push(HttpEvent::End),
+            _ => {}
+        }
+    }
+}
+
+/// convert httparse headers to owned pairs
+fn collect_headers(headers: &[httparse::Header<'_>]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| {
+            (
+                h.name.to_string(),
+                String::from_utf8_lossy(h.value).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// find header value by name (case insensitive)
+pub fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// determine framing from Transfer-Encoding and Content-Length, if present
+fn framed_body_state(headers: &[(String, String)]) -> Option<ParseState> {
+    if let Some(te) = find_header(headers, "transfer-encoding") {
+        if te
+            .rsplit(',')
+            .next()
+            .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        {
+            return Some(ParseState::ChunkSize);
+        }
+    }
+    let len = find_header(headers, "content-length")?;
+    match len.trim().parse::<u64>() {
+        Ok(len) => Some(ParseState::Body(len)),
+        Err(_) => {
+            warn!("http: invalid content-length {len:?}");
+            None
+        }
+    }
+}
+
+/// body framing of a request
+fn request_body_state(headers: &[(String, String)]) -> ParseState {
+    framed_body_state(headers).unwrap_or(ParseState::Head)
+}
+
+/// body framing of a response to a request with `method`
+fn response_body_state(method: Option<&str>, head: &HttpResponseHead) -> ParseState {
+    if method == Some("HEAD") || head.status == 204 || head.status == 304 {
+        return ParseState::Head;
+    }
+    if method == Some("CONNECT") && (200..300).contains(&head.status) {
+        return ParseState::Upgraded;
+    }
+    framed_body_state(&head.headers).unwrap_or(ParseState::UntilClose)
+}
+
+/// shared state for HttpExtractHandler
+pub struct HttpExtractSharedInfoInner {
+    pub base_dir: PathBuf,
+    pub index_file: Mutex<File>,
+}
+
+#[derive(Clone)]
+pub struct HttpExtractSharedInfo {
+    pub inner: Arc<HttpExtractSharedInfoInner>,
+}
+
+impl HttpExtractSharedInfo {
+    /// create with output path
+    pub fn new(base_dir: PathBuf) -> std::io::Result<Self> {
+        let mut index_file = File::create(base_dir.join("http.json"))?;
+        index_file.write_all(b"[\n")?;
+        Ok(HttpExtractSharedInfo {
+            inner: Arc::new(HttpExtractSharedInfoInner {
+                base_dir,
+                index_file: Mutex::new(index_file),
+            }),
+        })
+    }
+
+    /// write transaction to index
+    pub fn record_transaction(&self, info: &HttpTransactionInfo) -> std::io::Result<()> {
+        let mut serialized =
+            serde_json::to_string(info).expect("failed to serialize HttpTransactionInfo");
+        serialized += ",\n";
+        let mut file = self.inner.index_file.lock();
+        file.write_all(serialized.as_bytes())
+    }
+
+    /// close index file
+    pub fn close(self) -> std::io::Result<()> {
+        let mut index_file = Arc::into_inner(self.inner).unwrap().index_file.into_inner();
+        let current_pos = index_file.stream_position()?;
+        if current_pos > 2 {
+            // overwrite trailing comma and close array
+            index_file.seek(SeekFrom::Current(-2))?;
+            index_file.write_all(b"\n]\n")?;
+        } else {
+            index_file.write_all(b"]\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// transaction being extracted
+pub struct HttpTransaction {
+    pub info: HttpTransactionInfo,
+    pub request_body: Option<BufWriter<File>>,
+    pub response_body: Option<BufWriter<File>>,
+}
+
+/// ConnectionHandler to extract HTTP/1.x transactions to a directory
+///
+/// Forward direction is assumed to carry requests. Bodies are written with
+/// transfer encoding removed to `{id}.{n}.req` and `{id}.{n}.resp`, and
+/// transactions are recorded in `http.json`.
+pub struct HttpExtractHandler {
+    pub shared_info: HttpExtractSharedInfo,
+    pub id: Uuid,
+    pub request_parser: HttpParser,
+    pub response_parser: HttpParser,
+    /// request currently being read
+    pub current_request: Option<HttpTransaction>,
+    /// requests awaiting response
+    pub pending: VecDeque<HttpTransaction>,
+    /// response currently being read
+    pub current_response: Option<HttpTransaction>,
+    /// index of next transaction
+    pub next_index: u64,
+
+    events: Vec<HttpEvent>,
+    gaps: Vec<StreamGap>,
+    segments: Vec<SegmentInfo>,
+    buf: Vec<u8>,
+}
+
+impl HttpExtractHandler {
+    /// read data from stream and feed to parser for direction
+    pub fn read_stream(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        len: usize,
+    ) -> eyre::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        self.gaps.clear();
+        self.segments.clear();
+        self.buf.clear();
+        let stream = connection.get_stream(direction);
+        let end_offset = stream.buffer_start() + len as u64;
+        let buf = &mut self.buf;
+        let read = stream.read_next(end_offset, &mut self.segments, &mut self.gaps, |slice| {
+            let (a, b) = slice.as_slices();
+            buf.extend_from_slice(a);
+            if let Some(b) = b {
+                buf.extend_from_slice(b);
+            }
+        });
+        if read.is_none() {
+            error!("http: {direction} stream cannot fulfill range, skipping");
+            return Ok(());
+        }
+
+        let parser = match direction {
+            Direction::Forward => &mut self.request_parser,
+            Direction::Reverse => &mut self.response_parser,
+        };
+        if !self.gaps.is_empty() {
+            if parser.is_active() {
+                debug!("http: {direction} stream has gaps, no longer parsing");
+            }
+            parser.fail();
+        }
+        parser.feed(&self.buf, &mut self.events);
+        self.handle_events(direction)
+    }
+
+    /// process events from parser
+    fn handle_events(&mut self, direction: Direction) -> eyre::Result<()> {
+        let mut events = std::mem::take(&mut self.events);
+        for event in events.drain(..) {
+            match event {
+                HttpEvent::Request(head) => {
+                    self.response_parser
+                        .request_methods
+                        .push_back(head.method.clone());
+                    let mut transaction = self.new_transaction();
+                    transaction.info.set_request(head);
+                    self.current_request = Some(transaction);
+                }
+                HttpEvent::Response(head) => {
+                    if (100..200).contains(&head.status) && head.status != 101 {
+                        trace!("http: ignoring interim response {}", head.status);
+                        continue;
+                    }
+                    if head.status == 101 {
+                        debug!("http: connection upgraded");
+                        self.request_parser.set_upgraded();
+                    }
+                    let mut transaction = self
+                        .pending
+                        .pop_front()
+                        .or_else(|| self.current_request.take())
+                        .unwrap_or_else(|| self.new_transaction());
+                    transaction.info.set_response(head);
+                    if let Some(previous) = self.current_response.replace(transaction) {
+                        self.finish_transaction(previous)?;
+                    }
+                }
+                HttpEvent::Body(data) => {
+                    let base_dir = &self.shared_info.inner.base_dir;
+                    let (transaction, is_request) = match direction {
+                        Direction::Forward => (self.current_request.as_mut(), true),
+                        Direction::Reverse => (self.current_response.as_mut(), false),
+                    };
+                    let Some(transaction) = transaction else {
+                        continue;
+                    };
+                    transaction.write_body(base_dir, self.id, is_request, &data)?;
+                }
+                HttpEvent::End => match direction {
+                    Direction::Forward => {
+                        if let Some(transaction) = self.current_request.take() {
+                            self.pending.push_back(transaction);
+                        }
+                    }
+                    Direction::Reverse => {
+                        if let Some(mut transaction) = self.current_response.take() {
+                            transaction.info.complete = true;
+                            self.finish_transaction(transaction)?;
+                        }
+                    }
+                },
+            }
+        }
+        self.events = events;
+        Ok(())
+    }
+
+    /// create new transaction with next index
+    fn new_transaction(&mut self) -> HttpTransaction {
+        let index = self.next_index;
+        self.next_index += 1;
+        HttpTransaction {
+            info: HttpTransactionInfo::new(self.id, index),
+            request_body: None,
+            response_body: None,
+        }
+    }
+
+    /// flush body files and record transaction in index
+    fn finish_transaction(&mut self, mut transaction: HttpTransaction) -> eyre::Result<()> {
+        if let Some(body) = &mut transaction.request_body {
+            body.flush().wrap_err("flushing request body")?;
+        }
+        if let Some(body) = &mut transaction.response_body {
+            body.flush().wrap_err("flushing response body")?;
+        }
+        self.shared_info
+            .record_transaction(&transaction.info)
+            .wrap_err("writing http index")
+    }
+
+    /// read all remaining data and record incomplete transactions
+    pub fn write_remaining(&mut self, connection: &mut Connection<Self>) -> eyre::Result<()> {
+        for direction in [Direction::Forward, Direction::Reverse] {
+            let len = connection.get_stream(direction).total_buffered_length();
+            self.read_stream(connection, direction, len)?;
+        }
+        self.response_parser.finish(&mut self.events);
+        self.handle_events(Direction::Reverse)?;
+
+        let incomplete: Vec<_> = self
+            .pending
+            .drain(..)
+            .chain(self.current_request.take())
+            .chain(self.current_response.take())
+            .collect();
+        for transaction in incomplete {
+            self.finish_transaction(transaction)?;
+        }
+        Ok(())
+    }
+}
+
+impl HttpTransaction {
+    /// append body data, creating the body file if necessary
+    pub fn write_body(
+        &mut self,
+        base_dir: &std::path::Path,
+        id: Uuid,
+        is_request: bool,
+        data: &[u8],
+    ) -> eyre::Result<()> {
+        let index = self.info.index;
+        let (file, name, len) = if is_request {
+            (
+                &mut self.request_body,
+                &mut self.info.request_body_file,
+                &mut self.info.request_body_len,
+            )
+        } else {
+            (
+                &mut self.response_body,
+                &mut self.info.response_body_file,
+                &mut self.info.response_body_len,
+            )
+        };
+        let file = match file {
+            Some(file) => file,
+            None => {
+                let file_name = format!("{id}.{index}.{}", if is_request { "req" } else { "resp" });
+                let created =
+                    File::create(base_dir.join(&file_name)).wrap_err("creating body file")?;
+                *name = Some(file_name);
+                file.insert(BufWriter::new(created))
+            }
+        };
+        file.write_all(data).wrap_err("writing body file")?;
+        *len += data.len() as u64;
+        Ok(())
+    }
+}
+
+impl ConnectionHandler for HttpExtractHandler {
+    type InitialData = HttpExtractSharedInfo;
+    type ConstructError = eyre::Report;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> eyre::Result<Self> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(HttpExtractHandler {
+            shared_info,
+            id: connection.uuid,
+            request_parser: HttpParser::new(HttpMessageKind::Request),
+            response_parser: HttpParser::new(HttpMessageKind::Response),
+            current_request: None,
+            pending: VecDeque::new(),
+            current_response: None,
+            next_index: 0,
+            events: Vec::new(),
+            gaps: Vec::new(),
+            segments: Vec::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        let result = if readable_len > 0 {
+            self.read_stream(connection, direction, readable_len)
+        } else if stream.total_buffered_length() > MAX_HEAD_SIZE {
+            // stuck behind a gap, skip ahead
+            let len = stream.total_buffered_length();
+            self.read_stream(connection, direction, len)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            tracing::error!("failed to extract http data: {e:?}");
+        }
+    }
+
+    fn gap_detected(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        direction: Direction,
+        range: Range<u64>,
+    ) {
+        let parser = match direction {
+            Direction::Forward => &mut self.request_parser,
+            Direction::Reverse => &mut self.response_parser,
+        };
+        if parser.is_active() {
+            debug!(
+                "http: {direction} stream lost {} bytes at offset {}, no longer parsing",
+                range.end - range.start,
+                range.start
+            );
+        }
+        parser.fail();
+    }
+
+    fn direction_changed(&mut self, _connection: &mut Connection<Self>) {
+        if self.next_index == 0 {
+            // nothing parsed yet, data so far went to the wrong parsers
+            self.request_parser = HttpParser::new(HttpMessageKind::Request);
+            self.response_parser = HttpParser::new(HttpMessageKind::Response);
+        } else {
+            debug!("http: direction changed after transactions were extracted");
+        }
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        info!(
+            "removing connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        if let Err(e) = self.write_remaining(connection) {
+            tracing::error!("failed to write remaining http data: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HttpEvent, HttpMessageKind, HttpParser};
+
+    fn bodies(events: &[HttpEvent]) -> Vec<u8> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                HttpEvent::Body(data) => Some(data.as_slice()),
+                _ => None,
+            })
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let mut parser = HttpParser::new(HttpMessageKind::Request);
+        let mut events = Vec::new();
+        let input = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+            GET /b HTTP/1.1\r\nHost: x\r\n\r\n";
+        // feed in small pieces
+        for chunk in input.chunks(7) {
+            parser.feed(chunk, &mut events);
+        }
+        assert!(parser.is_idle());
+        let requests: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                HttpEvent::Request(head) => Some(head.uri.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(requests, ["/a", "/b"]);
+        assert_eq!(bodies(&events), b"hello");
+        let ends = events
+            .iter()
+            .filter(|e| matches!(e, HttpEvent::End))
+            .count();
+        assert_eq!(ends, 2);
+    }
+
+    #[test]
+    fn chunked_response() {
+        let mut parser = HttpParser::new(HttpMessageKind::Response);
+        parser.request_methods.push_back("GET".into());
+        parser.request_methods.push_back("HEAD".into());
+        let mut events = Vec::new();
+        let input = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n";
+        parser.feed(input, &mut events);
+        assert!(parser.is_idle());
+        assert_eq!(bodies(&events), b"hello, world");
+        let ends = events
+            .iter()
+            .filter(|e| matches!(e, HttpEvent::End))
+            .count();
+        assert_eq!(ends, 2);
+    }
+
+    #[test]
+    fn response_until_close() {
+        let mut parser = HttpParser::new(HttpMessageKind::Response);
+        let mut events = Vec::new();
+        parser.feed(b"HTTP/1.0 200 OK\r\n\r\nsome data", &mut events);
+        parser.feed(b" and more", &mut events);
+        assert!(!matches!(events.last(), Some(HttpEvent::End)));
+        parser.finish(&mut events);
+        assert!(matches!(events.last(), Some(HttpEvent::End)));
+        assert_eq!(bodies(&events), b"some data and more");
+    }
+}
@@ -0,0 +1,823 @@
+//! HTTP/2 connection decoding
+//!
+//! `Http2ExtractHandler` decodes cleartext HTTP/2 connections starting with the
+//! client connection preface. Frames of each direction are parsed from the
+//! reassembled stream and header blocks are decompressed with an HPACK decoder
+//! per direction. Each HTTP/2 stream is written like an HTTP/1.x transaction:
+//! bodies to `{id}.h2.{stream id}.req` and `{id}.h2.{stream id}.resp`, and
+//! metadata to `http2.json`.
+//!
+//! A direction is no longer parsed after data was lost, as neither frame
+//! boundaries nor the HPACK dynamic table can be recovered.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use eyre::Context;
+use parking_lot::Mutex;
+use tracing::{debug, error, info, trace};
+use uuid::Uuid;
+
+use crate::connection::{Connection, Direction};
+use crate::hpack::{HpackDecoder, HpackError};
+use crate::serialized::Http2StreamInfo;
+use crate::stream::{SegmentInfo, StreamGap};
+use crate::ConnectionHandler;
+
+/// client connection preface
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// length of a frame header
+const FRAME_HEADER_LENGTH: usize = 9;
+/// max server data buffered while waiting for the client preface
+pub const MAX_PENDING_LENGTH: usize = 64 << 10; // 64 KB
+/// max size of a header block spanning CONTINUATION frames
+pub const MAX_HEADER_BLOCK_LENGTH: usize = 1 << 20; // 1 MB
+
+const FRAME_DATA: u8 = 0;
+const FRAME_HEADERS: u8 = 1;
+const FRAME_RST_STREAM: u8 = 3;
+const FRAME_PUSH_PROMISE: u8 = 5;
+const FRAME_GOAWAY: u8 = 7;
+const FRAME_CONTINUATION: u8 = 9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+/// error parsing frames
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Http2Error {
+    /// stream did not start with the client preface
+    NoPreface,
+    /// frame payload inconsistent with its type
+    MalformedFrame(u8),
+    /// header block interrupted by another frame
+    ExpectedContinuation,
+    HeaderBlockTooLarge,
+    Hpack(HpackError),
+}
+
+impl Display for Http2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Http2Error::NoPreface => write!(f, "missing connection preface"),
+            Http2Error::MalformedFrame(kind) => write!(f, "malformed frame of type {kind}"),
+            Http2Error::ExpectedContinuation => write!(f, "expected CONTINUATION frame"),
+            Http2Error::HeaderBlockTooLarge => write!(f, "header block too large"),
+            Http2Error::Hpack(e) => write!(f, "hpack: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Http2Error {}
+
+/// event emitted by Http2FrameParser
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Http2Event {
+    /// decoded header block
+    Headers {
+        stream_id: u32,
+        headers: Vec<(String, String)>,
+        end_stream: bool,
+    },
+    /// stream promised by the server, with its request headers
+    PushPromise {
+        stream_id: u32,
+        promised_id: u32,
+        headers: Vec<(String, String)>,
+    },
+    /// data with padding removed
+    Data {
+        stream_id: u32,
+        data: Vec<u8>,
+        end_stream: bool,
+    },
+    Reset {
+        stream_id: u32,
+        error_code: u32,
+    },
+    GoAway {
+        last_stream_id: u32,
+        error_code: u32,
+    },
+}
+
+/// header block continued in CONTINUATION frames
+struct PendingHeaders {
+    stream_id: u32,
+    promised_id: Option<u32>,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+/// incremental frame parser for one direction of a connection
+pub struct Http2FrameParser {
+    /// whether the client preface is expected before frames
+    expect_preface: bool,
+    /// unconsumed input
+    buf: Vec<u8>,
+    hpack: HpackDecoder,
+    pending: Option<PendingHeaders>,
+    failed: bool,
+}
+
+/// big-endian u32 with the reserved bit cleared
+fn stream_id(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]]) & 0x7fff_ffff
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+impl Http2FrameParser {
+    /// create parser, for the client side if `expect_preface` is set
+    pub fn new(expect_preface: bool) -> Self {
+        Http2FrameParser {
+            expect_preface,
+            buf: Vec::new(),
+            hpack: HpackDecoder::default(),
+            pending: None,
+            failed: false,
+        }
+    }
+
+    /// whether the parser can accept more data
+    pub fn is_active(&self) -> bool {
+        !self.failed
+    }
+
+    /// whether the client preface was seen
+    pub fn got_preface(&self) -> bool {
+        !self.failed && !self.expect_preface
+    }
+
+    /// stop parsing (e.g. on stream gaps)
+    pub fn fail(&mut self) {
+        self.failed = true;
+        self.buf = Vec::new();
+        self.pending = None;
+    }
+
+    /// feed stream data, appending parsed events to `events`
+    pub fn feed(&mut self, data: &[u8], events: &mut Vec<Http2Event>) {
+        if self.failed {
+            return;
+        }
+        self.buf.extend_from_slice(data);
+        let buf = std::mem::take(&mut self.buf);
+        match self.parse_frames(&buf, events) {
+            Ok(pos) => {
+                self.buf = buf;
+                self.buf.drain(..pos);
+            }
+            Err(e) => {
+                debug!("http2: {e}, no longer parsing");
+                self.fail();
+            }
+        }
+    }
+
+    /// parse complete frames in `buf`, returning length consumed
+    fn parse_frames(
+        &mut self,
+        buf: &[u8],
+        events: &mut Vec<Http2Event>,
+    ) -> Result<usize, Http2Error> {
+        let mut pos = 0;
+        if self.expect_preface {
+            let len = usize::min(buf.len(), PREFACE.len());
+            if buf[..len] != PREFACE[..len] {
+                return Err(Http2Error::NoPreface);
+            }
+            if len < PREFACE.len() {
+                return Ok(0);
+            }
+            pos = len;
+            self.expect_preface = false;
+        }
+        while let Some(header) = buf.get(pos..pos + FRAME_HEADER_LENGTH) {
+            let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+            let payload_start = pos + FRAME_HEADER_LENGTH;
+            let Some(payload) = buf.get(payload_start..payload_start + len) else {
+                break;
+            };
+            self.frame(
+                header[3],
+                header[4],
+                stream_id(&header[5..]),
+                payload,
+                events,
+            )?;
+            pos = payload_start + len;
+        }
+        Ok(pos)
+    }
+
+    /// handle a complete frame
+    fn frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+        events: &mut Vec<Http2Event>,
+    ) -> Result<(), Http2Error> {
+        trace!("http2: frame type {kind} flags {flags:#x} stream {stream_id}");
+        if let Some(pending) = &mut self.pending {
+            if kind != FRAME_CONTINUATION || stream_id != pending.stream_id {
+                return Err(Http2Error::ExpectedContinuation);
+            }
+            pending.block.extend_from_slice(payload);
+            if pending.block.len() > MAX_HEADER_BLOCK_LENGTH {
+                return Err(Http2Error::HeaderBlockTooLarge);
+            }
+            if flags & FLAG_END_HEADERS != 0 {
+                self.finish_headers(events)?;
+            }
+            return Ok(());
+        }
+        let malformed = Http2Error::MalformedFrame(kind);
+        match kind {
+            FRAME_DATA => {
+                let data = strip_padding(flags, payload).ok_or(malformed)?;
+                events.push(Http2Event::Data {
+                    stream_id,
+                    data: data.to_vec(),
+                    end_stream: flags & FLAG_END_STREAM != 0,
+                });
+            }
+            FRAME_HEADERS | FRAME_PUSH_PROMISE => {
+                let mut fragment = strip_padding(flags, payload).ok_or(malformed.clone())?;
+                let mut promised_id = None;
+                if kind == FRAME_PUSH_PROMISE {
+                    let id = fragment.get(..4).ok_or(malformed)?;
+                    promised_id = Some(self::stream_id(id));
+                    fragment = &fragment[4..];
+                } else if flags & FLAG_PRIORITY != 0 {
+                    // stream dependency and weight
+                    fragment = fragment.get(5..).ok_or(malformed)?;
+                }
+                self.pending = Some(PendingHeaders {
+                    stream_id,
+                    promised_id,
+                    end_stream: kind == FRAME_HEADERS && flags & FLAG_END_STREAM != 0,
+                    block: fragment.to_vec(),
+                });
+                if flags & FLAG_END_HEADERS != 0 {
+                    self.finish_headers(events)?;
+                }
+            }
+            FRAME_RST_STREAM => {
+                if payload.len() != 4 {
+                    return Err(malformed);
+                }
+                events.push(Http2Event::Reset {
+                    stream_id,
+                    error_code: read_u32(payload),
+                });
+            }
+            FRAME_GOAWAY => {
+                if payload.len() < 8 {
+                    return Err(malformed);
+                }
+                events.push(Http2Event::GoAway {
+                    last_stream_id: self::stream_id(payload),
+                    error_code: read_u32(&payload[4..]),
+                });
+            }
+            FRAME_CONTINUATION => return Err(malformed),
+            // SETTINGS, PING, PRIORITY, WINDOW_UPDATE and extensions
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// decode completed header block
+    fn finish_headers(&mut self, events: &mut Vec<Http2Event>) -> Result<(), Http2Error> {
+        let pending = self.pending.take().expect("no header block pending");
+        let headers = self
+            .hpack
+            .decode(&pending.block)
+            .map_err(Http2Error::Hpack)?;
+        events.push(match pending.promised_id {
+            Some(promised_id) => Http2Event::PushPromise {
+                stream_id: pending.stream_id,
+                promised_id,
+                headers,
+            },
+            None => Http2Event::Headers {
+                stream_id: pending.stream_id,
+                headers,
+                end_stream: pending.end_stream,
+            },
+        });
+        Ok(())
+    }
+}
+
+/// remove padding from payload of a frame with the PADDED flag
+fn strip_padding(flags: u8, payload: &[u8]) -> Option<&[u8]> {
+    if flags & FLAG_PADDED == 0 {
+        return Some(payload);
+    }
+    let (&pad_length, rest) = payload.split_first()?;
+    rest.len()
+        .checked_sub(pad_length as usize)
+        .map(|len| &rest[..len])
+}
+
+/// shared state for Http2ExtractHandler
+pub struct Http2ExtractSharedInfoInner {
+    pub base_dir: PathBuf,
+    pub index_file: Mutex<File>,
+}
+
+#[derive(Clone)]
+pub struct Http2ExtractSharedInfo {
+    pub inner: Arc<Http2ExtractSharedInfoInner>,
+}
+
+impl Http2ExtractSharedInfo {
+    /// create with output path
+    pub fn new(base_dir: PathBuf) -> std::io::Result<Self> {
+        let mut index_file = File::create(base_dir.join("http2.json"))?;
+        index_file.write_all(b"[\n")?;
+        Ok(Http2ExtractSharedInfo {
+            inner: Arc::new(Http2ExtractSharedInfoInner {
+                base_dir,
+                index_file: Mutex::new(index_file),
+            }),
+        })
+    }
+
+    /// write stream to index
+    pub fn record_stream(&self, info: &Http2StreamInfo) -> std::io::Result<()> {
+        let mut serialized =
+            serde_json::to_string(info).expect("failed to serialize Http2StreamInfo");
+        serialized += ",\n";
+        let mut file = self.inner.index_file.lock();
+        file.write_all(serialized.as_bytes())
+    }
+
+    /// close index file
+    pub fn close(self) -> std::io::Result<()> {
+        let mut index_file = Arc::into_inner(self.inner).unwrap().index_file.into_inner();
+        let current_pos = index_file.stream_position()?;
+        if current_pos > 2 {
+            // overwrite trailing comma and close array
+            index_file.seek(SeekFrom::Current(-2))?;
+            index_file.write_all(b"\n]\n")?;
+        } else {
+            index_file.write_all(b"]\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// HTTP/2 stream being extracted
+pub struct Http2Stream {
+    pub info: Http2StreamInfo,
+    pub request_body: Option<BufWriter<File>>,
+    pub response_body: Option<BufWriter<File>>,
+    pub request_ended: bool,
+    pub response_ended: bool,
+}
+
+impl Http2Stream {
+    pub fn new(id: Uuid, stream_id: u32) -> Self {
+        Http2Stream {
+            info: Http2StreamInfo::new(id, stream_id),
+            request_body: None,
+            response_body: None,
+            request_ended: false,
+            response_ended: false,
+        }
+    }
+
+    /// append body data, creating the body file if necessary
+    pub fn write_body(
+        &mut self,
+        base_dir: &Path,
+        is_request: bool,
+        data: &[u8],
+    ) -> eyre::Result<()> {
+        let (file, name, len) = if is_request {
+            (
+                &mut self.request_body,
+                &mut self.info.request_body_file,
+                &mut self.info.request_body_len,
+            )
+        } else {
+            (
+                &mut self.response_body,
+                &mut self.info.response_body_file,
+                &mut self.info.response_body_len,
+            )
+        };
+        let file = match file {
+            Some(file) => file,
+            None => {
+                let file_name = format!(
+                    "{}.h2.{}.{}",
+                    self.info.id,
+                    self.info.stream_id,
+                    if is_request { "req" } else { "resp" }
+                );
+                let created =
+                    File::create(base_dir.join(&file_name)).wrap_err("creating body file")?;
+                *name = Some(file_name);
+                file.insert(BufWriter::new(created))
+            }
+        };
+        file.write_all(data).wrap_err("writing body file")?;
+        *len += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// ConnectionHandler to decode HTTP/2 connections to a directory
+///
+/// Forward direction is assumed to be sent by the client. Connections not
+/// starting with the client preface are discarded.
+pub struct Http2ExtractHandler {
+    pub shared_info: Http2ExtractSharedInfo,
+    pub id: Uuid,
+    pub client_parser: Http2FrameParser,
+    pub server_parser: Http2FrameParser,
+    /// whether the connection is HTTP/2, decided once client data is seen
+    pub is_http2: Option<bool>,
+    /// server data received before the client preface
+    pub pending_reverse: Vec<u8>,
+    /// open streams by id
+    pub streams: BTreeMap<u32, Http2Stream>,
+
+    events: Vec<Http2Event>,
+    gaps: Vec<StreamGap>,
+    segments: Vec<SegmentInfo>,
+    buf: Vec<u8>,
+}
+
+impl Http2ExtractHandler {
+    /// read data from stream and feed to parser for direction
+    pub fn read_stream(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        len: usize,
+    ) -> eyre::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        self.gaps.clear();
+        self.segments.clear();
+        self.buf.clear();
+        let stream = connection.get_stream(direction);
+        let end_offset = stream.buffer_start() + len as u64;
+        let buf = &mut self.buf;
+        let read = stream.read_next(end_offset, &mut self.segments, &mut self.gaps, |slice| {
+            let (a, b) = slice.as_slices();
+            buf.extend_from_slice(a);
+            if let Some(b) = b {
+                buf.extend_from_slice(b);
+            }
+        });
+        if read.is_none() {
+            error!("http2: {direction} stream cannot fulfill range, skipping");
+            return Ok(());
+        }
+
+        if !self.gaps.is_empty() {
+            let parser = match direction {
+                Direction::Forward => &mut self.client_parser,
+                Direction::Reverse => &mut self.server_parser,
+            };
+            if parser.is_active() && self.is_http2 != Some(false) {
+                debug!("http2: {direction} stream has gaps, no longer parsing");
+            }
+            parser.fail();
+        }
+        match direction {
+            Direction::Forward => {
+                self.client_parser.feed(&self.buf, &mut self.events);
+                self.handle_events(Direction::Forward)?;
+                if self.is_http2.is_none() {
+                    if self.client_parser.got_preface() {
+                        self.is_http2 = Some(true);
+                        let pending = std::mem::take(&mut self.pending_reverse);
+                        self.server_parser.feed(&pending, &mut self.events);
+                        self.handle_events(Direction::Reverse)?;
+                    } else if !self.client_parser.is_active() {
+                        trace!("http2: {} is not http2", self.id);
+                        self.is_http2 = Some(false);
+                        self.pending_reverse = Vec::new();
+                    }
+                }
+            }
+            Direction::Reverse => match self.is_http2 {
+                Some(true) => {
+                    self.server_parser.feed(&self.buf, &mut self.events);
+                    self.handle_events(Direction::Reverse)?;
+                }
+                Some(false) => {}
+                None => {
+                    if self.pending_reverse.len() + self.buf.len() > MAX_PENDING_LENGTH {
+                        debug!("http2: too much server data before client preface");
+                        self.server_parser.fail();
+                    } else if self.gaps.is_empty() {
+                        self.pending_reverse.extend_from_slice(&self.buf);
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// process events from parser
+    fn handle_events(&mut self, direction: Direction) -> eyre::Result<()> {
+        let mut events = std::mem::take(&mut self.events);
+        for event in events.drain(..) {
+            let stream_id = match &event {
+                Http2Event::GoAway {
+                    last_stream_id,
+                    error_code,
+                } => {
+                    debug!("http2: {direction} GOAWAY, last stream {last_stream_id}, error {error_code}");
+                    continue;
+                }
+                Http2Event::Headers { stream_id, .. }
+                | Http2Event::PushPromise { stream_id, .. }
+                | Http2Event::Data { stream_id, .. }
+                | Http2Event::Reset { stream_id, .. } => *stream_id,
+            };
+            if stream_id == 0 {
+                continue;
+            }
+            let id = self.id;
+            let stream = self
+                .streams
+                .entry(stream_id)
+                .or_insert_with(|| Http2Stream::new(id, stream_id));
+            let is_request = direction == Direction::Forward;
+            match event {
+                Http2Event::Headers {
+                    headers,
+                    end_stream,
+                    ..
+                } => {
+                    if is_request {
+                        if stream.info.request_headers.is_empty() {
+                            stream.info.set_request(headers);
+                        } else {
+                            stream.info.request_trailers = headers;
+                        }
+                        stream.request_ended |= end_stream;
+                    } else {
+                        if stream.info.response_headers.is_empty() {
+                            stream.info.set_response(headers);
+                            if stream.info.status.is_some_and(|s| (100..200).contains(&s)) {
+                                trace!("http2: ignoring interim response on {stream_id}");
+                                stream.info.status = None;
+                                stream.info.response_headers = Vec::new();
+                            }
+                        } else {
+                            stream.info.response_trailers = headers;
+                        }
+                        stream.response_ended |= end_stream;
+                    }
+                }
+                Http2Event::PushPromise {
+                    promised_id,
+                    headers,
+                    ..
+                } => {
+                    let mut promised = Http2Stream::new(id, promised_id);
+                    promised.info.pushed = true;
+                    promised.info.set_request(headers);
+                    promised.request_ended = true;
+                    self.streams.insert(promised_id, promised);
+                    continue;
+                }
+                Http2Event::Data {
+                    data, end_stream, ..
+                } => {
+                    let base_dir = &self.shared_info.inner.base_dir;
+                    stream.write_body(base_dir, is_request, &data)?;
+                    if is_request {
+                        stream.request_ended |= end_stream;
+                    } else {
+                        stream.response_ended |= end_stream;
+                    }
+                }
+                Http2Event::Reset { error_code, .. } => {
+                    stream.info.reset_error = Some(error_code);
+                    let stream = self.streams.remove(&stream_id).expect("stream exists");
+                    self.finish_stream(stream)?;
+                    continue;
+                }
+                Http2Event::GoAway { .. } => unreachable!("handled above"),
+            }
+            if stream.request_ended && stream.response_ended {
+                let mut stream = self.streams.remove(&stream_id).expect("stream exists");
+                stream.info.complete = true;
+                self.finish_stream(stream)?;
+            }
+        }
+        self.events = events;
+        Ok(())
+    }
+
+    /// flush body files and record stream in index
+    fn finish_stream(&mut self, mut stream: Http2Stream) -> eyre::Result<()> {
+        if let Some(body) = &mut stream.request_body {
+            body.flush().wrap_err("flushing request body")?;
+        }
+        if let Some(body) = &mut stream.response_body {
+            body.flush().wrap_err("flushing response body")?;
+        }
+        self.shared_info
+            .record_stream(&stream.info)
+            .wrap_err("writing http2 index")
+    }
+
+    /// read all remaining data and record unfinished streams
+    pub fn write_remaining(&mut self, connection: &mut Connection<Self>) -> eyre::Result<()> {
+        for direction in [Direction::Forward, Direction::Reverse] {
+            let len = connection.get_stream(direction).total_buffered_length();
+            self.read_stream(connection, direction, len)?;
+        }
+        for (_, stream) in std::mem::take(&mut self.streams) {
+            self.finish_stream(stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl ConnectionHandler for Http2ExtractHandler {
+    type InitialData = Http2ExtractSharedInfo;
+    type ConstructError = eyre::Report;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> eyre::Result<Self> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(Http2ExtractHandler {
+            shared_info,
+            id: connection.uuid,
+            client_parser: Http2FrameParser::new(true),
+            server_parser: Http2FrameParser::new(false),
+            is_http2: None,
+            pending_reverse: Vec::new(),
+            streams: BTreeMap::new(),
+            events: Vec::new(),
+            gaps: Vec::new(),
+            segments: Vec::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        let result = if readable_len > 0 {
+            self.read_stream(connection, direction, readable_len)
+        } else if stream.total_buffered_length() > MAX_PENDING_LENGTH {
+            // stuck behind a gap, skip ahead
+            let len = stream.total_buffered_length();
+            self.read_stream(connection, direction, len)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            tracing::error!("failed to extract http2 data: {e:?}");
+        }
+    }
+
+    fn gap_detected(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        direction: Direction,
+        range: Range<u64>,
+    ) {
+        let parser = match direction {
+            Direction::Forward => &mut self.client_parser,
+            Direction::Reverse => &mut self.server_parser,
+        };
+        if parser.is_active() && self.is_http2 == Some(true) {
+            debug!(
+                "http2: {direction} stream lost {} bytes at offset {}, no longer parsing",
+                range.end - range.start,
+                range.start
+            );
+        }
+        parser.fail();
+    }
+
+    fn direction_changed(&mut self, _connection: &mut Connection<Self>) {
+        if self.streams.is_empty() && self.is_http2 != Some(true) {
+            // data so far went to the wrong parsers
+            self.client_parser = Http2FrameParser::new(true);
+            self.server_parser = Http2FrameParser::new(false);
+            self.is_http2 = None;
+            self.pending_reverse.clear();
+        } else {
+            debug!("http2: direction changed after frames were parsed");
+        }
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        info!(
+            "removing connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        if let Err(e) = self.write_remaining(connection) {
+            tracing::error!("failed to write remaining http2 data: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Http2Error, Http2Event, Http2FrameParser, PREFACE};
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        out.push(kind);
+        out.push(flags);
+        out.extend_from_slice(&stream_id.to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    #[test]
+    fn client_frames() {
+        let mut input = PREFACE.to_vec();
+        // SETTINGS
+        input.extend(frame(4, 0, 0, &[0, 3, 0, 0, 0, 100]));
+        // GET http://a/ split across HEADERS (with priority) and CONTINUATION
+        input.extend(frame(1, 0x20, 1, &[0, 0, 0, 0, 16, 0x82, 0x86]));
+        input.extend(frame(9, 0x4, 1, &[0x84, 0x41, 0x01, b'a']));
+        // POST with padded DATA
+        input.extend(frame(1, 0x4, 3, &[0x83, 0x86, 0x84, 0xbe]));
+        input.extend(frame(0, 0x9, 3, &[2, b'h', b'i', 0, 0]));
+        input.extend(frame(3, 0, 1, &8u32.to_be_bytes()));
+
+        let mut parser = Http2FrameParser::new(true);
+        let mut events = Vec::new();
+        for chunk in input.chunks(7) {
+            parser.feed(chunk, &mut events);
+        }
+        assert!(parser.got_preface());
+        let request = |method: &str| {
+            [
+                (":method", method),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "a"),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .to_vec()
+        };
+        assert_eq!(
+            events,
+            [
+                Http2Event::Headers {
+                    stream_id: 1,
+                    headers: request("GET"),
+                    end_stream: false,
+                },
+                Http2Event::Headers {
+                    stream_id: 3,
+                    headers: request("POST"),
+                    end_stream: false,
+                },
+                Http2Event::Data {
+                    stream_id: 3,
+                    data: b"hi".to_vec(),
+                    end_stream: true,
+                },
+                Http2Event::Reset {
+                    stream_id: 1,
+                    error_code: 8,
+                },
+            ]
+        );
+
+        let mut parser = Http2FrameParser::new(true);
+        parser.feed(b"GET / HTTP/1.1\r\n", &mut events);
+        assert!(!parser.is_active());
+        assert_eq!(
+            Http2Error::NoPreface.to_string(),
+            "missing connection preface"
+        );
+    }
+}
@@ -0,0 +1,203 @@
+//! Interleaved stream output
+//!
+//! Writes data of both directions of a connection to a single file in
+//! conversation order, using the same `reverse_acked` ordering as follow-stream
+//! output. Each record is a JSON header line followed by `len` bytes of data
+//! and a newline. Missing (gap) data is omitted.
+
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
+
+use crate::connection::Direction;
+use crate::follow::{goes_first, FollowChunk, FollowStream};
+
+/// buffered bytes after which records are written even if the other
+/// direction has not caught up
+pub const MAX_PENDING_BYTES: usize = 4 << 20;
+
+/// header line of a record
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordHeader {
+    pub direction: Direction,
+    /// stream offset of data
+    pub offset: u64,
+    pub len: usize,
+    /// capture time of segment (microseconds since epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_us: Option<u64>,
+}
+
+/// data of both directions waiting to be placed in conversation order
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Interleaver {
+    pub forward: FollowStream,
+    pub reverse: FollowStream,
+}
+
+impl Interleaver {
+    /// get collected data of direction
+    pub fn stream_mut(&mut self, direction: Direction) -> &mut FollowStream {
+        match direction {
+            Direction::Forward => &mut self.forward,
+            Direction::Reverse => &mut self.reverse,
+        }
+    }
+
+    /// bytes held for either direction
+    pub fn pending_bytes(&self) -> usize {
+        self.forward.data.len() + self.reverse.data.len()
+    }
+
+    /// append records whose position is known to `out`
+    ///
+    /// A chunk is written once the next chunk of the other direction is known
+    /// to follow it. If `finish` is set or `MAX_PENDING_BYTES` is exceeded,
+    /// everything collected so far is written.
+    pub fn write_ready(&mut self, out: &mut Vec<u8>, finish: bool) {
+        let force = finish || self.pending_bytes() > MAX_PENDING_BYTES;
+        let (mut f, mut r) = (0, 0);
+        loop {
+            let take_forward = match (
+                next_chunk(&self.forward, f, force),
+                next_chunk(&self.reverse, r, force),
+            ) {
+                (Some(a), Some(b)) => goes_first(a, b),
+                (Some(_), None) if force => true,
+                (None, Some(_)) if force => false,
+                _ => break,
+            };
+            if take_forward {
+                write_record(
+                    out,
+                    Direction::Forward,
+                    &self.forward,
+                    &self.forward.chunks[f],
+                );
+                f += 1;
+            } else {
+                write_record(
+                    out,
+                    Direction::Reverse,
+                    &self.reverse,
+                    &self.reverse.chunks[r],
+                );
+                r += 1;
+            }
+        }
+        self.forward.consume(f);
+        self.reverse.consume(r);
+    }
+}
+
+/// chunk at index `i` if it can be written
+fn next_chunk(stream: &FollowStream, i: usize, force: bool) -> Option<&FollowChunk> {
+    stream
+        .chunks
+        .get(i)
+        .filter(|chunk| force || stream.has_data(chunk))
+}
+
+/// append record for chunk, skipping chunks without data
+fn write_record(
+    out: &mut Vec<u8>,
+    direction: Direction,
+    stream: &FollowStream,
+    chunk: &FollowChunk,
+) {
+    let data = stream.chunk_data(chunk);
+    if data.is_empty() {
+        return;
+    }
+    let header = RecordHeader {
+        direction,
+        offset: chunk.range.start,
+        len: data.len(),
+        timestamp_us: chunk.timestamp.map(|t| t.as_micros() as u64),
+    };
+    serde_json::to_writer(&mut *out, &header).expect("failed to serialize record header");
+    out.push(b'\n');
+    out.extend_from_slice(data);
+    out.push(b'\n');
+}
+
+/// parse records of an interleaved output file
+pub fn parse_records(mut buf: &[u8]) -> serde_json::Result<Vec<(RecordHeader, &[u8])>> {
+    let mut records = Vec::new();
+    while !buf.is_empty() {
+        let line_end = buf
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| serde_json::Error::custom("truncated record header"))?;
+        let header: RecordHeader = serde_json::from_slice(&buf[..line_end])?;
+        let data_start = line_end + 1;
+        let data_end = data_start + header.len;
+        if buf.len() <= data_end {
+            return Err(serde_json::Error::custom("truncated record data"));
+        }
+        records.push((header, &buf[data_start..data_end]));
+        buf = &buf[data_end + 1..];
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_records, Interleaver};
+    use crate::connection::Direction;
+    use crate::serialized::PacketExtra;
+    use crate::stream::{SegmentInfo, SegmentType};
+
+    fn segment(offset: u64, len: usize, reverse_acked: u64) -> SegmentInfo {
+        SegmentInfo {
+            offset,
+            reverse_acked,
+            extra: PacketExtra::None,
+            tcp: Default::default(),
+            data: SegmentType::Data {
+                len,
+                is_retransmit: false,
+            },
+        }
+    }
+
+    #[test]
+    fn incremental() {
+        let mut interleaver = Interleaver::default();
+        let mut out = Vec::new();
+
+        // request arrives in two parts, nothing known about the reply yet
+        let forward = interleaver.stream_mut(Direction::Forward);
+        forward.add_data(0, b"GET");
+        forward.add_segment(&segment(0, 4, 0));
+        interleaver.write_ready(&mut out, false);
+        assert!(out.is_empty());
+        interleaver.forward.add_data(3, b"\nBYE\n");
+        interleaver.forward.add_segment(&segment(4, 4, 3));
+
+        // reply sent after the first request
+        let reverse = interleaver.stream_mut(Direction::Reverse);
+        reverse.add_data(0, b"OK\n");
+        reverse.add_segment(&segment(0, 3, 4));
+        interleaver.write_ready(&mut out, false);
+        // second request waits for what follows the reply
+        assert_eq!(interleaver.forward.chunks.len(), 1);
+        assert_eq!(interleaver.forward.data, b"BYE\n");
+        assert!(interleaver.reverse.data.is_empty());
+
+        interleaver.write_ready(&mut out, true);
+        let records = parse_records(&out).unwrap();
+        let summary: Vec<_> = records
+            .iter()
+            .map(|(header, data)| (header.direction, header.offset, *data))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Direction::Forward, 0, &b"GET\n"[..]),
+                (Direction::Reverse, 0, &b"OK\n"[..]),
+                (Direction::Forward, 4, &b"BYE\n"[..]),
+            ]
+        );
+        assert!(parse_records(&out[..out.len() - 1]).is_err());
+    }
+}
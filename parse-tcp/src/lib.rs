@@ -1,19 +1,74 @@
 use std::fmt::Debug;
 use std::net::IpAddr;
+use std::ops::Range;
 
 use connection::{Connection, Direction};
+use handshake::HandshakeEvent;
+use kinesin_rdt::stream::inbound::OverlapConflict;
+use log_format::LogFormat;
+use matching::StreamMatch;
+use resync::ResyncOutcome;
+use serde::{Deserialize, Serialize};
 use serialized::PacketExtra;
+use stream::LateData;
+use udp::UdpFlow;
 
+#[cfg(feature = "async-handler")]
+pub mod async_handler;
+pub mod checkpoint;
+pub mod classify;
+#[cfg(feature = "parquet")]
+pub mod columnar;
+pub mod config;
 pub mod connection;
+pub mod dedup;
+pub mod direction;
+pub mod dispatch;
+pub mod dns;
+pub mod duplicate;
 pub mod emit;
+pub mod events;
+pub mod extract;
+pub mod filter;
 pub mod flow_table;
+pub mod follow;
+pub mod fragment;
 pub mod handler;
+pub mod handshake;
+pub mod hpack;
+pub mod http;
+pub mod http2;
+pub mod interleave;
+pub mod log_format;
+pub mod log_throttle;
+pub mod matching;
+pub mod memory;
+pub mod metrics;
+pub mod output_dir;
 pub mod parser;
+pub mod pcap_recovery;
+pub mod pcap_writer;
+pub mod progress;
+pub mod report;
+pub mod resync;
+pub mod rtt;
+pub mod segment_file;
 pub mod serialized;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
 pub mod stream;
+pub mod synth;
+pub mod tls;
+#[cfg(feature = "tls-decrypt")]
+pub mod tls_decrypt;
+pub mod ttl;
+pub mod udp;
+pub mod virtual_conn;
+pub mod writer;
 
 /// TCP packet metadata
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TcpMeta {
     /// source address
     pub src_addr: IpAddr,
@@ -31,16 +86,57 @@ pub struct TcpMeta {
     pub flags: TcpFlags,
     /// raw window value
     pub window: u16,
+    /// urgent pointer, meaningful only with URG flag
+    pub urgent_pointer: u16,
+    /// TCP checksum field
+    pub checksum: u16,
 
     // options
+    /// maximum segment size option
+    pub option_mss: Option<u16>,
     /// window scale option
     pub option_window_scale: Option<u8>,
     /// timestamp option (value, echo)
     pub option_timestamp: Option<(u32, u32)>,
+    /// TCP Fast Open cookie option, empty for a cookie request
+    pub option_tfo_cookie: Option<Vec<u8>>,
+
+    // encapsulation
+    /// outermost VLAN id, if any
+    pub vlan_id: Option<u16>,
+    /// ECN field of IP header
+    pub ip_ecn: u8,
+    /// identification field of IPv4 header, 0 for IPv6
+    pub ip_id: u16,
+    /// TTL of IPv4 header or hop limit of IPv6 header
+    pub ip_ttl: u8,
+    /// whether IP or TCP checksum verification failed
+    pub bad_checksum: bool,
+}
+
+/// UDP datagram metadata
+#[derive(Clone, Debug)]
+pub struct UdpMeta {
+    /// source address
+    pub src_addr: IpAddr,
+    /// source port
+    pub src_port: u16,
+    /// destination address
+    pub dst_addr: IpAddr,
+    /// destination port
+    pub dst_port: u16,
+
+    // encapsulation
+    /// outermost VLAN id, if any
+    pub vlan_id: Option<u16>,
+    /// TTL of IPv4 header or hop limit of IPv6 header
+    pub ip_ttl: u8,
+    /// whether IP or UDP checksum verification failed
+    pub bad_checksum: bool,
 }
 
 /// TCP packet flags (at least, the ones we care about)
-#[derive(Clone, Default)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct TcpFlags {
     /// SYN flag
     pub syn: bool,
@@ -50,6 +146,14 @@ pub struct TcpFlags {
     pub fin: bool,
     /// RST flag
     pub rst: bool,
+    /// PSH flag
+    pub psh: bool,
+    /// URG flag
+    pub urg: bool,
+    /// ECE flag (ECN-Echo)
+    pub ece: bool,
+    /// CWR flag (congestion window reduced)
+    pub cwr: bool,
 }
 
 impl Debug for TcpFlags {
@@ -78,6 +182,18 @@ impl Debug for TcpFlags {
         if self.rst {
             write_flag!("RST");
         }
+        if self.psh {
+            write_flag!("PSH");
+        }
+        if self.urg {
+            write_flag!("URG");
+        }
+        if self.ece {
+            write_flag!("ECE");
+        }
+        if self.cwr {
+            write_flag!("CWR");
+        }
         // silence warning
         let _ = has_prev;
         write!(f, "]")?;
@@ -99,8 +215,25 @@ where
         init_data: Self::InitialData,
         connection: &mut Connection<Self>,
     ) -> Result<Self, Self::ConstructError>;
+    /// called for every packet belonging to the connection, after it has been
+    /// processed
+    fn packet_received(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        _extra: &PacketExtra,
+    ) {
+    }
     /// called on handshake finish (or incomplete handshake)
     fn handshake_done(&mut self, _connection: &mut Connection<Self>) {}
+    /// notable handshake occurrence, `direction` is of the packet causing it
+    fn handshake_event(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        _event: HandshakeEvent,
+    ) {
+    }
     /// called on data received
     fn data_received(&mut self, _connection: &mut Connection<Self>, _direction: Direction) {}
     /// called when data is acked, direction is of the ack packet, not the stream
@@ -120,18 +253,132 @@ where
     /// connection fatally desynchronized, `direction` is our best guess for the
     /// direction of the packet which caused the desync
     fn connection_desync(&mut self, _connection: &mut Connection<Self>, _direction: Direction) {}
+    /// connection was kept or rebased instead of recreated after a SYN in
+    /// `direction`, see `crate::resync`. Held packets are handled after this
+    fn connection_resync(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        _outcome: ResyncOutcome,
+    ) {
+    }
+    /// data in `range` was not received before enough later data arrived and
+    /// is probably lost, called before data_received
+    fn gap_detected(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        _range: Range<u64>,
+    ) {
+    }
+    /// retransmitted data differed from data received earlier, only called
+    /// if the overlap policy is not `FirstWins`
+    fn overlap_conflict(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        _conflict: OverlapConflict,
+    ) {
+    }
+    /// data arrived for part of a gap which was already read past, see
+    /// `ReassemblyConfig::late_data_packets`
+    fn late_data(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        _late: &LateData,
+    ) {
+    }
+    /// data matching a configured pattern became contiguous, called before
+    /// data_received
+    fn stream_match(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        _found: StreamMatch,
+    ) {
+    }
+    /// segment was dropped as its TSval fell far behind the highest TSval
+    /// seen in its direction, see `ReassemblyConfig::paws_max_regression`
+    fn paws_rejected(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        _tsval: u32,
+        _ts_recent: u32,
+    ) {
+    }
+    /// TTL of a packet differed from the most common TTL in its direction by
+    /// more than `ReassemblyConfig::ttl_anomaly_threshold`, possibly as it
+    /// was injected by a middlebox
+    fn ttl_anomaly(
+        &mut self,
+        _connection: &mut Connection<Self>,
+        _direction: Direction,
+        _expected: u8,
+        _observed: u8,
+    ) {
+    }
+    /// the memory budget is exceeded and this stream is among the largest,
+    /// buffered data should be written out and consumed
+    fn memory_pressure(&mut self, _connection: &mut Connection<Self>, _direction: Direction) {}
+    /// forward and reverse were swapped as the client was misidentified, flow
+    /// and streams of the connection are already reversed
+    fn direction_changed(&mut self, _connection: &mut Connection<Self>) {}
     /// called when the connection is removed from the hashtable
     fn will_retire(&mut self, _connection: &mut Connection<Self>) {}
+    /// state to persist in a checkpoint of the connection, passed to
+    /// `restored` when the checkpoint is restored
+    fn checkpoint_state(&self) -> Option<serde_json::Value> {
+        None
+    }
+    /// called instead of `handshake_done` when a connection past its
+    /// handshake is restored from a checkpoint
+    fn restored(&mut self, connection: &mut Connection<Self>, _state: Option<serde_json::Value>) {
+        self.handshake_done(connection);
+    }
 }
 
-pub fn setup_log_handlers() {
+/// event handler for UDP flow object
+pub trait UdpFlowHandler
+where
+    Self: Sized,
+{
+    /// initial data provided to new
+    type InitialData;
+    /// error type raised from new
+    type ConstructError;
+    /// construct handler object
+    fn new(
+        init_data: Self::InitialData,
+        flow: &mut UdpFlow<Self>,
+    ) -> Result<Self, Self::ConstructError>;
+    /// called for every datagram belonging to the flow
+    fn datagram_received(
+        &mut self,
+        _flow: &mut UdpFlow<Self>,
+        _direction: Direction,
+        _data: &[u8],
+        _extra: &PacketExtra,
+    ) {
+    }
+    /// called when the flow is removed from the hashtable, either from
+    /// inactivity or on close
+    fn will_retire(&mut self, _flow: &mut UdpFlow<Self>) {}
+}
+
+pub fn setup_log_handlers(format: LogFormat) {
     use tracing_error::ErrorLayer;
     use tracing_subscriber::prelude::*;
     use tracing_subscriber::{fmt, EnvFilter};
 
     color_eyre::install().unwrap();
 
-    let fmt_layer = fmt::layer();
+    // keep stdout free for output
+    let (fmt_layer, json_layer) = match format {
+        LogFormat::Text => (Some(fmt::layer().with_writer(std::io::stderr)), None),
+        LogFormat::Json => (None, Some(fmt::layer().json().with_writer(std::io::stderr))),
+    };
     let filter_layer = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("info"))
         .unwrap();
@@ -139,13 +386,18 @@ pub fn setup_log_handlers() {
     tracing_subscriber::registry()
         .with(filter_layer)
         .with(fmt_layer)
+        .with(json_layer)
         .with(ErrorLayer::default())
         .init();
 }
 
 pub fn initialize_logging() {
+    initialize_logging_with(LogFormat::Text);
+}
+
+pub fn initialize_logging_with(format: LogFormat) {
     use parking_lot::Once;
 
     static INITIALIZE: Once = Once::new();
-    INITIALIZE.call_once(setup_log_handlers);
+    INITIALIZE.call_once(|| setup_log_handlers(format));
 }
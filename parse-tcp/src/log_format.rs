@@ -0,0 +1,78 @@
+//! Log output formats
+//!
+//! Besides the default human readable output, events can be written as one
+//! JSON object per line for machine ingestion, using the JSON formatter of
+//! `tracing_subscriber`. Fields of all spans an event occurred in are
+//! included with the event. The capture file span is always recorded, while
+//! per-packet spans (packet index, flow and connection id) are only recorded
+//! at debug level to keep them off the hot path.
+
+/// format of log output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// human readable lines
+    #[default]
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+    use serde_json::{json, Value};
+    use tracing::{info_span, warn};
+    use tracing_subscriber::fmt;
+    use tracing_subscriber::prelude::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(fmt::layer().json().with_writer(move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _capture = info_span!("capture", file = "a.pcap").entered();
+            let packet = info_span!("packet", index = 3u64, flow = tracing::field::Empty);
+            packet.record("flow", "tcp/10.0.0.1:1234 -> 10.0.0.2:80");
+            let _packet = packet.entered();
+            warn!(seq = 5, "out of window");
+        });
+
+        let output = buffer.0.lock();
+        let line: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(
+            line["fields"],
+            json!({ "message": "out of window", "seq": 5 })
+        );
+        assert_eq!(
+            line["spans"],
+            json!([
+                { "name": "capture", "file": "a.pcap" },
+                {
+                    "name": "packet",
+                    "index": 3,
+                    "flow": "tcp/10.0.0.1:1234 -> 10.0.0.2:80",
+                },
+            ])
+        );
+    }
+}
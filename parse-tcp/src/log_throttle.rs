@@ -0,0 +1,169 @@
+//! Throttling of repeated warnings
+//!
+//! Malformed captures can trigger the same warning for nearly every packet.
+//! Each category of warning has a token bucket: a burst of warnings is
+//! logged, after which one is let through per refill interval. The number of
+//! warnings suppressed in between is logged along with the next one let
+//! through, and remaining counts are logged by `flush`.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tracing::warn;
+
+/// warnings of a category logged before throttling
+pub const THROTTLE_BURST: u32 = 10;
+/// time for a throttled category to regain one warning
+pub const THROTTLE_REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// category of throttled warnings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WarnCategory {
+    /// sequence or acknowledgment number outside of window
+    OutOfWindow,
+    /// packets exceeding buffer limits
+    BufferLimit,
+    /// resets which are likely invalid
+    InvalidReset,
+    /// unexpected handshake packets or options
+    Handshake,
+    /// other packets inconsistent with connection state
+    Unexpected,
+    /// segments rejected by PAWS
+    Paws,
+}
+
+impl WarnCategory {
+    const ALL: [WarnCategory; 6] = [
+        WarnCategory::OutOfWindow,
+        WarnCategory::BufferLimit,
+        WarnCategory::InvalidReset,
+        WarnCategory::Handshake,
+        WarnCategory::Unexpected,
+        WarnCategory::Paws,
+    ];
+
+    /// name used in summaries
+    pub fn name(self) -> &'static str {
+        match self {
+            WarnCategory::OutOfWindow => "out of window",
+            WarnCategory::BufferLimit => "buffer limit",
+            WarnCategory::InvalidReset => "invalid reset",
+            WarnCategory::Handshake => "handshake",
+            WarnCategory::Unexpected => "unexpected packet",
+            WarnCategory::Paws => "PAWS",
+        }
+    }
+}
+
+/// token bucket of one category
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: u32,
+    /// time tokens were last refilled, None if never used
+    refilled: Option<Instant>,
+    /// warnings suppressed since the last one logged
+    suppressed: u64,
+}
+
+impl Bucket {
+    const fn new() -> Self {
+        Bucket {
+            tokens: THROTTLE_BURST,
+            refilled: None,
+            suppressed: 0,
+        }
+    }
+
+    /// take a token, returning number of warnings suppressed before this one
+    /// if it should be logged
+    fn take(&mut self, now: Instant) -> Option<u64> {
+        let refilled = *self.refilled.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(refilled);
+        let new_tokens = elapsed.as_nanos() / THROTTLE_REFILL_INTERVAL.as_nanos();
+        if new_tokens >= THROTTLE_BURST as u128 {
+            self.tokens = THROTTLE_BURST;
+            self.refilled = Some(now);
+        } else if new_tokens > 0 {
+            let new_tokens = new_tokens as u32;
+            self.tokens = (self.tokens + new_tokens).min(THROTTLE_BURST);
+            self.refilled = Some(refilled + THROTTLE_REFILL_INTERVAL * new_tokens);
+        }
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            Some(std::mem::take(&mut self.suppressed))
+        } else {
+            self.suppressed += 1;
+            None
+        }
+    }
+}
+
+static BUCKETS: Mutex<[Bucket; WarnCategory::ALL.len()]> =
+    Mutex::new([Bucket::new(); WarnCategory::ALL.len()]);
+
+/// whether a warning of category should be logged, returning number of
+/// warnings suppressed before it
+pub fn allow(category: WarnCategory) -> Option<u64> {
+    BUCKETS.lock()[category as usize].take(Instant::now())
+}
+
+/// log counts of warnings suppressed since the last one of each category
+pub fn flush() {
+    let mut buckets = BUCKETS.lock();
+    for category in WarnCategory::ALL {
+        let suppressed = std::mem::take(&mut buckets[category as usize].suppressed);
+        if suppressed > 0 {
+            warn!(
+                "suppressed {suppressed} similar warnings ({})",
+                category.name()
+            );
+        }
+    }
+}
+
+/// log warning unless category is being throttled
+macro_rules! warn_throttled {
+    ($category:expr, $($arg:tt)+) => {
+        if let Some(suppressed) = $crate::log_throttle::allow($category) {
+            ::tracing::warn!($($arg)+);
+            if suppressed > 0 {
+                ::tracing::warn!(
+                    "suppressed {suppressed} similar warnings ({})",
+                    $category.name()
+                );
+            }
+        }
+    };
+}
+
+pub(crate) use warn_throttled;
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Bucket, THROTTLE_BURST};
+
+    #[test]
+    fn bucket() {
+        let mut bucket = Bucket::new();
+        let start = Instant::now();
+        for _ in 0..THROTTLE_BURST {
+            assert_eq!(bucket.take(start), Some(0));
+        }
+        for _ in 0..5 {
+            assert_eq!(bucket.take(start + Duration::from_millis(500)), None);
+        }
+        // one token regained per interval
+        assert_eq!(bucket.take(start + Duration::from_millis(1200)), Some(5));
+        assert_eq!(bucket.take(start + Duration::from_millis(1900)), None);
+        assert_eq!(bucket.take(start + Duration::from_millis(2000)), Some(1));
+        // refills up to burst size
+        let later = start + Duration::from_secs(60);
+        for _ in 0..THROTTLE_BURST {
+            assert_eq!(bucket.take(later), Some(0));
+        }
+        assert_eq!(bucket.take(later), None);
+    }
+}
@@ -0,0 +1,275 @@
+//! Content matching over reassembled streams
+//!
+//! Patterns are registered through `ReassemblyConfig::patterns`. Each
+//! direction of a connection is scanned as data becomes contiguous, before
+//! handlers are notified of it, and matches are reported through
+//! `ConnectionHandler::stream_match`.
+//!
+//! The last `PatternSet::window` bytes scanned are carried over, so matches
+//! spanning packets or handler flushes are found. Regex matches longer than
+//! `MATCH_WINDOW` may be missed or truncated at a boundary, and anchors refer
+//! to the scanned window rather than the stream. Scanning restarts without
+//! carryover after data declared lost.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use memchr::memmem::Finder;
+use regex::bytes::Regex;
+
+use crate::stream::Stream;
+
+/// bytes of preceding data kept for regexes spanning boundaries
+pub const MATCH_WINDOW: usize = 4096;
+
+/// single pattern
+///
+/// Parsed from `re:<regex>`, `hex:<hex bytes>`, or any other text, which is
+/// matched literally.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Bytes(Box<Finder<'static>>),
+    Regex(Regex),
+}
+
+/// error parsing pattern
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatternParseError {
+    pub message: String,
+}
+
+impl Display for PatternParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid pattern: {}", self.message)
+    }
+}
+
+impl std::error::Error for PatternParseError {}
+
+impl Pattern {
+    /// match bytes literally
+    pub fn bytes(needle: &[u8]) -> Self {
+        Pattern::Bytes(Box::new(Finder::new(needle).into_owned()))
+    }
+
+    /// bytes of preceding data needed to find matches ending in new data
+    fn window(&self) -> usize {
+        match self {
+            Pattern::Bytes(finder) => finder.needle().len(),
+            Pattern::Regex(_) => MATCH_WINDOW,
+        }
+    }
+
+    /// call `found` with the range of each match in `haystack`
+    fn find_each(&self, haystack: &[u8], mut found: impl FnMut(usize, usize)) {
+        match self {
+            Pattern::Bytes(finder) => {
+                let len = finder.needle().len();
+                for start in finder.find_iter(haystack) {
+                    found(start, start + len);
+                }
+            }
+            Pattern::Regex(regex) => {
+                for m in regex.find_iter(haystack) {
+                    found(m.start(), m.end());
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = PatternParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |message: String| PatternParseError { message };
+        if let Some(regex) = s.strip_prefix("re:") {
+            Regex::new(regex)
+                .map(Pattern::Regex)
+                .map_err(|e| error(e.to_string()))
+        } else if let Some(hex) = s.strip_prefix("hex:") {
+            let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+            if !digits.len().is_multiple_of(2) {
+                return Err(error("odd number of hex digits".into()));
+            }
+            let needle = digits
+                .chunks(2)
+                .map(|pair| {
+                    std::str::from_utf8(pair)
+                        .ok()
+                        .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                        .ok_or_else(|| error(format!("bad hex digits in {hex:?}")))
+                })
+                .collect::<Result<Vec<u8>, _>>()?;
+            if needle.is_empty() {
+                return Err(error("empty pattern".into()));
+            }
+            Ok(Pattern::bytes(&needle))
+        } else if s.is_empty() {
+            Err(error("empty pattern".into()))
+        } else {
+            Ok(Pattern::bytes(s.as_bytes()))
+        }
+    }
+}
+
+/// patterns searched for in all streams
+#[derive(Clone, Debug)]
+pub struct PatternSet {
+    pub patterns: Vec<Pattern>,
+    /// bytes of carryover kept between scans
+    pub window: usize,
+}
+
+impl PatternSet {
+    pub fn new(patterns: Vec<Pattern>) -> Self {
+        let window = patterns.iter().map(Pattern::window).max().unwrap_or(0);
+        PatternSet { patterns, window }
+    }
+}
+
+/// match found in a stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamMatch {
+    /// index of pattern in `PatternSet::patterns`
+    pub pattern: usize,
+    /// stream offset of first byte of match
+    pub offset: u64,
+    /// matched bytes
+    pub data: Vec<u8>,
+}
+
+/// scan state for one direction of a connection
+#[derive(Clone, Debug, Default)]
+pub struct StreamScanner {
+    /// stream offset up to which data was scanned
+    pub scanned: u64,
+    /// carryover followed by data being scanned
+    window: Vec<u8>,
+    /// end offset of the last match reported for each pattern
+    reported_end: Vec<u64>,
+}
+
+impl StreamScanner {
+    /// create scanner for stream already scanned up to `offset`
+    pub fn starting_at(offset: u64) -> Self {
+        StreamScanner {
+            scanned: offset,
+            ..Default::default()
+        }
+    }
+
+    /// restart scanning at `offset`, forgetting carryover
+    fn skip_to(&mut self, offset: u64) {
+        self.scanned = offset;
+        self.window.clear();
+    }
+
+    /// scan newly contiguous data of stream, adding matches to `out`
+    pub fn scan(&mut self, set: &PatternSet, stream: &Stream, out: &mut Vec<StreamMatch>) {
+        let received = &stream.state.received;
+        if stream.buffer_start() > self.scanned {
+            // consumed without being contiguous first
+            self.skip_to(stream.buffer_start());
+        }
+        if self.scanned < stream.declared_gap_end && !received.has_value(self.scanned) {
+            // waiting on data declared lost
+            self.skip_to(stream.declared_gap_end);
+        }
+        let buffer_end = stream.buffer_start() + stream.total_buffered_length() as u64;
+        let end = u64::min(received.first_missing_after(self.scanned), buffer_end);
+        if end <= self.scanned {
+            return;
+        }
+        let Some(slice) = stream.state.read_segment(self.scanned..end) else {
+            return;
+        };
+        let (a, b) = slice.as_slices();
+        self.window.extend_from_slice(a);
+        if let Some(b) = b {
+            self.window.extend_from_slice(b);
+        }
+
+        let window_start = end - self.window.len() as u64;
+        let previous_end = self.scanned;
+        self.reported_end.resize(set.patterns.len(), 0);
+        for (index, pattern) in set.patterns.iter().enumerate() {
+            let reported_end = &mut self.reported_end[index];
+            pattern.find_each(&self.window, |start, match_end| {
+                let offset = window_start + start as u64;
+                let end_offset = window_start + match_end as u64;
+                // matches ending in carryover were reported by earlier scans
+                if start == match_end || end_offset <= previous_end || offset < *reported_end {
+                    return;
+                }
+                *reported_end = end_offset;
+                out.push(StreamMatch {
+                    pattern: index,
+                    offset,
+                    data: self.window[start..match_end].to_vec(),
+                });
+            });
+        }
+
+        self.scanned = end;
+        let keep = usize::min(self.window.len(), set.window);
+        self.window.drain(..self.window.len() - keep);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Pattern, PatternSet, StreamMatch, StreamScanner};
+    use crate::serialized::PacketExtra;
+    use crate::stream::Stream;
+
+    #[test]
+    fn spanning_matches() {
+        let set = PatternSet::new(vec![
+            "secret".parse().unwrap(),
+            "re:id=[0-9]+;".parse().unwrap(),
+            "hex:00ff".parse().unwrap(),
+        ]);
+        assert!("hex:0".parse::<Pattern>().is_err());
+        assert!("re:(".parse::<Pattern>().is_err());
+
+        let mut stream = Stream::default();
+        stream.set_isn(0, 0);
+        stream.state.set_limit(1 << 20);
+        let mut scanner = StreamScanner::default();
+        let mut found = Vec::new();
+        let deliver = |stream: &mut Stream, seq: u32, data: &[u8]| {
+            assert!(stream.handle_data_packet(seq, data, &PacketExtra::None, Default::default()));
+        };
+
+        deliver(&mut stream, 0, b"xx sec");
+        scanner.scan(&set, &stream, &mut found);
+        assert!(found.is_empty());
+        // out of order, not scanned until contiguous
+        deliver(&mut stream, 13, b"12;\x00");
+        scanner.scan(&set, &stream, &mut found);
+        assert!(found.is_empty());
+        deliver(&mut stream, 6, b"ret id=");
+        scanner.scan(&set, &stream, &mut found);
+        // consumed by a handler before more data arrives
+        stream.consume_until(10);
+        deliver(&mut stream, 17, b"\xff");
+        scanner.scan(&set, &stream, &mut found);
+
+        let expected = [
+            (0, 3, &b"secret"[..]),
+            (1, 10, b"id=12;"),
+            (2, 16, b"\x00\xff"),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(pattern, offset, data)| StreamMatch {
+                pattern,
+                offset,
+                data: data.to_vec(),
+            })
+            .collect();
+        assert_eq!(found, expected);
+        assert_eq!(scanner.scanned, 18);
+    }
+}
@@ -0,0 +1,258 @@
+//! Memory accounting across connections
+//!
+//! `max_buffer_size` bounds each stream, but not the total with many
+//! concurrent connections. A `MemoryBudget` shared through `ReassemblyConfig`
+//! counts the bytes buffered by all streams. When the budget is exceeded
+//! after a packet, the flow table calls `ConnectionHandler::memory_pressure`
+//! for the largest streams, largest first, until usage is within the budget
+//! again. Handlers which buffer data should write out as much as they can.
+//!
+//! A stream whose handler did not reduce its usage is not asked again until
+//! it grows. If every stream was asked and the budget is still exceeded, the
+//! flow table waits `MEMORY_PRESSURE_BACKOFF` packets before trying again.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// packets over budget handled before streams are scanned again after
+/// flushing could not get within the budget
+pub const MEMORY_PRESSURE_BACKOFF: u32 = 64;
+
+/// byte budget shared by all streams
+#[derive(Debug)]
+pub struct MemoryBudget {
+    /// max bytes buffered before handlers are asked to write out data
+    limit: u64,
+    /// bytes currently buffered
+    used: AtomicU64,
+    /// highest value of `used`
+    peak: AtomicU64,
+    /// number of times handlers were asked to write out data
+    pressure_calls: AtomicU64,
+}
+
+impl MemoryBudget {
+    /// create budget of `limit` bytes
+    pub fn new(limit: u64) -> Self {
+        MemoryBudget {
+            limit,
+            used: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+            pressure_calls: AtomicU64::new(0),
+        }
+    }
+
+    /// max bytes buffered before handlers are asked to write out data
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// bytes currently buffered
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// highest number of bytes buffered at once
+    pub fn peak(&self) -> u64 {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// number of times handlers were asked to write out data
+    pub fn pressure_calls(&self) -> u64 {
+        self.pressure_calls.load(Ordering::Relaxed)
+    }
+
+    /// whether more than `limit` bytes are buffered
+    pub fn exceeded(&self) -> bool {
+        self.used() > self.limit
+    }
+
+    /// update usage of a stream from `old` to `new` bytes
+    pub fn update(&self, old: u64, new: u64) {
+        if new > old {
+            let used = self.used.fetch_add(new - old, Ordering::Relaxed) + (new - old);
+            self.peak.fetch_max(used, Ordering::Relaxed);
+        } else if old > new {
+            self.used.fetch_sub(old - new, Ordering::Relaxed);
+        }
+    }
+
+    /// record a call to `ConnectionHandler::memory_pressure`
+    pub fn record_pressure_call(&self) {
+        self.pressure_calls.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{MemoryBudget, MEMORY_PRESSURE_BACKOFF};
+    use crate::config::ReassemblyConfig;
+    use crate::connection::{Connection, Direction};
+    use crate::flow_table::FlowTable;
+    use crate::serialized::PacketExtra;
+    use crate::stream::Stream;
+    use crate::{ConnectionHandler, TcpFlags, TcpMeta};
+
+    #[test]
+    fn stream_accounting() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let config = Arc::new(ReassemblyConfig {
+            memory_budget: Some(budget.clone()),
+            ..Default::default()
+        });
+        let mut stream = Stream::new(config.clone());
+        stream.set_isn(0, 0);
+        stream.state.set_limit(1 << 20);
+        let extra = PacketExtra::None;
+        assert!(stream.handle_data_packet(0, &[0; 60], &extra, Default::default()));
+        assert_eq!(budget.used(), 60);
+        let mut other = Stream::new(config);
+        other.set_isn(0, 0);
+        other.state.set_limit(1 << 20);
+        assert!(other.handle_data_packet(0, &[0; 50], &extra, Default::default()));
+        assert!(budget.exceeded());
+        stream.consume_until(40);
+        assert_eq!(budget.used(), 70);
+        drop(other);
+        assert_eq!(budget.used(), 20);
+        assert_eq!(budget.peak(), 110);
+    }
+
+    /// handler writing out nothing until asked to
+    struct HoardingHandler;
+
+    impl ConnectionHandler for HoardingHandler {
+        type InitialData = ();
+        type ConstructError = ();
+        fn new(_init: (), _connection: &mut Connection<Self>) -> Result<Self, ()> {
+            Ok(HoardingHandler)
+        }
+        fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+            let stream = connection.get_stream(direction);
+            let end = stream.buffer_start() + stream.total_buffered_length() as u64;
+            stream.consume_until(end);
+        }
+    }
+
+    #[test]
+    fn forced_flush() {
+        let budget = Arc::new(MemoryBudget::new(150));
+        let config = ReassemblyConfig {
+            memory_budget: Some(budget.clone()),
+            ..Default::default()
+        };
+        let mut table: FlowTable<HoardingHandler> = FlowTable::with_config((), config);
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 0,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let extra = PacketExtra::None;
+        table.handle_packet(&meta, &[0; 100], &extra).unwrap();
+        meta.src_port = 1001;
+        table.handle_packet(&meta, &[0; 40], &extra).unwrap();
+        assert_eq!(budget.used(), 140);
+        assert_eq!(budget.pressure_calls(), 0);
+        // over budget, only the largest stream is written out
+        meta.src_port = 1002;
+        table.handle_packet(&meta, &[0; 20], &extra).unwrap();
+        assert_eq!(budget.used(), 60);
+        assert_eq!(budget.pressure_calls(), 1);
+        table.close();
+    }
+
+    /// handler writing out everything except data sent from port 1000
+    struct StubbornHandler;
+
+    impl ConnectionHandler for StubbornHandler {
+        type InitialData = ();
+        type ConstructError = ();
+        fn new(_init: (), _connection: &mut Connection<Self>) -> Result<Self, ()> {
+            Ok(StubbornHandler)
+        }
+        fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+            if connection.forward_flow.src_port == 1000 {
+                return;
+            }
+            let stream = connection.get_stream(direction);
+            let end = stream.buffer_start() + stream.total_buffered_length() as u64;
+            stream.consume_until(end);
+        }
+    }
+
+    #[test]
+    fn unrelieved_pressure() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let config = ReassemblyConfig {
+            memory_budget: Some(budget.clone()),
+            ..Default::default()
+        };
+        let mut table: FlowTable<StubbornHandler> = FlowTable::with_config((), config);
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 0,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let extra = PacketExtra::None;
+        table.handle_packet(&meta, &[0; 150], &extra).unwrap();
+        assert_eq!(budget.pressure_calls(), 1);
+        assert_eq!(table.memory_pressure_backoff, MEMORY_PRESSURE_BACKOFF);
+        let stuck = table.map.values().next().unwrap();
+        assert_eq!(stuck.forward_stream.memory_pressure_unrelieved, Some(150));
+        // no scan while backing off
+        for port in 0..MEMORY_PRESSURE_BACKOFF as u16 {
+            meta.src_port = 2000 + port;
+            table.handle_packet(&meta, &[0; 1], &extra).unwrap();
+        }
+        assert_eq!(budget.pressure_calls(), 1);
+        assert_eq!(budget.used(), 150 + MEMORY_PRESSURE_BACKOFF as u64);
+        // next scan flushes every other stream but skips the stuck one
+        meta.src_port = 3000;
+        table.handle_packet(&meta, &[0; 1], &extra).unwrap();
+        assert_eq!(
+            budget.pressure_calls(),
+            1 + MEMORY_PRESSURE_BACKOFF as u64 + 1
+        );
+        assert_eq!(budget.used(), 150);
+        table.close();
+    }
+}
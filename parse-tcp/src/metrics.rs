@@ -0,0 +1,247 @@
+//! Flow table metrics
+//!
+//! Counters for monitoring long-running captures, shared through
+//! `ReassemblyConfig` and updated by flow tables and streams. They can be
+//! read directly, written in the Prometheus text exposition format, or
+//! served over HTTP for scraping.
+
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use tracing::debug;
+
+/// counters shared by flow tables and streams
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// connections currently in flow tables
+    active_flows: AtomicU64,
+    /// connections removed from flow tables
+    retired_flows: AtomicU64,
+    /// TCP packets handled by flow tables
+    packets: AtomicU64,
+    /// packets which could not be parsed
+    parse_failures: AtomicU64,
+    /// bytes buffered by all streams
+    bytes_buffered: AtomicU64,
+    /// segment info objects dropped because a stream held too many
+    segments_info_dropped: AtomicU64,
+}
+
+impl Metrics {
+    /// create new instance with all counters at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// connections currently in flow tables
+    pub fn active_flows(&self) -> u64 {
+        self.active_flows.load(Ordering::Relaxed)
+    }
+
+    /// connections removed from flow tables
+    pub fn retired_flows(&self) -> u64 {
+        self.retired_flows.load(Ordering::Relaxed)
+    }
+
+    /// TCP packets handled by flow tables
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    /// packets which could not be parsed
+    pub fn parse_failures(&self) -> u64 {
+        self.parse_failures.load(Ordering::Relaxed)
+    }
+
+    /// bytes buffered by all streams
+    pub fn bytes_buffered(&self) -> u64 {
+        self.bytes_buffered.load(Ordering::Relaxed)
+    }
+
+    /// segment info objects dropped because a stream held too many
+    pub fn segments_info_dropped(&self) -> u64 {
+        self.segments_info_dropped.load(Ordering::Relaxed)
+    }
+
+    /// record a new connection
+    pub fn flow_created(&self) {
+        self.active_flows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// record removal of a connection
+    pub fn flow_retired(&self) {
+        self.active_flows.fetch_sub(1, Ordering::Relaxed);
+        self.retired_flows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// record a packet handled by a flow table
+    pub fn packet_handled(&self) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// set number of packets which could not be parsed, as counted by the
+    /// parser
+    pub fn set_parse_failures(&self, count: u64) {
+        self.parse_failures.store(count, Ordering::Relaxed);
+    }
+
+    /// update bytes buffered by a stream from `old` to `new`
+    pub fn update_buffered(&self, old: u64, new: u64) {
+        if new > old {
+            self.bytes_buffered.fetch_add(new - old, Ordering::Relaxed);
+        } else if old > new {
+            self.bytes_buffered.fetch_sub(old - new, Ordering::Relaxed);
+        }
+    }
+
+    /// record a dropped segment info object
+    pub fn segment_info_dropped(&self) {
+        self.segments_info_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// write counters in the Prometheus text exposition format
+    pub fn write_prometheus(&self, out: &mut impl Write) -> io::Result<()> {
+        let metrics = [
+            (
+                "parse_tcp_active_flows",
+                "gauge",
+                "Connections currently tracked.",
+                self.active_flows(),
+            ),
+            (
+                "parse_tcp_retired_flows_total",
+                "counter",
+                "Connections removed from the flow table.",
+                self.retired_flows(),
+            ),
+            (
+                "parse_tcp_packets_total",
+                "counter",
+                "TCP packets processed.",
+                self.packets(),
+            ),
+            (
+                "parse_tcp_parse_failures_total",
+                "counter",
+                "Packets which could not be parsed.",
+                self.parse_failures(),
+            ),
+            (
+                "parse_tcp_bytes_buffered",
+                "gauge",
+                "Bytes buffered by all streams.",
+                self.bytes_buffered(),
+            ),
+            (
+                "parse_tcp_segments_info_dropped_total",
+                "counter",
+                "Segment metadata dropped because a stream held too many.",
+                self.segments_info_dropped(),
+            ),
+        ];
+        for (name, kind, help, value) in metrics {
+            writeln!(out, "# HELP {name} {help}")?;
+            writeln!(out, "# TYPE {name} {kind}")?;
+            writeln!(out, "{name} {value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// answer every HTTP request on `listener` with the metrics in Prometheus
+/// text format, returns only if accepting connections fails
+pub fn serve_prometheus(metrics: Arc<Metrics>, listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (mut stream, peer) = listener.accept()?;
+        // request is not inspected, any path returns metrics
+        let mut request = [0u8; 1024];
+        let result = stream.read(&mut request).and_then(|_| {
+            let mut body = Vec::new();
+            metrics.write_prometheus(&mut body)?;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\n\
+                Content-Type: text/plain; version=0.0.4\r\n\
+                Content-Length: {}\r\n\
+                Connection: close\r\n\r\n",
+                body.len()
+            )?;
+            stream.write_all(&body)
+        });
+        if let Err(e) = result {
+            debug!("failed to serve metrics to {peer}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::Metrics;
+    use crate::config::ReassemblyConfig;
+    use crate::flow_table::FlowTable;
+    use crate::handler::DumpHandler;
+    use crate::serialized::PacketExtra;
+    use crate::{TcpFlags, TcpMeta};
+
+    #[test]
+    fn flow_table_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        let config = ReassemblyConfig {
+            max_segments_info: 1,
+            metrics: Some(metrics.clone()),
+            ..Default::default()
+        };
+        let mut table: FlowTable<DumpHandler> = FlowTable::with_config((), config);
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 0,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let extra = PacketExtra::None;
+        table.handle_packet(&meta, b"world", &extra).unwrap();
+        meta.seq_number += 5;
+        table.handle_packet(&meta, b"!", &extra).unwrap();
+        meta.src_port = 1001;
+        table.handle_packet(&meta, b"hello", &extra).unwrap();
+        assert_eq!(metrics.active_flows(), 2);
+        assert_eq!(metrics.packets(), 3);
+        assert!(metrics.bytes_buffered() > 0);
+        assert!(metrics.segments_info_dropped() > 0);
+        table.close();
+        drop(table);
+        assert_eq!(metrics.active_flows(), 0);
+        assert_eq!(metrics.retired_flows(), 2);
+        assert_eq!(metrics.bytes_buffered(), 0);
+
+        metrics.set_parse_failures(4);
+        let mut out = Vec::new();
+        metrics.write_prometheus(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("# TYPE parse_tcp_active_flows gauge\nparse_tcp_active_flows 0\n"));
+        assert!(out.contains("\nparse_tcp_retired_flows_total 2\n"));
+        assert!(out.contains("\nparse_tcp_parse_failures_total 4\n"));
+    }
+}
@@ -0,0 +1,82 @@
+//! Reading directory output
+//!
+//! Helpers for tools working on a directory written by
+//! `DirectoryOutputHandler` after the fact. Files may have been compressed
+//! with gzip or zstd since, in which case the compressed variant is read.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+use uuid::Uuid;
+
+use crate::segment_file::SegmentReader;
+use crate::serialized::{ConnInfo, SerializedSegment};
+
+pub(crate) fn invalid_data(error: impl ToString) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, error.to_string())
+}
+
+/// open output file, trying compressed variants if it does not exist
+pub fn open_output_file(path: &Path) -> io::Result<Option<Box<dyn Read>>> {
+    for extension in [None, Some("gz"), Some("zst")] {
+        let mut name = path.as_os_str().to_owned();
+        if let Some(extension) = extension {
+            name.push(".");
+            name.push(extension);
+        }
+        let file = match File::open(PathBuf::from(name)) {
+            Ok(file) => BufReader::new(file),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        return Ok(Some(match extension {
+            None => Box::new(file),
+            Some("gz") => Box::new(MultiGzDecoder::new(file)),
+            _ => Box::new(zstd::Decoder::with_buffer(file)?),
+        }));
+    }
+    Ok(None)
+}
+
+/// read connections.json, keeping the first entry of each connection
+pub fn read_connections(dir: &Path) -> io::Result<Vec<ConnInfo>> {
+    let Some(reader) = open_output_file(&dir.join("connections.json"))? else {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            "connections.json not found",
+        ));
+    };
+    let mut connections: Vec<ConnInfo> = serde_json::from_reader(reader).map_err(invalid_data)?;
+    // connections may be written more than once, e.g. by TLS metadata output
+    let mut seen = HashSet::new();
+    connections.retain(|info| seen.insert(info.id));
+    Ok(connections)
+}
+
+/// read segment file of direction `label` (`f` or `r`), in either format
+pub fn read_segments(
+    dir: &Path,
+    id: Uuid,
+    label: &str,
+) -> io::Result<Option<Vec<SerializedSegment>>> {
+    let mut segments = Vec::new();
+    if let Some(reader) = open_output_file(&dir.join(format!("{id}.{label}.seg")))? {
+        let mut reader = SegmentReader::new(reader)?;
+        while let Some(segment) = reader.read_segment()? {
+            segments.push(segment);
+        }
+    } else if let Some(reader) = open_output_file(&dir.join(format!("{id}.{label}.jsonl")))? {
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if !line.is_empty() {
+                segments.push(serde_json::from_str(&line).map_err(invalid_data)?);
+            }
+        }
+    } else {
+        return Ok(None);
+    }
+    Ok(Some(segments))
+}
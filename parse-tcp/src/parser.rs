@@ -1,15 +1,105 @@
+use std::mem;
 use std::net::IpAddr;
+use std::time::Duration;
 
-use etherparse::{InternetSlice, SlicedPacket, TcpOptionElement, TransportSlice};
+use etherparse::{
+    IpNumber, Ipv6ExtensionSlice, NetSlice, SlicedPacket, TcpOptionElement, TcpSlice,
+    TransportSlice, UdpSlice,
+};
 use tracing::{debug, trace};
 
-use crate::{TcpFlags, TcpMeta};
+use crate::flow_table::Flow;
+use crate::fragment::{Fragment, FragmentKey, FragmentReassembler};
+use crate::pcap_writer::RawFrame;
+use crate::{TcpFlags, TcpMeta, UdpMeta};
 
-/// parses only TCP packets with etherparse
+/// default max number of VLAN tags and MPLS labels stripped before IP
+pub const MAX_ENCAPSULATION_DEPTH: usize = 8;
+
+/// ethertype of IPv4
+const ETHERTYPE_IPV4: u16 = 0x0800;
+/// ethertype of IPv6
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+/// ethertype of 802.1Q VLAN tag
+const ETHERTYPE_VLAN: u16 = 0x8100;
+/// ethertype of 802.1ad (QinQ) service VLAN tag
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+/// legacy ethertype used for QinQ outer tags
+const ETHERTYPE_QINQ_LEGACY: u16 = 0x9100;
+/// ethertype of MPLS unicast
+const ETHERTYPE_MPLS: u16 = 0x8847;
+/// ethertype of MPLS multicast
+const ETHERTYPE_MPLS_MULTICAST: u16 = 0x8848;
+
+/// packet returned from TcpParser::parse_packet_any
+pub enum ParsedPacket<'a> {
+    Tcp(TcpMeta, &'a [u8]),
+    Udp(UdpMeta, &'a [u8]),
+}
+
+impl ParsedPacket<'_> {
+    /// outermost VLAN id, if any
+    pub fn vlan_id(&self) -> Option<u16> {
+        match self {
+            ParsedPacket::Tcp(meta, _) => meta.vlan_id,
+            ParsedPacket::Udp(meta, _) => meta.vlan_id,
+        }
+    }
+
+    /// TTL or hop limit of IP header
+    pub fn ttl(&self) -> u8 {
+        match self {
+            ParsedPacket::Tcp(meta, _) => meta.ip_ttl,
+            ParsedPacket::Udp(meta, _) => meta.ip_ttl,
+        }
+    }
+
+    /// whether the packet failed checksum verification
+    pub fn bad_checksum(&self) -> bool {
+        match self {
+            ParsedPacket::Tcp(meta, _) => meta.bad_checksum,
+            ParsedPacket::Udp(meta, _) => meta.bad_checksum,
+        }
+    }
+
+    /// flow of packet, in the direction it was sent
+    pub fn flow(&self) -> Flow {
+        match self {
+            ParsedPacket::Tcp(meta, _) => meta.into(),
+            ParsedPacket::Udp(meta, _) => meta.into(),
+        }
+    }
+}
+
+/// handling of packets with incorrect checksums
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumPolicy {
+    /// do not verify checksums (e.g. captures with checksum offloading)
+    #[default]
+    Ignore,
+    /// drop packets with incorrect checksums
+    Drop,
+    /// keep packets with incorrect checksums, marking them as such
+    Annotate,
+}
+
+/// parses TCP (and optionally UDP) packets with etherparse
 pub struct TcpParser {
     pub layer: ParseLayer,
     pub failed_parse: usize,
     pub ignored: usize,
+    /// max number of VLAN tags and MPLS labels to strip
+    pub max_encapsulation_depth: usize,
+    /// IP fragment reassembly state
+    pub fragments: FragmentReassembler,
+    /// handling of packets with incorrect checksums
+    pub checksum_policy: ChecksumPolicy,
+    /// count of packets with incorrect IPv4 header checksum
+    pub bad_ip_checksum: usize,
+    /// count of packets with incorrect TCP or UDP checksum
+    pub bad_transport_checksum: usize,
+    /// buffer holding the most recently reassembled datagram
+    reassembled: Vec<u8>,
 }
 
 impl TcpParser {
@@ -18,13 +108,63 @@ impl TcpParser {
             layer: ParseLayer::Link,
             failed_parse: 0,
             ignored: 0,
+            max_encapsulation_depth: MAX_ENCAPSULATION_DEPTH,
+            fragments: FragmentReassembler::new(),
+            checksum_policy: ChecksumPolicy::Ignore,
+            bad_ip_checksum: 0,
+            bad_transport_checksum: 0,
+            reassembled: Vec::new(),
         }
     }
 
+    /// set capture time of the next packet (used to expire IP fragments)
+    pub fn set_current_time(&mut self, now: Duration) {
+        self.fragments.set_current_time(now);
+    }
+
     /// parse tcp packets into TcpMeta and data
-    pub fn parse_packet<'a>(&mut self, data: &'a [u8]) -> Option<(TcpMeta, &'a [u8])> {
+    ///
+    /// If the packet completes a fragmented datagram, the returned data will
+    /// borrow from the parser's reassembly buffer.
+    pub fn parse_packet<'a>(&'a mut self, data: &'a [u8]) -> Option<(TcpMeta, &'a [u8])> {
+        match self.parse(data, false, &mut Vec::new())? {
+            ParsedPacket::Tcp(meta, data) => Some((meta, data)),
+            ParsedPacket::Udp(..) => unreachable!("udp not requested"),
+        }
+    }
+
+    /// parse tcp and udp packets
+    pub fn parse_packet_any<'a>(&'a mut self, data: &'a [u8]) -> Option<ParsedPacket<'a>> {
+        self.parse(data, true, &mut Vec::new())
+    }
+
+    /// parse packets, keeping the captured frames of fragmented datagrams
+    ///
+    /// `frames` should hold the captured frame of `data`. If the packet
+    /// completes a fragmented datagram, it is replaced with the frames of all
+    /// fragments. UDP packets are only parsed if `parse_udp` is set.
+    pub fn parse_packet_frames<'a>(
+        &'a mut self,
+        data: &'a [u8],
+        parse_udp: bool,
+        frames: &mut Vec<RawFrame>,
+    ) -> Option<ParsedPacket<'a>> {
+        self.parse(data, parse_udp, frames)
+    }
+
+    fn parse<'a>(
+        &'a mut self,
+        data: &'a [u8],
+        parse_udp: bool,
+        frames: &mut Vec<RawFrame>,
+    ) -> Option<ParsedPacket<'a>> {
+        let mut vlan_id = None;
         let parse_result = match self.layer {
-            ParseLayer::Link => SlicedPacket::from_ethernet(data),
+            ParseLayer::Link => {
+                let (ip_data, outer_vlan) = self.strip_link_layer(data)?;
+                vlan_id = outer_vlan;
+                SlicedPacket::from_ip(ip_data)
+            }
             ParseLayer::IP => SlicedPacket::from_ip(data),
             // BSD loopback has 4 byte header before IP, remove it
             ParseLayer::BsdLoopback => SlicedPacket::from_ip(&data[4..]),
@@ -40,26 +180,16 @@ impl TcpParser {
             self.ignored += 1;
             return None;
         };
-        let Some(transport_slice) = parsed.transport else {
-            trace!("ignoring packet: no transport layer");
-            self.ignored += 1;
-            return None;
-        };
-        let TransportSlice::Tcp(tcp_slice) = transport_slice else {
-            trace!("ignoring packet: not tcp");
-            self.ignored += 1;
-            return None;
-        };
 
-        let (src_addr, dst_addr): (IpAddr, IpAddr) = match internet_slice {
-            InternetSlice::Ipv4(v4) => {
+        let (src_addr, dst_addr): (IpAddr, IpAddr) = match &internet_slice {
+            NetSlice::Ipv4(v4) => {
                 let header = v4.header();
                 (
                     header.source_addr().into(),
                     header.destination_addr().into(),
                 )
             }
-            InternetSlice::Ipv6(v6) => {
+            NetSlice::Ipv6(v6) => {
                 let header = v6.header();
                 (
                     header.source_addr().into(),
@@ -68,10 +198,326 @@ impl TcpParser {
             }
         };
 
+        let ip_ok = match &internet_slice {
+            NetSlice::Ipv4(v4) if self.checksum_policy != ChecksumPolicy::Ignore => {
+                checksum_valid(ones_complement_sum(0, v4.header().slice()))
+            }
+            _ => true,
+        };
+
+        let ip_payload = internet_slice
+            .ip_payload_ref()
+            .expect("NetSlice always has ip payload");
+        if ip_payload.fragmented {
+            let proto = ip_payload.ip_number;
+            if proto != IpNumber::TCP && !(parse_udp && proto == IpNumber::UDP) {
+                trace!("ignoring packet: fragment of unhandled datagram");
+                self.ignored += 1;
+                return None;
+            }
+            if !self.check_ip_fragment(ip_ok) {
+                return None;
+            }
+            return self.handle_fragment(
+                &internet_slice,
+                proto,
+                src_addr,
+                dst_addr,
+                vlan_id,
+                frames,
+            );
+        }
+        let ip_ecn = ip_ecn(&internet_slice);
+        let ip_id = ip_id(&internet_slice);
+        let ip_ttl = ip_ttl(&internet_slice);
+
+        let Some(transport_slice) = parsed.transport else {
+            trace!("ignoring packet: no transport layer");
+            self.ignored += 1;
+            return None;
+        };
+        match transport_slice {
+            TransportSlice::Tcp(tcp_slice) => {
+                let bad_checksum = self.check_checksums(
+                    ip_ok,
+                    src_addr,
+                    dst_addr,
+                    IpNumber::TCP,
+                    tcp_slice.slice(),
+                )?;
+                let (mut meta, data) = Self::read_tcp(
+                    src_addr, dst_addr, vlan_id, ip_ecn, ip_id, ip_ttl, tcp_slice,
+                );
+                meta.bad_checksum = bad_checksum;
+                Some(ParsedPacket::Tcp(meta, data))
+            }
+            TransportSlice::Udp(udp_slice) if parse_udp => {
+                let bad_checksum = self.check_checksums(
+                    ip_ok,
+                    src_addr,
+                    dst_addr,
+                    IpNumber::UDP,
+                    udp_slice.slice(),
+                )?;
+                let (mut meta, data) =
+                    Self::read_udp(src_addr, dst_addr, vlan_id, ip_ttl, udp_slice);
+                meta.bad_checksum = bad_checksum;
+                Some(ParsedPacket::Udp(meta, data))
+            }
+            _ => {
+                trace!("ignoring packet: not tcp");
+                self.ignored += 1;
+                None
+            }
+        }
+    }
+
+    /// strip ethernet header and any VLAN/MPLS encapsulation, returning the
+    /// IP packet and the outermost VLAN id
+    fn strip_link_layer<'a>(&mut self, data: &'a [u8]) -> Option<(&'a [u8], Option<u16>)> {
+        let read_u16 = |offset: usize| -> Option<u16> {
+            let bytes = data.get(offset..offset + 2)?;
+            Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+        };
+
+        // skip destination and source MAC addresses
+        let mut offset = 12;
+        let mut vlan_id = None;
+        let mut depth = 0;
+        loop {
+            let Some(ethertype) = read_u16(offset) else {
+                debug!("packet failed parse: truncated link layer header");
+                self.failed_parse += 1;
+                return None;
+            };
+            offset += 2;
+            match ethertype {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => return Some((&data[offset..], vlan_id)),
+                ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_LEGACY => {
+                    let Some(tci) = read_u16(offset) else {
+                        debug!("packet failed parse: truncated vlan tag");
+                        self.failed_parse += 1;
+                        return None;
+                    };
+                    vlan_id.get_or_insert(tci & 0x0fff);
+                    offset += 2;
+                }
+                ETHERTYPE_MPLS | ETHERTYPE_MPLS_MULTICAST => {
+                    return self.strip_mpls(data, offset, depth).map(|ip| (ip, vlan_id));
+                }
+                _ => {
+                    trace!("ignoring packet: unknown ethertype {ethertype:#06x}");
+                    self.ignored += 1;
+                    return None;
+                }
+            }
+
+            depth += 1;
+            if depth > self.max_encapsulation_depth {
+                debug!("ignoring packet: exceeded max encapsulation depth");
+                self.ignored += 1;
+                return None;
+            }
+        }
+    }
+
+    /// strip MPLS label stack starting at `offset`, returning the IP packet
+    fn strip_mpls<'a>(
+        &mut self,
+        data: &'a [u8],
+        mut offset: usize,
+        mut depth: usize,
+    ) -> Option<&'a [u8]> {
+        loop {
+            let Some(label) = data.get(offset..offset + 4) else {
+                debug!("packet failed parse: truncated mpls label");
+                self.failed_parse += 1;
+                return None;
+            };
+            offset += 4;
+            depth += 1;
+            if depth > self.max_encapsulation_depth {
+                debug!("ignoring packet: exceeded max encapsulation depth");
+                self.ignored += 1;
+                return None;
+            }
+            // bottom of stack bit
+            if label[2] & 1 != 0 {
+                break;
+            }
+        }
+
+        // MPLS does not identify its payload, guess from IP version
+        match data.get(offset).map(|b| b >> 4) {
+            Some(4) | Some(6) => Some(&data[offset..]),
+            _ => {
+                trace!("ignoring packet: mpls payload is not IP");
+                self.ignored += 1;
+                None
+            }
+        }
+    }
+
+    /// feed IP fragment to reassembler, parsing the datagram once complete
+    ///
+    /// The frame in `frames` is kept with the fragment, and replaced with the
+    /// frames of all fragments once the datagram is complete.
+    fn handle_fragment<'a>(
+        &'a mut self,
+        internet_slice: &NetSlice<'_>,
+        proto: IpNumber,
+        src_addr: IpAddr,
+        dst_addr: IpAddr,
+        vlan_id: Option<u16>,
+        frames: &mut Vec<RawFrame>,
+    ) -> Option<ParsedPacket<'a>> {
+        let frame = frames.pop();
+        let (id, fragment) = match internet_slice {
+            NetSlice::Ipv4(v4) => {
+                let header = v4.header();
+                let fragment = Fragment {
+                    offset: header.fragments_offset().value() as usize * 8,
+                    more_fragments: header.more_fragments(),
+                    data: v4.payload().payload,
+                    frame,
+                };
+                (header.identification() as u32, fragment)
+            }
+            NetSlice::Ipv6(v6) => {
+                let frag_header = v6
+                    .extensions()
+                    .clone()
+                    .into_iter()
+                    .find_map(|ext| match ext {
+                        Ipv6ExtensionSlice::Fragment(frag) => Some(frag),
+                        _ => None,
+                    });
+                let Some(frag_header) = frag_header else {
+                    debug!("fragmented ipv6 packet without fragment header?");
+                    self.failed_parse += 1;
+                    return None;
+                };
+                let fragment = Fragment {
+                    offset: frag_header.fragment_offset().value() as usize * 8,
+                    more_fragments: frag_header.more_fragments(),
+                    data: v6.payload().payload,
+                    frame,
+                };
+                (frag_header.identification(), fragment)
+            }
+        };
+        let key = FragmentKey {
+            src_addr,
+            dst_addr,
+            id,
+            proto: proto.0,
+        };
+
+        if !self.fragments.process(key, fragment, &mut self.reassembled) {
+            return None;
+        }
+        *frames = self.fragments.take_frames();
+        // ECN field of the final fragment
+        let ip_ecn = ip_ecn(internet_slice);
+        let ip_id = ip_id(internet_slice);
+        let ip_ttl = ip_ttl(internet_slice);
+        // IP header checksums of fragments were checked as they arrived
+        let reassembled = mem::take(&mut self.reassembled);
+        let bad_checksum = self.check_checksums(true, src_addr, dst_addr, proto, &reassembled);
+        self.reassembled = reassembled;
+        let bad_checksum = bad_checksum?;
+        if proto == IpNumber::UDP {
+            match UdpSlice::from_slice(&self.reassembled) {
+                Ok(udp_slice) => {
+                    let (mut meta, data) =
+                        Self::read_udp(src_addr, dst_addr, vlan_id, ip_ttl, udp_slice);
+                    meta.bad_checksum = bad_checksum;
+                    Some(ParsedPacket::Udp(meta, data))
+                }
+                Err(e) => {
+                    debug!("reassembled datagram failed parse: {e:?}");
+                    self.failed_parse += 1;
+                    None
+                }
+            }
+        } else {
+            match TcpSlice::from_slice(&self.reassembled) {
+                Ok(tcp_slice) => {
+                    let (mut meta, data) = Self::read_tcp(
+                        src_addr, dst_addr, vlan_id, ip_ecn, ip_id, ip_ttl, tcp_slice,
+                    );
+                    meta.bad_checksum = bad_checksum;
+                    Some(ParsedPacket::Tcp(meta, data))
+                }
+                Err(e) => {
+                    debug!("reassembled datagram failed parse: {e:?}");
+                    self.failed_parse += 1;
+                    None
+                }
+            }
+        }
+    }
+
+    /// account for IPv4 header checksum of fragment, returns false if the
+    /// fragment should be dropped
+    fn check_ip_fragment(&mut self, ip_ok: bool) -> bool {
+        if ip_ok {
+            return true;
+        }
+        self.bad_ip_checksum += 1;
+        trace!("fragment has bad IPv4 header checksum");
+        self.checksum_policy != ChecksumPolicy::Drop
+    }
+
+    /// verify transport checksum of segment (header and payload) according
+    /// to policy, returns None if the packet should be dropped, otherwise
+    /// whether it should be marked as bad
+    fn check_checksums(
+        &mut self,
+        ip_ok: bool,
+        src_addr: IpAddr,
+        dst_addr: IpAddr,
+        proto: IpNumber,
+        segment: &[u8],
+    ) -> Option<bool> {
+        if self.checksum_policy == ChecksumPolicy::Ignore {
+            return Some(false);
+        }
+        if !ip_ok {
+            self.bad_ip_checksum += 1;
+        }
+        let transport_ok = transport_checksum_valid(src_addr, dst_addr, proto, segment);
+        if !transport_ok {
+            self.bad_transport_checksum += 1;
+        }
+        if ip_ok && transport_ok {
+            return Some(false);
+        }
+        trace!("packet has bad checksum (ip ok: {ip_ok}, transport ok: {transport_ok})");
+        match self.checksum_policy {
+            ChecksumPolicy::Drop => None,
+            _ => Some(true),
+        }
+    }
+
+    /// extract TcpMeta and payload from TCP header
+    fn read_tcp<'a>(
+        src_addr: IpAddr,
+        dst_addr: IpAddr,
+        vlan_id: Option<u16>,
+        ip_ecn: u8,
+        ip_id: u16,
+        ip_ttl: u8,
+        tcp_slice: TcpSlice<'a>,
+    ) -> (TcpMeta, &'a [u8]) {
+        let mut option_mss = None;
         let mut option_window_scale = None;
         let mut option_timestamp = None;
         for opt in tcp_slice.options_iterator() {
             match opt {
+                Ok(TcpOptionElement::MaximumSegmentSize(mss)) => {
+                    option_mss = Some(mss);
+                }
                 Ok(TcpOptionElement::WindowScale(scale)) => {
                     option_window_scale = Some(scale);
                 }
@@ -82,6 +528,8 @@ impl TcpParser {
                 _ => {}
             }
         }
+        // not decoded by etherparse
+        let option_tfo_cookie = find_tfo_cookie(tcp_slice.options());
 
         let meta = TcpMeta {
             src_addr,
@@ -95,13 +543,46 @@ impl TcpParser {
                 ack: tcp_slice.ack(),
                 fin: tcp_slice.fin(),
                 rst: tcp_slice.rst(),
+                psh: tcp_slice.psh(),
+                urg: tcp_slice.urg(),
+                ece: tcp_slice.ece(),
+                cwr: tcp_slice.cwr(),
             },
             window: tcp_slice.window_size(),
+            urgent_pointer: tcp_slice.urgent_pointer(),
+            checksum: tcp_slice.checksum(),
+            option_mss,
             option_window_scale,
             option_timestamp,
+            option_tfo_cookie,
+            vlan_id,
+            ip_ecn,
+            ip_id,
+            ip_ttl,
+            bad_checksum: false,
         };
 
-        Some((meta, tcp_slice.payload()))
+        (meta, tcp_slice.payload())
+    }
+
+    /// extract UdpMeta and payload from UDP header
+    fn read_udp<'a>(
+        src_addr: IpAddr,
+        dst_addr: IpAddr,
+        vlan_id: Option<u16>,
+        ip_ttl: u8,
+        udp_slice: UdpSlice<'a>,
+    ) -> (UdpMeta, &'a [u8]) {
+        let meta = UdpMeta {
+            src_addr,
+            src_port: udp_slice.source_port(),
+            dst_addr,
+            dst_port: udp_slice.destination_port(),
+            vlan_id,
+            ip_ttl,
+            bad_checksum: false,
+        };
+        (meta, udp_slice.payload())
     }
 }
 
@@ -111,7 +592,119 @@ impl Default for TcpParser {
     }
 }
 
+/// add 16-bit big-endian words of data to a ones' complement sum
+fn ones_complement_sum(mut sum: u64, data: &[u8]) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u64;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u64) << 8;
+    }
+    sum
+}
+
+/// whether a ones' complement sum including the checksum field is valid
+fn checksum_valid(mut sum: u64) -> bool {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum == 0xffff
+}
+
+/// whether the TCP or UDP checksum of segment (header and payload) is valid
+pub fn transport_checksum_valid(
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    proto: IpNumber,
+    segment: &[u8],
+) -> bool {
+    let mut sum = match (src_addr, dst_addr) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            if proto == IpNumber::UDP && segment.get(6..8) == Some(&[0, 0]) {
+                // checksum not computed by sender
+                return true;
+            }
+            ones_complement_sum(ones_complement_sum(0, &src.octets()), &dst.octets())
+        }
+        (src, dst) => {
+            let octets = |addr: IpAddr| match addr {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+                IpAddr::V6(v6) => v6.octets(),
+            };
+            ones_complement_sum(ones_complement_sum(0, &octets(src)), &octets(dst))
+        }
+    };
+    // rest of pseudo header
+    let len = segment.len() as u64;
+    sum += (len >> 16) + (len & 0xffff) + proto.0 as u64;
+    checksum_valid(ones_complement_sum(sum, segment))
+}
+
+/// identification field of IPv4 header, 0 for IPv6
+fn ip_id(internet_slice: &NetSlice<'_>) -> u16 {
+    match internet_slice {
+        NetSlice::Ipv4(v4) => v4.header().identification(),
+        NetSlice::Ipv6(_) => 0,
+    }
+}
+
+/// TTL of IPv4 header or hop limit of IPv6 header
+fn ip_ttl(internet_slice: &NetSlice<'_>) -> u8 {
+    match internet_slice {
+        NetSlice::Ipv4(v4) => v4.header().ttl(),
+        NetSlice::Ipv6(v6) => v6.header().hop_limit(),
+    }
+}
+
+/// ECN field of IP header
+fn ip_ecn(internet_slice: &NetSlice<'_>) -> u8 {
+    match internet_slice {
+        NetSlice::Ipv4(v4) => v4.header().ecn().value(),
+        NetSlice::Ipv6(v6) => v6.header().traffic_class() & 0b11,
+    }
+}
+
+/// TCP option kind of TCP Fast Open cookie (RFC 7413)
+pub const TCP_OPTION_TFO: u8 = 34;
+/// experimental option kind used by TCP Fast Open before assignment
+pub const TCP_OPTION_EXPERIMENTAL: u8 = 254;
+/// magic number identifying TCP Fast Open in the experimental option
+pub const TFO_EXPERIMENTAL_MAGIC: [u8; 2] = [0xf9, 0x89];
+
+/// find TCP Fast Open cookie in raw TCP options
+pub fn find_tfo_cookie(mut options: &[u8]) -> Option<Vec<u8>> {
+    while let Some(&kind) = options.first() {
+        match kind {
+            // end of option list
+            0 => break,
+            // no-op
+            1 => {
+                options = &options[1..];
+                continue;
+            }
+            _ => {}
+        }
+        let len = *options.get(1)? as usize;
+        if len < 2 || len > options.len() {
+            // malformed
+            return None;
+        }
+        let value = &options[2..len];
+        match kind {
+            TCP_OPTION_TFO => return Some(value.to_vec()),
+            TCP_OPTION_EXPERIMENTAL if value.starts_with(&TFO_EXPERIMENTAL_MAGIC) => {
+                return Some(value[2..].to_vec());
+            }
+            _ => {}
+        }
+        options = &options[len..];
+    }
+    None
+}
+
 /// layer of input packets
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ParseLayer {
     /// link layer (layer 2)
     Link,
@@ -120,3 +713,218 @@ pub enum ParseLayer {
     /// BSD loopback (linktype 0/NULL)
     BsdLoopback,
 }
+
+#[cfg(test)]
+mod test {
+    use etherparse::{
+        IpFragOffset, IpNumber, Ipv4Header, PacketBuilder, TcpOptionElement, VlanId,
+    };
+
+    use super::{find_tfo_cookie, ChecksumPolicy, ParseLayer, ParsedPacket, TcpParser};
+    use crate::pcap_writer::RawFrame;
+
+    fn tcp_packet(payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .tcp(1234, 80, 1000, 512)
+            .write(&mut out, payload)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn vlan() {
+        let mut packet = Vec::new();
+        PacketBuilder::ethernet2([1; 6], [2; 6])
+            .double_vlan(VlanId::try_new(100).unwrap(), VlanId::try_new(200).unwrap())
+            .ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .tcp(1234, 80, 1000, 512)
+            .write(&mut packet, b"test")
+            .unwrap();
+
+        let mut parser = TcpParser::new();
+        let (meta, data) = parser.parse_packet(&packet).unwrap();
+        assert_eq!(meta.vlan_id, Some(100));
+        assert_eq!(meta.dst_port, 80);
+        assert_eq!(data, b"test");
+
+        parser.max_encapsulation_depth = 1;
+        assert!(parser.parse_packet(&packet).is_none());
+        assert_eq!(parser.ignored, 1);
+    }
+
+    #[test]
+    fn mpls() {
+        let mut packet = vec![2; 6];
+        packet.extend_from_slice(&[1; 6]);
+        packet.extend_from_slice(&0x8847u16.to_be_bytes());
+        // two labels, second has bottom of stack set
+        packet.extend_from_slice(&[0x00, 0x01, 0x00, 0x40]);
+        packet.extend_from_slice(&[0x00, 0x02, 0x01, 0x40]);
+        packet.extend_from_slice(&tcp_packet(b"test"));
+
+        let mut parser = TcpParser::new();
+        let (meta, data) = parser.parse_packet(&packet).unwrap();
+        assert_eq!(meta.vlan_id, None);
+        assert_eq!(meta.src_port, 1234);
+        assert_eq!(data, b"test");
+    }
+
+    #[test]
+    fn fragmented_frames() {
+        let packet = tcp_packet(b"split across two fragments");
+        let tcp = &packet[20..];
+        let fragment = |offset: usize, data: &[u8], more_fragments: bool| {
+            let mut header = Ipv4Header::new(
+                data.len() as u16,
+                64,
+                IpNumber::TCP,
+                [10, 0, 0, 1],
+                [10, 0, 0, 2],
+            )
+            .unwrap();
+            header.identification = 7;
+            header.more_fragments = more_fragments;
+            header.fragment_offset = IpFragOffset::try_new(offset as u16 / 8).unwrap();
+            let mut out = Vec::new();
+            header.write(&mut out).unwrap();
+            out.extend_from_slice(data);
+            out
+        };
+        let first = fragment(0, &tcp[..24], true);
+        let second = fragment(24, &tcp[24..], false);
+        let frame = |ts_sec, data: &[u8]| RawFrame {
+            linktype: 101,
+            ts_sec,
+            ts_usec: 0,
+            orig_len: data.len() as u32,
+            data: data.to_vec(),
+        };
+
+        let mut parser = TcpParser::new();
+        parser.layer = ParseLayer::IP;
+        let mut frames = vec![frame(1, &first)];
+        assert!(parser
+            .parse_packet_frames(&first, false, &mut frames)
+            .is_none());
+        let mut frames = vec![frame(2, &second)];
+        let Some(ParsedPacket::Tcp(meta, data)) =
+            parser.parse_packet_frames(&second, false, &mut frames)
+        else {
+            panic!("expected reassembled tcp packet");
+        };
+        assert_eq!(meta.dst_port, 80);
+        assert_eq!(data, b"split across two fragments");
+        assert_eq!(frames, [frame(1, &first), frame(2, &second)]);
+        assert_eq!(parser.fragments.buffered_bytes, 0);
+
+        // unfragmented packets keep their own frame
+        let mut frames = vec![frame(3, &packet)];
+        assert!(parser
+            .parse_packet_frames(&packet, false, &mut frames)
+            .is_some());
+        assert_eq!(frames, [frame(3, &packet)]);
+    }
+
+    #[test]
+    fn udp() {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .udp(5353, 53)
+            .write(&mut packet, b"query")
+            .unwrap();
+
+        let mut parser = TcpParser::new();
+        parser.layer = super::ParseLayer::IP;
+        assert!(parser.parse_packet(&packet).is_none());
+        assert_eq!(parser.ignored, 1);
+        let Some(ParsedPacket::Udp(meta, data)) = parser.parse_packet_any(&packet) else {
+            panic!("expected udp packet");
+        };
+        assert_eq!((meta.src_port, meta.dst_port), (5353, 53));
+        assert_eq!(data, b"query");
+    }
+
+    #[test]
+    fn ecn_and_urgent() {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .tcp(1234, 80, 1000, 512)
+            .psh()
+            .urg(3)
+            .ece()
+            .cwr()
+            .options(&[TcpOptionElement::MaximumSegmentSize(1460)])
+            .unwrap()
+            .write(&mut packet, b"test")
+            .unwrap();
+        // mark as congestion experienced
+        packet[1] |= 0b11;
+
+        let mut parser = TcpParser::new();
+        parser.layer = super::ParseLayer::IP;
+        let (meta, _) = parser.parse_packet(&packet).unwrap();
+        assert!(meta.flags.psh && meta.flags.urg && meta.flags.ece && meta.flags.cwr);
+        assert_eq!(meta.urgent_pointer, 3);
+        assert_eq!(meta.option_mss, Some(1460));
+        assert_eq!(meta.ip_ecn, 3);
+    }
+
+    #[test]
+    fn checksums() {
+        let mut parser = TcpParser::new();
+        parser.layer = super::ParseLayer::IP;
+        parser.checksum_policy = ChecksumPolicy::Annotate;
+        let mut packet = tcp_packet(b"test");
+        let (meta, _) = parser.parse_packet(&packet).unwrap();
+        assert!(!meta.bad_checksum);
+        let mut v6 = Vec::new();
+        PacketBuilder::ipv6([1; 16], [2; 16], 64)
+            .udp(5353, 53)
+            .write(&mut v6, b"odd")
+            .unwrap();
+        let Some(ParsedPacket::Udp(meta, _)) = parser.parse_packet_any(&v6) else {
+            panic!("expected udp packet");
+        };
+        assert!(!meta.bad_checksum);
+
+        // corrupt payload
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        let (meta, _) = parser.parse_packet(&packet).unwrap();
+        assert!(meta.bad_checksum);
+        assert_eq!(parser.bad_transport_checksum, 1);
+        // corrupt TTL
+        packet[last] ^= 1;
+        packet[8] -= 1;
+        assert!(parser.parse_packet(&packet).unwrap().0.bad_checksum);
+        assert_eq!(parser.bad_ip_checksum, 1);
+
+        parser.checksum_policy = ChecksumPolicy::Drop;
+        assert!(parser.parse_packet(&packet).is_none());
+        parser.checksum_policy = ChecksumPolicy::Ignore;
+        assert!(!parser.parse_packet(&packet).unwrap().0.bad_checksum);
+        assert_eq!(parser.bad_ip_checksum, 2);
+    }
+
+    #[test]
+    fn tfo_cookie() {
+        // MSS, NOP, NOP, TFO with 8 byte cookie
+        let options = [2, 4, 5, 180, 1, 1, 34, 10, 1, 2, 3, 4, 5, 6, 7, 8, 0, 0];
+        assert_eq!(
+            find_tfo_cookie(&options),
+            Some(vec![1, 2, 3, 4, 5, 6, 7, 8])
+        );
+        // cookie request
+        assert_eq!(find_tfo_cookie(&[1, 34, 2, 0]), Some(vec![]));
+        // experimental option
+        assert_eq!(
+            find_tfo_cookie(&[254, 8, 0xf9, 0x89, 9, 9, 9, 9]),
+            Some(vec![9; 4])
+        );
+        assert_eq!(find_tfo_cookie(&[254, 4, 0x12, 0x34]), None);
+        // truncated
+        assert_eq!(find_tfo_cookie(&[34, 10, 1]), None);
+        assert_eq!(find_tfo_cookie(&[2, 4, 5, 180]), None);
+    }
+}
@@ -0,0 +1,195 @@
+//! Recovery from damaged legacy pcap files
+//!
+//! Record headers carry no magic number, so a damaged record cannot be told
+//! apart from packet data directly. Records are instead checked for plausible
+//! fields, and after damage the next record is found by scanning for a
+//! plausible header which is followed by another plausible header, or by the
+//! end of the file.
+
+use pcap_parser::PcapHeader;
+
+use crate::pcap_writer::PCAP_SNAPLEN;
+
+/// length of legacy pcap record headers
+pub const RECORD_HEADER_LEN: usize = 16;
+/// largest change in timestamp between records considered plausible, in
+/// seconds
+pub const RESYNC_MAX_TIME_JUMP: u32 = 24 * 60 * 60;
+
+/// fields of a record header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordHeader {
+    pub ts_sec: u32,
+    /// microseconds or nanoseconds, depending on the file header
+    pub ts_frac: u32,
+    pub caplen: u32,
+    pub origlen: u32,
+}
+
+/// result of scanning for the next record
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resync {
+    /// plausible record starts at offset
+    Found(usize),
+    /// plausible record starts at offset, but data up to the header
+    /// following it is needed to confirm
+    Unconfirmed(usize),
+    /// no record starts before offset, more data is needed to continue
+    Skip(usize),
+}
+
+/// checks records of one legacy pcap file
+#[derive(Clone, Debug)]
+pub struct RecordCheck {
+    big_endian: bool,
+    /// upper limit of fractional timestamp
+    frac_limit: u32,
+    /// largest plausible captured length
+    max_caplen: u32,
+    /// timestamp of the last accepted record
+    pub last_ts_sec: Option<u32>,
+}
+
+impl RecordCheck {
+    /// create from file header
+    pub fn new(header: &PcapHeader) -> Self {
+        RecordCheck {
+            big_endian: header.is_bigendian(),
+            frac_limit: if header.is_nanosecond_precision() {
+                1_000_000_000
+            } else {
+                1_000_000
+            },
+            // some writers record a smaller snaplen than they capture with
+            max_caplen: header.snaplen.max(PCAP_SNAPLEN),
+            last_ts_sec: None,
+        }
+    }
+
+    /// read record header at start of data
+    pub fn read_header(&self, data: &[u8]) -> Option<RecordHeader> {
+        let field = |i: usize| {
+            let bytes: [u8; 4] = data[i * 4..i * 4 + 4].try_into().unwrap();
+            if self.big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        if data.len() < RECORD_HEADER_LEN {
+            return None;
+        }
+        Some(RecordHeader {
+            ts_sec: field(0),
+            ts_frac: field(1),
+            caplen: field(2),
+            origlen: field(3),
+        })
+    }
+
+    /// whether header fields are plausible for a record following one with
+    /// timestamp `last_ts_sec`
+    fn plausible_after(&self, header: &RecordHeader, last_ts_sec: Option<u32>) -> bool {
+        header.ts_frac < self.frac_limit
+            && header.caplen <= self.max_caplen
+            && header.origlen >= header.caplen
+            && last_ts_sec.is_none_or(|last| last.abs_diff(header.ts_sec) <= RESYNC_MAX_TIME_JUMP)
+    }
+
+    /// whether header fields are plausible
+    pub fn plausible(&self, header: &RecordHeader) -> bool {
+        self.plausible_after(header, self.last_ts_sec)
+    }
+
+    /// check record header, remembering its timestamp if plausible
+    pub fn accept(&mut self, header: &RecordHeader) -> bool {
+        if self.plausible(header) {
+            self.last_ts_sec = Some(header.ts_sec);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// find the next plausible record in data, `at_eof` if data extends to
+    /// the end of the file
+    pub fn find_record(&self, data: &[u8], at_eof: bool) -> Resync {
+        for start in 0..data.len() {
+            let Some(header) = self.read_header(&data[start..]) else {
+                // too short for a header
+                return Resync::Skip(if at_eof { data.len() } else { start });
+            };
+            if !self.plausible(&header) {
+                continue;
+            }
+            let next = start + RECORD_HEADER_LEN + header.caplen as usize;
+            let confirmed = match self.read_header(data.get(next..).unwrap_or_default()) {
+                Some(following) => self.plausible_after(&following, Some(header.ts_sec)),
+                // record ends exactly at end of file
+                None if at_eof => next == data.len(),
+                None => return Resync::Unconfirmed(start),
+            };
+            if confirmed {
+                return Resync::Found(start);
+            }
+        }
+        Resync::Skip(data.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pcap_parser::PcapHeader;
+
+    use super::{RecordCheck, RecordHeader, Resync};
+
+    fn record(ts_sec: u32, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for field in [ts_sec, 0, data.len() as u32, data.len() as u32] {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+        out.extend_from_slice(data);
+        out
+    }
+
+    #[test]
+    fn find_record() {
+        let mut header = PcapHeader::new();
+        header.snaplen = 65535;
+        let mut check = RecordCheck::new(&header);
+        assert!(check.accept(&RecordHeader {
+            ts_sec: 1_700_000_000,
+            ts_frac: 0,
+            caplen: 60,
+            origlen: 60,
+        }));
+        assert!(!check.plausible(&RecordHeader {
+            ts_sec: 1_700_000_000,
+            ts_frac: 0,
+            caplen: 0x4500_0000,
+            origlen: 0x4500_0000,
+        }));
+        assert!(!check.plausible(&RecordHeader {
+            ts_sec: 12,
+            ts_frac: 0,
+            caplen: 60,
+            origlen: 60,
+        }));
+
+        // garbage followed by two records
+        let mut data = vec![0xff; 37];
+        data.extend(record(1_700_000_001, &[0x45; 40]));
+        data.extend(record(1_700_000_002, &[0x45; 20]));
+        assert_eq!(check.find_record(&data, true), Resync::Found(37));
+        assert_eq!(
+            check.find_record(&data[..data.len() - 30], false),
+            Resync::Unconfirmed(37)
+        );
+        // second record alone ends at end of file
+        let second = data.len() - 36;
+        assert_eq!(check.find_record(&data[second..], true), Resync::Found(0));
+        // trailing bytes too short for a header
+        assert_eq!(check.find_record(&[0xff; 40], false), Resync::Skip(25));
+        assert_eq!(check.find_record(&[0xff; 40], true), Resync::Skip(40));
+    }
+}
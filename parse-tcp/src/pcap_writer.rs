@@ -0,0 +1,98 @@
+use std::io::{self, Write};
+
+/// magic number of legacy pcap files (microsecond timestamps)
+pub const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+/// default snapshot length written to pcap headers
+pub const PCAP_SNAPLEN: u32 = 262144;
+
+/// packet as captured, kept to be written out again
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawFrame {
+    /// pcap link type of the capture
+    pub linktype: u32,
+    /// timestamp (seconds)
+    pub ts_sec: u32,
+    /// timestamp (microseconds)
+    pub ts_usec: u32,
+    /// original length of packet on the wire
+    pub orig_len: u32,
+    /// captured packet data
+    pub data: Vec<u8>,
+}
+
+/// writes packets in legacy pcap format
+pub struct PcapWriter<W: Write> {
+    pub writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// create writer, writing the file header
+    pub fn new(mut writer: W, linktype: u32) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        header[0..4].copy_from_slice(&PCAP_MAGIC.to_le_bytes());
+        // version 2.4
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        // thiszone and sigfigs are left as zero
+        header[16..20].copy_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header[20..24].copy_from_slice(&linktype.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(PcapWriter { writer })
+    }
+
+    /// write a packet record
+    pub fn write_packet(
+        &mut self,
+        ts_sec: u32,
+        ts_usec: u32,
+        orig_len: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(&ts_sec.to_le_bytes());
+        header[4..8].copy_from_slice(&ts_usec.to_le_bytes());
+        header[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&orig_len.to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(data)
+    }
+
+    /// write a captured frame
+    pub fn write_frame(&mut self, frame: &RawFrame) -> io::Result<()> {
+        self.write_packet(frame.ts_sec, frame.ts_usec, frame.orig_len, &frame.data)
+    }
+
+    /// flush underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pcap_parser::traits::PcapReaderIterator;
+    use pcap_parser::{LegacyPcapReader, Linktype, PcapBlockOwned};
+
+    use super::PcapWriter;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = PcapWriter::new(Vec::new(), 1).unwrap();
+        writer.write_packet(10, 20, 4, b"test").unwrap();
+        let buf = writer.writer;
+
+        let mut reader = LegacyPcapReader::new(1 << 16, &buf[..]).unwrap();
+        let (offset, block) = reader.next().unwrap();
+        let PcapBlockOwned::LegacyHeader(header) = block else {
+            panic!("expected header");
+        };
+        assert_eq!(header.network, Linktype::ETHERNET);
+        reader.consume(offset);
+        let (_, block) = reader.next().unwrap();
+        let PcapBlockOwned::Legacy(packet) = block else {
+            panic!("expected packet");
+        };
+        assert_eq!((packet.ts_sec, packet.ts_usec), (10, 20));
+        assert_eq!(packet.data, b"test");
+    }
+}
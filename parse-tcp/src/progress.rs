@@ -0,0 +1,351 @@
+//! Progress reporting for reading captures
+//!
+//! Inputs are wrapped in a `CountingReader`, which adds bytes read to a shared
+//! `ProgressCounter`. A `ProgressReporter` thread compares the counter with
+//! the total size of the inputs and periodically logs progress with rate and
+//! estimated time remaining, or redraws a progress bar on stderr. The bar is
+//! drawn by indicatif if the `progress-bar` feature is enabled.
+
+use std::fmt;
+#[cfg(not(feature = "progress-bar"))]
+use std::io::Write;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "progress-bar")]
+use indicatif::{ProgressBar, ProgressStyle};
+use parking_lot::{Condvar, Mutex};
+use tracing::info;
+
+/// default interval between progress log messages
+pub const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// interval between progress bar redraws
+const BAR_REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+/// number of characters in progress bar
+#[cfg(not(feature = "progress-bar"))]
+const BAR_WIDTH: usize = 30;
+/// indicatif template of progress bar if the total size is known
+#[cfg(feature = "progress-bar")]
+const BAR_TEMPLATE: &str = "[{bar:30}] {binary_bytes} of {binary_total_bytes} ({percent}%), \
+    {binary_bytes_per_sec}, ETA {eta}";
+/// indicatif template of progress bar if the total size is unknown
+#[cfg(feature = "progress-bar")]
+const SPINNER_TEMPLATE: &str = "{spinner} {binary_bytes}, {binary_bytes_per_sec}";
+
+/// bytes consumed from inputs, shared between readers and reporter
+#[derive(Clone, Debug, Default)]
+pub struct ProgressCounter(Arc<AtomicU64>);
+
+impl ProgressCounter {
+    /// create new instance at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// bytes consumed so far
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// wrap reader to count bytes read from it
+    pub fn wrap<R: Read>(&self, inner: R) -> CountingReader<R> {
+        CountingReader {
+            inner,
+            counter: self.clone(),
+        }
+    }
+}
+
+/// reader adding bytes read to a counter
+pub struct CountingReader<R: Read> {
+    inner: R,
+    counter: ProgressCounter,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.counter.0.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+/// format byte count with binary prefix
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{value:.0} B")
+    } else {
+        format!("{value:.2} {}", UNITS[unit])
+    }
+}
+
+/// format duration as hours, minutes and seconds
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, s) => format!("{h}h{m:02}m{s:02}s"),
+    }
+}
+
+/// progress at one point in time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProgressState {
+    /// bytes consumed
+    pub consumed: u64,
+    /// total size of inputs, if known
+    pub total: Option<u64>,
+    /// time since reading started
+    pub elapsed: Duration,
+}
+
+impl ProgressState {
+    /// fraction of inputs consumed
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(total) if total > 0 => Some((self.consumed as f64 / total as f64).min(1.0)),
+            _ => None,
+        }
+    }
+
+    /// average rate in bytes per second
+    pub fn rate(&self) -> Option<f64> {
+        let secs = self.elapsed.as_secs_f64();
+        (secs > 0.0).then(|| self.consumed as f64 / secs)
+    }
+
+    /// estimated time until all inputs are consumed, if not yet done
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = self.total?.saturating_sub(self.consumed);
+        if remaining == 0 {
+            return None;
+        }
+        let rate = self.rate().filter(|rate| *rate > 0.0)?;
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
+impl fmt::Display for ProgressState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_bytes(self.consumed as f64))?;
+        if let (Some(total), Some(fraction)) = (self.total, self.fraction()) {
+            write!(
+                f,
+                " of {} ({:.1}%)",
+                format_bytes(total as f64),
+                fraction * 100.0
+            )?;
+        }
+        if let Some(rate) = self.rate() {
+            write!(f, ", {}/s", format_bytes(rate))?;
+        }
+        if let Some(eta) = self.eta() {
+            write!(f, ", ETA {}", format_duration(eta))?;
+        }
+        Ok(())
+    }
+}
+
+/// how progress is reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// log progress at interval
+    Log(Duration),
+    /// redraw progress bar on stderr
+    Bar,
+}
+
+/// stop flag of reporter thread
+type StopSignal = Arc<(Mutex<bool>, Condvar)>;
+
+/// reports progress from a background thread until dropped
+pub struct ProgressReporter {
+    stop: StopSignal,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ProgressReporter {
+    /// start reporting progress of counter against `total` bytes
+    pub fn start(counter: ProgressCounter, total: Option<u64>, mode: ProgressMode) -> Self {
+        let stop: StopSignal = Default::default();
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+            .name("progress".into())
+            .spawn(move || report(counter, total, mode, thread_stop))
+            .expect("failed to spawn progress thread");
+        ProgressReporter {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        let (stopped, condvar) = &*self.stop;
+        *stopped.lock() = true;
+        condvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// progress bar on stderr
+#[cfg(feature = "progress-bar")]
+struct Bar(ProgressBar);
+
+#[cfg(feature = "progress-bar")]
+impl Bar {
+    fn new(total: Option<u64>) -> Self {
+        let (bar, template) = match total {
+            Some(total) => (ProgressBar::new(total), BAR_TEMPLATE),
+            None => (ProgressBar::new_spinner(), SPINNER_TEMPLATE),
+        };
+        let style = ProgressStyle::with_template(template)
+            .expect("invalid progress bar template")
+            .progress_chars("#-");
+        Bar(bar.with_style(style))
+    }
+
+    fn draw(&self, state: &ProgressState) {
+        self.0.set_position(state.consumed);
+    }
+
+    fn finish(&self) {
+        self.0.finish();
+    }
+}
+
+/// progress bar on stderr
+#[cfg(not(feature = "progress-bar"))]
+struct Bar;
+
+#[cfg(not(feature = "progress-bar"))]
+impl Bar {
+    fn new(_total: Option<u64>) -> Self {
+        Bar
+    }
+
+    /// draw progress bar over the current line
+    fn draw(&self, state: &ProgressState) {
+        let mut line = String::from("\r");
+        if let Some(fraction) = state.fraction() {
+            let filled = (fraction * BAR_WIDTH as f64) as usize;
+            line += &format!(
+                "[{}{}] ",
+                "#".repeat(filled),
+                "-".repeat(BAR_WIDTH - filled)
+            );
+        }
+        line += &format!("{state}\x1b[K");
+        let mut stderr = io::stderr().lock();
+        let _ = stderr.write_all(line.as_bytes());
+        let _ = stderr.flush();
+    }
+
+    fn finish(&self) {
+        eprintln!();
+    }
+}
+
+fn report(counter: ProgressCounter, total: Option<u64>, mode: ProgressMode, stop: StopSignal) {
+    let start = Instant::now();
+    let interval = match mode {
+        ProgressMode::Log(interval) => interval,
+        ProgressMode::Bar => BAR_REDRAW_INTERVAL,
+    };
+    let bar = (mode == ProgressMode::Bar).then(|| Bar::new(total));
+    let (stopped, condvar) = &*stop;
+    let mut guard = stopped.lock();
+    loop {
+        let deadline = Instant::now() + interval;
+        while !*guard && !condvar.wait_until(&mut guard, deadline).timed_out() {}
+        let state = ProgressState {
+            consumed: counter.get(),
+            total,
+            elapsed: start.elapsed(),
+        };
+        match mode {
+            ProgressMode::Log(_) if *guard => {
+                info!(
+                    "read {} in {}",
+                    format_bytes(state.consumed as f64),
+                    format_duration(state.elapsed)
+                );
+            }
+            ProgressMode::Log(_) => info!("progress: {state}"),
+            ProgressMode::Bar => {
+                let bar = bar.as_ref().unwrap();
+                bar.draw(&state);
+                if *guard {
+                    bar.finish();
+                }
+            }
+        }
+        if *guard {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::time::Duration;
+
+    #[cfg(feature = "progress-bar")]
+    use super::Bar;
+    use super::{ProgressCounter, ProgressState};
+
+    #[test]
+    fn counting() {
+        let counter = ProgressCounter::new();
+        let mut reader = counter.wrap(&[0u8; 3000][..]);
+        let mut buf = [0u8; 1024];
+        while reader.read(&mut buf).unwrap() > 0 {}
+        assert_eq!(counter.get(), 3000);
+
+        let state = ProgressState {
+            consumed: 3 << 30,
+            total: Some(12 << 30),
+            elapsed: Duration::from_secs(30),
+        };
+        assert_eq!(state.eta(), Some(Duration::from_secs(90)));
+        assert_eq!(
+            state.to_string(),
+            "3.00 GiB of 12.00 GiB (25.0%), 102.40 MiB/s, ETA 1m30s"
+        );
+        let state = ProgressState {
+            consumed: 500,
+            total: None,
+            elapsed: Duration::ZERO,
+        };
+        assert_eq!(state.to_string(), "500 B");
+    }
+
+    #[cfg(feature = "progress-bar")]
+    #[test]
+    fn bar_templates() {
+        for total in [Some(1000), None] {
+            let bar = Bar::new(total);
+            bar.draw(&ProgressState {
+                consumed: 500,
+                total,
+                elapsed: Duration::from_secs(1),
+            });
+            bar.finish();
+        }
+    }
+}
@@ -0,0 +1,555 @@
+//! Aggregate reports over directory output
+//!
+//! Reads `connections.json` and the segment files written by
+//! `DirectoryOutputHandler` and summarizes all connections: hosts sending and
+//! receiving the most data, distributions of connection duration, goodput and
+//! retransmit rate, and bytes captured per time interval.
+//!
+//! Counters recorded in `connections.json` at retire are used if present,
+//! otherwise they are counted from segment files, which lack packets that
+//! carried no data or acknowledged nothing new.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::output_dir::{read_connections, read_segments};
+use crate::serialized::{ConnInfo, SerializedSegment};
+use crate::stats::StreamStats;
+
+/// default number of hosts listed as top talkers
+pub const REPORT_TOP_TALKERS: usize = 10;
+/// default length of throughput intervals, in microseconds
+pub const REPORT_INTERVAL_US: u64 = 1_000_000;
+
+/// upper bounds of connection duration buckets, in seconds
+const DURATION_BOUNDS: [f64; 7] = [0.001, 0.01, 0.1, 1.0, 10.0, 100.0, 1000.0];
+/// upper bounds of goodput buckets, in bytes per second
+const GOODPUT_BOUNDS: [f64; 6] = [1e3, 1e4, 1e5, 1e6, 1e7, 1e8];
+/// upper bounds of retransmit rate buckets, as a fraction of payload bytes
+const RETRANSMIT_BOUNDS: [f64; 5] = [0.001, 0.01, 0.05, 0.1, 0.25];
+
+/// values within [lower, upper), the last bucket has no upper bound
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    pub lower: f64,
+    pub upper: Option<f64>,
+    pub count: u64,
+}
+
+/// distribution of a per-connection value
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Distribution {
+    /// number of connections with a value
+    pub count: u64,
+    pub min: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+    pub histogram: Vec<HistogramBucket>,
+}
+
+impl Distribution {
+    /// compute from values, with buckets split at `bounds`
+    fn new(mut values: Vec<f64>, bounds: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        // nearest-rank percentile
+        let percentile = |p: f64| {
+            let rank = (p * values.len() as f64).ceil() as usize;
+            values[rank.clamp(1, values.len()) - 1]
+        };
+        let mut histogram: Vec<HistogramBucket> = [0.0]
+            .iter()
+            .chain(bounds)
+            .zip(bounds.iter().map(|b| Some(*b)).chain([None]))
+            .map(|(lower, upper)| HistogramBucket {
+                lower: *lower,
+                upper,
+                count: 0,
+            })
+            .collect();
+        for value in &values {
+            let bucket = bounds.partition_point(|bound| bound <= value);
+            histogram[bucket].count += 1;
+        }
+        Some(Distribution {
+            count: values.len() as u64,
+            min: values[0],
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: values[values.len() - 1],
+            histogram,
+        })
+    }
+}
+
+/// data sent and received by one host
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Talker {
+    pub addr: IpAddr,
+    /// connections the host took part in
+    pub connections: u64,
+    /// payload bytes sent, including retransmissions
+    pub bytes_sent: u64,
+    /// payload bytes received, including retransmissions
+    pub bytes_received: u64,
+}
+
+/// payload bytes captured in one interval
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThroughputSample {
+    /// start of interval, in microseconds since epoch
+    pub start_us: u64,
+    pub bytes: u64,
+}
+
+/// report over all connections of directory output
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DirectoryReport {
+    /// TCP connections
+    pub connections: u64,
+    /// UDP flows
+    pub udp_flows: u64,
+    /// totals over both directions of all connections and flows
+    pub total: StreamStats,
+    /// fraction of TCP payload bytes which were retransmitted
+    pub retransmit_rate: Option<f64>,
+    /// hosts ordered by bytes sent and received
+    pub top_talkers: Vec<Talker>,
+    /// connection duration, in seconds
+    pub durations: Option<Distribution>,
+    /// average goodput of connections over both directions, in bytes per
+    /// second
+    pub goodput: Option<Distribution>,
+    /// fraction of retransmitted payload bytes of connections which sent data
+    pub retransmit_rates: Option<Distribution>,
+    /// length of throughput intervals, in microseconds
+    pub interval_us: u64,
+    /// payload bytes captured per interval, including retransmissions
+    pub throughput: Vec<ThroughputSample>,
+}
+
+/// count stream statistics of both directions from segment files
+fn stats_from_segments(segments: [&[SerializedSegment]; 2]) -> [StreamStats; 2] {
+    let mut stats = [StreamStats::default(), StreamStats::default()];
+    for (i, segments) in segments.into_iter().enumerate() {
+        for segment in segments {
+            match segment {
+                SerializedSegment::Data {
+                    len, is_retransmit, ..
+                } => {
+                    let s = &mut stats[i];
+                    s.packets += 1;
+                    s.bytes += *len as u64;
+                    if *is_retransmit {
+                        s.retransmits += 1;
+                        s.retransmit_bytes += *len as u64;
+                    } else {
+                        s.goodput_bytes += *len as u64;
+                    }
+                }
+                SerializedSegment::Datagram { len, .. } => {
+                    stats[i].add(&StreamStats::datagrams(1, *len as u64));
+                }
+                // ack records of a stream were sent by the other side
+                SerializedSegment::Ack { .. } => stats[1 - i].packets += 1,
+                SerializedSegment::Fin { .. } => {
+                    stats[i].packets += 1;
+                    stats[i].fin_count += 1;
+                }
+                SerializedSegment::Rst { .. } => {
+                    stats[i].packets += 1;
+                    stats[i].rst_count += 1;
+                }
+                SerializedSegment::Gap { len, .. } => stats[i].gap_bytes += len,
+                // the late packet is also recorded as a data segment
+                SerializedSegment::LateData { len, .. } => {
+                    stats[i].gap_bytes = stats[i].gap_bytes.saturating_sub(*len as u64);
+                }
+            }
+        }
+    }
+    stats
+}
+
+/// add payload bytes of segments to their intervals
+fn add_throughput(segments: &[SerializedSegment], interval_us: u64, out: &mut BTreeMap<u64, u64>) {
+    for segment in segments {
+        let (len, extra) = match segment {
+            SerializedSegment::Data { len, extra, .. }
+            | SerializedSegment::Datagram { len, extra, .. } => (len, extra),
+            _ => continue,
+        };
+        if let Some(time) = extra.timestamp() {
+            let interval = time.as_micros() as u64 / interval_us;
+            *out.entry(interval).or_default() += *len as u64;
+        }
+    }
+}
+
+impl DirectoryReport {
+    /// read directory output and compute report, listing `top_talkers` hosts
+    /// and counting throughput over intervals of `interval_us`
+    pub fn from_dir(dir: &Path, top_talkers: usize, interval_us: u64) -> io::Result<Self> {
+        assert!(interval_us > 0, "interval must not be zero");
+        let connections = read_connections(dir)?;
+        let mut report = DirectoryReport {
+            connections: 0,
+            udp_flows: 0,
+            total: StreamStats::default(),
+            retransmit_rate: None,
+            top_talkers: Vec::new(),
+            durations: None,
+            goodput: None,
+            retransmit_rates: None,
+            interval_us,
+            throughput: Vec::new(),
+        };
+        let mut talkers: HashMap<IpAddr, Talker> = HashMap::new();
+        let (mut durations, mut goodput, mut retransmit_rates) =
+            (Vec::new(), Vec::new(), Vec::new());
+        let mut tcp_total = StreamStats::default();
+        let mut intervals = BTreeMap::new();
+
+        for info in &connections {
+            let forward = read_segments(dir, info.id, "f")?.unwrap_or_default();
+            let reverse = read_segments(dir, info.id, "r")?.unwrap_or_default();
+            add_throughput(&forward, interval_us, &mut intervals);
+            add_throughput(&reverse, interval_us, &mut intervals);
+            let [forward, reverse] = match (&info.forward_stats, &info.reverse_stats) {
+                (Some(forward), Some(reverse)) => [forward.clone(), reverse.clone()],
+                _ => {
+                    debug!("no counters for connection {}, reading segments", info.id);
+                    stats_from_segments([&forward, &reverse])
+                }
+            };
+            report.add_connection(info, &forward, &reverse, &mut talkers);
+
+            let mut both = forward;
+            both.add(&reverse);
+            if info.udp {
+                report.udp_flows += 1;
+            } else {
+                report.connections += 1;
+                tcp_total.add(&both);
+                if both.bytes > 0 {
+                    retransmit_rates.push(both.retransmit_bytes as f64 / both.bytes as f64);
+                }
+            }
+            if let (Some(first), Some(last)) = (info.first_packet_us, info.last_packet_us) {
+                let duration_us = last.saturating_sub(first);
+                durations.push(duration_us as f64 / 1e6);
+                if duration_us > 0 {
+                    goodput.push(both.goodput_bytes as f64 * 1e6 / duration_us as f64);
+                }
+            }
+        }
+
+        if tcp_total.bytes > 0 {
+            report.retransmit_rate =
+                Some(tcp_total.retransmit_bytes as f64 / tcp_total.bytes as f64);
+        }
+        let mut talkers: Vec<Talker> = talkers.into_values().collect();
+        talkers.sort_by(|a, b| {
+            (b.bytes_sent + b.bytes_received)
+                .cmp(&(a.bytes_sent + a.bytes_received))
+                .then(a.addr.cmp(&b.addr))
+        });
+        talkers.truncate(top_talkers);
+        report.top_talkers = talkers;
+        report.durations = Distribution::new(durations, &DURATION_BOUNDS);
+        report.goodput = Distribution::new(goodput, &GOODPUT_BOUNDS);
+        report.retransmit_rates = Distribution::new(retransmit_rates, &RETRANSMIT_BOUNDS);
+        // include empty intervals between first and last
+        if let (Some(first), Some(last)) = (intervals.keys().next(), intervals.keys().next_back()) {
+            report.throughput = (*first..=*last)
+                .map(|interval| ThroughputSample {
+                    start_us: interval * interval_us,
+                    bytes: intervals.get(&interval).copied().unwrap_or(0),
+                })
+                .collect();
+        }
+        Ok(report)
+    }
+
+    fn add_connection(
+        &mut self,
+        info: &ConnInfo,
+        forward: &StreamStats,
+        reverse: &StreamStats,
+        talkers: &mut HashMap<IpAddr, Talker>,
+    ) {
+        self.total.add(forward);
+        self.total.add(reverse);
+        for (addr, sent, received) in [
+            (info.src_addr, forward.bytes, reverse.bytes),
+            (info.dst_addr, reverse.bytes, forward.bytes),
+        ] {
+            let talker = talkers.entry(addr).or_insert(Talker {
+                addr,
+                connections: 0,
+                bytes_sent: 0,
+                bytes_received: 0,
+            });
+            talker.connections += 1;
+            talker.bytes_sent += sent;
+            talker.bytes_received += received;
+        }
+    }
+
+    /// write report as JSON
+    pub fn write_json(&self, writer: impl Write) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(writer, self)
+    }
+
+    /// write report as human readable text
+    pub fn write_text(&self, mut writer: impl Write) -> io::Result<()> {
+        fn distribution(
+            writer: &mut impl Write,
+            title: &str,
+            unit: &str,
+            dist: &Option<Distribution>,
+        ) -> io::Result<()> {
+            let Some(dist) = dist else {
+                return writeln!(writer, "{title}: no data\n");
+            };
+            writeln!(
+                writer,
+                "{title} ({} connections): min {:.3}{unit}, p50 {:.3}{unit}, \
+                    p90 {:.3}{unit}, p99 {:.3}{unit}, max {:.3}{unit}",
+                dist.count, dist.min, dist.p50, dist.p90, dist.p99, dist.max
+            )?;
+            for bucket in &dist.histogram {
+                let range = match bucket.upper {
+                    Some(upper) => format!("{}{unit} - {upper}{unit}", bucket.lower),
+                    None => format!(">= {}{unit}", bucket.lower),
+                };
+                writeln!(writer, "  {range:>24}  {}", bucket.count)?;
+            }
+            writeln!(writer)
+        }
+
+        writeln!(
+            writer,
+            "{} connections, {} UDP flows",
+            self.connections, self.udp_flows
+        )?;
+        writeln!(
+            writer,
+            "{} packets, {} payload bytes ({} retransmitted, {} lost to gaps)",
+            self.total.packets, self.total.bytes, self.total.retransmit_bytes, self.total.gap_bytes
+        )?;
+        if let Some(rate) = self.retransmit_rate {
+            writeln!(writer, "retransmit rate: {rate:.4}")?;
+        }
+        writeln!(writer)?;
+
+        writeln!(writer, "top talkers:")?;
+        for talker in &self.top_talkers {
+            writeln!(
+                writer,
+                "  {:>39}  {} connections, {} bytes sent, {} bytes received",
+                talker.addr.to_string(),
+                talker.connections,
+                talker.bytes_sent,
+                talker.bytes_received
+            )?;
+        }
+        writeln!(writer)?;
+
+        distribution(&mut writer, "duration", "s", &self.durations)?;
+        distribution(&mut writer, "goodput", "B/s", &self.goodput)?;
+        distribution(&mut writer, "retransmit rate", "", &self.retransmit_rates)?;
+
+        writeln!(
+            writer,
+            "throughput per {}s interval:",
+            self.interval_us as f64 / 1e6
+        )?;
+        for sample in &self.throughput {
+            writeln!(
+                writer,
+                "  {:>20.3}  {}",
+                sample.start_us as f64 / 1e6,
+                sample.bytes
+            )?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::path::Path;
+
+    use uuid::Uuid;
+
+    use super::{DirectoryReport, Distribution, ThroughputSample};
+    use crate::flow_table::{Flow, IPPROTO_TCP};
+    use crate::serialized::{ConnInfo, PacketExtra, SerializedSegment};
+    use crate::stats::StreamStats;
+
+    fn extra(ts_sec: u32, ts_usec: u32) -> PacketExtra {
+        PacketExtra::LegacyPcap {
+            index: 0,
+            ts_sec,
+            ts_usec,
+            vlan_id: None,
+            ttl: None,
+            bad_checksum: false,
+            frames: None,
+        }
+    }
+
+    fn data(len: usize, is_retransmit: bool, ts_sec: u32, ts_usec: u32) -> SerializedSegment {
+        SerializedSegment::Data {
+            offset: 0,
+            len,
+            is_retransmit,
+            reverse_acked: 0,
+            extra: extra(ts_sec, ts_usec),
+            tcp: Default::default(),
+        }
+    }
+
+    fn write_segments(dir: &Path, id: Uuid, label: &str, segments: &[SerializedSegment]) {
+        let mut file = std::fs::File::create(dir.join(format!("{id}.{label}.jsonl"))).unwrap();
+        for segment in segments {
+            writeln!(file, "{}", serde_json::to_string(segment).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn distribution() {
+        let dist = Distribution::new(vec![0.5, 0.0005, 20.0, 2.0], &[0.001, 1.0, 10.0]).unwrap();
+        assert_eq!((dist.count, dist.min, dist.max), (4, 0.0005, 20.0));
+        assert_eq!((dist.p50, dist.p90), (0.5, 20.0));
+        let counts: Vec<_> = dist.histogram.iter().map(|b| b.count).collect();
+        assert_eq!(counts, [1, 1, 1, 1]);
+        assert_eq!(dist.histogram[3].upper, None);
+        assert!(Distribution::new(Vec::new(), &[1.0]).is_none());
+    }
+
+    #[test]
+    fn from_dir() {
+        let dir = std::env::temp_dir().join(format!("parse-tcp-report-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let flow = |src: [u8; 4], src_port| Flow {
+            proto: IPPROTO_TCP,
+            src_addr: src.into(),
+            src_port,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+        };
+
+        // counters recorded at retire
+        let mut recorded = ConnInfo::new(Uuid::new_v4(), &flow([10, 0, 0, 1], 1000));
+        recorded.first_packet_us = Some(1_000_000);
+        recorded.last_packet_us = Some(3_000_000);
+        recorded.forward_stats = Some(StreamStats {
+            packets: 10,
+            bytes: 1000,
+            goodput_bytes: 900,
+            retransmit_bytes: 100,
+            retransmits: 1,
+            ..Default::default()
+        });
+        recorded.reverse_stats = Some(StreamStats {
+            packets: 10,
+            bytes: 9000,
+            goodput_bytes: 9000,
+            ..Default::default()
+        });
+        write_segments(
+            &dir,
+            recorded.id,
+            "f",
+            &[data(600, false, 1, 0), data(400, false, 2, 500_000)],
+        );
+
+        // counted from segments
+        let mut counted = ConnInfo::new(Uuid::new_v4(), &flow([10, 0, 0, 3], 2000));
+        counted.first_packet_us = Some(1_500_000);
+        counted.last_packet_us = Some(1_500_000);
+        write_segments(
+            &dir,
+            counted.id,
+            "f",
+            &[
+                data(100, false, 1, 500_000),
+                SerializedSegment::new_gap(100, 50),
+                data(100, true, 1, 600_000),
+            ],
+        );
+        let fin = SerializedSegment::Fin {
+            offset: 0,
+            reverse_acked: 200,
+            extra: extra(1, 700_000),
+            tcp: Default::default(),
+        };
+        let ack = SerializedSegment::Ack {
+            offset: 1,
+            window: 1024,
+            reverse_acked: 200,
+            extra: extra(1, 700_000),
+            tcp: Default::default(),
+        };
+        write_segments(&dir, counted.id, "r", &[fin, ack]);
+
+        // duplicate entries are counted once
+        let connections = [&recorded, &counted, &recorded];
+        std::fs::write(
+            dir.join("connections.json"),
+            serde_json::to_string(&connections).unwrap(),
+        )
+        .unwrap();
+
+        let report = DirectoryReport::from_dir(&dir, 1, 1_000_000).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((report.connections, report.udp_flows), (2, 0));
+        assert_eq!(report.total.packets, 24);
+        assert_eq!(report.total.bytes, 10200);
+        assert_eq!(report.total.retransmit_bytes, 200);
+        assert_eq!(report.total.gap_bytes, 50);
+        assert_eq!(report.total.fin_count, 1);
+        assert_eq!(report.retransmit_rate, Some(200.0 / 10200.0));
+
+        assert_eq!(report.top_talkers.len(), 1);
+        let server = &report.top_talkers[0];
+        assert_eq!(server.addr, "10.0.0.2".parse::<std::net::IpAddr>().unwrap());
+        assert_eq!(server.connections, 2);
+        assert_eq!((server.bytes_sent, server.bytes_received), (9000, 1200));
+
+        let durations = report.durations.unwrap();
+        assert_eq!(
+            (durations.count, durations.min, durations.max),
+            (2, 0.0, 2.0)
+        );
+        // zero duration has no goodput
+        assert_eq!(report.goodput.unwrap().p50, 9900.0 / 2.0);
+        assert_eq!(report.retransmit_rates.unwrap().count, 2);
+        assert_eq!(
+            report.throughput,
+            [
+                ThroughputSample {
+                    start_us: 1_000_000,
+                    bytes: 800,
+                },
+                ThroughputSample {
+                    start_us: 2_000_000,
+                    bytes: 400,
+                },
+            ]
+        );
+    }
+}
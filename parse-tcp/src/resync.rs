@@ -0,0 +1,193 @@
+//! Recovery from connection desynchronization
+//!
+//! A SYN received for an established connection may open a new connection on
+//! the same flow, or may be spurious (e.g. injected, or a stray retransmission
+//! of an earlier handshake). SYNs far from the current position of their
+//! stream are treated as port reuse by `FlowTable::handle_packet`. For
+//! others, instead of recreating the connection right away, the following
+//! packets are held back and compared against the current position of each
+//! stream and against a new numbering starting at the SYN.
+//! Once `ReassemblyConfig::resync_packets` packets were observed:
+//! - if most follow the current numbering, the SYN is ignored
+//! - if most follow the new numbering, streams are rebased so the new
+//!   numbering continues at the current end of each stream
+//! - otherwise the connection is recreated, and the held packets are handled
+//!   by the new connection
+//!
+//! If the connection is kept or rebased, held packets other than SYNs are
+//! handled as usual once resolved.
+
+use serde::{Deserialize, Serialize};
+
+use crate::connection::Direction;
+use crate::serialized::PacketExtra;
+use crate::TcpMeta;
+
+/// default number of packets observed before resolving a desynchronization
+pub const RESYNC_PACKETS: u32 = 4;
+
+/// how a desynchronized connection was resolved
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResyncOutcome {
+    /// packets followed the current numbering, the SYN was ignored
+    Kept,
+    /// packets followed the new numbering, streams were rebased
+    Rebased,
+    /// no consistent numbering, the connection is recreated
+    Recreated,
+}
+
+/// sequence numbering of one direction started by the SYN
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewNumbering {
+    /// sequence number of the first byte of data
+    pub base: u32,
+    /// sequence number past the highest data seen
+    pub next: u32,
+}
+
+impl NewNumbering {
+    fn new(base: u32, len: u32) -> Self {
+        NewNumbering {
+            base,
+            next: base.wrapping_add(len),
+        }
+    }
+
+    /// whether sequence number falls within data seen so far or continues it
+    fn contains(&self, number: u32) -> bool {
+        number.wrapping_sub(self.base) <= self.next.wrapping_sub(self.base)
+    }
+
+    /// extend by segment at `number` of `len`
+    fn extend(&mut self, number: u32, len: u32) {
+        let end = number.wrapping_add(len);
+        if end.wrapping_sub(self.base) > self.next.wrapping_sub(self.base) {
+            self.next = end;
+        }
+    }
+}
+
+/// packet held back while resynchronizing
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HeldPacket {
+    pub meta: TcpMeta,
+    pub data: Vec<u8>,
+    pub extra: PacketExtra,
+}
+
+/// state of a resynchronization attempt
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Resync {
+    /// direction of the unexpected SYN
+    pub syn_direction: Direction,
+    /// new numbering (forward, reverse), once seen
+    pub numbering: [Option<NewNumbering>; 2],
+    /// packets observed, excluding SYNs
+    pub observed: u32,
+    /// packets near the current position of their stream
+    pub current_packets: u32,
+    /// packets following the new numbering
+    pub new_packets: u32,
+    /// packets held back, starting with the SYN
+    pub held: Vec<HeldPacket>,
+}
+
+fn index(direction: Direction) -> usize {
+    match direction {
+        Direction::Forward => 0,
+        Direction::Reverse => 1,
+    }
+}
+
+impl Resync {
+    /// start resynchronizing after SYN sent in direction
+    pub fn new(direction: Direction, meta: &TcpMeta, data: &[u8], extra: &PacketExtra) -> Self {
+        let mut numbering = [None; 2];
+        numbering[index(direction)] = Some(NewNumbering::new(
+            meta.seq_number.wrapping_add(1),
+            data.len() as u32,
+        ));
+        Resync {
+            syn_direction: direction,
+            numbering,
+            observed: 0,
+            current_packets: 0,
+            new_packets: 0,
+            held: vec![HeldPacket {
+                meta: meta.clone(),
+                data: data.to_vec(),
+                extra: extra.clone(),
+            }],
+        }
+    }
+
+    /// hold back packet sent in direction and count which numbering it
+    /// follows, `current` if it is near the current position of its stream
+    pub fn observe(
+        &mut self,
+        direction: Direction,
+        meta: &TcpMeta,
+        data: &[u8],
+        extra: &PacketExtra,
+        current: bool,
+    ) {
+        self.held.push(HeldPacket {
+            meta: meta.clone(),
+            data: data.to_vec(),
+            extra: extra.clone(),
+        });
+        let idx = index(direction);
+        let syn_numbering = self.numbering[index(self.syn_direction)];
+        if meta.flags.syn {
+            // SYN/ACK for the SYN starts the numbering of the other side
+            if meta.flags.ack
+                && direction != self.syn_direction
+                && syn_numbering.is_some_and(|syn| meta.ack_number == syn.base)
+            {
+                self.numbering[idx] = Some(NewNumbering::new(
+                    meta.seq_number.wrapping_add(1),
+                    data.len() as u32,
+                ));
+            }
+            return;
+        }
+
+        self.observed += 1;
+        let len = data.len() as u32 + meta.flags.fin as u32;
+        let other = self.numbering[1 - idx];
+        if let Some(numbering) = &mut self.numbering[idx] {
+            if numbering.contains(meta.seq_number) {
+                numbering.extend(meta.seq_number, len);
+                self.new_packets += 1;
+                return;
+            }
+        } else if meta.flags.ack && other.is_some_and(|other| other.contains(meta.ack_number)) {
+            // acknowledges data of the new numbering
+            self.numbering[idx] = Some(NewNumbering::new(meta.seq_number, len));
+            self.new_packets += 1;
+            return;
+        }
+        if current {
+            self.current_packets += 1;
+        }
+    }
+
+    /// outcome once `packets` packets were observed, None to keep waiting
+    pub fn outcome(&self, packets: u32) -> Option<ResyncOutcome> {
+        (self.observed >= packets).then(|| self.decide())
+    }
+
+    /// outcome based on packets observed so far
+    pub fn decide(&self) -> ResyncOutcome {
+        let majority = |count: u32| count > 0 && count * 2 >= self.observed;
+        if self.new_packets > self.current_packets && majority(self.new_packets) {
+            ResyncOutcome::Rebased
+        } else if self.current_packets > self.new_packets && majority(self.current_packets) {
+            ResyncOutcome::Kept
+        } else {
+            ResyncOutcome::Recreated
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::TcpMeta;
+
+/// max number of outstanding segments or timestamps tracked per direction
+pub const MAX_PENDING_SAMPLES: usize = 256;
+
+/// summary of RTT estimates, in microseconds
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RttStats {
+    /// smoothed RTT
+    pub srtt_us: u64,
+    /// RTT variance
+    pub rttvar_us: u64,
+    /// minimum observed RTT
+    pub min_rtt_us: u64,
+    /// number of samples taken
+    pub samples: u64,
+}
+
+/// RTT estimator for segments sent in one direction
+///
+/// Samples are taken from TSval/TSecr pairs when timestamps are in use, and
+/// from seq/ack pairs otherwise. Retransmitted segments are not sampled
+/// (Karn's algorithm). Smoothing follows RFC 6298.
+#[derive(Default)]
+pub struct RttEstimator {
+    /// smoothed RTT
+    pub srtt: Option<Duration>,
+    /// RTT variance
+    pub rttvar: Duration,
+    /// minimum observed RTT
+    pub min_rtt: Option<Duration>,
+    /// number of samples taken
+    pub samples: u64,
+
+    /// whether segments in this direction carry timestamps
+    pub uses_timestamps: bool,
+    /// (end sequence number, send time) of outstanding segments
+    pending_seq: VecDeque<(u32, Duration)>,
+    /// highest end sequence number sent
+    highest_end_seq: Option<u32>,
+    /// (TSval, first send time) of segments awaiting echo
+    pending_ts: VecDeque<(u32, Duration)>,
+}
+
+/// wrapping comparison of sequence numbers, a >= b
+fn seq_ge(a: u32, b: u32) -> bool {
+    a.wrapping_sub(b) as i32 >= 0
+}
+
+impl RttEstimator {
+    /// create new instance
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// record a segment sent in this direction at capture time `now`
+    pub fn on_segment_sent(&mut self, meta: &TcpMeta, payload_len: usize, now: Duration) {
+        let seq_len = payload_len as u32 + meta.flags.syn as u32 + meta.flags.fin as u32;
+        if seq_len == 0 {
+            // pure ack, will not be acknowledged
+            return;
+        }
+
+        if let Some((tsval, _)) = meta.option_timestamp {
+            self.uses_timestamps = true;
+            // only the first segment with a given TSval gives a valid sample
+            if self
+                .pending_ts
+                .back()
+                .is_none_or(|&(last, _)| last != tsval)
+            {
+                if self.pending_ts.len() >= MAX_PENDING_SAMPLES {
+                    self.pending_ts.pop_front();
+                }
+                self.pending_ts.push_back((tsval, now));
+            }
+            return;
+        }
+
+        let end_seq = meta.seq_number.wrapping_add(seq_len);
+        if let Some(highest) = self.highest_end_seq {
+            if seq_ge(highest, end_seq) {
+                // retransmission, ambiguous samples discarded
+                self.pending_seq.clear();
+                return;
+            }
+        }
+        self.highest_end_seq = Some(end_seq);
+        if self.pending_seq.len() >= MAX_PENDING_SAMPLES {
+            self.pending_seq.pop_front();
+        }
+        self.pending_seq.push_back((end_seq, now));
+    }
+
+    /// record a packet received in the opposite direction at capture time `now`
+    pub fn on_ack_received(&mut self, meta: &TcpMeta, now: Duration) {
+        if self.uses_timestamps {
+            let Some((_, tsecr)) = meta.option_timestamp else {
+                return;
+            };
+            let Some(index) = self.pending_ts.iter().position(|&(v, _)| v == tsecr) else {
+                return;
+            };
+            let (_, sent) = self.pending_ts[index];
+            self.pending_ts.drain(..=index);
+            self.add_sample(now.saturating_sub(sent));
+            return;
+        }
+
+        if !meta.flags.ack {
+            return;
+        }
+        let mut sample = None;
+        while let Some(&(end_seq, sent)) = self.pending_seq.front() {
+            if !seq_ge(meta.ack_number, end_seq) {
+                break;
+            }
+            // only the most recent segment covered by the ack is sampled
+            sample = Some(sent);
+            self.pending_seq.pop_front();
+        }
+        if let Some(sent) = sample {
+            self.add_sample(now.saturating_sub(sent));
+        }
+    }
+
+    /// update estimates with a new sample
+    pub fn add_sample(&mut self, rtt: Duration) {
+        self.samples += 1;
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// get summary, if any samples were taken
+    pub fn stats(&self) -> Option<RttStats> {
+        Some(RttStats {
+            srtt_us: self.srtt?.as_micros() as u64,
+            rttvar_us: self.rttvar.as_micros() as u64,
+            min_rtt_us: self.min_rtt?.as_micros() as u64,
+            samples: self.samples,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::RttEstimator;
+    use crate::{TcpFlags, TcpMeta};
+
+    fn meta(seq: u32, ack: u32, timestamp: Option<(u32, u32)>) -> TcpMeta {
+        TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: seq,
+            ack_number: ack,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: timestamp,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn seq_ack() {
+        let mut rtt = RttEstimator::new();
+        rtt.on_segment_sent(&meta(100, 0, None), 10, ms(0));
+        rtt.on_segment_sent(&meta(110, 0, None), 10, ms(5));
+        // ack covering both segments samples the latest one
+        rtt.on_ack_received(&meta(0, 120, None), ms(25));
+        assert_eq!(rtt.samples, 1);
+        assert_eq!(rtt.srtt, Some(ms(20)));
+
+        // retransmission is not sampled
+        rtt.on_segment_sent(&meta(120, 0, None), 10, ms(30));
+        rtt.on_segment_sent(&meta(120, 0, None), 10, ms(60));
+        rtt.on_ack_received(&meta(0, 130, None), ms(70));
+        assert_eq!(rtt.samples, 1);
+
+        rtt.on_segment_sent(&meta(130, 0, None), 10, ms(80));
+        rtt.on_ack_received(&meta(0, 140, None), ms(92));
+        let stats = rtt.stats().unwrap();
+        assert_eq!(stats.samples, 2);
+        assert_eq!(stats.min_rtt_us, 12_000);
+        assert_eq!(stats.srtt_us, 19_000);
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut rtt = RttEstimator::new();
+        rtt.on_segment_sent(&meta(100, 0, Some((1, 0))), 10, ms(0));
+        // same TSval, only first send counts
+        rtt.on_segment_sent(&meta(110, 0, Some((1, 0))), 10, ms(3));
+        rtt.on_segment_sent(&meta(120, 0, Some((2, 0))), 10, ms(10));
+        rtt.on_ack_received(&meta(0, 130, Some((50, 1))), ms(15));
+        assert_eq!(rtt.srtt, Some(ms(15)));
+        // echo of an already sampled TSval is ignored
+        rtt.on_ack_received(&meta(0, 130, Some((51, 1))), ms(20));
+        assert_eq!(rtt.samples, 1);
+        rtt.on_ack_received(&meta(0, 130, Some((52, 2))), ms(18));
+        assert_eq!(rtt.samples, 2);
+        assert_eq!(rtt.min_rtt, Some(ms(8)));
+    }
+}
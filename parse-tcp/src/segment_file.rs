@@ -0,0 +1,679 @@
+//! Binary segment file format
+//!
+//! A compact alternative to segments JSONL. Files start with an 8 byte header:
+//! the magic `KSEG`, a little-endian `u16` version, and two reserved zero
+//! bytes. Each record follows as:
+//!
+//! - kind (`u8`): 0 data, 1 ack, 2 fin, 3 rst, 4 datagram, 5 gap, 6 late
+//!   data
+//! - packet extra kind (`u8`): 0 none, 1 legacy pcap, 2 pcapng
+//! - flags (`u16`, little-endian), see `flags`
+//! - body length (varint)
+//! - body: varint fields in the order listed below
+//!
+//! Varints are unsigned LEB128. Fields of the body, in order:
+//!
+//! - `offset`
+//! - data: `len`, `reverse_acked`; ack: `window`, `reverse_acked`; fin and
+//!   rst: `reverse_acked`; datagram, gap and late data: `len`
+//! - data, ack, fin and rst: `urgent_pointer` and `mss`, if flagged
+//! - legacy pcap: `index`, `ts_sec`, `ts_usec`, then `vlan_id` and `ttl` if
+//!   flagged
+//! - pcapng: `index`, `interface_id`, `ts_nsec`, then `dropped`,
+//!   `interface_dropped`, `vlan_id`, `interface_name` (length followed by
+//!   UTF-8 bytes), and `ttl`, each if flagged
+//! - gap, if annotated: `cause` (0 unknown, 1 loss, 2 buffer overrun),
+//!   `packets_skipped`, then `index` and `ts_us` of the packets before, after
+//!   and declaring the gap, each plus one (0 if unknown)
+//!
+//! Readers ignore trailing body bytes, so later versions may append fields.
+//! Records are decoded to `SerializedSegment`, the same type as JSONL lines.
+
+use std::io::{self, ErrorKind, Read};
+
+use crate::serialized::{GapCause, GapInfo, PacketExtra, PacketRef, SerializedSegment};
+use crate::stream::SegmentTcpInfo;
+
+/// file magic
+pub const MAGIC: [u8; 4] = *b"KSEG";
+/// current format version
+pub const VERSION: u16 = 1;
+/// length of file header
+pub const FILE_HEADER_LEN: usize = 8;
+/// largest body accepted by the reader
+pub const MAX_BODY_LEN: usize = 64 << 10;
+
+/// bits of the record flags field
+pub mod flags {
+    pub const IS_RETRANSMIT: u16 = 1 << 0;
+    pub const PSH: u16 = 1 << 1;
+    pub const ECE: u16 = 1 << 2;
+    pub const CWR: u16 = 1 << 3;
+    pub const BAD_CHECKSUM: u16 = 1 << 4;
+    pub const HAS_URGENT_POINTER: u16 = 1 << 5;
+    pub const HAS_MSS: u16 = 1 << 6;
+    pub const HAS_VLAN_ID: u16 = 1 << 7;
+    pub const HAS_INTERFACE_NAME: u16 = 1 << 8;
+    pub const HAS_DROPPED: u16 = 1 << 9;
+    pub const HAS_INTERFACE_DROPPED: u16 = 1 << 10;
+    pub const HAS_TTL: u16 = 1 << 11;
+    pub const PATCHED: u16 = 1 << 12;
+    /// shift of the 2-bit IP ECN field
+    pub const IP_ECN_SHIFT: u16 = 14;
+}
+
+const KIND_DATA: u8 = 0;
+const KIND_ACK: u8 = 1;
+const KIND_FIN: u8 = 2;
+const KIND_RST: u8 = 3;
+const KIND_DATAGRAM: u8 = 4;
+const KIND_GAP: u8 = 5;
+const KIND_LATE_DATA: u8 = 6;
+
+const EXTRA_NONE: u8 = 0;
+const EXTRA_LEGACY_PCAP: u8 = 1;
+const EXTRA_PCAPNG: u8 = 2;
+
+/// append file header
+pub fn write_file_header(out: &mut Vec<u8>) {
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&[0, 0]);
+}
+
+/// append varint
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// append annotation of gap record
+fn write_gap_info(out: &mut Vec<u8>, info: &GapInfo) {
+    write_varint(
+        out,
+        match info.cause {
+            None => 0,
+            Some(GapCause::Loss) => 1,
+            Some(GapCause::BufferOverrun) => 2,
+        },
+    );
+    write_varint(out, info.packets_skipped as u64);
+    for packet in [info.before, info.after, info.declared] {
+        let packet = packet.unwrap_or_default();
+        write_varint(out, packet.index.map_or(0, |index| index + 1));
+        write_varint(out, packet.ts_us.map_or(0, |ts_us| ts_us + 1));
+    }
+}
+
+/// append record for segment
+pub fn encode_segment(out: &mut Vec<u8>, segment: &SerializedSegment) {
+    let mut bits = 0;
+    let mut body = Vec::with_capacity(32);
+    let (kind, extra, tcp) = match segment {
+        SerializedSegment::Data {
+            offset,
+            len,
+            is_retransmit,
+            reverse_acked,
+            extra,
+            tcp,
+        } => {
+            if *is_retransmit {
+                bits |= flags::IS_RETRANSMIT;
+            }
+            write_varint(&mut body, *offset);
+            write_varint(&mut body, *len as u64);
+            write_varint(&mut body, *reverse_acked);
+            (KIND_DATA, Some(extra), Some(tcp))
+        }
+        SerializedSegment::Ack {
+            offset,
+            window,
+            reverse_acked,
+            extra,
+            tcp,
+        } => {
+            write_varint(&mut body, *offset);
+            write_varint(&mut body, *window as u64);
+            write_varint(&mut body, *reverse_acked);
+            (KIND_ACK, Some(extra), Some(tcp))
+        }
+        SerializedSegment::Fin {
+            offset,
+            reverse_acked,
+            extra,
+            tcp,
+        }
+        | SerializedSegment::Rst {
+            offset,
+            reverse_acked,
+            extra,
+            tcp,
+        } => {
+            write_varint(&mut body, *offset);
+            write_varint(&mut body, *reverse_acked);
+            let kind = if matches!(segment, SerializedSegment::Fin { .. }) {
+                KIND_FIN
+            } else {
+                KIND_RST
+            };
+            (kind, Some(extra), Some(tcp))
+        }
+        SerializedSegment::Datagram { offset, len, extra } => {
+            write_varint(&mut body, *offset);
+            write_varint(&mut body, *len as u64);
+            (KIND_DATAGRAM, Some(extra), None)
+        }
+        SerializedSegment::Gap { offset, len, info } => {
+            write_varint(&mut body, *offset);
+            write_varint(&mut body, *len);
+            if *info != GapInfo::default() {
+                write_gap_info(&mut body, info);
+            }
+            (KIND_GAP, None, None)
+        }
+        SerializedSegment::LateData {
+            offset,
+            len,
+            patched,
+            extra,
+        } => {
+            if *patched {
+                bits |= flags::PATCHED;
+            }
+            write_varint(&mut body, *offset);
+            write_varint(&mut body, *len as u64);
+            (KIND_LATE_DATA, Some(extra), None)
+        }
+    };
+
+    if let Some(tcp) = tcp {
+        if tcp.psh {
+            bits |= flags::PSH;
+        }
+        if tcp.ece {
+            bits |= flags::ECE;
+        }
+        if tcp.cwr {
+            bits |= flags::CWR;
+        }
+        bits |= (tcp.ip_ecn as u16 & 0b11) << flags::IP_ECN_SHIFT;
+        if let Some(urgent_pointer) = tcp.urgent_pointer {
+            bits |= flags::HAS_URGENT_POINTER;
+            write_varint(&mut body, urgent_pointer as u64);
+        }
+        if let Some(mss) = tcp.mss {
+            bits |= flags::HAS_MSS;
+            write_varint(&mut body, mss as u64);
+        }
+    }
+
+    let extra_kind = match extra {
+        None | Some(PacketExtra::None) => EXTRA_NONE,
+        Some(PacketExtra::LegacyPcap {
+            index,
+            ts_sec,
+            ts_usec,
+            vlan_id,
+            ttl,
+            bad_checksum,
+            ..
+        }) => {
+            write_varint(&mut body, *index);
+            write_varint(&mut body, *ts_sec as u64);
+            write_varint(&mut body, *ts_usec as u64);
+            if let Some(vlan_id) = vlan_id {
+                bits |= flags::HAS_VLAN_ID;
+                write_varint(&mut body, *vlan_id as u64);
+            }
+            if let Some(ttl) = ttl {
+                bits |= flags::HAS_TTL;
+                write_varint(&mut body, *ttl as u64);
+            }
+            if *bad_checksum {
+                bits |= flags::BAD_CHECKSUM;
+            }
+            EXTRA_LEGACY_PCAP
+        }
+        Some(PacketExtra::Pcapng {
+            index,
+            interface_id,
+            interface_name,
+            ts_nsec,
+            dropped,
+            interface_dropped,
+            vlan_id,
+            ttl,
+            bad_checksum,
+            ..
+        }) => {
+            write_varint(&mut body, *index);
+            write_varint(&mut body, *interface_id as u64);
+            write_varint(&mut body, *ts_nsec);
+            if let Some(dropped) = dropped {
+                bits |= flags::HAS_DROPPED;
+                write_varint(&mut body, *dropped);
+            }
+            if let Some(interface_dropped) = interface_dropped {
+                bits |= flags::HAS_INTERFACE_DROPPED;
+                write_varint(&mut body, *interface_dropped);
+            }
+            if let Some(vlan_id) = vlan_id {
+                bits |= flags::HAS_VLAN_ID;
+                write_varint(&mut body, *vlan_id as u64);
+            }
+            if let Some(interface_name) = interface_name {
+                bits |= flags::HAS_INTERFACE_NAME;
+                write_varint(&mut body, interface_name.len() as u64);
+                body.extend_from_slice(interface_name.as_bytes());
+            }
+            if let Some(ttl) = ttl {
+                bits |= flags::HAS_TTL;
+                write_varint(&mut body, *ttl as u64);
+            }
+            if *bad_checksum {
+                bits |= flags::BAD_CHECKSUM;
+            }
+            EXTRA_PCAPNG
+        }
+    };
+
+    out.push(kind);
+    out.push(extra_kind);
+    out.extend_from_slice(&bits.to_le_bytes());
+    write_varint(out, body.len() as u64);
+    out.extend_from_slice(&body);
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// cursor over the fields of a record body
+struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl Fields<'_> {
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self
+                .buf
+                .split_first()
+                .ok_or_else(|| invalid_data("truncated record body"))?;
+            self.buf = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("varint too long"))
+    }
+
+    fn varint_as<T: TryFrom<u64>>(&mut self) -> io::Result<T> {
+        T::try_from(self.varint()?).map_err(|_| invalid_data("field out of range"))
+    }
+
+    fn optional<T: TryFrom<u64>>(&mut self, present: bool) -> io::Result<Option<T>> {
+        present.then(|| self.varint_as()).transpose()
+    }
+
+    /// read field stored plus one, 0 if not present
+    fn optional_plus_one(&mut self) -> io::Result<Option<u64>> {
+        Ok(self.varint()?.checked_sub(1))
+    }
+
+    fn packet_ref(&mut self) -> io::Result<Option<PacketRef>> {
+        let packet = PacketRef {
+            index: self.optional_plus_one()?,
+            ts_us: self.optional_plus_one()?,
+        };
+        Ok((packet != PacketRef::default()).then_some(packet))
+    }
+
+    fn gap_info(&mut self) -> io::Result<GapInfo> {
+        if self.buf.is_empty() {
+            // not annotated
+            return Ok(GapInfo::default());
+        }
+        let cause = match self.varint()? {
+            1 => Some(GapCause::Loss),
+            2 => Some(GapCause::BufferOverrun),
+            _ => None,
+        };
+        Ok(GapInfo {
+            cause,
+            packets_skipped: self.varint_as()?,
+            before: self.packet_ref()?,
+            after: self.packet_ref()?,
+            declared: self.packet_ref()?,
+        })
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.buf.len() < len {
+            return Err(invalid_data("truncated record body"));
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+}
+
+/// decode record with fixed header `header` and body `body`
+pub fn decode_segment(header: [u8; 4], body: &[u8]) -> io::Result<SerializedSegment> {
+    let [kind, extra_kind, flags_lo, flags_hi] = header;
+    let bits = u16::from_le_bytes([flags_lo, flags_hi]);
+    let has = |flag: u16| bits & flag != 0;
+    let mut fields = Fields { buf: body };
+
+    let offset = fields.varint()?;
+    let (len, reverse_acked) = match kind {
+        KIND_DATA | KIND_ACK => (fields.varint()?, fields.varint()?),
+        KIND_FIN | KIND_RST => (0, fields.varint()?),
+        KIND_DATAGRAM | KIND_GAP | KIND_LATE_DATA => (fields.varint()?, 0),
+        _ => return Err(invalid_data("unknown record kind")),
+    };
+    let tcp = SegmentTcpInfo {
+        psh: has(flags::PSH),
+        urgent_pointer: fields.optional(has(flags::HAS_URGENT_POINTER))?,
+        ece: has(flags::ECE),
+        cwr: has(flags::CWR),
+        ip_ecn: (bits >> flags::IP_ECN_SHIFT) as u8,
+        mss: fields.optional(has(flags::HAS_MSS))?,
+    };
+    let extra = match extra_kind {
+        EXTRA_NONE => PacketExtra::None,
+        EXTRA_LEGACY_PCAP => PacketExtra::LegacyPcap {
+            index: fields.varint()?,
+            ts_sec: fields.varint_as()?,
+            ts_usec: fields.varint_as()?,
+            vlan_id: fields.optional(has(flags::HAS_VLAN_ID))?,
+            ttl: fields.optional(has(flags::HAS_TTL))?,
+            bad_checksum: has(flags::BAD_CHECKSUM),
+            frames: None,
+        },
+        EXTRA_PCAPNG => {
+            let index = fields.varint()?;
+            let interface_id = fields.varint_as()?;
+            let ts_nsec = fields.varint()?;
+            let dropped = fields.optional(has(flags::HAS_DROPPED))?;
+            let interface_dropped = fields.optional(has(flags::HAS_INTERFACE_DROPPED))?;
+            let vlan_id = fields.optional(has(flags::HAS_VLAN_ID))?;
+            let interface_name = if has(flags::HAS_INTERFACE_NAME) {
+                let len = fields.varint_as()?;
+                let name = std::str::from_utf8(fields.bytes(len)?)
+                    .map_err(|_| invalid_data("interface name is not UTF-8"))?;
+                Some(name.into())
+            } else {
+                None
+            };
+            PacketExtra::Pcapng {
+                index,
+                interface_id,
+                interface_name,
+                ts_nsec,
+                dropped,
+                interface_dropped,
+                vlan_id,
+                ttl: fields.optional(has(flags::HAS_TTL))?,
+                bad_checksum: has(flags::BAD_CHECKSUM),
+                frames: None,
+            }
+        }
+        _ => return Err(invalid_data("unknown packet extra kind")),
+    };
+
+    let to_usize =
+        |value: u64| usize::try_from(value).map_err(|_| invalid_data("length out of range"));
+    Ok(match kind {
+        KIND_DATA => SerializedSegment::Data {
+            offset,
+            len: to_usize(len)?,
+            is_retransmit: has(flags::IS_RETRANSMIT),
+            reverse_acked,
+            extra,
+            tcp,
+        },
+        KIND_ACK => SerializedSegment::Ack {
+            offset,
+            window: to_usize(len)?,
+            reverse_acked,
+            extra,
+            tcp,
+        },
+        KIND_FIN => SerializedSegment::Fin {
+            offset,
+            reverse_acked,
+            extra,
+            tcp,
+        },
+        KIND_RST => SerializedSegment::Rst {
+            offset,
+            reverse_acked,
+            extra,
+            tcp,
+        },
+        KIND_DATAGRAM => SerializedSegment::Datagram {
+            offset,
+            len: to_usize(len)?,
+            extra,
+        },
+        KIND_LATE_DATA => SerializedSegment::LateData {
+            offset,
+            len: to_usize(len)?,
+            patched: has(flags::PATCHED),
+            extra,
+        },
+        _ => SerializedSegment::Gap {
+            offset,
+            len,
+            info: fields.gap_info()?,
+        },
+    })
+}
+
+/// reads records from a binary segment file
+///
+/// Wrap `reader` in a decoder first if the file is compressed.
+pub struct SegmentReader<R: Read> {
+    reader: R,
+    /// format version from file header
+    pub version: u16,
+    body: Vec<u8>,
+}
+
+impl<R: Read> SegmentReader<R> {
+    /// read and check file header
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; FILE_HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(invalid_data("not a segment file"));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version == 0 || version > VERSION {
+            return Err(invalid_data("unsupported segment file version"));
+        }
+        Ok(SegmentReader {
+            reader,
+            version,
+            body: Vec::new(),
+        })
+    }
+
+    /// read next record, returning None at end of file
+    pub fn read_segment(&mut self) -> io::Result<Option<SerializedSegment>> {
+        let mut header = [0; 4];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let body_len = self.read_varint()?;
+        if body_len > MAX_BODY_LEN as u64 {
+            return Err(invalid_data("record body too long"));
+        }
+        self.body.resize(body_len as usize, 0);
+        self.reader.read_exact(&mut self.body)?;
+        decode_segment(header, &self.body).map(Some)
+    }
+
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0];
+            self.reader.read_exact(&mut byte)?;
+            value |= ((byte[0] & 0x7f) as u64) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_data("varint too long"))
+    }
+
+    /// get back the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for SegmentReader<R> {
+    type Item = io::Result<SerializedSegment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_segment().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_segment, write_file_header, SegmentReader, FILE_HEADER_LEN};
+    use crate::serialized::{GapCause, GapInfo, PacketExtra, PacketRef, SerializedSegment};
+    use crate::stream::SegmentTcpInfo;
+
+    #[test]
+    fn round_trip() {
+        let segments = [
+            SerializedSegment::Data {
+                offset: 1 << 40,
+                len: 1460,
+                is_retransmit: true,
+                reverse_acked: 300,
+                extra: PacketExtra::Pcapng {
+                    index: 7,
+                    interface_id: 1,
+                    interface_name: Some("eth0".into()),
+                    ts_nsec: 1_700_000_000_123_456_789,
+                    dropped: Some(0),
+                    interface_dropped: None,
+                    vlan_id: Some(100),
+                    ttl: Some(57),
+                    bad_checksum: true,
+                    frames: None,
+                },
+                tcp: SegmentTcpInfo {
+                    psh: true,
+                    urgent_pointer: Some(3),
+                    ip_ecn: 3,
+                    mss: Some(1460),
+                    ..Default::default()
+                },
+            },
+            SerializedSegment::Ack {
+                offset: 0,
+                window: 65535 << 7,
+                reverse_acked: 1,
+                extra: PacketExtra::LegacyPcap {
+                    index: 0,
+                    ts_sec: u32::MAX,
+                    ts_usec: 999_999,
+                    vlan_id: None,
+                    ttl: None,
+                    bad_checksum: false,
+                    frames: None,
+                },
+                tcp: Default::default(),
+            },
+            SerializedSegment::Fin {
+                offset: 5,
+                reverse_acked: 2,
+                extra: PacketExtra::None,
+                tcp: Default::default(),
+            },
+            SerializedSegment::Rst {
+                offset: 5,
+                reverse_acked: 2,
+                extra: PacketExtra::None,
+                tcp: SegmentTcpInfo {
+                    cwr: true,
+                    ece: true,
+                    ..Default::default()
+                },
+            },
+            SerializedSegment::Datagram {
+                offset: 10,
+                len: 20,
+                extra: PacketExtra::None,
+            },
+            SerializedSegment::new_gap(30, 1 << 33),
+            SerializedSegment::LateData {
+                offset: 35,
+                len: 2,
+                patched: true,
+                extra: PacketExtra::None,
+            },
+            SerializedSegment::Gap {
+                offset: 40,
+                len: 100,
+                info: GapInfo {
+                    cause: Some(GapCause::BufferOverrun),
+                    before: Some(PacketRef {
+                        index: Some(0),
+                        ts_us: Some(1_700_000_000_000_000),
+                    }),
+                    after: None,
+                    declared: Some(PacketRef {
+                        index: Some(9),
+                        ts_us: None,
+                    }),
+                    packets_skipped: 3,
+                },
+            },
+        ];
+        let mut buf = Vec::new();
+        write_file_header(&mut buf);
+        assert_eq!(buf.len(), FILE_HEADER_LEN);
+        for segment in &segments {
+            encode_segment(&mut buf, segment);
+        }
+
+        let decoded: Vec<_> = SegmentReader::new(&buf[..])
+            .unwrap()
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(decoded.len(), segments.len());
+        for (a, b) in segments.iter().zip(&decoded) {
+            assert_eq!(
+                serde_json::to_string(a).unwrap(),
+                serde_json::to_string(b).unwrap()
+            );
+        }
+
+        // truncated record
+        let mut reader = SegmentReader::new(&buf[..buf.len() - 1]).unwrap();
+        assert!(reader.by_ref().take(segments.len() - 1).all(|r| r.is_ok()));
+        assert!(reader.next().unwrap().is_err());
+        // bad magic
+        assert!(SegmentReader::new(&b"JSON\x01\0\0\0"[..]).is_err());
+    }
+}
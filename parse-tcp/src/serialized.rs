@@ -1,10 +1,21 @@
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::flow_table::Flow;
-use crate::stream::{SegmentInfo, SegmentType};
+use crate::classify::AppProtocol;
+use crate::connection::{CloseReason, Connection};
+use crate::flow_table::{Flow, IPPROTO_UDP};
+use crate::http::{find_header, HttpRequestHead, HttpResponseHead};
+use crate::pcap_writer::RawFrame;
+use crate::rtt::RttStats;
+use crate::stats::StreamStats;
+use crate::stream::{LateData, SegmentInfo, SegmentTcpInfo, SegmentType, StreamGap};
+use crate::tls::TlsInfo;
+use crate::ttl::TtlStats;
+use crate::ConnectionHandler;
 
 /// extra information that may be associated with the packet
 #[derive(Clone, Serialize, Deserialize)]
@@ -18,7 +29,279 @@ pub enum PacketExtra {
         ts_sec: u32,
         /// timestamp (microseconds)
         ts_usec: u32,
+        /// outermost VLAN id
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        vlan_id: Option<u16>,
+        /// captured frames making up the packet, if kept
+        #[serde(skip)]
+        frames: Option<Arc<[RawFrame]>>,
+        /// TTL or hop limit of IP header
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u8>,
+        /// whether checksum verification failed
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        bad_checksum: bool,
     },
+    Pcapng {
+        /// packet number
+        index: u64,
+        /// id of capture interface within the section
+        interface_id: u32,
+        /// name of capture interface (`if_name`), if recorded
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        interface_name: Option<Arc<str>>,
+        /// timestamp (nanoseconds since epoch)
+        ts_nsec: u64,
+        /// packets dropped since the previous packet (`epb_dropcount`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dropped: Option<u64>,
+        /// packets dropped by the interface so far, from the latest
+        /// statistics block (`isb_ifdrop`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        interface_dropped: Option<u64>,
+        /// outermost VLAN id
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        vlan_id: Option<u16>,
+        /// captured frames making up the packet, if kept
+        #[serde(skip)]
+        frames: Option<Arc<[RawFrame]>>,
+        /// TTL or hop limit of IP header
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u8>,
+        /// whether checksum verification failed
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        bad_checksum: bool,
+    },
+}
+
+impl PacketExtra {
+    /// captured frames making up the packet, more than one if it was
+    /// reassembled from IP fragments. Empty unless frames were kept
+    pub fn frames(&self) -> &[RawFrame] {
+        match self {
+            PacketExtra::LegacyPcap {
+                frames: Some(frames),
+                ..
+            }
+            | PacketExtra::Pcapng {
+                frames: Some(frames),
+                ..
+            } => frames,
+            _ => &[],
+        }
+    }
+}
+
+impl PacketExtra {
+    /// capture timestamp of packet, if known
+    pub fn timestamp(&self) -> Option<Duration> {
+        match self {
+            PacketExtra::None => None,
+            PacketExtra::LegacyPcap {
+                ts_sec, ts_usec, ..
+            } => Some(Duration::new(
+                *ts_sec as u64,
+                ts_usec.saturating_mul(1000).min(999_999_999),
+            )),
+            PacketExtra::Pcapng { ts_nsec, .. } => Some(Duration::from_nanos(*ts_nsec)),
+        }
+    }
+
+    /// index of packet in capture, if known
+    pub fn index(&self) -> Option<u64> {
+        match self {
+            PacketExtra::None => None,
+            PacketExtra::LegacyPcap { index, .. } | PacketExtra::Pcapng { index, .. } => {
+                Some(*index)
+            }
+        }
+    }
+
+    /// reference to packet for gap records, if known
+    pub fn packet_ref(&self) -> Option<PacketRef> {
+        match self {
+            PacketExtra::None => None,
+            _ => Some(PacketRef {
+                index: self.index(),
+                ts_us: self.timestamp().map(|ts| ts.as_micros() as u64),
+            }),
+        }
+    }
+
+    /// TTL or hop limit of IP header, if known
+    pub fn ttl(&self) -> Option<u8> {
+        match self {
+            PacketExtra::None => None,
+            PacketExtra::LegacyPcap { ttl, .. } | PacketExtra::Pcapng { ttl, .. } => *ttl,
+        }
+    }
+
+    /// capture timestamp of packet as wall clock time, if known
+    pub fn system_time(&self) -> Option<SystemTime> {
+        self.timestamp()
+            .and_then(|ts| SystemTime::UNIX_EPOCH.checked_add(ts))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HttpTransactionInfo {
+    /// connection id
+    pub id: Uuid,
+    /// index of transaction within connection
+    pub index: u64,
+    /// whether the full response was seen
+    pub complete: bool,
+    pub method: Option<String>,
+    pub uri: Option<String>,
+    pub request_version: Option<u8>,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body_file: Option<String>,
+    pub request_body_len: u64,
+    pub status: Option<u16>,
+    pub reason: Option<String>,
+    pub response_version: Option<u8>,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body_file: Option<String>,
+    pub response_body_len: u64,
+}
+
+impl HttpTransactionInfo {
+    pub fn new(id: Uuid, index: u64) -> Self {
+        HttpTransactionInfo {
+            id,
+            index,
+            complete: false,
+            method: None,
+            uri: None,
+            request_version: None,
+            request_headers: Vec::new(),
+            request_body_file: None,
+            request_body_len: 0,
+            status: None,
+            reason: None,
+            response_version: None,
+            response_headers: Vec::new(),
+            response_body_file: None,
+            response_body_len: 0,
+        }
+    }
+
+    /// fill in request fields
+    pub fn set_request(&mut self, head: HttpRequestHead) {
+        self.method = Some(head.method);
+        self.uri = Some(head.uri);
+        self.request_version = Some(head.version);
+        self.request_headers = head.headers;
+    }
+
+    /// fill in response fields
+    pub fn set_response(&mut self, head: HttpResponseHead) {
+        self.status = Some(head.status);
+        self.reason = Some(head.reason);
+        self.response_version = Some(head.version);
+        self.response_headers = head.headers;
+    }
+}
+
+/// HTTP/2 stream of a connection
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Http2StreamInfo {
+    /// connection id
+    pub id: Uuid,
+    pub stream_id: u32,
+    /// whether both sides ended the stream
+    pub complete: bool,
+    /// whether the stream was promised by the server
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pushed: bool,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub authority: Option<String>,
+    /// request header fields, including pseudo-headers
+    pub request_headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request_trailers: Vec<(String, String)>,
+    pub request_body_file: Option<String>,
+    pub request_body_len: u64,
+    pub status: Option<u16>,
+    /// response header fields, including pseudo-headers
+    pub response_headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response_trailers: Vec<(String, String)>,
+    pub response_body_file: Option<String>,
+    pub response_body_len: u64,
+    /// error code of RST_STREAM, if the stream was reset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset_error: Option<u32>,
+}
+
+impl Http2StreamInfo {
+    pub fn new(id: Uuid, stream_id: u32) -> Self {
+        Http2StreamInfo {
+            id,
+            stream_id,
+            complete: false,
+            pushed: false,
+            method: None,
+            path: None,
+            authority: None,
+            request_headers: Vec::new(),
+            request_trailers: Vec::new(),
+            request_body_file: None,
+            request_body_len: 0,
+            status: None,
+            response_headers: Vec::new(),
+            response_trailers: Vec::new(),
+            response_body_file: None,
+            response_body_len: 0,
+            reset_error: None,
+        }
+    }
+
+    /// fill in request fields from header block
+    pub fn set_request(&mut self, headers: Vec<(String, String)>) {
+        let find = |name: &str| find_header(&headers, name).map(str::to_string);
+        self.method = find(":method");
+        self.path = find(":path");
+        self.authority = find(":authority");
+        self.request_headers = headers;
+    }
+
+    /// fill in response fields from header block
+    pub fn set_response(&mut self, headers: Vec<(String, String)>) {
+        self.status = find_header(&headers, ":status").and_then(|s| s.parse().ok());
+        self.response_headers = headers;
+    }
+}
+
+/// file or message extracted from a plaintext protocol session
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExtractedFileInfo {
+    /// connection carrying the data
+    pub id: Uuid,
+    pub protocol: AppProtocol,
+    /// index of file within the session
+    pub index: u64,
+    /// FTP control connection the transfer was requested on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control_id: Option<Uuid>,
+    /// command requesting the transfer (e.g. `RETR notes.txt`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// user name the session logged in with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// SMTP envelope sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mail_from: Option<String>,
+    /// SMTP envelope recipients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rcpt_to: Vec<String>,
+    /// name of file in output directory
+    pub file: String,
+    pub len: u64,
+    /// whether the end of the file was seen without data loss
+    pub complete: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -28,6 +311,42 @@ pub struct ConnInfo {
     pub src_port: u16,
     pub dst_addr: IpAddr,
     pub dst_port: u16,
+    /// whether this is a UDP flow rather than a TCP connection
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub udp: bool,
+    /// TLS hello metadata, if recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsInfo>,
+    /// RTT estimate for forward direction segments
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_rtt: Option<RttStats>,
+    /// RTT estimate for reverse direction segments
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverse_rtt: Option<RttStats>,
+    /// TTLs of forward direction packets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_ttl: Option<TtlStats>,
+    /// TTLs of reverse direction packets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverse_ttl: Option<TtlStats>,
+    /// guessed application protocol
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<AppProtocol>,
+    /// timestamp of first packet (microseconds since epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_packet_us: Option<u64>,
+    /// timestamp of last packet (microseconds since epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_packet_us: Option<u64>,
+    /// forward direction counters, recorded at retire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward_stats: Option<StreamStats>,
+    /// reverse direction counters, recorded at retire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverse_stats: Option<StreamStats>,
+    /// why the connection was closed, recorded at retire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub close_reason: Option<CloseReason>,
 }
 
 impl ConnInfo {
@@ -38,8 +357,123 @@ impl ConnInfo {
             src_port: flow.src_port,
             dst_addr: flow.dst_addr,
             dst_port: flow.dst_port,
+            udp: flow.proto == IPPROTO_UDP,
+            tls: None,
+            forward_rtt: None,
+            reverse_rtt: None,
+            forward_ttl: None,
+            reverse_ttl: None,
+            protocol: None,
+            first_packet_us: None,
+            last_packet_us: None,
+            forward_stats: None,
+            reverse_stats: None,
+            close_reason: None,
         }
     }
+
+    /// set first and last packet timestamps
+    pub fn set_packet_times(&mut self, first: Option<Duration>, last: Option<Duration>) {
+        self.first_packet_us = first.map(|t| t.as_micros() as u64);
+        self.last_packet_us = last.map(|t| t.as_micros() as u64);
+    }
+
+    /// create from connection, including statistics
+    pub fn from_connection<H: ConnectionHandler>(conn: &Connection<H>) -> Self {
+        let mut info = Self::new(conn.uuid, &conn.forward_flow);
+        info.forward_rtt = conn.forward_rtt.stats();
+        info.reverse_rtt = conn.reverse_rtt.stats();
+        info.forward_ttl = conn.forward_ttl.stats();
+        info.reverse_ttl = conn.reverse_ttl.stats();
+        info.protocol = conn.classification.protocol();
+        info.set_packet_times(conn.first_packet_time, conn.last_packet_time);
+        info.forward_stats = Some(StreamStats::from_stream(&conn.forward_stream));
+        info.reverse_stats = Some(StreamStats::from_stream(&conn.reverse_stream));
+        info.close_reason = conn.close_reason;
+        info
+    }
+
+    /// swap source and destination
+    pub fn reverse(&mut self) {
+        std::mem::swap(&mut self.src_addr, &mut self.dst_addr);
+        std::mem::swap(&mut self.src_port, &mut self.dst_port);
+        std::mem::swap(&mut self.forward_rtt, &mut self.reverse_rtt);
+        std::mem::swap(&mut self.forward_ttl, &mut self.reverse_ttl);
+        std::mem::swap(&mut self.forward_stats, &mut self.reverse_stats);
+    }
+}
+
+/// output written for one direction
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DirectionProgress {
+    /// stream offset up to which data was written
+    pub end_offset: u64,
+    /// bytes of stream data written, to the data file or via the manifest
+    pub data_bytes: u64,
+    /// bytes written to the segments file, before compression
+    pub segments_bytes: u64,
+}
+
+/// contents of the `<id>.meta.json` sidecar of a connection
+///
+/// The sidecar is replaced atomically as output is written. If `complete` is
+/// false, the output was not finished and files may be truncated past the
+/// recorded counters.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputProgress {
+    pub id: Uuid,
+    /// whether all files were closed normally
+    pub complete: bool,
+    /// progress of `f.*` files
+    pub forward: DirectionProgress,
+    /// progress of `r.*` files
+    pub reverse: DirectionProgress,
+}
+
+/// packet referred to by a gap record
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PacketRef {
+    /// packet number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<u64>,
+    /// timestamp (microseconds since epoch)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts_us: Option<u64>,
+}
+
+/// why data in a stream was skipped
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GapCause {
+    /// data was not captured: enough later data arrived without it, or the
+    /// connection ended without it
+    Loss,
+    /// data was read past before it was declared lost, as buffer limits were
+    /// reached
+    BufferOverrun,
+}
+
+/// when and why a gap was skipped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GapInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cause: Option<GapCause>,
+    /// last data packet received before the gap appeared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<PacketRef>,
+    /// first data packet received past the gap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<PacketRef>,
+    /// packet being handled when the gap was declared or read past
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub declared: Option<PacketRef>,
+    /// data packets received past the gap before it was skipped
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub packets_skipped: u32,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
 }
 
 #[derive(Serialize, Deserialize)]
@@ -53,6 +487,8 @@ pub enum SerializedSegment {
         reverse_acked: u64,
         #[serde(flatten)]
         extra: PacketExtra,
+        #[serde(flatten)]
+        tcp: SegmentTcpInfo,
     },
     #[serde(rename = "ack")]
     Ack {
@@ -61,6 +497,8 @@ pub enum SerializedSegment {
         reverse_acked: u64,
         #[serde(flatten)]
         extra: PacketExtra,
+        #[serde(flatten)]
+        tcp: SegmentTcpInfo,
     },
     #[serde(rename = "fin")]
     Fin {
@@ -68,6 +506,8 @@ pub enum SerializedSegment {
         reverse_acked: u64,
         #[serde(flatten)]
         extra: PacketExtra,
+        #[serde(flatten)]
+        tcp: SegmentTcpInfo,
     },
     #[serde(rename = "rst")]
     Rst {
@@ -75,14 +515,62 @@ pub enum SerializedSegment {
         reverse_acked: u64,
         #[serde(flatten)]
         extra: PacketExtra,
+        #[serde(flatten)]
+        tcp: SegmentTcpInfo,
+    },
+    #[serde(rename = "datagram")]
+    Datagram {
+        offset: u64,
+        len: usize,
+        #[serde(flatten)]
+        extra: PacketExtra,
     },
     #[serde(rename = "gap")]
-    Gap { offset: u64, len: u64 },
+    Gap {
+        offset: u64,
+        len: u64,
+        #[serde(flatten)]
+        info: GapInfo,
+    },
+    /// correction for part of a gap, data arrived after it was read past
+    #[serde(rename = "late_data")]
+    LateData {
+        offset: u64,
+        len: usize,
+        /// whether data was patched into the data file, otherwise it was
+        /// appended to the late data file
+        patched: bool,
+        #[serde(flatten)]
+        extra: PacketExtra,
+    },
 }
 
 impl SerializedSegment {
     pub fn new_gap(offset: u64, len: u64) -> Self {
-        Self::Gap { offset, len }
+        Self::Gap {
+            offset,
+            len,
+            info: GapInfo::default(),
+        }
+    }
+
+    pub fn new_late_data(late: &LateData, patched: bool) -> Self {
+        Self::LateData {
+            offset: late.offset,
+            len: late.data.len(),
+            patched,
+            extra: late.extra.clone(),
+        }
+    }
+}
+
+impl From<&StreamGap> for SerializedSegment {
+    fn from(gap: &StreamGap) -> Self {
+        Self::Gap {
+            offset: gap.range.start,
+            len: gap.range.end - gap.range.start,
+            info: gap.info,
+        }
     }
 }
 
@@ -95,23 +583,104 @@ impl From<&SegmentInfo> for SerializedSegment {
                 is_retransmit,
                 reverse_acked: info.reverse_acked,
                 extra: info.extra.clone(),
+                tcp: info.tcp,
             },
             SegmentType::Ack { window } => Self::Ack {
                 offset: info.offset,
                 window,
                 reverse_acked: info.reverse_acked,
                 extra: info.extra.clone(),
+                tcp: info.tcp,
             },
             SegmentType::Fin { end_offset } => Self::Fin {
                 offset: end_offset,
                 reverse_acked: info.reverse_acked,
                 extra: info.extra.clone(),
+                tcp: info.tcp,
             },
             SegmentType::Rst => Self::Rst {
                 offset: info.offset,
                 reverse_acked: info.reverse_acked,
                 extra: info.extra.clone(),
+                tcp: info.tcp,
             },
         }
     }
 }
+
+/// entry of a deduplicated stream manifest
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// stream offset of first byte of block
+    pub offset: u64,
+    pub len: usize,
+    /// BLAKE3 hash of block, hex encoded
+    pub hash: String,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{PacketExtra, SerializedSegment};
+    use crate::stream::{SegmentInfo, SegmentTcpInfo, SegmentType};
+
+    #[test]
+    fn pcapng_extra() {
+        let info = SegmentInfo {
+            offset: 10,
+            reverse_acked: 0,
+            extra: PacketExtra::Pcapng {
+                index: 3,
+                interface_id: 1,
+                interface_name: Some("eth0".into()),
+                ts_nsec: 1_500_000_001,
+                dropped: Some(2),
+                interface_dropped: None,
+                vlan_id: None,
+                frames: None,
+                ttl: None,
+                bad_checksum: false,
+            },
+            tcp: SegmentTcpInfo {
+                ece: true,
+                ip_ecn: 3,
+                ..Default::default()
+            },
+            data: SegmentType::Data {
+                len: 5,
+                is_retransmit: false,
+            },
+        };
+        assert_eq!(info.extra.timestamp(), Some(Duration::new(1, 500_000_001)));
+        let json = serde_json::to_string(&SerializedSegment::from(&info)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"data","offset":10,"len":5,"is_retransmit":false,"reverse_acked":0,"index":3,"interface_id":1,"interface_name":"eth0","ts_nsec":1500000001,"dropped":2,"ece":true,"ip_ecn":3}"#
+        );
+        let SerializedSegment::Data { extra, tcp, .. } = serde_json::from_str(&json).unwrap()
+        else {
+            panic!("wrong segment type");
+        };
+        assert!(tcp.congestion_experienced());
+        let PacketExtra::Pcapng {
+            interface_name,
+            dropped,
+            ..
+        } = extra
+        else {
+            panic!("wrong extra variant");
+        };
+        assert_eq!(interface_name.as_deref(), Some("eth0"));
+        assert_eq!(dropped, Some(2));
+
+        // legacy records still deserialize as before
+        let legacy = r#"{"type":"ack","offset":1,"window":0,"reverse_acked":0,"index":0,"ts_sec":1,"ts_usec":2}"#;
+        let SerializedSegment::Ack { extra, tcp, .. } = serde_json::from_str(legacy).unwrap()
+        else {
+            panic!("wrong segment type");
+        };
+        assert_eq!(tcp, SegmentTcpInfo::default());
+        assert!(matches!(extra, PacketExtra::LegacyPcap { ts_usec: 2, .. }));
+    }
+}
@@ -0,0 +1,498 @@
+//! SQLite output
+//!
+//! Writes connections, segment metadata and optionally stream data into a
+//! single database. Writes are batched into transactions.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use rusqlite::params;
+use tracing::{debug, error, info, trace};
+
+use crate::connection::{Connection, Direction};
+use crate::serialized::ConnInfo;
+use crate::stream::{SegmentInfo, SegmentType, StreamGap};
+use crate::ConnectionHandler;
+
+/// number of inserts per transaction
+pub const SQLITE_COMMIT_INTERVAL: usize = 16 << 10;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS connections (
+    id TEXT PRIMARY KEY,
+    src_addr TEXT NOT NULL,
+    src_port INTEGER NOT NULL,
+    dst_addr TEXT NOT NULL,
+    dst_port INTEGER NOT NULL,
+    protocol TEXT,
+    first_packet_us INTEGER,
+    last_packet_us INTEGER,
+    info TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS connections_tuple
+    ON connections (src_addr, src_port, dst_addr, dst_port);
+CREATE INDEX IF NOT EXISTS connections_time ON connections (first_packet_us);
+
+CREATE TABLE IF NOT EXISTS segments (
+    conn_id TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    type TEXT NOT NULL,
+    offset INTEGER NOT NULL,
+    len INTEGER,
+    is_retransmit INTEGER,
+    window INTEGER,
+    reverse_acked INTEGER,
+    packet_index INTEGER,
+    ts_us INTEGER
+);
+CREATE INDEX IF NOT EXISTS segments_conn ON segments (conn_id, direction, offset);
+CREATE INDEX IF NOT EXISTS segments_time ON segments (ts_us);
+
+CREATE TABLE IF NOT EXISTS stream_data (
+    conn_id TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    offset INTEGER NOT NULL,
+    data BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS stream_data_conn ON stream_data (conn_id, direction, offset);
+";
+
+/// database connection with an open transaction
+pub struct SqliteWriter {
+    pub db: rusqlite::Connection,
+    /// inserts since the last commit
+    pub pending: usize,
+}
+
+impl SqliteWriter {
+    /// account for an insert, committing if the batch is full
+    fn inserted(&mut self) -> rusqlite::Result<()> {
+        self.pending += 1;
+        if self.pending >= SQLITE_COMMIT_INTERVAL {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// commit current transaction and start a new one
+    pub fn commit(&mut self) -> rusqlite::Result<()> {
+        trace!("committing {} inserts", self.pending);
+        self.db.execute_batch("COMMIT; BEGIN")?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// insert connection row
+    pub fn insert_connection(&mut self, info: &ConnInfo) -> rusqlite::Result<()> {
+        let protocol = info
+            .protocol
+            .map(|p| serde_json::to_value(p).expect("failed to serialize protocol"));
+        self.db
+            .prepare_cached(
+                "INSERT OR REPLACE INTO connections VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?
+            .execute(params![
+                info.id.to_string(),
+                info.src_addr.to_string(),
+                info.src_port,
+                info.dst_addr.to_string(),
+                info.dst_port,
+                protocol.as_ref().and_then(|p| p.as_str()),
+                info.first_packet_us.map(|t| t as i64),
+                info.last_packet_us.map(|t| t as i64),
+                serde_json::to_string(info).expect("failed to serialize ConnInfo"),
+            ])?;
+        self.inserted()
+    }
+
+    /// swap direction of rows already written for connection
+    pub fn swap_directions(&mut self, id: &str) -> rusqlite::Result<()> {
+        for table in ["segments", "stream_data"] {
+            self.db.execute(
+                &format!(
+                    "UPDATE {table} SET direction = CASE direction \
+                    WHEN 'forward' THEN 'reverse' ELSE 'forward' END WHERE conn_id = ?1"
+                ),
+                params![id],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// insert segment row
+    pub fn insert_segment(
+        &mut self,
+        id: &str,
+        direction: &str,
+        segment: &SegmentInfo,
+    ) -> rusqlite::Result<()> {
+        let (kind, offset, len, is_retransmit, window) = match segment.data {
+            SegmentType::Data { len, is_retransmit } => {
+                ("data", segment.offset, Some(len), Some(is_retransmit), None)
+            }
+            SegmentType::Ack { window } => ("ack", segment.offset, None, None, Some(window)),
+            SegmentType::Fin { end_offset } => ("fin", end_offset, None, None, None),
+            SegmentType::Rst => ("rst", segment.offset, None, None, None),
+        };
+        self.db
+            .prepare_cached(
+                "INSERT INTO segments VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            )?
+            .execute(params![
+                id,
+                direction,
+                kind,
+                offset as i64,
+                len.map(|l| l as i64),
+                is_retransmit,
+                window.map(|w| w as i64),
+                segment.reverse_acked as i64,
+                segment.extra.index().map(|i| i as i64),
+                segment.extra.timestamp().map(|t| t.as_micros() as i64),
+            ])?;
+        self.inserted()
+    }
+
+    /// insert gap as a segment row, with the packet it was declared at
+    pub fn insert_gap(
+        &mut self,
+        id: &str,
+        direction: &str,
+        gap: &StreamGap,
+    ) -> rusqlite::Result<()> {
+        let declared = gap.info.declared.unwrap_or_default();
+        self.db
+            .prepare_cached(
+                "INSERT INTO segments (conn_id, direction, type, offset, len, packet_index, ts_us)
+                    VALUES (?1, ?2, 'gap', ?3, ?4, ?5, ?6)",
+            )?
+            .execute(params![
+                id,
+                direction,
+                gap.range.start as i64,
+                (gap.range.end - gap.range.start) as i64,
+                declared.index.map(|i| i as i64),
+                declared.ts_us.map(|t| t as i64),
+            ])?;
+        self.inserted()
+    }
+
+    /// insert stream data chunk
+    pub fn insert_data(
+        &mut self,
+        id: &str,
+        direction: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> rusqlite::Result<()> {
+        self.db
+            .prepare_cached("INSERT INTO stream_data VALUES (?1, ?2, ?3, ?4)")?
+            .execute(params![id, direction, offset as i64, data])?;
+        self.inserted()
+    }
+}
+
+/// shared state for SqliteOutputHandler
+pub struct SqliteOutputSharedInfoInner {
+    pub writer: Mutex<SqliteWriter>,
+    /// whether stream data is stored in addition to metadata
+    pub store_data: bool,
+}
+
+#[derive(Clone)]
+pub struct SqliteOutputSharedInfo {
+    pub inner: Arc<SqliteOutputSharedInfoInner>,
+}
+
+impl SqliteOutputSharedInfo {
+    /// open or create database at path
+    pub fn new(path: &Path, store_data: bool) -> rusqlite::Result<Self> {
+        let db = rusqlite::Connection::open(path)?;
+        // output can be regenerated, favor speed over durability
+        db.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = OFF;")?;
+        Self::with_connection(db, store_data)
+    }
+
+    /// create from existing database connection
+    pub fn with_connection(db: rusqlite::Connection, store_data: bool) -> rusqlite::Result<Self> {
+        db.execute_batch(SCHEMA)?;
+        db.execute_batch("BEGIN")?;
+        Ok(SqliteOutputSharedInfo {
+            inner: Arc::new(SqliteOutputSharedInfoInner {
+                writer: Mutex::new(SqliteWriter { db, pending: 0 }),
+                store_data,
+            }),
+        })
+    }
+
+    /// commit pending writes and close database
+    pub fn close(self) -> rusqlite::Result<()> {
+        let inner = Arc::into_inner(self.inner).expect("handlers still hold shared info");
+        let writer = inner.writer.into_inner();
+        writer.db.execute_batch("COMMIT")?;
+        writer.db.close().map_err(|(_, e)| e)
+    }
+}
+
+/// ConnectionHandler to write connections to a SQLite database
+pub struct SqliteOutputHandler {
+    pub shared_info: SqliteOutputSharedInfo,
+    pub id: String,
+    pub gaps: Vec<StreamGap>,
+    pub segments: Vec<SegmentInfo>,
+    /// whether we received the handshake_done event
+    pub got_handshake_done: bool,
+}
+
+impl SqliteOutputHandler {
+    /// write out segments, gaps and data up to `maybe_dump_len` bytes, or
+    /// everything remaining
+    pub fn write_stream_data(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        maybe_dump_len: Option<usize>,
+    ) -> rusqlite::Result<()> {
+        self.gaps.clear();
+        self.segments.clear();
+        let store_data = self.shared_info.inner.store_data;
+        let stream = connection.get_stream(direction);
+        let dump_len = if let Some(dump_len) = maybe_dump_len {
+            dump_len
+        } else {
+            stream.pop_segments_until(None, &mut self.segments);
+            stream.total_buffered_length()
+        };
+
+        let direction = direction.to_string();
+        let mut writer = self.shared_info.inner.writer.lock();
+        if dump_len > 0 {
+            let start_offset = stream.buffer_start();
+            let end_offset = start_offset + dump_len as u64;
+            let id = &self.id;
+            let read = stream.read_next(end_offset, &mut self.segments, &mut self.gaps, |slice| {
+                if !store_data {
+                    return Ok(());
+                }
+                let (a, b) = slice.as_slices();
+                let mut data = Vec::with_capacity(dump_len);
+                data.extend_from_slice(a);
+                if let Some(b) = b {
+                    data.extend_from_slice(b);
+                }
+                writer.insert_data(id, &direction, start_offset, &data)
+            });
+            let Some(result) = read else {
+                error!("sqlite: {direction} stream cannot fulfill range, skipping");
+                return Ok(());
+            };
+            result?;
+        }
+        for gap in &self.gaps {
+            writer.insert_gap(&self.id, &direction, gap)?;
+        }
+        for segment in &self.segments {
+            writer.insert_segment(&self.id, &direction, segment)?;
+        }
+        self.gaps.clear();
+        self.segments.clear();
+        Ok(())
+    }
+}
+
+fn log_error(result: rusqlite::Result<()>, what: &str) {
+    if let Err(e) = result {
+        error!("{what}: {e:?}");
+    }
+}
+
+impl ConnectionHandler for SqliteOutputHandler {
+    type InitialData = SqliteOutputSharedInfo;
+    type ConstructError = eyre::Report;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> eyre::Result<Self> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(SqliteOutputHandler {
+            shared_info,
+            id: connection.uuid.to_string(),
+            gaps: Vec::new(),
+            segments: Vec::new(),
+            got_handshake_done: false,
+        })
+    }
+
+    fn handshake_done(&mut self, connection: &mut Connection<Self>) {
+        info!(
+            "writing data for new connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        self.got_handshake_done = true;
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let config = connection.config.clone();
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        if readable_len > config.flush_readable_threshold
+            || stream.segments_info.len() > config.flush_segments_threshold
+        {
+            log_error(
+                self.write_stream_data(connection, direction, Some(readable_len)),
+                "failed to write stream data",
+            );
+        } else if stream.total_buffered_length() > config.flush_total_threshold {
+            log_error(
+                self.write_stream_data(connection, direction, Some(config.flush_total_advance)),
+                "failed to write stream data",
+            );
+        }
+    }
+
+    fn memory_pressure(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        log_error(
+            self.write_stream_data(connection, direction, None),
+            "failed to write stream data",
+        );
+    }
+
+    fn direction_changed(&mut self, _connection: &mut Connection<Self>) {
+        let result = self
+            .shared_info
+            .inner
+            .writer
+            .lock()
+            .swap_directions(&self.id);
+        log_error(result, "failed to update stream directions");
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        info!(
+            "removing connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        if !self.got_handshake_done {
+            // nothing to write if no data
+            return;
+        }
+        for direction in [Direction::Forward, Direction::Reverse] {
+            log_error(
+                self.write_stream_data(connection, direction, None),
+                "failed to write final stream data",
+            );
+        }
+        let info = ConnInfo::from_connection(connection);
+        let result = self
+            .shared_info
+            .inner
+            .writer
+            .lock()
+            .insert_connection(&info);
+        log_error(result, "failed to write connection info");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::flow_table::FlowTable;
+    use crate::serialized::PacketExtra;
+    use crate::{TcpFlags, TcpMeta};
+
+    use super::{SqliteOutputHandler, SqliteOutputSharedInfo};
+
+    #[test]
+    fn write_connection() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        let shared_info = SqliteOutputSharedInfo::with_connection(db, true).unwrap();
+        let mut table: FlowTable<SqliteOutputHandler> = FlowTable::new(shared_info.clone());
+
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let extra = |index| PacketExtra::LegacyPcap {
+            index,
+            ts_sec: 1,
+            ts_usec: 0,
+            vlan_id: None,
+            frames: None,
+            ttl: None,
+            bad_checksum: false,
+        };
+        let mut syn_ack = meta.clone();
+        std::mem::swap(&mut syn_ack.src_addr, &mut syn_ack.dst_addr);
+        std::mem::swap(&mut syn_ack.src_port, &mut syn_ack.dst_port);
+        syn_ack.seq_number = 500;
+        syn_ack.ack_number = 101;
+        syn_ack.flags.ack = true;
+        table.handle_packet(&meta, &[], &extra(0)).unwrap();
+        table.handle_packet(&syn_ack, &[], &extra(1)).unwrap();
+        meta.seq_number = 101;
+        meta.ack_number = 501;
+        meta.flags = TcpFlags {
+            ack: true,
+            ..Default::default()
+        };
+        table.handle_packet(&meta, &[], &extra(2)).unwrap();
+        table
+            .handle_packet(&meta, b"GET / HTTP/1.1\r\n\r\n", &extra(3))
+            .unwrap();
+        table.close();
+        drop(table);
+
+        {
+            let writer = shared_info.inner.writer.lock();
+            let (dst_port, protocol): (u16, String) = writer
+                .db
+                .query_row("SELECT dst_port, protocol FROM connections", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .unwrap();
+            assert_eq!((dst_port, protocol.as_str()), (80, "http"));
+            let data: Vec<u8> = writer
+                .db
+                .query_row(
+                    "SELECT data FROM stream_data WHERE direction = 'forward'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(data, b"GET / HTTP/1.1\r\n\r\n");
+            let data_segments: i64 = writer
+                .db
+                .query_row(
+                    "SELECT count(*) FROM segments WHERE type = 'data' AND packet_index = 3",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(data_segments, 1);
+        }
+        shared_info.close().unwrap();
+    }
+}
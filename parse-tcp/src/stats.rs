@@ -0,0 +1,388 @@
+use std::io::{self, Write};
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::connection::Connection;
+use crate::stream::Stream;
+use crate::ConnectionHandler;
+
+/// statistics for one direction of a connection
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StreamStats {
+    /// packets sent in this direction
+    pub packets: u64,
+    /// payload bytes, including retransmissions
+    pub bytes: u64,
+    /// payload bytes excluding retransmissions
+    pub goodput_bytes: u64,
+    /// payload bytes in retransmitted segments
+    pub retransmit_bytes: u64,
+    /// number of retransmitted segments
+    pub retransmits: u64,
+    /// bytes skipped due to gaps
+    pub gap_bytes: u64,
+    /// number of FIN packets
+    pub fin_count: u64,
+    /// number of accepted RST packets
+    pub rst_count: u64,
+    /// number of segments rejected as their TSval regressed
+    #[serde(default)]
+    pub paws_rejected: u64,
+}
+
+impl StreamStats {
+    /// collect from stream
+    pub fn from_stream(stream: &Stream) -> Self {
+        StreamStats {
+            packets: stream.packet_count,
+            bytes: stream.data_bytes,
+            goodput_bytes: stream.data_bytes - stream.retransmit_bytes,
+            retransmit_bytes: stream.retransmit_bytes,
+            retransmits: stream.retransmit_count as u64,
+            gap_bytes: stream.gaps_length,
+            fin_count: stream.fin_count as u64,
+            rst_count: stream.rst_count as u64,
+            paws_rejected: stream.paws_rejected as u64,
+        }
+    }
+
+    /// counters for datagrams, which are never retransmitted or lost
+    pub fn datagrams(packets: u64, bytes: u64) -> Self {
+        StreamStats {
+            packets,
+            bytes,
+            goodput_bytes: bytes,
+            ..Default::default()
+        }
+    }
+
+    /// add counters of other
+    pub fn add(&mut self, other: &StreamStats) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+        self.goodput_bytes += other.goodput_bytes;
+        self.retransmit_bytes += other.retransmit_bytes;
+        self.retransmits += other.retransmits;
+        self.gap_bytes += other.gap_bytes;
+        self.fin_count += other.fin_count;
+        self.rst_count += other.rst_count;
+        self.paws_rejected += other.paws_rejected;
+    }
+}
+
+/// statistics for a connection
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub id: Uuid,
+    pub src_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub dst_port: u16,
+    /// capture time of first packet, in microseconds
+    pub start_us: Option<u64>,
+    /// time between first and last packet, in microseconds
+    pub duration_us: Option<u64>,
+    pub observed_handshake: bool,
+    pub observed_close: bool,
+    pub forward: StreamStats,
+    pub reverse: StreamStats,
+}
+
+/// compute average rate in bytes per second
+fn rate(bytes: u64, duration_us: Option<u64>) -> Option<f64> {
+    match duration_us {
+        Some(us) if us > 0 => Some(bytes as f64 * 1_000_000.0 / us as f64),
+        _ => None,
+    }
+}
+
+impl ConnectionStats {
+    /// collect from connection
+    pub fn from_connection<H: ConnectionHandler>(conn: &Connection<H>) -> Self {
+        let flow = &conn.forward_flow;
+        let duration = match (conn.first_packet_time, conn.last_packet_time) {
+            (Some(first), Some(last)) => Some(last.saturating_sub(first)),
+            _ => None,
+        };
+        ConnectionStats {
+            id: conn.uuid,
+            src_addr: flow.src_addr,
+            src_port: flow.src_port,
+            dst_addr: flow.dst_addr,
+            dst_port: flow.dst_port,
+            start_us: conn.first_packet_time.map(|t| t.as_micros() as u64),
+            duration_us: duration.map(|d| d.as_micros() as u64),
+            observed_handshake: conn.observed_handshake,
+            observed_close: conn.observed_close,
+            forward: StreamStats::from_stream(&conn.forward_stream),
+            reverse: StreamStats::from_stream(&conn.reverse_stream),
+        }
+    }
+
+    /// average forward goodput in bytes per second
+    pub fn forward_goodput(&self) -> Option<f64> {
+        rate(self.forward.goodput_bytes, self.duration_us)
+    }
+
+    /// average reverse goodput in bytes per second
+    pub fn reverse_goodput(&self) -> Option<f64> {
+        rate(self.reverse.goodput_bytes, self.duration_us)
+    }
+}
+
+/// totals over all connections
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsSummary {
+    /// number of connections
+    pub connections: u64,
+    /// connections with a complete handshake
+    pub handshakes: u64,
+    /// connections with an observed close
+    pub closes: u64,
+    /// capture time of first packet, in microseconds
+    pub start_us: Option<u64>,
+    /// time between first and last packet, in microseconds
+    pub duration_us: Option<u64>,
+    /// totals over both directions
+    pub total: StreamStats,
+    /// average goodput over the capture, in bytes per second
+    pub goodput: Option<f64>,
+}
+
+/// full statistics report
+#[derive(Serialize, Deserialize)]
+pub struct StatsReport {
+    pub summary: StatsSummary,
+    pub connections: Vec<ConnectionStats>,
+}
+
+/// collects statistics of retired connections
+#[derive(Default)]
+pub struct StatsCollector {
+    /// per-connection statistics
+    pub connections: Vec<ConnectionStats>,
+}
+
+impl StatsCollector {
+    /// create new instance
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// record statistics of connection
+    pub fn record<H: ConnectionHandler>(&mut self, conn: &Connection<H>) {
+        self.connections
+            .push(ConnectionStats::from_connection(conn));
+    }
+
+    /// compute totals
+    pub fn summary(&self) -> StatsSummary {
+        let mut summary = StatsSummary::default();
+        let mut end: Option<u64> = None;
+        for conn in &self.connections {
+            summary.connections += 1;
+            summary.handshakes += conn.observed_handshake as u64;
+            summary.closes += conn.observed_close as u64;
+            summary.total.add(&conn.forward);
+            summary.total.add(&conn.reverse);
+            if let Some(start) = conn.start_us {
+                let conn_end = start + conn.duration_us.unwrap_or(0);
+                summary.start_us = Some(summary.start_us.map_or(start, |s| s.min(start)));
+                end = Some(end.map_or(conn_end, |e| e.max(conn_end)));
+            }
+        }
+        if let (Some(start), Some(end)) = (summary.start_us, end) {
+            summary.duration_us = Some(end - start);
+        }
+        summary.goodput = rate(summary.total.goodput_bytes, summary.duration_us);
+        summary
+    }
+
+    /// consume into report
+    pub fn into_report(self) -> StatsReport {
+        StatsReport {
+            summary: self.summary(),
+            connections: self.connections,
+        }
+    }
+
+    /// write report as JSON
+    pub fn write_json(self, writer: impl Write) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(writer, &self.into_report())
+    }
+
+    /// write one row per connection as CSV, followed by a row of totals with
+    /// id `total`
+    pub fn write_csv(&self, mut writer: impl Write) -> io::Result<()> {
+        fn opt<T: ToString>(value: Option<T>) -> String {
+            value.map(|v| v.to_string()).unwrap_or_default()
+        }
+        fn stream(s: &StreamStats) -> String {
+            format!(
+                "{},{},{},{},{},{},{},{},{}",
+                s.packets,
+                s.bytes,
+                s.goodput_bytes,
+                s.retransmit_bytes,
+                s.retransmits,
+                s.gap_bytes,
+                s.fin_count,
+                s.rst_count,
+                s.paws_rejected
+            )
+        }
+        const STREAM_COLUMNS: [&str; 9] = [
+            "packets",
+            "bytes",
+            "goodput_bytes",
+            "retransmit_bytes",
+            "retransmits",
+            "gap_bytes",
+            "fin_count",
+            "rst_count",
+            "paws_rejected",
+        ];
+
+        let mut header = String::from(
+            "id,src_addr,src_port,dst_addr,dst_port,start_us,duration_us,\
+                observed_handshake,observed_close",
+        );
+        for dir in ["forward", "reverse"] {
+            for column in STREAM_COLUMNS {
+                header += &format!(",{dir}_{column}");
+            }
+        }
+        header += ",forward_goodput,reverse_goodput";
+        writeln!(writer, "{header}")?;
+
+        let summary = self.summary();
+        for conn in &self.connections {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                conn.id,
+                conn.src_addr,
+                conn.src_port,
+                conn.dst_addr,
+                conn.dst_port,
+                opt(conn.start_us),
+                opt(conn.duration_us),
+                conn.observed_handshake,
+                conn.observed_close,
+                stream(&conn.forward),
+                stream(&conn.reverse),
+                opt(conn.forward_goodput()),
+                opt(conn.reverse_goodput()),
+            )?;
+        }
+        // totals are not split by direction, reverse columns are left empty
+        writeln!(
+            writer,
+            "total,,,,,{},{},{},{},{}{},{},",
+            opt(summary.start_us),
+            opt(summary.duration_us),
+            summary.handshakes,
+            summary.closes,
+            stream(&summary.total),
+            ",".repeat(STREAM_COLUMNS.len()),
+            opt(summary.goodput),
+        )?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use super::StatsCollector;
+    use crate::connection::Connection;
+    use crate::serialized::PacketExtra;
+    use crate::{ConnectionHandler, TcpFlags, TcpMeta};
+
+    struct NullHandler;
+    impl ConnectionHandler for NullHandler {
+        type InitialData = ();
+        type ConstructError = Infallible;
+        fn new(_init: (), _conn: &mut Connection<Self>) -> Result<Self, Infallible> {
+            Ok(NullHandler)
+        }
+    }
+
+    fn extra(index: u64, ts_sec: u32) -> PacketExtra {
+        PacketExtra::LegacyPcap {
+            index,
+            ts_sec,
+            ts_usec: 0,
+            vlan_id: None,
+            frames: None,
+            ttl: None,
+            bad_checksum: false,
+        }
+    }
+
+    #[test]
+    fn counters() {
+        let mut meta = TcpMeta {
+            src_addr: [10, 0, 0, 1].into(),
+            src_port: 1000,
+            dst_addr: [10, 0, 0, 2].into(),
+            dst_port: 80,
+            seq_number: 100,
+            ack_number: 500,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            window: 1000,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let mut conn: Connection<NullHandler> = Connection::new((&meta).into(), ()).unwrap();
+        // connection picked up mid-stream
+        assert!(conn.handle_packet(&meta, b"hello", &extra(0, 10)));
+        // retransmit
+        conn.handle_packet(&meta, b"hello", &extra(1, 11));
+        meta.seq_number += 5;
+        meta.flags.fin = true;
+        assert!(conn.handle_packet(&meta, b"!", &extra(2, 12)));
+
+        let mut collector = StatsCollector::new();
+        collector.record(&conn);
+        let stats = &collector.connections[0];
+        assert_eq!(stats.duration_us, Some(2_000_000));
+        assert_eq!(stats.forward.packets, 3);
+        assert_eq!(stats.forward.bytes, 11);
+        assert_eq!(stats.forward.goodput_bytes, 6);
+        assert_eq!(stats.forward.retransmit_bytes, 5);
+        assert_eq!(stats.forward.fin_count, 1);
+        assert_eq!(stats.reverse.packets, 0);
+        assert_eq!(stats.forward_goodput(), Some(3.0));
+
+        let summary = collector.summary();
+        assert_eq!(summary.connections, 1);
+        assert_eq!(summary.start_us, Some(10_000_000));
+        assert_eq!(summary.duration_us, Some(2_000_000));
+        assert_eq!(summary.total.bytes, 11);
+
+        let mut csv = Vec::new();
+        collector.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let columns = lines[0].split(',').count();
+        assert!(lines.iter().all(|l| l.split(',').count() == columns));
+        assert!(lines[2].starts_with("total,"));
+    }
+}
@@ -1,30 +1,53 @@
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, VecDeque};
+use std::mem;
 use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
 
 use kinesin_rdt::common::ring_buffer::RingBufSlice;
 use kinesin_rdt::stream::inbound::{ReceiveSegmentResult, StreamInboundState};
+use serde::{Deserialize, Serialize};
 use tracing::{debug, trace, warn};
 
-use crate::PacketExtra;
+use crate::config::ReassemblyConfig;
+use crate::log_throttle::{warn_throttled, WarnCategory};
+use crate::serialized::{GapCause, GapInfo, PacketRef};
+use crate::{PacketExtra, TcpMeta};
 
-/// size of the sequence number sliding window
+/// default size of the sequence number sliding window
 pub const SEQ_WINDOW_SIZE: u32 = 1024 << 20; // MB
-/// threshold for advancing the sequence number window
+/// default threshold for advancing the sequence number window
 pub const SEQ_WINDOW_ADVANCE_THRESHOLD: u32 = 512 << 20;
-/// how much to advance the sequence number window by
+/// default for how much to advance the sequence number window by
 pub const SEQ_WINDOW_ADVANCE_BY: u32 = 256 << 20;
-/// max allowed size of stream buffer
+/// default max allowed size of stream buffer
 pub const MAX_ALLOWED_BUFFER_SIZE: u64 = 128 << 20;
-/// max size of segments_info in eleemnts
+/// default max size of segments_info in elements
 pub const MAX_SEGMENTS_INFO_COUNT: usize = 128 << 10;
-/// how far forward to allow reset packets
+/// default for how far forward to allow reset packets
 pub const RESET_MAX_LOOKAHEAD: u32 = 16 << 20;
-/// how far back to allow reset packets
+/// default for how far back to allow reset packets
 pub const RESET_MAX_LOOKBEHIND: u32 = 256 << 10;
+/// default number of data packets received past missing data before it is
+/// declared a gap
+pub const GAP_WAIT_PACKETS: u32 = 8;
+/// default number of bytes buffered past missing data before it is declared a
+/// gap
+pub const GAP_WAIT_BYTES: u64 = 256 << 10;
+/// default for how far TSval may fall behind the highest accepted TSval before
+/// a segment is rejected
+pub const PAWS_MAX_REGRESSION: u32 = 1 << 20;
+/// default number of packets for which skipped gaps accept late data, 0
+/// disables late data handling
+pub const LATE_DATA_PACKETS: u32 = 0;
+/// default for how far behind the read offset skipped gaps accept late data
+pub const LATE_DATA_BYTES: u64 = 1 << 20;
 
 // TODO: track segments so we can have metadata in a heap or something
 /// unidirectional stream of a connection
 pub struct Stream {
+    /// reassembly limits
+    pub config: Arc<ReassemblyConfig>,
     /// initial sequence number
     pub initial_sequence_number: u32,
     /// offset from packet sequence number to absolute stream offset
@@ -51,23 +74,72 @@ pub struct Stream {
 
     /// count of bytes skipped due to gaps
     pub gaps_length: u64,
+    /// end of the last missing range declared a gap
+    pub declared_gap_end: u64,
+    /// start of first missing range not yet declared a gap, and data packets
+    /// received since
+    pub pending_gap: Option<(u64, u32)>,
+    /// last data packet before and first data packet past the pending gap
+    pub pending_gap_edges: (Option<PacketRef>, Option<PacketRef>),
+    /// gaps declared but not yet read, in order of offset
+    pub declared_gaps: VecDeque<StreamGap>,
+    /// gaps already read past which may still be filled in by late data, in
+    /// order of offset
+    pub skipped_gaps: VecDeque<SkippedGap>,
+    /// late data for skipped gaps not yet passed to handlers
+    pub late_data: Vec<LateData>,
+    /// count of late bytes received for skipped gaps
+    pub late_data_bytes: u64,
+    /// last data packet received
+    pub last_data_packet: Option<PacketRef>,
+    /// data packet received before the last one
+    pub previous_data_packet: Option<PacketRef>,
     /// detected retransmission count
     pub retransmit_count: usize,
+    /// number of packets sent in this direction
+    pub packet_count: u64,
+    /// timestamp of first packet sent in this direction
+    pub first_packet_time: Option<Duration>,
+    /// timestamp of last packet sent in this direction
+    pub last_packet_time: Option<Duration>,
+    /// count of payload bytes received, including retransmissions
+    pub data_bytes: u64,
+    /// count of payload bytes received in retransmitted segments
+    pub retransmit_bytes: u64,
+    /// number of FIN packets received, including retransmissions
+    pub fin_count: usize,
+    /// number of accepted RST packets
+    pub rst_count: usize,
+    /// highest TSval of accepted segments
+    pub ts_recent: Option<u32>,
+    /// number of segments rejected as their TSval regressed
+    pub paws_rejected: usize,
     /// segment metadata
     pub segments_info: BinaryHeap<SegmentInfo>,
     /// number of packets not written to segments_info because it was full
     pub segments_info_dropped: usize,
+    /// buffer length last reported to the memory budget and metrics
+    pub memory_accounted: u64,
+    /// usage left after a `memory_pressure` call which did not reduce it, the
+    /// stream is skipped by later calls until it grows beyond this
+    pub memory_pressure_unrelieved: Option<u64>,
 }
 
 impl Stream {
     /// create new instance
-    pub fn new() -> Self {
+    pub fn new(config: Arc<ReassemblyConfig>) -> Self {
+        let mut state = match &config.buffer_pool {
+            Some(pool) => StreamInboundState::with_pool(0, true, pool.clone()),
+            None => StreamInboundState::new(0, true),
+        };
+        state.overlap_policy = config.overlap_policy;
         Stream {
+            config,
             initial_sequence_number: 0,
             seq_offset: SeqOffset::Initial(0),
             window_scale: 0,
             got_window_scale: false,
-            state: StreamInboundState::new(0, true),
+            state,
             seq_window_start: 0,
             seq_window_end: 0,
             highest_acked: 0,
@@ -75,10 +147,47 @@ impl Stream {
             had_reset: false,
             has_ended: false,
             gaps_length: 0,
+            declared_gap_end: 0,
+            pending_gap: None,
+            pending_gap_edges: (None, None),
+            declared_gaps: VecDeque::new(),
+            skipped_gaps: VecDeque::new(),
+            late_data: Vec::new(),
+            late_data_bytes: 0,
+            last_data_packet: None,
+            previous_data_packet: None,
             retransmit_count: 0,
+            packet_count: 0,
+            first_packet_time: None,
+            last_packet_time: None,
+            data_bytes: 0,
+            retransmit_bytes: 0,
+            fin_count: 0,
+            rst_count: 0,
+            ts_recent: None,
+            paws_rejected: 0,
             segments_info: BinaryHeap::new(),
             segments_info_dropped: 0,
+            memory_accounted: 0,
+            memory_pressure_unrelieved: None,
+        }
+    }
+
+    /// report change in buffer length to the memory budget and metrics, if
+    /// any
+    pub fn update_memory_usage(&mut self) {
+        self.report_buffered(self.state.buffer.len() as u64);
+    }
+
+    /// report `buffered` bytes in place of the previously reported length
+    fn report_buffered(&mut self, buffered: u64) {
+        if let Some(budget) = &self.config.memory_budget {
+            budget.update(self.memory_accounted, buffered);
+        }
+        if let Some(metrics) = &self.config.metrics {
+            metrics.update_buffered(self.memory_accounted, buffered);
         }
+        self.memory_accounted = buffered;
     }
 
     /// return the number of bytes currently buffered and readable
@@ -105,7 +214,10 @@ impl Stream {
     pub fn set_window_scale(&mut self, window_scale: u8) -> bool {
         if window_scale > 14 {
             // max value is 14
-            warn!("rejected oversized window_scale value: {window_scale}");
+            warn_throttled!(
+                WarnCategory::Handshake,
+                "rejected oversized window_scale value: {window_scale}"
+            );
             false
         } else {
             self.window_scale = window_scale;
@@ -154,32 +266,40 @@ impl Stream {
         self.seq_offset = SeqOffset::Initial(isn);
         // set seq window to sane initial values
         self.seq_window_start = isn;
-        self.seq_window_end = self.seq_window_start.wrapping_add(SEQ_WINDOW_SIZE);
+        self.seq_window_end = self
+            .seq_window_start
+            .wrapping_add(self.config.seq_window_size);
         // update expected receive window
         let window_size = (window_size as u64) << self.window_scale as u64;
-        if window_size < MAX_ALLOWED_BUFFER_SIZE {
+        if window_size < self.config.max_buffer_size {
             trace!("got initial window size from handshake: {window_size}");
             self.state.set_limit(window_size);
         } else {
-            warn!("received window size in handshake is too large: {window_size}");
-            self.state.set_limit(MAX_ALLOWED_BUFFER_SIZE);
+            warn_throttled!(
+                WarnCategory::Handshake,
+                "received window size in handshake is too large: {window_size}"
+            );
+            self.state.set_limit(self.config.max_buffer_size);
         }
     }
 
     /// update seq_window and seq_offset based on current window, return whether
     /// the value was in the current window and the absolute stream offset
     pub fn update_offset(&mut self, number: u32, should_advance: bool) -> Option<u64> {
+        let window_size = self.config.seq_window_size;
+        let advance_threshold = self.config.seq_window_advance_threshold;
+        let advance_by = self.config.seq_window_advance_by;
         // ensure in range
         if self.seq_window_start < self.seq_window_end {
             // does not wrap
             if !(number >= self.seq_window_start && number < self.seq_window_end) {
                 None
             } else {
-                if should_advance && number - self.seq_window_start > SEQ_WINDOW_ADVANCE_THRESHOLD {
+                if should_advance && number - self.seq_window_start > advance_threshold {
                     // advance window
                     let old_start = self.seq_window_start;
-                    self.seq_window_start = number - SEQ_WINDOW_ADVANCE_BY;
-                    self.seq_window_end = self.seq_window_start.wrapping_add(SEQ_WINDOW_SIZE);
+                    self.seq_window_start = number - advance_by;
+                    self.seq_window_end = self.seq_window_start.wrapping_add(window_size);
                     trace!(
                         "advance seq_window {} -> {} (received seq {})",
                         old_start,
@@ -194,11 +314,11 @@ impl Stream {
             None
         } else if number >= self.seq_window_start {
             // at high section of window
-            if should_advance && number - self.seq_window_start > SEQ_WINDOW_ADVANCE_THRESHOLD {
+            if should_advance && number - self.seq_window_start > advance_threshold {
                 // advance window
                 let old_start = self.seq_window_start;
-                self.seq_window_start = number - SEQ_WINDOW_ADVANCE_BY;
-                self.seq_window_end = self.seq_window_start.wrapping_add(SEQ_WINDOW_SIZE);
+                self.seq_window_start = number - advance_by;
+                self.seq_window_end = self.seq_window_start.wrapping_add(window_size);
                 trace!(
                     "advance seq_window {} -> {} (received seq {})",
                     old_start,
@@ -215,11 +335,11 @@ impl Stream {
                 SeqOffset::Initial(isn) => SeqOffset::Subsequent((1 << 32) - isn as u64),
                 SeqOffset::Subsequent(off) => SeqOffset::Subsequent(off + (1 << 32)),
             };
-            if should_advance && bytes_from_start > SEQ_WINDOW_ADVANCE_THRESHOLD {
+            if should_advance && bytes_from_start > advance_threshold {
                 // advance window
                 let old_start = self.seq_window_start;
-                self.seq_window_start = number.wrapping_sub(SEQ_WINDOW_ADVANCE_BY);
-                self.seq_window_end = self.seq_window_start.wrapping_add(SEQ_WINDOW_SIZE);
+                self.seq_window_start = number.wrapping_sub(advance_by);
+                self.seq_window_end = self.seq_window_start.wrapping_add(window_size);
                 trace!(
                     "advance seq_window {} -> {} (received seq {})",
                     old_start,
@@ -238,17 +358,70 @@ impl Stream {
         }
     }
 
+    /// whether sequence number is within the max buffer size of the end of
+    /// the stream, i.e. it plausibly continues the stream
+    pub fn is_near_position(&mut self, number: u32) -> bool {
+        let end = self.state.buffer_offset + self.state.buffer.len() as u64;
+        self.update_offset(number, false)
+            .is_some_and(|offset| offset.abs_diff(end) <= self.config.max_buffer_size)
+    }
+
+    /// continue stream at its current end with sequence number `number`,
+    /// e.g. after the sender restarted its sequence numbering
+    pub fn rebase(&mut self, number: u32) {
+        let end = self.state.buffer_offset + self.state.buffer.len() as u64;
+        trace!("rebase: sequence number {number} now at offset {end}");
+        self.seq_offset = match end.checked_sub(number as u64) {
+            Some(offset) => SeqOffset::Subsequent(offset),
+            None => SeqOffset::Initial(number - end as u32),
+        };
+        self.seq_window_start = number;
+        self.seq_window_end = number.wrapping_add(self.config.seq_window_size);
+        // timestamps restart as well
+        self.ts_recent = None;
+    }
+
+    /// check TSval of a segment against the highest TSval accepted (PAWS),
+    /// returns false if it fell behind by more than `paws_max_regression`
+    ///
+    /// Does not update `ts_recent`, call `update_ts_recent` once the segment
+    /// was accepted.
+    pub fn check_paws(&mut self, tsval: u32) -> bool {
+        if let Some(ts_recent) = self.ts_recent {
+            let regression = ts_recent.wrapping_sub(tsval) as i32;
+            let max_regression = self.config.paws_max_regression;
+            if regression > 0 && max_regression > 0 && regression as u32 > max_regression {
+                self.paws_rejected += 1;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// record TSval of an accepted in-window segment (RFC 7323 section 5.3)
+    pub fn update_ts_recent(&mut self, tsval: u32) {
+        match self.ts_recent {
+            // reordered, keep highest
+            Some(ts_recent) if (tsval.wrapping_sub(ts_recent) as i32) < 0 => {}
+            _ => self.ts_recent = Some(tsval),
+        }
+    }
+
     /// handle data packet in the forward direction
     pub fn handle_data_packet(
         &mut self,
         sequence_number: u32,
         mut data: &[u8],
         extra: &PacketExtra,
+        tcp: SegmentTcpInfo,
     ) -> bool {
         let Some(offset) = self.update_offset(sequence_number, true) else {
-            warn!(
+            warn_throttled!(
+                WarnCategory::OutOfWindow,
                 "received seq number {} outside of window ({} - {})",
-                sequence_number, self.seq_window_start, self.seq_window_end
+                sequence_number,
+                self.seq_window_start,
+                self.seq_window_end
             );
             return false;
         };
@@ -265,7 +438,7 @@ impl Stream {
                 self.state.window_limit
             );
             // try to extend the window limit
-            if packet_end_offset - self.state.buffer_offset < MAX_ALLOWED_BUFFER_SIZE {
+            if packet_end_offset - self.state.buffer_offset < self.config.max_buffer_size {
                 if !self.got_window_scale {
                     if self.estimate_window_scale(packet_end_offset) {
                         debug_assert!(self.state.window_limit >= packet_end_offset);
@@ -277,16 +450,20 @@ impl Stream {
                     self.state.set_limit(packet_end_offset);
                 }
             } else {
-                let max_offset = self.state.buffer_offset + MAX_ALLOWED_BUFFER_SIZE;
+                let max_offset = self.state.buffer_offset + self.config.max_buffer_size;
                 let max_len = max_offset.saturating_sub(offset) as usize;
                 if max_len > 0 {
-                    warn!(
+                    warn_throttled!(
+                        WarnCategory::BufferLimit,
                         "packet exceeds max buffer, dropping {} bytes",
                         data.len() - max_len
                     );
                     data = &data[..max_len];
                 } else {
-                    warn!("packet exceeds max buffer, dropping packet");
+                    warn_throttled!(
+                        WarnCategory::BufferLimit,
+                        "packet exceeds max buffer, dropping packet"
+                    );
                     return false;
                 }
             }
@@ -294,10 +471,18 @@ impl Stream {
 
         // read in the packet
         let mut is_retransmit = false;
-        match self.state.receive_segment(offset, data) {
+        self.data_bytes += data.len() as u64;
+        self.previous_data_packet = mem::replace(&mut self.last_data_packet, extra.packet_ref());
+        if offset < self.state.buffer_offset {
+            self.take_late_data(offset, data, extra);
+        }
+        let result = self.state.receive_segment(offset, data);
+        self.update_memory_usage();
+        match result {
             ReceiveSegmentResult::Duplicate => {
                 // probably a retransmit
                 self.retransmit_count += 1;
+                self.retransmit_bytes += data.len() as u64;
                 is_retransmit = true;
                 trace!(
                     "handle_data_packet: got retransmit of {} bytes at seq {}, offset {}",
@@ -325,6 +510,7 @@ impl Stream {
             offset,
             reverse_acked: self.reverse_acked,
             extra: extra.clone(),
+            tcp,
             data: SegmentType::Data {
                 len: data.len(),
                 is_retransmit,
@@ -340,11 +526,15 @@ impl Stream {
         acknowledgment_number: u32,
         window_size: u16,
         extra: &PacketExtra,
+        tcp: SegmentTcpInfo,
     ) -> bool {
         let Some(offset) = self.update_offset(acknowledgment_number, true) else {
-            warn!(
+            warn_throttled!(
+                WarnCategory::OutOfWindow,
                 "received ack number {} outside of window ({} - {})",
-                acknowledgment_number, self.seq_window_start, self.seq_window_end
+                acknowledgment_number,
+                self.seq_window_start,
+                self.seq_window_end
             );
             return false;
         };
@@ -374,17 +564,21 @@ impl Stream {
 
         if limit > self.state.window_limit {
             let new_buffer_size = limit - self.state.buffer_offset;
-            if new_buffer_size > MAX_ALLOWED_BUFFER_SIZE {
+            if new_buffer_size > self.config.max_buffer_size {
                 // would make buffer too large, either window too large (DoS?)
                 // or the buffer is not getting drained properly
-                warn!(
+                warn_throttled!(
+                    WarnCategory::BufferLimit,
                     "received ack packet which would result in a buffer size \
                         exceeding the maximum allowed buffer size: \
                         ack: {}, win: {}, win scale: {}, absolute window limit: {}",
-                    acknowledgment_number, window_size, self.window_scale, limit
+                    acknowledgment_number,
+                    window_size,
+                    self.window_scale,
+                    limit
                 );
                 self.state
-                    .set_limit(self.state.buffer_offset + MAX_ALLOWED_BUFFER_SIZE);
+                    .set_limit(self.state.buffer_offset + self.config.max_buffer_size);
             } else {
                 trace!(
                     "received window increase: {} -> {} ({} bytes)",
@@ -400,6 +594,7 @@ impl Stream {
             offset,
             reverse_acked: self.reverse_acked,
             extra: extra.clone(),
+            tcp,
             data: SegmentType::Ack {
                 window: real_window as usize,
             },
@@ -414,15 +609,20 @@ impl Stream {
         sequence_number: u32,
         data_len: usize,
         extra: &PacketExtra,
+        tcp: SegmentTcpInfo,
     ) -> bool {
         let Some(offset) = self.update_offset(sequence_number, true) else {
-            warn!(
+            warn_throttled!(
+                WarnCategory::OutOfWindow,
                 "received fin with seq number {} outside of window ({} - {})",
-                sequence_number, self.seq_window_start, self.seq_window_end
+                sequence_number,
+                self.seq_window_start,
+                self.seq_window_end
             );
             return false;
         };
         let fin_offset = offset + data_len as u64;
+        self.fin_count += 1;
 
         match self.state.final_offset {
             None => {
@@ -434,9 +634,11 @@ impl Stream {
             }
             Some(prev_fin) => {
                 if fin_offset != prev_fin {
-                    warn!(
+                    warn_throttled!(
+                        WarnCategory::Unexpected,
                         "received duplicate FIN different from previous: prev: {}, now: {}",
-                        prev_fin, fin_offset
+                        prev_fin,
+                        fin_offset
                     );
                 }
                 trace!("handle_fin_packet: detected retransmitted FIN");
@@ -448,6 +650,7 @@ impl Stream {
             offset,
             reverse_acked: self.reverse_acked,
             extra: extra.clone(),
+            tcp,
             data: SegmentType::Fin {
                 end_offset: fin_offset,
             },
@@ -456,16 +659,24 @@ impl Stream {
     }
 
     /// handle reset packet in established state
-    pub fn handle_rst_packet(&mut self, sequence_number: u32, extra: &PacketExtra) -> bool {
+    pub fn handle_rst_packet(
+        &mut self,
+        sequence_number: u32,
+        extra: &PacketExtra,
+        tcp: SegmentTcpInfo,
+    ) -> bool {
         // we send reset packets to the aligned stream (i.e. if the packet is sent in
         // the forward direction, then it is sent to the forward stream).
         // to validate, compare sequence number of reset to highest_acked.
         // do not update seq_window, as some middleboxes will generate reset packets
         // with incorrect sequence numbers.
         let Some(offset) = self.update_offset(sequence_number, false) else {
-            warn!(
+            warn_throttled!(
+                WarnCategory::OutOfWindow,
                 "received reset with seq number {} outside of window ({} - {})",
-                sequence_number, self.seq_window_start, self.seq_window_end
+                sequence_number,
+                self.seq_window_start,
+                self.seq_window_end
             );
             return false;
         };
@@ -473,24 +684,28 @@ impl Stream {
         if offset
             >= self
                 .highest_acked
-                .saturating_sub(RESET_MAX_LOOKBEHIND as u64)
+                .saturating_sub(self.config.reset_max_lookbehind as u64)
             && offset
                 < self
                     .highest_acked
-                    .saturating_add(RESET_MAX_LOOKAHEAD as u64)
+                    .saturating_add(self.config.reset_max_lookahead as u64)
         {
             debug!("handle_rst_packet: got reset at offset {offset}");
             self.add_segment_info(SegmentInfo {
                 offset,
                 reverse_acked: self.reverse_acked,
                 extra: extra.clone(),
+                tcp,
                 data: SegmentType::Rst,
             });
             true
         } else {
-            warn!(
+            warn_throttled!(
+                WarnCategory::InvalidReset,
                 "got likely invalid reset packet at offset {} (highest acked {}, seq {})",
-                offset, self.highest_acked, sequence_number
+                offset,
+                self.highest_acked,
+                sequence_number
             );
             false
         }
@@ -498,11 +713,14 @@ impl Stream {
 
     /// add an info object to segments_info
     pub fn add_segment_info(&mut self, info: SegmentInfo) -> bool {
-        if self.segments_info.len() < MAX_SEGMENTS_INFO_COUNT {
+        if self.segments_info.len() < self.config.max_segments_info {
             self.segments_info.push(info);
             true
         } else {
             self.segments_info_dropped += 1;
+            if let Some(metrics) = &self.config.metrics {
+                metrics.segment_info_dropped();
+            }
             false
         }
     }
@@ -514,10 +732,7 @@ impl Stream {
         end_offset: Option<u64>,
         in_segments: &mut Vec<SegmentInfo>,
     ) {
-        loop {
-            let Some(info_peek) = self.segments_info.peek() else {
-                break;
-            };
+        while let Some(info_peek) = self.segments_info.peek() {
             if let Some(end_offset) = end_offset {
                 if info_peek.offset >= end_offset {
                     break;
@@ -528,13 +743,166 @@ impl Stream {
         }
     }
 
+    /// check for missing data before buffered data after a data packet was
+    /// received, returning the missing range once it is considered lost
+    pub fn detect_gap(&mut self) -> Option<Range<u64>> {
+        let buffer_end = self.state.buffer_offset + self.state.buffer.len() as u64;
+        let start = u64::max(self.state.buffer_offset, self.declared_gap_end);
+        let Some(gap) = self
+            .state
+            .received
+            .range_complement(start..buffer_end)
+            .next()
+        else {
+            self.pending_gap = None;
+            return None;
+        };
+        let packets = match self.pending_gap {
+            Some((pending_start, packets)) if pending_start == gap.start => packets + 1,
+            _ => {
+                // this packet is the first received past the gap
+                self.pending_gap_edges = (self.previous_data_packet, self.last_data_packet);
+                1
+            }
+        };
+        self.pending_gap = Some((gap.start, packets));
+        if packets < self.config.gap_wait_packets
+            && buffer_end - gap.end < self.config.gap_wait_bytes
+        {
+            return None;
+        }
+        trace!("detect_gap: declaring gap {} .. {}", gap.start, gap.end);
+        self.declare_gap(gap.clone());
+        Some(gap)
+    }
+
+    /// annotation of gap starting at `start` skipped now for `cause`
+    fn gap_info(&self, start: u64, cause: GapCause) -> GapInfo {
+        let mut info = GapInfo {
+            cause: Some(cause),
+            declared: self.last_data_packet,
+            ..Default::default()
+        };
+        if let Some((pending_start, packets)) = self.pending_gap {
+            if pending_start == start {
+                (info.before, info.after) = self.pending_gap_edges;
+                info.packets_skipped = packets;
+            }
+        }
+        info
+    }
+
+    /// declare missing range lost, to be skipped by readers
+    pub fn declare_gap(&mut self, range: Range<u64>) {
+        let info = self.gap_info(range.start, GapCause::Loss);
+        self.pending_gap = None;
+        self.declared_gap_end = range.end;
+        self.declared_gaps.push_back(StreamGap { range, info });
+    }
+
+    /// declare all data missing from the buffer lost (e.g. as the connection
+    /// ends)
+    pub fn declare_remaining_gaps(&mut self) {
+        let buffer_end = self.state.buffer_offset + self.state.buffer.len() as u64;
+        let start = u64::max(self.state.buffer_offset, self.declared_gap_end);
+        let gaps: Vec<_> = self
+            .state
+            .received
+            .range_complement(start..buffer_end)
+            .collect();
+        for gap in gaps {
+            self.declare_gap(gap);
+        }
+    }
+
     /// read gaps in buffer in a given range, adding to vec and accounting in gaps_length
-    pub fn read_gaps_until(&mut self, end_offset: u64, in_gaps: &mut Vec<Range<u64>>) {
+    ///
+    /// Gaps not declared before are read past due to buffer limits.
+    pub fn read_gaps_until(&mut self, end_offset: u64, in_gaps: &mut Vec<StreamGap>) {
         let range = self.state.buffer_offset..end_offset;
-        for gap in self.state.received.range_complement(range) {
+        let gaps: Vec<_> = self.state.received.range_complement(range).collect();
+        self.expire_skipped_gaps();
+        for gap in gaps {
             trace!("read_gaps: gap: {} .. {}", gap.start, gap.end);
-            in_gaps.push(gap.clone());
+            // declared gaps may have been filled in since
+            while self
+                .declared_gaps
+                .front()
+                .is_some_and(|declared| declared.range.end <= gap.start)
+            {
+                self.declared_gaps.pop_front();
+            }
+            let info = match self.declared_gaps.front() {
+                Some(declared) if declared.range.start < gap.end => {
+                    let info = declared.info;
+                    if declared.range.end <= gap.end {
+                        self.declared_gaps.pop_front();
+                    }
+                    info
+                }
+                _ => self.gap_info(gap.start, GapCause::BufferOverrun),
+            };
             self.gaps_length += gap.end - gap.start;
+            if self.config.late_data_packets > 0 {
+                self.skipped_gaps.push_back(SkippedGap {
+                    range: gap.clone(),
+                    expires_after: self.packet_count + self.config.late_data_packets as u64,
+                });
+            }
+            in_gaps.push(StreamGap { range: gap, info });
+        }
+    }
+
+    /// forget skipped gaps which no longer accept late data
+    fn expire_skipped_gaps(&mut self) {
+        while self.skipped_gaps.front().is_some_and(|skipped| {
+            skipped.expires_after < self.packet_count
+                || self.state.buffer_offset.saturating_sub(skipped.range.end)
+                    > self.config.late_data_bytes
+        }) {
+            self.skipped_gaps.pop_front();
+        }
+    }
+
+    /// collect data of a packet starting before the buffer which falls into
+    /// skipped gaps
+    fn take_late_data(&mut self, offset: u64, data: &[u8], extra: &PacketExtra) {
+        self.expire_skipped_gaps();
+        let end = offset + data.len() as u64;
+        let mut i = 0;
+        while i < self.skipped_gaps.len() {
+            let skipped = self.skipped_gaps[i].clone();
+            if skipped.range.start >= end {
+                break;
+            }
+            if skipped.range.end <= offset {
+                i += 1;
+                continue;
+            }
+            let start = u64::max(skipped.range.start, offset);
+            let stop = u64::min(skipped.range.end, end);
+            trace!("take_late_data: got late data {start} .. {stop}");
+            self.late_data.push(LateData {
+                offset: start,
+                data: data[(start - offset) as usize..(stop - offset) as usize].to_vec(),
+                extra: extra.clone(),
+            });
+            self.late_data_bytes += stop - start;
+
+            // keep parts of the gap not filled in
+            self.skipped_gaps.remove(i);
+            for rest in [skipped.range.start..start, stop..skipped.range.end] {
+                if !rest.is_empty() {
+                    self.skipped_gaps.insert(
+                        i,
+                        SkippedGap {
+                            range: rest,
+                            expires_after: skipped.expires_after,
+                        },
+                    );
+                    i += 1;
+                }
+            }
         }
     }
 
@@ -563,15 +931,53 @@ impl Stream {
         )
     }
 
+    /// read data until offset, collecting segment info and gaps, then advance
+    /// the buffer past it
+    ///
+    /// Segment info and gaps before `end_offset` are added to the vecs and
+    /// `read_fn` is called with the buffered data, with gaps zero-filled.
+    /// Returns None without changing anything if the range is empty or not
+    /// available.
+    pub fn read_next<R>(
+        &mut self,
+        end_offset: u64,
+        in_segments: &mut Vec<SegmentInfo>,
+        in_gaps: &mut Vec<StreamGap>,
+        read_fn: impl FnOnce(RingBufSlice<'_, u8>) -> R,
+    ) -> Option<R> {
+        let start_offset = self.state.buffer_offset;
+        if end_offset <= start_offset
+            || (end_offset - start_offset) as usize > self.state.buffer.len()
+        {
+            warn!("read_next: range {start_offset} .. {end_offset} not available");
+            return None;
+        }
+        self.pop_segments_until(Some(end_offset), in_segments);
+        self.read_gaps_until(end_offset, in_gaps);
+        let result = read_fn(
+            self.read_buffer_until(end_offset)
+                .expect("range checked above"),
+        );
+        self.consume_until(end_offset);
+        Some(result)
+    }
+
     pub fn consume_until(&mut self, end_offset: u64) {
         // advance backing buffer
         self.state.advance_buffer(end_offset);
+        self.update_memory_usage();
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.report_buffered(0);
     }
 }
 
 impl Default for Stream {
     fn default() -> Self {
-        Self::new()
+        Self::new(Arc::default())
     }
 }
 
@@ -590,8 +996,33 @@ pub fn in_range_wrapping(base: u32, before: u32, after: u32, value: u32) -> bool
     }
 }
 
-/// information on each segment received
+/// missing data skipped when reading a stream
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamGap {
+    pub range: Range<u64>,
+    pub info: GapInfo,
+}
+
+/// gap already read past which still accepts late data
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedGap {
+    pub range: Range<u64>,
+    /// packet count of the stream after which late data is no longer accepted
+    pub expires_after: u64,
+}
+
+/// data received late for a gap which was already read past
 #[derive(Clone)]
+pub struct LateData {
+    /// stream offset of data
+    pub offset: u64,
+    pub data: Vec<u8>,
+    /// extra information of the late packet
+    pub extra: PacketExtra,
+}
+
+/// information on each segment received
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SegmentInfo {
     /// offset into stream of this segment
     pub offset: u64,
@@ -599,12 +1030,62 @@ pub struct SegmentInfo {
     pub reverse_acked: u64,
     /// extra metadata from packet
     pub extra: PacketExtra,
+    /// TCP/IP header fields from packet
+    #[serde(default)]
+    pub tcp: SegmentTcpInfo,
     /// segment type and type-specific info
     pub data: SegmentType,
 }
 
+/// TCP/IP header fields of a segment beyond those needed for reassembly
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SegmentTcpInfo {
+    /// PSH flag
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub psh: bool,
+    /// urgent pointer, if URG flag set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub urgent_pointer: Option<u16>,
+    /// ECE flag (ECN-Echo)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ece: bool,
+    /// CWR flag
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cwr: bool,
+    /// ECN field of IP header (1: ECT(1), 2: ECT(0), 3: CE)
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub ip_ecn: u8,
+    /// maximum segment size option
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mss: Option<u16>,
+}
+
+impl SegmentTcpInfo {
+    /// whether the packet was marked congestion experienced
+    pub fn congestion_experienced(&self) -> bool {
+        self.ip_ecn == 3
+    }
+}
+
+impl From<&TcpMeta> for SegmentTcpInfo {
+    fn from(meta: &TcpMeta) -> Self {
+        SegmentTcpInfo {
+            psh: meta.flags.psh,
+            urgent_pointer: meta.flags.urg.then_some(meta.urgent_pointer),
+            ece: meta.flags.ece,
+            cwr: meta.flags.cwr,
+            ip_ecn: meta.ip_ecn,
+            mss: meta.option_mss,
+        }
+    }
+}
+
+fn is_zero(value: &u8) -> bool {
+    *value == 0
+}
+
 /// type-specific information for each segment
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum SegmentType {
     Data { len: usize, is_retransmit: bool },
     Ack { window: usize },
@@ -644,7 +1125,7 @@ impl PartialEq for SegmentInfo {
 impl Eq for SegmentInfo {}
 
 /// represents offset from packet sequence number to absolute offset
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum SeqOffset {
     /// negative offset due to initial sequence number
     Initial(u32),
@@ -0,0 +1,603 @@
+//! Synthesize packet captures from directory output
+//!
+//! Reads `connections.json` along with the stream data and segment files
+//! written by `DirectoryOutputHandler`, and writes each TCP connection as a
+//! handshake followed by in-order data, ACK, FIN and RST packets. Data lost in
+//! gaps is left out and sequence numbers shifted to match, so the capture
+//! reassembles without holes. Retransmissions, reordering and redundant ACKs
+//! are not reproduced.
+//!
+//! Connections are written one after another in order of their first packet,
+//! so timestamps of overlapping connections are not monotonic. Only the split
+//! data layout without deduplication is supported.
+
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+use etherparse::PacketBuilder;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::connection::Direction;
+use crate::output_dir::{invalid_data, open_output_file, read_connections, read_segments};
+use crate::pcap_writer::PcapWriter;
+use crate::serialized::{ConnInfo, SerializedSegment};
+
+/// largest payload of synthesized packets
+pub const SYNTH_MAX_PAYLOAD: usize = 1460;
+/// link type of synthesized captures (Ethernet)
+pub const SYNTH_LINKTYPE: u32 = 1;
+/// window advertised by synthesized packets
+const SYNTH_WINDOW: u16 = 65535;
+/// TTL or hop limit of synthesized packets
+const SYNTH_TTL: u8 = 64;
+/// locally administered MAC addresses of client and server
+const CLIENT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+const SERVER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+
+/// counts of synthesized output
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SynthSummary {
+    /// connections written
+    pub connections: u64,
+    /// connections skipped, as they are UDP flows or lack usable files
+    pub skipped: u64,
+    /// packets written
+    pub packets: u64,
+}
+
+/// one direction of a connection read from directory output
+struct SynthStream {
+    /// stream data, gaps zero-filled
+    data: Vec<u8>,
+    /// lost ranges, sorted
+    gaps: Vec<Range<u64>>,
+    segments: Vec<SerializedSegment>,
+}
+
+impl SynthStream {
+    /// read files of direction `label`, returns None if missing
+    fn read(dir: &Path, id: Uuid, label: &str) -> io::Result<Option<Self>> {
+        let Some(segments) = read_segments(dir, id, label)? else {
+            return Ok(None);
+        };
+        let Some(mut reader) = open_output_file(&dir.join(format!("{id}.{label}.data")))? else {
+            warn!("no {label}.data file for {id}, only the split layout is supported");
+            return Ok(None);
+        };
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut gaps: Vec<Range<u64>> = segments
+            .iter()
+            .filter_map(|segment| match segment {
+                SerializedSegment::Gap { offset, len, .. } => Some(*offset..offset + len),
+                _ => None,
+            })
+            .collect();
+        gaps.sort_by_key(|gap| gap.start);
+        Ok(Some(SynthStream {
+            data,
+            gaps,
+            segments,
+        }))
+    }
+
+    /// offset with lost bytes before it removed
+    fn remap(&self, offset: u64) -> u64 {
+        let lost: u64 = self
+            .gaps
+            .iter()
+            .take_while(|gap| gap.start < offset)
+            .map(|gap| gap.end.min(offset) - gap.start)
+            .sum();
+        offset - lost
+    }
+
+    /// split received data within range into packet payloads
+    fn chunks(&self, range: Range<u64>, out: &mut Vec<Range<u64>>) {
+        let mut push = |received: Range<u64>| {
+            let mut start = received.start;
+            while start < received.end {
+                let end = received.end.min(start + SYNTH_MAX_PAYLOAD as u64);
+                out.push(start..end);
+                start = end;
+            }
+        };
+        let mut pos = range.start;
+        for gap in &self.gaps {
+            if gap.end <= pos {
+                continue;
+            }
+            if gap.start >= range.end {
+                break;
+            }
+            if gap.start > pos {
+                push(pos..gap.start);
+            }
+            pos = gap.end;
+        }
+        if pos < range.end {
+            push(pos..range.end);
+        }
+    }
+}
+
+/// packet to synthesize
+enum PacketKind {
+    /// payload at stream offsets, and offset acked in the other direction
+    Data {
+        range: Range<u64>,
+        reverse_acked: u64,
+    },
+    /// acknowledges other direction up to stream offset
+    Ack(u64),
+    Fin,
+    Rst,
+}
+
+struct SynthPacket {
+    time: Duration,
+    index: u64,
+    direction: Direction,
+    kind: PacketKind,
+}
+
+/// collect packets sent in direction, data ordered by offset
+fn collect_packets(direction: Direction, stream: &SynthStream, out: &mut Vec<SynthPacket>) {
+    // time and capture index of records without them are carried forward
+    let (mut time, mut index) = (Duration::ZERO, 0);
+    let mut data_segments = Vec::new();
+    let (mut fin, mut rst) = (None, None);
+    for segment in &stream.segments {
+        let extra = match segment {
+            SerializedSegment::Data { extra, .. }
+            | SerializedSegment::Ack { extra, .. }
+            | SerializedSegment::Fin { extra, .. }
+            | SerializedSegment::Rst { extra, .. }
+            | SerializedSegment::Datagram { extra, .. } => extra,
+            SerializedSegment::Gap { .. } | SerializedSegment::LateData { .. } => continue,
+        };
+        time = extra.timestamp().unwrap_or(time);
+        index = extra.index().unwrap_or(index);
+        match segment {
+            SerializedSegment::Data {
+                offset,
+                len,
+                reverse_acked,
+                ..
+            } => data_segments.push((*offset..offset + *len as u64, time, index, *reverse_acked)),
+            // ack records of a stream were sent by the other side
+            SerializedSegment::Ack { offset, .. } => out.push(SynthPacket {
+                time,
+                index,
+                direction: direction.swap(),
+                kind: PacketKind::Ack(*offset),
+            }),
+            SerializedSegment::Fin { .. } => fin = fin.or(Some((time, index))),
+            SerializedSegment::Rst { .. } => rst = rst.or(Some((time, index))),
+            _ => {}
+        }
+    }
+
+    // send data in order, keeping time from going backwards
+    data_segments.sort_by_key(|(range, ..)| range.start);
+    let end = stream.data.len() as u64;
+    let mut sent = 0;
+    let mut last = (Duration::ZERO, 0);
+    let mut chunks = Vec::new();
+    let mut push_data = |range: Range<u64>, at: (Duration, u64), reverse_acked: u64| {
+        chunks.clear();
+        stream.chunks(range, &mut chunks);
+        for chunk in chunks.drain(..) {
+            out.push(SynthPacket {
+                time: at.0,
+                index: at.1,
+                direction,
+                kind: PacketKind::Data {
+                    range: chunk,
+                    reverse_acked,
+                },
+            });
+        }
+    };
+    let mut reverse_acked = 0;
+    for (range, time, index, acked) in data_segments {
+        let segment_end = range.end.min(end);
+        if segment_end <= sent {
+            continue;
+        }
+        last = last.max((time, index));
+        reverse_acked = reverse_acked.max(acked);
+        push_data(sent..segment_end, last, reverse_acked);
+        sent = segment_end;
+    }
+    if sent < end {
+        // data not covered by segment records, e.g. if they were dropped
+        push_data(sent..end, last, reverse_acked);
+    }
+    for (at, kind) in [(fin, PacketKind::Fin), (rst, PacketKind::Rst)] {
+        if let Some(at) = at {
+            let (time, index) = last.max(at);
+            out.push(SynthPacket {
+                time,
+                index,
+                direction,
+                kind,
+            });
+        }
+    }
+}
+
+/// writes packets of one connection
+struct ConnectionWriter<'a, W: Write> {
+    info: &'a ConnInfo,
+    out: &'a mut PcapWriter<W>,
+    /// initial sequence numbers (forward, reverse)
+    isn: [u32; 2],
+    /// stream bytes sent, with lost bytes removed
+    sent: [u64; 2],
+    fin_sent: [bool; 2],
+    /// sequence space of the other direction acknowledged, relative to its
+    /// initial sequence number plus one
+    acked: [u64; 2],
+    packets: u64,
+}
+
+#[derive(Default)]
+struct Flags {
+    syn: bool,
+    fin: bool,
+    rst: bool,
+    psh: bool,
+}
+
+fn index(direction: Direction) -> usize {
+    match direction {
+        Direction::Forward => 0,
+        Direction::Reverse => 1,
+    }
+}
+
+impl<W: Write> ConnectionWriter<'_, W> {
+    /// sequence space used in direction, relative to its initial sequence
+    /// number plus one
+    fn sent_total(&self, direction: Direction) -> u64 {
+        let i = index(direction);
+        self.sent[i] + self.fin_sent[i] as u64
+    }
+
+    fn next_seq(&self, direction: Direction) -> u32 {
+        let relative = self.sent_total(direction) as u32;
+        self.isn[index(direction)]
+            .wrapping_add(1)
+            .wrapping_add(relative)
+    }
+
+    fn ack_number(&self, direction: Direction) -> u32 {
+        let relative = self.acked[index(direction)] as u32;
+        self.isn[index(direction.swap())]
+            .wrapping_add(1)
+            .wrapping_add(relative)
+    }
+
+    /// raise acknowledged position of direction, limited to what was sent
+    fn acknowledge(&mut self, direction: Direction, relative: u64) -> bool {
+        let relative = relative.min(self.sent_total(direction.swap()));
+        let acked = &mut self.acked[index(direction)];
+        if relative > *acked {
+            *acked = relative;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn write_packet(
+        &mut self,
+        direction: Direction,
+        time: Duration,
+        seq: u32,
+        ack: Option<u32>,
+        flags: Flags,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let info = self.info;
+        let (src, dst) = match direction {
+            Direction::Forward => (
+                (info.src_addr, info.src_port, CLIENT_MAC),
+                (info.dst_addr, info.dst_port, SERVER_MAC),
+            ),
+            Direction::Reverse => (
+                (info.dst_addr, info.dst_port, SERVER_MAC),
+                (info.src_addr, info.src_port, CLIENT_MAC),
+            ),
+        };
+        let ethernet = PacketBuilder::ethernet2(src.2, dst.2);
+        let ip = match (src.0, dst.0) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => ethernet.ipv4(s.octets(), d.octets(), SYNTH_TTL),
+            (IpAddr::V6(s), IpAddr::V6(d)) => ethernet.ipv6(s.octets(), d.octets(), SYNTH_TTL),
+            _ => return Err(invalid_data("mixed address families")),
+        };
+        let mut tcp = ip.tcp(src.1, dst.1, seq, SYNTH_WINDOW);
+        if flags.syn {
+            tcp = tcp.syn();
+        }
+        if flags.fin {
+            tcp = tcp.fin();
+        }
+        if flags.rst {
+            tcp = tcp.rst();
+        }
+        if flags.psh {
+            tcp = tcp.psh();
+        }
+        if let Some(ack) = ack {
+            tcp = tcp.ack(ack);
+        }
+        let mut packet = Vec::with_capacity(tcp.size(payload.len()));
+        tcp.write(&mut packet, payload).map_err(invalid_data)?;
+        self.out.write_packet(
+            time.as_secs() as u32,
+            time.subsec_micros(),
+            packet.len() as u32,
+            &packet,
+        )?;
+        self.packets += 1;
+        Ok(())
+    }
+
+    /// write handshake followed by packets in order
+    fn write(
+        &mut self,
+        streams: &[SynthStream; 2],
+        mut packets: Vec<SynthPacket>,
+    ) -> io::Result<()> {
+        packets.sort_by_key(|packet| (packet.time, packet.index));
+        let start = match (self.info.first_packet_us, packets.first()) {
+            (Some(us), _) => Duration::from_micros(us),
+            (None, Some(packet)) => packet.time,
+            (None, None) => Duration::ZERO,
+        };
+        let (forward, reverse) = (Direction::Forward, Direction::Reverse);
+        let syn = || Flags {
+            syn: true,
+            ..Default::default()
+        };
+        self.write_packet(forward, start, self.isn[0], None, syn(), &[])?;
+        let ack = self.isn[0].wrapping_add(1);
+        self.write_packet(reverse, start, self.isn[1], Some(ack), syn(), &[])?;
+        let (seq, ack) = (self.next_seq(forward), self.ack_number(forward));
+        self.write_packet(forward, start, seq, Some(ack), Flags::default(), &[])?;
+
+        for packet in packets {
+            let direction = packet.direction;
+            let stream = &streams[index(direction)];
+            let other = &streams[index(direction.swap())];
+            match packet.kind {
+                PacketKind::Data {
+                    range,
+                    reverse_acked,
+                } => {
+                    debug_assert_eq!(stream.remap(range.start), self.sent[index(direction)]);
+                    self.acknowledge(direction, other.remap(reverse_acked));
+                    let (seq, ack) = (self.next_seq(direction), self.ack_number(direction));
+                    let flags = Flags {
+                        psh: true,
+                        ..Default::default()
+                    };
+                    let payload = &stream.data[range.start as usize..range.end as usize];
+                    self.write_packet(direction, packet.time, seq, Some(ack), flags, payload)?;
+                    self.sent[index(direction)] += range.end - range.start;
+                }
+                PacketKind::Ack(offset) => {
+                    if self.acknowledge(direction, other.remap(offset)) {
+                        let (seq, ack) = (self.next_seq(direction), self.ack_number(direction));
+                        let flags = Flags::default();
+                        self.write_packet(direction, packet.time, seq, Some(ack), flags, &[])?;
+                    }
+                }
+                PacketKind::Fin => {
+                    if !self.fin_sent[index(direction)] {
+                        let (seq, ack) = (self.next_seq(direction), self.ack_number(direction));
+                        let flags = Flags {
+                            fin: true,
+                            ..Default::default()
+                        };
+                        self.write_packet(direction, packet.time, seq, Some(ack), flags, &[])?;
+                        self.fin_sent[index(direction)] = true;
+                    }
+                }
+                PacketKind::Rst => {
+                    let (seq, ack) = (self.next_seq(direction), self.ack_number(direction));
+                    let flags = Flags {
+                        rst: true,
+                        ..Default::default()
+                    };
+                    self.write_packet(direction, packet.time, seq, Some(ack), flags, &[])?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// write TCP connections of directory output as packets to `out`
+pub fn synthesize_dir<W: Write>(dir: &Path, out: &mut PcapWriter<W>) -> io::Result<SynthSummary> {
+    let mut connections = read_connections(dir)?;
+    connections.sort_by_key(|info| info.first_packet_us);
+
+    let mut summary = SynthSummary::default();
+    for info in &connections {
+        if info.udp {
+            summary.skipped += 1;
+            continue;
+        }
+        let (Some(forward), Some(reverse)) = (
+            SynthStream::read(dir, info.id, "f")?,
+            SynthStream::read(dir, info.id, "r")?,
+        ) else {
+            debug!("no stream files for connection {}", info.id);
+            summary.skipped += 1;
+            continue;
+        };
+        let streams = [forward, reverse];
+        let mut packets = Vec::new();
+        collect_packets(Direction::Forward, &streams[0], &mut packets);
+        collect_packets(Direction::Reverse, &streams[1], &mut packets);
+
+        let id = info.id.as_bytes();
+        let mut writer = ConnectionWriter {
+            info,
+            out: &mut *out,
+            isn: [
+                u32::from_be_bytes(id[0..4].try_into().unwrap()),
+                u32::from_be_bytes(id[4..8].try_into().unwrap()),
+            ],
+            sent: [0; 2],
+            fin_sent: [false; 2],
+            acked: [0; 2],
+            packets: 0,
+        };
+        writer.write(&streams, packets)?;
+        summary.packets += writer.packets;
+        summary.connections += 1;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use pcap_parser::traits::PcapReaderIterator;
+    use pcap_parser::{LegacyPcapReader, PcapBlockOwned, PcapError};
+
+    use super::{synthesize_dir, SYNTH_LINKTYPE};
+    use crate::flow_table::FlowTable;
+    use crate::handler::{DirectoryOutputHandler, DirectoryOutputSharedInfo};
+    use crate::parser::TcpParser;
+    use crate::pcap_writer::PcapWriter;
+    use crate::serialized::PacketExtra;
+    use crate::{TcpFlags, TcpMeta};
+
+    fn meta(forward: bool, seq_number: u32, ack_number: u32, flags: &TcpFlags) -> TcpMeta {
+        let (client, server) = ([192, 0, 2, 1].into(), [192, 0, 2, 2].into());
+        let ((src_addr, src_port), (dst_addr, dst_port)) = if forward {
+            ((client, 40000), (server, 80))
+        } else {
+            ((server, 80), (client, 40000))
+        };
+        TcpMeta {
+            src_addr,
+            src_port,
+            dst_addr,
+            dst_port,
+            seq_number,
+            ack_number,
+            flags: flags.clone(),
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        }
+    }
+
+    #[test]
+    fn gapless_round_trip() {
+        let dir = std::env::temp_dir().join(format!("parse-tcp-synth-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (shared_info, errors_rx) = DirectoryOutputSharedInfo::new(dir.clone()).unwrap();
+        let mut table: FlowTable<DirectoryOutputHandler> = FlowTable::new(shared_info.clone());
+        let syn = TcpFlags {
+            syn: true,
+            ..Default::default()
+        };
+        let syn_ack = TcpFlags { ack: true, ..syn };
+        let ack = TcpFlags {
+            ack: true,
+            ..Default::default()
+        };
+        let fin = TcpFlags { fin: true, ..ack };
+        // forward data loses 5 bytes between "hello" and "world"
+        let packets: [(TcpMeta, &[u8]); 8] = [
+            (meta(true, 1000, 0, &syn), b""),
+            (meta(false, 5000, 1001, &syn_ack), b""),
+            (meta(true, 1001, 5001, &ack), b""),
+            (meta(true, 1001, 5001, &ack), b"hello"),
+            (meta(true, 1011, 5001, &ack), b"world"),
+            (meta(false, 5001, 1006, &ack), b"ok"),
+            (meta(true, 1016, 5003, &fin), b""),
+            (meta(false, 5003, 1017, &fin), b""),
+        ];
+        for (i, (meta, data)) in packets.iter().enumerate() {
+            let extra = PacketExtra::LegacyPcap {
+                index: i as u64,
+                ts_sec: 10 + i as u32,
+                ts_usec: 0,
+                vlan_id: None,
+                ttl: None,
+                bad_checksum: false,
+                frames: None,
+            };
+            table.handle_packet(meta, data, &extra).unwrap();
+        }
+        table.close();
+        drop(table);
+        shared_info.close().unwrap();
+        assert!(errors_rx.try_recv().is_err());
+
+        let mut out = PcapWriter::new(Vec::new(), SYNTH_LINKTYPE).unwrap();
+        let summary = synthesize_dir(&dir, &mut out).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(summary.connections, 1);
+        assert_eq!(summary.skipped, 0);
+
+        let mut reader = LegacyPcapReader::new(1 << 16, &out.writer[..]).unwrap();
+        let mut parser = TcpParser::new();
+        let mut parsed = Vec::new();
+        loop {
+            match reader.next() {
+                Ok((offset, block)) => {
+                    if let PcapBlockOwned::Legacy(packet) = block {
+                        let (meta, data) = parser.parse_packet(packet.data).unwrap();
+                        parsed.push((meta, data.to_vec()));
+                    }
+                    reader.consume(offset);
+                }
+                // whole capture is in the buffer
+                Err(PcapError::Eof | PcapError::Incomplete(_)) => break,
+                Err(e) => panic!("{e:?}"),
+            }
+        }
+        assert_eq!(summary.packets, parsed.len() as u64);
+        assert!(parsed[0].0.flags.syn && !parsed[0].0.flags.ack);
+        assert!(parsed[1].0.flags.syn && parsed[1].0.flags.ack);
+
+        // payload is contiguous in sequence space in both directions
+        for (src_port, expected) in [(40000, &b"helloworld"[..]), (80, b"ok")] {
+            let sent: Vec<_> = parsed
+                .iter()
+                .filter(|(m, _)| m.src_port == src_port)
+                .collect();
+            let mut next_seq = sent[0].0.seq_number.wrapping_add(1);
+            let mut payload = Vec::new();
+            for (meta, data) in &sent[1..] {
+                assert_eq!(meta.seq_number, next_seq);
+                next_seq = next_seq.wrapping_add(data.len() as u32 + meta.flags.fin as u32);
+                payload.extend_from_slice(data);
+            }
+            assert_eq!(payload, expected);
+            assert!(sent.iter().any(|(meta, _)| meta.flags.fin));
+        }
+    }
+}
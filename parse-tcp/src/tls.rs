@@ -0,0 +1,623 @@
+use std::fmt::Write as _;
+
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, trace};
+
+use crate::connection::{Connection, Direction};
+use crate::handler::DirectoryOutputSharedInfo;
+use crate::serialized::ConnInfo;
+use crate::stream::{SegmentInfo, StreamGap};
+use crate::ConnectionHandler;
+
+/// max bytes of stream prefix buffered while looking for a hello message
+pub const MAX_HELLO_BUFFER: usize = 64 << 10; // 64 KB
+
+/// TLS record content type for handshake messages
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+/// handshake message type of ClientHello
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+/// handshake message type of ServerHello
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_ALPN: u16 = 16;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+
+/// TLS metadata of a connection
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TlsInfo {
+    /// legacy version field of ClientHello
+    pub client_version: Option<u16>,
+    /// versions offered in supported_versions extension
+    pub client_supported_versions: Vec<u16>,
+    /// server name indication
+    pub sni: Option<String>,
+    /// ALPN protocols offered by client
+    pub alpn: Vec<String>,
+    /// JA3 fingerprint string
+    pub ja3: Option<String>,
+    /// MD5 hash of JA3 string
+    pub ja3_hash: Option<String>,
+    /// negotiated version (from supported_versions if present)
+    pub server_version: Option<u16>,
+    /// selected cipher suite
+    pub cipher_suite: Option<u16>,
+    /// ALPN protocol selected by server
+    pub server_alpn: Option<String>,
+    /// JA3S fingerprint string
+    pub ja3s: Option<String>,
+    /// MD5 hash of JA3S string
+    pub ja3s_hash: Option<String>,
+}
+
+/// parsed hello message
+#[derive(Debug)]
+pub enum TlsHello {
+    Client(ClientHello),
+    Server(ServerHello),
+}
+
+/// fields of ClientHello relevant to metadata and fingerprinting
+#[derive(Debug, Default)]
+pub struct ClientHello {
+    pub version: u16,
+    pub random: [u8; 32],
+    pub cipher_suites: Vec<u16>,
+    pub extensions: Vec<u16>,
+    pub sni: Option<String>,
+    pub alpn: Vec<String>,
+    pub supported_versions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+}
+
+/// fields of ServerHello relevant to metadata and fingerprinting
+#[derive(Debug, Default)]
+pub struct ServerHello {
+    pub version: u16,
+    pub random: [u8; 32],
+    pub cipher_suite: u16,
+    pub extensions: Vec<u16>,
+    pub alpn: Option<String>,
+    pub selected_version: Option<u16>,
+}
+
+/// result of attempting to parse a hello from a stream prefix
+#[derive(Debug)]
+pub enum HelloParseResult {
+    /// need more data
+    Incomplete,
+    /// stream does not start with a TLS hello
+    NotTls,
+    /// hello parsed
+    Hello(Box<TlsHello>),
+}
+
+/// check for GREASE values (RFC 8701), which are excluded from fingerprints
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// simple big-endian reader over a byte slice
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            return None;
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    /// read u8 length-prefixed sub-reader
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()? as usize;
+        self.bytes(len).map(|data| Reader { data })
+    }
+
+    /// read u16 length-prefixed sub-reader
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()? as usize;
+        self.bytes(len).map(|data| Reader { data })
+    }
+
+    fn u16_list(mut self) -> Option<Vec<u16>> {
+        let mut out = Vec::with_capacity(self.data.len() / 2);
+        while !self.data.is_empty() {
+            out.push(self.u16()?);
+        }
+        Some(out)
+    }
+}
+
+/// read ALPN protocol name list
+fn read_alpn(mut data: Reader<'_>) -> Option<Vec<String>> {
+    let mut list = data.vec16()?;
+    let mut out = Vec::new();
+    while !list.data.is_empty() {
+        let name = list.vec8()?;
+        out.push(String::from_utf8_lossy(name.data).into_owned());
+    }
+    Some(out)
+}
+
+/// read host name from server_name extension
+fn read_sni(mut data: Reader<'_>) -> Option<String> {
+    let mut list = data.vec16()?;
+    while !list.data.is_empty() {
+        let name_type = list.u8()?;
+        let name = list.vec16()?;
+        if name_type == 0 {
+            return Some(String::from_utf8_lossy(name.data).into_owned());
+        }
+    }
+    None
+}
+
+/// parse ClientHello body
+fn parse_client_hello(mut r: Reader<'_>) -> Option<ClientHello> {
+    let mut hello = ClientHello {
+        version: r.u16()?,
+        ..Default::default()
+    };
+    hello.random.copy_from_slice(r.bytes(32)?);
+    r.vec8()?; // session id
+    hello.cipher_suites = r.vec16()?.u16_list()?;
+    r.vec8()?; // compression methods
+    if r.data.is_empty() {
+        // no extensions
+        return Some(hello);
+    }
+    let mut extensions = r.vec16()?;
+    while !extensions.data.is_empty() {
+        let ext_type = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+        hello.extensions.push(ext_type);
+        match ext_type {
+            EXT_SERVER_NAME => hello.sni = read_sni(data),
+            EXT_ALPN => hello.alpn = read_alpn(data).unwrap_or_default(),
+            EXT_SUPPORTED_GROUPS => {
+                hello.supported_groups = data.vec16()?.u16_list().unwrap_or_default()
+            }
+            EXT_EC_POINT_FORMATS => hello.ec_point_formats = data.vec8()?.data.to_vec(),
+            EXT_SUPPORTED_VERSIONS => {
+                hello.supported_versions = data.vec8()?.u16_list().unwrap_or_default()
+            }
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+/// parse ServerHello body
+fn parse_server_hello(mut r: Reader<'_>) -> Option<ServerHello> {
+    let mut hello = ServerHello {
+        version: r.u16()?,
+        ..Default::default()
+    };
+    hello.random.copy_from_slice(r.bytes(32)?);
+    r.vec8()?; // session id
+    hello.cipher_suite = r.u16()?;
+    r.u8()?; // compression method
+    if r.data.is_empty() {
+        return Some(hello);
+    }
+    let mut extensions = r.vec16()?;
+    while !extensions.data.is_empty() {
+        let ext_type = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+        hello.extensions.push(ext_type);
+        match ext_type {
+            EXT_ALPN => hello.alpn = read_alpn(data).and_then(|list| list.into_iter().next()),
+            EXT_SUPPORTED_VERSIONS => hello.selected_version = data.u16(),
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+/// parse body of a ClientHello or ServerHello handshake message
+pub fn parse_hello_message(msg_type: u8, body: &[u8]) -> Option<TlsHello> {
+    let body = Reader { data: body };
+    match msg_type {
+        HANDSHAKE_CLIENT_HELLO => parse_client_hello(body).map(TlsHello::Client),
+        HANDSHAKE_SERVER_HELLO => parse_server_hello(body).map(TlsHello::Server),
+        _ => None,
+    }
+}
+
+/// attempt to parse a ClientHello or ServerHello from the start of a stream
+pub fn parse_hello(data: &[u8]) -> HelloParseResult {
+    // collect handshake payload across records until one message is complete
+    let mut handshake = Vec::new();
+    let mut r = Reader { data };
+    loop {
+        let Some(header) = r.bytes(5) else {
+            return HelloParseResult::Incomplete;
+        };
+        let content_type = header[0];
+        let major_version = header[1];
+        let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if content_type != CONTENT_TYPE_HANDSHAKE || major_version != 3 {
+            return HelloParseResult::NotTls;
+        }
+        let Some(payload) = r.bytes(record_len) else {
+            return HelloParseResult::Incomplete;
+        };
+        handshake.extend_from_slice(payload);
+
+        let mut msg = Reader { data: &handshake };
+        let (Some(msg_type), Some(msg_len)) = (msg.u8(), msg.u24()) else {
+            continue;
+        };
+        if msg_type != HANDSHAKE_CLIENT_HELLO && msg_type != HANDSHAKE_SERVER_HELLO {
+            return HelloParseResult::NotTls;
+        }
+        let Some(body) = msg.bytes(msg_len) else {
+            continue;
+        };
+        return match parse_hello_message(msg_type, body) {
+            Some(hello) => HelloParseResult::Hello(Box::new(hello)),
+            None => HelloParseResult::NotTls,
+        };
+    }
+}
+
+/// join values with dashes, as used in JA3
+fn ja3_list<T: Copy + Into<u16>>(values: &[T]) -> String {
+    let mut out = String::new();
+    for value in values
+        .iter()
+        .map(|v| (*v).into())
+        .filter(|v| !is_grease(*v))
+    {
+        if !out.is_empty() {
+            out.push('-');
+        }
+        write!(out, "{value}").unwrap();
+    }
+    out
+}
+
+/// hex encoded MD5 hash
+fn md5_hex(data: &str) -> String {
+    let hash = Md5::digest(data.as_bytes());
+    let mut out = String::with_capacity(32);
+    for byte in hash {
+        write!(out, "{byte:02x}").unwrap();
+    }
+    out
+}
+
+impl ClientHello {
+    /// compute JA3 fingerprint string
+    pub fn ja3(&self) -> String {
+        format!(
+            "{},{},{},{},{}",
+            self.version,
+            ja3_list(&self.cipher_suites),
+            ja3_list(&self.extensions),
+            ja3_list(&self.supported_groups),
+            ja3_list(&self.ec_point_formats),
+        )
+    }
+}
+
+impl ServerHello {
+    /// compute JA3S fingerprint string
+    pub fn ja3s(&self) -> String {
+        format!(
+            "{},{},{}",
+            self.version,
+            self.cipher_suite,
+            ja3_list(&self.extensions),
+        )
+    }
+}
+
+impl TlsInfo {
+    /// fill in fields from ClientHello
+    pub fn set_client_hello(&mut self, hello: &ClientHello) {
+        let ja3 = hello.ja3();
+        self.client_version = Some(hello.version);
+        self.client_supported_versions = hello
+            .supported_versions
+            .iter()
+            .copied()
+            .filter(|v| !is_grease(*v))
+            .collect();
+        self.sni = hello.sni.clone();
+        self.alpn = hello.alpn.clone();
+        self.ja3_hash = Some(md5_hex(&ja3));
+        self.ja3 = Some(ja3);
+    }
+
+    /// fill in fields from ServerHello
+    pub fn set_server_hello(&mut self, hello: &ServerHello) {
+        let ja3s = hello.ja3s();
+        self.server_version = Some(hello.selected_version.unwrap_or(hello.version));
+        self.cipher_suite = Some(hello.cipher_suite);
+        self.server_alpn = hello.alpn.clone();
+        self.ja3s_hash = Some(md5_hex(&ja3s));
+        self.ja3s = Some(ja3s);
+    }
+}
+
+/// hello detection state for one direction
+#[derive(Default)]
+pub struct TlsStreamState {
+    /// buffered stream prefix
+    pub buf: Vec<u8>,
+    /// whether detection has finished for this direction
+    pub done: bool,
+}
+
+/// ConnectionHandler to record TLS metadata of connections
+///
+/// Connection info, including TLS hello metadata if seen, is written to
+/// connections.json when the connection is retired. Stream data is
+/// discarded.
+pub struct TlsMetadataHandler {
+    pub shared_info: DirectoryOutputSharedInfo,
+    pub forward: TlsStreamState,
+    pub reverse: TlsStreamState,
+    pub tls: Option<TlsInfo>,
+    /// whether the connection had a handshake or data
+    pub got_handshake_done: bool,
+
+    gaps: Vec<StreamGap>,
+    segments: Vec<SegmentInfo>,
+}
+
+impl TlsMetadataHandler {
+    /// consume data from stream, passing it to hello detection
+    pub fn read_stream(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        len: usize,
+    ) {
+        if len == 0 {
+            return;
+        }
+        self.gaps.clear();
+        self.segments.clear();
+        let stream = connection.get_stream(direction);
+        let end_offset = stream.buffer_start() + len as u64;
+        let state = match direction {
+            Direction::Forward => &mut self.forward,
+            Direction::Reverse => &mut self.reverse,
+        };
+        let read = stream.read_next(end_offset, &mut self.segments, &mut self.gaps, |slice| {
+            if state.done {
+                return;
+            }
+            let (a, b) = slice.as_slices();
+            let wanted = MAX_HELLO_BUFFER.saturating_sub(state.buf.len());
+            let a = &a[..a.len().min(wanted)];
+            state.buf.extend_from_slice(a);
+            if let Some(b) = b {
+                let wanted = MAX_HELLO_BUFFER.saturating_sub(state.buf.len());
+                state.buf.extend_from_slice(&b[..b.len().min(wanted)]);
+            }
+        });
+        if read.is_none() {
+            error!("tls: {direction} stream cannot fulfill range, skipping");
+            return;
+        }
+        if !state.done && !self.gaps.is_empty() {
+            debug!("tls: {direction} stream has gaps, giving up");
+            state.done = true;
+            state.buf = Vec::new();
+        }
+        self.detect(direction);
+    }
+
+    /// try to parse hello message in direction
+    fn detect(&mut self, direction: Direction) {
+        let state = match direction {
+            Direction::Forward => &mut self.forward,
+            Direction::Reverse => &mut self.reverse,
+        };
+        if state.done || state.buf.is_empty() {
+            return;
+        }
+        let hello = match parse_hello(&state.buf) {
+            HelloParseResult::Incomplete => {
+                if state.buf.len() >= MAX_HELLO_BUFFER {
+                    debug!("tls: {direction} hello exceeded buffer limit");
+                    state.done = true;
+                    state.buf = Vec::new();
+                }
+                return;
+            }
+            HelloParseResult::NotTls => {
+                trace!("tls: {direction} stream is not tls");
+                None
+            }
+            HelloParseResult::Hello(hello) => Some(*hello),
+        };
+        state.done = true;
+        state.buf = Vec::new();
+
+        match hello {
+            Some(TlsHello::Client(hello)) => {
+                trace!("tls: got ClientHello (sni {:?})", hello.sni);
+                self.tls
+                    .get_or_insert_with(Default::default)
+                    .set_client_hello(&hello);
+            }
+            Some(TlsHello::Server(hello)) => {
+                trace!("tls: got ServerHello (cipher {:#06x})", hello.cipher_suite);
+                self.tls
+                    .get_or_insert_with(Default::default)
+                    .set_server_hello(&hello);
+            }
+            None => {}
+        }
+    }
+}
+
+impl ConnectionHandler for TlsMetadataHandler {
+    type InitialData = DirectoryOutputSharedInfo;
+    type ConstructError = eyre::Report;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> eyre::Result<Self> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(TlsMetadataHandler {
+            shared_info,
+            forward: TlsStreamState::default(),
+            reverse: TlsStreamState::default(),
+            tls: None,
+            got_handshake_done: false,
+            gaps: Vec::new(),
+            segments: Vec::new(),
+        })
+    }
+
+    fn handshake_done(&mut self, _connection: &mut Connection<Self>) {
+        self.got_handshake_done = true;
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        let total_len = stream.total_buffered_length();
+        if readable_len > 0 {
+            self.read_stream(connection, direction, readable_len);
+        } else if total_len > MAX_HELLO_BUFFER {
+            // stuck behind a gap, skip ahead
+            self.read_stream(connection, direction, total_len);
+        }
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        info!(
+            "removing connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        for direction in [Direction::Forward, Direction::Reverse] {
+            let len = connection.get_stream(direction).total_buffered_length();
+            self.read_stream(connection, direction, len);
+        }
+        if !self.got_handshake_done {
+            return;
+        }
+        let mut info = ConnInfo::from_connection(connection);
+        info.tls = self.tls.take();
+        if let Err(e) = self.shared_info.write_conn_info(&info) {
+            tracing::error!("failed to write connection info: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_grease, parse_hello, HelloParseResult, TlsHello, TlsInfo};
+
+    /// build a TLS record containing one handshake message
+    fn record(msg_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut msg = vec![msg_type];
+        msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        msg.extend_from_slice(body);
+        let mut out = vec![22, 3, 1];
+        out.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+        out.extend_from_slice(&msg);
+        out
+    }
+
+    fn extension(ext_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut out = ext_type.to_be_bytes().to_vec();
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn client_hello() -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0); // session id
+                      // ciphers: GREASE, 0x1301, 0xc02f
+        body.extend_from_slice(&[0, 6, 0x0a, 0x0a, 0x13, 0x01, 0xc0, 0x2f]);
+        body.extend_from_slice(&[1, 0]); // compression
+
+        let mut extensions = Vec::new();
+        let host = b"example.com";
+        let mut sni = ((host.len() + 3) as u16).to_be_bytes().to_vec();
+        sni.push(0);
+        sni.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        sni.extend_from_slice(host);
+        extensions.extend(extension(0, &sni));
+        extensions.extend(extension(10, &[0, 4, 0, 29, 0, 23]));
+        extensions.extend(extension(11, &[1, 0]));
+        extensions.extend(extension(16, b"\x00\x0c\x02h2\x08http/1.1"));
+        extensions.extend(extension(43, &[4, 3, 4, 3, 3]));
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        record(1, &body)
+    }
+
+    #[test]
+    fn grease() {
+        assert!(is_grease(0x0a0a));
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+        assert!(!is_grease(0x1301));
+    }
+
+    #[test]
+    fn client_hello_metadata() {
+        let data = client_hello();
+        assert!(matches!(
+            parse_hello(&data[..data.len() - 1]),
+            HelloParseResult::Incomplete
+        ));
+        let HelloParseResult::Hello(hello) = parse_hello(&data) else {
+            panic!("failed to parse ClientHello");
+        };
+        let TlsHello::Client(hello) = *hello else {
+            panic!("parsed ServerHello");
+        };
+        assert_eq!(hello.ja3(), "771,4865-49199,0-10-11-16-43,29-23,0");
+
+        let mut info = TlsInfo::default();
+        info.set_client_hello(&hello);
+        assert_eq!(info.sni.as_deref(), Some("example.com"));
+        assert_eq!(info.alpn, ["h2", "http/1.1"]);
+        assert_eq!(info.client_supported_versions, [0x0304, 0x0303]);
+        assert_eq!(info.ja3_hash.as_ref().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn not_tls() {
+        assert!(matches!(
+            parse_hello(b"GET / HTTP/1.1\r\n\r\n"),
+            HelloParseResult::NotTls
+        ));
+    }
+}
@@ -0,0 +1,1268 @@
+//! TLS decryption using an NSS key log (`SSLKEYLOGFILE`)
+//!
+//! Records are reassembled from the stream, handshake messages are tracked to
+//! learn the randoms, cipher suite and version, and keys are derived from the
+//! secrets in the key log. Decrypted application data is pushed into a
+//! `VirtualConnection` with its own handler.
+//!
+//! Supported are TLS 1.2 with AES-GCM or ChaCha20-Poly1305 suites and all
+//! TLS 1.3 suites except CCM. CBC suites, TLS 1.3 early data and
+//! connections with loss are not decrypted. Connections not starting with a
+//! TLS record are passed through unchanged.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha384};
+use tracing::{debug, error, info, trace};
+
+use crate::connection::{Connection, Direction};
+use crate::serialized::PacketExtra;
+use crate::stream::{SegmentInfo, StreamGap};
+use crate::tls::{parse_hello_message, TlsHello};
+use crate::virtual_conn::VirtualConnection;
+use crate::ConnectionHandler;
+
+/// maximum length of a record payload, allowing for expansion
+pub const MAX_RECORD_LENGTH: usize = (1 << 14) + 2048;
+/// maximum length of a handshake message
+const MAX_HANDSHAKE_LENGTH: usize = 1 << 20;
+
+const CONTENT_TYPE_CHANGE_CIPHER_SPEC: u8 = 20;
+const CONTENT_TYPE_ALERT: u8 = 21;
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 23;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+const HANDSHAKE_FINISHED: u8 = 20;
+const HANDSHAKE_KEY_UPDATE: u8 = 24;
+
+const VERSION_TLS13: u16 = 0x0304;
+
+/// ServerHello random marking a HelloRetryRequest
+const HELLO_RETRY_REQUEST_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// secret type in the key log
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SecretLabel {
+    /// TLS 1.2 master secret (`CLIENT_RANDOM`)
+    MasterSecret,
+    ClientHandshakeTraffic,
+    ServerHandshakeTraffic,
+    ClientTraffic0,
+    ServerTraffic0,
+}
+
+impl SecretLabel {
+    /// look up label as written in the key log
+    pub fn from_label(label: &str) -> Option<Self> {
+        match label {
+            "CLIENT_RANDOM" => Some(SecretLabel::MasterSecret),
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET" => Some(SecretLabel::ClientHandshakeTraffic),
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET" => Some(SecretLabel::ServerHandshakeTraffic),
+            "CLIENT_TRAFFIC_SECRET_0" => Some(SecretLabel::ClientTraffic0),
+            "SERVER_TRAFFIC_SECRET_0" => Some(SecretLabel::ServerTraffic0),
+            _ => None,
+        }
+    }
+}
+
+/// secrets from an NSS key log, by client random
+#[derive(Debug, Default)]
+pub struct KeyLog {
+    secrets: HashMap<([u8; 32], SecretLabel), Vec<u8>>,
+}
+
+impl KeyLog {
+    /// parse key log text, ignoring comments and unknown labels
+    pub fn parse(text: &str) -> Self {
+        let mut keylog = KeyLog::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_ascii_whitespace();
+            let (Some(label), Some(random), Some(secret)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                debug!("keylog: line {} is malformed", index + 1);
+                continue;
+            };
+            let Some(label) = SecretLabel::from_label(label) else {
+                trace!("keylog: ignoring label {label}");
+                continue;
+            };
+            let (Some(random), Some(secret)) = (decode_hex(random), decode_hex(secret)) else {
+                debug!("keylog: line {} has invalid hex", index + 1);
+                continue;
+            };
+            let Ok(random) = <[u8; 32]>::try_from(random) else {
+                debug!("keylog: line {} has invalid client random", index + 1);
+                continue;
+            };
+            keylog.secrets.insert((random, label), secret);
+        }
+        keylog
+    }
+
+    /// read and parse key log file
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        Ok(KeyLog::parse(&fs::read_to_string(path)?))
+    }
+
+    /// look up secret for connection
+    pub fn get(&self, client_random: &[u8; 32], label: SecretLabel) -> Option<&[u8]> {
+        self.secrets
+            .get(&(*client_random, label))
+            .map(Vec::as_slice)
+    }
+
+    /// number of secrets
+    pub fn len(&self) -> usize {
+        self.secrets.len()
+    }
+
+    /// whether no secrets were loaded
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+}
+
+/// decode hex string
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AeadAlgorithm {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HashAlgorithm {
+    Sha256,
+    Sha384,
+}
+
+/// supported cipher suite
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CipherSuite {
+    pub id: u16,
+    aead: AeadAlgorithm,
+    hash: HashAlgorithm,
+    tls13: bool,
+}
+
+impl CipherSuite {
+    /// look up suite by id, None if unsupported
+    pub fn from_id(id: u16) -> Option<Self> {
+        use AeadAlgorithm::*;
+        use HashAlgorithm::*;
+        let (aead, hash, tls13) = match id {
+            0x1301 => (Aes128Gcm, Sha256, true),
+            0x1302 => (Aes256Gcm, Sha384, true),
+            0x1303 => (ChaCha20Poly1305, Sha256, true),
+            // RSA, DHE_RSA, ECDHE_ECDSA and ECDHE_RSA with AES-GCM
+            0x009c | 0x009e | 0xc02b | 0xc02f => (Aes128Gcm, Sha256, false),
+            0x009d | 0x009f | 0xc02c | 0xc030 => (Aes256Gcm, Sha384, false),
+            // ECDHE_RSA, ECDHE_ECDSA and DHE_RSA with ChaCha20-Poly1305
+            0xcca8..=0xccaa => (ChaCha20Poly1305, Sha256, false),
+            _ => return None,
+        };
+        Some(CipherSuite {
+            id,
+            aead,
+            hash,
+            tls13,
+        })
+    }
+
+    fn key_len(&self) -> usize {
+        match self.aead {
+            AeadAlgorithm::Aes128Gcm => 16,
+            AeadAlgorithm::Aes256Gcm | AeadAlgorithm::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    fn hash_len(&self) -> usize {
+        match self.hash {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha384 => 48,
+        }
+    }
+
+    /// length of fixed IV in the TLS 1.2 key block
+    fn tls12_iv_len(&self) -> usize {
+        match self.aead {
+            AeadAlgorithm::Aes128Gcm | AeadAlgorithm::Aes256Gcm => 4,
+            AeadAlgorithm::ChaCha20Poly1305 => 12,
+        }
+    }
+}
+
+/// TLS 1.2 P_hash expansion
+fn p_hash<M: Mac + KeyInit + Clone>(secret: &[u8], seed: &[u8], len: usize) -> Vec<u8> {
+    let mac = <M as KeyInit>::new_from_slice(secret).expect("hmac accepts any key length");
+    let mut out = Vec::with_capacity(len);
+    let mut a = mac
+        .clone()
+        .chain_update(seed)
+        .finalize()
+        .into_bytes()
+        .to_vec();
+    while out.len() < len {
+        let block = mac.clone().chain_update(&a).chain_update(seed);
+        out.extend_from_slice(&block.finalize().into_bytes());
+        a = mac
+            .clone()
+            .chain_update(&a)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+    }
+    out.truncate(len);
+    out
+}
+
+/// TLS 1.2 PRF
+fn prf(hash: HashAlgorithm, secret: &[u8], label: &[u8], seed: &[u8], len: usize) -> Vec<u8> {
+    let seed = [label, seed].concat();
+    match hash {
+        HashAlgorithm::Sha256 => p_hash::<Hmac<Sha256>>(secret, &seed, len),
+        HashAlgorithm::Sha384 => p_hash::<Hmac<Sha384>>(secret, &seed, len),
+    }
+}
+
+/// TLS 1.3 HKDF-Expand-Label with empty context
+fn expand_label(hash: HashAlgorithm, secret: &[u8], label: &str, len: usize) -> Option<Vec<u8>> {
+    let full_label = format!("tls13 {label}");
+    let mut info = Vec::with_capacity(4 + full_label.len());
+    info.extend_from_slice(&(len as u16).to_be_bytes());
+    info.push(full_label.len() as u8);
+    info.extend_from_slice(full_label.as_bytes());
+    info.push(0);
+    let mut out = vec![0; len];
+    match hash {
+        HashAlgorithm::Sha256 => Hkdf::<Sha256>::from_prk(secret)
+            .ok()?
+            .expand(&info, &mut out),
+        HashAlgorithm::Sha384 => Hkdf::<Sha384>::from_prk(secret)
+            .ok()?
+            .expand(&info, &mut out),
+    }
+    .ok()?;
+    Some(out)
+}
+
+enum Cipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl Cipher {
+    fn new(aead: AeadAlgorithm, key: &[u8]) -> Option<Self> {
+        Some(match aead {
+            AeadAlgorithm::Aes128Gcm => {
+                Cipher::Aes128Gcm(Box::new(Aes128Gcm::new_from_slice(key).ok()?))
+            }
+            AeadAlgorithm::Aes256Gcm => {
+                Cipher::Aes256Gcm(Box::new(Aes256Gcm::new_from_slice(key).ok()?))
+            }
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                Cipher::ChaCha20Poly1305(Box::new(ChaCha20Poly1305::new_from_slice(key).ok()?))
+            }
+        })
+    }
+
+    /// decrypt and authenticate ciphertext with tag in place
+    fn decrypt(&self, nonce: &[u8; 12], aad: &[u8], buf: &mut Vec<u8>) -> Option<()> {
+        let nonce = Nonce::from_slice(nonce);
+        match self {
+            Cipher::Aes128Gcm(c) => c.decrypt_in_place(nonce, aad, buf),
+            Cipher::Aes256Gcm(c) => c.decrypt_in_place(nonce, aad, buf),
+            Cipher::ChaCha20Poly1305(c) => c.decrypt_in_place(nonce, aad, buf),
+        }
+        .ok()
+    }
+}
+
+/// keys and sequence number for one direction
+struct RecordKeys {
+    suite: CipherSuite,
+    cipher: Cipher,
+    iv: [u8; 12],
+    seq: u64,
+    /// TLS 1.3 traffic secret, for key updates
+    secret: Vec<u8>,
+}
+
+impl RecordKeys {
+    /// derive TLS 1.3 keys from traffic secret
+    fn tls13(suite: CipherSuite, secret: &[u8]) -> Option<Self> {
+        let key = expand_label(suite.hash, secret, "key", suite.key_len())?;
+        let iv = expand_label(suite.hash, secret, "iv", 12)?;
+        Some(RecordKeys {
+            suite,
+            cipher: Cipher::new(suite.aead, &key)?,
+            iv: iv.try_into().ok()?,
+            seq: 0,
+            secret: secret.to_vec(),
+        })
+    }
+
+    /// TLS 1.2 keys from key block, `iv` is the fixed part only
+    fn tls12(suite: CipherSuite, key: &[u8], iv: &[u8]) -> Option<Self> {
+        let mut full_iv = [0; 12];
+        full_iv[..iv.len()].copy_from_slice(iv);
+        Some(RecordKeys {
+            suite,
+            cipher: Cipher::new(suite.aead, key)?,
+            iv: full_iv,
+            seq: 0,
+            secret: Vec::new(),
+        })
+    }
+
+    /// apply TLS 1.3 KeyUpdate
+    fn update(&mut self) -> Option<()> {
+        let hash_len = self.suite.hash_len();
+        let secret = expand_label(self.suite.hash, &self.secret, "traffic upd", hash_len)?;
+        *self = RecordKeys::tls13(self.suite, &secret)?;
+        Some(())
+    }
+
+    /// nonce from IV with sequence number mixed in
+    fn xor_nonce(&self) -> [u8; 12] {
+        let mut nonce = self.iv;
+        for (n, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        nonce
+    }
+
+    /// decrypt record, returning content type and plaintext
+    ///
+    /// The sequence number only advances if decryption succeeds.
+    fn decrypt(&mut self, header: &[u8], payload: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let (content_type, buf) = if self.suite.tls13 {
+            let mut buf = payload.to_vec();
+            self.cipher.decrypt(&self.xor_nonce(), header, &mut buf)?;
+            // strip padding, inner content type is the last non-zero byte
+            let end = buf.iter().rposition(|&b| b != 0)?;
+            let content_type = buf[end];
+            buf.truncate(end);
+            (content_type, buf)
+        } else {
+            let (nonce, ciphertext) = if self.suite.aead == AeadAlgorithm::ChaCha20Poly1305 {
+                (self.xor_nonce(), payload)
+            } else {
+                // 4-byte implicit salt followed by explicit nonce in record
+                let explicit = payload.get(..8)?;
+                let mut nonce = self.iv;
+                nonce[4..].copy_from_slice(explicit);
+                (nonce, &payload[8..])
+            };
+            let plaintext_len = ciphertext.len().checked_sub(16)?;
+            let mut aad = Vec::with_capacity(13);
+            aad.extend_from_slice(&self.seq.to_be_bytes());
+            aad.extend_from_slice(&header[..3]);
+            aad.extend_from_slice(&(plaintext_len as u16).to_be_bytes());
+            let mut buf = ciphertext.to_vec();
+            self.cipher.decrypt(&nonce, &aad, &mut buf)?;
+            (header[0], buf)
+        };
+        self.seq += 1;
+        Some((content_type, buf))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DecryptMode {
+    /// waiting for the first bytes of the connection
+    Detect,
+    Tls,
+    /// not TLS, data is passed through
+    Passthrough,
+}
+
+/// record and key state of one direction
+#[derive(Default)]
+struct DirectionState {
+    /// unprocessed record data
+    buf: Vec<u8>,
+    /// handshake message data not yet parsed
+    handshake: Vec<u8>,
+    keys: Option<RecordKeys>,
+    /// TLS 1.2 ChangeCipherSpec seen, further records are encrypted
+    change_cipher_spec: bool,
+    /// a record was decrypted
+    decrypted_any: bool,
+    /// decryption stopped
+    failed: bool,
+    /// loss not yet reported to the sink
+    lost_pending: bool,
+}
+
+/// decryption state for one connection
+pub struct TlsDecryptor {
+    keylog: Arc<KeyLog>,
+    mode: DecryptMode,
+    /// direction the ClientHello was sent in
+    client_direction: Option<Direction>,
+    client_random: Option<[u8; 32]>,
+    server_random: Option<[u8; 32]>,
+    suite: Option<CipherSuite>,
+    tls13: bool,
+    directions: [DirectionState; 2],
+}
+
+impl TlsDecryptor {
+    pub fn new(keylog: Arc<KeyLog>) -> Self {
+        TlsDecryptor {
+            keylog,
+            mode: DecryptMode::Detect,
+            client_direction: None,
+            client_random: None,
+            server_random: None,
+            suite: None,
+            tls13: false,
+            directions: Default::default(),
+        }
+    }
+
+    /// whether the connection is TLS, None if undecided
+    pub fn is_tls(&self) -> Option<bool> {
+        match self.mode {
+            DecryptMode::Detect => None,
+            DecryptMode::Tls => Some(true),
+            DecryptMode::Passthrough => Some(false),
+        }
+    }
+
+    /// swap state of both directions after forward and reverse of the
+    /// connection were swapped
+    pub fn reversed(&mut self) {
+        self.directions.swap(0, 1);
+        self.client_direction = self.client_direction.map(Direction::swap);
+    }
+
+    /// direction of plaintext from stream direction, Forward for the client
+    pub fn plaintext_direction(&self, direction: Direction) -> Direction {
+        match self.client_direction {
+            Some(Direction::Reverse) => direction.swap(),
+            _ => direction,
+        }
+    }
+
+    fn state(&mut self, direction: Direction) -> &mut DirectionState {
+        match direction {
+            Direction::Forward => &mut self.directions[0],
+            Direction::Reverse => &mut self.directions[1],
+        }
+    }
+
+    fn is_client(&self, direction: Direction) -> bool {
+        self.client_direction.unwrap_or(Direction::Forward) == direction
+    }
+
+    /// stop decrypting direction
+    fn fail(&mut self, direction: Direction, reason: &str) {
+        let state = self.state(direction);
+        if !state.failed {
+            debug!("tls: {direction} no longer decrypting: {reason}");
+            state.failed = true;
+            state.lost_pending = true;
+            state.buf = Vec::new();
+            state.handshake = Vec::new();
+        }
+    }
+
+    /// feed stream data, appending plaintext to `out`
+    ///
+    /// Returns whether plaintext was lost before the data in `out`.
+    pub fn feed(
+        &mut self,
+        direction: Direction,
+        data: &[u8],
+        lost: bool,
+        out: &mut Vec<u8>,
+    ) -> bool {
+        if self.mode == DecryptMode::Detect {
+            let state = self.state(direction);
+            state.buf.extend_from_slice(data);
+            if state.buf.len() < 2 && !lost {
+                return false;
+            }
+            self.mode = if state.buf.starts_with(&[CONTENT_TYPE_HANDSHAKE, 3]) && !lost {
+                DecryptMode::Tls
+            } else {
+                trace!("tls: connection is not tls, passing through");
+                DecryptMode::Passthrough
+            };
+            let buffered = std::mem::take(&mut self.state(direction).buf);
+            return self.feed(direction, &buffered, lost, out);
+        }
+
+        match self.mode {
+            DecryptMode::Passthrough => {
+                let state = self.state(direction);
+                out.append(&mut state.buf);
+                out.extend_from_slice(data);
+                lost
+            }
+            _ => {
+                if lost {
+                    self.fail(direction, "stream has gaps");
+                }
+                if !self.state(direction).failed {
+                    self.state(direction).buf.extend_from_slice(data);
+                    self.process_records(direction, out);
+                }
+                std::mem::take(&mut self.state(direction).lost_pending)
+            }
+        }
+    }
+
+    /// process all complete records of direction
+    fn process_records(&mut self, direction: Direction, out: &mut Vec<u8>) {
+        let buf = std::mem::take(&mut self.state(direction).buf);
+        let mut pos = 0;
+        while let Some(header) = buf.get(pos..pos + 5) {
+            let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+            if header[1] != 3 || length > MAX_RECORD_LENGTH {
+                self.fail(direction, "invalid record header");
+                return;
+            }
+            let Some(payload) = buf.get(pos + 5..pos + 5 + length) else {
+                break;
+            };
+            self.record(direction, header, payload, out);
+            pos += 5 + length;
+            if self.state(direction).failed {
+                return;
+            }
+        }
+        self.state(direction).buf = buf[pos..].to_vec();
+    }
+
+    /// handle one record
+    fn record(&mut self, direction: Direction, header: &[u8], payload: &[u8], out: &mut Vec<u8>) {
+        let content_type = header[0];
+        if content_type == CONTENT_TYPE_CHANGE_CIPHER_SPEC {
+            // only a compatibility record in TLS 1.3
+            if !self.tls13 {
+                self.state(direction).change_cipher_spec = true;
+                if self.state(direction).keys.is_none() {
+                    self.derive_tls12_keys();
+                }
+            }
+            return;
+        }
+
+        let encrypted = if self.tls13 {
+            content_type == CONTENT_TYPE_APPLICATION_DATA
+        } else {
+            self.state(direction).change_cipher_spec
+        };
+        if !encrypted {
+            match content_type {
+                CONTENT_TYPE_HANDSHAKE => self.handshake_data(direction, payload),
+                CONTENT_TYPE_ALERT => trace!("tls: {direction} alert"),
+                _ => trace!("tls: {direction} ignoring plaintext record {content_type}"),
+            }
+            return;
+        }
+
+        let early_data_possible = self.is_client(direction) && !self.state(direction).decrypted_any;
+        let Some(keys) = self.state(direction).keys.as_mut() else {
+            if early_data_possible && self.server_random.is_none() {
+                trace!("tls: skipping early data");
+            } else {
+                self.fail(direction, "no keys");
+            }
+            return;
+        };
+        let Some((inner_type, plaintext)) = keys.decrypt(header, payload) else {
+            if early_data_possible && self.tls13 {
+                // early data is encrypted with keys we do not derive
+                trace!("tls: skipping undecryptable early data");
+            } else {
+                self.fail(direction, "decryption failed");
+            }
+            return;
+        };
+        self.state(direction).decrypted_any = true;
+        match inner_type {
+            CONTENT_TYPE_APPLICATION_DATA => out.extend_from_slice(&plaintext),
+            CONTENT_TYPE_HANDSHAKE => self.handshake_data(direction, &plaintext),
+            CONTENT_TYPE_ALERT => trace!("tls: {direction} alert"),
+            _ => trace!("tls: {direction} ignoring record {inner_type}"),
+        }
+    }
+
+    /// append handshake data and handle complete messages
+    fn handshake_data(&mut self, direction: Direction, data: &[u8]) {
+        let mut handshake = std::mem::take(&mut self.state(direction).handshake);
+        handshake.extend_from_slice(data);
+        let mut pos = 0;
+        while let Some(header) = handshake.get(pos..pos + 4) {
+            let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            if length > MAX_HANDSHAKE_LENGTH {
+                self.fail(direction, "handshake message too long");
+                return;
+            }
+            let Some(body) = handshake.get(pos + 4..pos + 4 + length) else {
+                break;
+            };
+            self.handshake_message(direction, header[0], body);
+            pos += 4 + length;
+            if self.state(direction).failed {
+                return;
+            }
+        }
+        handshake.drain(..pos);
+        self.state(direction).handshake = handshake;
+    }
+
+    /// handle one handshake message
+    fn handshake_message(&mut self, direction: Direction, msg_type: u8, body: &[u8]) {
+        match msg_type {
+            HANDSHAKE_CLIENT_HELLO => {
+                if let Some(TlsHello::Client(hello)) = parse_hello_message(msg_type, body) {
+                    self.client_direction = Some(direction);
+                    self.client_random = Some(hello.random);
+                }
+            }
+            HANDSHAKE_SERVER_HELLO => {
+                let Some(TlsHello::Server(hello)) = parse_hello_message(msg_type, body) else {
+                    return;
+                };
+                if hello.random == HELLO_RETRY_REQUEST_RANDOM {
+                    trace!("tls: hello retry request");
+                    return;
+                }
+                self.server_random = Some(hello.random);
+                self.suite = CipherSuite::from_id(hello.cipher_suite);
+                if self.suite.is_none() {
+                    debug!("tls: unsupported cipher suite {:#06x}", hello.cipher_suite);
+                }
+                self.tls13 = hello.selected_version == Some(VERSION_TLS13);
+                if self.tls13 {
+                    let server = direction;
+                    let client = direction.swap();
+                    self.state(server).keys = self.tls13_keys(SecretLabel::ServerHandshakeTraffic);
+                    self.state(client).keys = self.tls13_keys(SecretLabel::ClientHandshakeTraffic);
+                }
+            }
+            HANDSHAKE_FINISHED if self.tls13 => {
+                let label = if self.is_client(direction) {
+                    SecretLabel::ClientTraffic0
+                } else {
+                    SecretLabel::ServerTraffic0
+                };
+                self.state(direction).keys = self.tls13_keys(label);
+            }
+            HANDSHAKE_KEY_UPDATE if self.tls13 => {
+                let updated = self
+                    .state(direction)
+                    .keys
+                    .as_mut()
+                    .and_then(RecordKeys::update);
+                if updated.is_none() {
+                    self.fail(direction, "key update failed");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// derive TLS 1.3 keys for secret
+    fn tls13_keys(&self, label: SecretLabel) -> Option<RecordKeys> {
+        let suite = self.suite.filter(|suite| suite.tls13)?;
+        let Some(secret) = self.keylog.get(self.client_random.as_ref()?, label) else {
+            debug!("tls: no {label:?} secret in key log");
+            return None;
+        };
+        RecordKeys::tls13(suite, secret)
+    }
+
+    /// derive TLS 1.2 keys for both directions from the master secret
+    fn derive_tls12_keys(&mut self) {
+        let (Some(suite), Some(client_random), Some(server_random)) =
+            (self.suite, self.client_random, self.server_random)
+        else {
+            return;
+        };
+        let Some(master_secret) = self.keylog.get(&client_random, SecretLabel::MasterSecret) else {
+            debug!("tls: no master secret in key log");
+            return;
+        };
+        let key_len = suite.key_len();
+        let iv_len = suite.tls12_iv_len();
+        let seed = [server_random, client_random].concat();
+        let block = prf(
+            suite.hash,
+            master_secret,
+            b"key expansion",
+            &seed,
+            2 * key_len + 2 * iv_len,
+        );
+        let (client_key, rest) = block.split_at(key_len);
+        let (server_key, rest) = rest.split_at(key_len);
+        let (client_iv, server_iv) = rest.split_at(iv_len);
+
+        let client = self.client_direction.unwrap_or(Direction::Forward);
+        self.state(client).keys = RecordKeys::tls12(suite, client_key, client_iv);
+        self.state(client.swap()).keys = RecordKeys::tls12(suite, server_key, server_iv);
+    }
+}
+
+/// initial data for TlsDecryptHandler
+#[derive(Clone)]
+pub struct TlsDecryptSharedInfo<T> {
+    pub keylog: Arc<KeyLog>,
+    /// initial data for the handler of decrypted data
+    pub inner: T,
+}
+
+/// ConnectionHandler decrypting TLS connections
+///
+/// Decrypted application data is pushed into a `VirtualConnection` handled
+/// by `H`, with the client as the forward direction. Connections which are
+/// not TLS are passed on unchanged.
+pub struct TlsDecryptHandler<H: ConnectionHandler> {
+    pub decryptor: TlsDecryptor,
+    /// connection carrying decrypted data
+    pub inner: VirtualConnection<H>,
+
+    plaintext: Vec<u8>,
+    gaps: Vec<StreamGap>,
+    segments: Vec<SegmentInfo>,
+    buf: Vec<u8>,
+}
+
+impl<H: ConnectionHandler> TlsDecryptHandler<H> {
+    /// read data from stream and pass it on decrypted
+    pub fn read_stream(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        len: usize,
+    ) {
+        if len == 0 {
+            return;
+        }
+        self.gaps.clear();
+        self.segments.clear();
+        self.buf.clear();
+        let stream = connection.get_stream(direction);
+        let start_offset = stream.buffer_start();
+        let buf = &mut self.buf;
+        let read = stream.read_next(
+            start_offset + len as u64,
+            &mut self.segments,
+            &mut self.gaps,
+            |slice| {
+                let (a, b) = slice.as_slices();
+                buf.extend_from_slice(a);
+                if let Some(b) = b {
+                    buf.extend_from_slice(b);
+                }
+            },
+        );
+        if read.is_none() {
+            error!("{direction}: stream cannot fulfill range of {len} bytes, skipping");
+            return;
+        }
+
+        self.plaintext.clear();
+        let lost = self.decryptor.feed(
+            direction,
+            &self.buf,
+            !self.gaps.is_empty(),
+            &mut self.plaintext,
+        );
+        let inner_direction = self.decryptor.plaintext_direction(direction);
+        self.inner.sync(connection);
+        if self.decryptor.is_tls() == Some(false) {
+            // passed through, keep gaps in place
+            let prefix = self.plaintext.len() - self.buf.len();
+            let mut pos = 0;
+            for StreamGap { range: gap, .. } in &self.gaps {
+                let gap_start = prefix + (gap.start - start_offset) as usize;
+                self.inner
+                    .push_data(inner_direction, &self.plaintext[pos..gap_start]);
+                self.inner.push_gap(inner_direction, gap.end - gap.start);
+                pos = gap_start + (gap.end - gap.start) as usize;
+            }
+            self.inner
+                .push_data(inner_direction, &self.plaintext[pos..]);
+        } else {
+            if lost {
+                // length of lost plaintext is unknown, use that of the
+                // ciphertext if any was lost
+                let lost_len: u64 = self
+                    .gaps
+                    .iter()
+                    .map(|gap| gap.range.end - gap.range.start)
+                    .sum();
+                self.inner.push_gap(inner_direction, lost_len.max(1));
+            }
+            self.inner.push_data(inner_direction, &self.plaintext);
+        }
+    }
+
+    /// read all remaining data and retire the inner connection
+    pub fn write_remaining(&mut self, connection: &mut Connection<Self>) {
+        for direction in [Direction::Forward, Direction::Reverse] {
+            let len = connection.get_stream(direction).total_buffered_length();
+            self.read_stream(connection, direction, len);
+        }
+        self.inner.sync(connection);
+        self.inner.retire();
+    }
+}
+
+impl<H: ConnectionHandler> ConnectionHandler for TlsDecryptHandler<H> {
+    type InitialData = TlsDecryptSharedInfo<H::InitialData>;
+    type ConstructError = H::ConstructError;
+    fn new(
+        shared_info: Self::InitialData,
+        connection: &mut Connection<Self>,
+    ) -> Result<Self, Self::ConstructError> {
+        debug!(
+            "connection created: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        Ok(TlsDecryptHandler {
+            decryptor: TlsDecryptor::new(shared_info.keylog),
+            inner: VirtualConnection::new(connection, shared_info.inner)?,
+            plaintext: Vec::new(),
+            gaps: Vec::new(),
+            segments: Vec::new(),
+            buf: Vec::new(),
+        })
+    }
+
+    fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        let stream = connection.get_stream(direction);
+        let readable_len = stream.readable_buffered_length();
+        if readable_len > 0 {
+            self.read_stream(connection, direction, readable_len);
+        } else if stream.total_buffered_length() > 2 * MAX_RECORD_LENGTH {
+            // stuck behind a gap, skip ahead
+            let len = stream.total_buffered_length();
+            self.read_stream(connection, direction, len);
+        }
+    }
+
+    fn fin_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+        self.inner.sync(connection);
+        self.inner
+            .push_fin(self.decryptor.plaintext_direction(direction));
+    }
+
+    fn rst_received(
+        &mut self,
+        connection: &mut Connection<Self>,
+        direction: Direction,
+        extra: PacketExtra,
+    ) {
+        self.inner.sync(connection);
+        self.inner
+            .push_rst(self.decryptor.plaintext_direction(direction), extra);
+    }
+
+    fn direction_changed(&mut self, _connection: &mut Connection<Self>) {
+        self.decryptor.reversed();
+        // the client stays forward of the decrypted connection once known,
+        // otherwise it follows the captured connection
+        if self.decryptor.client_direction.is_none() {
+            self.inner.reverse_direction();
+        }
+    }
+
+    fn will_retire(&mut self, connection: &mut Connection<Self>) {
+        info!(
+            "removing connection: {} ({})",
+            connection.forward_flow, connection.uuid
+        );
+        self.write_remaining(connection);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    use aes_gcm::aead::{AeadInPlace, KeyInit};
+    use aes_gcm::{Aes128Gcm, Nonce};
+    use parking_lot::Mutex;
+
+    use super::{
+        decode_hex, expand_label, prf, CipherSuite, HashAlgorithm, KeyLog, RecordKeys, SecretLabel,
+        TlsDecryptHandler, TlsDecryptSharedInfo, TlsDecryptor,
+    };
+    use crate::connection::{Connection, Direction};
+    use crate::flow_table::FlowTable;
+    use crate::handler::{DirectoryOutputHandler, DirectoryOutputSharedInfo};
+    use crate::serialized::PacketExtra;
+    use crate::{ConnectionHandler, TcpFlags, TcpMeta};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// handler of decrypted connection logging events
+    struct LogHandler {
+        log: Log,
+    }
+
+    impl ConnectionHandler for LogHandler {
+        type InitialData = Log;
+        type ConstructError = Infallible;
+        fn new(log: Log, _conn: &mut Connection<Self>) -> Result<Self, Infallible> {
+            Ok(LogHandler { log })
+        }
+
+        fn handshake_done(&mut self, _connection: &mut Connection<Self>) {
+            self.log.lock().push("handshake".into());
+        }
+
+        fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+            let stream = connection.get_stream(direction);
+            let end = stream.buffer_start() + stream.readable_buffered_length() as u64;
+            let (mut segments, mut gaps) = (Vec::new(), Vec::new());
+            let data = stream
+                .read_next(end, &mut segments, &mut gaps, |slice| {
+                    let (a, b) = slice.as_slices();
+                    [a, b.unwrap_or_default()].concat()
+                })
+                .unwrap();
+            let line = format!("{direction} {}", String::from_utf8(data).unwrap());
+            self.log.lock().push(line);
+        }
+
+        fn fin_received(&mut self, _connection: &mut Connection<Self>, direction: Direction) {
+            self.log.lock().push(format!("fin {direction}"));
+        }
+
+        fn rst_received(
+            &mut self,
+            _connection: &mut Connection<Self>,
+            direction: Direction,
+            _extra: PacketExtra,
+        ) {
+            self.log.lock().push(format!("rst {direction}"));
+        }
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        decode_hex(&s.replace(' ', "")).unwrap()
+    }
+
+    /// build record with header
+    fn record(content_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut out = vec![content_type, 3, 3];
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// build handshake message
+    fn handshake(msg_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![msg_type];
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// encrypt TLS 1.3 record with AES-128-GCM keys derived from secret
+    fn encrypt13(keys: &mut RecordKeys, secret: &[u8], content_type: u8, data: &[u8]) -> Vec<u8> {
+        let key = expand_label(HashAlgorithm::Sha256, secret, "key", 16).unwrap();
+        let cipher = Aes128Gcm::new_from_slice(&key).unwrap();
+        let mut buf = data.to_vec();
+        buf.push(content_type);
+        let header = [23, 3, 3, 0, (buf.len() + 16) as u8];
+        let nonce = keys.xor_nonce();
+        cipher
+            .encrypt_in_place(Nonce::from_slice(&nonce), &header, &mut buf)
+            .unwrap();
+        keys.seq += 1;
+        record(23, &buf)
+    }
+
+    #[test]
+    fn handler_events() {
+        let log = Log::default();
+        let shared_info = TlsDecryptSharedInfo {
+            keylog: Arc::new(KeyLog::parse("")),
+            inner: log.clone(),
+        };
+        let mut table: FlowTable<TlsDecryptHandler<LogHandler>> = FlowTable::new(shared_info);
+        let syn = TcpMeta {
+            src_addr: [10, 6, 0, 1].into(),
+            src_port: 40100,
+            dst_addr: [10, 6, 0, 2].into(),
+            dst_port: 443,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let syn_ack = TcpMeta {
+            src_addr: syn.dst_addr,
+            src_port: syn.dst_port,
+            dst_addr: syn.src_addr,
+            dst_port: syn.src_port,
+            seq_number: 5000,
+            ack_number: 1001,
+            flags: TcpFlags {
+                syn: true,
+                ack: true,
+                ..Default::default()
+            },
+            ..syn.clone()
+        };
+        let ack = TcpMeta {
+            seq_number: 1001,
+            ack_number: 5001,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            ..syn.clone()
+        };
+        for meta in [&syn, &syn_ack] {
+            assert!(table.handle_packet(meta, &[], &PacketExtra::None).unwrap());
+        }
+        // not TLS, passed on unchanged
+        assert!(table
+            .handle_packet(&ack, b"hello", &PacketExtra::None)
+            .unwrap());
+        let mut fin = ack.clone();
+        fin.seq_number += 5;
+        fin.flags.fin = true;
+        assert!(table.handle_packet(&fin, &[], &PacketExtra::None).unwrap());
+        let rst = TcpMeta {
+            seq_number: 5001,
+            ack_number: 1001,
+            flags: TcpFlags {
+                ack: true,
+                rst: true,
+                ..Default::default()
+            },
+            ..syn_ack.clone()
+        };
+        assert!(table.handle_packet(&rst, &[], &PacketExtra::None).unwrap());
+
+        assert_eq!(
+            log.lock().as_slice(),
+            ["handshake", "forward hello", "fin forward", "rst reverse"]
+        );
+    }
+
+    #[test]
+    fn decrypted_stream_output() {
+        let dir =
+            std::env::temp_dir().join(format!("parse-tcp-tls-decrypt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (inner, errors) = DirectoryOutputSharedInfo::new(dir.clone()).unwrap();
+        let shared_info = TlsDecryptSharedInfo {
+            keylog: Arc::new(KeyLog::parse("")),
+            inner: inner.clone(),
+        };
+        let mut table: FlowTable<TlsDecryptHandler<DirectoryOutputHandler>> =
+            FlowTable::new(shared_info);
+        let syn = TcpMeta {
+            src_addr: [10, 6, 0, 3].into(),
+            src_port: 40200,
+            dst_addr: [10, 6, 0, 4].into(),
+            dst_port: 443,
+            seq_number: 1000,
+            ack_number: 0,
+            flags: TcpFlags {
+                syn: true,
+                ..Default::default()
+            },
+            window: 1024,
+            urgent_pointer: 0,
+            checksum: 0,
+            option_mss: None,
+            option_window_scale: None,
+            option_timestamp: None,
+            option_tfo_cookie: None,
+            vlan_id: None,
+            ip_ecn: 0,
+            ip_id: 0,
+            ip_ttl: 64,
+            bad_checksum: false,
+        };
+        let syn_ack = TcpMeta {
+            src_addr: syn.dst_addr,
+            src_port: syn.dst_port,
+            dst_addr: syn.src_addr,
+            dst_port: syn.src_port,
+            seq_number: 5000,
+            ack_number: 1001,
+            flags: TcpFlags {
+                syn: true,
+                ack: true,
+                ..Default::default()
+            },
+            ..syn.clone()
+        };
+        let ack = TcpMeta {
+            seq_number: 1001,
+            ack_number: 5001,
+            flags: TcpFlags {
+                ack: true,
+                ..Default::default()
+            },
+            ..syn.clone()
+        };
+        for meta in [&syn, &syn_ack] {
+            assert!(table.handle_packet(meta, &[], &PacketExtra::None).unwrap());
+        }
+        // not TLS, written as is
+        assert!(table
+            .handle_packet(&ack, b"hello", &PacketExtra::None)
+            .unwrap());
+        let id = table.map.values().next().unwrap().uuid;
+        table.close();
+        drop(table);
+        inner.close().unwrap();
+        assert!(errors.try_recv().is_err());
+
+        let data = std::fs::read(dir.join(format!("{id}.f.data"))).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn key_derivation() {
+        // RFC 8448 simple 1-RTT handshake, server handshake traffic keys
+        let secret = hex("b6 7b 7d 69 0c c1 6c 4e 75 e5 42 13 cb 2d 37 b4 \
+             e9 c9 12 bc de d9 10 5d 42 be fd 59 d3 91 ad 38");
+        let key = expand_label(HashAlgorithm::Sha256, &secret, "key", 16).unwrap();
+        assert_eq!(key, hex("3f ce 51 60 09 c2 17 27 d0 f2 e4 e8 6e e4 03 bc"));
+        let iv = expand_label(HashAlgorithm::Sha256, &secret, "iv", 12).unwrap();
+        assert_eq!(iv, hex("5d 31 3e b2 67 12 76 ee 13 00 0b 30"));
+
+        // TLS 1.2 PRF with SHA-256
+        let out = prf(
+            HashAlgorithm::Sha256,
+            &hex("9b be 43 6b a9 40 f0 17 b1 76 52 84 9a 71 db 35"),
+            b"test label",
+            &hex("a0 ba 9f 93 6c da 31 18 27 a6 f7 96 ff d5 19 8c"),
+            100,
+        );
+        assert_eq!(
+            out[..16],
+            hex("e3 f2 29 ba 72 7b e1 7b 8d 12 26 20 55 7c d4 53")
+        );
+    }
+
+    #[test]
+    fn decrypt_tls13() {
+        let client_random = [1u8; 32];
+        let server_hs = [2u8; 32];
+        let server_app = [3u8; 32];
+        let keylog = KeyLog::parse(&format!(
+            "# comment\n\
+             SERVER_HANDSHAKE_TRAFFIC_SECRET {random} {hs}\n\
+             SERVER_TRAFFIC_SECRET_0 {random} {app}\n\
+             CLIENT_TRAFFIC_SECRET_0 zz\n",
+            random = "01".repeat(32),
+            hs = "02".repeat(32),
+            app = "03".repeat(32),
+        ));
+        assert_eq!(keylog.len(), 2);
+        assert!(keylog
+            .get(&client_random, SecretLabel::ServerTraffic0)
+            .is_some());
+
+        // ClientHello: version, random, session id, suites, compression
+        let mut client_hello = vec![3, 3];
+        client_hello.extend_from_slice(&client_random);
+        client_hello.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        // ServerHello selecting TLS 1.3 with TLS_AES_128_GCM_SHA256
+        let mut server_hello = vec![3, 3];
+        server_hello.extend_from_slice(&[9; 32]);
+        server_hello.extend_from_slice(&[0, 0x13, 0x01, 0]);
+        server_hello.extend_from_slice(&[0, 6, 0, 43, 0, 2, 3, 4]);
+
+        let suite = CipherSuite::from_id(0x1301).unwrap();
+        let mut hs_keys = RecordKeys::tls13(suite, &server_hs).unwrap();
+        let mut app_keys = RecordKeys::tls13(suite, &server_app).unwrap();
+        let mut server = record(22, &handshake(2, &server_hello));
+        server.extend(record(20, &[1]));
+        let finished = handshake(20, &[0; 32]);
+        server.extend(encrypt13(&mut hs_keys, &server_hs, 22, &finished));
+        server.extend(encrypt13(&mut app_keys, &server_app, 23, b"hello, "));
+        server.extend(encrypt13(&mut app_keys, &server_app, 23, b"world"));
+
+        let mut decryptor = TlsDecryptor::new(Arc::new(keylog));
+        let mut out = Vec::new();
+        let client = record(22, &handshake(1, &client_hello));
+        assert!(!decryptor.feed(Direction::Forward, &client, false, &mut out));
+        assert_eq!(decryptor.is_tls(), Some(true));
+        // split records across reads
+        let (a, b) = server.split_at(server.len() - 20);
+        assert!(!decryptor.feed(Direction::Reverse, a, false, &mut out));
+        assert_eq!(out, b"hello, ");
+        assert!(!decryptor.feed(Direction::Reverse, b, false, &mut out));
+        assert_eq!(out, b"hello, world");
+
+        // loss stops decryption and is reported once
+        out.clear();
+        assert!(decryptor.feed(Direction::Reverse, b"x", true, &mut out));
+        assert!(!decryptor.feed(Direction::Reverse, b"x", false, &mut out));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn passthrough() {
+        let mut decryptor = TlsDecryptor::new(Arc::new(KeyLog::default()));
+        let mut out = Vec::new();
+        assert!(!decryptor.feed(Direction::Forward, b"G", false, &mut out));
+        assert!(out.is_empty());
+        assert!(!decryptor.feed(Direction::Forward, b"ET /", false, &mut out));
+        assert_eq!(decryptor.is_tls(), Some(false));
+        assert_eq!(out, b"GET /");
+    }
+}
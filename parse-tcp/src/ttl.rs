@@ -0,0 +1,121 @@
+//! Per-direction IP TTL tracking
+//!
+//! Packets sent by one host usually arrive with the same TTL (or hop limit),
+//! as they take the same path. A packet with a TTL far from the usual value
+//! was likely sent by another host on the path, such as a middlebox
+//! injecting RST or data packets.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// packets seen in a direction before deviations are reported
+pub const TTL_BASELINE_PACKETS: u64 = 3;
+/// default difference from the most common TTL reported as an anomaly
+pub const TTL_ANOMALY_THRESHOLD: u8 = 5;
+
+/// summary of TTLs seen in one direction
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TtlStats {
+    /// lowest TTL seen
+    pub min: u8,
+    /// highest TTL seen
+    pub max: u8,
+    /// most frequently seen TTL
+    pub most_common: u8,
+    /// number of packets reported as anomalous
+    pub anomalies: u64,
+}
+
+/// TTL histogram for packets sent in one direction
+#[derive(Clone, Debug, Default)]
+pub struct TtlTracker {
+    /// number of packets seen per TTL
+    counts: BTreeMap<u8, u64>,
+    /// total number of packets seen
+    pub packets: u64,
+    /// number of packets reported as anomalous
+    pub anomalies: u64,
+}
+
+impl TtlTracker {
+    /// create new instance
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// record TTL of a packet, returning the most common TTL seen so far if
+    /// this one differs from it by more than `threshold`
+    pub fn record(&mut self, ttl: u8, threshold: u8) -> Option<u8> {
+        let expected = if self.packets >= TTL_BASELINE_PACKETS {
+            self.most_common()
+        } else {
+            None
+        };
+        *self.counts.entry(ttl).or_default() += 1;
+        self.packets += 1;
+        let expected = expected?;
+        if expected.abs_diff(ttl) > threshold {
+            self.anomalies += 1;
+            Some(expected)
+        } else {
+            None
+        }
+    }
+
+    /// lowest TTL seen
+    pub fn min(&self) -> Option<u8> {
+        self.counts.keys().next().copied()
+    }
+
+    /// highest TTL seen
+    pub fn max(&self) -> Option<u8> {
+        self.counts.keys().next_back().copied()
+    }
+
+    /// most frequently seen TTL, the lowest one on ties
+    pub fn most_common(&self) -> Option<u8> {
+        self.counts
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| **count)
+            .map(|(ttl, _)| *ttl)
+    }
+
+    /// get summary, if any packets were seen
+    pub fn stats(&self) -> Option<TtlStats> {
+        Some(TtlStats {
+            min: self.min()?,
+            max: self.max()?,
+            most_common: self.most_common()?,
+            anomalies: self.anomalies,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TtlStats, TtlTracker};
+
+    #[test]
+    fn record() {
+        let mut tracker = TtlTracker::new();
+        assert_eq!(tracker.stats(), None);
+        // no baseline yet
+        assert_eq!(tracker.record(64, 5), None);
+        assert_eq!(tracker.record(250, 5), None);
+        assert_eq!(tracker.record(64, 5), None);
+        // small variation is tolerated
+        assert_eq!(tracker.record(62, 5), None);
+        assert_eq!(tracker.record(128, 5), Some(64));
+        assert_eq!(
+            tracker.stats(),
+            Some(TtlStats {
+                min: 62,
+                max: 250,
+                most_common: 64,
+                anomalies: 1,
+            })
+        );
+    }
+}
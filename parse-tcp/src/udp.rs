@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tracing::{debug, info, trace};
+use uuid::Uuid;
+
+use crate::connection::{CloseReason, Direction};
+use crate::filter::FilterExpr;
+use crate::flow_table::{Flow, FlowCompare, IPPROTO_UDP};
+use crate::handler::{DirectoryOutputHandlerFiles, DirectoryOutputSharedInfo};
+use crate::serialized::{ConnInfo, PacketExtra, SerializedSegment};
+use crate::stats::StreamStats;
+use crate::writer::WriterMessage;
+use crate::{UdpFlowHandler, UdpMeta};
+
+/// default time without datagrams after which a flow is retired
+pub const DEFAULT_UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+/// how often (in capture time) to scan for idle flows
+pub const UDP_EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+impl From<&UdpMeta> for Flow {
+    fn from(value: &UdpMeta) -> Self {
+        Flow {
+            proto: IPPROTO_UDP,
+            src_addr: value.src_addr,
+            src_port: value.src_port,
+            dst_addr: value.dst_addr,
+            dst_port: value.dst_port,
+        }
+    }
+}
+
+/// datagrams exchanged between a pair of endpoints
+pub struct UdpFlow<H: UdpFlowHandler> {
+    /// unique identifier for flow
+    pub uuid: Uuid,
+    /// forward direction flow identifier, the sender of the first datagram
+    /// is considered the client
+    pub forward_flow: Flow,
+    /// capture time of first datagram, if known
+    pub first_packet_time: Option<Duration>,
+    /// capture time of most recent datagram, if known
+    pub last_packet_time: Option<Duration>,
+    /// datagrams sent in forward direction
+    pub forward_packets: u64,
+    /// datagrams sent in reverse direction
+    pub reverse_packets: u64,
+    /// payload bytes sent in forward direction
+    pub forward_bytes: u64,
+    /// payload bytes sent in reverse direction
+    pub reverse_bytes: u64,
+    /// why the flow was retired, set before `will_retire`
+    pub close_reason: Option<CloseReason>,
+
+    /// event handler object
+    pub event_handler: Option<Box<H>>,
+}
+
+impl<H: UdpFlowHandler> UdpFlow<H> {
+    /// create new flow
+    pub fn new(
+        forward_flow: Flow,
+        handler_init_data: H::InitialData,
+    ) -> Result<Self, H::ConstructError> {
+        let mut flow = UdpFlow {
+            uuid: Uuid::new_v4(),
+            forward_flow,
+            first_packet_time: None,
+            last_packet_time: None,
+            forward_packets: 0,
+            reverse_packets: 0,
+            forward_bytes: 0,
+            reverse_bytes: 0,
+            close_reason: None,
+            event_handler: None,
+        };
+        let handler = H::new(handler_init_data, &mut flow)?;
+        flow.event_handler = Some(Box::new(handler));
+        Ok(flow)
+    }
+
+    /// handle a datagram belonging to this flow
+    #[tracing::instrument(name = "udp_flow", level = "debug", skip_all, fields(id = %self.uuid))]
+    pub fn handle_datagram(&mut self, meta: &UdpMeta, data: &[u8], extra: &PacketExtra) {
+        let direction = match self.forward_flow.compare(&meta.into()) {
+            FlowCompare::Forward => Direction::Forward,
+            FlowCompare::Reverse => Direction::Reverse,
+            FlowCompare::None => unreachable!("flow got unrelated datagram"),
+        };
+        match direction {
+            Direction::Forward => {
+                self.forward_packets += 1;
+                self.forward_bytes += data.len() as u64;
+            }
+            Direction::Reverse => {
+                self.reverse_packets += 1;
+                self.reverse_bytes += data.len() as u64;
+            }
+        }
+        if let Some(now) = extra.timestamp() {
+            self.first_packet_time.get_or_insert(now);
+            self.last_packet_time = Some(now);
+        }
+        self.call_handler(|flow, h| h.datagram_received(flow, direction, data, extra));
+    }
+
+    /// call the event handler, if one exists
+    pub fn call_handler(&mut self, do_thing: impl FnOnce(&mut Self, &mut H)) {
+        if let Some(mut handler) = self.event_handler.take() {
+            do_thing(self, &mut handler);
+            self.event_handler = Some(handler);
+        }
+    }
+
+    /// called before flow is removed from hashtable
+    pub fn will_retire(&mut self) {
+        self.call_handler(|flow, h| h.will_retire(flow));
+    }
+
+    /// whether the flow has not seen datagrams since `now - timeout`
+    pub fn is_idle(&self, now: Duration, timeout: Duration) -> bool {
+        self.last_packet_time
+            .is_some_and(|last| now.saturating_sub(last) > timeout)
+    }
+}
+
+/// a table of UDP flows
+pub struct UdpFlowTable<H: UdpFlowHandler>
+where
+    H::InitialData: Clone,
+{
+    /// map holding flows by tuple
+    pub map: HashMap<Flow, UdpFlow<H>>,
+    /// time without datagrams after which a flow is retired
+    pub timeout: Duration,
+    /// capture time of last scan for idle flows
+    pub last_expire: Option<Duration>,
+    /// only create flows matching this filter
+    pub filter: Option<FilterExpr>,
+    /// number of datagrams dropped because they did not match the filter
+    pub filtered_packets: u64,
+    /// initial data for UdpFlowHandler
+    pub handler_init_data: H::InitialData,
+}
+
+impl<H: UdpFlowHandler> UdpFlowTable<H>
+where
+    H::InitialData: Clone,
+{
+    /// create new instance
+    pub fn new(handler_init_data: H::InitialData) -> Self {
+        Self {
+            map: HashMap::new(),
+            timeout: DEFAULT_UDP_FLOW_TIMEOUT,
+            last_expire: None,
+            filter: None,
+            filtered_packets: 0,
+            handler_init_data,
+        }
+    }
+
+    /// handle a datagram, creating a flow if necessary
+    pub fn handle_packet(
+        &mut self,
+        meta: &UdpMeta,
+        data: &[u8],
+        extra: &PacketExtra,
+    ) -> Result<(), H::ConstructError> {
+        let flow: Flow = meta.into();
+        let now = extra.timestamp();
+        if let Some(now) = now {
+            self.maybe_expire(now);
+        }
+
+        let existing_idle = self
+            .map
+            .get(&flow)
+            .map(|f| now.is_some_and(|now| f.is_idle(now, self.timeout)));
+        match existing_idle {
+            Some(false) => {}
+            Some(true) => {
+                // same tuple reused after timeout, start a new flow
+                self.retire_flow(flow.clone());
+                self.create_flow(flow.clone())?;
+            }
+            None => {
+                if self.filter.as_ref().is_some_and(|f| !f.matches(&flow)) {
+                    self.filtered_packets += 1;
+                    return Ok(());
+                }
+                self.create_flow(flow.clone())?
+            }
+        }
+        self.map
+            .get_mut(&flow)
+            .expect("flow just created")
+            .handle_datagram(meta, data, extra);
+        Ok(())
+    }
+
+    /// create flow
+    fn create_flow(&mut self, flow: Flow) -> Result<(), H::ConstructError> {
+        let udp_flow = UdpFlow::new(flow.clone(), self.handler_init_data.clone())?;
+        debug!("new udp flow: {} {flow}", udp_flow.uuid);
+        self.map.insert(flow, udp_flow);
+        Ok(())
+    }
+
+    /// retire flows idle for longer than the timeout, at most once per
+    /// UDP_EXPIRE_INTERVAL of capture time
+    pub fn maybe_expire(&mut self, now: Duration) {
+        if self
+            .last_expire
+            .is_some_and(|last| now.saturating_sub(last) < UDP_EXPIRE_INTERVAL)
+        {
+            return;
+        }
+        self.last_expire = Some(now);
+        self.expire(now);
+    }
+
+    /// retire all flows idle for longer than the timeout
+    pub fn expire(&mut self, now: Duration) {
+        let timeout = self.timeout;
+        let idle: Vec<Flow> = self
+            .map
+            .iter()
+            .filter(|(_, f)| f.is_idle(now, timeout))
+            .map(|(flow, _)| flow.clone())
+            .collect();
+        if !idle.is_empty() {
+            trace!("expiring {} idle udp flows", idle.len());
+        }
+        for flow in idle {
+            self.retire_flow(flow);
+        }
+    }
+
+    /// remove idle flow from table
+    pub fn retire_flow(&mut self, flow: Flow) {
+        let Some(mut udp_flow) = self.map.remove(&flow) else {
+            return;
+        };
+        debug!("remove udp flow: {} {flow}", udp_flow.uuid);
+        udp_flow.close_reason = Some(CloseReason::Timeout);
+        udp_flow.will_retire();
+    }
+
+    /// close flowtable and retire all flows
+    pub fn close(&mut self) {
+        debug!("udp flowtable closing");
+        for (flow, mut udp_flow) in self.map.drain() {
+            debug!("remove udp flow: {} {flow}", udp_flow.uuid);
+            udp_flow.close_reason = Some(CloseReason::Eof);
+            udp_flow.will_retire();
+        }
+    }
+}
+
+/// UdpFlowHandler to write datagrams to a directory, in the same layout as
+/// DirectoryOutputHandler
+pub struct UdpDirectoryOutputHandler {
+    pub shared_info: DirectoryOutputSharedInfo,
+    pub files: DirectoryOutputHandlerFiles,
+    /// offset of next datagram in forward data file
+    pub forward_offset: u64,
+    /// offset of next datagram in reverse data file
+    pub reverse_offset: u64,
+}
+
+impl UdpFlowHandler for UdpDirectoryOutputHandler {
+    type InitialData = DirectoryOutputSharedInfo;
+    type ConstructError = eyre::Report;
+    fn new(shared_info: Self::InitialData, flow: &mut UdpFlow<Self>) -> eyre::Result<Self> {
+        info!(
+            "writing data for new udp flow: {} ({})",
+            flow.forward_flow, flow.uuid
+        );
+        let inner = &shared_info.inner;
+        let files = DirectoryOutputHandlerFiles::create(
+            &inner.writer,
+            &inner.base_dir,
+            flow.uuid,
+            ["f.data", "r.data"],
+            inner.segment_format,
+        );
+        Ok(UdpDirectoryOutputHandler {
+            shared_info,
+            files,
+            forward_offset: 0,
+            reverse_offset: 0,
+        })
+    }
+
+    fn datagram_received(
+        &mut self,
+        _flow: &mut UdpFlow<Self>,
+        direction: Direction,
+        data: &[u8],
+        extra: &PacketExtra,
+    ) {
+        let (data_file, segments_file, offset) = match direction {
+            Direction::Forward => (
+                self.files.forward_data,
+                self.files.forward_segments,
+                &mut self.forward_offset,
+            ),
+            Direction::Reverse => (
+                self.files.reverse_data,
+                self.files.reverse_segments,
+                &mut self.reverse_offset,
+            ),
+        };
+        let info = SerializedSegment::Datagram {
+            offset: *offset,
+            len: data.len(),
+            extra: extra.clone(),
+        };
+        *offset += data.len() as u64;
+        let end_offset = *offset;
+        let writer = &self.shared_info.inner.writer;
+        let mut segment = Vec::new();
+        self.shared_info
+            .inner
+            .segment_format
+            .write(&mut segment, &info);
+        let segment_len = segment.len() as u64;
+
+        writer.send(WriterMessage::Write {
+            id: data_file,
+            data: data.to_vec(),
+        });
+        writer.send(WriterMessage::Write {
+            id: segments_file,
+            data: segment,
+        });
+        self.files.record_progress(
+            writer,
+            direction,
+            end_offset,
+            data.len() as u64,
+            segment_len,
+        );
+    }
+
+    fn will_retire(&mut self, flow: &mut UdpFlow<Self>) {
+        info!("removing udp flow: {} ({})", flow.forward_flow, flow.uuid);
+        self.files.close(&self.shared_info.inner.writer);
+        let mut info = ConnInfo::new(flow.uuid, &flow.forward_flow);
+        info.set_packet_times(flow.first_packet_time, flow.last_packet_time);
+        info.forward_stats = Some(StreamStats::datagrams(
+            flow.forward_packets,
+            flow.forward_bytes,
+        ));
+        info.reverse_stats = Some(StreamStats::datagrams(
+            flow.reverse_packets,
+            flow.reverse_bytes,
+        ));
+        info.close_reason = flow.close_reason;
+        if let Err(e) = self.shared_info.write_conn_info(&info) {
+            tracing::error!("failed to write connection info: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+
+    use super::{UdpDirectoryOutputHandler, UdpFlow, UdpFlowTable};
+    use crate::connection::{CloseReason, Direction};
+    use crate::handler::DirectoryOutputSharedInfo;
+    use crate::serialized::{OutputProgress, PacketExtra};
+    use crate::{UdpFlowHandler, UdpMeta};
+
+    static RETIRED: Mutex<Vec<(u64, u64, Option<CloseReason>)>> = Mutex::new(Vec::new());
+
+    struct TestHandler;
+    impl UdpFlowHandler for TestHandler {
+        type InitialData = ();
+        type ConstructError = Infallible;
+        fn new(_init: (), _flow: &mut UdpFlow<Self>) -> Result<Self, Infallible> {
+            Ok(TestHandler)
+        }
+        fn will_retire(&mut self, flow: &mut UdpFlow<Self>) {
+            RETIRED.lock().push((
+                flow.forward_packets,
+                flow.reverse_packets,
+                flow.close_reason,
+            ));
+        }
+    }
+
+    fn datagram(reverse: bool) -> UdpMeta {
+        let (client, server) = (([10, 0, 0, 1].into(), 5353), ([10, 0, 0, 2].into(), 53));
+        let ((src_addr, src_port), (dst_addr, dst_port)) = if reverse {
+            (server, client)
+        } else {
+            (client, server)
+        };
+        UdpMeta {
+            src_addr,
+            src_port,
+            dst_addr,
+            dst_port,
+            vlan_id: None,
+            ip_ttl: 64,
+            bad_checksum: false,
+        }
+    }
+
+    fn at(ts_sec: u32) -> PacketExtra {
+        PacketExtra::LegacyPcap {
+            index: 0,
+            ts_sec,
+            ts_usec: 0,
+            vlan_id: None,
+            frames: None,
+            ttl: None,
+            bad_checksum: false,
+        }
+    }
+
+    #[test]
+    fn flows_and_timeout() {
+        let mut table: UdpFlowTable<TestHandler> = UdpFlowTable::new(());
+        table.timeout = Duration::from_secs(10);
+        table
+            .handle_packet(&datagram(false), b"query", &at(0))
+            .unwrap();
+        table
+            .handle_packet(&datagram(true), b"answer", &at(1))
+            .unwrap();
+        assert_eq!(table.map.len(), 1);
+        let flow = table.map.values().next().unwrap();
+        assert_eq!(flow.forward_bytes, 5);
+        assert_eq!(flow.reverse_bytes, 6);
+        assert_eq!(
+            flow.forward_flow
+                .compare(&(&datagram(false)).into())
+                .to_direction(),
+            Some(Direction::Forward)
+        );
+
+        // tuple reused after timeout starts a new flow
+        table
+            .handle_packet(&datagram(true), b"late", &at(30))
+            .unwrap();
+        assert_eq!(
+            RETIRED.lock().as_slice(),
+            &[(1, 1, Some(CloseReason::Timeout))]
+        );
+        let flow = table.map.values().next().unwrap();
+        assert_eq!(flow.forward_packets, 1);
+        assert_eq!(
+            flow.forward_flow
+                .compare(&(&datagram(true)).into())
+                .to_direction(),
+            Some(Direction::Forward)
+        );
+
+        table.expire(Duration::from_secs(50));
+        assert!(table.map.is_empty());
+        assert_eq!(RETIRED.lock().len(), 2);
+
+        table
+            .handle_packet(&datagram(false), b"query", &at(60))
+            .unwrap();
+        table.close();
+        assert_eq!(RETIRED.lock()[2], (1, 0, Some(CloseReason::Eof)));
+    }
+
+    #[test]
+    fn directory_output_sidecar() {
+        let dir = std::env::temp_dir().join(format!("parse-tcp-udp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (shared_info, errors_rx) = DirectoryOutputSharedInfo::new(dir.clone()).unwrap();
+        let mut table: UdpFlowTable<UdpDirectoryOutputHandler> =
+            UdpFlowTable::new(shared_info.clone());
+        table
+            .handle_packet(&datagram(false), b"query", &at(0))
+            .unwrap();
+        table
+            .handle_packet(&datagram(true), b"answer", &at(1))
+            .unwrap();
+        let id = table.map.values().next().unwrap().uuid;
+        table.close();
+        drop(table);
+        shared_info.close().unwrap();
+        assert!(errors_rx.try_recv().is_err());
+
+        let sidecar = std::fs::read(dir.join(format!("{id}.meta.json"))).unwrap();
+        let progress: OutputProgress = serde_json::from_slice(&sidecar).unwrap();
+        assert!(progress.complete);
+        assert_eq!(progress.forward.end_offset, 5);
+        assert_eq!(progress.forward.data_bytes, 5);
+        assert_eq!(progress.reverse.data_bytes, 6);
+        let segments = std::fs::read(dir.join(format!("{id}.r.jsonl"))).unwrap();
+        assert_eq!(progress.reverse.segments_bytes, segments.len() as u64);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+//! Connections fed with data produced by another handler
+//!
+//! A handler transforming a stream, such as TLS decryption, owns a
+//! `VirtualConnection` and pushes its output into it. The virtual connection
+//! shares flow, id and reassembly limits with its parent and calls its own
+//! `ConnectionHandler` as a captured connection would, so handlers compose
+//! without special support.
+//!
+//! Data is appended in order and the handshake is considered complete once
+//! the handler is constructed. Data not consumed by the handler is buffered
+//! up to `max_buffer_size`, past which it is dropped and reported as a gap.
+
+use std::ops::Range;
+
+use kinesin_rdt::stream::inbound::ReceiveSegmentResult;
+use tracing::warn;
+
+use crate::connection::{CloseReason, Connection, ConnectionState, Direction};
+use crate::serialized::PacketExtra;
+use crate::stream::{SegmentInfo, SegmentType};
+use crate::ConnectionHandler;
+
+/// connection fed with data by another handler
+pub struct VirtualConnection<H: ConnectionHandler> {
+    pub connection: Connection<H>,
+    /// next stream offset to write (forward, reverse)
+    offsets: [u64; 2],
+}
+
+impl<H: ConnectionHandler> VirtualConnection<H> {
+    /// create connection derived from parent, constructing its handler
+    pub fn new<P: ConnectionHandler>(
+        parent: &Connection<P>,
+        init_data: H::InitialData,
+    ) -> Result<Self, H::ConstructError> {
+        let mut connection =
+            Connection::without_handler(parent.forward_flow.clone(), parent.config.clone());
+        connection.uuid = parent.uuid;
+        connection.conn_state = ConnectionState::Established {
+            forward_isn: 0,
+            reverse_isn: 0,
+        };
+        connection.observed_handshake = parent.observed_handshake;
+        connection.first_packet_time = parent.first_packet_time;
+        connection.last_packet_time = parent.last_packet_time;
+        connection.current_packet_index = parent.current_packet_index;
+        let handler = H::new(init_data, &mut connection)?;
+        connection.event_handler = Some(Box::new(handler));
+        connection.call_handler(|conn, h| h.handshake_done(conn));
+        Ok(VirtualConnection {
+            connection,
+            offsets: [0; 2],
+        })
+    }
+
+    /// get event handler
+    pub fn handler(&mut self) -> Option<&mut H> {
+        self.connection.event_handler.as_deref_mut()
+    }
+
+    /// copy time and packet index of the packet being handled from parent
+    pub fn sync<P: ConnectionHandler>(&mut self, parent: &Connection<P>) {
+        self.connection.last_packet_time = parent.last_packet_time;
+        self.connection.current_packet_index = parent.current_packet_index;
+    }
+
+    fn offset(&mut self, direction: Direction) -> &mut u64 {
+        match direction {
+            Direction::Forward => &mut self.offsets[0],
+            Direction::Reverse => &mut self.offsets[1],
+        }
+    }
+
+    /// append data to stream in direction and notify handler
+    pub fn push_data(&mut self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let offset = *self.offset(direction);
+        let end = offset + data.len() as u64;
+        let max_buffer_size = self.connection.config.max_buffer_size;
+        let stream = self.connection.get_stream(direction);
+        if end - stream.buffer_start() > max_buffer_size {
+            warn!("virtual {direction} stream exceeds max buffer, dropping data");
+            self.push_gap(direction, data.len() as u64);
+            return;
+        }
+        if end > stream.state.window_limit {
+            stream.state.set_limit(end);
+        }
+        let result = stream.state.receive_segment(offset, data);
+        debug_assert_ne!(result, ReceiveSegmentResult::ExceedsWindow);
+        stream.update_memory_usage();
+        stream.data_bytes += data.len() as u64;
+        stream.packet_count += 1;
+        stream.add_segment_info(SegmentInfo {
+            offset,
+            reverse_acked: 0,
+            extra: PacketExtra::None,
+            tcp: Default::default(),
+            data: SegmentType::Data {
+                len: data.len(),
+                is_retransmit: false,
+            },
+        });
+        *self.offset(direction) = end;
+
+        let stream = match direction {
+            Direction::Forward => &self.connection.forward_stream,
+            Direction::Reverse => &self.connection.reverse_stream,
+        };
+        self.connection.classification.inspect(direction, stream);
+        self.connection.scan_matches(direction);
+        self.connection
+            .call_handler(|conn, h| h.data_received(conn, direction));
+    }
+
+    /// skip `len` bytes of lost data in direction and notify handler
+    pub fn push_gap(&mut self, direction: Direction, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let start = *self.offset(direction);
+        let gap: Range<u64> = start..start + len;
+        *self.offset(direction) = gap.end;
+        self.connection
+            .get_stream(direction)
+            .declare_gap(gap.clone());
+        self.connection
+            .call_handler(|conn, h| h.gap_detected(conn, direction, gap));
+    }
+
+    /// end stream in direction at the current offset and notify handler
+    pub fn push_fin(&mut self, direction: Direction) {
+        let offset = *self.offset(direction);
+        let stream = self.connection.get_stream(direction);
+        if !stream.state.set_final_offset(offset) {
+            // already ended
+            return;
+        }
+        stream.fin_count += 1;
+        // nothing acknowledges the FIN of a virtual stream
+        stream.has_ended = true;
+        self.connection
+            .call_handler(|conn, h| h.fin_received(conn, direction));
+    }
+
+    /// close connection after a reset sent in direction and notify handler
+    pub fn push_rst(&mut self, direction: Direction, extra: PacketExtra) {
+        let stream = self.connection.get_stream(direction);
+        stream.had_reset = true;
+        stream.rst_count += 1;
+        self.connection.conn_state = ConnectionState::Closed;
+        self.connection.observed_close = true;
+        self.connection.close_reason = Some(CloseReason::Rst);
+        self.connection
+            .call_handler(|conn, h| h.rst_received(conn, direction, extra));
+    }
+
+    /// swap forward and reverse direction, notifying the handler
+    pub fn reverse_direction(&mut self) {
+        self.offsets.swap(0, 1);
+        self.connection.reverse_direction();
+    }
+
+    /// notify handler that the connection is being removed
+    pub fn retire(&mut self) {
+        self.connection.will_retire();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::ops::Range;
+
+    use super::VirtualConnection;
+    use crate::connection::{Connection, ConnectionState, Direction};
+    use crate::flow_table::{Flow, IPPROTO_TCP};
+    use crate::serialized::PacketExtra;
+    use crate::ConnectionHandler;
+
+    /// handler collecting stream data, gaps and other events
+    #[derive(Default)]
+    struct Collect {
+        data: Vec<u8>,
+        /// gaps read from the stream along with data
+        gaps: Vec<Range<u64>>,
+        events: Vec<String>,
+        retired: bool,
+    }
+
+    impl ConnectionHandler for Collect {
+        type InitialData = ();
+        type ConstructError = Infallible;
+        fn new(_init: (), _conn: &mut Connection<Self>) -> Result<Self, Infallible> {
+            Ok(Collect::default())
+        }
+
+        fn handshake_done(&mut self, _connection: &mut Connection<Self>) {
+            self.events.push("handshake".into());
+        }
+
+        fn data_received(&mut self, connection: &mut Connection<Self>, direction: Direction) {
+            let stream = connection.get_stream(direction);
+            let end = stream.buffer_start() + stream.total_buffered_length() as u64;
+            let (mut segments, mut gaps) = (Vec::new(), Vec::new());
+            let data = &mut self.data;
+            stream.read_next(end, &mut segments, &mut gaps, |slice| {
+                let (a, b) = slice.as_slices();
+                data.extend_from_slice(a);
+                data.extend_from_slice(b.unwrap_or_default());
+            });
+            self.gaps.extend(gaps.into_iter().map(|gap| gap.range));
+        }
+
+        fn gap_detected(
+            &mut self,
+            _connection: &mut Connection<Self>,
+            direction: Direction,
+            range: Range<u64>,
+        ) {
+            self.events.push(format!("gap {direction} {range:?}"));
+        }
+
+        fn fin_received(&mut self, _connection: &mut Connection<Self>, direction: Direction) {
+            self.events.push(format!("fin {direction}"));
+        }
+
+        fn rst_received(
+            &mut self,
+            _connection: &mut Connection<Self>,
+            direction: Direction,
+            _extra: PacketExtra,
+        ) {
+            self.events.push(format!("rst {direction}"));
+        }
+
+        fn direction_changed(&mut self, connection: &mut Connection<Self>) {
+            let port = connection.forward_flow.src_port;
+            self.events.push(format!("reversed {port}"));
+        }
+
+        fn will_retire(&mut self, _connection: &mut Connection<Self>) {
+            self.retired = true;
+        }
+    }
+
+    fn parent() -> Connection<Collect> {
+        let flow = Flow {
+            proto: IPPROTO_TCP,
+            src_addr: [192, 0, 2, 1].into(),
+            src_port: 51000,
+            dst_addr: [192, 0, 2, 2].into(),
+            dst_port: 443,
+        };
+        Connection::new(flow, ()).unwrap()
+    }
+
+    #[test]
+    fn push_data() {
+        let parent = parent();
+        let mut virt: VirtualConnection<Collect> = VirtualConnection::new(&parent, ()).unwrap();
+        assert_eq!(virt.connection.uuid, parent.uuid);
+
+        virt.push_data(Direction::Forward, b"GET / HTTP/1.1\r\n");
+        virt.push_gap(Direction::Forward, 3);
+        virt.push_data(Direction::Forward, b"\r\n");
+        virt.retire();
+
+        let handler = virt.handler().unwrap();
+        assert_eq!(handler.data, b"GET / HTTP/1.1\r\n\0\0\0\r\n");
+        assert_eq!(handler.gaps, vec![16..19]);
+        assert_eq!(handler.events, ["handshake", "gap forward 16..19"]);
+        assert!(handler.retired);
+    }
+
+    #[test]
+    fn push_events() {
+        let parent = parent();
+        let mut virt: VirtualConnection<Collect> = VirtualConnection::new(&parent, ()).unwrap();
+        virt.push_data(Direction::Forward, b"abc");
+        virt.reverse_direction();
+        // data sent earlier is now in the reverse direction
+        virt.push_data(Direction::Reverse, b"def");
+        assert_eq!(virt.connection.reverse_stream.buffer_start(), 6);
+        virt.push_fin(Direction::Reverse);
+        virt.push_fin(Direction::Reverse);
+        virt.push_rst(Direction::Forward, PacketExtra::None);
+        assert_eq!(virt.connection.conn_state, ConnectionState::Closed);
+
+        let handler = virt.handler().unwrap();
+        assert_eq!(handler.data, b"abcdef");
+        assert_eq!(
+            handler.events,
+            ["handshake", "reversed 443", "fin reverse", "rst forward"]
+        );
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use eyre::Context;
+use flate2::write::GzEncoder;
+use tracing::{debug, trace, warn};
+
+/// default number of writer threads
+pub const DEFAULT_WRITER_THREADS: usize = 2;
+/// default number of queued messages per writer thread before blocking
+pub const DEFAULT_WRITER_QUEUE_DEPTH: usize = 1024;
+
+/// default gzip compression level
+pub const DEFAULT_GZIP_LEVEL: u32 = 6;
+/// default zstd compression level
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// compression applied to files written by the pool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// gzip with level 0-9
+    Gzip { level: u32 },
+    /// zstd with level 1-22
+    Zstd { level: i32 },
+}
+
+impl Compression {
+    /// extension appended to file names, without leading dot
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip { .. } => Some("gz"),
+            Compression::Zstd { .. } => Some("zst"),
+        }
+    }
+
+    /// create file at path, appending the codec extension
+    ///
+    /// If `append` is set, an existing file is kept and written past its end.
+    /// Compressed output then starts a new gzip member or zstd frame, which
+    /// decoders read as a continuation.
+    fn create(&self, mut path: PathBuf, append: bool) -> io::Result<OutputFile> {
+        if let Some(extension) = self.extension() {
+            let mut name = path.file_name().unwrap_or_default().to_owned();
+            name.push(".");
+            name.push(extension);
+            path.set_file_name(name);
+        }
+        let file = if append {
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(false)
+                .open(&path)
+                .and_then(|mut file| file.seek(SeekFrom::End(0)).map(|_| file))
+        } else {
+            File::create(&path)
+        };
+        let file = file
+            .map_err(|e| io::Error::new(e.kind(), format!("creating {}: {e}", path.display())))?;
+        let file = BufWriter::new(file);
+        Ok(match *self {
+            Compression::None => OutputFile::Plain(file),
+            Compression::Gzip { level } => {
+                OutputFile::Gzip(GzEncoder::new(file, flate2::Compression::new(level)))
+            }
+            Compression::Zstd { level } => OutputFile::Zstd(zstd::Encoder::new(file, level)?),
+        })
+    }
+}
+
+/// open file, possibly compressed
+enum OutputFile {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl OutputFile {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(w) => w.write_all(data),
+            OutputFile::Gzip(w) => w.write_all(data),
+            OutputFile::Zstd(w) => w.write_all(data),
+        }
+    }
+
+    /// overwrite data at offset of an uncompressed file, keeping the write
+    /// position at the end
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let OutputFile::Plain(w) = self else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cannot patch compressed file",
+            ));
+        };
+        let end = w.stream_position()?;
+        w.seek(SeekFrom::Start(offset))?;
+        w.write_all(data)?;
+        w.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    /// flush buffered data, ending a compressed block if needed
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(w) => w.flush(),
+            OutputFile::Gzip(w) => w.flush(),
+            OutputFile::Zstd(w) => w.flush(),
+        }
+    }
+
+    /// write trailers and flush
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            OutputFile::Plain(w) => w,
+            OutputFile::Gzip(w) => w.finish()?,
+            OutputFile::Zstd(w) => w.finish()?,
+        };
+        file.flush()
+    }
+}
+
+/// identifier for a file managed by the writer pool
+pub type WriterFileId = u64;
+
+/// request to a writer thread
+pub enum WriterMessage {
+    /// create (or truncate) file
+    Create { id: WriterFileId, path: PathBuf },
+    /// open file, keeping existing contents and writing past them
+    Append { id: WriterFileId, path: PathBuf },
+    /// append data to file
+    Write { id: WriterFileId, data: Vec<u8> },
+    /// overwrite data at offset of an uncompressed file
+    WriteAt {
+        id: WriterFileId,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// flush and close file
+    Close { id: WriterFileId },
+    /// flush files in `flush`, then atomically replace the uncompressed file
+    /// at `path` with `data`
+    ///
+    /// Files in `flush` must be serviced by the same thread as `id`, see
+    /// `WriterPool::allocate_group`.
+    Replace {
+        id: WriterFileId,
+        flush: Vec<WriterFileId>,
+        path: PathBuf,
+        data: Vec<u8>,
+    },
+}
+
+/// write data to a temporary file next to path, then rename it over path
+fn replace_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    let temp_path = path.with_file_name(name);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    std::fs::rename(&temp_path, path)
+}
+
+/// pool of threads performing file I/O off the packet processing path
+///
+/// Each file is always serviced by the same thread, so writes to a file are
+/// performed in order. Queues are bounded: when a writer falls behind,
+/// `send` blocks, applying backpressure to the caller. All files created by
+/// the pool are compressed with the same codec.
+pub struct WriterPool {
+    senders: Vec<Sender<WriterMessage>>,
+    threads: Vec<JoinHandle<()>>,
+    next_id: AtomicU64,
+}
+
+impl WriterPool {
+    /// spawn writer threads, reporting I/O errors to `errors`
+    pub fn new(
+        thread_count: usize,
+        queue_depth: usize,
+        errors: Sender<eyre::Report>,
+    ) -> std::io::Result<Self> {
+        Self::with_compression(thread_count, queue_depth, Compression::None, errors)
+    }
+
+    /// spawn writer threads compressing all files with `compression`
+    pub fn with_compression(
+        thread_count: usize,
+        queue_depth: usize,
+        compression: Compression,
+        errors: Sender<eyre::Report>,
+    ) -> std::io::Result<Self> {
+        assert!(thread_count > 0, "need at least one writer thread");
+        let mut senders = Vec::with_capacity(thread_count);
+        let mut threads = Vec::with_capacity(thread_count);
+        for i in 0..thread_count {
+            let (tx, rx) = crossbeam_channel::bounded(queue_depth);
+            let errors = errors.clone();
+            let thread = std::thread::Builder::new()
+                .name(format!("writer-{i}"))
+                .spawn(move || writer_thread(rx, compression, errors))?;
+            senders.push(tx);
+            threads.push(thread);
+        }
+        Ok(WriterPool {
+            senders,
+            threads,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// allocate a new file id
+    pub fn allocate_id(&self) -> WriterFileId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// allocate `count` file ids serviced by the same thread, so messages
+    /// for all of them are handled in order
+    pub fn allocate_group(&self, count: usize) -> Vec<WriterFileId> {
+        let stride = self.senders.len() as u64;
+        let base = self
+            .next_id
+            .fetch_add(stride * count as u64, Ordering::Relaxed);
+        (0..count as u64).map(|i| base + i * stride).collect()
+    }
+
+    /// queue message, blocking if the writer's queue is full
+    pub fn send(&self, message: WriterMessage) {
+        let id = match &message {
+            WriterMessage::Create { id, .. }
+            | WriterMessage::Append { id, .. }
+            | WriterMessage::Write { id, .. }
+            | WriterMessage::WriteAt { id, .. }
+            | WriterMessage::Close { id }
+            | WriterMessage::Replace { id, .. } => *id,
+        };
+        let sender = &self.senders[(id % self.senders.len() as u64) as usize];
+        match sender.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(message)) => {
+                trace!("writer queue full, blocking");
+                sender.send(message).expect("writer thread died");
+            }
+            Err(TrySendError::Disconnected(_)) => panic!("writer thread died"),
+        }
+    }
+
+    /// number of messages waiting in all queues
+    pub fn queued(&self) -> usize {
+        self.senders.iter().map(|s| s.len()).sum()
+    }
+
+    /// wait for all queued messages to be written and stop threads
+    pub fn close(self) {
+        drop(self.senders);
+        for thread in self.threads {
+            if thread.join().is_err() {
+                warn!("writer thread panicked");
+            }
+        }
+    }
+}
+
+/// writer thread main loop
+fn writer_thread(
+    rx: Receiver<WriterMessage>,
+    compression: Compression,
+    errors: Sender<eyre::Report>,
+) {
+    let mut files: HashMap<WriterFileId, OutputFile> = HashMap::new();
+    for message in rx {
+        let result = match message {
+            WriterMessage::Create { id, path } => compression
+                .create(path, false)
+                .map(|file| {
+                    files.insert(id, file);
+                })
+                .map_err(eyre::Report::from),
+            WriterMessage::Append { id, path } => compression
+                .create(path, true)
+                .map(|file| {
+                    files.insert(id, file);
+                })
+                .map_err(eyre::Report::from),
+            WriterMessage::Write { id, data } => match files.get_mut(&id) {
+                Some(file) => file.write_all(&data).wrap_err("writing file"),
+                // file failed to open, error already reported
+                None => Ok(()),
+            },
+            WriterMessage::WriteAt { id, offset, data } => match files.get_mut(&id) {
+                Some(file) => file.write_at(offset, &data).wrap_err("patching file"),
+                None => Ok(()),
+            },
+            WriterMessage::Close { id } => match files.remove(&id) {
+                Some(file) => file.finish().wrap_err("flushing file"),
+                None => Ok(()),
+            },
+            WriterMessage::Replace {
+                id: _,
+                flush,
+                path,
+                data,
+            } => flush
+                .iter()
+                .try_for_each(|id| match files.get_mut(id) {
+                    Some(file) => file.flush(),
+                    None => Ok(()),
+                })
+                .wrap_err("flushing file")
+                .and_then(|()| {
+                    replace_file(&path, &data)
+                        .wrap_err_with(|| format!("replacing {}", path.display()))
+                }),
+        };
+        if let Err(e) = result {
+            // receiver may be gone if we are shutting down
+            let _ = errors.send(e);
+        }
+    }
+    debug!("writer thread exiting ({} files left open)", files.len());
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::{Compression, WriterMessage, WriterPool};
+
+    #[test]
+    fn ordered_writes() {
+        let dir = std::env::temp_dir().join(format!("parse-tcp-writer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (errors_tx, errors_rx) = crossbeam_channel::unbounded();
+        // tiny queue to exercise blocking
+        let pool = WriterPool::new(2, 1, errors_tx).unwrap();
+        let ids: Vec<_> = (0..4).map(|_| pool.allocate_id()).collect();
+        for &id in &ids {
+            let path = dir.join(format!("{id}.data"));
+            pool.send(WriterMessage::Create { id, path });
+        }
+        for i in 0..100u8 {
+            for &id in &ids {
+                pool.send(WriterMessage::Write { id, data: vec![i] });
+            }
+        }
+        for &id in &ids {
+            pool.send(WriterMessage::Close { id });
+        }
+        pool.close();
+        assert!(errors_rx.try_recv().is_err());
+
+        let expected: Vec<u8> = (0..100).collect();
+        for id in ids {
+            let path = dir.join(format!("{id}.data"));
+            assert_eq!(std::fs::read(&path).unwrap(), expected);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn grouped_replace() {
+        let dir =
+            std::env::temp_dir().join(format!("parse-tcp-writer-replace-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (errors_tx, errors_rx) = crossbeam_channel::unbounded();
+        let pool = WriterPool::new(3, 4, errors_tx).unwrap();
+        pool.allocate_id();
+        let ids = pool.allocate_group(2);
+        assert_eq!(ids[0] % 3, ids[1] % 3);
+        let (data, meta) = (ids[0], ids[1]);
+        let data_path = dir.join("test.data");
+        let meta_path = dir.join("test.meta");
+        pool.send(WriterMessage::Create {
+            id: data,
+            path: data_path.clone(),
+        });
+        pool.send(WriterMessage::Write {
+            id: data,
+            data: b"a\0c".to_vec(),
+        });
+        pool.send(WriterMessage::WriteAt {
+            id: data,
+            offset: 1,
+            data: b"b".to_vec(),
+        });
+        pool.send(WriterMessage::Replace {
+            id: meta,
+            flush: vec![data],
+            path: meta_path.clone(),
+            data: b"first".to_vec(),
+        });
+        pool.send(WriterMessage::Replace {
+            id: meta,
+            flush: vec![data],
+            path: meta_path.clone(),
+            data: b"second".to_vec(),
+        });
+        pool.close();
+        assert!(errors_rx.try_recv().is_err());
+
+        // data was flushed even though the file was never closed
+        assert_eq!(std::fs::read(&data_path).unwrap(), b"abc");
+        assert_eq!(std::fs::read(&meta_path).unwrap(), b"second");
+        assert!(!dir.join("test.meta.tmp").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_writes() {
+        let dir = std::env::temp_dir().join(format!(
+            "parse-tcp-writer-compressed-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let expected: Vec<u8> = (0..10000u32).flat_map(|i| i.to_le_bytes()).collect();
+        for compression in [
+            Compression::Gzip { level: 6 },
+            Compression::Zstd { level: 3 },
+        ] {
+            let (errors_tx, errors_rx) = crossbeam_channel::unbounded();
+            let pool = WriterPool::with_compression(1, 16, compression, errors_tx).unwrap();
+            let id = pool.allocate_id();
+            let path = dir.join("test.data");
+            pool.send(WriterMessage::Create { id, path });
+            for chunk in expected.chunks(1000) {
+                pool.send(WriterMessage::Write {
+                    id,
+                    data: chunk.to_vec(),
+                });
+            }
+            pool.send(WriterMessage::Close { id });
+            pool.close();
+            assert!(errors_rx.try_recv().is_err());
+
+            let extension = compression.extension().unwrap();
+            let file = std::fs::File::open(dir.join(format!("test.data.{extension}"))).unwrap();
+            let mut decoded = Vec::new();
+            match compression {
+                Compression::Gzip { .. } => {
+                    flate2::read::GzDecoder::new(file)
+                        .read_to_end(&mut decoded)
+                        .unwrap();
+                }
+                _ => decoded = zstd::decode_all(file).unwrap(),
+            }
+            assert_eq!(decoded, expected);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}